@@ -0,0 +1,205 @@
+use common::raf::{Raf, RafByteOrder};
+
+/// A contiguous range of bytes from the source CBF file
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct RawBlock {
+    /// Offset of the block in the file
+    pub offset: usize,
+    pub data: Vec<u8>,
+    /// True if the parser models the contents of this block
+    pub known: bool,
+    /// True if the block has been replaced since the file was loaded
+    pub modified: bool,
+}
+
+/// The source file split into the blocks that the parser understands, and the bytes
+/// between them which it doesn't.
+///
+/// Saving re-emits every unknown block verbatim, so a file round trips byte for byte
+/// even though the parser only covers part of the format
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct BlockMap {
+    blocks: Vec<RawBlock>,
+}
+
+impl BlockMap {
+    /// Splits the source data into blocks
+    ///
+    /// # Params
+    /// * data - Entire source file
+    /// * known - (offset, length) of each block the parser models. Ranges may overlap,
+    /// and are clipped to the size of the file
+    pub fn from_source(data: &[u8], known: &[(usize, usize)]) -> Self {
+        let mut ranges: Vec<(usize, usize)> = known.iter()
+            .filter(|(offset, len)| *len > 0 && *offset < data.len())
+            .map(|(offset, len)| (*offset, std::cmp::min(offset + len, data.len())))
+            .collect();
+        ranges.sort_unstable();
+
+        // Merge overlapping ranges
+        let mut merged: Vec<(usize, usize)> = Vec::new();
+        for (start, end) in ranges {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = std::cmp::max(last.1, end),
+                _ => merged.push((start, end))
+            }
+        }
+
+        let mut blocks = Vec::new();
+        let mut pos = 0;
+        let mut push = |start: usize, end: usize, known: bool| {
+            blocks.push(RawBlock { offset: start, data: data[start..end].to_vec(), known, modified: false })
+        };
+        for (start, end) in merged {
+            if start > pos {
+                push(pos, start, false);
+            }
+            push(start, end, true);
+            pos = end;
+        }
+        if pos < data.len() {
+            push(pos, data.len(), false);
+        }
+        Self { blocks }
+    }
+
+    pub fn blocks(&self) -> &[RawBlock] {
+        &self.blocks
+    }
+
+    /// Returns every block the parser did not model
+    pub fn unknown_blocks(&self) -> impl Iterator<Item = &RawBlock> {
+        self.blocks.iter().filter(|b| !b.known)
+    }
+
+    /// Replaces the contents of a known block with re-serialized data.
+    ///
+    /// Other structures in the file refer to blocks by absolute offset, so the new data
+    /// must be the same length as the block it replaces
+    pub fn replace(&mut self, offset: usize, data: Vec<u8>) -> Result<(), String> {
+        let block = self.blocks.iter_mut()
+            .find(|b| b.known && b.offset == offset)
+            .ok_or(format!("No known block at offset 0x{:08X}", offset))?;
+        if block.data.len() != data.len() {
+            return Err(format!("Block at 0x{:08X} is {} bytes, cannot replace it with {} bytes", offset, block.data.len(), data.len()));
+        }
+        block.modified = block.data != data;
+        block.data = data;
+        Ok(())
+    }
+
+    /// Returns true if any block was changed since loading
+    pub fn is_modified(&self) -> bool {
+        self.blocks.iter().any(|b| b.modified)
+    }
+
+    /// Writes the blocks back out as a complete file
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.blocks.iter().flat_map(|b| b.data.iter().copied()).collect()
+    }
+
+    /// Writes the blocks back out as a complete file, then updates the length and checksum
+    /// fields of the format so the loader accepts the modified file.
+    ///
+    /// If nothing was replaced the file is written exactly as it was loaded, including a
+    /// checksum that was already wrong
+    pub fn to_bytes_with(&self, format: &FileFormat) -> Result<Vec<u8>, String> {
+        let mut res = self.to_bytes();
+        if self.is_modified() {
+            format.finish(&mut res)?;
+        }
+        Ok(res)
+    }
+}
+
+/// Checksum stored in a file, which has to be recomputed after the file is modified
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ChecksumAlgorithm {
+    /// File has no checksum
+    None,
+    /// IEEE CRC32 of everything before it, stored little endian in the last 4 bytes of the file
+    Crc32Trailer,
+}
+
+/// Fields of a file format which depend on the rest of the file, and are back-patched
+/// once the file has been serialized
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FileFormat {
+    pub checksum: ChecksumAlgorithm,
+    /// Offset of a little endian u32 holding the total size of the file, if the format has one
+    pub length_field: Option<usize>,
+}
+
+/// CBF files end with a CRC32 trailer, checked by [CContainer::verify_checksum](fn@crate::caesar::CContainer::verify_checksum).
+/// Sizes in the header describe individual blocks, which are always saved at their original size
+pub const CBF_FORMAT: FileFormat = FileFormat { checksum: ChecksumAlgorithm::Crc32Trailer, length_field: None };
+
+impl FileFormat {
+    /// Updates the length field, then the checksum, of a serialized file. The length is
+    /// written first so that it is covered by the checksum
+    pub fn finish(&self, data: &mut [u8]) -> Result<(), String> {
+        if let Some(offset) = self.length_field {
+            if offset + 4 > data.len() {
+                return Err(format!("Length field at 0x{:08X} is past the end of the file", offset));
+            }
+            data[offset..offset + 4].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        }
+        match self.checksum {
+            ChecksumAlgorithm::None => {},
+            ChecksumAlgorithm::Crc32Trailer => {
+                if data.len() < 4 {
+                    return Err(format!("File is {} bytes, too short to hold a checksum", data.len()));
+                }
+                let body_len = data.len() - 4;
+                let crc = Raf::from_bytes(&data[..body_len].to_vec(), RafByteOrder::LE).crc32(0, body_len).map_err(|e| e.to_string())?;
+                data[body_len..].copy_from_slice(&crc.to_le_bytes());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_round_trip_unknown_blocks() {
+    let data: Vec<u8> = (0..64).collect();
+    let mut map = BlockMap::from_source(&data, &[(8, 16), (40, 8), (44, 8), (60, 100)]);
+    let ranges: Vec<(usize, usize, bool)> = map.blocks().iter().map(|b| (b.offset, b.data.len(), b.known)).collect();
+    assert_eq!(ranges, vec![(0, 8, false), (8, 16, true), (24, 16, false), (40, 12, true), (52, 8, false), (60, 4, true)]);
+    assert_eq!(map.unknown_blocks().count(), 3);
+    assert_eq!(map.to_bytes(), data);
+
+    // Unmodified replacement does not mark the block as changed
+    map.replace(8, (8..24).collect()).unwrap();
+    assert!(!map.is_modified());
+
+    map.replace(40, vec![0xFF; 12]).unwrap();
+    assert!(map.is_modified());
+    let saved = map.to_bytes();
+    assert_eq!(&saved[..40], &data[..40]);
+    assert_eq!(&saved[40..52], &[0xFF; 12]);
+    assert_eq!(&saved[52..], &data[52..]);
+
+    assert!(map.replace(40, vec![0xFF; 4]).is_err());
+    assert!(map.replace(0, vec![0x00; 8]).is_err()); // Unknown blocks cannot be replaced
+}
+
+#[test]
+fn test_file_format_finish() {
+    let data: Vec<u8> = (0..32).collect();
+    let mut map = BlockMap::from_source(&data, &[(8, 8)]);
+    let format = FileFormat { checksum: ChecksumAlgorithm::Crc32Trailer, length_field: Some(4) };
+    // Unmodified files are saved as loaded
+    assert_eq!(map.to_bytes_with(&format).unwrap(), data);
+
+    map.replace(8, vec![0xAA; 8]).unwrap();
+    let saved = map.to_bytes_with(&format).unwrap();
+    assert_eq!(&saved[4..8], &32u32.to_le_bytes());
+    assert_eq!(&saved[8..16], &[0xAA; 8]);
+    let crc = Raf::from_bytes(&saved, RafByteOrder::LE).crc32(0, 28).unwrap();
+    assert_eq!(&saved[28..], &crc.to_le_bytes());
+
+    let no_checksum = FileFormat { checksum: ChecksumAlgorithm::None, length_field: None };
+    assert_eq!(&map.to_bytes_with(&no_checksum).unwrap()[28..], &data[28..]);
+    assert!(FileFormat { checksum: ChecksumAlgorithm::None, length_field: Some(30) }.finish(&mut vec![0; 32]).is_err());
+    assert!(CBF_FORMAT.finish(&mut vec![0; 3]).is_err());
+}
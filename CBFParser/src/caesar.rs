@@ -1,6 +1,7 @@
-use common::raf::{Raf, Result};
+use common::raf::{Raf, RafError, Result};
 use crate::cxf::*;
 use crate::ecu::*;
+use crate::blocks::{BlockMap, CBF_FORMAT};
 use serde::*;
 pub struct CReader{}
 
@@ -11,9 +12,9 @@ impl CReader {
             true => {
                 let str_offset = reader.read_i32().expect("Error reading string offset") as usize;
                 let pos = reader.pos;
-                reader.seek(str_offset + virtual_base as usize);
+                reader.seek(str_offset + virtual_base as usize).unwrap();
                 let res = Self::read_string(reader);
-                reader.seek(pos);
+                reader.seek(pos).unwrap();
                 Some(res)
             },
             false => None
@@ -26,9 +27,9 @@ impl CReader {
             true => {
                 let dump_offset = reader.read_i32().expect("Error reading offset") as usize;
                 let pos = reader.pos;
-                reader.seek(dump_offset + virtual_base as usize);
+                reader.seek(dump_offset + virtual_base as usize).unwrap();
                 let res = Self::read_array(reader, dump_size as usize);
-                reader.seek(pos);
+                reader.seek(pos).unwrap();
                 match res {
                     Ok(r) => Some(r),
                     Err(_) => None
@@ -100,15 +101,213 @@ impl CReader {
 
 }
 
+/// Where a record which could not be parsed is in its source file
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RecordLocation {
+    /// Offset of the record in a CBF file
+    Offset(i64),
+    /// Line number of the element in an ODX file
+    Line(u64),
+}
+
+impl std::fmt::Display for RecordLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RecordLocation::Offset(o) if *o < 0 => write!(f, "-0x{:08X}", o.unsigned_abs()),
+            RecordLocation::Offset(o) => write!(f, "0x{:08X}", o),
+            RecordLocation::Line(l) => write!(f, "line {}", l),
+        }
+    }
+}
+
+/// A record which could not be parsed in lenient mode, so was skipped
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseWarning {
+    /// Type of record. Example: DTC
+    pub record: &'static str,
+    /// Index of the record in its table
+    pub index: usize,
+    pub location: RecordLocation,
+    pub message: String,
+}
+
+impl std::fmt::Display for ParseWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} #{} at {}: {}", self.record, self.index, self.location, self.message)
+    }
+}
+
+thread_local! {
+    /// Set whilst [ParseLog::record] is reading a record, so a panic it catches is not printed
+    static QUIET_PANICS: std::cell::Cell<bool> = std::cell::Cell::new(false);
+}
+
+/// Wraps the panic hook (Once per process) so that panics raised whilst [QUIET_PANICS] is set
+/// on the panicking thread are not printed. Every other panic goes to the previous hook
+fn install_quiet_panic_hook() {
+    static INSTALL: std::sync::Once = std::sync::Once::new();
+    INSTALL.call_once(|| {
+        let prev = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            if !QUIET_PANICS.with(|q| q.get()) {
+                prev(info)
+            }
+        }));
+    });
+}
+
+/// Silences panics on this thread until dropped, including when dropped by unwinding
+struct QuietPanics(bool);
+
+impl QuietPanics {
+    fn new() -> Self {
+        install_quiet_panic_hook();
+        Self(QUIET_PANICS.with(|q| q.replace(true)))
+    }
+}
+
+impl Drop for QuietPanics {
+    fn drop(&mut self) {
+        QUIET_PANICS.with(|q| q.set(self.0))
+    }
+}
+
+/// Controls how record parse errors are handled, and collects the warnings in lenient mode
+#[derive(Debug, Default)]
+pub struct ParseLog {
+    lenient: bool,
+    pub warnings: Vec<ParseWarning>,
+}
+
+impl ParseLog {
+    /// Any record that fails to parse aborts the whole parse
+    pub fn strict() -> Self {
+        Self::default()
+    }
+
+    /// Records that fail to parse are skipped, and a warning is recorded for each
+    pub fn lenient() -> Self {
+        Self { lenient: true, warnings: Vec::new() }
+    }
+
+    /// Parses a single record from a table.
+    ///
+    /// The record readers treat corrupt data as fatal (They panic on a failed read), so in
+    /// lenient mode the panic is caught and turned into a warning, and None is returned.
+    /// The panic is not printed. A record whose offset is outside of the file is skipped without trying to read it.
+    ///
+    /// Catching the panic needs unwinding, so lenient mode does not work in a build with `panic = "abort"`
+    ///
+    /// # Params
+    /// * record - Type of record, for the warning
+    /// * index - Index of the record in its table
+    /// * offset - Offset of the record in the file
+    /// * file_size - Size of the file
+    /// * read - Reads the record
+    pub fn record<T, F: FnOnce(&mut Self) -> T>(&mut self, record: &'static str, index: usize, offset: i64, file_size: usize, read: F) -> Option<T> {
+        if !self.lenient {
+            return Some(read(self))
+        }
+        let location = RecordLocation::Offset(offset);
+        if offset < 0 || offset as usize >= file_size {
+            self.warnings.push(ParseWarning { record, index, location, message: "Offset is outside of the file".into() });
+            return None
+        }
+        let mut nested = ParseLog::lenient();
+        let res = {
+            let _quiet = QuietPanics::new();
+            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| read(&mut nested)))
+        };
+        // Keep warnings from records nested inside this one, even if it then failed itself
+        self.warnings.append(&mut nested.warnings);
+        match res {
+            Ok(r) => Some(r),
+            Err(e) => {
+                let message = e.downcast_ref::<&str>().map(|s| s.to_string())
+                    .or_else(|| e.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "Unknown error".into());
+                self.warnings.push(ParseWarning { record, index, location, message });
+                None
+            }
+        }
+    }
+
+    /// Handles the result of parsing a record which returns an error rather than panicking.
+    ///
+    /// # Returns
+    /// The error in strict mode. In lenient mode the error is recorded as a warning and None is returned
+    pub fn result<T>(&mut self, record: &'static str, index: usize, location: RecordLocation, res: std::result::Result<T, String>) -> std::result::Result<Option<T>, String> {
+        match res {
+            Ok(r) => Ok(Some(r)),
+            Err(message) if self.lenient => {
+                self.warnings.push(ParseWarning { record, index, location, message });
+                Ok(None)
+            },
+            Err(e) => Err(e)
+        }
+    }
+}
+
 #[derive(Default, Debug, Serialize, Deserialize, Clone)]
 pub struct CContainer{
     pub cff_header: CFFHeader,
     pub ctf_header: CTFHeader,
-    pub ecus: Vec<ECU>
+    pub ecus: Vec<ECU>,
+    /// Source file, split into modelled and unmodelled blocks for saving
+    #[serde(skip)]
+    pub blocks: BlockMap,
 }
 impl CContainer {
+    /// Checks the CRC32 stored in the last 4 bytes of the file against the CRC32 of
+    /// everything before it. This catches truncated or corrupt downloads before parsing starts
+    ///
+    /// # Returns
+    /// The file checksum if it is valid, or [RafError::ChecksumMismatch]
+    pub fn verify_checksum(reader: &mut Raf) -> Result<u32> {
+        let size = reader.size();
+        if size < 4 {
+            return Err(RafError::BufferOverflow);
+        }
+        let expected = reader.seek_read_at(size - 4, Raf::read_u32)?;
+        let actual = reader.crc32(0, size - 4)?;
+        if expected != actual {
+            return Err(RafError::ChecksumMismatch { expected, actual });
+        }
+        Ok(actual)
+    }
+
+    /// Verifies the file checksum (Unless `skip_checksum` is set), then parses the container
+    ///
+    /// # Params
+    /// * skip_checksum - Don't verify the checksum. Useful for debugging partial files
+    pub fn load(reader: &mut Raf, skip_checksum: bool) -> Result<Self> {
+        if !skip_checksum {
+            Self::verify_checksum(reader)?;
+        }
+        Ok(Self::new(reader))
+    }
+
+    /// Same as [load](fn@CContainer::load), but records (ECUs, services, variants, DTCs) which
+    /// fail to parse are skipped instead of aborting, so a file with a corrupt record can still
+    /// be viewed.
+    ///
+    /// # Returns
+    /// The container with every record that parsed, and a warning for each record that did not
+    pub fn load_lenient(reader: &mut Raf, skip_checksum: bool) -> Result<(Self, Vec<ParseWarning>)> {
+        if !skip_checksum {
+            Self::verify_checksum(reader)?;
+        }
+        let mut log = ParseLog::lenient();
+        let res = Self::parse(reader, &mut log);
+        Ok((res, log.warnings))
+    }
+
     pub fn new(reader: &mut Raf) -> Self {
-        reader.seek(0);
+        Self::parse(reader, &mut ParseLog::strict())
+    }
+
+    fn parse(reader: &mut Raf, log: &mut ParseLog) -> Self {
+        reader.seek(0).unwrap();
         let header = reader.read_bytes(STUB_HEADER_SIZE).expect("Error reading header");
         BaseHeader::read_header(header.as_slice());
         let cff_header_size = reader.read_i32().expect("Cannot read CFF Header size");
@@ -122,12 +321,41 @@ impl CContainer {
         let mut res = Self {
             cff_header,
             ctf_header,
-            ecus: Vec::new()
+            ecus: Vec::new(),
+            blocks: BlockMap::default(),
         };
-        res.read_ecu(reader);
+        res.read_ecu(reader, log);
+        // Done after reading the ECUs, so their copies of the container don't hold the whole file
+        res.blocks = BlockMap::from_source(reader.peek(0, reader.size()).unwrap_or(&[]), &res.known_ranges());
+        res
+    }
+
+    /// (offset, length) of every block in the file which the parser models
+    fn known_ranges(&self) -> Vec<(usize, usize)> {
+        let mut res = vec![(0, STUB_HEADER_SIZE + 4 + self.cff_header.cff_header_size as usize)];
+        for ecu in &self.ecus {
+            for blk in &[&ecu.ecuvarient_blk, &ecu.diagjob_blk, &ecu.dtc_blk, &ecu.env_blk, &ecu.vcdomain_blk,
+                         &ecu.presentations_blk, &ecu.info_blk, &ecu.unk_blk] {
+                if blk.block_offset >= 0 && blk.block_size > 0 {
+                    res.push((blk.block_offset as usize, blk.block_size as usize))
+                }
+            }
+        }
         res
     }
 
+    /// Replaces a block of the file with re-serialized data. See [BlockMap::replace]
+    pub fn replace_block(&mut self, offset: usize, data: Vec<u8>) -> std::result::Result<(), String> {
+        self.blocks.replace(offset, data)
+    }
+
+    /// Returns the file contents to save. Blocks that were not replaced, including any the
+    /// parser does not understand, are written exactly as they were loaded.
+    /// If a block was replaced, the file checksum is recomputed so the file still loads
+    pub fn save(&self) -> std::result::Result<Vec<u8>, String> {
+        self.blocks.to_bytes_with(&CBF_FORMAT)
+    }
+
     fn read_ctf(header: &CFFHeader, reader: &mut Raf) -> CTFHeader {
         if header.ctf_offset == 0 {
             panic!("No CTF Header");
@@ -147,17 +375,22 @@ impl CContainer {
         cff_header
     }
 
-    fn read_ecu(&mut self, reader: &mut Raf) {
+    fn read_ecu(&mut self, reader: &mut Raf, log: &mut ParseLog) {
         let cff_header = &self.cff_header;
         let lang = &self.ctf_header.ctf_langs[0];
         let ecu_table_offset = cff_header.ecuOffsets as i64 + cff_header.base_address;
+        let file_size = reader.size();
 
         for i in 0..cff_header.ecu_count as i64 {
             println!("Reading ECU {}", i);
-            reader.seek((ecu_table_offset + (i*4)) as usize);
+            reader.seek((ecu_table_offset + (i*4)) as usize).unwrap();
 
             let offset_to_ecu = reader.read_i32().expect("Error reading offset");
-            self.ecus.push(ECU::new(reader, lang, cff_header, ecu_table_offset + offset_to_ecu as i64, self.clone()));
+            let ecu_addr = ecu_table_offset + offset_to_ecu as i64;
+            let container = self.clone();
+            if let Some(ecu) = log.record("ECU", i as usize, ecu_addr, file_size, |log| ECU::new(reader, lang, cff_header, ecu_addr, container, log)) {
+                self.ecus.push(ecu);
+            }
         }
     }
 }
@@ -169,4 +402,61 @@ fn test_advance_bitflag() {
     assert_eq!(CReader::check_and_advance_bitflag(&mut bf), false);
     assert_eq!(bf, 1);
     assert_eq!(CReader::check_and_advance_bitflag(&mut bf), true);
-}
\ No newline at end of file
+}
+#[test]
+fn test_verify_checksum() {
+    let mut data: Vec<u8> = (0..64).collect();
+    let crc = Raf::from_bytes(&data, common::raf::RafByteOrder::LE).crc32(0, 64).unwrap();
+    data.extend_from_slice(&crc.to_le_bytes());
+    let mut reader = Raf::from_bytes(&data, common::raf::RafByteOrder::LE);
+    assert_eq!(CContainer::verify_checksum(&mut reader).unwrap(), crc);
+    assert_eq!(reader.pos, 0);
+
+    data[10] ^= 0x01;
+    let mut reader = Raf::from_bytes(&data, common::raf::RafByteOrder::LE);
+    match CContainer::verify_checksum(&mut reader) {
+        Err(RafError::ChecksumMismatch { expected, actual }) => {
+            assert_eq!(expected, crc);
+            assert_ne!(actual, crc);
+        },
+        x => panic!("Expected ChecksumMismatch, got {:?}", x)
+    }
+}
+
+#[test]
+fn test_save_recomputes_checksum() {
+    let mut data: Vec<u8> = (0..64).collect();
+    let crc = Raf::from_bytes(&data, common::raf::RafByteOrder::LE).crc32(0, 64).unwrap();
+    data.extend_from_slice(&crc.to_le_bytes());
+    let mut container = CContainer { blocks: BlockMap::from_source(&data, &[(16, 16)]), ..Default::default() };
+    assert_eq!(container.save().unwrap(), data);
+
+    container.replace_block(16, vec![0x55; 16]).unwrap();
+    let saved = container.save().unwrap();
+    assert_eq!(saved.len(), data.len());
+    assert_eq!(&saved[16..32], &[0x55; 16]);
+    let new_crc = CContainer::verify_checksum(&mut Raf::from_bytes(&saved, common::raf::RafByteOrder::LE)).unwrap();
+    assert_ne!(new_crc, crc);
+}
+
+#[test]
+fn test_lenient_record() {
+    let mut log = ParseLog::lenient();
+    let records: Vec<u32> = (0..4usize).filter_map(|i| log.record("Test", i, i as i64 * 0x10, 0x30, |_| {
+        if i == 1 {
+            panic!("Error reading i32");
+        }
+        i as u32
+    })).collect();
+    // Record 1 is corrupt, record 3 is past the end of the file
+    assert_eq!(records, vec![0, 2]);
+    assert_eq!(log.warnings.len(), 2);
+    assert_eq!(log.warnings[0], ParseWarning { record: "Test", index: 1, location: RecordLocation::Offset(0x10), message: "Error reading i32".into() });
+    assert_eq!(log.warnings[1].index, 3);
+    assert_eq!(log.warnings[0].to_string(), "Test #1 at 0x00000010: Error reading i32");
+    // Panics outside of a record are not silenced
+    assert!(!QUIET_PANICS.with(|q| q.get()));
+
+    log.record("Test", 4, -0x10, 0x30, |_| 0);
+    assert_eq!(log.warnings[2].to_string(), "Test #4 at -0x00000010: Offset is outside of the file");
+}
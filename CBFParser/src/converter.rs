@@ -7,5 +7,7 @@ use common::*;
 
 
 pub fn convert(container: &CContainer) {
+    // Records refer to strings in the first language, which they were parsed with
+    let _strings = container.ctf_header.ctf_langs.first().map(|lang| lang.pool().resolve_refs());
     println!("{}", serde_json::to_string_pretty(container).unwrap());
 }
\ No newline at end of file
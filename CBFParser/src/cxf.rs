@@ -3,6 +3,7 @@ use std::vec;
 
 use common::raf;
 use crate::caesar::CReader;
+use crate::string_pool::{StrRef, StringPool};
 use serde::*;
 use std::io::Read;
 use hyper::Client;
@@ -50,7 +51,7 @@ pub struct CFFHeader {
 
 impl CFFHeader {
     pub fn new(reader: &mut raf::Raf) -> Self {
-        reader.seek(STUB_HEADER_SIZE);
+        reader.seek(STUB_HEADER_SIZE).unwrap();
         let cff_header_size = reader.read_i32().expect("Error reading header size");
         let base_address = reader.pos as i64;
         let mut bitflag = reader.read_u16().expect("Error reading bitflag") as u64;
@@ -80,7 +81,7 @@ impl CFFHeader {
         if res.dsc_count > 0 {
             res.dsc_block_offset = (res.dsc_offset + data_buffer_offset_after_string) as i64;
             res.dsc_block_size = (res.dsc_entry_size * res.dsc_count) as i64;
-            reader.seek(res.dsc_block_offset as usize);
+            reader.seek(res.dsc_block_offset as usize).unwrap();
             res.dsc_pool = reader.read_bytes(res.dsc_block_size as usize).unwrap()
         }
         res
@@ -95,7 +96,7 @@ pub struct CTFLanguage {
     str_pool_size: i32,
     str_pool_offset: i32,
     str_count: i32,
-    strings: Vec<String>,
+    strings: StringPool,
 }
 
 // Translation url- Source is DE (German)
@@ -105,7 +106,7 @@ impl CTFLanguage {
     pub fn new(reader: &mut raf::Raf, base_addr: i64, header: &CFFHeader) -> Self {
         println!("Starting at {}", base_addr);
         let mut base_address = base_addr;
-        reader.seek(base_addr as usize);
+        reader.seek(base_addr as usize).unwrap();
         
         let mut language_entry_bitflag = reader.read_u16().expect("Failed to read language entry bitflag") as u64;
         let lang_name = CReader::read_bitflag_string(&mut language_entry_bitflag, reader, base_address);
@@ -125,26 +126,18 @@ impl CTFLanguage {
         }
     }
 
-    fn load_strings(reader: &mut raf::Raf, header: &CFFHeader, str_count: usize) -> Vec<String> {
+    fn load_strings(reader: &mut raf::Raf, header: &CFFHeader, str_count: usize) -> StringPool {
         let str_table_offset = (header.cff_header_size + 0x410 + 4) as usize;
-
-        (0..str_count)
-            .map(|i| {
-                reader.seek(str_table_offset + (i*4));
-                let offset = reader.read_i32().expect("Error reading String offset") as usize;
-                reader.seek(str_table_offset + offset);
-                CReader::read_string(reader)
-            })
-            .collect()
+        StringPool::read(reader, str_table_offset, str_count)
     }
 
     pub fn translate(&mut self) {
         let mut ids: Vec<usize> = vec![];
         let mut to_translate : Vec<String> = vec![];
-        for i in 0..self.str_count as usize {
-            if self.strings[i].split(' ').collect::<Vec<_>>().len() > 1 {
+        for (i, s) in self.strings.iter().enumerate() {
+            if s.split(' ').collect::<Vec<_>>().len() > 1 {
                 ids.push(i);
-                to_translate.push(self.strings[i].clone());
+                to_translate.push(s.to_string());
             }
         }
         //ids = Vec::from(&ids[0..10]);
@@ -179,7 +172,22 @@ impl CTFLanguage {
     }
 
     pub fn get_string(&self, idx: i32) -> Option<String> {
-        self.strings.get(idx as usize).map(|x| x.clone())
+        self.strings.index(idx).and_then(|r| self.strings.get(r)).map(|x| x.to_string())
+    }
+
+    /// Returns a reference to the string at an index read from a record, which is
+    /// resolved later with [pool](fn@CTFLanguage::pool)
+    pub fn string_ref(&self, idx: i32) -> Option<StrRef> {
+        self.strings.index(idx)
+    }
+
+    pub fn pool(&self) -> &StringPool {
+        &self.strings
+    }
+
+    #[cfg(test)]
+    pub fn from_strings(strings: Vec<String>) -> Self {
+        Self { lang_name: None, lang_index: 0, str_pool_size: 0, str_pool_offset: 0, str_count: strings.len() as i32, strings: StringPool::from_strings(strings) }
     }
 }
 
@@ -199,7 +207,7 @@ pub struct CTFHeader {
 impl CTFHeader {
     pub fn new(reader: &mut raf::Raf, base_addr: i64, header: &CFFHeader) -> Self {
         let base_addr = base_addr;
-        reader.seek(base_addr as usize);
+        reader.seek(base_addr as usize).unwrap();
 
         let mut bitflag = reader.read_u16().expect("Error reading CTF Bit flag") as u64;
         let ctf_unk1 = CReader::read_bitflag_i32(&mut bitflag, reader, 0);
@@ -214,7 +222,7 @@ impl CTFHeader {
 
         let ctf_langs = (0..ctf_lang_count as i64).map(|lang_entry| {
             let lang_table_offset_entry = ctf_lang_table_offset_relative + (lang_entry * 4);
-            reader.seek(lang_table_offset_entry as usize);
+            reader.seek(lang_table_offset_entry as usize).unwrap();
             let lang_table_address = reader.read_i32().unwrap() as i64 + ctf_lang_table_offset_relative as i64;
             CTFLanguage::new(reader, lang_table_address, &header)
         })
@@ -3,6 +3,7 @@
  use crate::cxf::*;
  use common::raf;
  use crate::structure::*;
+ use crate::string_pool::{StrRef, StringPool};
 use serde::*;
 const INT_SIZE_MAPPING: [u8; 7] = [0x00, 0x01, 0x04, 0x08, 0x10, 0x20, 0x40];
 
@@ -11,23 +12,23 @@ const INT_SIZE_MAPPING: [u8; 7] = [0x00, 0x01, 0x04, 0x08, 0x10, 0x20, 0x40];
 pub struct DTC {
     unk1: i32,
     code: String,
-    name: String,
-    desc: Vec<String>,
+    name: StrRef,
+    desc: Vec<StrRef>,
 }
 
 impl DTC {
     pub fn new(reader: &mut raf::Raf, lang: &CTFLanguage, base_addr: i64, pool_index: i32, parent_ecu: &ECU) -> Self {
-        reader.seek(base_addr as usize);
+        reader.seek(base_addr as usize).unwrap();
         let mut bitflags = reader.read_u16().expect("Unable to read DiagService bitflags") as u64;
         let unk1 = CReader::read_bitflag_i32(&mut bitflags, reader, -1);
         let name_idx = CReader::read_bitflag_i32(&mut bitflags, reader, -1);
-        let mut err_text: Vec<String> = Vec::new();
+        let mut err_text: Vec<StrRef> = Vec::new();
         loop {
             let desc_idx = CReader::read_bitflag_i32(&mut bitflags, reader, -1);
             if desc_idx == -1 {
                 break;
             } else {
-                err_text.push(lang.get_string(desc_idx).unwrap())
+                err_text.push(lang.string_ref(desc_idx).unwrap())
             }
         }
         // Actual name of error (P2000)
@@ -37,9 +38,24 @@ impl DTC {
             unk1,
             code,
             desc: err_text,
-            name: lang.get_string(name_idx).unwrap()
+            name: lang.string_ref(name_idx).unwrap()
         }
     }
+
+    /// Actual name of the error. Example: P2000
+    pub fn code(&self) -> &str {
+        &self.code
+    }
+
+    /// Resolves the name of the error from its language's string pool
+    pub fn name<'a>(&self, pool: &'a StringPool) -> Option<&'a str> {
+        pool.get(self.name)
+    }
+
+    /// Resolves each line of the description from its language's string pool
+    pub fn desc<'a>(&self, pool: &'a StringPool) -> Vec<&'a str> {
+        self.desc.iter().filter_map(|r| pool.get(*r)).collect()
+    }
 }
 
 #[derive(Debug)]
@@ -60,8 +76,10 @@ pub enum ServiceType {
 pub struct DiagService{
     pub name: Option<String>,
     pub prep: Vec<DiagPreparation>,
-    pub name_ctf: Option<String>,
-    pub desc_ctf: Option<String>,
+    /// Translated name, in the language's string pool
+    pub name_ctf: Option<StrRef>,
+    /// Translated description, in the language's string pool
+    pub desc_ctf: Option<StrRef>,
     pub is_executable: bool,
     pub client_access_level: u16,
     pub security_access_level: u16,
@@ -93,7 +111,7 @@ pub struct DiagService{
 
 impl DiagService {
     pub fn new(reader: &mut raf::Raf, lang: &CTFLanguage, base_addr: i64, pool_index: i32, parent_ecu: &ECU) -> Self {
-        reader.seek(base_addr as usize);
+        reader.seek(base_addr as usize).unwrap();
         let mut bitflags = reader.read_u32().expect("Unable to read DiagService bitflags") as u64;
         let bitflags_ext = reader.read_u32().expect("Unable to read DiagService bitflags") as u64;
 
@@ -161,15 +179,15 @@ impl DiagService {
         let req_bytes = match req_bytes_count {
             0 => Vec::new(),
             _ => {
-                reader.seek(base_addr as usize + req_bytes_offset as usize);
+                reader.seek(base_addr as usize + req_bytes_offset as usize).unwrap();
                 reader.read_bytes(req_bytes_count as usize).unwrap()
             }
         };
 
         let mut res = DiagService{
             name,
-            name_ctf: lang.get_string(name_ctf_idx),
-            desc_ctf: lang.get_string(desc_ctf_idx),
+            name_ctf: lang.string_ref(name_ctf_idx),
+            desc_ctf: lang.string_ref(desc_ctf_idx),
             prep: Vec::new(),
             is_executable: executable > 0,
             security_access_level,
@@ -203,7 +221,7 @@ impl DiagService {
         // Preperations steps
         res.prep = (0..u_prep_count as usize).map(|prep_index| {
             let pres_table_offset = base_addr + u_prep_offset as i64;
-            reader.seek(pres_table_offset as usize + (prep_index*10));
+            reader.seek(pres_table_offset as usize + (prep_index*10)).unwrap();
 
             let prep_entry_offset = reader.read_i32().unwrap() as i64;
             let prep_entry_bitpos = reader.read_i32().unwrap();
@@ -215,14 +233,14 @@ impl DiagService {
         // Output presentation formats
         let output_presentation_base_address = base_addr + res.w_outpres_offset as i64;
         (0..res.w_outpres_count).for_each(|pres_idx| {
-            reader.seek((output_presentation_base_address + (pres_idx * 8) as i64) as usize);
+            reader.seek((output_presentation_base_address + (pres_idx * 8) as i64) as usize).unwrap();
 
             let res_pres_count = reader.read_i32().unwrap();
             let res_pres_offset = reader.read_i32().unwrap();
 
             let result_presentations: Vec<DiagPreparation> = (0..res_pres_count).map(|pres_inner_idx|{
                 let pres_table_offset = output_presentation_base_address + res_pres_offset as i64;
-                reader.seek((pres_table_offset + (pres_idx * 10) as i64) as usize);
+                reader.seek((pres_table_offset + (pres_idx * 10) as i64) as usize).unwrap();
                 let prep_entry_offset = reader.read_i32().unwrap();
                 let prep_entry_bit_offset = reader.read_i32().unwrap();
                 let prep_entry_mode = reader.read_u16().unwrap();
@@ -235,7 +253,7 @@ impl DiagService {
         // COM Parameters
         let com_param_table_offset = base_addr + t_comparam_offset as i64;
         res.diag_com_parameters = (0..t_comparam_count).map(|cp_index| {
-            reader.seek((com_param_table_offset + (cp_index * 4) as i64) as usize);
+            reader.seek((com_param_table_offset + (cp_index * 4) as i64) as usize).unwrap();
             let res_cp_offset = reader.read_i32().unwrap();
             let cp_entry_base_addr = com_param_table_offset + res_cp_offset as i64;
             ComParameter::new(reader, cp_entry_base_addr, &parent_ecu.ecu_ifaces[0])
@@ -247,9 +265,9 @@ impl DiagService {
 
         let mut dtc_reader = raf::Raf::from_bytes(&dtc_pool, raf::RafByteOrder::LE);
         (0..diag_service_code_count).for_each(|dtc_index| {
-            reader.seek((dtc_table_base_address + (4*dtc_index) as i64) as usize);
+            reader.seek((dtc_table_base_address + (4*dtc_index) as i64) as usize).unwrap();
             let dtc_entry_base_address = reader.read_i32().unwrap() as i64 + dtc_table_base_address;
-            reader.seek(dtc_entry_base_address as usize);
+            reader.seek(dtc_entry_base_address as usize).unwrap();
 
             let mut dtc_entry_bit_flags = reader.read_u16().unwrap() as u64;
             let idk1 = CReader::read_bitflag_u8(&mut dtc_entry_bit_flags, reader, 0);
@@ -257,12 +275,12 @@ impl DiagService {
             let dtc_pool_offset = CReader::read_bitflag_i32(&mut dtc_entry_bit_flags, reader, 0);
             let dtc_qualifier = CReader::read_bitflag_string(&mut dtc_entry_bit_flags, reader, dtc_entry_base_address);
 
-            dtc_reader.seek(dtc_pool_offset as usize * 8);
+            dtc_reader.seek(dtc_pool_offset as usize * 8).unwrap();
 
             let dtc_record_offset = dtc_reader.read_i32().unwrap() as i64 + parent_ecu.parent_container.cff_header.dsc_block_offset;
             let dtc_record_size = dtc_reader.read_i32().unwrap();
 
-            reader.seek(dtc_record_offset as usize);
+            reader.seek(dtc_record_offset as usize).unwrap();
 
             println!("{:?}", dtc_qualifier);//, reader.read_bytes(dtc_record_size as usize).unwrap());
         });
@@ -319,7 +337,7 @@ pub struct DiagPreparation{
     size_in_bits: i32,
     pres_pool_idx: i32,
     bit_pos: i32,
-    name_ctf: Option<String>,
+    name_ctf: Option<StrRef>,
     unk1: u8,
     unk2: u8,
     bit_offset: i32,
@@ -328,10 +346,15 @@ pub struct DiagPreparation{
 
 
 impl DiagPreparation {
+    /// Resolves the translated name of the parameter from its language's string pool
+    pub fn name_ctf<'a>(&self, pool: &'a StringPool) -> Option<&'a str> {
+        self.name_ctf.and_then(|r| pool.get(r))
+    }
+
     // parent_diag_service: &'a mut DiagService
     pub fn new(reader: &mut raf::Raf, lang: &CTFLanguage, base_addr: i64, bit_pos: i32, mode_cfg: u16, parent_ecu: &ECU, parent_diag_service: &DiagService) -> Self {
             
-        reader.seek(base_addr as usize);
+        reader.seek(base_addr as usize).unwrap();
             let mut bitflags = reader.read_u32().expect("unable to read bitflags!") as u64;
 
             let mut diagPrep = DiagPreparation::default();
@@ -340,6 +363,7 @@ impl DiagPreparation {
 
             diagPrep.name = CReader::read_bitflag_string(&mut bitflags, reader, base_addr);
             let name_ctf_idx = CReader::read_bitflag_i32(&mut  bitflags, reader, -1);
+            diagPrep.name_ctf = lang.string_ref(name_ctf_idx);
             diagPrep.unk1 = CReader::read_bitflag_u8(&mut  bitflags, reader, 0);
             diagPrep.unk2 = CReader::read_bitflag_u8(&mut  bitflags, reader, 0);
             diagPrep.alternative_bit_width = CReader::read_bitflag_i32(&mut  bitflags, reader, 0);
@@ -401,12 +425,12 @@ impl DiagPreparation {
 
                     let mut pool_reader = raf::Raf::from_bytes(&pool_bytes, raf::RafByteOrder::LE);
 
-                    pool_reader.seek(info_block.entry_size as usize * self.info_pool_idx as usize);
+                    pool_reader.seek(info_block.entry_size as usize * self.info_pool_idx as usize).unwrap();
 
                     let presentation_struct_offset = pool_reader.read_i32().unwrap();
                     let presentation_struct_size = pool_reader.read_i32().unwrap();
 
-                    reader.seek((presentation_struct_offset + (info_block.block_offset)) as usize);
+                    reader.seek((presentation_struct_offset + (info_block.block_offset)) as usize).unwrap();
 
                     let presentation_struct = reader.read_bytes(presentation_struct_size as usize).expect("Error reading presentation structure!");
                 
@@ -429,12 +453,12 @@ impl DiagPreparation {
 
                     let mut pool_reader = raf::Raf::from_bytes(&pool_bytes, raf::RafByteOrder::LE);
 
-                    pool_reader.seek(pres_block.entry_size as usize * self.pres_pool_idx as usize);
+                    pool_reader.seek(pres_block.entry_size as usize * self.pres_pool_idx as usize).unwrap();
 
                     let presentation_struct_offset = pool_reader.read_i32().unwrap();
                     let presentation_struct_size = pool_reader.read_i32().unwrap();
 
-                    reader.seek((presentation_struct_offset + (pres_block.block_offset)) as usize);
+                    reader.seek((presentation_struct_offset + (pres_block.block_offset)) as usize).unwrap();
                     let presentation_struct = reader.read_bytes(presentation_struct_size as usize).expect("Error reading presentation structure!");
                 
                     let presentation_mode = read_cbf_with_offset(0x1C, &StructureName::PRESENTATION_STRUCTURE, &presentation_struct); // Type
@@ -1,8 +1,10 @@
 use common::raf;
-use crate::caesar::{CReader, CContainer};
+use crate::caesar::{CReader, CContainer, ParseLog};
 use crate::cxf::*;
 use crate::diag::*;
+use crate::string_pool::StrRef;
 use serde::*;
+use std::collections::HashMap;
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct block {
@@ -194,7 +196,7 @@ impl ComParameter {
     /// * parent_iface - Parent ECU Interface
     pub fn new(reader: &mut raf::Raf, base_addr: i64, parent_iface: &ECUInterface) -> Self {
 
-        reader.seek(base_addr as usize);
+        reader.seek(base_addr as usize).unwrap();
         let mut bitflags = reader.read_u16().expect("Error reading bitflags") as u64;
 
         let param_index = CReader::read_bitflag_i16(&mut bitflags, reader, 0) as i32;
@@ -235,9 +237,9 @@ pub struct ECUInterfaceSubType {
     /// Name of interface from CBF
     pub name: String,
     /// Name of interface from String table
-    pub name_ctf: Option<String>,
+    pub name_ctf: Option<StrRef>,
     /// Description of interface from String table
-    pub desc_ctf: Option<String>,
+    pub desc_ctf: Option<StrRef>,
     /// Base address within CBF File
     pub base_addr: i64,
     /// Sub interface Index
@@ -262,7 +264,7 @@ impl ECUInterfaceSubType {
     /// * base_addr - Base address within the CBF File to read data from
     /// * index - Index of the ECU Sub Interface
     pub fn new(reader: &mut raf::Raf, lang: &CTFLanguage, base_addr: i64, index: i32) -> Self {
-        reader.seek(base_addr as usize);
+        reader.seek(base_addr as usize).unwrap();
 
         let mut bitflags = reader.read_u32().expect("Error reading iface bitflag") as u64;
 
@@ -270,8 +272,8 @@ impl ECUInterfaceSubType {
             index: index,
             base_addr: base_addr,
             name: CReader::read_bitflag_string(&mut bitflags, reader, base_addr).unwrap(),
-            name_ctf: lang.get_string(CReader::read_bitflag_i32(&mut bitflags, reader, -1)),
-            desc_ctf: lang.get_string(CReader::read_bitflag_i32(&mut bitflags, reader, -1)),
+            name_ctf: lang.string_ref(CReader::read_bitflag_i32(&mut bitflags, reader, -1)),
+            desc_ctf: lang.string_ref(CReader::read_bitflag_i32(&mut bitflags, reader, -1)),
 
             unk3: CReader::read_bitflag_i16(&mut bitflags, reader, 0) as i32,
             unk4: CReader::read_bitflag_i16(&mut bitflags, reader, 0) as i32,
@@ -329,7 +331,7 @@ pub struct ECUVarientPattern {
 
 impl ECUVarientPattern {
     pub fn new(reader: &mut raf::Raf, base_addr: i64) -> Self {
-        reader.seek(base_addr as usize);
+        reader.seek(base_addr as usize).unwrap();
         let mut bitflags = reader.read_u32().unwrap() as u64;
         let mut ret: ECUVarientPattern = ECUVarientPattern::default();
 
@@ -362,6 +364,31 @@ impl ECUVarientPattern {
     }
 }
 
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+/// A single condition used to identify an ECU varient. The data read from
+/// `did` must contain `expected` starting at `offset`
+pub struct VariantPattern {
+    pub did: u16,
+    pub offset: usize,
+    pub expected: Vec<u8>,
+}
+
+impl VariantPattern {
+    pub fn matches(&self, data: &[u8]) -> bool {
+        data.len() >= self.offset + self.expected.len() &&
+            data[self.offset..self.offset + self.expected.len()] == self.expected[..]
+    }
+}
+
+/// Something that can read DIDs from a live ECU, for varient matching
+pub trait DidReader {
+    /// Reads a DID from the ECU.
+    ///
+    /// # Returns
+    /// The DID data, or None if the ECU does not support the DID
+    fn read_did(&mut self, did: u16) -> std::result::Result<Option<Vec<u8>>, String>;
+}
+
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 /// An ECU Varient is a HW or SW version of the same ECU.
 /// Each varient of the ECU can have its own functions list, or DTC codes
@@ -370,8 +397,8 @@ impl ECUVarientPattern {
 pub struct ECUVarient {
     /// Name of ECU Varient
     pub name: Option<String>,
-    pub name_ctf: Option<String>,
-    pub desc_ctf: Option<String>,
+    pub name_ctf: Option<StrRef>,
+    pub desc_ctf: Option<StrRef>,
     pub unk_str1: Option<String>,
     pub unk_str2: Option<String>,
     pub unk1: i32,
@@ -400,13 +427,20 @@ pub struct ECUVarient {
     pub diag_services_pool_offsets: Vec<i32>, 
     pub vc_domains: Vec<VCDomain>,
     pub varient_patterns: Vec<ECUVarientPattern>,
+    /// Sets of DID conditions used to identify this varient.
+    /// The varient matches if every condition in any one of the sets matches.
+    ///
+    /// These are not filled in from [varient_patterns](ECUVarient::varient_patterns). Which DID, offset
+    /// and bytes a pattern record compares is not known (Its known fields are the vendor, diag version,
+    /// supplier and varient ID, none of which name a DID), so the rules have to be supplied by the caller
+    pub matching_rules: Vec<Vec<VariantPattern>>,
     pub diag_services: Vec<DiagService>,
     pub base_addr: i64
 }
 
 impl ECUVarient {
     pub fn new(reader: &mut raf::Raf, lang: &CTFLanguage, parent_ecu: &mut ECU, base_addr: i64, block_size: i32) -> Self {
-        reader.seek(base_addr as usize);
+        reader.seek(base_addr as usize).unwrap();
 
         let varient_bytes = reader.read_bytes(block_size as usize).expect("Error reading ECU Varient bytes");
 
@@ -417,8 +451,8 @@ impl ECUVarient {
         let skip = varreader.read_i32().unwrap();
         ret.base_addr = base_addr;
         ret.name = CReader::read_bitflag_string(&mut bitflags, &mut varreader, 0);
-        ret.name_ctf = lang.get_string(CReader::read_bitflag_i32(&mut bitflags, &mut varreader, -1));
-        ret.desc_ctf = lang.get_string(CReader::read_bitflag_i32(&mut bitflags, &mut varreader, -1));
+        ret.name_ctf = lang.string_ref(CReader::read_bitflag_i32(&mut bitflags, &mut varreader, -1));
+        ret.desc_ctf = lang.string_ref(CReader::read_bitflag_i32(&mut bitflags, &mut varreader, -1));
         ret.unk_str1 = CReader::read_bitflag_string(&mut bitflags, &mut varreader, 0);
         ret.unk_str2 = CReader::read_bitflag_string(&mut bitflags, &mut varreader, 0);
 
@@ -447,11 +481,11 @@ impl ECUVarient {
         ret.negative_resp_name = CReader::read_bitflag_string(&mut bitflags, &mut varreader, 0).unwrap_or(String::new());
 
         ret.unk_byte = CReader::read_bitflag_i8(&mut bitflags, &mut varreader, 0) as i32;
-        varreader.seek(ret.VCDomainsOffset as usize);
+        varreader.seek(ret.VCDomainsOffset as usize).unwrap();
 
         ret.vc_domain_pool_offsets = (0..ret.VCDomainsCount).map(|i| {varreader.read_i32().unwrap()}).collect();
 
-        varreader.seek(ret.diag_services_offset as usize);
+        varreader.seek(ret.diag_services_offset as usize).unwrap();
         ret.diag_services_pool_offsets = (0..ret.VCDomainsCount).map(|i| {varreader.read_i32().unwrap()}).collect();
 
 
@@ -477,10 +511,10 @@ impl ECUVarient {
 
     fn create_var_patterns(&mut self, reader: &mut raf::Raf) {
         let table_offset = self.base_addr + self.matching_pattern_offset as i64;
-        reader.seek(table_offset as usize);
+        reader.seek(table_offset as usize).unwrap();
         println!("CREATE VAR PATTERNS");
         self.varient_patterns = (0..self.matching_pattern_count).map(|pattern_index| {
-            reader.seek((table_offset + (pattern_index*4) as i64) as usize);
+            reader.seek((table_offset + (pattern_index*4) as i64) as usize).unwrap();
 
             let pattern_offset = reader.read_i32().unwrap();
             let pattern_address = pattern_offset as i64 + table_offset;
@@ -491,7 +525,7 @@ impl ECUVarient {
 
     fn create_com_params(&mut self, reader: &mut raf::Raf, parent_ecu: &mut ECU) {
         let com_param_base_address = self.base_addr + self.com_param_offset as i64;
-        reader.seek(com_param_base_address as usize);
+        reader.seek(com_param_base_address as usize).unwrap();
         let com_param_offsets: Vec<i64> = (0..self.com_param_count).map(|_| reader.read_i32().unwrap() as i64 + com_param_base_address).collect();
 
         let mut i  = 0;
@@ -527,7 +561,7 @@ pub struct ECUInterface {
 
 impl ECUInterface {
     pub fn new(reader: &mut raf::Raf, base_addr: i64) -> Self {
-        reader.seek(base_addr as usize);
+        reader.seek(base_addr as usize).unwrap();
 
         let mut iface_bf = reader.read_i32().expect("Error reading ECU Bitflag") as u64;
 
@@ -545,10 +579,10 @@ impl ECUInterface {
         let com_param_foffset = ret.com_param_list_offset as i64 + base_addr;
 
         ret.com_parameters = (0..ret.com_param_count).map(|str_index|{
-            reader.seek((com_param_foffset + (str_index*4) as i64) as usize);
+            reader.seek((com_param_foffset + (str_index*4) as i64) as usize).unwrap();
 
             let iface_read_ptr = reader.read_i32().unwrap() as i64 + com_param_foffset;
-            reader.seek(iface_read_ptr as usize);
+            reader.seek(iface_read_ptr as usize).unwrap();
             CReader::read_string(reader)
         }).collect();
         ret
@@ -558,8 +592,8 @@ impl ECUInterface {
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct ECU {
     pub name: String,
-    pub ecuname_ctf: Option<String>,
-    pub ecudesc_ctf: Option<String>,
+    pub ecuname_ctf: Option<StrRef>,
+    pub ecudesc_ctf: Option<StrRef>,
     pub xml_version: String,
     pub interface_block_count: i32,
     pub interface_table_offset: i32,
@@ -601,7 +635,7 @@ pub struct ECU {
 }
 
 impl ECU {
-    pub fn new(reader: &mut raf::Raf, lang: &CTFLanguage, header: &CFFHeader, base_addr: i64, pcontainer: CContainer) -> Self {
+    pub fn new(reader: &mut raf::Raf, lang: &CTFLanguage, header: &CFFHeader, base_addr: i64, pcontainer: CContainer, log: &mut ParseLog) -> Self {
 
         let mut ecu_bitflags = reader.read_u32().expect("Error reading ECU Bitflag") as u64;
         let ecu_bitflags_ext = reader.read_i16().expect("Error reading ECU Ext Bitflag") as u64;
@@ -615,8 +649,8 @@ impl ECU {
         let mut ret: ECU = ECU::default();
 
         ret.name = CReader::read_bitflag_string(&mut ecu_bitflags, reader, base_addr).expect("ECU Has no name!?");
-        let ecuname_ctf_idx = CReader::read_bitflag_i32(&mut ecu_bitflags, reader, -1);
-        let ecudesc_ctf_idx = CReader::read_bitflag_i32(&mut ecu_bitflags, reader, -1);
+        ret.ecuname_ctf = lang.string_ref(CReader::read_bitflag_i32(&mut ecu_bitflags, reader, -1));
+        ret.ecudesc_ctf = lang.string_ref(CReader::read_bitflag_i32(&mut ecu_bitflags, reader, -1));
         ret.xml_version = CReader::read_bitflag_string(&mut ecu_bitflags, reader, base_addr).unwrap();
         ret.interface_block_count = CReader::read_bitflag_i32(&mut ecu_bitflags, reader, 0);
         ret.interface_table_offset = CReader::read_bitflag_i32(&mut ecu_bitflags, reader, 0);
@@ -650,7 +684,7 @@ impl ECU {
         let iface_table_addr = base_addr + ret.interface_table_offset as i64;
 
         ret.ecu_ifaces = (0..ret.interface_block_count).map(|iface_buff_index| {
-            reader.seek((iface_table_addr + (iface_buff_index*4) as i64) as usize);
+            reader.seek((iface_table_addr + (iface_buff_index*4) as i64) as usize).unwrap();
             let iface_blockoffset = reader.read_i32().unwrap();
             let ecu_iface_baseaddr = iface_table_addr + iface_blockoffset as i64;
             ECUInterface::new(reader, ecu_iface_baseaddr)
@@ -659,7 +693,7 @@ impl ECU {
 
         let ct_table_addr = (base_addr + ret.sub_interface_offset as i64) as usize;
         ret.ecu_ifaces_subtype = (0..ret.sub_interface_count as usize).map(|buf_index| {
-            reader.seek(ct_table_addr + (buf_index*4));
+            reader.seek(ct_table_addr + (buf_index*4)).unwrap();
             let actual_blk_offset = reader.read_i32().unwrap();
             let ct_base_addr = ct_table_addr as i64 + actual_blk_offset as i64;
 
@@ -672,60 +706,213 @@ impl ECU {
         ret.dtcs = Vec::new();
         ret.parent_container = pcontainer;
 
-        ret.create_diag_pool(reader, lang);
-        ret.create_ecu_varients(reader, lang);
-        ret.create_dtcs(reader, lang);
+        ret.create_diag_pool(reader, lang, log);
+        ret.create_ecu_varients(reader, lang, log);
+        ret.create_dtcs(reader, lang, log);
         ret
     }
 
-    pub fn create_dtcs(&mut self, reader: &mut raf::Raf, lang: &CTFLanguage) {
+    pub fn create_dtcs(&mut self, reader: &mut raf::Raf, lang: &CTFLanguage, log: &mut ParseLog) {
         // Create diag services
         let pool = ECU::read_ecu_pool(reader, &self.dtc_blk);
         eprintln!("DTC pool: {:?}", &self.dtc_blk);
         let mut dreader = raf::Raf::from_bytes(&pool, raf::RafByteOrder::LE);
+        let file_size = reader.size();
         (0..self.dtc_blk.entry_count as usize).for_each(|dtc_index| {
             let offset = dreader.read_i32().unwrap();
             let diag_base_addr = offset + self.dtc_blk.block_offset;
             let unk = dreader.read_bytes((&self.dtc_blk.entry_size-4) as usize).unwrap();
             eprintln!("UNK: {:02X?}", unk);
-            self.dtcs.push(DTC::new(reader, lang, diag_base_addr as i64, dtc_index as i32, &self));
+            let ecu = &*self;
+            if let Some(dtc) = log.record("DTC", dtc_index, diag_base_addr as i64, file_size, |_| DTC::new(reader, lang, diag_base_addr as i64, dtc_index as i32, ecu)) {
+                self.dtcs.push(dtc);
+            }
         })
     }
 
-    pub fn create_diag_pool(&mut self, reader: &mut raf::Raf, lang: &CTFLanguage) {
+    pub fn create_diag_pool(&mut self, reader: &mut raf::Raf, lang: &CTFLanguage, log: &mut ParseLog) {
         // Create diag services
         let pool = ECU::read_ecu_pool(reader, &self.diagjob_blk);
         let mut dreader = raf::Raf::from_bytes(&pool, raf::RafByteOrder::LE);
-        self.diag_services = (0..self.diagjob_blk.entry_count as usize).map(|diag_job_index| {
+        let file_size = reader.size();
+        let ecu = &*self;
+        self.diag_services = (0..self.diagjob_blk.entry_count as usize).filter_map(|diag_job_index| {
             let offset = dreader.read_i32().unwrap();
             let size = dreader.read_i32().unwrap();
             let crc = dreader.read_i32().unwrap();
             let config = dreader.read_i16().unwrap();
-            let diag_base_addr = offset + self.diagjob_blk.block_offset;
-            DiagService::new(reader, lang, diag_base_addr as i64, diag_job_index as i32, &self)
+            let diag_base_addr = offset + ecu.diagjob_blk.block_offset;
+            log.record("Service", diag_job_index, diag_base_addr as i64, file_size, |_| DiagService::new(reader, lang, diag_base_addr as i64, diag_job_index as i32, ecu))
         }).collect();
     }
 
-    pub fn create_ecu_varients(&mut self, reader: &mut raf::Raf, lang: &CTFLanguage) {
+    pub fn create_ecu_varients(&mut self, reader: &mut raf::Raf, lang: &CTFLanguage, log: &mut ParseLog) {
         let var_block = &self.ecuvarient_blk;
         let pool = ECU::read_ecu_pool(reader, &self.ecuvarient_blk);
         let mut vreader = raf::Raf::from_bytes(&pool, raf::RafByteOrder::LE);
         let mut copy = self.clone();
-        let res: Vec<ECUVarient> = (0..var_block.entry_count as usize).map(|index|{
-            vreader.seek(index * var_block.entry_size as usize);
+        let file_size = reader.size();
+        let res: Vec<ECUVarient> = (0..var_block.entry_count as usize).filter_map(|index|{
+            vreader.seek(index * var_block.entry_size as usize).unwrap();
             let entry_offset = vreader.read_i32().unwrap();
             let entry_size = vreader.read_i32().unwrap();
             let pool_entry_attrib = vreader.read_u16().unwrap();
             let varient_block_address = entry_offset + var_block.block_offset;
-            ECUVarient::new(reader, lang,&mut copy, varient_block_address as i64, entry_size)
+            log.record("Variant", index, varient_block_address as i64, file_size, |_| ECUVarient::new(reader, lang, &mut copy, varient_block_address as i64, entry_size))
         }).collect();
         copy.ecu_varients = res;
         *self = copy;
 
     }
 
+    /// Reads DIDs from the connected ECU in order to work out which varient it is.
+    ///
+    /// Each DID is only read once, even if multiple varients check it.
+    ///
+    /// # Returns
+    /// The first varient whose matching rules match the ECU, or None if no varient matched
+    pub fn match_variant<D: DidReader>(&self, client: &mut D) -> std::result::Result<Option<&ECUVarient>, String> {
+        let mut cache: HashMap<u16, Option<Vec<u8>>> = HashMap::new();
+        for varient in &self.ecu_varients {
+            for rules in &varient.matching_rules {
+                let mut all_match = true;
+                for rule in rules {
+                    if !cache.contains_key(&rule.did) {
+                        cache.insert(rule.did, client.read_did(rule.did)?);
+                    }
+                    match &cache[&rule.did] {
+                        Some(data) if rule.matches(data) => {},
+                        _ => { all_match = false; break }
+                    }
+                }
+                if all_match {
+                    return Ok(Some(varient))
+                }
+            }
+        }
+        Ok(None)
+    }
+
     pub fn read_ecu_pool(reader: &mut raf::Raf, blk: &block) -> Vec<u8> {
-        reader.seek(blk.block_offset as usize);
+        reader.seek(blk.block_offset as usize).unwrap();
         reader.read_bytes(blk.entry_count as usize * blk.entry_size as usize).expect("Error reading block")
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+struct MockDidReader {
+    dids: HashMap<u16, Vec<u8>>,
+    reads: usize,
+}
+
+#[cfg(test)]
+impl DidReader for MockDidReader {
+    fn read_did(&mut self, did: u16) -> std::result::Result<Option<Vec<u8>>, String> {
+        self.reads += 1;
+        Ok(self.dids.get(&did).cloned())
+    }
+}
+
+#[test]
+fn test_match_variant() {
+    let mut ecu = ECU::default();
+    let mut add_varient = |name: &str, rules: Vec<Vec<VariantPattern>>| {
+        let mut v = ECUVarient::default();
+        v.name = Some(name.into());
+        v.matching_rules = rules;
+        ecu.ecu_varients.push(v);
+    };
+    add_varient("VAR_A", vec![vec![VariantPattern { did: 0xF100, offset: 0, expected: vec![0x00, 0x02, 0x14] }]]);
+    // Requires 2 DIDs to both match
+    add_varient("VAR_B", vec![vec![
+        VariantPattern { did: 0xF100, offset: 0, expected: vec![0x00, 0x02, 0x15] },
+        VariantPattern { did: 0xF150, offset: 1, expected: vec![0x41] },
+    ]]);
+    add_varient("VAR_C", vec![vec![VariantPattern { did: 0xF100, offset: 0, expected: vec![0x00, 0x02, 0x15] }]]);
+
+    let mut mock = MockDidReader { dids: HashMap::new(), reads: 0 };
+    mock.dids.insert(0xF100, vec![0x00, 0x02, 0x15, 0xFF]);
+    mock.dids.insert(0xF150, vec![0x00, 0x41]);
+    assert_eq!(ecu.match_variant(&mut mock).unwrap().unwrap().name.as_deref(), Some("VAR_B"));
+    assert_eq!(mock.reads, 2);
+
+    // F150 not supported, so VAR_B does not match, and VAR_C is used instead
+    mock.dids.remove(&0xF150);
+    assert_eq!(ecu.match_variant(&mut mock).unwrap().unwrap().name.as_deref(), Some("VAR_C"));
+
+    mock.dids.insert(0xF100, vec![0x00, 0x09, 0x99]);
+    assert!(ecu.match_variant(&mut mock).unwrap().is_none());
+}
+
+#[test]
+fn test_parse_varient_patterns() {
+    // Table with one pattern offset, then the pattern: vendor name, diag version, supplier,
+    // varient ID and pattern type present, followed by the vendor name string
+    let bitflags: u32 = 1 << 5 | 1 << 6 | 1 << 10 | 1 << 23 | 1 << 24;
+    let mut data = 4i32.to_le_bytes().to_vec();
+    data.extend_from_slice(&bitflags.to_le_bytes());
+    data.extend_from_slice(&19i32.to_le_bytes());
+    data.extend_from_slice(&0x0102u16.to_le_bytes());
+    data.push(0x03);
+    data.extend_from_slice(&0x000215i32.to_le_bytes());
+    data.extend_from_slice(&2i32.to_le_bytes());
+    data.extend_from_slice(b"Bosch\0");
+    let mut reader = raf::Raf::from_bytes(&data, raf::RafByteOrder::LE);
+
+    let mut ecu = ECU::default();
+    let mut varient = ECUVarient::default();
+    varient.matching_pattern_count = 1;
+    varient.create_var_patterns(&mut reader);
+    let pattern = &varient.varient_patterns[0];
+    assert_eq!(pattern.vendor_name.as_deref(), Some("Bosch"));
+    assert_eq!(pattern.diag_version, 0x0102);
+    assert_eq!(pattern.supplier_code, 0x03);
+    assert_eq!(pattern.variant_id, 0x000215);
+    assert_eq!(pattern.pattern_type, 2);
+
+    // No DID rules are made up from the pattern, so nothing is read from the ECU
+    assert!(varient.matching_rules.is_empty());
+    ecu.ecu_varients.push(varient);
+    let mut mock = MockDidReader { dids: HashMap::new(), reads: 0 };
+    mock.dids.insert(0xF100, vec![0x00, 0x02, 0x15]);
+    assert!(ecu.match_variant(&mut mock).unwrap().is_none());
+    assert_eq!(mock.reads, 0);
+}
+
+#[test]
+fn test_lenient_dtc_table() {
+    // Table of 3 DTCs (Offset + 8 unknown bytes each), followed by the DTC records
+    let mut data = Vec::new();
+    let record = |name_idx: i32, code: &str| {
+        let mut r = vec![0x07, 0x00]; // unk1, name and 1 description present
+        r.extend_from_slice(&0i32.to_le_bytes());
+        r.extend_from_slice(&name_idx.to_le_bytes());
+        r.extend_from_slice(&1i32.to_le_bytes());
+        r.extend_from_slice(code.as_bytes());
+        r.push(0x00);
+        r
+    };
+    // The 2nd record refers to a string which does not exist
+    let records = vec![record(0, "P2000"), record(99, "P2001"), record(0, "P2002")];
+    let mut offset = 36;
+    for r in &records {
+        data.extend_from_slice(&(offset as i32).to_le_bytes());
+        data.extend_from_slice(&[0x00; 8]);
+        offset += r.len();
+    }
+    records.iter().for_each(|r| data.extend_from_slice(r));
+
+    let lang = CTFLanguage::from_strings(vec!["Name".into(), "Desc".into()]);
+    let mut ecu = ECU::default();
+    ecu.dtc_blk = block { block_offset: 0, entry_count: 3, entry_size: 12, block_size: 36 };
+    let mut reader = raf::Raf::from_bytes(&data, raf::RafByteOrder::LE);
+    let mut log = ParseLog::lenient();
+    ecu.create_dtcs(&mut reader, &lang, &mut log);
+    assert_eq!(ecu.dtcs.len(), 2);
+    assert_eq!(ecu.dtcs[1].code(), "P2002");
+    assert_eq!(ecu.dtcs[1].name(lang.pool()), Some("Name"));
+    assert_eq!(ecu.dtcs[1].desc(lang.pool()), vec!["Desc"]);
+    assert_eq!(log.warnings.len(), 1);
+    assert_eq!((log.warnings[0].record, log.warnings[0].index), ("DTC", 1));
+    assert_eq!(log.warnings[0].location, crate::caesar::RecordLocation::Offset(36 + records[0].len() as i64));
+}
@@ -0,0 +1,120 @@
+use common::layout::{FieldType, Layout};
+use common::raf::RafByteOrder;
+use crate::cxf::{FILE_HEADER, STUB_HEADER_SIZE};
+
+const LE: RafByteOrder = RafByteOrder::LE;
+
+/// Adds fields which are each only present if their bit of `flags` is set, in bit order
+fn flagged_fields(mut layout: Layout, flags: &str, fields: &[(&str, FieldType)]) -> Layout {
+    for (bit, (name, ty)) in fields.iter().enumerate() {
+        layout = layout.field_if(name, *ty, LE, flags, bit as u32);
+    }
+    layout
+}
+
+/// Layout of the start of a CBF file, up to the end of the CFF header. Matches `CFFHeader::new`.
+/// String fields hold the offset of the string from `cff_bitflags`
+pub fn cbf_header_layout() -> Layout {
+    let layout = Layout::new()
+        .field_at("translator_version", 0, FieldType::String(FILE_HEADER.len()), LE)
+        .field_at("header_id", 0x401, FieldType::U8, LE)
+        .field_at("cff_header_size", STUB_HEADER_SIZE, FieldType::I32, LE)
+        .field("cff_bitflags", FieldType::U16, LE);
+    flagged_fields(layout, "cff_bitflags", &[
+        ("caesar_version", FieldType::I32),
+        ("gpd_version", FieldType::I32),
+        ("ecu_count", FieldType::I32),
+        ("ecu_offsets", FieldType::I32),
+        ("ctf_offset", FieldType::I32),
+        ("size_of_str_pool", FieldType::I32),
+        ("dsc_offset", FieldType::I32),
+        ("dsc_count", FieldType::I32),
+        ("dsc_entry_size", FieldType::I32),
+        ("cbf_version_string", FieldType::I32),
+        ("gpd_version_string", FieldType::I32),
+        ("diogenes_xml_string", FieldType::I32),
+    ])
+}
+
+/// Layout of a diagnostic service record. Matches `DiagService::new`.
+/// String fields hold the offset of the string from the start of the record
+pub fn diag_service_layout() -> Layout {
+    let layout = Layout::new()
+        .field("bitflags", FieldType::U32, LE)
+        .field("bitflags_ext", FieldType::U32, LE);
+    let layout = flagged_fields(layout, "bitflags", &[
+        ("name", FieldType::I32),
+        ("name_ctf", FieldType::I32),
+        ("desc_ctf", FieldType::I32),
+        ("dataclass_servicetype", FieldType::U16),
+        ("is_executable", FieldType::U16),
+        ("client_access_level", FieldType::U16),
+        ("security_access_level", FieldType::U16),
+        ("t_comparam_count", FieldType::I32),
+        ("t_comparam_offset", FieldType::I32),
+        ("q_count", FieldType::I32),
+        ("q_offset", FieldType::I32),
+        ("r_count", FieldType::I32),
+        ("r_offset", FieldType::I32),
+        ("input_ref_name", FieldType::I32),
+        ("u_prep_count", FieldType::I32),
+        ("u_prep_offset", FieldType::I32),
+        ("v_count", FieldType::I32),
+        ("v_offset", FieldType::I32),
+        ("req_bytes_count", FieldType::I16),
+        ("req_bytes_offset", FieldType::I32),
+        ("w_outpres_count", FieldType::I32),
+        ("w_outpres_offset", FieldType::I32),
+        ("field50", FieldType::U16),
+        ("neg_response", FieldType::I32),
+        ("unkstr3", FieldType::I32),
+        ("unkstr4", FieldType::I32),
+        ("p_count", FieldType::I32),
+        ("p_offset", FieldType::I32),
+        ("diag_service_code_count", FieldType::I32),
+        ("diag_service_code_offset", FieldType::I32),
+        ("s_count", FieldType::I32),
+        ("s_offset", FieldType::I32),
+    ]);
+    flagged_fields(layout, "bitflags_ext", &[
+        ("x_count", FieldType::I32),
+        ("x_offset", FieldType::I32),
+        ("y_count", FieldType::I32),
+        ("y_offset", FieldType::I32),
+        ("z_count", FieldType::I32),
+        ("z_offset", FieldType::I32),
+    ])
+}
+
+/// Every record layout, with the name it is exported as
+pub fn record_layouts() -> Vec<(&'static str, Layout)> {
+    vec![("cbf_header", cbf_header_layout()), ("diag_service", diag_service_layout())]
+}
+
+#[test]
+fn test_read_cbf_header_layout() {
+    use common::layout::FieldValue;
+    use common::raf::Raf;
+
+    let mut data = vec![0u8; STUB_HEADER_SIZE];
+    data[..FILE_HEADER.len()].copy_from_slice(FILE_HEADER);
+    data[0x401] = 3;
+    data.extend_from_slice(&0x20i32.to_le_bytes());
+    data.extend_from_slice(&0b0000_0101u16.to_le_bytes()); // Caesar version and ECU count
+    data.extend_from_slice(&400i32.to_le_bytes());
+    data.extend_from_slice(&2i32.to_le_bytes());
+    let values = Raf::from_bytes(&data, LE).read_layout(&cbf_header_layout()).unwrap();
+    assert_eq!(values["translator_version"], FieldValue::String("CBF-TRANSLATOR-VERSION:04.00".into()));
+    assert_eq!(values["header_id"], FieldValue::Unsigned(3));
+    assert_eq!(values["caesar_version"], FieldValue::Signed(400));
+    assert_eq!(values["ecu_count"], FieldValue::Signed(2));
+    assert!(!values.contains_key("gpd_version"));
+
+    for (name, layout) in record_layouts() {
+        assert!(layout.to_c_header(name).is_ok(), "{}", name);
+        assert!(layout.to_ksy(name).is_ok(), "{}", name);
+    }
+    let ksy = cbf_header_layout().to_ksy("cbf_header").unwrap();
+    assert!(ksy.contains("  - id: pad_1c\n    size: 997\n"));
+    assert!(ksy.contains("  - id: diogenes_xml_string\n    type: s4le\n    if: ((cff_bitflags >> 11) & 1) == 1\n"));
+}
@@ -9,6 +9,10 @@ mod converter;
 extern crate xml;
 mod log;
 mod caesar;
+mod blocks;
+mod odx;
+mod layouts;
+mod string_pool;
 use cxf::*;
 use ecu::*;
 use diag::*;
@@ -19,20 +23,45 @@ use std::io::Read;
 fn help(err: String) -> ! {
     println!("Error: {}", err);
     println!("Usage:");
-    println!("cbf_parser <INPUT.CBF>");
+    println!("cbf_parser <INPUT.CBF> [--no-checksum] [--lenient]");
+    println!("cbf_parser --export-c | --export-ksy");
     std::process::exit(1);
 }
 
 fn main() {
     let args: Vec<String> = env::args().collect();
-    match args.len() {
-        2 => read_file(&args[1]),
-        _ => help(format!("Invalid number of args: {}", args.len() - 1)),
+    if args.len() == 2 && (args[1] == "--export-c" || args[1] == "--export-ksy") {
+        export_layouts(args[1] == "--export-c");
+        return;
+    }
+    if args.len() < 2 || args.len() > 4 {
+        help(format!("Invalid number of args: {}", args.len() - 1))
+    }
+    let mut skip_checksum = false;
+    let mut lenient = false;
+    for arg in &args[2..] {
+        match arg.as_str() {
+            "--no-checksum" => skip_checksum = true,
+            "--lenient" => lenient = true,
+            _ => help(format!("Unknown option: {}", arg)),
+        }
     }
+    read_file(&args[1], skip_checksum, lenient);
     println!("Hello, world!");
 }
 
-fn read_file(path: &String) {
+/// Prints the layout of each CBF record, as a C header or as Kaitai Struct
+fn export_layouts(c_header: bool) {
+    for (name, layout) in layouts::record_layouts() {
+        let res = if c_header { layout.to_c_header(name) } else { layout.to_ksy(name) };
+        match res {
+            Ok(s) => println!("{}", s),
+            Err(e) => eprintln!("Cannot export {}: {}", name, e),
+        }
+    }
+}
+
+fn read_file(path: &String, skip_checksum: bool, lenient: bool) {
     if path.ends_with(".cff") {
         eprintln!("Cannot be used with CFF. Only CBF!");
         return;
@@ -42,7 +71,20 @@ fn read_file(path: &String) {
     let mut buffer = vec![0; f.metadata().unwrap().len() as usize];
     f.read(&mut buffer).expect("Error reading file");
     let mut br = Raf::from_bytes(&buffer, common::raf::RafByteOrder::LE);
-    let container = caesar::CContainer::new(&mut br);
+    let res = if lenient {
+        caesar::CContainer::load_lenient(&mut br, skip_checksum)
+    } else {
+        caesar::CContainer::load(&mut br, skip_checksum).map(|c| (c, Vec::new()))
+    };
+    let container = match res {
+        Ok((c, warnings)) => {
+            for w in &warnings {
+                eprintln!("Warning: skipped {}", w);
+            }
+            c
+        },
+        Err(e) => help(format!("Cannot load {}: {:?}", path, e))
+    };
     converter::convert(&container);
 
 }
@@ -0,0 +1,991 @@
+use std::io::Read;
+use serde::*;
+use common::hex::parse_hex;
+use common::measurement::ScaledValue;
+use xml::common::Position;
+use xml::reader::{EventReader, XmlEvent};
+use crate::caesar::{ParseLog, ParseWarning, RecordLocation};
+
+/// Minimal element tree built from an ODX document, so sections can be
+/// looked up by path rather than tracked while streaming
+#[derive(Debug, Default)]
+struct XmlNode {
+    name: String,
+    /// Line of the start tag in the document
+    line: u64,
+    attributes: Vec<(String, String)>,
+    text: String,
+    children: Vec<XmlNode>,
+}
+
+impl XmlNode {
+    fn parse<R: Read>(reader: R) -> Result<Self, String> {
+        let mut stack: Vec<XmlNode> = vec![XmlNode::default()];
+        let mut parser = EventReader::new(reader);
+        loop {
+            match parser.next().map_err(|e| format!("XML error {}", e))? {
+                XmlEvent::StartElement { name, attributes, .. } => {
+                    stack.push(XmlNode {
+                        name: name.local_name,
+                        line: parser.position().row + 1,
+                        attributes: attributes.into_iter().map(|a| (a.name.local_name, a.value)).collect(),
+                        ..Default::default()
+                    })
+                },
+                XmlEvent::EndElement { .. } => {
+                    let node = stack.pop().ok_or("Unbalanced XML")?;
+                    stack.last_mut().ok_or("Unbalanced XML")?.children.push(node);
+                },
+                XmlEvent::Characters(s) | XmlEvent::CData(s) => {
+                    if let Some(n) = stack.last_mut() {
+                        n.text.push_str(&s)
+                    }
+                },
+                XmlEvent::EndDocument => break,
+                _ => {}
+            }
+        }
+        stack.pop().ok_or_else(|| "Empty XML document".into())
+    }
+
+    fn attr(&self, name: &str) -> Option<&str> {
+        self.attributes.iter().find(|(k, _)| k == name).map(|(_, v)| v.as_str())
+    }
+
+    fn child(&self, name: &str) -> Option<&XmlNode> {
+        self.children.iter().find(|c| c.name == name)
+    }
+
+    fn children<'a>(&'a self, name: &'a str) -> impl Iterator<Item=&'a XmlNode> + 'a {
+        self.children.iter().filter(move |c| c.name == name)
+    }
+
+    fn child_text(&self, name: &str) -> Option<String> {
+        self.child(name).map(|c| c.text.trim().to_string())
+    }
+
+    /// Finds the first element with a name anywhere below this one
+    fn find(&self, name: &str) -> Option<&XmlNode> {
+        for c in &self.children {
+            if c.name == name {
+                return Some(c)
+            }
+            if let Some(n) = c.find(name) {
+                return Some(n)
+            }
+        }
+        None
+    }
+
+    /// Finds every element with a name anywhere below this one, in document order
+    fn find_all<'a>(&'a self, name: &str, res: &mut Vec<&'a XmlNode>) {
+        for c in &self.children {
+            if c.name == name {
+                res.push(c)
+            }
+            c.find_all(name, res)
+        }
+    }
+
+    /// Follows a path of child element names, returning every element at the end of it
+    fn path<'a>(&'a self, path: &[&str]) -> Vec<&'a XmlNode> {
+        let mut nodes = vec![self];
+        for name in path {
+            nodes = nodes.into_iter().flat_map(move |n: &'a XmlNode| n.children.iter().filter(move |c| c.name == *name)).collect();
+        }
+        nodes
+    }
+}
+
+fn parse_hex_u32(s: &str) -> Result<u32, String> {
+    let t = s.trim();
+    let digits = t.strip_prefix("0x").or_else(|| t.strip_prefix("0X")).unwrap_or(t);
+    u32::from_str_radix(digits, 16).map_err(|_| format!("Invalid hex address '{}'", s))
+}
+
+/// Format of the data stored in a FLASHDATA element
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DataFormat {
+    Binary,
+    IntelHex,
+    MotorolaS,
+    UserDefined,
+}
+
+impl DataFormat {
+    fn from_selection(s: &str) -> Self {
+        match s {
+            "INTEL-HEX" => DataFormat::IntelHex,
+            "MOTOROLA-S" => DataFormat::MotorolaS,
+            "USER-DEFINED" => DataFormat::UserDefined,
+            _ => DataFormat::Binary
+        }
+    }
+}
+
+/// Where the bytes of a FLASHDATA are stored
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FlashDataSource {
+    /// Data is stored in the ODX file (INTERN-FLASHDATA)
+    Inline(Vec<u8>),
+    /// Data is in a separate file within the PDX (EXTERN-FLASHDATA)
+    External(String),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FlashData {
+    pub id: String,
+    pub name: String,
+    pub format: DataFormat,
+    /// Value of ENCRYPT-COMPRESS-METHOD. OEM specific, usually 00 for plain data
+    pub encrypt_compress_method: Option<String>,
+    pub source: FlashDataSource,
+}
+
+/// A contiguous region of memory written by a datablock
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FlashSegment {
+    pub name: String,
+    pub start_address: u32,
+    /// Last address of the segment (Inclusive)
+    pub end_address: Option<u32>,
+    pub uncompressed_size: Option<u32>,
+    pub compressed_size: Option<u32>,
+}
+
+impl FlashSegment {
+    /// Number of bytes written to the ECU for the segment. None if the size is not given, or the
+    /// end address is before the start address
+    pub fn size(&self) -> Option<u32> {
+        self.uncompressed_size.or_else(|| self.end_address?.checked_sub(self.start_address)?.checked_add(1))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DataBlock {
+    pub id: String,
+    pub name: String,
+    /// DATABLOCK TYPE attribute (For example DATA, BOOT or CODE)
+    pub block_type: String,
+    pub flashdata_ref: Option<String>,
+    pub segments: Vec<FlashSegment>,
+}
+
+/// Contents of an ODX FLASH (ODX-F) section, describing what is written to an ECU
+/// when reflashing and where the data comes from
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FlashSection {
+    pub name: String,
+    pub datablocks: Vec<DataBlock>,
+    pub flashdatas: Vec<FlashData>,
+}
+
+impl FlashSection {
+    /// Parses the FLASH element of an ODX document
+    pub fn parse<R: Read>(reader: R) -> Result<Self, String> {
+        Self::parse_with_log(reader, &mut ParseLog::strict())
+    }
+
+    /// Same as [parse](fn@FlashSection::parse), but datablocks and flash datas which fail
+    /// to parse are skipped instead of aborting. The document itself must still be valid XML
+    ///
+    /// # Returns
+    /// The section with every record that parsed, and a warning for each record that did not
+    pub fn parse_lenient<R: Read>(reader: R) -> Result<(Self, Vec<ParseWarning>), String> {
+        let mut log = ParseLog::lenient();
+        let res = Self::parse_with_log(reader, &mut log)?;
+        Ok((res, log.warnings))
+    }
+
+    fn parse_with_log<R: Read>(reader: R, log: &mut ParseLog) -> Result<Self, String> {
+        let root = XmlNode::parse(reader)?;
+        let flash = root.find("FLASH").ok_or("ODX has no FLASH section")?;
+
+        let mut datablocks = Vec::new();
+        for (i, db) in flash.children("ECU-MEMS").flat_map(|m| m.children("ECU-MEM")).filter_map(|m| m.child("MEM")).filter_map(|m| m.child("DATABLOCKS")).flat_map(|d| d.children("DATABLOCK")).enumerate() {
+            if let Some(block) = log.result("DATABLOCK", i, RecordLocation::Line(db.line), Self::parse_datablock(db))? {
+                datablocks.push(block)
+            }
+        }
+
+        let mut flashdatas = Vec::new();
+        for (i, fd) in flash.children("ECU-MEMS").flat_map(|m| m.children("ECU-MEM")).filter_map(|m| m.child("MEM")).filter_map(|m| m.child("FLASHDATAS")).flat_map(|d| d.children("FLASHDATA")).enumerate() {
+            if let Some(data) = log.result("FLASHDATA", i, RecordLocation::Line(fd.line), Self::parse_flashdata(fd))? {
+                flashdatas.push(data)
+            }
+        }
+
+        Ok(Self {
+            name: flash.child_text("SHORT-NAME").unwrap_or_default(),
+            datablocks,
+            flashdatas,
+        })
+    }
+
+    fn parse_datablock(db: &XmlNode) -> Result<DataBlock, String> {
+        let mut segments = Vec::new();
+        for seg in db.child("SEGMENTS").map(|s| s.children("SEGMENT").collect::<Vec<_>>()).unwrap_or_default() {
+            segments.push(FlashSegment {
+                name: seg.child_text("SHORT-NAME").unwrap_or_default(),
+                start_address: parse_hex_u32(&seg.child_text("SOURCE-START-ADDRESS").ok_or("Segment has no start address")?)?,
+                end_address: seg.child_text("SOURCE-END-ADDRESS").map(|s| parse_hex_u32(&s)).transpose()?,
+                uncompressed_size: seg.child_text("UNCOMPRESSED-SIZE").map(|s| s.parse().map_err(|_| format!("Invalid size '{}'", s))).transpose()?,
+                compressed_size: seg.child_text("COMPRESSED-SIZE").map(|s| s.parse().map_err(|_| format!("Invalid size '{}'", s))).transpose()?,
+            })
+        }
+        Ok(DataBlock {
+            id: db.attr("ID").unwrap_or_default().into(),
+            name: db.child_text("SHORT-NAME").unwrap_or_default(),
+            block_type: db.attr("TYPE").unwrap_or_default().into(),
+            flashdata_ref: db.child("FLASHDATA-REF").and_then(|r| r.attr("ID-REF")).map(String::from),
+            segments,
+        })
+    }
+
+    fn parse_flashdata(fd: &XmlNode) -> Result<FlashData, String> {
+        let source = if let Some(file) = fd.child_text("DATAFILE") {
+            FlashDataSource::External(file)
+        } else {
+            FlashDataSource::Inline(parse_hex(&fd.child_text("DATA").unwrap_or_default()).map_err(|e| format!("Invalid flash data: {}", e))?)
+        };
+        Ok(FlashData {
+            id: fd.attr("ID").unwrap_or_default().into(),
+            name: fd.child_text("SHORT-NAME").unwrap_or_default(),
+            format: fd.child("DATAFORMAT").and_then(|d| d.attr("SELECTION")).map(DataFormat::from_selection).unwrap_or(DataFormat::Binary),
+            encrypt_compress_method: fd.child_text("ENCRYPT-COMPRESS-METHOD"),
+            source,
+        })
+    }
+
+    pub fn get_flashdata(&self, id: &str) -> Option<&FlashData> {
+        self.flashdatas.iter().find(|f| f.id == id)
+    }
+
+    /// Reads the bytes that a datablock writes to the ECU
+    ///
+    /// # Params
+    /// * block - Datablock to read
+    /// * read_file - Reads an external data file from the PDX by name
+    pub fn read_datablock<F: Fn(&str) -> Option<Vec<u8>>>(&self, block: &DataBlock, read_file: F) -> Result<Vec<u8>, String> {
+        let fd_ref = block.flashdata_ref.as_ref().ok_or_else(|| format!("Datablock {} has no flash data", block.name))?;
+        let fd = self.get_flashdata(fd_ref).ok_or_else(|| format!("Flash data {} not found", fd_ref))?;
+        if fd.format != DataFormat::Binary {
+            return Err(format!("Flash data {} is {:?}, only binary data is supported", fd.name, fd.format))
+        }
+        match &fd.source {
+            FlashDataSource::Inline(data) => Ok(data.clone()),
+            FlashDataSource::External(file) => read_file(file).ok_or_else(|| format!("{} not found in PDX", file))
+        }
+    }
+}
+
+/// Type of an ODX diagnostic layer. Ordered from the most to the least specific, which is
+/// the order that layers inheriting from several parents take services from them
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum LayerKind {
+    EcuVariant,
+    BaseVariant,
+    FunctionalGroup,
+    Protocol,
+    EcuSharedData,
+}
+
+impl LayerKind {
+    fn from_element(name: &str) -> Option<Self> {
+        match name {
+            "ECU-VARIANT" => Some(LayerKind::EcuVariant),
+            "BASE-VARIANT" => Some(LayerKind::BaseVariant),
+            "FUNCTIONAL-GROUP" => Some(LayerKind::FunctionalGroup),
+            "PROTOCOL" => Some(LayerKind::Protocol),
+            "ECU-SHARED-DATA" => Some(LayerKind::EcuSharedData),
+            _ => None
+        }
+    }
+}
+
+/// How a COMPU-SCALE limit bounds its range
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum IntervalType {
+    /// The limit is part of the range
+    Closed,
+    /// The limit is not part of the range
+    Open,
+    /// There is no limit on this side
+    Infinite,
+}
+
+/// LOWER-LIMIT or UPPER-LIMIT of a COMPU-SCALE
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CompuLimit {
+    pub value: f64,
+    pub interval: IntervalType,
+}
+
+/// Value of a COMPU-CONST or COMPU-DEFAULT-VALUE
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum CompuConst {
+    /// Text (VT)
+    Text(String),
+    /// Number (V)
+    Number(f64),
+}
+
+/// One COMPU-SCALE of a computation, which converts the raw values in its range
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CompuScale {
+    /// None if the range has no lower limit
+    pub lower: Option<CompuLimit>,
+    /// None if the range has no upper limit. In a TEXTTABLE, a scale without an upper limit
+    /// only covers its lower limit
+    pub upper: Option<CompuLimit>,
+    /// Numerator coefficients, lowest order first
+    pub numerator: Vec<f64>,
+    /// Denominator coefficients, lowest order first. Empty is the same as 1
+    pub denominator: Vec<f64>,
+    /// Value every raw value in the range converts to, used instead of the coefficients
+    pub constant: Option<CompuConst>,
+}
+
+impl CompuScale {
+    fn contains(&self, raw: f64, text_table: bool) -> bool {
+        let above = |l: &CompuLimit| match l.interval {
+            IntervalType::Closed => raw >= l.value,
+            IntervalType::Open => raw > l.value,
+            IntervalType::Infinite => true,
+        };
+        let below = |l: &CompuLimit| match l.interval {
+            IntervalType::Closed => raw <= l.value,
+            IntervalType::Open => raw < l.value,
+            IntervalType::Infinite => true,
+        };
+        let upper = match (&self.upper, &self.lower) {
+            (None, Some(l)) if text_table => Some(l),
+            (u, _) => u.as_ref(),
+        };
+        self.lower.as_ref().map_or(true, above) && upper.map_or(true, below)
+    }
+
+    fn convert(&self, raw: f64) -> Result<ScaledValue, String> {
+        match &self.constant {
+            Some(CompuConst::Text(t)) => return Ok(ScaledValue::Text(t.clone())),
+            Some(CompuConst::Number(n)) => return Ok(ScaledValue::Number { value: *n, unit: None }),
+            None => {}
+        }
+        let poly = |coeffs: &[f64]| coeffs.iter().rev().fold(0.0, |acc, c| acc * raw + c);
+        let den = if self.denominator.is_empty() { 1.0 } else { poly(&self.denominator) };
+        if den == 0.0 {
+            return Err(format!("Denominator is 0 for raw value {}", raw))
+        }
+        Ok(ScaledValue::Number { value: poly(&self.numerator) / den, unit: None })
+    }
+}
+
+/// Computation which converts a DOP's internal (Raw) value to its physical value
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CompuMethod {
+    /// CATEGORY of the method (For example IDENTICAL, LINEAR or TEXTTABLE)
+    pub category: String,
+    /// Scales in the order they are defined
+    pub scales: Vec<CompuScale>,
+    /// Value of raw values which no scale covers
+    pub default: Option<CompuConst>,
+}
+
+impl CompuMethod {
+    /// Converts a raw value with the first scale whose range covers it, or the default value if none do.
+    /// IDENTICAL methods return the raw value
+    pub fn scale(&self, raw: f64) -> Result<ScaledValue, String> {
+        if self.category == "IDENTICAL" {
+            return Ok(ScaledValue::Number { value: raw, unit: None })
+        }
+        let text_table = self.category == "TEXTTABLE";
+        match (self.scales.iter().find(|s| s.contains(raw, text_table)), &self.default) {
+            (Some(s), _) => s.convert(raw),
+            (None, Some(CompuConst::Text(t))) => Ok(ScaledValue::Text(t.clone())),
+            (None, Some(CompuConst::Number(n))) => Ok(ScaledValue::Number { value: *n, unit: None }),
+            (None, None) => Err(format!("No COMPU-SCALE covers raw value {}", raw))
+        }
+    }
+}
+
+/// Data object property, describing how a parameter is encoded
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DataObjectProp {
+    pub id: String,
+    pub name: String,
+    pub compu_method: Option<CompuMethod>,
+    /// Short name of the layer the DOP is defined in
+    pub layer: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OdxService {
+    pub id: String,
+    pub name: String,
+    pub semantic: Option<String>,
+    pub request_ref: Option<String>,
+    pub pos_response_refs: Vec<String>,
+    pub neg_response_refs: Vec<String>,
+    /// Short name of the layer the service is defined in
+    pub layer: String,
+}
+
+/// Reference from a layer to a layer it inherits from
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ParentRef {
+    pub id: String,
+    /// Short names of services which are not inherited from the parent
+    pub not_inherited_services: Vec<String>,
+    /// Short names of DOPs which are not inherited from the parent
+    pub not_inherited_dops: Vec<String>,
+}
+
+/// A single DIAG-LAYER, before inheritance is applied
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiagLayer {
+    pub id: String,
+    pub name: String,
+    pub kind: LayerKind,
+    pub parents: Vec<ParentRef>,
+    pub services: Vec<OdxService>,
+    pub dops: Vec<DataObjectProp>,
+}
+
+/// A layer with everything it inherits merged in
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Variant {
+    pub name: String,
+    pub kind: LayerKind,
+    pub services: Vec<OdxService>,
+    pub dops: Vec<DataObjectProp>,
+}
+
+impl Variant {
+    pub fn get_service(&self, name: &str) -> Option<&OdxService> {
+        self.services.iter().find(|s| s.name == name)
+    }
+
+    pub fn get_dop(&self, name: &str) -> Option<&DataObjectProp> {
+        self.dops.iter().find(|d| d.name == name)
+    }
+}
+
+fn snrefs(node: &XmlNode, path: &[&str]) -> Vec<String> {
+    node.path(path).iter().filter_map(|r| r.attr("SHORT-NAME")).map(String::from).collect()
+}
+
+fn id_refs(node: &XmlNode, name: &str) -> Vec<String> {
+    node.children(name).filter_map(|r| r.attr("ID-REF")).map(String::from).collect()
+}
+
+fn parse_coeffs(node: Option<&XmlNode>) -> Result<Vec<f64>, String> {
+    node.map(|n| n.children("V").map(|v| v.text.trim().parse().map_err(|_| format!("Invalid coefficient '{}'", v.text.trim()))).collect::<Result<Vec<f64>, String>>())
+        .unwrap_or_else(|| Ok(Vec::new()))
+}
+
+fn parse_number(node: &XmlNode) -> Result<f64, String> {
+    node.text.trim().parse().map_err(|_| format!("Invalid number '{}' on line {}", node.text.trim(), node.line))
+}
+
+fn parse_limit(node: Option<&XmlNode>) -> Result<Option<CompuLimit>, String> {
+    let node = match node {
+        Some(n) => n,
+        None => return Ok(None)
+    };
+    let interval = match node.attr("INTERVAL-TYPE").unwrap_or("CLOSED") {
+        "CLOSED" => IntervalType::Closed,
+        "OPEN" => IntervalType::Open,
+        "INFINITE" => return Ok(Some(CompuLimit { value: 0.0, interval: IntervalType::Infinite })),
+        x => return Err(format!("Invalid INTERVAL-TYPE '{}' on line {}", x, node.line))
+    };
+    Ok(Some(CompuLimit { value: parse_number(node)?, interval }))
+}
+
+/// Parses a COMPU-CONST or COMPU-DEFAULT-VALUE
+fn parse_const(node: Option<&XmlNode>) -> Result<Option<CompuConst>, String> {
+    match (node.and_then(|n| n.child("VT")), node.and_then(|n| n.child("V"))) {
+        (Some(vt), _) => Ok(Some(CompuConst::Text(vt.text.trim().into()))),
+        (None, Some(v)) => Ok(Some(CompuConst::Number(parse_number(v)?))),
+        (None, None) => Ok(None)
+    }
+}
+
+fn parse_compu_method(node: &XmlNode) -> Result<CompuMethod, String> {
+    let scales = node.path(&["COMPU-INTERNAL-TO-PHYS", "COMPU-SCALES", "COMPU-SCALE"]).iter().map(|s| {
+        let coeffs = s.child("COMPU-RATIONAL-COEFFS");
+        Ok(CompuScale {
+            lower: parse_limit(s.child("LOWER-LIMIT"))?,
+            upper: parse_limit(s.child("UPPER-LIMIT"))?,
+            numerator: parse_coeffs(coeffs.and_then(|k| k.child("COMPU-NUMERATOR")))?,
+            denominator: parse_coeffs(coeffs.and_then(|k| k.child("COMPU-DENOMINATOR")))?,
+            constant: parse_const(s.child("COMPU-CONST"))?,
+        })
+    }).collect::<Result<Vec<_>, String>>()?;
+    Ok(CompuMethod {
+        category: node.child_text("CATEGORY").unwrap_or_default(),
+        scales,
+        default: parse_const(node.child("COMPU-INTERNAL-TO-PHYS").and_then(|i| i.child("COMPU-DEFAULT-VALUE")))?,
+    })
+}
+
+/// Every diagnostic layer in one or more ODX documents, which are resolved into variants
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DiagLayerContainer {
+    pub layers: Vec<DiagLayer>,
+}
+
+impl DiagLayerContainer {
+    /// Parses every diagnostic layer in an ODX document
+    pub fn parse<R: Read>(reader: R) -> Result<Self, String> {
+        let mut res = Self::default();
+        res.add_document(reader)?;
+        Ok(res)
+    }
+
+    /// Adds the layers of another ODX document. PDX files usually split the layers
+    /// of an ECU across several documents which reference each other
+    pub fn add_document<R: Read>(&mut self, reader: R) -> Result<(), String> {
+        let root = XmlNode::parse(reader)?;
+        let mut containers = Vec::new();
+        root.find_all("DIAG-LAYER-CONTAINER", &mut containers);
+        for container in containers {
+            for group in &container.children {
+                for layer in &group.children {
+                    if let Some(kind) = LayerKind::from_element(&layer.name) {
+                        self.layers.push(Self::parse_layer(layer, kind)?)
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_layer(node: &XmlNode, kind: LayerKind) -> Result<DiagLayer, String> {
+        let name = node.child_text("SHORT-NAME").ok_or_else(|| format!("{} on line {} has no SHORT-NAME", node.name, node.line))?;
+        let parents = node.path(&["PARENT-REFS", "PARENT-REF"]).iter().map(|p| Ok(ParentRef {
+            id: p.attr("ID-REF").ok_or_else(|| format!("PARENT-REF on line {} has no ID-REF", p.line))?.into(),
+            not_inherited_services: snrefs(p, &["NOT-INHERITED-DIAG-COMMS", "NOT-INHERITED-DIAG-COMM", "DIAG-COMM-SNREF"]),
+            not_inherited_dops: snrefs(p, &["NOT-INHERITED-DOPS", "NOT-INHERITED-DOP", "DOP-BASE-SNREF"]),
+        })).collect::<Result<Vec<_>, String>>()?;
+
+        let services = node.path(&["DIAG-COMMS", "DIAG-SERVICE"]).iter().map(|s| OdxService {
+            id: s.attr("ID").unwrap_or_default().into(),
+            name: s.child_text("SHORT-NAME").unwrap_or_default(),
+            semantic: s.attr("SEMANTIC").map(String::from),
+            request_ref: s.child("REQUEST-REF").and_then(|r| r.attr("ID-REF")).map(String::from),
+            pos_response_refs: s.child("POS-RESPONSE-REFS").map(|r| id_refs(r, "POS-RESPONSE-REF")).unwrap_or_default(),
+            neg_response_refs: s.child("NEG-RESPONSE-REFS").map(|r| id_refs(r, "NEG-RESPONSE-REF")).unwrap_or_default(),
+            layer: name.clone(),
+        }).collect();
+
+        let mut dops = Vec::new();
+        for d in node.path(&["DIAG-DATA-DICTIONARY-SPEC", "DATA-OBJECT-PROPS", "DATA-OBJECT-PROP"]) {
+            let compu_method = match d.child("COMPU-METHOD") {
+                Some(c) => Some(parse_compu_method(c)?),
+                None => None
+            };
+            dops.push(DataObjectProp {
+                id: d.attr("ID").unwrap_or_default().into(),
+                name: d.child_text("SHORT-NAME").unwrap_or_default(),
+                compu_method,
+                layer: name.clone(),
+            })
+        }
+
+        Ok(DiagLayer { id: node.attr("ID").unwrap_or_default().into(), name, kind, parents, services, dops })
+    }
+
+    /// Finds a layer by its ID or short name
+    pub fn get_layer(&self, name: &str) -> Option<&DiagLayer> {
+        self.layers.iter().find(|l| l.id == name).or_else(|| self.layers.iter().find(|l| l.name == name))
+    }
+
+    /// Builds the flattened view of a layer, with everything it inherits.
+    ///
+    /// Services and DOPs are matched by short name. Anything the layer defines itself overrides
+    /// its parents. If several parents define the same name, the most specific kind of layer wins
+    /// (ECU variant, base variant, functional group, protocol, then ECU shared data), and parents
+    /// of the same kind are taken in the order they are referenced
+    ///
+    /// # Params
+    /// * name - ID or short name of the layer
+    pub fn resolve(&self, name: &str) -> Result<Variant, String> {
+        let layer = self.get_layer(name).ok_or_else(|| format!("Layer {} not found", name))?;
+        let (services, dops) = self.resolve_layer(layer, &mut Vec::new())?;
+        Ok(Variant { name: layer.name.clone(), kind: layer.kind, services, dops })
+    }
+
+    fn resolve_layer<'a>(&'a self, layer: &'a DiagLayer, visiting: &mut Vec<&'a str>) -> Result<(Vec<OdxService>, Vec<DataObjectProp>), String> {
+        if visiting.contains(&layer.id.as_str()) {
+            return Err(format!("Layer {} inherits from itself", layer.name))
+        }
+        visiting.push(&layer.id);
+        let mut services = layer.services.clone();
+        let mut dops = layer.dops.clone();
+
+        let mut parents = Vec::new();
+        for r in &layer.parents {
+            let parent = self.layers.iter().find(|l| l.id == r.id).ok_or_else(|| format!("Layer {} references missing parent {}", layer.name, r.id))?;
+            parents.push((parent, r));
+        }
+        // Stable, so parents of the same kind keep their order
+        parents.sort_by_key(|(p, _)| p.kind);
+        for (parent, r) in parents {
+            let (p_services, p_dops) = self.resolve_layer(parent, visiting)?;
+            for s in p_services {
+                if !r.not_inherited_services.contains(&s.name) && !services.iter().any(|x| x.name == s.name) {
+                    services.push(s)
+                }
+            }
+            for d in p_dops {
+                if !r.not_inherited_dops.contains(&d.name) && !dops.iter().any(|x| x.name == d.name) {
+                    dops.push(d)
+                }
+            }
+        }
+        visiting.pop();
+        Ok((services, dops))
+    }
+}
+
+#[test]
+fn test_parse_flash_section() {
+    let odx = r#"<?xml version="1.0" encoding="UTF-8"?>
+<ODX VERSION="2.2.0">
+  <FLASH ID="FL_ECM">
+    <SHORT-NAME>FL_ECM</SHORT-NAME>
+    <ECU-MEMS>
+      <ECU-MEM ID="EM_ECM">
+        <SHORT-NAME>EM_ECM</SHORT-NAME>
+        <MEM>
+          <DATABLOCKS>
+            <DATABLOCK ID="DB_CAL" TYPE="DATA">
+              <SHORT-NAME>Calibration</SHORT-NAME>
+              <FLASHDATA-REF ID-REF="FD_CAL"/>
+              <SEGMENTS>
+                <SEGMENT ID="SEG_CAL">
+                  <SHORT-NAME>SEG_CAL</SHORT-NAME>
+                  <SOURCE-START-ADDRESS>00080000</SOURCE-START-ADDRESS>
+                  <SOURCE-END-ADDRESS>00080007</SOURCE-END-ADDRESS>
+                </SEGMENT>
+              </SEGMENTS>
+            </DATABLOCK>
+            <DATABLOCK ID="DB_APP" TYPE="CODE">
+              <SHORT-NAME>Application</SHORT-NAME>
+              <FLASHDATA-REF ID-REF="FD_APP"/>
+            </DATABLOCK>
+          </DATABLOCKS>
+          <FLASHDATAS>
+            <FLASHDATA ID="FD_CAL">
+              <SHORT-NAME>FD_CAL</SHORT-NAME>
+              <DATAFORMAT SELECTION="BINARY"/>
+              <ENCRYPT-COMPRESS-METHOD TYPE="A_BYTEFIELD">00</ENCRYPT-COMPRESS-METHOD>
+              <DATA>DEADBEEF 01020304</DATA>
+            </FLASHDATA>
+            <FLASHDATA ID="FD_APP">
+              <SHORT-NAME>FD_APP</SHORT-NAME>
+              <DATAFORMAT SELECTION="BINARY"/>
+              <DATAFILE LATEBOUND-DATAFILE="false">app.bin</DATAFILE>
+            </FLASHDATA>
+          </FLASHDATAS>
+        </MEM>
+      </ECU-MEM>
+    </ECU-MEMS>
+  </FLASH>
+</ODX>"#;
+    let flash = FlashSection::parse(odx.as_bytes()).unwrap();
+    assert_eq!(flash.name, "FL_ECM");
+    assert_eq!(flash.datablocks.len(), 2);
+
+    let cal = &flash.datablocks[0];
+    assert_eq!(cal.block_type, "DATA");
+    assert_eq!(cal.segments[0].start_address, 0x0008_0000);
+    assert_eq!(cal.segments[0].size(), Some(8));
+    assert_eq!(flash.read_datablock(cal, |_| None).unwrap(), vec![0xDE, 0xAD, 0xBE, 0xEF, 0x01, 0x02, 0x03, 0x04]);
+
+    let app = &flash.datablocks[1];
+    let read_pdx = |name: &str| if name == "app.bin" { Some(vec![0x55; 4]) } else { None };
+    assert_eq!(flash.read_datablock(app, read_pdx).unwrap(), vec![0x55; 4]);
+    assert!(flash.read_datablock(app, |_| None).is_err());
+}
+
+#[test]
+fn test_flash_segment_size() {
+    let segment = |start_address, end_address| FlashSegment { name: String::new(), start_address, end_address: Some(end_address), uncompressed_size: None, compressed_size: None };
+    assert_eq!(segment(0x1000, 0x1000).size(), Some(1));
+    assert_eq!(segment(0x1000, 0x0FFF).size(), None);
+    assert_eq!(segment(0x0000, u32::MAX).size(), None);
+    assert_eq!(parse_hex_u32("0X1000"), Ok(0x1000));
+    assert_eq!(parse_hex_u32(" 0x1000 "), Ok(0x1000));
+    assert!(parse_hex_u32("0x0x1000").is_err());
+}
+
+#[test]
+fn test_parse_flash_section_lenient() {
+    let odx = r#"<?xml version="1.0" encoding="UTF-8"?>
+<ODX VERSION="2.2.0">
+  <FLASH ID="FL_ECM">
+    <SHORT-NAME>FL_ECM</SHORT-NAME>
+    <ECU-MEMS>
+      <ECU-MEM ID="EM_ECM">
+        <MEM>
+          <DATABLOCKS>
+            <DATABLOCK ID="DB_CAL" TYPE="DATA">
+              <SHORT-NAME>Calibration</SHORT-NAME>
+              <SEGMENTS>
+                <SEGMENT ID="SEG_CAL">
+                  <SOURCE-START-ADDRESS>0008ZZZZ</SOURCE-START-ADDRESS>
+                </SEGMENT>
+              </SEGMENTS>
+            </DATABLOCK>
+            <DATABLOCK ID="DB_APP" TYPE="CODE">
+              <SHORT-NAME>Application</SHORT-NAME>
+              <FLASHDATA-REF ID-REF="FD_APP"/>
+            </DATABLOCK>
+          </DATABLOCKS>
+          <FLASHDATAS>
+            <FLASHDATA ID="FD_APP">
+              <SHORT-NAME>FD_APP</SHORT-NAME>
+              <DATA>0102</DATA>
+            </FLASHDATA>
+          </FLASHDATAS>
+        </MEM>
+      </ECU-MEM>
+    </ECU-MEMS>
+  </FLASH>
+</ODX>"#;
+    assert!(FlashSection::parse(odx.as_bytes()).is_err());
+
+    let (flash, warnings) = FlashSection::parse_lenient(odx.as_bytes()).unwrap();
+    assert_eq!(flash.datablocks.len(), 1);
+    assert_eq!(flash.datablocks[0].name, "Application");
+    assert_eq!(flash.flashdatas.len(), 1);
+    assert_eq!(warnings, vec![ParseWarning { record: "DATABLOCK", index: 0, location: RecordLocation::Line(9), message: "Invalid hex address '0008ZZZZ'".into() }]);
+    assert_eq!(warnings[0].to_string(), "DATABLOCK #0 at line 9: Invalid hex address '0008ZZZZ'");
+}
+
+#[test]
+fn test_resolve_layers() {
+    let odx = r#"<?xml version="1.0" encoding="UTF-8"?>
+<ODX VERSION="2.2.0">
+  <DIAG-LAYER-CONTAINER ID="DLC_ECM">
+    <SHORT-NAME>DLC_ECM</SHORT-NAME>
+    <PROTOCOLS>
+      <PROTOCOL ID="PR_UDS">
+        <SHORT-NAME>UDS</SHORT-NAME>
+        <DIAG-COMMS>
+          <DIAG-SERVICE ID="PR_UDS.DS_TesterPresent" SEMANTIC="TESTING">
+            <SHORT-NAME>TesterPresent</SHORT-NAME>
+            <REQUEST-REF ID-REF="PR_UDS.RQ_TesterPresent"/>
+          </DIAG-SERVICE>
+          <DIAG-SERVICE ID="PR_UDS.DS_ReadVIN">
+            <SHORT-NAME>ReadVIN</SHORT-NAME>
+            <REQUEST-REF ID-REF="PR_UDS.RQ_ReadVIN"/>
+          </DIAG-SERVICE>
+        </DIAG-COMMS>
+      </PROTOCOL>
+    </PROTOCOLS>
+    <FUNCTIONAL-GROUPS>
+      <FUNCTIONAL-GROUP ID="FG_Powertrain">
+        <SHORT-NAME>Powertrain</SHORT-NAME>
+        <DIAG-COMMS>
+          <DIAG-SERVICE ID="FG_Powertrain.DS_ReadVIN">
+            <SHORT-NAME>ReadVIN</SHORT-NAME>
+            <REQUEST-REF ID-REF="FG_Powertrain.RQ_ReadVIN"/>
+          </DIAG-SERVICE>
+        </DIAG-COMMS>
+        <PARENT-REFS>
+          <PARENT-REF ID-REF="PR_UDS"/>
+        </PARENT-REFS>
+      </FUNCTIONAL-GROUP>
+    </FUNCTIONAL-GROUPS>
+    <BASE-VARIANTS>
+      <BASE-VARIANT ID="BV_ECM">
+        <SHORT-NAME>ECM</SHORT-NAME>
+        <DIAG-COMMS>
+          <DIAG-SERVICE ID="BV_ECM.DS_ReadTemp" SEMANTIC="CURRENTDATA">
+            <SHORT-NAME>ReadTemp</SHORT-NAME>
+            <REQUEST-REF ID-REF="BV_ECM.RQ_ReadTemp"/>
+            <POS-RESPONSE-REFS>
+              <POS-RESPONSE-REF ID-REF="BV_ECM.PR_ReadTemp"/>
+            </POS-RESPONSE-REFS>
+          </DIAG-SERVICE>
+          <DIAG-SERVICE ID="BV_ECM.DS_Reset">
+            <SHORT-NAME>Reset</SHORT-NAME>
+          </DIAG-SERVICE>
+        </DIAG-COMMS>
+        <DIAG-DATA-DICTIONARY-SPEC>
+          <DATA-OBJECT-PROPS>
+            <DATA-OBJECT-PROP ID="BV_ECM.DOP_Temp">
+              <SHORT-NAME>Temperature</SHORT-NAME>
+              <COMPU-METHOD>
+                <CATEGORY>LINEAR</CATEGORY>
+                <COMPU-INTERNAL-TO-PHYS>
+                  <COMPU-SCALES>
+                    <COMPU-SCALE>
+                      <COMPU-RATIONAL-COEFFS>
+                        <COMPU-NUMERATOR><V>-40</V><V>0.5</V></COMPU-NUMERATOR>
+                        <COMPU-DENOMINATOR><V>1</V></COMPU-DENOMINATOR>
+                      </COMPU-RATIONAL-COEFFS>
+                    </COMPU-SCALE>
+                  </COMPU-SCALES>
+                </COMPU-INTERNAL-TO-PHYS>
+              </COMPU-METHOD>
+            </DATA-OBJECT-PROP>
+          </DATA-OBJECT-PROPS>
+        </DIAG-DATA-DICTIONARY-SPEC>
+        <PARENT-REFS>
+          <PARENT-REF ID-REF="PR_UDS"/>
+          <PARENT-REF ID-REF="FG_Powertrain"/>
+        </PARENT-REFS>
+      </BASE-VARIANT>
+    </BASE-VARIANTS>
+    <ECU-VARIANTS>
+      <ECU-VARIANT ID="EV_ECM_V2">
+        <SHORT-NAME>ECM_V2</SHORT-NAME>
+        <DIAG-COMMS>
+          <DIAG-SERVICE ID="EV_ECM_V2.DS_ReadTemp" SEMANTIC="CURRENTDATA">
+            <SHORT-NAME>ReadTemp</SHORT-NAME>
+            <REQUEST-REF ID-REF="EV_ECM_V2.RQ_ReadTemp"/>
+          </DIAG-SERVICE>
+        </DIAG-COMMS>
+        <PARENT-REFS>
+          <PARENT-REF ID-REF="BV_ECM">
+            <NOT-INHERITED-DIAG-COMMS>
+              <NOT-INHERITED-DIAG-COMM>
+                <DIAG-COMM-SNREF SHORT-NAME="Reset"/>
+              </NOT-INHERITED-DIAG-COMM>
+            </NOT-INHERITED-DIAG-COMMS>
+          </PARENT-REF>
+        </PARENT-REFS>
+      </ECU-VARIANT>
+    </ECU-VARIANTS>
+  </DIAG-LAYER-CONTAINER>
+</ODX>"#;
+    let container = DiagLayerContainer::parse(odx.as_bytes()).unwrap();
+    assert_eq!(container.layers.len(), 4);
+
+    let variant = container.resolve("ECM_V2").unwrap();
+    assert_eq!(variant.kind, LayerKind::EcuVariant);
+    let names: Vec<&str> = variant.services.iter().map(|s| s.name.as_str()).collect();
+    assert_eq!(names, vec!["ReadTemp", "ReadVIN", "TesterPresent"]);
+    // Overridden by the ECU variant
+    assert_eq!(variant.get_service("ReadTemp").unwrap().request_ref.as_deref(), Some("EV_ECM_V2.RQ_ReadTemp"));
+    // Functional group is more specific than the protocol, even though it is referenced second
+    assert_eq!(variant.get_service("ReadVIN").unwrap().layer, "Powertrain");
+    assert_eq!(variant.get_service("TesterPresent").unwrap().layer, "UDS");
+    assert!(variant.get_service("Reset").is_none());
+    let temp = variant.get_dop("Temperature").unwrap();
+    assert_eq!(temp.layer, "ECM");
+    assert_eq!(temp.compu_method, Some(CompuMethod {
+        category: "LINEAR".into(),
+        scales: vec![CompuScale { lower: None, upper: None, numerator: vec![-40.0, 0.5], denominator: vec![1.0], constant: None }],
+        default: None,
+    }));
+
+    // Base variant still has everything it defines
+    let base = container.resolve("BV_ECM").unwrap();
+    assert_eq!(base.get_service("ReadTemp").unwrap().pos_response_refs, vec!["BV_ECM.PR_ReadTemp".to_string()]);
+    assert!(base.get_service("Reset").is_some());
+
+    assert!(container.resolve("ECM_V3").is_err());
+    let mut cyclic = container.clone();
+    cyclic.layers[0].parents.push(ParentRef { id: "EV_ECM_V2".into(), not_inherited_services: vec![], not_inherited_dops: vec![] });
+    assert!(cyclic.resolve("ECM_V2").is_err());
+}
+
+#[test]
+fn test_compu_scales() {
+    let odx = r#"<?xml version="1.0" encoding="UTF-8"?>
+<ODX VERSION="2.2.0">
+  <DIAG-LAYER-CONTAINER ID="DLC_ECM">
+    <SHORT-NAME>DLC_ECM</SHORT-NAME>
+    <BASE-VARIANTS>
+      <BASE-VARIANT ID="BV_ECM">
+        <SHORT-NAME>ECM</SHORT-NAME>
+        <DIAG-DATA-DICTIONARY-SPEC>
+          <DATA-OBJECT-PROPS>
+            <DATA-OBJECT-PROP ID="BV_ECM.DOP_Level">
+              <SHORT-NAME>Level</SHORT-NAME>
+              <COMPU-METHOD>
+                <CATEGORY>TEXTTABLE</CATEGORY>
+                <COMPU-INTERNAL-TO-PHYS>
+                  <COMPU-SCALES>
+                    <COMPU-SCALE>
+                      <LOWER-LIMIT>0</LOWER-LIMIT>
+                      <UPPER-LIMIT>5</UPPER-LIMIT>
+                      <COMPU-CONST><VT>Low</VT></COMPU-CONST>
+                    </COMPU-SCALE>
+                    <COMPU-SCALE>
+                      <LOWER-LIMIT INTERVAL-TYPE="CLOSED">6</LOWER-LIMIT>
+                      <UPPER-LIMIT INTERVAL-TYPE="CLOSED">255</UPPER-LIMIT>
+                      <COMPU-CONST><VT>High</VT></COMPU-CONST>
+                    </COMPU-SCALE>
+                    <COMPU-SCALE>
+                      <LOWER-LIMIT>1000</LOWER-LIMIT>
+                      <COMPU-CONST><VT>Sensor fault</VT></COMPU-CONST>
+                    </COMPU-SCALE>
+                  </COMPU-SCALES>
+                  <COMPU-DEFAULT-VALUE><VT>Invalid</VT></COMPU-DEFAULT-VALUE>
+                </COMPU-INTERNAL-TO-PHYS>
+              </COMPU-METHOD>
+            </DATA-OBJECT-PROP>
+            <DATA-OBJECT-PROP ID="BV_ECM.DOP_Pressure">
+              <SHORT-NAME>Pressure</SHORT-NAME>
+              <COMPU-METHOD>
+                <CATEGORY>SCALE-LINEAR</CATEGORY>
+                <COMPU-INTERNAL-TO-PHYS>
+                  <COMPU-SCALES>
+                    <COMPU-SCALE>
+                      <LOWER-LIMIT INTERVAL-TYPE="INFINITE"/>
+                      <UPPER-LIMIT INTERVAL-TYPE="OPEN">100</UPPER-LIMIT>
+                      <COMPU-RATIONAL-COEFFS>
+                        <COMPU-NUMERATOR><V>0</V><V>2</V></COMPU-NUMERATOR>
+                      </COMPU-RATIONAL-COEFFS>
+                    </COMPU-SCALE>
+                    <COMPU-SCALE>
+                      <LOWER-LIMIT>100</LOWER-LIMIT>
+                      <UPPER-LIMIT>200</UPPER-LIMIT>
+                      <COMPU-RATIONAL-COEFFS>
+                        <COMPU-NUMERATOR><V>100</V><V>1</V></COMPU-NUMERATOR>
+                        <COMPU-DENOMINATOR><V>2</V></COMPU-DENOMINATOR>
+                      </COMPU-RATIONAL-COEFFS>
+                    </COMPU-SCALE>
+                  </COMPU-SCALES>
+                </COMPU-INTERNAL-TO-PHYS>
+              </COMPU-METHOD>
+            </DATA-OBJECT-PROP>
+          </DATA-OBJECT-PROPS>
+        </DIAG-DATA-DICTIONARY-SPEC>
+      </BASE-VARIANT>
+    </BASE-VARIANTS>
+  </DIAG-LAYER-CONTAINER>
+</ODX>"#;
+    let container = DiagLayerContainer::parse(odx.as_bytes()).unwrap();
+    let variant = container.resolve("ECM").unwrap();
+    let text = |v: Result<ScaledValue, String>| v.unwrap().to_string();
+
+    let level = variant.get_dop("Level").unwrap().compu_method.as_ref().unwrap();
+    assert_eq!(level.scales.len(), 3);
+    assert_eq!(level.scales[0].lower, Some(CompuLimit { value: 0.0, interval: IntervalType::Closed }));
+    assert_eq!(text(level.scale(0.0)), "Low");
+    assert_eq!(text(level.scale(5.0)), "Low");
+    assert_eq!(text(level.scale(6.0)), "High");
+    assert_eq!(text(level.scale(255.0)), "High");
+    // Scale without an upper limit only covers its lower limit
+    assert_eq!(text(level.scale(1000.0)), "Sensor fault");
+    assert_eq!(text(level.scale(1001.0)), "Invalid");
+    assert_eq!(text(level.scale(-1.0)), "Invalid");
+
+    // Piecewise linear: 2x below 100, (100 + x) / 2 from 100 to 200
+    let pressure = variant.get_dop("Pressure").unwrap().compu_method.as_ref().unwrap();
+    assert_eq!(pressure.scales[0].lower.unwrap().interval, IntervalType::Infinite);
+    assert_eq!(pressure.scale(-10.0), Ok(ScaledValue::Number { value: -20.0, unit: None }));
+    assert_eq!(pressure.scale(99.0), Ok(ScaledValue::Number { value: 198.0, unit: None }));
+    assert_eq!(pressure.scale(100.0), Ok(ScaledValue::Number { value: 100.0, unit: None }));
+    assert_eq!(pressure.scale(200.0), Ok(ScaledValue::Number { value: 150.0, unit: None }));
+    assert!(pressure.scale(201.0).is_err());
+
+    let identical = CompuMethod { category: "IDENTICAL".into(), scales: vec![], default: None };
+    assert_eq!(identical.scale(42.0), Ok(ScaledValue::Number { value: 42.0, unit: None }));
+}
@@ -0,0 +1,291 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::Arc;
+use common::raf;
+use crate::caesar::CReader;
+use serde::*;
+
+thread_local! {
+    /// Pool references are resolved against whilst serializing, see [StringPool::resolve_refs]
+    static RESOLVING: RefCell<Option<StringPool>> = RefCell::new(None);
+}
+
+/// Reference to a string in a [StringPool]
+///
+/// Serializes as `{"Index": n}` or `{"Offset": n}`. Whilst a [ResolveGuard] is held, a `"text"` field
+/// with the string it points to (Or null for a dangling reference) is added, which is ignored when
+/// deserializing, so both forms deserialize back to the reference
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum StrRef {
+    /// Index of the string in the pool's offset table. This is what records refer to strings by
+    Index(u32),
+    /// Offset of the start of the string from the start of the pool's offset table
+    Offset(u32),
+}
+
+/// Serialized form of a [StrRef]
+#[derive(Serialize, Deserialize)]
+struct StrRefRepr {
+    #[serde(rename = "Index", skip_serializing_if = "Option::is_none", default)]
+    index: Option<u32>,
+    #[serde(rename = "Offset", skip_serializing_if = "Option::is_none", default)]
+    offset: Option<u32>,
+    /// Only present whilst resolving, null for a dangling reference
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    text: Option<Option<String>>,
+}
+
+impl Serialize for StrRef {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let text = RESOLVING.with(|pool| pool.borrow().as_ref().map(|pool| pool.get(*self).map(|s| s.to_string())));
+        let (index, offset) = match *self {
+            StrRef::Index(idx) => (Some(idx), None),
+            StrRef::Offset(offset) => (None, Some(offset)),
+        };
+        StrRefRepr { index, offset, text }.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for StrRef {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let repr = StrRefRepr::deserialize(deserializer)?;
+        match (repr.index, repr.offset) {
+            (Some(idx), None) => Ok(StrRef::Index(idx)),
+            (None, Some(offset)) => Ok(StrRef::Offset(offset)),
+            _ => Err(de::Error::custom("String reference needs exactly one of Index or Offset")),
+        }
+    }
+}
+
+/// While held, every [StrRef] serialized on this thread includes the text it points to.
+/// Dropping the guard (Including when unwinding from a panic) restores the previous state
+#[must_use]
+pub struct ResolveGuard {
+    prev: Option<StringPool>,
+}
+
+impl Drop for ResolveGuard {
+    fn drop(&mut self) {
+        let prev = self.prev.take();
+        RESOLVING.with(|pool| *pool.borrow_mut() = prev);
+    }
+}
+
+/// Storage of a [StringPool], shared between clones of it
+#[derive(Debug, Default)]
+struct PoolData {
+    /// Every distinct string, one after the other
+    text: String,
+    /// Start and end of each distinct string in `text`
+    spans: Vec<(u32, u32)>,
+    /// Distinct string of each index in the pool
+    indexes: Vec<u32>,
+    /// Distinct string at each offset in the pool
+    offsets: HashMap<u32, u32>,
+}
+
+/// Strings of a CBF language, which records refer to by their index in the pool.
+///
+/// Many entries in the pool hold the same text, so each distinct string is only stored once,
+/// and every reference to it resolves to the same `&str`. Clones share the same storage.
+/// Serialized as the list of strings in index order, so offsets are only kept for strings laid
+/// out as in [StringPool::from_strings]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(from = "Vec<String>", into = "Vec<String>")]
+pub struct StringPool {
+    data: Arc<PoolData>,
+}
+
+impl StringPool {
+    /// Reads a pool, which starts with a table of `count` offsets to each string (From the start
+    /// of the table), followed by the NUL terminated strings
+    pub fn read(reader: &mut raf::Raf, table_offset: usize, count: usize) -> Self {
+        let entries: Vec<(u32, String)> = (0..count)
+            .map(|i| {
+                reader.seek(table_offset + (i*4)).unwrap();
+                let offset = reader.read_i32().expect("Error reading String offset") as usize;
+                reader.seek(table_offset + offset).unwrap();
+                (offset as u32, CReader::read_string(reader))
+            })
+            .collect();
+        Self::from_entries(entries)
+    }
+
+    /// Creates a pool from each string and its offset, in index order
+    pub fn from_entries<I: IntoIterator<Item = (u32, String)>>(entries: I) -> Self {
+        let mut pool = PoolData::default();
+        let mut distinct: HashMap<String, u32> = HashMap::new();
+        for (offset, s) in entries {
+            let id = match distinct.get(&s) {
+                Some(id) => *id,
+                None => {
+                    let start = pool.text.len() as u32;
+                    pool.text.push_str(&s);
+                    pool.spans.push((start, pool.text.len() as u32));
+                    let id = pool.spans.len() as u32 - 1;
+                    distinct.insert(s, id);
+                    id
+                }
+            };
+            pool.indexes.push(id);
+            pool.offsets.insert(offset, id);
+        }
+        Self { data: Arc::new(pool) }
+    }
+
+    /// Creates a pool of strings laid out one after the other with NUL terminators,
+    /// starting at offset 0
+    pub fn from_strings(strings: Vec<String>) -> Self {
+        let mut offset = 0;
+        Self::from_entries(strings.into_iter().map(|s| {
+            let start = offset;
+            offset += s.len() as u32 + 1;
+            (start, s)
+        }))
+    }
+
+    /// Resolves a reference to its string
+    ///
+    /// # Returns
+    /// None if the reference does not point to a string in the pool
+    pub fn get(&self, reference: StrRef) -> Option<&str> {
+        let id = match reference {
+            StrRef::Index(idx) => self.data.indexes.get(idx as usize)?,
+            StrRef::Offset(offset) => self.data.offsets.get(&offset)?,
+        };
+        let (start, end) = self.data.spans[*id as usize];
+        Some(&self.data.text[start as usize..end as usize])
+    }
+
+    /// Returns a reference to the string at an index read from a record,
+    /// or None if there is no string at the index (Such as -1 for no string)
+    pub fn index(&self, idx: i32) -> Option<StrRef> {
+        if idx >= 0 && (idx as usize) < self.data.indexes.len() {
+            Some(StrRef::Index(idx as u32))
+        } else {
+            None
+        }
+    }
+
+    /// Number of strings in the pool, including repeats
+    pub fn len(&self) -> usize {
+        self.data.indexes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.indexes.is_empty()
+    }
+
+    /// Number of distinct strings stored
+    pub fn distinct_len(&self) -> usize {
+        self.data.spans.len()
+    }
+
+    /// Iterates over every string, in index order
+    pub fn iter(&self) -> impl Iterator<Item = &str> + '_ {
+        (0..self.len()).filter_map(move |i| self.get(StrRef::Index(i as u32)))
+    }
+
+    /// Makes every [StrRef] serialized on this thread include the text it points to in this pool,
+    /// until the returned guard is dropped, so records can be written out with their strings.
+    /// The pool's storage is shared with the guard, not copied
+    pub fn resolve_refs(&self) -> ResolveGuard {
+        let prev = RESOLVING.with(|pool| pool.replace(Some(self.clone())));
+        ResolveGuard { prev }
+    }
+}
+
+impl From<Vec<String>> for StringPool {
+    fn from(strings: Vec<String>) -> Self {
+        Self::from_strings(strings)
+    }
+}
+
+impl From<StringPool> for Vec<String> {
+    fn from(pool: StringPool) -> Self {
+        pool.iter().map(|s| s.to_string()).collect()
+    }
+}
+
+#[test]
+fn test_string_pool() {
+    // Offset table of 5 entries, with 2 entries pointing at the same text and a repeated string
+    let strings = ["Motor", "Getriebe", "Motor", "Drehzahl"];
+    let mut data = Vec::new();
+    let mut text = Vec::new();
+    let offset_of = |text: &Vec<u8>| 20 + text.len() as i32;
+    let mut offsets = Vec::new();
+    for s in &strings {
+        offsets.push(offset_of(&text));
+        text.extend_from_slice(s.as_bytes());
+        text.push(0x00);
+    }
+    offsets.push(offsets[1]);
+    offsets.iter().for_each(|o| data.extend_from_slice(&o.to_le_bytes()));
+    data.extend_from_slice(&text);
+
+    let mut reader = raf::Raf::from_bytes(&data, raf::RafByteOrder::LE);
+    let pool = StringPool::read(&mut reader, 0, 5);
+    assert_eq!(pool.len(), 5);
+    assert_eq!(pool.distinct_len(), 3);
+    assert_eq!(pool.get(StrRef::Index(0)), Some("Motor"));
+    assert_eq!(pool.get(StrRef::Index(3)), Some("Drehzahl"));
+    assert_eq!(pool.get(StrRef::Index(4)), Some("Getriebe"));
+    assert_eq!(pool.get(StrRef::Offset(20)), Some("Motor"));
+    assert_eq!(pool.get(StrRef::Offset(26)), Some("Getriebe"));
+    // Repeats share the same storage
+    assert!(std::ptr::eq(pool.get(StrRef::Index(0)).unwrap(), pool.get(StrRef::Index(2)).unwrap()));
+    assert_eq!(pool.iter().collect::<Vec<_>>(), vec!["Motor", "Getriebe", "Motor", "Drehzahl", "Getriebe"]);
+
+    // Dangling references
+    assert_eq!(pool.get(StrRef::Index(5)), None);
+    assert_eq!(pool.get(StrRef::Offset(21)), None);
+    assert_eq!(pool.get(StrRef::Offset(0)), None);
+    assert_eq!(pool.index(-1), None);
+    assert_eq!(pool.index(5), None);
+    assert_eq!(pool.index(3), Some(StrRef::Index(3)));
+
+    let pool = StringPool::from_strings(vec!["Name".into(), "Desc".into(), "Name".into()]);
+    assert_eq!(pool.distinct_len(), 2);
+    assert_eq!(pool.get(StrRef::Offset(5)), Some("Desc"));
+    assert_eq!(pool.get(StrRef::Offset(10)), Some("Name"));
+}
+
+#[test]
+fn test_serialize_resolved() {
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Record {
+        name_ctf: Option<StrRef>,
+        desc: Vec<StrRef>,
+    }
+    let pool = StringPool::from_strings(vec!["Motor".into(), "Getriebe".into()]);
+    let record = Record { name_ctf: Some(StrRef::Index(1)), desc: vec![StrRef::Offset(0), StrRef::Index(7)] };
+    let raw = serde_json::to_string(&record).unwrap();
+    assert_eq!(raw, r#"{"name_ctf":{"Index":1},"desc":[{"Offset":0},{"Index":7}]}"#);
+    let resolved = {
+        let _strings = pool.resolve_refs();
+        serde_json::to_string(&record).unwrap()
+    };
+    assert_eq!(resolved, r#"{"name_ctf":{"Index":1,"text":"Getriebe"},"desc":[{"Offset":0,"text":"Motor"},{"Index":7,"text":null}]}"#);
+    // Both forms deserialize back to the references
+    assert_eq!(serde_json::from_str::<Record>(&raw).unwrap(), record);
+    assert_eq!(serde_json::from_str::<Record>(&resolved).unwrap(), record);
+    assert!(serde_json::from_str::<StrRef>(r#"{"Index":1,"Offset":2}"#).is_err());
+
+    // Guard is released by a panic too
+    let res = std::panic::catch_unwind(|| {
+        let _strings = pool.resolve_refs();
+        panic!("Serializing failed")
+    });
+    assert!(res.is_err());
+    assert_eq!(serde_json::to_string(&record).unwrap(), raw);
+
+    // Clones share the pool's storage
+    let clone = pool.clone();
+    assert!(std::ptr::eq(pool.get(StrRef::Index(0)).unwrap(), clone.get(StrRef::Index(0)).unwrap()));
+
+    let json = serde_json::to_string(&pool).unwrap();
+    assert_eq!(json, r#"["Motor","Getriebe"]"#);
+    let pool: StringPool = serde_json::from_str(&json).unwrap();
+    assert_eq!(pool.get(StrRef::Index(1)), Some("Getriebe"));
+}
@@ -90,7 +90,7 @@ pub fn read_cbf_with_offset(memeber_index: i32, name: &StructureName, input: &[u
 
     let layout = name.get_layout();
     let mut reader  =Raf::from_bytes(&Vec::from(input), RafByteOrder::LE);
-    reader.seek(byte_offset);
+    reader.seek(byte_offset).unwrap();
     match layout[memeber_index as usize] {
         1 => reader.read_i8().unwrap() as i32,
         2 => reader.read_i16().unwrap() as i32,
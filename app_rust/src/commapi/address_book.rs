@@ -0,0 +1,80 @@
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use crate::commapi::protocols::auto_connect::{ConnectProfile, DiagProtocol};
+use crate::error::Result;
+
+/// Bus settings and CAN IDs to talk to one ECU with
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EcuAddress {
+    /// Name the ECU is looked up by. Example: `Engine`
+    pub name: String,
+    /// CAN ID requests are sent to
+    pub tx: u32,
+    /// CAN ID the ECU responds with
+    pub rx: u32,
+    /// True if the IDs are 29bit
+    #[serde(default)]
+    pub extended: bool,
+    pub protocol: DiagProtocol,
+    /// Speed of the CAN bus in bps
+    pub baud: u32,
+}
+
+impl EcuAddress {
+    pub fn to_profile(&self) -> ConnectProfile {
+        ConnectProfile::new(self.baud, self.extended, self.tx, self.rx, self.protocol)
+    }
+}
+
+/// Which CAN IDs and protocol each ECU in a vehicle uses, so ECUs can be connected to by name.
+/// Example books for common vehicles are in the `address_books` directory
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AddressBook {
+    /// Vehicle the book is for
+    pub vehicle: String,
+    pub ecus: Vec<EcuAddress>,
+}
+
+impl AddressBook {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    pub fn from_json(json: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(json)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        Ok(std::fs::write(path, self.to_json())?)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        Ok(Self::from_json(&text)?)
+    }
+
+    /// Looks up an ECU by name, ignoring case
+    pub fn get(&self, name: &str) -> Option<&EcuAddress> {
+        self.ecus.iter().find(|e| e.name.eq_ignore_ascii_case(name))
+    }
+}
+
+#[test]
+fn test_example_address_books() {
+    for json in &[
+        include_str!("../../address_books/generic_obd_11bit.json"),
+        include_str!("../../address_books/generic_obd_29bit.json"),
+        include_str!("../../address_books/generic_kwp2000_11bit.json"),
+    ] {
+        let book = AddressBook::from_json(json).unwrap();
+        assert!(!book.ecus.is_empty());
+        assert_eq!(AddressBook::from_json(&book.to_json()).unwrap(), book);
+        let max_id = |e: &EcuAddress| if e.extended { 0x1FFF_FFFF } else { 0x7FF };
+        assert!(book.ecus.iter().all(|e| e.tx <= max_id(e) && e.rx <= max_id(e)), "{}", book.vehicle);
+    }
+    let book = AddressBook::from_json(include_str!("../../address_books/generic_obd_29bit.json")).unwrap();
+    let engine = book.get("engine").unwrap();
+    assert_eq!((engine.tx, engine.rx), (0x18DA10F1, 0x18DAF110));
+    assert_eq!(engine.to_profile().to_string(), "Uds at 500kbps, 29bit 0x18DA10F1 -> 0x18DAF110");
+    assert!(book.get("Radio").is_none());
+}
@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use crate::commapi::pcap::CapturedFrame;
+
+/// Traffic of a single CAN ID
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct IdStats {
+    pub id: u32,
+    /// Number of frames seen in the window
+    pub count: usize,
+    pub frames_per_sec: f64,
+}
+
+/// Traffic statistics over a time window
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct BusStats {
+    /// Number of frames seen in the window
+    pub frame_count: usize,
+    pub frames_per_sec: f64,
+    /// Estimated percentage of the bus bandwidth used
+    pub bus_load_pct: f64,
+    /// Traffic of each ID, busiest first
+    pub per_id: Vec<IdStats>,
+}
+
+impl BusStats {
+    /// Returns the `n` IDs which sent the most frames
+    pub fn top_talkers(&self, n: usize) -> &[IdStats] {
+        &self.per_id[..std::cmp::min(n, self.per_id.len())]
+    }
+}
+
+/// Estimates the number of bits a frame occupies on the wire, including the inter frame space.
+///
+/// Stuff bits are estimated as the worst case (One every 4 bits of the stuffed region), and
+/// CAN-FD frames are treated as if the whole frame was sent at the nominal bitrate, so the
+/// result is an upper bound
+pub fn frame_bits(f: &CapturedFrame) -> usize {
+    let data_bits = f.frame.get_len() * 8;
+    // SOF to end of CRC, which is the region that gets bit stuffed
+    let stuffed = if f.extended { 54 } else { 34 } + data_bits;
+    // CRC delimiter, ACK, EOF and IFS
+    stuffed + (stuffed - 1) / 4 + 13
+}
+
+/// Computes statistics over the frames received within `window` of the newest frame
+///
+/// # Params
+/// * frames - Captured frames, oldest first
+/// * window - Time span to compute the statistics over
+/// * baud - Nominal bitrate of the bus
+pub fn compute_stats<'a, I: IntoIterator<Item = &'a CapturedFrame>>(frames: I, window: Duration, baud: u32) -> BusStats {
+    let frames: Vec<&CapturedFrame> = frames.into_iter().collect();
+    let last = match frames.last() {
+        Some(f) => f.timestamp_us,
+        None => return BusStats::default()
+    };
+    let window_us = window.as_micros() as u64;
+    let secs = window.as_secs_f64();
+    if secs == 0.0 {
+        return BusStats::default()
+    }
+
+    let mut counts: HashMap<u32, usize> = HashMap::new();
+    let mut frame_count = 0;
+    let mut bits = 0;
+    for f in frames.iter().rev().take_while(|f| last - f.timestamp_us < window_us) {
+        *counts.entry(f.frame.id).or_insert(0) += 1;
+        frame_count += 1;
+        bits += frame_bits(f);
+    }
+    let mut per_id: Vec<IdStats> = counts.into_iter()
+        .map(|(id, count)| IdStats { id, count, frames_per_sec: count as f64 / secs })
+        .collect();
+    // Busiest first, ties in ID order so the list does not jump around
+    per_id.sort_by(|a, b| b.count.cmp(&a.count).then(a.id.cmp(&b.id)));
+    BusStats {
+        frame_count,
+        frames_per_sec: frame_count as f64 / secs,
+        bus_load_pct: bits as f64 / (secs * baud as f64) * 100.0,
+        per_id,
+    }
+}
+
+#[test]
+fn test_compute_stats() {
+    use crate::commapi::comm_api::CanFrame;
+    // 0x100 every 10ms, 0x200 every 100ms, 0x300 once, over 2 seconds
+    let mut frames = Vec::new();
+    for ms in 0..2000u64 {
+        if ms % 10 == 0 {
+            frames.push(CapturedFrame::new(ms * 1000, CanFrame::new(0x100, &[0; 8])));
+        }
+        if ms % 100 == 0 {
+            frames.push(CapturedFrame::new(ms * 1000, CanFrame::new(0x200, &[0; 8])));
+        }
+        if ms == 1500 {
+            frames.push(CapturedFrame::new(ms * 1000, CanFrame::new(0x300, &[0; 2])));
+        }
+    }
+    let stats = compute_stats(&frames, Duration::from_secs(1), 500_000);
+    assert_eq!(stats.frame_count, 111);
+    assert_eq!(stats.frames_per_sec, 111.0);
+    assert_eq!(stats.per_id, vec![
+        IdStats { id: 0x100, count: 100, frames_per_sec: 100.0 },
+        IdStats { id: 0x200, count: 10, frames_per_sec: 10.0 },
+        IdStats { id: 0x300, count: 1, frames_per_sec: 1.0 },
+    ]);
+    assert_eq!(stats.top_talkers(1)[0].id, 0x100);
+    assert_eq!(stats.top_talkers(10).len(), 3);
+
+    // 8 byte standard frame is 98 + 24 stuff bits, 2 byte frame is 50 + 12
+    assert_eq!(frame_bits(&frames[0]), 135);
+    let load = (110.0 * 135.0 + 75.0) / 500_000.0 * 100.0;
+    assert!((stats.bus_load_pct - load).abs() < 1e-9);
+
+    assert_eq!(compute_stats(&[], Duration::from_secs(1), 500_000), BusStats::default());
+}
@@ -0,0 +1,538 @@
+use std::sync::Arc;
+use std::time::Duration;
+use crate::commapi::comm_api::{ComServer, CanFrame, CanIdFilter, ComServerError, FilterType, ERR_NOT_SUPPORTED};
+use crate::commapi::connection::{ConnectionEvent, ConnectionObserver, ConnectionObservers};
+
+/// How a [CanChannel] tries to recover when the adapter is lost
+#[derive(Debug, Copy, Clone)]
+pub struct ReconnectPolicy {
+    /// Number of times to try re-opening the adapter. 0 disables reconnecting
+    pub max_attempts: u32,
+    /// Delay before the first attempt, doubled after each failed attempt
+    pub initial_backoff_ms: u64,
+    /// Maximum delay between attempts
+    pub max_backoff_ms: u64,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff_ms: 250,
+            max_backoff_ms: 2000,
+        }
+    }
+}
+
+/// Reported to the reconnect callback of a [CanChannel]
+#[derive(Debug, Clone)]
+pub enum ReconnectEvent {
+    /// Adapter was lost, starting reconnect attempt N (Starting at 1)
+    Attempt(u32),
+    /// Adapter was re-opened and the channel has been reconfigured
+    Reconnected,
+    /// Every attempt failed, the channel is no longer usable
+    Failed(ComServerError),
+}
+
+type ReconnectCallback = Arc<dyn Fn(&ReconnectEvent) + Send + Sync>;
+
+/// Time to wait for a looped back frame, or for an ECU to respond, during [CanChannel::self_test]
+const SELF_TEST_TIMEOUT_MS: u64 = 200;
+
+/// Outcome of one check done by [CanChannel::self_test]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CheckResult {
+    Passed,
+    /// Check failed, with why
+    Failed(String),
+    /// Check could not be done, with why. Example: the adapter does not support loopback
+    Skipped(String),
+}
+
+/// Results of [CanChannel::self_test]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelfTestReport {
+    /// CAN controller is not reporting errors
+    pub bus_state: CheckResult,
+    /// A frame sent with loopback enabled was received back
+    pub loopback: CheckResult,
+    /// At least one ECU responded to a functionally addressed TesterPresent request
+    pub ecu_response: CheckResult,
+}
+
+impl SelfTestReport {
+    /// Every check, with the name it is shown by
+    pub fn checks(&self) -> [(&'static str, &CheckResult); 3] {
+        [("Bus state", &self.bus_state), ("Loopback", &self.loopback), ("ECU response", &self.ecu_response)]
+    }
+
+    /// True if no check failed. Skipped checks do not count as failures
+    pub fn passed(&self) -> bool {
+        self.checks().iter().all(|(_, r)| !matches!(r, CheckResult::Failed(_)))
+    }
+}
+
+/// Turns the outcome of a check into a [CheckResult]. Errors from the CAN controller fail the
+/// check, any other error (Example: the adapter was lost) is returned
+fn check_result(res: Result<bool, ComServerError>, fail_msg: &str) -> Result<CheckResult, ComServerError> {
+    match res {
+        Ok(true) => Ok(CheckResult::Passed),
+        Ok(false) => Ok(CheckResult::Failed(fail_msg.into())),
+        Err(e) => match e.can_error() {
+            Some(c) => Ok(CheckResult::Failed(c.to_string())),
+            None => Err(e)
+        }
+    }
+}
+
+/// Raw CAN channel on an adapter, with receive filtering.
+///
+/// Filters are programmed into the adapter where possible so unwanted frames never reach
+/// the PC. If the adapter rejects a filter (Unsupported, or too many filters), the channel
+/// falls back to receiving everything and filtering in software.
+///
+/// Channels created with [open](fn@open) remember their bus settings, so if the adapter
+/// drops off USB mid session, the channel re-opens it according to its [ReconnectPolicy],
+/// re-applies the bus settings and filters, then retries the failed operation.
+///
+/// Errors reported by the adapter's CAN controller (Bus off, error frames) are returned as is,
+/// use [ComServerError::can_error](fn@ComServerError::can_error) to tell them apart.
+pub struct CanChannel {
+    server: Box<dyn ComServer>,
+    hw_filter_ids: Vec<u32>,
+    sw_filters: Vec<CanIdFilter>,
+    hw_filtering: bool,
+    /// Baud rate and extended addressing the interface was opened with
+    bus_cfg: Option<(u32, bool)>,
+    policy: ReconnectPolicy,
+    on_reconnect: Option<ReconnectCallback>,
+    observers: ConnectionObservers,
+}
+
+impl std::fmt::Debug for CanChannel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CanChannel")
+            .field("server", &self.server)
+            .field("sw_filters", &self.sw_filters)
+            .field("hw_filtering", &self.hw_filtering)
+            .field("bus_cfg", &self.bus_cfg)
+            .finish()
+    }
+}
+
+impl CanChannel {
+    /// Creates a channel over a comm server which already has an open CAN interface.
+    /// No frames are received until [set_filter](fn@set_filter) is called.
+    ///
+    /// The bus settings are not known, so the channel cannot reconnect if the adapter is lost
+    pub fn new(server: Box<dyn ComServer>) -> Self {
+        Self {
+            server,
+            hw_filter_ids: Vec::new(),
+            sw_filters: Vec::new(),
+            hw_filtering: false,
+            bus_cfg: None,
+            policy: ReconnectPolicy::default(),
+            on_reconnect: None,
+            observers: ConnectionObservers::new(),
+        }
+    }
+
+    /// Opens the CAN interface on the adapter and creates a channel over it
+    ///
+    /// # Params
+    /// * bus_speed - Baud rate of the CAN network
+    /// * is_ext_can - Use 29 bit CAN IDs
+    pub fn open(mut server: Box<dyn ComServer>, bus_speed: u32, is_ext_can: bool) -> Result<Self, ComServerError> {
+        server.open_can_interface(bus_speed, is_ext_can)?;
+        let mut channel = Self::new(server);
+        channel.bus_cfg = Some((bus_speed, is_ext_can));
+        Ok(channel)
+    }
+
+    pub fn set_reconnect_policy(&mut self, policy: ReconnectPolicy) {
+        self.policy = policy
+    }
+
+    /// Sets a function which is called on every reconnect attempt, so the user can be told
+    /// why the session has paused
+    pub fn set_reconnect_callback<F: Fn(&ReconnectEvent) + Send + Sync + 'static>(&mut self, f: F) {
+        self.on_reconnect = Some(Arc::new(f))
+    }
+
+    /// Registers an observer which is told about reconnect attempts, and whether they succeeded
+    pub fn add_observer(&self, observer: Arc<dyn ConnectionObserver>) {
+        self.observers.add(observer)
+    }
+
+    pub fn get_server(&self) -> &dyn ComServer {
+        self.server.as_ref()
+    }
+
+    /// Returns true if the active filters are being done by the adapter
+    pub fn is_hw_filtering(&self) -> bool {
+        self.hw_filtering
+    }
+
+    fn clear_hw_filters(&mut self) {
+        for idx in self.hw_filter_ids.drain(..) {
+            if let Err(e) = self.server.rem_can_filter(idx) {
+                eprintln!("Cannot remove CAN filter {} {}", idx, e)
+            }
+        }
+    }
+
+    /// Replaces the channel's receive filters. Only frames matching at least one of the
+    /// filters will be returned by [recv](fn@recv)
+    ///
+    /// # Params
+    /// * filters - List of ID / Mask pairs to accept
+    pub fn set_filter(&mut self, filters: &[CanIdFilter]) -> Result<(), ComServerError> {
+        self.clear_hw_filters();
+        self.sw_filters = Vec::from(filters);
+        for f in filters {
+            match self.server.add_can_filter(FilterType::Pass, f.id, f.mask) {
+                Ok(idx) => self.hw_filter_ids.push(idx),
+                Err(_) => {
+                    // Adapter can't do it, accept everything and filter in software
+                    self.clear_hw_filters();
+                    self.hw_filtering = false;
+                    if let Ok(idx) = self.server.add_can_filter(FilterType::Pass, 0, 0) {
+                        self.hw_filter_ids.push(idx)
+                    }
+                    return Ok(())
+                }
+            }
+        }
+        self.hw_filtering = true;
+        Ok(())
+    }
+
+    fn notify(&self, event: ReconnectEvent) {
+        if let Some(cb) = &self.on_reconnect {
+            cb(&event)
+        }
+        self.observers.notify(match event {
+            ReconnectEvent::Attempt(n) => ConnectionEvent::ReconnectAttempt(n),
+            ReconnectEvent::Reconnected => ConnectionEvent::Connected,
+            ReconnectEvent::Failed(_) => ConnectionEvent::Disconnected,
+        })
+    }
+
+    /// Re-opens the adapter and restores the bus settings and filters
+    fn reopen(&mut self, bus_speed: u32, is_ext_can: bool) -> Result<(), ComServerError> {
+        let _ = self.server.close_device(); // Handle might already be invalid
+        self.server.open_device()?;
+        self.server.open_can_interface(bus_speed, is_ext_can)?;
+        // Old filter IDs died with the device
+        self.hw_filter_ids.clear();
+        let filters = self.sw_filters.clone();
+        self.set_filter(&filters)
+    }
+
+    fn reconnect(&mut self, err: ComServerError) -> Result<(), ComServerError> {
+        let (bus_speed, is_ext_can) = match self.bus_cfg {
+            Some(cfg) if self.policy.max_attempts > 0 => cfg,
+            _ => return Err(err)
+        };
+        let mut backoff = self.policy.initial_backoff_ms;
+        let mut last_err = err;
+        for attempt in 1..=self.policy.max_attempts {
+            self.notify(ReconnectEvent::Attempt(attempt));
+            std::thread::sleep(Duration::from_millis(backoff));
+            match self.reopen(bus_speed, is_ext_can) {
+                Ok(()) => {
+                    self.notify(ReconnectEvent::Reconnected);
+                    return Ok(())
+                },
+                Err(e) => last_err = e
+            }
+            backoff = std::cmp::min(backoff * 2, self.policy.max_backoff_ms);
+        }
+        self.notify(ReconnectEvent::Failed(last_err.clone()));
+        Err(last_err)
+    }
+
+    /// Runs an operation on the adapter, reconnecting and retrying once if the adapter was lost
+    fn with_reconnect<T, F: Fn(&dyn ComServer) -> Result<T, ComServerError>>(&mut self, f: F) -> Result<T, ComServerError> {
+        match f(self.server.as_ref()) {
+            Err(e) if e.is_device_lost() => {
+                self.reconnect(e)?;
+                f(self.server.as_ref())
+            },
+            res => res
+        }
+    }
+
+    /// Restarts the CAN controller after the channel has returned [CanError::BusOff](crate::commapi::comm_api::CanError::BusOff),
+    /// and drops any frames received before the bus went off
+    pub fn recover_from_bus_off(&mut self) -> Result<(), ComServerError> {
+        self.server.recover_from_bus_off()?;
+        self.server.clear_can_rx_buffer()
+    }
+
+    /// Sends frames to the CAN network. See [ComServer::send_can_packets](fn@ComServer::send_can_packets)
+    pub fn send(&mut self, frames: &[CanFrame], timeout_ms: u32) -> Result<usize, ComServerError> {
+        self.with_reconnect(|s| s.send_can_packets(frames, timeout_ms))
+    }
+
+    /// Sends a burst of frames, given as (CAN ID, data, CAN-FD) tuples. CAN-FD frames are
+    /// sent without bit rate switching. See [send_batch](fn@CanChannel::send_batch)
+    pub fn send_frames(&mut self, frames: &[(u32, Vec<u8>, bool)]) -> Result<usize, ComServerError> {
+        let frames: Vec<CanFrame> = frames.iter().map(|(id, data, fd)| {
+            if *fd { CanFrame::new_fd(*id, data, false) } else { CanFrame::new(*id, data) }
+        }).collect();
+        self.send_batch(&frames)
+    }
+
+    /// Sends a burst of frames, in one call to the adapter if it
+    /// [supports it](fn@ComServer::supports_batched_send), otherwise one frame at a time.
+    ///
+    /// # Returns
+    /// The number of frames sent. If sending one frame at a time fails part way through,
+    /// the frames sent before the failure are counted rather than an error being returned
+    pub fn send_batch(&mut self, frames: &[CanFrame]) -> Result<usize, ComServerError> {
+        if self.server.supports_batched_send() {
+            return self.send(frames, 0)
+        }
+        let mut sent = 0;
+        for f in frames {
+            match self.send(std::slice::from_ref(f), 0) {
+                Ok(_) => sent += 1,
+                Err(e) if sent == 0 => return Err(e),
+                Err(_) => break
+            }
+        }
+        Ok(sent)
+    }
+
+    /// Checks the adapter and bus are working, before starting anything which should not be
+    /// interrupted (Example: flashing). Frames received before the test are dropped.
+    ///
+    /// The checks are, in order:
+    /// 1. The CAN controller is not reporting errors. If it is, the other checks are skipped
+    /// 2. A frame sent with loopback enabled is received back, if the adapter supports loopback.
+    ///    The frame is a functionally addressed TesterPresent with no response requested, which ECUs ignore
+    /// 3. If `ping_ecus` is set, at least one ECU responds to a functionally addressed TesterPresent
+    ///    (0x7DF, or 0x18DB33F1 on a 29bit channel)
+    ///
+    /// The channel's filters are restored afterwards
+    ///
+    /// # Returns
+    /// The result of each check, or an error if the adapter could not be used at all
+    pub fn self_test(&mut self, ping_ecus: bool) -> Result<SelfTestReport, ComServerError> {
+        let ext_can = self.bus_cfg.map(|(_, ext)| ext).unwrap_or(false);
+        let (func_id, resp_filter) = if ext_can {
+            (0x18DB_33F1, CanIdFilter::new(0x18DA_F100, 0x1FFF_FF00))
+        } else {
+            (0x07DF, CanIdFilter::new(0x07E8, 0x07F8))
+        };
+        let filters = self.sw_filters.clone();
+        self.set_filter(&[CanIdFilter::exact(func_id), resp_filter])?;
+        let res = self.run_self_test(func_id, resp_filter, ping_ecus);
+        self.set_filter(&filters)?;
+        res
+    }
+
+    fn run_self_test(&mut self, func_id: u32, resp_filter: CanIdFilter, ping_ecus: bool) -> Result<SelfTestReport, ComServerError> {
+        let bus_state = check_result(self.recv(0, 64).map(|_| true), "")?;
+        if bus_state != CheckResult::Passed {
+            let skipped = CheckResult::Skipped("CAN bus is not usable".into());
+            return Ok(SelfTestReport { bus_state, loopback: skipped.clone(), ecu_response: skipped })
+        }
+
+        let probe = CanFrame::new(func_id, &[0x02, 0x3E, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        let loopback = match self.server.set_can_loopback(true) {
+            Err(e) if e.err_code == ERR_NOT_SUPPORTED => CheckResult::Skipped("Adapter does not support loopback".into()),
+            Err(e) => return Err(e),
+            Ok(()) => {
+                let res = self.send(&[probe], 0)
+                    .and_then(|_| self.wait_for_frame(|f| f.id == func_id && f.get_data() == probe.get_data()));
+                self.server.set_can_loopback(false)?;
+                check_result(res, "Frame sent was not received back. Check the adapter is connected to a powered bus")?
+            }
+        };
+
+        let ecu_response = if ping_ecus {
+            let res = self.send(&[CanFrame::new(func_id, &[0x02, 0x3E, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00])], 0)
+                .and_then(|_| self.wait_for_frame(|f| resp_filter.matches(f.id)));
+            check_result(res, "No ECU responded to TesterPresent")?
+        } else {
+            CheckResult::Skipped("Not requested".into())
+        };
+        Ok(SelfTestReport { bus_state, loopback, ecu_response })
+    }
+
+    /// Reads frames until one matches, for up to [SELF_TEST_TIMEOUT_MS]
+    fn wait_for_frame<F: Fn(&CanFrame) -> bool>(&mut self, f: F) -> Result<bool, ComServerError> {
+        let start = std::time::Instant::now();
+        while start.elapsed() < Duration::from_millis(SELF_TEST_TIMEOUT_MS) {
+            if self.recv(0, 64)?.iter().any(&f) {
+                return Ok(true)
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        Ok(false)
+    }
+
+    /// Reads frames matching the channel's filters
+    pub fn recv(&mut self, timeout_ms: u32, max_msgs: usize) -> Result<Vec<CanFrame>, ComServerError> {
+        let frames = self.with_reconnect(|s| s.read_can_packets(timeout_ms, max_msgs))?;
+        if self.hw_filtering {
+            Ok(frames)
+        } else {
+            Ok(frames.into_iter().filter(|f| self.sw_filters.iter().any(|x| x.matches(f.id))).collect())
+        }
+    }
+}
+
+impl Drop for CanChannel {
+    fn drop(&mut self) {
+        self.clear_hw_filters()
+    }
+}
+
+#[cfg(test)]
+fn push_test_frames(mock: &crate::commapi::mock_api::MockComServer) {
+    for id in &[0x0100, 0x07E8, 0x0200, 0x07E9, 0x0300] {
+        mock.push_rx(CanFrame::new(*id, &[0x00]));
+    }
+}
+
+#[test]
+fn test_hw_filter() {
+    let mock = crate::commapi::mock_api::MockComServer::new();
+    push_test_frames(&mock);
+    let mut channel = CanChannel::new(Box::new(mock.clone()));
+    channel.set_filter(&[CanIdFilter::new(0x07E8, 0x1FFF_FFF0)]).unwrap();
+    assert!(channel.is_hw_filtering());
+    assert_eq!(mock.get_filters().len(), 1);
+    let ids: Vec<u32> = channel.recv(0, 10).unwrap().iter().map(|f| f.id).collect();
+    assert_eq!(ids, vec![0x07E8, 0x07E9]);
+}
+
+#[test]
+fn test_sw_filter_fallback() {
+    let mut mock = crate::commapi::mock_api::MockComServer::new();
+    mock.no_hw_filters = true;
+    push_test_frames(&mock);
+    let mut channel = CanChannel::new(Box::new(mock));
+    channel.set_filter(&[CanIdFilter::exact(0x0200), CanIdFilter::exact(0x0300)]).unwrap();
+    assert!(!channel.is_hw_filtering());
+    let ids: Vec<u32> = channel.recv(0, 10).unwrap().iter().map(|f| f.id).collect();
+    assert_eq!(ids, vec![0x0200, 0x0300]);
+}
+
+#[test]
+fn test_reconnect_after_device_lost() {
+    let mock = crate::commapi::mock_api::MockComServer::new();
+    let mut channel = CanChannel::open(Box::new(mock.clone()), 500_000, false).unwrap();
+    channel.set_filter(&[CanIdFilter::exact(0x07E8)]).unwrap();
+    channel.set_reconnect_policy(ReconnectPolicy { max_attempts: 3, initial_backoff_ms: 1, max_backoff_ms: 5 });
+    let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let events_t = events.clone();
+    channel.set_reconnect_callback(move |e| events_t.lock().unwrap().push(format!("{:?}", e)));
+    let conn_events = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let conn_events_t = conn_events.clone();
+    channel.add_observer(Arc::new(move |e: &ConnectionEvent| conn_events_t.lock().unwrap().push(e.clone())));
+
+    // Adapter drops off, and the first attempt to re-open it fails
+    mock.simulate_device_lost(1);
+    push_test_frames(&mock);
+    let ids: Vec<u32> = channel.recv(0, 10).unwrap().iter().map(|f| f.id).collect();
+    assert_eq!(ids, vec![0x07E8]);
+    assert_eq!(mock.get_filters().len(), 1);
+    assert_eq!(*events.lock().unwrap(), vec!["Attempt(1)", "Attempt(2)", "Reconnected"]);
+    assert_eq!(*conn_events.lock().unwrap(), vec![ConnectionEvent::ReconnectAttempt(1), ConnectionEvent::ReconnectAttempt(2), ConnectionEvent::Connected]);
+
+    // Never comes back
+    mock.simulate_device_lost(10);
+    assert!(channel.send(&[CanFrame::new(0x07E0, &[0x00])], 0).unwrap_err().is_device_lost());
+    assert!(events.lock().unwrap().last().unwrap().starts_with("Failed"));
+    assert_eq!(conn_events.lock().unwrap().last(), Some(&ConnectionEvent::Disconnected));
+}
+
+#[test]
+fn test_bus_off() {
+    use crate::commapi::comm_api::CanError;
+    let mock = crate::commapi::mock_api::MockComServer::new();
+    let mut channel = CanChannel::open(Box::new(mock.clone()), 500_000, false).unwrap();
+    channel.set_filter(&[CanIdFilter::exact(0x07E8)]).unwrap();
+
+    // Error frames are reported once
+    mock.simulate_can_error(Some(CanError::ErrorFrame));
+    assert_eq!(channel.recv(0, 10).unwrap_err().can_error(), Some(CanError::ErrorFrame));
+    assert!(channel.recv(0, 10).is_ok());
+
+    mock.simulate_can_error(Some(CanError::BusOff));
+    assert_eq!(channel.send(&[CanFrame::new(0x07E0, &[0x00])], 0).unwrap_err().can_error(), Some(CanError::BusOff));
+    assert_eq!(channel.recv(0, 10).unwrap_err().can_error(), Some(CanError::BusOff));
+    // Frames from before the bus went off are dropped
+    push_test_frames(&mock);
+    channel.recover_from_bus_off().unwrap();
+    let ids: Vec<u32> = channel.recv(0, 10).unwrap().iter().map(|f| f.id).collect();
+    assert!(ids.is_empty());
+    mock.push_rx(CanFrame::new(0x07E8, &[0x01]));
+    assert_eq!(channel.recv(0, 10).unwrap().len(), 1);
+}
+
+#[test]
+fn test_send_frames() {
+    let frames: Vec<(u32, Vec<u8>, bool)> = (0..5u8).map(|i| (0x07E0, vec![0x21 + i, i], false))
+        .chain(std::iter::once((0x07E0, (0..24).collect(), true)))
+        .collect();
+    for batched in &[true, false] {
+        let mut mock = crate::commapi::mock_api::MockComServer::new();
+        mock.no_batched_send = !batched;
+        let mut channel = CanChannel::open(Box::new(mock.clone()), 500_000, false).unwrap();
+        assert_eq!(channel.send_frames(&frames).unwrap(), 6);
+        let log = mock.get_tx_log();
+        assert_eq!(log.len(), 6);
+        for (sent, (id, data, fd)) in log.iter().zip(frames.iter()) {
+            assert_eq!(sent.id, *id);
+            assert_eq!(sent.get_data(), data.as_slice());
+            assert_eq!(sent.fd, *fd);
+        }
+        assert_eq!(mock.get_send_calls(), if *batched { 1 } else { 6 });
+    }
+    assert_eq!(CanChannel::new(Box::new(crate::commapi::mock_api::MockComServer::new())).send_frames(&[]).unwrap(), 0);
+}
+
+#[test]
+fn test_self_test() {
+    use crate::commapi::mock_api::MockComServer;
+    use crate::commapi::comm_api::CanError;
+    let mut mock = MockComServer::new();
+    mock.set_responder(|f| match (f.id, f.get_data()) {
+        (0x07DF, [0x02, 0x3E, 0x00, ..]) => vec![CanFrame::new(0x07E9, &[0x02, 0x7E, 0x00])],
+        _ => vec![]
+    });
+    let mut channel = CanChannel::open(Box::new(mock.clone()), 500_000, false).unwrap();
+    channel.set_filter(&[CanIdFilter::exact(0x0100)]).unwrap();
+    let report = channel.self_test(true).unwrap();
+    assert_eq!(report, SelfTestReport { bus_state: CheckResult::Passed, loopback: CheckResult::Passed, ecu_response: CheckResult::Passed });
+    assert!(report.passed());
+    assert_eq!(mock.get_filters(), vec![CanIdFilter::exact(0x0100)]);
+    // Loopback was turned off again
+    channel.send(&[CanFrame::new(0x0100, &[0x00])], 0).unwrap();
+    assert!(channel.recv(0, 10).unwrap().is_empty());
+
+    // Adapter is not on a working bus, so nothing is looped back and no ECU answers
+    let mut mock = MockComServer::new();
+    mock.broken_loopback = true;
+    let mut channel = CanChannel::open(Box::new(mock), 500_000, false).unwrap();
+    let report = channel.self_test(true).unwrap();
+    assert_eq!(report.bus_state, CheckResult::Passed);
+    assert!(matches!(report.loopback, CheckResult::Failed(_)));
+    assert!(matches!(report.ecu_response, CheckResult::Failed(_)));
+    assert!(!report.passed());
+    assert_eq!(report.checks()[1].0, "Loopback");
+
+    let mock = MockComServer::new();
+    mock.simulate_can_error(Some(CanError::BusOff));
+    let mut channel = CanChannel::open(Box::new(mock), 500_000, false).unwrap();
+    let report = channel.self_test(false).unwrap();
+    assert_eq!(report.bus_state, CheckResult::Failed(CanError::BusOff.to_string()));
+    assert!(matches!(report.loopback, CheckResult::Skipped(_)));
+}
@@ -5,24 +5,103 @@ use std::fmt::Debug;
 use std::fmt;
 use std::time::Instant;
 
-#[derive(Debug, Copy, Clone, Default)]
+/// Payload lengths that each CAN-FD DLC code maps to. DLC values 0-8 are identical
+/// to classic CAN, anything above that is only valid on a CAN-FD bus
+pub const CAN_FD_DLC_LENGTHS: [usize; 16] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 12, 16, 20, 24, 32, 48, 64];
+
+/// Maximum payload size of a classic CAN frame
+pub const CAN_MAX_DATA_LEN: usize = 8;
+
+/// Maximum payload size of a CAN-FD frame
+pub const CAN_FD_MAX_DATA_LEN: usize = 64;
+
+/// Converts a DLC code into the number of bytes the frame carries
+pub fn dlc_to_len(dlc: u8) -> usize {
+    CAN_FD_DLC_LENGTHS[min(dlc as usize, 15)]
+}
+
+/// Converts a payload length into the smallest DLC code that can hold it.
+/// Lengths above 64 bytes are clamped to DLC 15
+pub fn len_to_dlc(len: usize) -> u8 {
+    CAN_FD_DLC_LENGTHS.iter().position(|x| *x >= len).unwrap_or(15) as u8
+}
+
+#[derive(Debug, Copy, Clone)]
 pub struct CanFrame {
     pub id: u32,
+    /// DLC code of the frame. On classic CAN this is the number of bytes in the frame,
+    /// on CAN-FD it should be converted with [dlc_to_len](fn@dlc_to_len)
     pub dlc: u8,
-    data: [u8; 8]
+    /// Flexible data rate frame (Up to 64 bytes)
+    pub fd: bool,
+    /// Bit rate switch - Data phase of an FD frame is transmitted at the higher bitrate
+    pub brs: bool,
+    /// Time the adapter received the frame, in microseconds. This is in the adapter's own clock
+    /// domain, see [timestamp_rollover_us](fn@ComServer::timestamp_rollover_us).
+    /// None if the backend does not timestamp frames, or for frames being sent
+    pub timestamp_us: Option<u64>,
+    data: [u8; CAN_FD_MAX_DATA_LEN]
+}
+
+impl Default for CanFrame {
+    fn default() -> Self {
+        Self {
+            id: 0,
+            dlc: 0,
+            fd: false,
+            brs: false,
+            timestamp_us: None,
+            data: [0; CAN_FD_MAX_DATA_LEN]
+        }
+    }
 }
 
 impl CanFrame {
     pub fn get_data(&self) -> &[u8] {
-        &self.data[0..self.dlc as usize]
+        &self.data[0..self.get_len()]
     }
+
+    /// Returns the number of data bytes in the frame
+    pub fn get_len(&self) -> usize {
+        if self.fd {
+            dlc_to_len(self.dlc)
+        } else {
+            min(self.dlc as usize, CAN_MAX_DATA_LEN)
+        }
+    }
+
+    /// Creates a classic CAN frame. Data over 8 bytes is truncated
     pub fn new(id: u32, data: &[u8]) -> Self {
-        let dlc = min(data.len(), 8) as usize;
-        let mut can_data: [u8; 8] = [0; 8];
+        let dlc = min(data.len(), CAN_MAX_DATA_LEN) as usize;
+        let mut can_data = [0u8; CAN_FD_MAX_DATA_LEN];
         can_data[0..dlc].copy_from_slice(&data[0..dlc]);
         Self {
             id,
             dlc: dlc as u8,
+            fd: false,
+            brs: false,
+            timestamp_us: None,
+            data: can_data
+        }
+    }
+
+    /// Creates a CAN-FD frame. Data over 64 bytes is truncated. If the data length
+    /// is not a valid FD length, the frame is padded with 0x00 up to the next valid length
+    ///
+    /// ## Params
+    /// * id - CAN ID of the frame
+    /// * data - Payload of the frame
+    /// * brs - Should the data phase be sent at the faster bitrate
+    pub fn new_fd(id: u32, data: &[u8], brs: bool) -> Self {
+        let len = min(data.len(), CAN_FD_MAX_DATA_LEN);
+        let mut can_data = [0u8; CAN_FD_MAX_DATA_LEN];
+        can_data[0..len].copy_from_slice(&data[0..len]);
+        Self {
+            id,
+            dlc: len_to_dlc(len),
+            fd: true,
+            brs,
+            timestamp_us: None,
             data: can_data
         }
     }
@@ -32,7 +111,11 @@ unsafe impl Sync for CanFrame{}
 
 impl std::fmt::Display for CanFrame {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "ID: 0x{:04X} Data: {:02X?}", self.id, &self.data[0..self.dlc as usize])
+        if self.fd {
+            write!(f, "ID: 0x{:04X} (FD) Data: {:02X?}", self.id, self.get_data())
+        } else {
+            write!(f, "ID: 0x{:04X} Data: {:02X?}", self.id, self.get_data())
+        }
     }
 }
 
@@ -70,18 +153,116 @@ pub enum FilterType {
     Block
 }
 
-#[derive(Debug, Clone)]
+/// CAN ID filter. A frame matches if `frame_id & mask == id & mask`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CanIdFilter {
+    pub id: u32,
+    pub mask: u32,
+}
+
+impl CanIdFilter {
+    pub fn new(id: u32, mask: u32) -> Self {
+        Self { id, mask }
+    }
+
+    /// Filter which only matches a single CAN ID
+    pub fn exact(id: u32) -> Self {
+        Self { id, mask: 0x1FFF_FFFF }
+    }
+
+    pub fn matches(&self, can_id: u32) -> bool {
+        can_id & self.mask == self.id & self.mask
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct ComServerError {
     pub err_code: u32,
     pub err_desc: String
 }
 
+/// Error code returned when the adapter has been unplugged or stopped responding.
+/// Matches J2534's ERR_DEVICE_NOT_CONNECTED
+pub const ERR_DEVICE_LOST: u32 = 0x08;
+
+/// Error code returned when the adapter does not support a function.
+/// Matches J2534's ERR_NOT_SUPPORTED
+pub const ERR_NOT_SUPPORTED: u32 = 0x01;
+
+/// Error code returned when a request failed for a reason with no more specific code.
+/// Matches J2534's ERR_FAILED
+pub const ERR_FAILED: u32 = 0x07;
+
+/// Error codes returned when the adapter reports a [CanError]. These are outside of the range used by J2534
+pub const ERR_BUS_OFF: u32 = 0x100;
+pub const ERR_ERROR_PASSIVE: u32 = 0x101;
+pub const ERR_ERROR_FRAME: u32 = 0x102;
+
+/// Error state reported by the adapter's CAN controller. These usually mean the bus is
+/// miswired, terminated incorrectly, or running at a different baud rate
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CanError {
+    /// Controller has seen so many errors that it has stopped taking part in the bus.
+    /// Nothing can be sent or received until it is recovered
+    BusOff,
+    /// Controller has seen enough errors that it only signals errors passively
+    ErrorPassive,
+    /// An error frame was seen on the bus
+    ErrorFrame,
+}
+
+impl CanError {
+    pub fn err_code(&self) -> u32 {
+        match self {
+            CanError::BusOff => ERR_BUS_OFF,
+            CanError::ErrorPassive => ERR_ERROR_PASSIVE,
+            CanError::ErrorFrame => ERR_ERROR_FRAME,
+        }
+    }
+}
+
+impl std::fmt::Display for CanError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CanError::BusOff => write!(f, "CAN bus off. Check the wiring, termination and baud rate"),
+            CanError::ErrorPassive => write!(f, "CAN controller is error passive. The bus is seeing a lot of errors"),
+            CanError::ErrorFrame => write!(f, "Error frame seen on the CAN bus"),
+        }
+    }
+}
+
+impl From<CanError> for ComServerError {
+    fn from(e: CanError) -> Self {
+        ComServerError { err_code: e.err_code(), err_desc: e.to_string() }
+    }
+}
+
+impl ComServerError {
+    /// Returns true if the error means the adapter is no longer connected, so
+    /// the device must be re-opened before it can be used again
+    pub fn is_device_lost(&self) -> bool {
+        self.err_code == ERR_DEVICE_LOST
+    }
+
+    /// Returns the CAN bus error the adapter reported, if this is one
+    pub fn can_error(&self) -> Option<CanError> {
+        match self.err_code {
+            ERR_BUS_OFF => Some(CanError::BusOff),
+            ERR_ERROR_PASSIVE => Some(CanError::ErrorPassive),
+            ERR_ERROR_FRAME => Some(CanError::ErrorFrame),
+            _ => None
+        }
+    }
+}
+
 impl std::fmt::Display for ComServerError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "Error code {} ({})", self.err_code, self.err_desc)
     }
 }
 
+impl std::error::Error for ComServerError {}
+
 #[derive(Debug, Copy, Clone, Eq, Ord, PartialOrd, PartialEq)]
 pub enum Capability {
     // The device supports the capability
@@ -112,6 +293,8 @@ pub struct DeviceCapabilities {
     pub (crate) j1850pwm: Capability,
     /// Supports regular CAN
     pub (crate) can: Capability,
+    /// Supports CAN-FD
+    pub (crate) can_fd: Capability,
     /// Supports ISO15765 (ISO-TP)
     pub (crate) iso15765: Capability,
     /// Supports K-Line OBD ISO9141
@@ -127,7 +310,8 @@ impl DeviceCapabilities {
     pub fn get_vendor(&self) -> String { self.vendor.clone() }
     pub fn get_lib_path(&self) -> String { self.library_path.clone() }
 
-    pub fn support_can_fd(&self) -> Capability { self.can }
+    pub fn supports_can(&self) -> Capability { self.can }
+    pub fn support_can_fd(&self) -> Capability { self.can_fd }
     pub fn supports_iso15765(&self) -> Capability { self.iso15765 }
 
     pub fn supports_j1850pwm(&self) -> Capability { self.j1850pwm }
@@ -266,6 +450,39 @@ pub trait ComServer : Send + Sync + Debug {
     ///                 flow control message from the ECU
     fn set_iso15765_params(&self, separation_time_min: u32, block_size: u32) -> Result<(), ComServerError>;
 
+    /// Returns the value at which the adapter's Rx timestamps wrap back around to 0, or None if
+    /// they never wrap (Or the backend does not provide timestamps).
+    ///
+    /// Each backend timestamps frames in its own clock domain:
+    /// * J2534 - Microseconds from the adapter's internal clock, as a 32 bit counter which wraps roughly every 71 minutes
+    /// * D-PDU - Not provided, frames are timestamped by the host when read
+    fn timestamp_rollover_us(&self) -> Option<u64> {
+        None
+    }
+
+    /// Returns true if [send_can_packets](fn@send_can_packets) hands the list of frames to the adapter
+    /// in one call (Example: J2534 PassThruWriteMsgs), rather than one call per frame.
+    /// Callers sending a burst of frames should pass them in one list if so
+    fn supports_batched_send(&self) -> bool {
+        false
+    }
+
+    /// Enables or disables loopback on the CAN interface. With loopback on, every frame sent
+    /// which the adapter successfully puts on the bus is also returned by [read_can_packets](fn@read_can_packets).
+    ///
+    /// Backends which cannot do this return [ERR_NOT_SUPPORTED]
+    fn set_can_loopback(&self, _enabled: bool) -> Result<(), ComServerError> {
+        Err(ComServerError { err_code: ERR_NOT_SUPPORTED, err_desc: "Loopback is not supported by this adapter".into() })
+    }
+
+    /// Restarts the adapter's CAN controller after it has gone bus off ([CanError::BusOff]).
+    ///
+    /// Backends which cannot do this return [ERR_NOT_SUPPORTED], in which case the device
+    /// has to be closed and re-opened instead
+    fn recover_from_bus_off(&mut self) -> Result<(), ComServerError> {
+        Err(ComServerError { err_code: ERR_NOT_SUPPORTED, err_desc: "Bus off recovery is not supported by this adapter".into() })
+    }
+
     /// Sends an ISOTP payload and attempts to read the ECUs response
     /// IMPORTANT - This function assumes the ISO15765 interface is ALREADY open
     fn send_receive_iso15765(&self, p: ISO15765Data, cfg: &ISO15765Config, max_timeout_ms: u128, max_resp: usize) -> Result<Vec<ISO15765Data>, ComServerError> {
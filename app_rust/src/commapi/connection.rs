@@ -0,0 +1,93 @@
+use std::sync::{Arc, Mutex};
+
+/// Diagnostic session an ECU is in
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SessionType {
+    Default,
+    Programming,
+    Extended,
+    SafetySystem,
+    /// Manufacturer or supplier specific session
+    Other(u8),
+}
+
+impl SessionType {
+    /// Gets the session from the sub function of a DiagnosticSessionControl request
+    pub fn from_byte(b: u8) -> Self {
+        // Bit 7 is the suppress positive response flag
+        match b & 0x7F {
+            0x01 => SessionType::Default,
+            0x02 => SessionType::Programming,
+            0x03 => SessionType::Extended,
+            0x04 => SessionType::SafetySystem,
+            x => SessionType::Other(x)
+        }
+    }
+}
+
+/// Change in the state of the connection to an ECU or adapter
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConnectionEvent {
+    /// Connection was opened, or was re-opened after the adapter was lost
+    Connected,
+    /// Connection was closed, or was lost and could not be re-opened
+    Disconnected,
+    /// ECU confirmed it has changed session
+    SessionChanged(SessionType),
+    /// Adapter was lost, starting reconnect attempt N (Starting at 1)
+    ReconnectAttempt(u32),
+    /// ECU accepted the key for a security level. The level is the requestSeed sub function (0x01, 0x03...)
+    SecurityUnlocked(u8),
+}
+
+/// Receives connection state changes as they happen, so the UI does not have to poll.
+///
+/// Events are sent from whichever thread caused the change, so observers should
+/// return quickly (For example by forwarding the event to the UI's message queue)
+pub trait ConnectionObserver: Send + Sync {
+    fn on_event(&self, event: &ConnectionEvent);
+}
+
+impl<F: Fn(&ConnectionEvent) + Send + Sync> ConnectionObserver for F {
+    fn on_event(&self, event: &ConnectionEvent) {
+        self(event)
+    }
+}
+
+/// List of observers shared between clones of a connection
+#[derive(Clone, Default)]
+pub struct ConnectionObservers {
+    observers: Arc<Mutex<Vec<Arc<dyn ConnectionObserver>>>>,
+}
+
+impl std::fmt::Debug for ConnectionObservers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ConnectionObservers({})", self.observers.lock().unwrap().len())
+    }
+}
+
+impl ConnectionObservers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&self, observer: Arc<dyn ConnectionObserver>) {
+        self.observers.lock().unwrap().push(observer)
+    }
+
+    /// Sends an event to every observer
+    pub fn notify(&self, event: ConnectionEvent) {
+        // Copied so an observer can add another observer without deadlocking
+        let observers = self.observers.lock().unwrap().clone();
+        for o in observers {
+            o.on_event(&event)
+        }
+    }
+}
+
+#[test]
+fn test_session_from_byte() {
+    assert_eq!(SessionType::from_byte(0x03), SessionType::Extended);
+    assert_eq!(SessionType::from_byte(0x82), SessionType::Programming);
+    assert_eq!(SessionType::from_byte(0x40), SessionType::Other(0x40));
+}
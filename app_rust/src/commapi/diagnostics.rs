@@ -0,0 +1,485 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+use crate::commapi::address_book::AddressBook;
+use crate::commapi::comm_api::{CanFrame, ComServer, ComServerError, DeviceCapabilities, FilterType, ISO15765Config, ISO15765Data, ERR_NOT_SUPPORTED};
+use crate::commapi::iso_tp::{encode_payload, flow_control_frame, parse_flow_control, st_min_to_duration, FlowStatus, IsoTpDecoder, IsoTpError, IsoTpOptions, RxResult};
+use crate::commapi::protocols::{ProtocolError, ProtocolServer};
+use crate::commapi::protocols::auto_connect::DiagProtocol;
+use crate::commapi::protocols::uds::UDSECU;
+use crate::commapi::shared_channel::{ChannelSubscriber, SharedChannel};
+
+/// Max time to wait for a flow control frame (N_Bs)
+const FC_TIMEOUT_MS: u64 = 1000;
+
+fn not_supported(desc: &str) -> ComServerError {
+    ComServerError { err_code: ERR_NOT_SUPPORTED, err_desc: desc.into() }
+}
+
+/// First come, first served lock on transmitting to the bus. Clients waiting to send
+/// are served in the order they asked, so a busy client cannot starve another
+#[derive(Default)]
+struct TxQueue {
+    /// Next ticket to hand out, and the ticket currently allowed to send
+    tickets: Mutex<(u64, u64)>,
+    turn: Condvar,
+}
+
+impl TxQueue {
+    fn run<T, F: FnOnce() -> T>(&self, f: F) -> T {
+        let mut tickets = self.tickets.lock().unwrap();
+        let ticket = tickets.0;
+        tickets.0 += 1;
+        while tickets.1 != ticket {
+            tickets = self.turn.wait(tickets).unwrap();
+        }
+        drop(tickets);
+        let res = f();
+        self.tickets.lock().unwrap().1 += 1;
+        self.turn.notify_all();
+        res
+    }
+}
+
+struct RouteState {
+    sub: ChannelSubscriber,
+    send_id: u32,
+    opts: IsoTpOptions,
+    decoder: IsoTpDecoder,
+    /// Payloads which have been fully received, but not read yet
+    rx: VecDeque<Vec<u8>>,
+    block_size: u8,
+    sep_time: u8,
+}
+
+impl RouteState {
+    /// Feeds a received frame to the ISO-TP decoder, sending flow control if the ECU wants it.
+    ///
+    /// # Returns
+    /// The frame if it is a flow control frame, which the decoder does not handle
+    fn on_frame(&mut self, frame: &CanFrame) -> Result<Option<CanFrame>, ComServerError> {
+        if self.opts.frame_data(frame).first().map(|x| x & 0xF0) == Some(0x30) {
+            return Ok(Some(*frame))
+        }
+        match self.decoder.on_frame(frame) {
+            Ok(RxResult::Complete(payload)) => self.rx.push_back(payload),
+            Ok(RxResult::FlowControlRequired) => {
+                let fc = flow_control_frame(self.send_id, FlowStatus::ContinueToSend, self.block_size, self.sep_time, &self.opts);
+                self.sub.send_can_packets(&[fc], 0)?;
+            },
+            Ok(RxResult::Pending) => {},
+            // Part of the payload was lost. Reported so the caller does not wait for the rest of it
+            Err(e @ IsoTpError::SequenceError { .. }) => return Err(e.into()),
+            // Payload is too large to accept, so the ECU is told to stop sending it
+            Err(e @ IsoTpError::RxTooLarge { .. }) => {
+                let fc = flow_control_frame(self.send_id, FlowStatus::Overflow, 0, 0, &self.opts);
+                self.sub.send_can_packets(&[fc], 0)?;
+                return Err(e.into())
+            },
+            // Corrupt payload, drop it and wait for the next one
+            Err(_) => self.decoder.reset()
+        }
+        Ok(None)
+    }
+
+    /// Waits for a flow control frame, skipping any Wait frames
+    fn wait_flow_control(&mut self) -> Result<(u8, u8), ComServerError> {
+        let mut deadline = Instant::now() + Duration::from_millis(FC_TIMEOUT_MS);
+        loop {
+            for f in self.sub.read_can_packets(0, 1)? {
+                if let Some(fc) = self.on_frame(&f)? {
+                    match parse_flow_control(&fc, &self.opts) {
+                        Ok((FlowStatus::ContinueToSend, bs, st)) => return Ok((bs, st)),
+                        Ok((FlowStatus::Wait, _, _)) => deadline = Instant::now() + Duration::from_millis(FC_TIMEOUT_MS),
+                        Ok((FlowStatus::Overflow, _, _)) => return Err(IsoTpError::Overflow.into()),
+                        Err(_) => {}
+                    }
+                }
+            }
+            if Instant::now() >= deadline {
+                return Err(IsoTpError::Timeout.into())
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn send(&mut self, payload: &[u8]) -> Result<(), ComServerError> {
+        let frames = encode_payload(self.send_id, payload, &self.opts).map_err(ComServerError::from)?;
+        self.sub.send_can_packets(&frames[0..1], 0)?;
+        if frames.len() == 1 {
+            return Ok(())
+        }
+        let (mut bs, mut st) = self.wait_flow_control()?;
+        let mut sent_in_block = 0;
+        for f in &frames[1..] {
+            if bs != 0 && sent_in_block == bs {
+                let (new_bs, new_st) = self.wait_flow_control()?;
+                bs = new_bs;
+                st = new_st;
+                sent_in_block = 0;
+            }
+            std::thread::sleep(st_min_to_duration(st));
+            self.sub.send_can_packets(&[*f], 0)?;
+            sent_in_block += 1;
+        }
+        Ok(())
+    }
+}
+
+/// ISO-TP connection to a single ECU over the [Diagnostics] coordinator's shared channel.
+///
+/// This is given to [UDSECU] in place of an adapter. Only the ISO15765 functions are
+/// implemented, raw CAN access has to go through the [SharedChannel] instead
+#[derive(Clone)]
+struct EcuRoute {
+    recv_id: u32,
+    state: Arc<Mutex<RouteState>>,
+    bus: Arc<TxQueue>,
+    channel: SharedChannel,
+}
+
+impl std::fmt::Debug for EcuRoute {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "EcuRoute (0x{:04X}/0x{:04X})", self.state.lock().unwrap().send_id, self.recv_id)
+    }
+}
+
+#[allow(unused_variables)]
+impl ComServer for EcuRoute {
+    fn open_device(&mut self) -> Result<(), ComServerError> { Ok(()) }
+
+    fn close_device(&mut self) -> Result<(), ComServerError> { Ok(()) }
+
+    fn send_can_packets(&self, data: &[CanFrame], timeout_ms: u32) -> Result<usize, ComServerError> {
+        Err(not_supported("Raw CAN is not available on a diagnostic route"))
+    }
+
+    fn is_connected(&self) -> bool { true }
+
+    fn read_can_packets(&self, timeout_ms: u32, max_msgs: usize) -> Result<Vec<CanFrame>, ComServerError> {
+        Err(not_supported("Raw CAN is not available on a diagnostic route"))
+    }
+
+    fn send_iso15765_data(&self, data: &[ISO15765Data], timeout_ms: u32) -> Result<usize, ComServerError> {
+        for d in data {
+            // Only the transmission waits for the bus, responses are received whilst other clients send
+            self.bus.run(|| self.state.lock().unwrap().send(&d.data))?;
+        }
+        Ok(data.len())
+    }
+
+    fn read_iso15765_packets(&self, timeout_ms: u32, max_msgs: usize) -> Result<Vec<ISO15765Data>, ComServerError> {
+        let start = Instant::now();
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                for f in state.sub.read_can_packets(0, 100)? {
+                    state.on_frame(&f)?; // Stray flow control frames are ignored
+                }
+                if !state.rx.is_empty() || start.elapsed().as_millis() >= timeout_ms as u128 {
+                    let n = std::cmp::min(state.rx.len(), max_msgs);
+                    let pad_frame = state.opts.pad_frame;
+                    return Ok(state.rx.drain(0..n).map(|data| ISO15765Data { id: self.recv_id, data, pad_frame }).collect())
+                }
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn open_can_interface(&mut self, bus_speed: u32, is_ext_can: bool) -> Result<(), ComServerError> {
+        Err(not_supported("Raw CAN is not available on a diagnostic route"))
+    }
+
+    fn close_can_interface(&mut self) -> Result<(), ComServerError> { Ok(()) }
+
+    // The shared channel is already open, and owned by the coordinator
+    fn open_iso15765_interface(&mut self, bus_speed: u32, is_ext_can: bool) -> Result<(), ComServerError> { Ok(()) }
+
+    fn close_iso15765_interface(&mut self) -> Result<(), ComServerError> { Ok(()) }
+
+    fn add_can_filter(&self, filter: FilterType, id: u32, mask: u32) -> Result<u32, ComServerError> {
+        Err(not_supported("Raw CAN is not available on a diagnostic route"))
+    }
+
+    fn rem_can_filter(&self, filter_idx: u32) -> Result<(), ComServerError> {
+        Err(not_supported("Raw CAN is not available on a diagnostic route"))
+    }
+
+    // Frames are already routed by response ID
+    fn add_iso15765_filter(&self, id: u32, mask: u32, resp_id: u32) -> Result<u32, ComServerError> { Ok(0) }
+
+    fn rem_iso15765_filter(&self, filter_idx: u32) -> Result<(), ComServerError> { Ok(()) }
+
+    fn set_iso15765_params(&self, separation_time_min: u32, block_size: u32) -> Result<(), ComServerError> {
+        let mut state = self.state.lock().unwrap();
+        state.sep_time = separation_time_min as u8;
+        state.block_size = block_size as u8;
+        Ok(())
+    }
+
+    fn clear_can_rx_buffer(&self) -> Result<(), ComServerError> { Ok(()) }
+
+    fn clear_can_tx_buffer(&self) -> Result<(), ComServerError> { Ok(()) }
+
+    fn clear_iso15765_rx_buffer(&self) -> Result<(), ComServerError> {
+        let mut state = self.state.lock().unwrap();
+        state.sub.read_can_packets(0, usize::MAX)?;
+        state.decoder.reset();
+        state.rx.clear();
+        Ok(())
+    }
+
+    fn clear_iso15765_tx_buffer(&self) -> Result<(), ComServerError> { Ok(()) }
+
+    fn read_battery_voltage(&self) -> Result<f32, ComServerError> {
+        self.channel.with_server(|s| s.read_battery_voltage())
+    }
+
+    fn clone_box(&self) -> Box<dyn ComServer> {
+        Box::new(self.clone())
+    }
+
+    fn get_capabilities(&self) -> DeviceCapabilities {
+        self.channel.with_server(|s| s.get_capabilities())
+    }
+
+    fn get_api(&self) -> &str {
+        "Shared"
+    }
+}
+
+/// Runs diagnostic sessions with several ECUs at once over a single CAN channel.
+///
+/// Each ECU gets its own [UDSECU] client, keyed by its request and response CAN IDs.
+/// Received frames are routed by response ID to the ISO-TP decoder of the client that owns
+/// that ID, so responses can never cross between clients. ISO-TP is done in software over
+/// raw CAN, as an adapter's ISO15765 channel only handles one ECU at a time.
+///
+/// Requests from different clients are transmitted one at a time, in the order they were
+/// made. Only the transmission is serialized, so a slow ECU does not hold up the others
+/// whilst it works on its response.
+pub struct Diagnostics {
+    channel: SharedChannel,
+    opts: IsoTpOptions,
+    bus: Arc<TxQueue>,
+    clients: Mutex<HashMap<(u32, u32), UDSECU>>,
+    address_book: Option<AddressBook>,
+}
+
+impl std::fmt::Debug for Diagnostics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Diagnostics ({} clients)", self.clients.lock().unwrap().len())
+    }
+}
+
+impl Diagnostics {
+    /// Wraps a comm server which already has an open CAN interface
+    ///
+    /// # Params
+    /// * server - Adapter to run every session on
+    /// * opts - ISO-TP framing options used by every client
+    pub fn new(server: Box<dyn ComServer>, opts: IsoTpOptions) -> Self {
+        Self {
+            channel: SharedChannel::new(server),
+            opts,
+            bus: Arc::new(TxQueue::default()),
+            clients: Mutex::new(HashMap::new()),
+            address_book: None,
+        }
+    }
+
+    /// Sets the address book [connect_named](fn@Diagnostics::connect_named) looks ECUs up in
+    pub fn set_address_book(&mut self, book: AddressBook) {
+        self.address_book = Some(book)
+    }
+
+    /// Returns the shared channel, for subscribers which want raw CAN frames (Such as the CAN Tracer)
+    pub fn channel(&self) -> &SharedChannel {
+        &self.channel
+    }
+
+    /// Returns a UDS client for an ECU, starting a diagnostic session with it if there is
+    /// not already one running. Clones of a client share the same session.
+    ///
+    /// Returns [ProtocolError::InvalidRequest] if another ECU already responds on `cfg.recv_id`
+    pub fn uds_client(&self, cfg: &ISO15765Config) -> Result<UDSECU, ProtocolError> {
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(c) = clients.get(&(cfg.send_id, cfg.recv_id)) {
+            return Ok(c.clone())
+        }
+        if let Some((send_id, _)) = clients.keys().find(|(_, recv_id)| *recv_id == cfg.recv_id) {
+            return Err(ProtocolError::InvalidRequest(format!("Response ID 0x{:04X} is already used by the ECU at 0x{:04X}", cfg.recv_id, send_id)))
+        }
+        let sub = self.channel.subscribe(&[cfg.recv_id]).map_err(ProtocolError::CommError)?;
+        let route = EcuRoute {
+            recv_id: cfg.recv_id,
+            state: Arc::new(Mutex::new(RouteState {
+                sub,
+                send_id: cfg.send_id,
+                opts: self.opts,
+                decoder: IsoTpDecoder::with_options(&self.opts),
+                rx: VecDeque::new(),
+                block_size: cfg.block_size as u8,
+                sep_time: cfg.sep_time as u8,
+            })),
+            bus: self.bus.clone(),
+            channel: self.channel.clone(),
+        };
+        let client = UDSECU::start_diag_session(Box::new(route), cfg)?;
+        clients.insert((cfg.send_id, cfg.recv_id), client.clone());
+        Ok(client)
+    }
+
+    /// Returns a UDS client for an ECU in the [address book](fn@Diagnostics::set_address_book),
+    /// as with [uds_client](fn@Diagnostics::uds_client).
+    ///
+    /// The channel must already be open at the bus speed and addressing of the entry,
+    /// as it is shared with every other ECU. Only UDS ECUs can be connected to
+    pub fn connect_named(&self, name: &str) -> Result<UDSECU, ProtocolError> {
+        let book = self.address_book.as_ref().ok_or_else(|| ProtocolError::InvalidRequest("No address book has been loaded".into()))?;
+        let entry = book.get(name).ok_or_else(|| ProtocolError::InvalidRequest(format!("{} is not in the address book for {}", name, book.vehicle)))?;
+        if entry.protocol != DiagProtocol::Uds {
+            return Err(ProtocolError::InvalidRequest(format!("{} uses {:?}, only UDS ECUs can share a channel", entry.name, entry.protocol)))
+        }
+        self.uds_client(&entry.to_profile().iso_tp)
+    }
+
+    /// Returns the request and response IDs of every running client
+    pub fn client_ids(&self) -> Vec<(u32, u32)> {
+        self.clients.lock().unwrap().keys().copied().collect()
+    }
+
+    /// Exits the diagnostic session with an ECU. Any clones of its client stop working
+    pub fn close_client(&self, send_id: u32, recv_id: u32) {
+        if let Some(mut c) = self.clients.lock().unwrap().remove(&(send_id, recv_id)) {
+            c.exit_diag_session()
+        }
+    }
+}
+
+#[test]
+fn test_concurrent_clients() {
+    use crate::commapi::mock_api::MockComServer;
+    use crate::commapi::mock_ecu::MockEcu;
+
+    let engine = MockEcu::new(0x07E0, 0x07E8);
+    engine.set_did(0xF190, b"ENGINE00000000001");
+    engine.set_did(0x1100, &[0x01]);
+    let gearbox = MockEcu::new(0x07E1, 0x07E9);
+    gearbox.set_did(0xF190, b"GEARBOX0000000002");
+    gearbox.set_did(0x1100, &[0x02]);
+
+    let mut mock = MockComServer::new();
+    let (engine_t, gearbox_t) = (engine.clone(), gearbox.clone());
+    mock.set_responder(move |f| {
+        let mut resp = engine_t.respond_can(f);
+        resp.extend(gearbox_t.respond_can(f));
+        resp
+    });
+    mock.open_can_interface(500_000, false).unwrap();
+    let diag = Diagnostics::new(Box::new(mock), IsoTpOptions::default());
+
+    let engine_cfg = ISO15765Config { send_id: 0x07E0, recv_id: 0x07E8, block_size: 0, sep_time: 0 };
+    let gearbox_cfg = ISO15765Config { send_id: 0x07E1, recv_id: 0x07E9, block_size: 0, sep_time: 0 };
+    let clients = vec![
+        (diag.uds_client(&engine_cfg).unwrap(), b"ENGINE00000000001", 0x01),
+        (diag.uds_client(&gearbox_cfg).unwrap(), b"GEARBOX0000000002", 0x02),
+    ];
+    assert_eq!(engine.get_session(), 0x03);
+    assert_eq!(gearbox.get_session(), 0x03);
+
+    // Multi frame and single frame responses from both ECUs at once
+    let threads: Vec<_> = clients.into_iter().map(|(client, vin, value)| std::thread::spawn(move || {
+        for _ in 0..25 {
+            assert_eq!(client.read_data_by_id(0xF190).unwrap(), vin.to_vec());
+            assert_eq!(client.read_data_by_id(0x1100).unwrap(), vec![value]);
+        }
+    })).collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+
+    // Same IDs share a session, a response ID can only belong to 1 ECU
+    diag.uds_client(&engine_cfg).unwrap();
+    assert_eq!(diag.client_ids().len(), 2);
+    let clash = ISO15765Config { send_id: 0x07E2, recv_id: 0x07E8, block_size: 0, sep_time: 0 };
+    assert!(matches!(diag.uds_client(&clash), Err(ProtocolError::InvalidRequest(_))));
+
+    diag.close_client(0x07E1, 0x07E9);
+    assert_eq!(diag.client_ids(), vec![(0x07E0, 0x07E8)]);
+}
+
+#[test]
+fn test_transaction_retries_sequence_error() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use crate::commapi::mock_api::MockComServer;
+    use crate::commapi::mock_ecu::MockEcu;
+    use crate::commapi::protocols::uds::RetryPolicy;
+
+    let ecu = MockEcu::new(0x07E0, 0x07E8);
+    ecu.set_did(0xF190, b"WDB2030461A123456");
+    let reorder = Arc::new(AtomicBool::new(true));
+    let mut mock = MockComServer::new();
+    let (ecu_t, reorder_t) = (ecu.clone(), reorder.clone());
+    mock.set_responder(move |f| {
+        let mut resp = ecu_t.respond_can(f);
+        // Consecutive frames of the next multi frame response arrive out of order
+        if resp.len() > 1 && reorder_t.swap(false, Ordering::SeqCst) {
+            resp.reverse();
+        }
+        resp
+    });
+    mock.open_can_interface(500_000, false).unwrap();
+    let diag = Diagnostics::new(Box::new(mock), IsoTpOptions::default());
+    let mut client = diag.uds_client(&ISO15765Config { send_id: 0x07E0, recv_id: 0x07E8, block_size: 0, sep_time: 0 }).unwrap();
+    client.set_retry_policy(RetryPolicy { max_attempts: 3, backoff: Duration::from_millis(1) });
+
+    let mut attempts = 0;
+    let vin = client.transaction(|c| {
+        attempts += 1;
+        c.read_data_by_id(0xF190)
+    }).unwrap();
+    assert_eq!(vin, b"WDB2030461A123456".to_vec());
+    assert_eq!(attempts, 2);
+
+    // Without retrying, the sequence error is reported rather than waiting for the timeout
+    client.set_retry_policy(RetryPolicy { max_attempts: 1, ..RetryPolicy::default() });
+    reorder.store(true, Ordering::SeqCst);
+    let start = Instant::now();
+    let err = client.transaction(|c| c.read_data_by_id(0xF190)).unwrap_err();
+    assert!(matches!(&err, ProtocolError::CommError(e) if e.err_desc.contains("SequenceError")), "{:?}", err);
+    assert!(start.elapsed() < Duration::from_millis(400));
+    assert_eq!(client.read_data_by_id(0xF190).unwrap(), b"WDB2030461A123456".to_vec());
+}
+
+#[test]
+fn test_connect_named() {
+    use crate::commapi::mock_api::MockComServer;
+    use crate::commapi::mock_ecu::MockEcu;
+
+    let book = AddressBook::from_json(r#"{
+        "vehicle": "W203",
+        "ecus": [
+            { "name": "Engine", "tx": 2016, "rx": 2024, "protocol": "Uds", "baud": 500000 },
+            { "name": "Gearbox", "tx": 2017, "rx": 2025, "protocol": "Uds", "baud": 500000 },
+            { "name": "Instrument cluster", "tx": 1760, "rx": 1768, "protocol": "Kwp2000", "baud": 500000 }
+        ]
+    }"#).unwrap();
+    let gearbox = MockEcu::new(0x07E1, 0x07E9);
+    gearbox.set_did(0xF190, b"GEARBOX0000000002");
+    let mut mock = MockComServer::new();
+    let gearbox_t = gearbox.clone();
+    mock.set_responder(move |f| gearbox_t.respond_can(f));
+    mock.open_can_interface(500_000, false).unwrap();
+    let mut diag = Diagnostics::new(Box::new(mock), IsoTpOptions::default());
+    assert!(matches!(diag.connect_named("Gearbox"), Err(ProtocolError::InvalidRequest(_))));
+    diag.set_address_book(book);
+
+    let client = diag.connect_named("gearbox").unwrap();
+    assert_eq!(gearbox.get_session(), 0x03);
+    assert_eq!(client.read_data_by_id(0xF190).unwrap(), b"GEARBOX0000000002".to_vec());
+    assert_eq!(diag.client_ids(), vec![(0x07E1, 0x07E9)]);
+
+    assert!(matches!(diag.connect_named("Radio"), Err(ProtocolError::InvalidRequest(_))));
+    assert!(matches!(diag.connect_named("Instrument cluster"), Err(ProtocolError::InvalidRequest(_))));
+}
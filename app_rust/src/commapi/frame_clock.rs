@@ -0,0 +1,98 @@
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use crate::commapi::comm_api::CanFrame;
+use crate::commapi::pcap::CapturedFrame;
+
+/// Converts the timestamps of received frames into microseconds since the UNIX epoch.
+///
+/// Frames timestamped by the adapter keep the adapter's spacing between frames, which is more
+/// accurate than the time they were read by the host. The first timestamped frame is anchored to
+/// the host clock, and adapter counters which wrap are unwrapped. Frames without a timestamp
+/// are given the current host time.
+#[derive(Debug, Clone)]
+pub struct FrameClock {
+    start: Instant,
+    start_unix_us: u64,
+    rollover_us: Option<u64>,
+    /// Unwrapped adapter timestamp of the first frame, and its host timestamp
+    anchor: Option<(u64, u64)>,
+    last_adapter_us: u64,
+    rollovers: u64,
+}
+
+impl FrameClock {
+    /// Creates a clock starting at the current time
+    ///
+    /// # Params
+    /// * rollover_us - Value the adapter's timestamps wrap at, from [timestamp_rollover_us](fn@crate::commapi::comm_api::ComServer::timestamp_rollover_us)
+    pub fn new(rollover_us: Option<u64>) -> Self {
+        let start_unix_us = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_micros() as u64).unwrap_or(0);
+        Self::starting_at(start_unix_us, rollover_us)
+    }
+
+    /// Creates a clock where the current time is `start_unix_us`
+    pub fn starting_at(start_unix_us: u64, rollover_us: Option<u64>) -> Self {
+        Self { start: Instant::now(), start_unix_us, rollover_us, anchor: None, last_adapter_us: 0, rollovers: 0 }
+    }
+
+    /// Returns the current host time
+    pub fn host_us(&self) -> u64 {
+        self.start_unix_us + self.start.elapsed().as_micros() as u64
+    }
+
+    /// Returns the time a frame was received. Frames must be passed in the order they were received
+    pub fn timestamp_us(&mut self, frame: &CanFrame) -> u64 {
+        let adapter_us = match frame.timestamp_us {
+            Some(ts) => ts,
+            None => return self.host_us()
+        };
+        let unwrapped = match self.rollover_us {
+            Some(period) => {
+                if adapter_us < self.last_adapter_us {
+                    self.rollovers += 1;
+                }
+                self.last_adapter_us = adapter_us;
+                adapter_us + self.rollovers * period
+            },
+            None => adapter_us
+        };
+        self.anchored(unwrapped)
+    }
+
+    fn anchored(&mut self, adapter_us: u64) -> u64 {
+        let host_us = self.host_us();
+        let (anchor_adapter, anchor_host) = *self.anchor.get_or_insert((adapter_us, host_us));
+        // Timestamps before the anchor can only come from a misbehaving adapter
+        anchor_host + adapter_us.saturating_sub(anchor_adapter)
+    }
+
+    /// Timestamps a received frame for the capture
+    pub fn capture(&mut self, frame: CanFrame) -> CapturedFrame {
+        CapturedFrame::new(self.timestamp_us(&frame), frame)
+    }
+}
+
+#[test]
+fn test_adapter_timestamps_in_capture() {
+    use crate::commapi::comm_api::ComServer;
+    use crate::commapi::mock_api::MockComServer;
+
+    let mut mock = MockComServer::new();
+    mock.no_hw_filters = true;
+    mock.timestamp_rollover_us = Some(1 << 32);
+    for (id, ts) in &[(0x100, Some(0xFFFF_0000u64)), (0x101, Some(0xFFFF_1000)), (0x102, Some(0x0000_2000)), (0x103, None)] {
+        let mut f = CanFrame::new(*id, &[0x01]);
+        f.timestamp_us = *ts;
+        mock.push_rx(f);
+    }
+    let start = 1_600_000_000_000_000;
+    let mut clock = FrameClock::starting_at(start, mock.timestamp_rollover_us());
+    let capture: Vec<CapturedFrame> = mock.read_can_packets(0, 10).unwrap().into_iter().map(|f| clock.capture(f)).collect();
+    assert_eq!(capture.len(), 4);
+
+    // Spacing between frames comes from the adapter, including over the counter wrapping
+    assert!(capture[0].timestamp_us >= start);
+    assert_eq!(capture[1].timestamp_us - capture[0].timestamp_us, 0x1000);
+    assert_eq!(capture[2].timestamp_us - capture[0].timestamp_us, 0x1_2000);
+    // Host time for the frame without a timestamp
+    assert!(capture[3].timestamp_us >= start && capture[3].timestamp_us < capture[2].timestamp_us);
+}
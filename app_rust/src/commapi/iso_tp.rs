@@ -0,0 +1,809 @@
+use crate::commapi::comm_api::{CanFrame, CanIdFilter, ComServerError, ISO15765Config, ERR_FAILED, CAN_MAX_DATA_LEN, CAN_FD_MAX_DATA_LEN, dlc_to_len, len_to_dlc};
+use crate::commapi::can_channel::CanChannel;
+use std::cmp::min;
+use std::time::{Duration, Instant};
+
+/// Byte used to pad ISO-TP frames (ISO15765-2 recommends 0xCC)
+pub const ISO_TP_PAD_BYTE: u8 = 0xCC;
+
+/// Largest payload length that fits in the 12 bit first frame length.
+/// Anything larger uses the 32 bit length escape
+const FF_DL_12BIT_MAX: usize = 0x0FFF;
+
+/// Largest payload accepted from a first frame when [IsoTpOptions::rx_max_len] is not set (1 MiB)
+pub const DEFAULT_RX_MAX_LEN: usize = 0x10_0000;
+
+/// ISO15765-2 addressing format, which decides if every frame starts with an address byte
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IsoTpAddressing {
+    /// The CAN ID alone addresses the ECU
+    Normal,
+    /// The first byte of every frame is the target address (N_TA). The address byte of
+    /// received frames is the sender's address, and is not checked
+    Extended(u8),
+    /// The first byte of every frame is the address extension (N_AE)
+    Mixed(u8),
+}
+
+impl Default for IsoTpAddressing {
+    fn default() -> Self {
+        IsoTpAddressing::Normal
+    }
+}
+
+impl IsoTpAddressing {
+    /// Returns the byte sent before the PCI of every frame, if there is one
+    pub fn address_byte(&self) -> Option<u8> {
+        match self {
+            IsoTpAddressing::Normal => None,
+            IsoTpAddressing::Extended(a) | IsoTpAddressing::Mixed(a) => Some(*a),
+        }
+    }
+
+    /// Returns the number of bytes of each frame used by the address
+    pub fn prefix_len(&self) -> usize {
+        if self.address_byte().is_some() { 1 } else { 0 }
+    }
+}
+
+/// Options for framing ISO-TP payloads into CAN frames
+#[derive(Debug, Copy, Clone, Default)]
+pub struct IsoTpOptions {
+    /// Send frames as CAN-FD frames (Up to 64 bytes per frame)
+    pub fd: bool,
+    /// Enable the bit rate switch on CAN-FD frames
+    pub brs: bool,
+    /// Pad classic CAN frames up to 8 bytes. CAN-FD frames are always padded
+    /// up to the next valid DLC length
+    pub pad_frame: bool,
+    /// Largest payload sent as a single frame, for ECUs which expect a first frame sooner
+    /// than usual. None uses the most a single frame can hold (7 bytes, or 62 on CAN-FD).
+    /// May not be less than a classic CAN first frame holds, see [IsoTpOptions::validate]
+    pub single_frame_max: Option<usize>,
+    pub addressing: IsoTpAddressing,
+    /// Largest payload accepted from the other node. A first frame announcing a larger payload is
+    /// rejected with [IsoTpError::RxTooLarge]. None uses [DEFAULT_RX_MAX_LEN]
+    pub rx_max_len: Option<usize>,
+}
+
+impl IsoTpOptions {
+    /// Returns the maximum number of bytes in a single CAN frame for these options
+    pub fn frame_len(&self) -> usize {
+        if self.fd { CAN_FD_MAX_DATA_LEN } else { CAN_MAX_DATA_LEN }
+    }
+
+    /// Returns the number of bytes in a single CAN frame left for ISO-TP, after the address byte
+    pub fn data_len(&self) -> usize {
+        self.frame_len() - self.addressing.prefix_len()
+    }
+
+    /// Returns the most a single frame can hold.
+    ///
+    /// On CAN-FD this uses the escape sequence (SF_DL in the 2nd byte), giving 62 bytes.
+    /// Extended and mixed addressing take one byte less
+    pub fn single_frame_capacity(&self) -> usize {
+        if self.fd { self.data_len() - 2 } else { self.data_len() - 1 }
+    }
+
+    /// Returns the ISO-TP data of a received frame, after the address byte
+    pub fn frame_data<'a>(&self, frame: &'a CanFrame) -> &'a [u8] {
+        frame.get_data().get(self.addressing.prefix_len()..).unwrap_or(&[])
+    }
+
+    /// Returns the maximum payload that is sent in a single frame. Larger payloads
+    /// are sent as a first frame and consecutive frames
+    pub fn max_single_frame_len(&self) -> usize {
+        self.single_frame_max.unwrap_or_else(|| self.single_frame_capacity())
+    }
+
+    /// Returns the most a first frame of a classic CAN frame holds (6 bytes, or 5 with an address byte)
+    fn classic_first_frame_capacity(&self) -> usize {
+        CAN_MAX_DATA_LEN - self.addressing.prefix_len() - 2
+    }
+
+    /// Checks the single frame threshold can be used with the frame size.
+    ///
+    /// A first frame is always full, and must be followed by at least one consecutive frame,
+    /// so the threshold may not be less than a classic CAN first frame holds. Otherwise
+    /// a payload between the two would be sent as a first frame alone, which receivers reject
+    pub fn validate(&self) -> Result<(), IsoTpError> {
+        match self.single_frame_max {
+            Some(max) if max < self.classic_first_frame_capacity() => Err(IsoTpError::InvalidConfig(format!(
+                "Single frame threshold of {} bytes is less than a first frame holds ({} bytes)",
+                max, self.classic_first_frame_capacity()
+            ))),
+            Some(max) if max > self.single_frame_capacity() => Err(IsoTpError::InvalidConfig(format!(
+                "Single frame threshold of {} bytes is more than a {} frame can hold ({} bytes)",
+                max, if self.fd { "CAN-FD" } else { "CAN" }, self.single_frame_capacity()
+            ))),
+            _ => Ok(())
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum IsoTpError {
+    /// Frame has an unknown PCI type (Upper nibble of the first byte)
+    InvalidPci(u8),
+    /// Length in the PCI does not match the data in the frame
+    InvalidLength,
+    /// A consecutive frame was received without a first frame
+    UnexpectedConsecutiveFrame,
+    /// A consecutive frame's sequence number was not the next one, so a frame was lost or
+    /// arrived out of order. The payload being received is dropped
+    SequenceError { expected: u8, got: u8 },
+    /// Payload is too large to be sent over ISO-TP
+    PayloadTooLarge,
+    /// The receiver reported that the payload is too large for its buffer
+    Overflow,
+    /// A first frame announced a payload larger than [IsoTpOptions::rx_max_len]. The payload is
+    /// dropped without allocating for it
+    RxTooLarge { len: usize, max: usize },
+    /// No response from the other node within the timeout
+    Timeout,
+    /// Driver error whilst sending or receiving frames
+    CommError(ComServerError),
+    /// [IsoTpOptions] cannot be used, see [IsoTpOptions::validate]
+    InvalidConfig(String),
+}
+
+impl From<ComServerError> for IsoTpError {
+    fn from(e: ComServerError) -> Self {
+        Self::CommError(e)
+    }
+}
+
+impl From<IsoTpError> for ComServerError {
+    fn from(e: IsoTpError) -> Self {
+        match e {
+            IsoTpError::CommError(e) => e,
+            e => ComServerError { err_code: ERR_FAILED, err_desc: format!("ISO-TP error: {:?}", e) }
+        }
+    }
+}
+
+/// Flow status sent in a flow control frame
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FlowStatus {
+    ContinueToSend = 0x00,
+    Wait = 0x01,
+    Overflow = 0x02,
+}
+
+/// Result of passing a frame to [IsoTpDecoder::on_frame](fn@IsoTpDecoder::on_frame)
+#[derive(Debug, Clone, PartialEq)]
+pub enum RxResult {
+    /// The full payload has been received
+    Complete(Vec<u8>),
+    /// A first frame was received, the sender is now waiting for a flow control frame
+    FlowControlRequired,
+    /// A consecutive frame was received, but more are still expected
+    Pending,
+}
+
+/// Builds a CAN frame from ISO-TP data, applying any padding needed
+fn make_frame(id: u32, mut data: Vec<u8>, opts: &IsoTpOptions) -> CanFrame {
+    if let Some(addr) = opts.addressing.address_byte() {
+        data.insert(0, addr);
+    }
+    if opts.fd {
+        let len = dlc_to_len(len_to_dlc(data.len()));
+        data.resize(len, ISO_TP_PAD_BYTE);
+        CanFrame::new_fd(id, &data, opts.brs)
+    } else {
+        if opts.pad_frame {
+            data.resize(CAN_MAX_DATA_LEN, ISO_TP_PAD_BYTE);
+        }
+        CanFrame::new(id, &data)
+    }
+}
+
+/// Splits a payload into a list of CAN Frames to send over ISO-TP.
+///
+/// For multi-frame payloads, the first element is the first frame, and the caller
+/// must wait for a flow control frame from the ECU before sending the consecutive frames.
+/// First frames are always full. With a lowered single frame threshold on CAN-FD, a payload
+/// which a full CAN-FD first frame would hold is sent in classic sized frames instead, so
+/// it still takes at least one consecutive frame.
+///
+/// ## Params
+/// * id - CAN ID to send the frames with
+/// * payload - ISO-TP payload to send
+/// * opts - Framing options
+pub fn encode_payload(id: u32, payload: &[u8], opts: &IsoTpOptions) -> Result<Vec<CanFrame>, IsoTpError> {
+    opts.validate()?;
+    if payload.len() > u32::MAX as usize {
+        return Err(IsoTpError::PayloadTooLarge)
+    }
+    let frame_len = opts.data_len();
+
+    // Single frame
+    if payload.len() <= opts.max_single_frame_len() {
+        let mut data = Vec::with_capacity(frame_len);
+        if payload.len() < CAN_MAX_DATA_LEN - opts.addressing.prefix_len() {
+            data.push(payload.len() as u8);
+        } else {
+            // CAN-FD escape sequence. SF_DL is in the 2nd byte
+            data.push(0x00);
+            data.push(payload.len() as u8);
+        }
+        data.extend_from_slice(payload);
+        return Ok(vec![make_frame(id, data, opts)]);
+    }
+
+    // First frame
+    let frame_len = if payload.len() <= frame_len - 2 { opts.classic_first_frame_capacity() + 2 } else { frame_len };
+    let mut frames = Vec::new();
+    let mut data = Vec::with_capacity(frame_len);
+    if payload.len() <= FF_DL_12BIT_MAX {
+        data.push(0x10 | (payload.len() >> 8) as u8);
+        data.push(payload.len() as u8);
+    } else {
+        data.extend_from_slice(&[0x10, 0x00]);
+        data.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    }
+    let mut pos = min(frame_len - data.len(), payload.len());
+    data.extend_from_slice(&payload[0..pos]);
+    frames.push(make_frame(id, data, opts));
+
+    // Consecutive frames
+    let mut seq: u8 = 1;
+    while pos < payload.len() {
+        let end = min(pos + frame_len - 1, payload.len());
+        let mut data = Vec::with_capacity(frame_len);
+        data.push(0x20 | seq);
+        data.extend_from_slice(&payload[pos..end]);
+        frames.push(make_frame(id, data, opts));
+        pos = end;
+        seq = (seq + 1) & 0x0F;
+    }
+    Ok(frames)
+}
+
+/// Creates a flow control frame to send back to an ECU after receiving a first frame
+///
+/// ## Params
+/// * id - CAN ID to send the frame with
+/// * status - Flow status
+/// * block_size - Number of consecutive frames to send before waiting for another flow control frame
+/// * sep_time - Minimum separation time between consecutive frames
+/// * opts - Framing options
+pub fn flow_control_frame(id: u32, status: FlowStatus, block_size: u8, sep_time: u8, opts: &IsoTpOptions) -> CanFrame {
+    make_frame(id, vec![0x30 | status as u8, block_size, sep_time], opts)
+}
+
+/// Attempts to read a flow control frame.
+///
+/// ## Params
+/// * opts - Framing options, for the addressing the frame was sent with
+///
+/// ## Returns
+/// The flow status, block size and separation time
+pub fn parse_flow_control(frame: &CanFrame, opts: &IsoTpOptions) -> Result<(FlowStatus, u8, u8), IsoTpError> {
+    let data = opts.frame_data(frame);
+    if data.len() < 3 {
+        return Err(IsoTpError::InvalidLength)
+    }
+    if data[0] & 0xF0 != 0x30 {
+        return Err(IsoTpError::InvalidPci(data[0]))
+    }
+    let status = match data[0] & 0x0F {
+        0x00 => FlowStatus::ContinueToSend,
+        0x01 => FlowStatus::Wait,
+        0x02 => FlowStatus::Overflow,
+        _ => return Err(IsoTpError::InvalidPci(data[0]))
+    };
+    Ok((status, data[1], data[2]))
+}
+
+/// Reassembles ISO-TP payloads from received CAN frames
+#[derive(Debug, Clone, Default)]
+pub struct IsoTpDecoder {
+    buffer: Vec<u8>,
+    expected_len: usize,
+    next_seq: u8,
+    in_progress: bool,
+    addressing: IsoTpAddressing,
+    max_len: Option<usize>,
+}
+
+impl IsoTpDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a decoder for frames which start with an address byte
+    pub fn with_addressing(addressing: IsoTpAddressing) -> Self {
+        Self { addressing, ..Self::default() }
+    }
+
+    /// Creates a decoder for the addressing and largest payload length of a set of options
+    pub fn with_options(opts: &IsoTpOptions) -> Self {
+        Self { addressing: opts.addressing, max_len: opts.rx_max_len, ..Self::default() }
+    }
+
+    /// Resets the decoder, dropping any partially received payload
+    pub fn reset(&mut self) {
+        self.buffer.clear();
+        self.expected_len = 0;
+        self.next_seq = 0;
+        self.in_progress = false;
+    }
+
+    /// Processes a received CAN frame. Both classic and CAN-FD frames are supported.
+    ///
+    /// The payload of a single frame is the length in its PCI (The low nibble, or the byte after
+    /// it for the CAN-FD escape sequence), so any padding after it is discarded
+    pub fn on_frame(&mut self, frame: &CanFrame) -> Result<RxResult, IsoTpError> {
+        let data = frame.get_data().get(self.addressing.prefix_len()..).unwrap_or(&[]);
+        if data.is_empty() {
+            return Err(IsoTpError::InvalidLength)
+        }
+        match data[0] & 0xF0 {
+            0x00 => {
+                self.reset();
+                let (len, start) = if data[0] & 0x0F != 0 {
+                    ((data[0] & 0x0F) as usize, 1)
+                } else if frame.get_data().len() > CAN_MAX_DATA_LEN {
+                    // CAN-FD escape sequence
+                    (data[1] as usize, 2)
+                } else {
+                    return Err(IsoTpError::InvalidLength)
+                };
+                if len == 0 || start + len > data.len() {
+                    return Err(IsoTpError::InvalidLength)
+                }
+                Ok(RxResult::Complete(Vec::from(&data[start..start + len])))
+            },
+            0x10 => {
+                self.reset();
+                if data.len() < 2 {
+                    return Err(IsoTpError::InvalidLength)
+                }
+                let mut len = ((data[0] & 0x0F) as usize) << 8 | data[1] as usize;
+                let mut start = 2;
+                if len == 0 {
+                    if data.len() < 6 {
+                        return Err(IsoTpError::InvalidLength)
+                    }
+                    len = u32::from_be_bytes([data[2], data[3], data[4], data[5]]) as usize;
+                    start = 6;
+                }
+                if len <= data.len() - start {
+                    // Would have fitted in a single frame
+                    return Err(IsoTpError::InvalidLength)
+                }
+                // Length comes from the other node, so the buffer only grows as frames arrive
+                let max = self.max_len.unwrap_or(DEFAULT_RX_MAX_LEN);
+                if len > max {
+                    return Err(IsoTpError::RxTooLarge { len, max })
+                }
+                self.expected_len = len;
+                self.buffer.extend_from_slice(&data[start..]);
+                self.next_seq = 1;
+                self.in_progress = true;
+                Ok(RxResult::FlowControlRequired)
+            },
+            0x20 => {
+                if !self.in_progress {
+                    return Err(IsoTpError::UnexpectedConsecutiveFrame)
+                }
+                let seq = data[0] & 0x0F;
+                if seq != self.next_seq {
+                    // A frame was lost, so the rest of the payload cannot be trusted
+                    let expected = self.next_seq;
+                    self.reset();
+                    return Err(IsoTpError::SequenceError { expected, got: seq })
+                }
+                self.next_seq = (self.next_seq + 1) & 0x0F;
+                let take = min(self.expected_len - self.buffer.len(), data.len() - 1);
+                self.buffer.extend_from_slice(&data[1..1 + take]);
+                if self.buffer.len() >= self.expected_len {
+                    let payload = std::mem::take(&mut self.buffer);
+                    self.reset();
+                    Ok(RxResult::Complete(payload))
+                } else {
+                    Ok(RxResult::Pending)
+                }
+            },
+            _ => Err(IsoTpError::InvalidPci(data[0]))
+        }
+    }
+}
+
+/// Converts an STmin byte from a flow control frame into a duration
+pub(crate) fn st_min_to_duration(st_min: u8) -> Duration {
+    match st_min {
+        0x00..=0x7F => Duration::from_millis(st_min as u64),
+        0xF1..=0xF9 => Duration::from_micros((st_min - 0xF0) as u64 * 100),
+        _ => Duration::from_millis(0x7F) // Reserved, use the longest time
+    }
+}
+
+/// Max time to wait for a flow control frame (N_Bs)
+const FC_TIMEOUT_MS: u128 = 1000;
+
+/// ISO-TP transport implemented in software over a raw [CanChannel].
+///
+/// This is for adapters (Or buses) where the adapter's own ISO15765 implementation
+/// cannot be used, for example with CAN-FD.
+#[derive(Debug)]
+pub struct IsoTpChannel {
+    channel: CanChannel,
+    cfg: ISO15765Config,
+    opts: IsoTpOptions,
+    decoder: IsoTpDecoder,
+}
+
+impl IsoTpChannel {
+    /// Creates a new ISO-TP channel, installing a receive filter on the CAN channel
+    /// for the configured receive ID
+    pub fn new(mut channel: CanChannel, cfg: ISO15765Config, opts: IsoTpOptions) -> Result<Self, IsoTpError> {
+        opts.validate()?;
+        channel.set_filter(&[CanIdFilter::exact(cfg.recv_id)])?;
+        Ok(Self {
+            channel,
+            cfg,
+            opts,
+            decoder: IsoTpDecoder::with_options(&opts),
+        })
+    }
+
+    fn read_frame(&mut self, deadline: Instant) -> Result<CanFrame, IsoTpError> {
+        loop {
+            if let Some(f) = self.channel.recv(0, 1)?.into_iter().find(|f| f.id == self.cfg.recv_id) {
+                return Ok(f)
+            }
+            if Instant::now() >= deadline {
+                return Err(IsoTpError::Timeout)
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// Waits for a flow control frame, skipping any Wait frames
+    fn wait_flow_control(&mut self) -> Result<(u8, u8), IsoTpError> {
+        loop {
+            let frame = self.read_frame(Instant::now() + Duration::from_millis(FC_TIMEOUT_MS as u64))?;
+            if self.opts.frame_data(&frame).first().map(|x| x & 0xF0) != Some(0x30) {
+                continue; // Not a flow control frame
+            }
+            match parse_flow_control(&frame, &self.opts)? {
+                (FlowStatus::ContinueToSend, bs, st) => return Ok((bs, st)),
+                (FlowStatus::Wait, _, _) => continue,
+                (FlowStatus::Overflow, _, _) => return Err(IsoTpError::Overflow)
+            }
+        }
+    }
+
+    /// Sends a payload to the ECU, handling flow control from the ECU
+    pub fn send(&mut self, payload: &[u8]) -> Result<(), IsoTpError> {
+        let frames = encode_payload(self.cfg.send_id, payload, &self.opts)?;
+        self.channel.send(&frames[0..1], 0)?;
+        if frames.len() == 1 {
+            return Ok(())
+        }
+        let (mut bs, mut st) = self.wait_flow_control()?;
+        let mut remaining = &frames[1..];
+        loop {
+            let block_len = if bs == 0 { remaining.len() } else { std::cmp::min(bs as usize, remaining.len()) };
+            let (block, rest) = remaining.split_at(block_len);
+            if st == 0 {
+                // No gap is needed between frames, so the block goes to the adapter in one burst
+                let sent = self.channel.send_batch(block)?;
+                if sent != block.len() {
+                    return Err(ComServerError { err_code: ERR_FAILED, err_desc: format!("Only {} of {} consecutive frames were sent", sent, block.len()) }.into())
+                }
+            } else {
+                for f in block {
+                    std::thread::sleep(st_min_to_duration(st));
+                    self.channel.send(&[*f], 0)?;
+                }
+            }
+            remaining = rest;
+            if remaining.is_empty() {
+                return Ok(())
+            }
+            let (new_bs, new_st) = self.wait_flow_control()?;
+            bs = new_bs;
+            st = new_st;
+        }
+    }
+
+    /// Receives a payload from the ECU, sending flow control frames as required.
+    /// A payload which is too large is refused with an overflow flow control frame
+    ///
+    /// # Params
+    /// * timeout_ms - Max time to wait between frames
+    pub fn recv(&mut self, timeout_ms: u32) -> Result<Vec<u8>, IsoTpError> {
+        loop {
+            let frame = self.read_frame(Instant::now() + Duration::from_millis(timeout_ms as u64))?;
+            if self.opts.frame_data(&frame).first().map(|x| x & 0xF0) == Some(0x30) {
+                continue; // Stray flow control frame
+            }
+            let res = self.decoder.on_frame(&frame);
+            if let Err(IsoTpError::RxTooLarge { .. }) = res {
+                let fc = flow_control_frame(self.cfg.send_id, FlowStatus::Overflow, 0, 0, &self.opts);
+                self.channel.send(&[fc], 0)?;
+            }
+            match res? {
+                RxResult::Complete(payload) => return Ok(payload),
+                RxResult::FlowControlRequired => {
+                    let fc = flow_control_frame(self.cfg.send_id, FlowStatus::ContinueToSend, self.cfg.block_size as u8, self.cfg.sep_time as u8, &self.opts);
+                    self.channel.send(&[fc], 0)?;
+                },
+                RxResult::Pending => {}
+            }
+        }
+    }
+}
+
+#[test]
+fn test_fd_single_frame() {
+    let payload: Vec<u8> = (0..40).collect();
+    let opts = IsoTpOptions { fd: true, brs: true, pad_frame: true, ..Default::default() };
+    let frames = encode_payload(0x07E0, &payload, &opts).unwrap();
+    assert_eq!(frames.len(), 1);
+    assert!(frames[0].fd);
+    assert_eq!(frames[0].get_len(), 48); // 40 bytes + 2 PCI bytes, padded to DLC 13
+    assert_eq!(&frames[0].get_data()[0..2], &[0x00, 40]);
+
+    let mut decoder = IsoTpDecoder::new();
+    assert_eq!(decoder.on_frame(&frames[0]), Ok(RxResult::Complete(payload)));
+}
+
+#[test]
+fn test_fd_multi_frame() {
+    let payload: Vec<u8> = (0..200).map(|x| x as u8).collect();
+    let opts = IsoTpOptions { fd: true, brs: false, pad_frame: true, ..Default::default() };
+    let frames = encode_payload(0x07E0, &payload, &opts).unwrap();
+    // 62 bytes in the first frame, then 63 bytes per consecutive frame
+    assert_eq!(frames.len(), 4);
+    assert_eq!(&frames[0].get_data()[0..2], &[0x10, 200]);
+    assert_eq!(frames[1].get_data()[0], 0x21);
+    assert_eq!(frames[3].get_len(), 16); // 12 bytes + PCI, padded to DLC 10
+
+    let mut decoder = IsoTpDecoder::new();
+    assert_eq!(decoder.on_frame(&frames[0]), Ok(RxResult::FlowControlRequired));
+    assert_eq!(decoder.on_frame(&frames[1]), Ok(RxResult::Pending));
+    assert_eq!(decoder.on_frame(&frames[2]), Ok(RxResult::Pending));
+    assert_eq!(decoder.on_frame(&frames[3]), Ok(RxResult::Complete(payload)));
+}
+
+#[test]
+fn test_consecutive_frame_sequence() {
+    // 6 bytes in the first frame and 7 per consecutive frame, so the sequence wraps past 15
+    let payload: Vec<u8> = (0..150).map(|x| x as u8).collect();
+    let frames = encode_payload(0x07E8, &payload, &IsoTpOptions::default()).unwrap();
+    assert_eq!(frames.len(), 22);
+    assert_eq!(frames[15].get_data()[0], 0x2F);
+    assert_eq!(frames[16].get_data()[0], 0x20);
+
+    let mut decoder = IsoTpDecoder::new();
+    let mut res = Vec::new();
+    for f in &frames {
+        res.push(decoder.on_frame(f).unwrap());
+    }
+    assert_eq!(res.last(), Some(&RxResult::Complete(payload)));
+
+    // Frame with sequence number 3 is lost
+    let mut decoder = IsoTpDecoder::new();
+    for f in &frames[0..3] {
+        decoder.on_frame(f).unwrap();
+    }
+    assert_eq!(decoder.on_frame(&frames[4]), Err(IsoTpError::SequenceError { expected: 3, got: 4 }));
+    // Reassembly was aborted
+    assert_eq!(decoder.on_frame(&frames[5]), Err(IsoTpError::UnexpectedConsecutiveFrame));
+    // A new first frame starts again
+    assert_eq!(decoder.on_frame(&frames[0]), Ok(RxResult::FlowControlRequired));
+}
+
+#[test]
+fn test_padded_single_frame() {
+    // ReadDataByID response with 3 bytes of data, padded to 8 bytes
+    for pad in &[ISO_TP_PAD_BYTE, 0x00, 0xAA, 0x55] {
+        let frame = CanFrame::new(0x07E8, &[0x03, 0x62, 0xF1, 0x90, *pad, *pad, *pad, *pad]);
+        assert_eq!(IsoTpDecoder::new().on_frame(&frame), Ok(RxResult::Complete(vec![0x62, 0xF1, 0x90])));
+    }
+    let frame = CanFrame::new(0x06F1, &[0xF1, 0x03, 0x62, 0xF1, 0x90, 0xCC, 0xCC, 0xCC]);
+    assert_eq!(IsoTpDecoder::with_addressing(IsoTpAddressing::Extended(0xF1)).on_frame(&frame), Ok(RxResult::Complete(vec![0x62, 0xF1, 0x90])));
+
+    // CAN-FD frames are padded up to the next valid length, with either length encoding
+    let frame = CanFrame::new_fd(0x07E8, &[0x03, 0x62, 0xF1, 0x90, 0xCC, 0xCC, 0xCC, 0xCC, 0xCC, 0xCC, 0xCC, 0xCC], false);
+    assert_eq!(IsoTpDecoder::new().on_frame(&frame), Ok(RxResult::Complete(vec![0x62, 0xF1, 0x90])));
+    let mut data = vec![0x00, 0x0A];
+    data.extend((0..10).map(|x| x as u8));
+    data.resize(64, ISO_TP_PAD_BYTE);
+    let frame = CanFrame::new_fd(0x07E8, &data, false);
+    assert_eq!(IsoTpDecoder::new().on_frame(&frame), Ok(RxResult::Complete((0..10).collect())));
+
+    // Length longer than the frame
+    let frame = CanFrame::new(0x07E8, &[0x05, 0x62, 0xF1, 0x90]);
+    assert_eq!(IsoTpDecoder::new().on_frame(&frame), Err(IsoTpError::InvalidLength));
+}
+
+#[test]
+fn test_single_frame_threshold() {
+    let opts = IsoTpOptions { single_frame_max: Some(7), ..Default::default() };
+    let frames = encode_payload(0x07E0, &[0x11; 7], &opts).unwrap();
+    assert_eq!(frames.len(), 1);
+    assert_eq!(frames[0].get_data()[0], 0x07);
+
+    let frames = encode_payload(0x07E0, &[0x11; 8], &opts).unwrap();
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[0].get_data(), &[0x10, 0x08, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11]);
+    assert_eq!(frames[1].get_data(), &[0x21, 0x11, 0x11]);
+
+    // ECU which wants a first frame for anything over 6 bytes
+    let opts = IsoTpOptions { single_frame_max: Some(6), ..Default::default() };
+    assert_eq!(encode_payload(0x07E0, &[0x11; 6], &opts).unwrap().len(), 1);
+    let frames = encode_payload(0x07E0, &[0x11; 7], &opts).unwrap();
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[0].get_data(), &[0x10, 0x07, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11]);
+    assert_eq!(frames[1].get_data(), &[0x21, 0x11]);
+
+    // CAN-FD escape sequence is not used if the threshold is below it, and a payload a full
+    // CAN-FD first frame would hold is sent in classic sized frames
+    let opts = IsoTpOptions { fd: true, single_frame_max: Some(7), ..Default::default() };
+    let frames = encode_payload(0x07E0, &[0x11; 20], &opts).unwrap();
+    assert_eq!(frames.len(), 3);
+    assert_eq!(frames[0].get_data(), &[0x10, 20, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11]);
+    assert!(frames.iter().all(|f| f.fd && f.get_len() == 8));
+    assert_eq!(frames[2].get_data()[0], 0x22);
+
+    // Below what a first frame holds
+    let ext = IsoTpOptions { addressing: IsoTpAddressing::Extended(0x40), single_frame_max: Some(5), ..Default::default() };
+    assert!(ext.validate().is_ok());
+    for (fd, max) in &[(false, 8), (false, 0), (false, 5), (true, 63), (true, 5)] {
+        let opts = IsoTpOptions { fd: *fd, single_frame_max: Some(*max), ..Default::default() };
+        assert!(matches!(opts.validate(), Err(IsoTpError::InvalidConfig(_))));
+        assert!(matches!(encode_payload(0x07E0, &[0x11], &opts), Err(IsoTpError::InvalidConfig(_))));
+    }
+    assert!(IsoTpOptions { fd: true, single_frame_max: Some(62), ..Default::default() }.validate().is_ok());
+}
+
+#[test]
+fn test_single_frame_threshold_round_trip() {
+    let payload: Vec<u8> = (0..100).collect();
+    let addressing = [IsoTpAddressing::Normal, IsoTpAddressing::Extended(0x40)];
+    for (fd, addr, max) in [false, true].iter().flat_map(|fd| addressing.iter().flat_map(move |a| (5..=8).map(move |m| (*fd, *a, m)))) {
+        let opts = IsoTpOptions { fd, addressing: addr, single_frame_max: Some(max), pad_frame: true, ..Default::default() };
+        if opts.validate().is_err() {
+            continue
+        }
+        for len in 1..payload.len() {
+            let frames = encode_payload(0x07E0, &payload[..len], &opts).unwrap();
+            assert_eq!(frames.len() == 1, len <= max, "{:?} {}", opts, len);
+            let mut decoder = IsoTpDecoder::with_addressing(addr);
+            let res: Vec<RxResult> = frames.iter().map(|f| decoder.on_frame(f).unwrap()).collect();
+            assert_eq!(res.last(), Some(&RxResult::Complete(payload[..len].to_vec())), "{:?} {}", opts, len);
+        }
+    }
+}
+
+#[test]
+fn test_channel_installs_rx_filter() {
+    use crate::commapi::mock_api::MockComServer;
+    let mut mock = MockComServer::new();
+    let payload: Vec<u8> = (0..20).collect();
+    let resp = payload.clone();
+    // ECU responds to our request with a multi frame response, whilst other traffic is on the bus
+    mock.set_responder(move |f| {
+        let data = f.get_data();
+        if f.id == 0x07E0 && data[0] == 0x02 {
+            let mut frames = encode_payload(0x07E8, &resp, &IsoTpOptions::default()).unwrap();
+            frames.insert(0, CanFrame::new(0x0100, &[0xFF; 8]));
+            frames.truncate(2); // Only FF until flow control is received
+            frames
+        } else if f.id == 0x07E0 && data[0] == 0x30 {
+            let mut frames = encode_payload(0x07E8, &resp, &IsoTpOptions::default()).unwrap();
+            frames.remove(0);
+            frames.insert(1, CanFrame::new(0x0200, &[0xFF; 8]));
+            frames
+        } else {
+            vec![]
+        }
+    });
+    let cfg = ISO15765Config { send_id: 0x07E0, recv_id: 0x07E8, block_size: 0, sep_time: 0 };
+    let mut channel = IsoTpChannel::new(CanChannel::new(Box::new(mock.clone())), cfg, IsoTpOptions::default()).unwrap();
+    assert_eq!(mock.get_filters(), vec![CanIdFilter::exact(0x07E8)]);
+
+    channel.send(&[0x22, 0xF1]).unwrap();
+    assert_eq!(channel.recv(100).unwrap(), payload);
+}
+
+#[test]
+fn test_extended_addressing_layout() {
+    let payload: Vec<u8> = (0..14).collect();
+    let normal = IsoTpOptions::default();
+    let frames: Vec<Vec<u8>> = encode_payload(0x7E0, &payload, &normal).unwrap().iter().map(|f| f.get_data().to_vec()).collect();
+    assert_eq!(frames, vec![
+        vec![0x10, 0x0E, 0, 1, 2, 3, 4, 5],
+        vec![0x21, 6, 7, 8, 9, 10, 11, 12],
+        vec![0x22, 13],
+    ]);
+
+    // Address byte first, leaving one byte less for data in every frame
+    let extended = IsoTpOptions { addressing: IsoTpAddressing::Extended(0x40), ..Default::default() };
+    let encoded = encode_payload(0x6F1, &payload, &extended).unwrap();
+    let frames: Vec<Vec<u8>> = encoded.iter().map(|f| f.get_data().to_vec()).collect();
+    assert_eq!(frames, vec![
+        vec![0x40, 0x10, 0x0E, 0, 1, 2, 3, 4],
+        vec![0x40, 0x21, 5, 6, 7, 8, 9, 10],
+        vec![0x40, 0x22, 11, 12, 13],
+    ]);
+    let mut decoder = IsoTpDecoder::with_addressing(IsoTpAddressing::Extended(0xF1));
+    assert_eq!(decoder.on_frame(&encoded[0]).unwrap(), RxResult::FlowControlRequired);
+    assert_eq!(decoder.on_frame(&encoded[1]).unwrap(), RxResult::Pending);
+    assert_eq!(decoder.on_frame(&encoded[2]).unwrap(), RxResult::Complete(payload.clone()));
+
+    // Single frames hold 6 bytes instead of 7
+    let mixed = IsoTpOptions { addressing: IsoTpAddressing::Mixed(0x12), pad_frame: true, ..Default::default() };
+    assert_eq!(mixed.single_frame_capacity(), 6);
+    assert_eq!(encode_payload(0x18CEF1DA, &payload[..6], &mixed).unwrap()[0].get_data(), &[0x12, 0x06, 0, 1, 2, 3, 4, 5]);
+    assert_eq!(encode_payload(0x18CEF1DA, &payload[..7], &mixed).unwrap().len(), 2);
+    assert_eq!(encode_payload(0x7E0, &payload[..7], &normal).unwrap().len(), 1);
+
+    let fc = flow_control_frame(0x6F1, FlowStatus::ContinueToSend, 8, 20, &extended);
+    assert_eq!(fc.get_data(), &[0x40, 0x30, 0x08, 0x14]);
+    assert_eq!(parse_flow_control(&fc, &extended).unwrap(), (FlowStatus::ContinueToSend, 8, 20));
+    assert!(parse_flow_control(&fc, &normal).is_err());
+
+    // CAN-FD escape sequence is used once the data does not fit in 8 bytes
+    let fd = IsoTpOptions { fd: true, addressing: IsoTpAddressing::Extended(0x40), ..Default::default() };
+    assert_eq!(fd.single_frame_capacity(), 61);
+    let sf = encode_payload(0x6F1, &payload[..10], &fd).unwrap();
+    assert_eq!(&sf[0].get_data()[..4], &[0x40, 0x00, 0x0A, 0x00]);
+    assert_eq!(IsoTpDecoder::with_addressing(IsoTpAddressing::Extended(0xF1)).on_frame(&sf[0]).unwrap(), RxResult::Complete(payload[..10].to_vec()));
+}
+
+#[test]
+fn test_send_consecutive_frames_in_blocks() {
+    use crate::commapi::mock_api::MockComServer;
+    // ECU asks for blocks of 4 consecutive frames with no gap between them
+    let mut mock = MockComServer::new();
+    mock.set_responder(|f| match f.get_data()[0] {
+        0x10 | 0x24 => vec![flow_control_frame(0x07E8, FlowStatus::ContinueToSend, 4, 0, &IsoTpOptions::default())],
+        _ => vec![]
+    });
+    let cfg = ISO15765Config { send_id: 0x07E0, recv_id: 0x07E8, block_size: 0, sep_time: 0 };
+    let mut channel = IsoTpChannel::new(CanChannel::new(Box::new(mock.clone())), cfg, IsoTpOptions::default()).unwrap();
+    let payload: Vec<u8> = (0..50).collect();
+    channel.send(&payload).unwrap();
+
+    // FF, then 7 CFs as a block of 4 and a block of 3
+    let sent: Vec<Vec<u8>> = mock.get_tx_log().iter().map(|f| f.get_data().to_vec()).collect();
+    let expected: Vec<Vec<u8>> = encode_payload(0x07E0, &payload, &IsoTpOptions::default()).unwrap().iter().map(|f| f.get_data().to_vec()).collect();
+    assert_eq!(sent, expected);
+    assert_eq!(sent.len(), 8);
+    assert_eq!(mock.get_send_calls(), 3);
+}
+
+#[test]
+fn test_rx_max_len() {
+    let payload: Vec<u8> = (0..200).map(|x| x as u8).collect();
+    let frames = encode_payload(0x07E8, &payload, &IsoTpOptions::default()).unwrap();
+    let mut decoder = IsoTpDecoder::with_options(&IsoTpOptions { rx_max_len: Some(199), ..Default::default() });
+    assert_eq!(decoder.on_frame(&frames[0]), Err(IsoTpError::RxTooLarge { len: 200, max: 199 }));
+    assert_eq!(decoder.on_frame(&frames[1]), Err(IsoTpError::UnexpectedConsecutiveFrame));
+
+    let mut decoder = IsoTpDecoder::with_options(&IsoTpOptions { rx_max_len: Some(200), ..Default::default() });
+    let res: Vec<RxResult> = frames.iter().map(|f| decoder.on_frame(f).unwrap()).collect();
+    assert_eq!(res.last(), Some(&RxResult::Complete(payload)));
+
+    // 32 bit length escape announcing 4 GiB, without the data to back it
+    let frame = CanFrame::new(0x07E8, &[0x10, 0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0x00, 0x00]);
+    let mut decoder = IsoTpDecoder::new();
+    assert_eq!(decoder.on_frame(&frame), Err(IsoTpError::RxTooLarge { len: 0xFFFF_FFFF, max: DEFAULT_RX_MAX_LEN }));
+    assert_eq!(decoder.buffer.capacity(), 0);
+
+    // Channel refuses the payload with an overflow flow control frame
+    use crate::commapi::mock_api::MockComServer;
+    let mut mock = MockComServer::new();
+    mock.set_responder(move |f| if f.id == 0x07E0 && f.get_data()[0] == 0x02 { vec![frame] } else { vec![] });
+    let cfg = ISO15765Config { send_id: 0x07E0, recv_id: 0x07E8, block_size: 0, sep_time: 0 };
+    let mut channel = IsoTpChannel::new(CanChannel::new(Box::new(mock.clone())), cfg, IsoTpOptions::default()).unwrap();
+    channel.send(&[0x22, 0xF1]).unwrap();
+    assert!(matches!(channel.recv(100), Err(IsoTpError::RxTooLarge { .. })));
+    assert_eq!(mock.get_tx_log().last().unwrap().get_data()[0], 0x32);
+}
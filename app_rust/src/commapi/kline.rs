@@ -0,0 +1,458 @@
+use std::time::{Duration, Instant};
+use crate::commapi::comm_api::{ComServerError, ERR_FAILED};
+use crate::commapi::serial::{ByteTransport, SerialTransport};
+
+/// Time the K-line has to be idle (High) before a wakeup (W5)
+const IDLE_TIME: Duration = Duration::from_millis(300);
+/// Length of each bit of the address in a 5 baud init
+const FIVE_BAUD_BIT_TIME: Duration = Duration::from_millis(200);
+/// Max time from the end of the address to the sync byte (W1)
+const SYNC_TIMEOUT: Duration = Duration::from_millis(300);
+/// Max time to wait for each key byte. The standard allows 20ms (W2, W3), the rest is for adapter latency
+const KEY_BYTE_TIMEOUT: Duration = Duration::from_millis(100);
+/// Time between the ECU's last key byte and the tester's inverted key byte 2 (W4, 25 to 50ms)
+const W4: Duration = Duration::from_millis(30);
+/// Length of the low, then high, pulses of a fast init (TiniL, TWuP - TiniL)
+const FAST_INIT_PULSE: Duration = Duration::from_millis(25);
+/// Max time to wait for the response to StartCommunication. The standard allows 50ms (P2max), the rest is for adapter latency
+const START_COMM_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// KWP2000 StartCommunication service
+const SID_START_COMMUNICATION: u8 = 0x81;
+
+fn kline_error(desc: String) -> ComServerError {
+    ComServerError { err_code: ERR_FAILED, err_desc: desc }
+}
+
+/// Adapter which can drive the K-line directly, rather than running a protocol itself.
+/// The wakeup patterns are made by holding the line low, so bits slower than any baud rate
+/// the UART can do (5 baud) can be sent
+pub trait KLineTransport: ByteTransport {
+    /// Holds the K-line low (Break), or releases it to idle high
+    fn set_line_low(&mut self, low: bool) -> Result<(), ComServerError>;
+
+    /// Sets the baud rate bytes are sent and received at
+    fn set_baud(&mut self, baud: u32) -> Result<(), ComServerError>;
+}
+
+/// A serial K-line (KKL) cable. The line is driven low with a break condition
+impl KLineTransport for SerialTransport {
+    fn set_line_low(&mut self, low: bool) -> Result<(), ComServerError> {
+        self.set_break(low)
+    }
+
+    fn set_baud(&mut self, baud: u32) -> Result<(), ComServerError> {
+        SerialTransport::set_baud(self, baud)
+    }
+}
+
+/// Protocol an ECU talks, from the key bytes it sends during init
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum KLineProtocol {
+    /// ISO 9141-2 (OBD on K-line). Key bytes 08 08 or 94 94
+    Iso9141_2,
+    /// ISO 14230 (KWP2000). Key word 2000 to 2031, the low 5 bits say which header formats
+    /// and timing the ECU supports
+    Kwp2000,
+    /// VAG KW1281. Key bytes 01 8A
+    Kw1281,
+    /// Key word which is not known
+    Unknown(u16),
+}
+
+/// The 2 key bytes an ECU sends during init, which say which protocol it talks
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct KeyBytes {
+    pub kb1: u8,
+    pub kb2: u8,
+}
+
+impl KeyBytes {
+    /// Key word the bytes encode, with the parity bits (Bit 7 of each byte) removed. Example: 2031 for EF 8F
+    pub fn key_word(&self) -> u16 {
+        (((self.kb2 & 0x7F) as u16) << 7) | (self.kb1 & 0x7F) as u16
+    }
+
+    pub fn protocol(&self) -> KLineProtocol {
+        match (self.kb1, self.kb2) {
+            (0x08, 0x08) | (0x94, 0x94) => KLineProtocol::Iso9141_2,
+            _ => match self.key_word() {
+                2000..=2031 => KLineProtocol::Kwp2000,
+                1281 => KLineProtocol::Kw1281,
+                x => KLineProtocol::Unknown(x)
+            }
+        }
+    }
+
+    /// KWP2000 only. The ECU accepts the length of a message in the format byte (AL0)
+    pub fn length_in_format(&self) -> bool {
+        self.kb1 & 0x01 != 0
+    }
+
+    /// KWP2000 only. The ECU accepts the length of a message in a separate length byte (AL1)
+    pub fn length_byte(&self) -> bool {
+        self.kb1 & 0x02 != 0
+    }
+
+    /// KWP2000 only. The ECU accepts messages with only a format byte as the header (HB0)
+    pub fn one_byte_header(&self) -> bool {
+        self.kb1 & 0x04 != 0
+    }
+
+    /// KWP2000 only. The ECU accepts messages with target and source addresses in the header (HB1)
+    pub fn address_header(&self) -> bool {
+        self.kb1 & 0x08 != 0
+    }
+
+    /// KWP2000 only. The ECU uses extended timing, rather than normal timing. ISO14230-2 encodes
+    /// normal timing as TP0 (Bit 4) set and TP1 (Bit 5) clear, and extended timing as the reverse
+    pub fn extended_timing(&self) -> bool {
+        self.kb1 & 0x30 == 0x20
+    }
+}
+
+/// Level of the K-line for each bit of a 5 baud address: a start bit, 8 data bits (Least
+/// significant first) and a stop bit. True is low
+fn five_baud_bits(addr: u8) -> [bool; 10] {
+    let mut res = [false; 10];
+    res[0] = true;
+    for (i, bit) in res[1..9].iter_mut().enumerate() {
+        *bit = (addr >> i) & 1 == 0;
+    }
+    res
+}
+
+/// Builds a KWP2000 message with a format byte holding the length, and target and source addresses
+fn build_kwp_frame(target: u8, source: u8, data: &[u8]) -> Vec<u8> {
+    let mut res = vec![0x80 | data.len() as u8, target, source];
+    res.extend_from_slice(data);
+    res.push(res.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)));
+    res
+}
+
+/// Parses a KWP2000 message from the start of `buf`
+///
+/// # Returns
+/// * Ok(None) - `buf` does not hold the whole message yet
+/// * Ok(Some(data)) - Data of the message, without the header and checksum
+/// * Err - The checksum is wrong
+fn parse_kwp_frame(buf: &[u8]) -> Result<Option<Vec<u8>>, ComServerError> {
+    let fmt = match buf.first() {
+        Some(f) => *f,
+        None => return Ok(None)
+    };
+    let mut header_len = if fmt & 0xC0 != 0 { 3 } else { 1 };
+    let len = match fmt & 0x3F {
+        0 => {
+            header_len += 1;
+            match buf.get(header_len - 1) {
+                Some(l) => *l as usize,
+                None => return Ok(None)
+            }
+        },
+        l => l as usize
+    };
+    if buf.len() < header_len + len + 1 {
+        return Ok(None)
+    }
+    let sum = buf[..header_len + len].iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+    if sum != buf[header_len + len] {
+        return Err(kline_error(format!("Invalid checksum in {:02X?}", &buf[..=header_len + len])))
+    }
+    Ok(Some(buf[header_len..header_len + len].to_vec()))
+}
+
+/// Sleeps until `deadline`. Used so that the length of a bit does not include the time taken to change the line
+fn sleep_until(deadline: Instant) {
+    if let Some(d) = deadline.checked_duration_since(Instant::now()) {
+        std::thread::sleep(d)
+    }
+}
+
+/// K-line connection to an ECU, for the pre-CAN ECUs which need a wakeup pattern before they
+/// will talk KWP2000 or ISO 9141.
+///
+/// Both wakeups are timing critical, and are done with the line held low by the transport,
+/// so they need an adapter which gives direct control of the line (Such as a KKL serial cable)
+pub struct KLineChannel<T: KLineTransport> {
+    transport: T,
+    baud: u32,
+    tester_addr: u8,
+    /// Bytes sent are also received, as on a single wire K-line cable
+    echo: bool,
+    key_bytes: Option<KeyBytes>,
+}
+
+impl<T: KLineTransport> std::fmt::Debug for KLineChannel<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "KLineChannel ({} baud, key bytes {:?})", self.baud, self.key_bytes)
+    }
+}
+
+impl<T: KLineTransport> KLineChannel<T> {
+    /// # Params
+    /// * baud - Baud rate the ECU talks at after init. Almost always 10400
+    pub fn new(transport: T, baud: u32) -> Self {
+        Self { transport, baud, tester_addr: 0xF1, echo: true, key_bytes: None }
+    }
+
+    /// Sets the address of the tester sent in KWP2000 headers. 0xF1 by default
+    pub fn set_tester_addr(&mut self, addr: u8) {
+        self.tester_addr = addr
+    }
+
+    /// Sets if bytes sent are received back. True by default, as on a KKL cable
+    pub fn set_echo(&mut self, echo: bool) {
+        self.echo = echo
+    }
+
+    /// Key bytes from the last successful init
+    pub fn key_bytes(&self) -> Option<KeyBytes> {
+        self.key_bytes
+    }
+
+    pub fn transport(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    fn read_byte(&mut self, timeout: Duration) -> Result<Option<u8>, ComServerError> {
+        let mut buf = [0u8; 1];
+        Ok(match self.transport.read_bytes(&mut buf, timeout)? {
+            0 => None,
+            _ => Some(buf[0])
+        })
+    }
+
+    fn expect_byte(&mut self, timeout: Duration, what: &str) -> Result<u8, ComServerError> {
+        self.read_byte(timeout)?.ok_or_else(|| kline_error(format!("ECU did not send the {}", what)))
+    }
+
+    /// Sends bytes, and reads back the echo if there is one
+    fn write(&mut self, data: &[u8]) -> Result<(), ComServerError> {
+        self.transport.write_bytes(data)?;
+        if self.echo {
+            for b in data {
+                match self.read_byte(KEY_BYTE_TIMEOUT)? {
+                    Some(e) if e == *b => {},
+                    e => return Err(kline_error(format!("Sent 0x{:02X} but read back {:02X?}, the K-line may be busy", b, e)))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Releases the line and waits for it to be idle long enough for a wakeup
+    fn idle(&mut self) -> Result<(), ComServerError> {
+        self.key_bytes = None;
+        self.transport.set_line_low(false)?;
+        self.transport.set_baud(self.baud)?;
+        std::thread::sleep(IDLE_TIME);
+        self.transport.clear_input()
+    }
+
+    /// Wakes an ECU by sending its address at 5 baud (ISO 9141 / ISO 14230 slow init),
+    /// then reads its key bytes and confirms them.
+    ///
+    /// Takes just over 2 seconds, most of it sending the address
+    ///
+    /// # Params
+    /// * addr - Address of the ECU, sent as is. 0x33 for OBD
+    pub fn five_baud_init(&mut self, addr: u8) -> Result<KeyBytes, ComServerError> {
+        self.idle()?;
+        let start = Instant::now();
+        for (i, low) in five_baud_bits(addr).iter().enumerate() {
+            self.transport.set_line_low(*low)?;
+            sleep_until(start + FIVE_BAUD_BIT_TIME * (i as u32 + 1));
+        }
+        // The UART sees the address as garbage
+        self.transport.clear_input()?;
+
+        match self.read_byte(SYNC_TIMEOUT)? {
+            Some(0x55) => {},
+            Some(b) => return Err(kline_error(format!("Expected sync byte 0x55 from ECU 0x{:02X}, got 0x{:02X}. Check the baud rate", addr, b))),
+            None => return Err(kline_error(format!("ECU 0x{:02X} did not respond to the 5 baud init", addr)))
+        }
+        let kb = KeyBytes {
+            kb1: self.expect_byte(KEY_BYTE_TIMEOUT, "first key byte")?,
+            kb2: self.expect_byte(KEY_BYTE_TIMEOUT, "second key byte")?,
+        };
+        std::thread::sleep(W4);
+        self.write(&[!kb.kb2])?;
+        match self.read_byte(KEY_BYTE_TIMEOUT)? {
+            Some(b) if b == !addr => {},
+            b => return Err(kline_error(format!("ECU did not confirm the key bytes, expected 0x{:02X} but got {:02X?}", !addr, b)))
+        }
+        self.key_bytes = Some(kb);
+        Ok(kb)
+    }
+
+    /// Wakes a KWP2000 ECU with a fast init (25ms low, 25ms high), then sends it StartCommunication.
+    /// The key bytes are in the positive response
+    ///
+    /// # Params
+    /// * target - Address of the ECU. Example: 0x33 for OBD
+    pub fn fast_init(&mut self, target: u8) -> Result<KeyBytes, ComServerError> {
+        self.idle()?;
+        let start = Instant::now();
+        self.transport.set_line_low(true)?;
+        sleep_until(start + FAST_INIT_PULSE);
+        self.transport.set_line_low(false)?;
+        sleep_until(start + FAST_INIT_PULSE * 2);
+        self.write(&build_kwp_frame(target, self.tester_addr, &[SID_START_COMMUNICATION]))?;
+
+        let start = Instant::now();
+        let mut buf = Vec::new();
+        let data = loop {
+            if let Some(data) = parse_kwp_frame(&buf)? {
+                break data
+            }
+            let left = START_COMM_TIMEOUT.checked_sub(start.elapsed())
+                .ok_or_else(|| kline_error(format!("ECU 0x{:02X} did not respond to StartCommunication", target)))?;
+            if let Some(b) = self.read_byte(left)? {
+                buf.push(b)
+            }
+        };
+        match data.as_slice() {
+            [0xC1, kb1, kb2, ..] => {
+                let kb = KeyBytes { kb1: *kb1, kb2: *kb2 };
+                self.key_bytes = Some(kb);
+                Ok(kb)
+            },
+            [0x7F, SID_START_COMMUNICATION, nrc, ..] => Err(kline_error(format!("ECU 0x{:02X} rejected StartCommunication (NRC 0x{:02X})", target, nrc))),
+            _ => Err(kline_error(format!("Invalid response to StartCommunication {:02X?}", data)))
+        }
+    }
+}
+
+/// K-line adapter which answers a wakeup with scripted bytes
+#[cfg(test)]
+#[derive(Default)]
+struct MockKLine {
+    /// Level set after each change, true is low
+    line: Vec<bool>,
+    written: Vec<u8>,
+    rx: std::collections::VecDeque<u8>,
+    /// Sent by the ECU once it sees a 5 baud address
+    wakeup_response: Vec<u8>,
+    /// Sent by the ECU in response to each write, after the echo
+    responses: std::collections::VecDeque<Vec<u8>>,
+}
+
+#[cfg(test)]
+impl ByteTransport for MockKLine {
+    fn write_bytes(&mut self, data: &[u8]) -> Result<(), ComServerError> {
+        self.written.extend_from_slice(data);
+        self.rx.extend(data);
+        if let Some(r) = self.responses.pop_front() {
+            self.rx.extend(r)
+        }
+        Ok(())
+    }
+
+    fn read_bytes(&mut self, buf: &mut [u8], _timeout: Duration) -> Result<usize, ComServerError> {
+        let n = std::cmp::min(buf.len(), self.rx.len());
+        for b in buf.iter_mut().take(n) {
+            *b = self.rx.pop_front().unwrap();
+        }
+        Ok(n)
+    }
+
+    fn clear_input(&mut self) -> Result<(), ComServerError> {
+        self.rx.clear();
+        // Address has been sent
+        if self.line.len() > 2 {
+            let r = std::mem::take(&mut self.wakeup_response);
+            self.rx.extend(r)
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl KLineTransport for MockKLine {
+    fn set_line_low(&mut self, low: bool) -> Result<(), ComServerError> {
+        self.line.push(low);
+        Ok(())
+    }
+
+    fn set_baud(&mut self, _baud: u32) -> Result<(), ComServerError> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_key_byte_protocols() {
+    let kb = |kb1, kb2| KeyBytes { kb1, kb2 };
+    // 5 baud init responses (After the 0x55 sync byte)
+    assert_eq!(kb(0x08, 0x08).protocol(), KLineProtocol::Iso9141_2);
+    assert_eq!(kb(0x94, 0x94).protocol(), KLineProtocol::Iso9141_2);
+    assert_eq!(kb(0x01, 0x8A).protocol(), KLineProtocol::Kw1281);
+    assert_eq!(kb(0x01, 0x8A).key_word(), 1281);
+    assert_eq!(kb(0x6B, 0x8F).protocol(), KLineProtocol::Kwp2000);
+
+    // Mercedes ECU, fast init: any header format, extended timing (TP1)
+    let mb = kb(0xEF, 0x8F);
+    assert_eq!(mb.key_word(), 2031);
+    assert_eq!(mb.protocol(), KLineProtocol::Kwp2000);
+    assert!(mb.length_in_format() && mb.length_byte() && mb.one_byte_header() && mb.address_header());
+    assert!(mb.extended_timing());
+
+    // Length in format byte and address header only, normal timing (TP0)
+    let normal = kb(0xD9, 0x8F);
+    assert_eq!(normal.key_word(), 2009);
+    assert_eq!(normal.protocol(), KLineProtocol::Kwp2000);
+    assert!(normal.length_in_format() && !normal.length_byte() && !normal.one_byte_header() && normal.address_header());
+    assert!(!normal.extended_timing());
+
+    assert_eq!(kb(0x00, 0x00).protocol(), KLineProtocol::Unknown(0));
+}
+
+#[test]
+fn test_kwp_frames() {
+    assert_eq!(build_kwp_frame(0x33, 0xF1, &[0x81]), vec![0x81, 0x33, 0xF1, 0x81, 0x26]);
+    // Positive response to StartCommunication, captured from an ECU at 0x10
+    let resp = [0x83, 0xF1, 0x10, 0xC1, 0xEF, 0x8F, 0xC3, 0xAA];
+    assert_eq!(parse_kwp_frame(&resp).unwrap(), Some(vec![0xC1, 0xEF, 0x8F]));
+    assert_eq!(parse_kwp_frame(&resp[..6]).unwrap(), None);
+    assert!(parse_kwp_frame(&[0x83, 0xF1, 0x10, 0xC1, 0xEF, 0x8F, 0xC4]).is_err());
+    // Separate length byte, and no addresses
+    assert_eq!(parse_kwp_frame(&[0x00, 0x02, 0x50, 0x81, 0xD3]).unwrap(), Some(vec![0x50, 0x81]));
+    assert_eq!(parse_kwp_frame(&[0x00]).unwrap(), None);
+}
+
+#[test]
+fn test_five_baud_init() {
+    assert_eq!(five_baud_bits(0x33), [true, false, false, true, true, false, false, true, true, false]);
+
+    let mock = MockKLine { wakeup_response: vec![0x55, 0x08, 0x08], responses: vec![vec![!0x33]].into(), ..Default::default() };
+    let mut channel = KLineChannel::new(mock, 10400);
+    let kb = channel.five_baud_init(0x33).unwrap();
+    assert_eq!(kb, KeyBytes { kb1: 0x08, kb2: 0x08 });
+    assert_eq!(kb.protocol(), KLineProtocol::Iso9141_2);
+    assert_eq!(channel.key_bytes(), Some(kb));
+    let mock = channel.transport();
+    assert_eq!(&mock.line[1..], &five_baud_bits(0x33));
+    assert_eq!(mock.written, vec![0xF7]);
+
+    // Wrong sync byte
+    let mock = MockKLine { wakeup_response: vec![0xAA, 0x08, 0x08], ..Default::default() };
+    let mut channel = KLineChannel::new(mock, 10400);
+    assert!(channel.five_baud_init(0x33).is_err());
+    assert_eq!(channel.key_bytes(), None);
+}
+
+#[test]
+fn test_fast_init() {
+    let mock = MockKLine { responses: vec![vec![0x83, 0xF1, 0x10, 0xC1, 0xEF, 0x8F, 0xC3]].into(), ..Default::default() };
+    let mut channel = KLineChannel::new(mock, 10400);
+    let kb = channel.fast_init(0x10).unwrap();
+    assert_eq!(kb.key_word(), 2031);
+    let mock = channel.transport();
+    assert_eq!(mock.line, vec![false, true, false]);
+    assert_eq!(mock.written, vec![0x81, 0x10, 0xF1, 0x81, 0x03]);
+
+    // Negative response
+    let mock = MockKLine { responses: vec![vec![0x83, 0xF1, 0x10, 0x7F, 0x81, 0x10, 0x94]].into(), ..Default::default() };
+    let mut channel = KLineChannel::new(mock, 10400);
+    assert!(channel.fast_init(0x10).unwrap_err().err_desc.contains("NRC 0x10"));
+}
@@ -0,0 +1,358 @@
+use crate::commapi::comm_api::{ComServer, CanError, CanFrame, CanIdFilter, ComServerError, DeviceCapabilities, FilterType, ISO15765Data, Capability, ERR_DEVICE_LOST};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+type Responder = Arc<dyn Fn(&CanFrame) -> Vec<CanFrame> + Send + Sync>;
+type IsoTpResponder = Arc<dyn Fn(&ISO15765Data) -> Vec<ISO15765Data> + Send + Sync>;
+
+/// In memory adapter used for testing code which talks to a [ComServer]
+/// without any hardware attached.
+///
+/// Frames pushed with [push_rx](fn@push_rx) are returned by `read_can_packets`, and every
+/// frame sent is recorded in the Tx log. A responder can be set to generate frames in
+/// response to each frame that is sent.
+#[derive(Clone, Default)]
+pub struct MockComServer {
+    rx_queue: Arc<Mutex<VecDeque<CanFrame>>>,
+    tx_log: Arc<Mutex<Vec<CanFrame>>>,
+    /// Number of calls to send_can_packets
+    send_calls: Arc<Mutex<usize>>,
+    filters: Arc<Mutex<Vec<(u32, FilterType, CanIdFilter)>>>,
+    next_filter_id: Arc<Mutex<u32>>,
+    responder: Option<Responder>,
+    iso_rx_queue: Arc<Mutex<VecDeque<ISO15765Data>>>,
+    iso_tx_log: Arc<Mutex<Vec<ISO15765Data>>>,
+    /// ISO-TP filters, as (index, ID, mask, flow control ID)
+    iso_filters: Arc<Mutex<Vec<(u32, u32, u32, u32)>>>,
+    iso_responder: Option<IsoTpResponder>,
+    can_open: Arc<Mutex<bool>>,
+    iso15765_open: Arc<Mutex<bool>>,
+    /// Bus speed and extended addressing the ISO15765 interface was last opened with
+    iso15765_bus: Arc<Mutex<Option<(u32, bool)>>>,
+    /// Remaining number of times open_device will fail, while the device is lost
+    device_lost: Arc<Mutex<Option<u32>>>,
+    /// Error reported by the CAN controller
+    can_error: Arc<Mutex<Option<CanError>>>,
+    /// Frames sent are added to the Rx queue
+    loopback: Arc<Mutex<bool>>,
+    /// Loopback can be enabled, but frames are never looped back
+    /// (As if the adapter is not connected to a working bus)
+    pub broken_loopback: bool,
+    /// Adapter does not support hardware filters. Every frame is received
+    pub no_hw_filters: bool,
+    /// Adapter sends frames one call at a time
+    pub no_batched_send: bool,
+    /// Value returned by timestamp_rollover_us
+    pub timestamp_rollover_us: Option<u64>,
+}
+
+impl std::fmt::Debug for MockComServer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "MockComServer ({} frames in Rx queue)", self.rx_queue.lock().unwrap().len())
+    }
+}
+
+impl MockComServer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets a function which is called with every frame that is sent, the frames
+    /// it returns are added to the Rx queue
+    pub fn set_responder<F: Fn(&CanFrame) -> Vec<CanFrame> + Send + Sync + 'static>(&mut self, f: F) {
+        self.responder = Some(Arc::new(f))
+    }
+
+    /// Sets a function which is called with every ISO-TP payload that is sent, the payloads
+    /// it returns are added to the ISO-TP Rx queue
+    pub fn set_iso15765_responder<F: Fn(&ISO15765Data) -> Vec<ISO15765Data> + Send + Sync + 'static>(&mut self, f: F) {
+        self.iso_responder = Some(Arc::new(f))
+    }
+
+    /// Returns every ISO-TP payload that has been sent to the mock
+    pub fn get_iso15765_tx_log(&self) -> Vec<ISO15765Data> {
+        self.iso_tx_log.lock().unwrap().clone()
+    }
+
+    /// Returns true if the ISO15765 interface is open
+    pub fn is_iso15765_open(&self) -> bool {
+        *self.iso15765_open.lock().unwrap()
+    }
+
+    /// Returns the active ISO-TP filters, as (ID, mask, flow control ID)
+    pub fn get_iso15765_filters(&self) -> Vec<(u32, u32, u32)> {
+        self.iso_filters.lock().unwrap().iter().map(|(_, id, mask, fc)| (*id, *mask, *fc)).collect()
+    }
+
+    /// Returns the bus speed and extended addressing flag of the open ISO15765 interface
+    pub fn iso15765_bus(&self) -> Option<(u32, bool)> {
+        if self.is_iso15765_open() {
+            *self.iso15765_bus.lock().unwrap()
+        } else {
+            None
+        }
+    }
+
+    /// Simulates the adapter being unplugged. Every call fails with [ERR_DEVICE_LOST] until
+    /// open_device succeeds, which happens after `failed_opens` failed attempts.
+    /// Filters and open interfaces are lost, as they would be with a real adapter
+    pub fn simulate_device_lost(&self, failed_opens: u32) {
+        *self.device_lost.lock().unwrap() = Some(failed_opens);
+        self.filters.lock().unwrap().clear();
+    }
+
+    fn check_device(&self) -> Result<(), ComServerError> {
+        match *self.device_lost.lock().unwrap() {
+            Some(_) => Err(ComServerError { err_code: ERR_DEVICE_LOST, err_desc: "Device not connected".into() }),
+            None => Ok(())
+        }
+    }
+
+    /// Simulates the CAN controller reporting an error until it is cleared. Bus off stops
+    /// sending and reading, and is also cleared by recover_from_bus_off. Other errors are
+    /// only reported when reading, and error frames are only reported once
+    pub fn simulate_can_error(&self, err: Option<CanError>) {
+        *self.can_error.lock().unwrap() = err;
+    }
+
+    fn check_bus(&self, reading: bool) -> Result<(), ComServerError> {
+        let mut err = self.can_error.lock().unwrap();
+        match *err {
+            Some(CanError::BusOff) => Err(CanError::BusOff.into()),
+            Some(CanError::ErrorFrame) if reading => Err(err.take().unwrap().into()),
+            Some(e) if reading => Err(e.into()),
+            _ => Ok(())
+        }
+    }
+
+    /// Adds a frame to the Rx queue as if it came from the vehicle
+    pub fn push_rx(&self, frame: CanFrame) {
+        self.rx_queue.lock().unwrap().push_back(frame)
+    }
+
+    /// Adds a payload to the ISO-TP Rx queue as if it came from the vehicle
+    pub fn push_iso15765_rx(&self, data: ISO15765Data) {
+        self.iso_rx_queue.lock().unwrap().push_back(data)
+    }
+
+    /// Returns every frame that has been sent to the mock
+    pub fn get_tx_log(&self) -> Vec<CanFrame> {
+        self.tx_log.lock().unwrap().clone()
+    }
+
+    /// Returns the number of times send_can_packets has been called
+    pub fn get_send_calls(&self) -> usize {
+        *self.send_calls.lock().unwrap()
+    }
+
+    /// Returns the active hardware filters
+    pub fn get_filters(&self) -> Vec<CanIdFilter> {
+        self.filters.lock().unwrap().iter().map(|(_, _, f)| *f).collect()
+    }
+
+    fn passes_filters(&self, frame: &CanFrame) -> bool {
+        if self.no_hw_filters {
+            return true;
+        }
+        let filters = self.filters.lock().unwrap();
+        let blocked = filters.iter().any(|(_, t, f)| matches!(t, FilterType::Block) && f.matches(frame.id));
+        let passed = filters.iter().any(|(_, t, f)| matches!(t, FilterType::Pass) && f.matches(frame.id));
+        passed && !blocked
+    }
+}
+
+#[allow(unused_variables)]
+impl ComServer for MockComServer {
+    fn open_device(&mut self) -> Result<(), ComServerError> {
+        let mut lost = self.device_lost.lock().unwrap();
+        match *lost {
+            Some(0) | None => {
+                *lost = None;
+                Ok(())
+            },
+            Some(n) => {
+                *lost = Some(n - 1);
+                Err(ComServerError { err_code: ERR_DEVICE_LOST, err_desc: "Device not found".into() })
+            }
+        }
+    }
+
+    fn close_device(&mut self) -> Result<(), ComServerError> { Ok(()) }
+
+    fn send_can_packets(&self, data: &[CanFrame], timeout_ms: u32) -> Result<usize, ComServerError> {
+        self.check_device()?;
+        self.check_bus(false)?;
+        *self.send_calls.lock().unwrap() += 1;
+        let loopback = *self.loopback.lock().unwrap() && !self.broken_loopback;
+        for f in data {
+            self.tx_log.lock().unwrap().push(*f);
+            if loopback {
+                self.push_rx(*f);
+            }
+            if let Some(r) = &self.responder {
+                for resp in r(f) {
+                    self.push_rx(resp);
+                }
+            }
+        }
+        Ok(data.len())
+    }
+
+    fn is_connected(&self) -> bool {
+        *self.can_open.lock().unwrap() || *self.iso15765_open.lock().unwrap()
+    }
+
+    fn read_can_packets(&self, timeout_ms: u32, max_msgs: usize) -> Result<Vec<CanFrame>, ComServerError> {
+        self.check_device()?;
+        self.check_bus(true)?;
+        let mut res = Vec::new();
+        while res.len() < max_msgs {
+            let next = self.rx_queue.lock().unwrap().pop_front();
+            match next {
+                Some(f) => if self.passes_filters(&f) { res.push(f) },
+                None => break
+            }
+        }
+        Ok(res)
+    }
+
+    fn send_iso15765_data(&self, data: &[ISO15765Data], timeout_ms: u32) -> Result<usize, ComServerError> {
+        self.check_bus(false)?;
+        for d in data {
+            self.iso_tx_log.lock().unwrap().push(d.clone());
+            if let Some(r) = &self.iso_responder {
+                let mut rx = self.iso_rx_queue.lock().unwrap();
+                for resp in r(d) {
+                    rx.push_back(resp);
+                }
+            }
+        }
+        Ok(data.len())
+    }
+
+    fn read_iso15765_packets(&self, timeout_ms: u32, max_msgs: usize) -> Result<Vec<ISO15765Data>, ComServerError> {
+        self.check_bus(true)?;
+        let mut rx = self.iso_rx_queue.lock().unwrap();
+        let n = std::cmp::min(rx.len(), max_msgs);
+        Ok(rx.drain(0..n).collect())
+    }
+
+    fn open_can_interface(&mut self, bus_speed: u32, is_ext_can: bool) -> Result<(), ComServerError> {
+        self.check_device()?;
+        *self.can_open.lock().unwrap() = true;
+        *self.iso15765_open.lock().unwrap() = false;
+        Ok(())
+    }
+
+    fn close_can_interface(&mut self) -> Result<(), ComServerError> {
+        *self.can_open.lock().unwrap() = false;
+        Ok(())
+    }
+
+    fn open_iso15765_interface(&mut self, bus_speed: u32, is_ext_can: bool) -> Result<(), ComServerError> {
+        *self.iso15765_open.lock().unwrap() = true;
+        *self.iso15765_bus.lock().unwrap() = Some((bus_speed, is_ext_can));
+        *self.can_open.lock().unwrap() = false;
+        Ok(())
+    }
+
+    fn close_iso15765_interface(&mut self) -> Result<(), ComServerError> {
+        *self.iso15765_open.lock().unwrap() = false;
+        self.iso_filters.lock().unwrap().clear();
+        Ok(())
+    }
+
+    fn add_can_filter(&self, filter: FilterType, id: u32, mask: u32) -> Result<u32, ComServerError> {
+        self.check_device()?;
+        if self.no_hw_filters {
+            return Err(ComServerError { err_code: 1, err_desc: "Filters not supported".into() })
+        }
+        let mut idx = self.next_filter_id.lock().unwrap();
+        *idx += 1;
+        self.filters.lock().unwrap().push((*idx, filter, CanIdFilter::new(id, mask)));
+        Ok(*idx)
+    }
+
+    fn rem_can_filter(&self, filter_idx: u32) -> Result<(), ComServerError> {
+        self.filters.lock().unwrap().retain(|(id, _, _)| *id != filter_idx);
+        Ok(())
+    }
+
+    fn add_iso15765_filter(&self, id: u32, mask: u32, resp_id: u32) -> Result<u32, ComServerError> {
+        let mut idx = self.next_filter_id.lock().unwrap();
+        *idx += 1;
+        self.iso_filters.lock().unwrap().push((*idx, id, mask, resp_id));
+        Ok(*idx)
+    }
+
+    fn rem_iso15765_filter(&self, filter_idx: u32) -> Result<(), ComServerError> {
+        self.iso_filters.lock().unwrap().retain(|(idx, _, _, _)| *idx != filter_idx);
+        Ok(())
+    }
+
+    fn set_iso15765_params(&self, separation_time_min: u32, block_size: u32) -> Result<(), ComServerError> { Ok(()) }
+
+    fn clear_can_rx_buffer(&self) -> Result<(), ComServerError> {
+        self.rx_queue.lock().unwrap().clear();
+        Ok(())
+    }
+
+    fn clear_can_tx_buffer(&self) -> Result<(), ComServerError> { Ok(()) }
+
+    fn clear_iso15765_rx_buffer(&self) -> Result<(), ComServerError> {
+        self.iso_rx_queue.lock().unwrap().clear();
+        Ok(())
+    }
+
+    fn clear_iso15765_tx_buffer(&self) -> Result<(), ComServerError> { Ok(()) }
+
+    fn read_battery_voltage(&self) -> Result<f32, ComServerError> { Ok(12.6) }
+
+    fn clone_box(&self) -> Box<dyn ComServer> {
+        Box::new(self.clone())
+    }
+
+    fn get_capabilities(&self) -> DeviceCapabilities {
+        DeviceCapabilities {
+            name: "Mock adapter".into(),
+            vendor: "OpenVehicleDiag".into(),
+            library_path: "".into(),
+            device_fw_version: "".into(),
+            library_version: "".into(),
+            j1850vpw: Capability::No,
+            j1850pwm: Capability::No,
+            can: Capability::Yes,
+            can_fd: Capability::Yes,
+            iso15765: Capability::Yes,
+            iso9141: Capability::No,
+            iso14230: Capability::No,
+            ip: Capability::No
+        }
+    }
+
+    fn get_api(&self) -> &str {
+        "Mock"
+    }
+
+    fn timestamp_rollover_us(&self) -> Option<u64> {
+        self.timestamp_rollover_us
+    }
+
+    fn supports_batched_send(&self) -> bool {
+        !self.no_batched_send
+    }
+
+    fn set_can_loopback(&self, enabled: bool) -> Result<(), ComServerError> {
+        self.check_device()?;
+        *self.loopback.lock().unwrap() = enabled;
+        Ok(())
+    }
+
+    fn recover_from_bus_off(&mut self) -> Result<(), ComServerError> {
+        let mut err = self.can_error.lock().unwrap();
+        if *err == Some(CanError::BusOff) {
+            *err = None;
+        }
+        Ok(())
+    }
+}
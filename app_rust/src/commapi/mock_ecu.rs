@@ -0,0 +1,373 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use common::measurement::{CompuMethod, DidDef};
+use common::schema::SchemaV1;
+use crate::commapi::comm_api::{CanFrame, ISO15765Data};
+use crate::commapi::iso_tp::{encode_payload, flow_control_frame, FlowStatus, IsoTpDecoder, IsoTpOptions, RxResult};
+
+// Negative response codes sent by the simulator
+const NRC_SERVICE_NOT_SUPPORTED: u8 = 0x11;
+const NRC_SUB_FUNCTION_NOT_SUPPORTED: u8 = 0x12;
+const NRC_INCORRECT_LENGTH: u8 = 0x13;
+const NRC_SEQUENCE_ERROR: u8 = 0x24;
+const NRC_REQUEST_OUT_OF_RANGE: u8 = 0x31;
+const NRC_SECURITY_ACCESS_DENIED: u8 = 0x33;
+const NRC_INVALID_KEY: u8 = 0x35;
+const NRC_NOT_SUPPORTED_IN_SESSION: u8 = 0x7F;
+
+const DEFAULT_SESSION: u8 = 0x01;
+
+type KeyFunction = Arc<dyn Fn(u8, &[u8]) -> Vec<u8> + Send + Sync>;
+
+#[derive(Debug, Default)]
+struct EcuState {
+    session: u8,
+    /// Security level that has been unlocked
+    unlocked: Option<u8>,
+    /// Level and seed of the last seed request, waiting for a key
+    pending_seed: Option<(u8, Vec<u8>)>,
+    /// Reassembles requests received as raw CAN frames
+    decoder: IsoTpDecoder,
+    /// Consecutive frames of a response, waiting for a flow control frame
+    pending_frames: Vec<CanFrame>,
+}
+
+/// Simulated UDS ECU, for testing and demos without a vehicle.
+///
+/// The simulator answers DID reads from a table (Which can be filled from a definition with
+/// [from_model](fn@MockEcu::from_model)), reports and clears a configurable set of DTCs, and tracks the
+/// diagnostic session and security access state like a real ECU. Protected DIDs can only be read
+/// or written once security access has been granted.
+///
+/// It can either be attached to an adapter's ISO-TP interface with [respond_iso15765](fn@MockEcu::respond_iso15765),
+/// or to its raw CAN interface with [respond_can](fn@MockEcu::respond_can), where it handles ISO-TP framing itself.
+#[derive(Clone)]
+pub struct MockEcu {
+    request_id: u32,
+    response_id: u32,
+    dids: Arc<Mutex<HashMap<u16, Vec<u8>>>>,
+    protected_dids: Arc<Mutex<Vec<u16>>>,
+    dtcs: Arc<Mutex<Vec<(u32, u8)>>>,
+    seed: Vec<u8>,
+    key_fn: KeyFunction,
+    state: Arc<Mutex<EcuState>>,
+}
+
+impl std::fmt::Debug for MockEcu {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "MockEcu (0x{:04X}/0x{:04X}, {} DIDs)", self.request_id, self.response_id, self.dids.lock().unwrap().len())
+    }
+}
+
+impl MockEcu {
+    /// Creates a simulator with no DIDs or DTCs. The seed defaults to `[0x12, 0x34]`, and
+    /// the key is the seed XOR 0xFF
+    ///
+    /// # Params
+    /// * request_id - CAN ID the tester sends requests with
+    /// * response_id - CAN ID the simulator responds with
+    pub fn new(request_id: u32, response_id: u32) -> Self {
+        Self {
+            request_id,
+            response_id,
+            dids: Arc::new(Mutex::new(HashMap::new())),
+            protected_dids: Arc::new(Mutex::new(Vec::new())),
+            dtcs: Arc::new(Mutex::new(Vec::new())),
+            seed: vec![0x12, 0x34],
+            key_fn: Arc::new(|_, seed| seed.iter().map(|x| x ^ 0xFF).collect()),
+            state: Arc::new(Mutex::new(EcuState { session: DEFAULT_SESSION, ..Default::default() })),
+        }
+    }
+
+    /// Creates a simulator which serves every measurement DID declared by a definition
+    pub fn from_model(request_id: u32, response_id: u32, model: &SchemaV1) -> Self {
+        let ecu = Self::new(request_id, response_id);
+        model.measurement_dids().for_each(|def| ecu.add_measurement(def));
+        ecu
+    }
+
+    /// Serves a measurement DID with a plausible value for its definition: the first entry of a
+    /// text table, otherwise half of the raw value's range
+    pub fn add_measurement(&self, def: &DidDef) {
+        let raw: i64 = match &def.compu {
+            CompuMethod::TextTable(t) if !t.is_empty() => t[0].0,
+            _ if def.signed => 0,
+            _ => 1i64.checked_shl(def.byte_len as u32 * 8 - 1).unwrap_or(0),
+        };
+        let mut data = vec![0u8; def.byte_offset + def.byte_len];
+        for i in 0..def.byte_len {
+            data[def.byte_offset + def.byte_len - 1 - i] = (raw >> (i * 8)) as u8;
+        }
+        self.set_did(def.did, &data)
+    }
+
+    /// Sets the value returned when a DID is read
+    pub fn set_did(&self, did: u16, data: &[u8]) {
+        self.dids.lock().unwrap().insert(did, data.to_vec());
+    }
+
+    /// Returns the current value of a DID, including any value written by the tester
+    pub fn get_did(&self, did: u16) -> Option<Vec<u8>> {
+        self.dids.lock().unwrap().get(&did).cloned()
+    }
+
+    /// Requires security access to read or write a DID
+    pub fn protect_did(&self, did: u16) {
+        self.protected_dids.lock().unwrap().push(did)
+    }
+
+    /// Sets the DTCs stored on the ECU, as (DTC number, status byte)
+    pub fn set_dtcs(&self, dtcs: &[(u32, u8)]) {
+        *self.dtcs.lock().unwrap() = dtcs.to_vec();
+    }
+
+    /// Sets the seed sent for security access requests, and the function which computes
+    /// the expected key from the security level and seed
+    pub fn set_seed_key<F: Fn(u8, &[u8]) -> Vec<u8> + Send + Sync + 'static>(&mut self, seed: &[u8], key_fn: F) {
+        self.seed = seed.to_vec();
+        self.key_fn = Arc::new(key_fn);
+    }
+
+    /// Returns the active diagnostic session
+    pub fn get_session(&self) -> u8 {
+        self.state.lock().unwrap().session
+    }
+
+    fn negative(sid: u8, nrc: u8) -> Option<Vec<u8>> {
+        Some(vec![0x7F, sid, nrc])
+    }
+
+    /// Handles a UDS request payload, returning the response payload.
+    /// None is returned if the request asked for the positive response to be suppressed
+    pub fn handle_request(&self, req: &[u8]) -> Option<Vec<u8>> {
+        let sid = *req.first()?;
+        let mut state = self.state.lock().unwrap();
+        match sid {
+            0x10 => match req.get(1).map(|x| x & 0x7F) {
+                Some(s @ 0x01..=0x03) => {
+                    state.session = s;
+                    state.unlocked = None;
+                    state.pending_seed = None;
+                    Some(vec![0x50, s])
+                },
+                Some(_) => Self::negative(sid, NRC_SUB_FUNCTION_NOT_SUPPORTED),
+                None => Self::negative(sid, NRC_INCORRECT_LENGTH)
+            },
+            0x11 => {
+                let sub = *req.get(1)?;
+                state.session = DEFAULT_SESSION;
+                state.unlocked = None;
+                state.pending_seed = None;
+                Some(vec![0x51, sub])
+            },
+            0x3E => match req.get(1) {
+                Some(0x80) => None,
+                Some(0x00) => Some(vec![0x7E, 0x00]),
+                _ => Self::negative(sid, NRC_SUB_FUNCTION_NOT_SUPPORTED)
+            },
+            0x27 => {
+                if state.session == DEFAULT_SESSION {
+                    return Self::negative(sid, NRC_NOT_SUPPORTED_IN_SESSION)
+                }
+                let level = *req.get(1)?;
+                if level % 2 == 1 {
+                    // Seed request. Unlocked levels get a zero seed
+                    let seed = if state.unlocked == Some(level) { vec![0x00; self.seed.len()] } else { self.seed.clone() };
+                    state.pending_seed = Some((level, seed.clone()));
+                    let mut resp = vec![0x67, level];
+                    resp.extend_from_slice(&seed);
+                    Some(resp)
+                } else {
+                    match state.pending_seed.take() {
+                        Some((seed_level, seed)) if seed_level == level - 1 => {
+                            if (self.key_fn)(seed_level, &seed) == req[2..] {
+                                state.unlocked = Some(seed_level);
+                                Some(vec![0x67, level])
+                            } else {
+                                Self::negative(sid, NRC_INVALID_KEY)
+                            }
+                        },
+                        _ => Self::negative(sid, NRC_SEQUENCE_ERROR)
+                    }
+                }
+            },
+            0x22 => {
+                if req.len() != 3 {
+                    return Self::negative(sid, NRC_INCORRECT_LENGTH)
+                }
+                let did = (req[1] as u16) << 8 | req[2] as u16;
+                if state.unlocked.is_none() && self.protected_dids.lock().unwrap().contains(&did) {
+                    return Self::negative(sid, NRC_SECURITY_ACCESS_DENIED)
+                }
+                match self.dids.lock().unwrap().get(&did) {
+                    Some(data) => {
+                        let mut resp = vec![0x62, req[1], req[2]];
+                        resp.extend_from_slice(data);
+                        Some(resp)
+                    },
+                    None => Self::negative(sid, NRC_REQUEST_OUT_OF_RANGE)
+                }
+            },
+            0x2E => {
+                if req.len() < 4 {
+                    return Self::negative(sid, NRC_INCORRECT_LENGTH)
+                }
+                let did = (req[1] as u16) << 8 | req[2] as u16;
+                if state.unlocked.is_none() {
+                    return Self::negative(sid, NRC_SECURITY_ACCESS_DENIED)
+                }
+                let mut dids = self.dids.lock().unwrap();
+                match dids.get_mut(&did) {
+                    Some(data) => {
+                        *data = req[3..].to_vec();
+                        Some(vec![0x6E, req[1], req[2]])
+                    },
+                    None => Self::negative(sid, NRC_REQUEST_OUT_OF_RANGE)
+                }
+            },
+            0x19 => {
+                let mask = *req.get(2)?;
+                let dtcs = self.dtcs.lock().unwrap();
+                let matching = dtcs.iter().filter(|(_, status)| status & mask != 0);
+                match req[1] {
+                    0x01 => {
+                        let count = matching.count() as u16;
+                        Some(vec![0x59, 0x01, 0xFF, 0x01, (count >> 8) as u8, count as u8])
+                    },
+                    0x02 => {
+                        let mut resp = vec![0x59, 0x02, 0xFF];
+                        for (dtc, status) in matching {
+                            resp.extend_from_slice(&[(dtc >> 16) as u8, (dtc >> 8) as u8, *dtc as u8, *status]);
+                        }
+                        Some(resp)
+                    },
+                    _ => Self::negative(sid, NRC_SUB_FUNCTION_NOT_SUPPORTED)
+                }
+            },
+            0x14 => {
+                if req.len() != 4 {
+                    return Self::negative(sid, NRC_INCORRECT_LENGTH)
+                }
+                let group = (req[1] as u32) << 16 | (req[2] as u32) << 8 | req[3] as u32;
+                let mut dtcs = self.dtcs.lock().unwrap();
+                if group == 0xFF_FFFF {
+                    dtcs.clear()
+                } else {
+                    dtcs.retain(|(dtc, _)| *dtc != group)
+                }
+                Some(vec![0x54])
+            },
+            _ => Self::negative(sid, NRC_SERVICE_NOT_SUPPORTED)
+        }
+    }
+
+    /// Handles a request received on an adapter's ISO-TP interface.
+    /// Requests which are not sent to the simulator's request ID are ignored
+    pub fn respond_iso15765(&self, req: &ISO15765Data) -> Vec<ISO15765Data> {
+        if req.id != self.request_id {
+            return Vec::new()
+        }
+        self.handle_request(&req.data)
+            .map(|data| vec![ISO15765Data { id: self.response_id, data, pad_frame: false }])
+            .unwrap_or_default()
+    }
+
+    /// Handles a raw CAN frame, reassembling ISO-TP requests, and sending responses with flow control.
+    /// Frames which are not sent to the simulator's request ID are ignored
+    pub fn respond_can(&self, frame: &CanFrame) -> Vec<CanFrame> {
+        if frame.id != self.request_id {
+            return Vec::new()
+        }
+        let opts = IsoTpOptions::default();
+        let res = {
+            let mut state = self.state.lock().unwrap();
+            if frame.get_data().first().map(|x| x & 0xF0) == Some(0x30) {
+                // Flow control for our response. Block size and STmin are ignored
+                return std::mem::take(&mut state.pending_frames)
+            }
+            state.decoder.on_frame(frame)
+        };
+        match res {
+            Ok(RxResult::Complete(req)) => {
+                let mut frames = match self.handle_request(&req).map(|resp| encode_payload(self.response_id, &resp, &opts)) {
+                    Some(Ok(f)) => f,
+                    _ => return Vec::new()
+                };
+                let rest = frames.split_off(1);
+                self.state.lock().unwrap().pending_frames = rest;
+                frames
+            },
+            Ok(RxResult::FlowControlRequired) => {
+                vec![flow_control_frame(self.response_id, FlowStatus::ContinueToSend, 0, 0, &opts)]
+            },
+            _ => Vec::new()
+        }
+    }
+}
+
+#[test]
+fn test_uds_client_against_mock_ecu() {
+    use crate::commapi::comm_api::ISO15765Config;
+    use crate::commapi::mock_api::MockComServer;
+    use crate::commapi::protocols::{ProtocolError, ProtocolServer};
+    use crate::commapi::protocols::uds::{UDSCommand, UDSECU};
+
+    let mut ecu = MockEcu::new(0x07E0, 0x07E8);
+    ecu.set_seed_key(&[0xAB, 0xCD], |level, seed| seed.iter().map(|x| x.wrapping_add(level)).collect());
+    let temp = DidDef { did: 0x1100, name: "Oil temperature".into(), byte_offset: 0, byte_len: 1, signed: false,
+        compu: CompuMethod::Linear { factor: 1.0, offset: -40.0 }, unit: Some("°C".into()), decimals: None };
+    ecu.add_measurement(&temp);
+    ecu.set_did(0xF190, b"WDB2110421A123456");
+    ecu.protect_did(0xF190);
+    ecu.set_dtcs(&[(0x9D0013, 0x09), (0x012300, 0x04)]);
+
+    let mut mock = MockComServer::new();
+    let ecu_t = ecu.clone();
+    mock.set_iso15765_responder(move |req| ecu_t.respond_iso15765(req));
+    let cfg = ISO15765Config { send_id: 0x07E0, recv_id: 0x07E8, block_size: 8, sep_time: 20 };
+    let client = UDSECU::start_diag_session(Box::new(mock.clone()), &cfg).unwrap();
+    assert_eq!(ecu.get_session(), 0x03);
+
+    let data = client.read_data_by_id(0x1100).unwrap();
+    assert_eq!(temp.scale(&data).unwrap().to_string(), "88 °C");
+    assert!(matches!(client.read_data_by_id(0xF190), Err(ProtocolError::ProtocolError(_))));
+
+    // Unlock, then the protected DID can be read
+    let seed = client.run_command(UDSCommand::SecurityAccess, &[0x01], 500).unwrap();
+    assert_eq!(seed, vec![0x01, 0xAB, 0xCD]);
+    assert!(client.run_command(UDSCommand::SecurityAccess, &[0x02, 0x00, 0x00], 500).is_err());
+    client.run_command(UDSCommand::SecurityAccess, &[0x01], 500).unwrap();
+    client.run_command(UDSCommand::SecurityAccess, &[0x02, 0xAC, 0xCE], 500).unwrap();
+    assert_eq!(client.read_data_by_id(0xF190).unwrap(), b"WDB2110421A123456".to_vec());
+
+    assert_eq!(client.read_dtc_count(0x08).unwrap(), (0xFF, 1));
+    assert_eq!(client.read_errors().unwrap().len(), 2);
+    client.clear_errors().unwrap();
+    assert!(client.read_errors().unwrap().is_empty());
+}
+
+#[test]
+fn test_mock_ecu_over_can() {
+    use crate::commapi::can_channel::CanChannel;
+    use crate::commapi::comm_api::ISO15765Config;
+    use crate::commapi::iso_tp::IsoTpChannel;
+    use crate::commapi::mock_api::MockComServer;
+
+    let ecu = MockEcu::new(0x07E0, 0x07E8);
+    ecu.set_did(0xF190, b"WDB2110421A123456");
+    let mut mock = MockComServer::new();
+    let ecu_t = ecu.clone();
+    mock.set_responder(move |f| ecu_t.respond_can(f));
+    let cfg = ISO15765Config { send_id: 0x07E0, recv_id: 0x07E8, block_size: 0, sep_time: 0 };
+    let mut channel = IsoTpChannel::new(CanChannel::new(Box::new(mock.clone())), cfg, IsoTpOptions::default()).unwrap();
+
+    // Multi frame response
+    channel.send(&[0x22, 0xF1, 0x90]).unwrap();
+    let resp = channel.recv(100).unwrap();
+    assert_eq!(&resp[0..3], &[0x62, 0xF1, 0x90]);
+    assert_eq!(&resp[3..], b"WDB2110421A123456");
+
+    channel.send(&[0x10, 0x02]).unwrap();
+    assert_eq!(channel.recv(100).unwrap(), vec![0x50, 0x02]);
+    assert_eq!(ecu.get_session(), 0x02);
+}
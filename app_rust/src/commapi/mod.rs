@@ -1,4 +1,20 @@
+pub mod address_book;
+pub mod bus_stats;
+pub mod can_channel;
 pub mod comm_api;
+pub mod connection;
+pub mod diagnostics;
+pub mod frame_clock;
+pub mod iso_tp;
+pub mod kline;
+pub mod mock_ecu;
+#[cfg(test)]
+pub mod mock_api;
 pub mod pdu_api;
 pub mod passthru_api;
+pub mod pcap;
+pub mod recording;
+pub mod serial;
+pub mod session_pool;
+pub mod shared_channel;
 pub mod protocols;
\ No newline at end of file
@@ -3,7 +3,7 @@ use crate::commapi::comm_api::{ComServer, ISO15765Data, FilterType, CanFrame, Co
 use J2534Common::{PassthruError, PASSTHRU_MSG, Protocol, IoctlID, SConfig, IoctlParam, SConfigList, ConnectFlags, TxFlag, Loggable};
 use J2534Common::IoctlID::READ_VBATT;
 use std::os::raw::c_void;
-use J2534Common::PassthruError::{ERR_INVALID_CHANNEL_ID, ERR_FAILED};
+use J2534Common::PassthruError::{ERR_INVALID_CHANNEL_ID, ERR_FAILED, ERR_NOT_SUPPORTED};
 use J2534Common::FilterType::{PASS_FILTER, BLOCK_FILTER, FLOW_CONTROL_FILTER};
 use std::sync::{Arc, Mutex, RwLock};
 
@@ -41,6 +41,10 @@ impl ComServer for PassthruApi {
             Some(id) => id,
             None => return Err(self.convert_error(ERR_INVALID_CHANNEL_ID))
         };
+        if data.iter().any(|cf| cf.fd) {
+            // J2534 04.04 has no CAN-FD protocol ID
+            return Err(self.convert_error(ERR_NOT_SUPPORTED))
+        }
         let mut msgs : Vec<PASSTHRU_MSG> = data.iter().map(|cf| PassthruApi::can_frame_to_pt_msg(cf)).collect();
         self.driver.lock().unwrap().write_messages(channel_id, &mut msgs, timeout_ms).map_err(|e| self.convert_error(e))
     }
@@ -230,6 +234,24 @@ impl ComServer for PassthruApi {
         ).map_err(|e| self.convert_error(e))
     }
 
+    fn set_can_loopback(&self, enabled: bool) -> Result<(), ComServerError> {
+        let channel_id = match *self.can_channel_idx.read().unwrap() {
+            Some(idx) => idx,
+            None => return Err(self.convert_error(ERR_INVALID_CHANNEL_ID))
+        };
+        let mut params = [SConfig { parameter: IoctlParam::LOOPBACK as u32, value: enabled as u32 }];
+        let mut sconfig_list = SConfigList {
+            num_of_params: 1,
+            config_ptr: params.as_mut_ptr()
+        };
+        self.driver.lock().unwrap().ioctl(
+            channel_id,
+            IoctlID::SET_CONFIG,
+            (&mut sconfig_list) as *mut _ as *mut c_void,
+            std::ptr::null_mut()
+        ).map_err(|e| self.convert_error(e))
+    }
+
     fn clear_can_rx_buffer(&self) -> Result<(), ComServerError> {
         match *self.can_channel_idx.read().unwrap() {
             Some(idx) => {
@@ -310,6 +332,7 @@ impl ComServer for PassthruApi {
             j1850vpw: Capability::from_bool(self.device.j1850vpw),
             j1850pwm: Capability::from_bool(self.device.j1850pwm),
             can: Capability::from_bool(self.device.can),
+            can_fd: Capability::NA,
             iso15765: Capability::from_bool(self.device.iso15765),
             iso9141: Capability::from_bool(self.device.iso9141),
             iso14230: Capability::from_bool(self.device.iso14230),
@@ -323,6 +346,14 @@ impl ComServer for PassthruApi {
         "SAE J2534"
     }
 
+    fn timestamp_rollover_us(&self) -> Option<u64> {
+        Some(1 << 32)
+    }
+
+    fn supports_batched_send(&self) -> bool {
+        true
+    }
+
     fn is_connected(&self) -> bool {
         return self.iso15765_channel_idx.read().unwrap().is_some() ||
             self.can_channel_idx.read().unwrap().is_some() ||
@@ -330,6 +361,32 @@ impl ComServer for PassthruApi {
     }
 }
 
+/// Closes any channels left open and then the device, once the last handle to the
+/// device is dropped. This means the adapter is released even if the application did not
+/// exit cleanly, and does not need to be replugged.
+///
+/// Protocol servers hold a clone of the API in their tester present thread, so those threads
+/// must be stopped before this runs. See [ProtocolServer::exit_diag_session](fn@crate::commapi::protocols::ProtocolServer::exit_diag_session)
+impl Drop for PassthruApi {
+    fn drop(&mut self) {
+        if Arc::strong_count(&self.driver) != 1 {
+            return; // Other clones still using the device
+        }
+        if let Err(e) = self.close_iso15765_interface() {
+            eprintln!("Error closing ISO15765 channel on drop {}", e)
+        }
+        if let Err(e) = self.close_can_interface() {
+            eprintln!("Error closing CAN channel on drop {}", e)
+        }
+        let connected = self.driver.lock().unwrap().is_connected();
+        if connected {
+            if let Err(e) = self.close_device() {
+                eprintln!("Error closing device on drop {}", e)
+            }
+        }
+    }
+}
+
 impl PassthruApi {
     pub fn new(desc: PassthruDevice, driver: PassthruDrv) -> Self {
         Self {
@@ -346,7 +403,7 @@ impl PassthruApi {
     fn can_frame_to_pt_msg(cf: &CanFrame) -> PASSTHRU_MSG {
         let mut msg = PASSTHRU_MSG {
             protocol_id: Protocol::CAN as u32,
-            data_size: cf.dlc as u32 + 4, // +4 for CAN ID
+            data_size: cf.get_len() as u32 + 4, // +4 for CAN ID
             ..Default::default()
         };
         PassthruApi::u32_to_msg_id(cf.id, &mut msg);
@@ -361,7 +418,9 @@ impl PassthruApi {
             return None;
         }
         let data = &msg.data[4..msg.data_size as usize];
-        Some(CanFrame::new(PassthruApi::msg_id_to_u32(msg), data))
+        let mut frame = CanFrame::new(PassthruApi::msg_id_to_u32(msg), data);
+        frame.timestamp_us = Some(msg.timestamp as u64);
+        Some(frame)
     }
 
     fn pt_msg_to_iso15765(msg: &PASSTHRU_MSG) -> Option<ISO15765Data> {
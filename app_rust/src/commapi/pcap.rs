@@ -0,0 +1,120 @@
+use std::io::{self, Write};
+use crate::commapi::comm_api::{CanFrame, CAN_FD_MAX_DATA_LEN, CAN_MAX_DATA_LEN};
+
+// File format is described at https://wiki.wireshark.org/Development/LibpcapFileFormat,
+// frames use the Linux SocketCAN can_frame / canfd_frame layout
+
+/// pcap link type for SocketCAN frames
+pub const LINKTYPE_CAN_SOCKETCAN: u32 = 227;
+
+const PCAP_MAGIC: u32 = 0xA1B2_C3D4;
+const SNAP_LEN: u32 = 65535;
+
+/// Extended frame format (29 bit ID)
+const CAN_EFF_FLAG: u32 = 0x8000_0000;
+/// Remote transmission request
+const CAN_RTR_FLAG: u32 = 0x4000_0000;
+/// Error frame
+const CAN_ERR_FLAG: u32 = 0x2000_0000;
+const CAN_EFF_MASK: u32 = 0x1FFF_FFFF;
+const CAN_SFF_MASK: u32 = 0x0000_07FF;
+
+/// canfd_frame flags
+const CANFD_BRS: u8 = 0x01;
+const CANFD_FDF: u8 = 0x04;
+
+/// A frame recorded on the bus
+#[derive(Debug, Copy, Clone)]
+pub struct CapturedFrame {
+    /// Microseconds since the UNIX epoch
+    pub timestamp_us: u64,
+    pub frame: CanFrame,
+    /// Frame uses a 29 bit ID
+    pub extended: bool,
+    /// Frame is a remote transmission request
+    pub rtr: bool,
+    /// Frame is an error frame
+    pub error: bool,
+}
+
+impl CapturedFrame {
+    /// Captures a data frame. Extended IDs are assumed for any ID which does not fit in 11 bits
+    pub fn new(timestamp_us: u64, frame: CanFrame) -> Self {
+        Self { timestamp_us, frame, extended: frame.id > CAN_SFF_MASK, rtr: false, error: false }
+    }
+
+    /// Encodes the frame in the SocketCAN layout. Multi byte fields are in network byte order
+    fn to_socketcan(&self) -> Vec<u8> {
+        let mut can_id = if self.extended { (self.frame.id & CAN_EFF_MASK) | CAN_EFF_FLAG } else { self.frame.id & CAN_SFF_MASK };
+        if self.rtr {
+            can_id |= CAN_RTR_FLAG
+        }
+        if self.error {
+            can_id |= CAN_ERR_FLAG
+        }
+        let (flags, data_len) = if self.frame.fd {
+            (CANFD_FDF | if self.frame.brs { CANFD_BRS } else { 0 }, CAN_FD_MAX_DATA_LEN)
+        } else {
+            (0, CAN_MAX_DATA_LEN)
+        };
+        let mut res = Vec::with_capacity(8 + data_len);
+        res.extend_from_slice(&can_id.to_be_bytes());
+        res.push(self.frame.get_len() as u8);
+        res.extend_from_slice(&[flags, 0x00, 0x00]);
+        let mut data = self.frame.get_data().to_vec();
+        data.resize(data_len, 0x00);
+        res.extend_from_slice(&data);
+        res
+    }
+}
+
+/// Writes frames to a pcap file which Wireshark can dissect
+pub fn write_pcap<'a, W: Write, I: IntoIterator<Item = &'a CapturedFrame>>(mut writer: W, frames: I) -> io::Result<()> {
+    writer.write_all(&PCAP_MAGIC.to_le_bytes())?;
+    writer.write_all(&2u16.to_le_bytes())?; // Version 2.4
+    writer.write_all(&4u16.to_le_bytes())?;
+    writer.write_all(&0i32.to_le_bytes())?; // Timestamps are UTC
+    writer.write_all(&0u32.to_le_bytes())?; // Timestamp accuracy
+    writer.write_all(&SNAP_LEN.to_le_bytes())?;
+    writer.write_all(&LINKTYPE_CAN_SOCKETCAN.to_le_bytes())?;
+    for f in frames {
+        let packet = f.to_socketcan();
+        writer.write_all(&((f.timestamp_us / 1_000_000) as u32).to_le_bytes())?;
+        writer.write_all(&((f.timestamp_us % 1_000_000) as u32).to_le_bytes())?;
+        writer.write_all(&(packet.len() as u32).to_le_bytes())?; // Captured length
+        writer.write_all(&(packet.len() as u32).to_le_bytes())?; // Original length
+        writer.write_all(&packet)?;
+    }
+    writer.flush()
+}
+
+#[test]
+fn test_write_pcap() {
+    let frames = vec![
+        CapturedFrame::new(1_600_000_000_250_000, CanFrame::new(0x07E8, &[0x02, 0x50, 0x03])),
+        CapturedFrame::new(1_600_000_001_000_000, CanFrame::new(0x18DA_F110, &[0x01])),
+        CapturedFrame { rtr: true, ..CapturedFrame::new(1_600_000_001_000_001, CanFrame::new(0x0123, &[])) },
+        CapturedFrame::new(1_600_000_002_000_000, CanFrame::new_fd(0x0456, &[0xAA; 12], true)),
+    ];
+    let mut out = Vec::new();
+    write_pcap(&mut out, &frames).unwrap();
+
+    assert_eq!(&out[0..24], &[
+        0xD4, 0xC3, 0xB2, 0xA1, 0x02, 0x00, 0x04, 0x00, // Magic, version
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // Zone, accuracy
+        0xFF, 0xFF, 0x00, 0x00, 0xE3, 0x00, 0x00, 0x00, // Snap length, link type
+    ]);
+    assert_eq!(&out[24..56], &[
+        0x00, 0x10, 0x5E, 0x5F, 0x90, 0xD0, 0x03, 0x00, // 1600000000s, 250000us
+        0x10, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, // 16 bytes captured
+        0x00, 0x00, 0x07, 0xE8, 0x03, 0x00, 0x00, 0x00, // ID, length, flags
+        0x02, 0x50, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ]);
+    // EFF flag is set on the 29 bit ID
+    assert_eq!(&out[72..76], &[0x98, 0xDA, 0xF1, 0x10]);
+    assert_eq!(&out[104..108], &[0x40, 0x00, 0x01, 0x23]);
+    // FD frame is 72 bytes, with the FDF and BRS flags
+    let fd = &out[120 + 16..];
+    assert_eq!(fd.len(), 72);
+    assert_eq!(&fd[0..8], &[0x00, 0x00, 0x04, 0x56, 12, 0x05, 0x00, 0x00]);
+}
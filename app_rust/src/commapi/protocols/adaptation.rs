@@ -0,0 +1,126 @@
+use std::path::Path;
+use common::schema::SchemaV1;
+use serde::{Deserialize, Serialize};
+use super::{ProtocolError, ProtocolResult};
+use super::uds::UDSECU;
+use crate::error::Result;
+
+/// Value of an adaptation DID at the time of a backup
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AdaptationValue {
+    pub did: u16,
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+/// Snapshot of an ECU's adaptation DIDs, taken before coding changes so they can be rolled back
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AdaptationBackup {
+    /// Name of the ECU from its definition
+    pub ecu_name: String,
+    /// When the backup was taken, in RFC 3339 format
+    pub created: String,
+    pub values: Vec<AdaptationValue>,
+}
+
+impl AdaptationBackup {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    pub fn from_json(json: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(json)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        Ok(std::fs::write(path, self.to_json())?)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        Ok(Self::from_json(&text)?)
+    }
+}
+
+/// Reads every adaptation DID declared by the ECU's definition.
+///
+/// If any DID cannot be read, no backup is returned, as restoring a partial backup
+/// would leave the ECU in a state that was never tested
+pub fn backup_adaptations(client: &UDSECU, model: &SchemaV1) -> ProtocolResult<AdaptationBackup> {
+    let mut values = Vec::new();
+    for def in model.adaptation_dids() {
+        let data = client.read_data_by_id(def.did)?;
+        values.push(AdaptationValue { did: def.did, name: def.name.clone(), data });
+    }
+    Ok(AdaptationBackup {
+        ecu_name: model.ecu_name().into(),
+        created: chrono::Utc::now().to_rfc3339(),
+        values,
+    })
+}
+
+/// Writes every value in a backup back to the ECU, reading each DID back to verify it.
+///
+/// Security access must already have been granted. Stops at the first DID which fails
+/// to write or does not read back the same, so later DIDs are left unchanged
+pub fn restore_adaptations(client: &UDSECU, backup: &AdaptationBackup) -> ProtocolResult<()> {
+    for v in &backup.values {
+        client.write_data_by_id(v.did, &v.data)?;
+        let read = client.read_data_by_id(v.did)?;
+        if read != v.data {
+            return Err(ProtocolError::InvalidResponse(format!("{} (0x{:04X}) reads back {:02X?} after writing {:02X?}", v.name, v.did, read, v.data)))
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_adaptation_round_trip() {
+    use crate::commapi::comm_api::ISO15765Config;
+    use crate::commapi::mock_api::MockComServer;
+    use crate::commapi::mock_ecu::MockEcu;
+    use super::ProtocolServer;
+    use super::uds::UDSCommand;
+
+    let model = SchemaV1::from_json(r#"{
+        "meta": { "name": "EGS52", "vendor": "Mercedes-Benz", "desc": "722.6 Controller Generation" },
+        "err_table": [],
+        "comm_data": [],
+        "adaptations": [
+            { "did": 1536, "name": "Shift pressure adaptation" },
+            { "did": 1537, "name": "Variant coding" }
+        ]
+    }"#).unwrap();
+    let ecu = MockEcu::new(0x07E0, 0x07E8);
+    ecu.set_did(0x0600, &[0x10, 0x20, 0x30]);
+    ecu.set_did(0x0601, &[0x02]);
+    let mut mock = MockComServer::new();
+    let ecu_t = ecu.clone();
+    mock.set_iso15765_responder(move |req| ecu_t.respond_iso15765(req));
+    let cfg = ISO15765Config { send_id: 0x07E0, recv_id: 0x07E8, block_size: 8, sep_time: 20 };
+    let client = UDSECU::start_diag_session(Box::new(mock), &cfg).unwrap();
+
+    let backup = backup_adaptations(&client, &model).unwrap();
+    assert_eq!(backup.ecu_name, "EGS52");
+    assert_eq!(backup.values, vec![
+        AdaptationValue { did: 0x0600, name: "Shift pressure adaptation".into(), data: vec![0x10, 0x20, 0x30] },
+        AdaptationValue { did: 0x0601, name: "Variant coding".into(), data: vec![0x02] },
+    ]);
+    let backup = AdaptationBackup::from_json(&backup.to_json()).unwrap();
+
+    // Key is the seed XOR 0xFF
+    client.run_command(UDSCommand::SecurityAccess, &[0x01], 500).unwrap();
+    client.run_command(UDSCommand::SecurityAccess, &[0x02, 0xED, 0xCB], 500).unwrap();
+    client.write_data_by_id(0x0600, &[0x00, 0x00, 0x00]).unwrap();
+    client.write_data_by_id(0x0601, &[0x05]).unwrap();
+    assert_eq!(ecu.get_did(0x0601), Some(vec![0x05]));
+
+    restore_adaptations(&client, &backup).unwrap();
+    assert_eq!(ecu.get_did(0x0600), Some(vec![0x10, 0x20, 0x30]));
+    assert_eq!(ecu.get_did(0x0601), Some(vec![0x02]));
+
+    // A DID the ECU does not have cannot be restored
+    let mut bad = backup.clone();
+    bad.values[0].did = 0x0700;
+    assert!(restore_adaptations(&client, &bad).is_err());
+}
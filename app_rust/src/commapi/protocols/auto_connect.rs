@@ -0,0 +1,186 @@
+use serde::{Deserialize, Serialize};
+use crate::commapi::comm_api::{ComServer, ISO15765Config};
+use super::{send_raw_iso15765, ProtocolError, ProtocolResult, ProtocolServer};
+use super::kwp2000::KWP2000ECU;
+use super::uds::UDSECU;
+
+/// Time to wait for an ECU to answer a probe. Kept short, as most candidates will not answer
+const PROBE_TIMEOUT_MS: u128 = 150;
+
+/// Diagnostic protocol spoken by an ECU
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DiagProtocol {
+    Uds,
+    Kwp2000,
+}
+
+impl DiagProtocol {
+    /// TesterPresent request, which every ECU accepts without changing anything.
+    /// KWP2000 ECUs only respond if asked to with 0x01
+    fn probe_request(&self) -> &'static [u8] {
+        match self {
+            DiagProtocol::Uds => &[0x3E, 0x00],
+            DiagProtocol::Kwp2000 => &[0x3E, 0x01],
+        }
+    }
+}
+
+/// Bus, addressing and protocol to try connecting to an ECU with
+#[derive(Debug, Copy, Clone)]
+pub struct ConnectProfile {
+    /// Speed of the CAN bus in bps
+    pub bus_speed: u32,
+    /// True if the ECU uses 29bit CAN IDs
+    pub ext_can: bool,
+    pub iso_tp: ISO15765Config,
+    pub protocol: DiagProtocol,
+}
+
+impl std::fmt::Display for ConnectProfile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?} at {}kbps, {} 0x{:X} -> 0x{:X}", self.protocol, self.bus_speed / 1000,
+               if self.ext_can { "29bit" } else { "11bit" }, self.iso_tp.send_id, self.iso_tp.recv_id)
+    }
+}
+
+impl ConnectProfile {
+    pub fn new(bus_speed: u32, ext_can: bool, send_id: u32, recv_id: u32, protocol: DiagProtocol) -> Self {
+        Self {
+            bus_speed,
+            ext_can,
+            iso_tp: ISO15765Config { send_id, recv_id, block_size: 8, sep_time: 20 },
+            protocol,
+        }
+    }
+
+    /// Profiles for the engine ECU of an unknown vehicle, most common first:
+    /// UDS then KWP2000 on the 500kbps OBD-II bus, then the same on 250kbps, each with 11bit
+    /// then 29bit (Normal fixed) addressing
+    pub fn obd_candidates() -> Vec<Self> {
+        let mut res = Vec::new();
+        for bus_speed in &[500_000, 250_000] {
+            for protocol in &[DiagProtocol::Uds, DiagProtocol::Kwp2000] {
+                res.push(Self::new(*bus_speed, false, 0x07E0, 0x07E8, *protocol));
+                res.push(Self::new(*bus_speed, true, 0x18DA10F1, 0x18DAF110, *protocol));
+            }
+        }
+        res
+    }
+}
+
+/// Diagnostic server for whichever protocol the ECU answered to
+#[derive(Debug, Clone)]
+pub enum DiagServer {
+    Uds(UDSECU),
+    Kwp2000(KWP2000ECU),
+}
+
+impl DiagServer {
+    pub fn exit_diag_session(&mut self) {
+        match self {
+            DiagServer::Uds(s) => s.exit_diag_session(),
+            DiagServer::Kwp2000(s) => s.exit_diag_session(),
+        }
+    }
+}
+
+/// Session opened by [auto_connect]
+#[derive(Debug, Clone)]
+pub struct ConnectedSession {
+    /// Profile the ECU answered to
+    pub profile: ConnectProfile,
+    pub server: DiagServer,
+}
+
+/// Sends a probe with a profile, and closes the interface again
+fn probe(comm_server: &mut Box<dyn ComServer>, profile: &ConnectProfile) -> ProtocolResult<Vec<u8>> {
+    comm_server.open_iso15765_interface(profile.bus_speed, profile.ext_can).map_err(ProtocolError::CommError)?;
+    let res = comm_server.add_iso15765_filter(profile.iso_tp.recv_id, if profile.ext_can { 0x1FFF_FFFF } else { 0xFFF }, profile.iso_tp.send_id)
+        .and_then(|_| comm_server.set_iso15765_params(profile.iso_tp.sep_time, profile.iso_tp.block_size))
+        .map_err(ProtocolError::CommError)
+        .and_then(|_| send_raw_iso15765(comm_server.as_ref(), profile.iso_tp.send_id, profile.protocol.probe_request(), PROBE_TIMEOUT_MS, PROBE_TIMEOUT_MS));
+    if let Err(e) = comm_server.close_iso15765_interface() {
+        eprintln!("ERROR closing ISO-TP interface after probe {}", e)
+    }
+    res
+}
+
+/// Connects to an ECU on an unknown vehicle, by trying each profile in turn until the ECU answers.
+///
+/// Each profile is probed with a TesterPresent request. Any response, positive or negative, shows
+/// the bus, addressing and protocol are right, and a diagnostic session is started with that profile.
+/// Profiles the adapter cannot open (Example: 29bit CAN on some adapters) are skipped
+///
+/// # Returns
+/// The session, and the profile it was opened with. If no profile worked, the error from the last one
+pub fn auto_connect(comm_server: &mut Box<dyn ComServer>, candidates: &[ConnectProfile]) -> ProtocolResult<ConnectedSession> {
+    let mut last_err = ProtocolError::InvalidRequest("No connection profiles to try".into());
+    for profile in candidates {
+        if let Err(e) = probe(comm_server, profile) {
+            match e {
+                // Nothing else will work either
+                ProtocolError::CommError(ref c) if c.is_device_lost() => return Err(e),
+                _ => last_err = e
+            }
+            continue
+        }
+        let server = match profile.protocol {
+            DiagProtocol::Uds => UDSECU::start_on_bus(comm_server.clone(), &profile.iso_tp, profile.bus_speed, profile.ext_can).map(DiagServer::Uds),
+            DiagProtocol::Kwp2000 => KWP2000ECU::start_on_bus(comm_server.clone(), &profile.iso_tp, profile.bus_speed, profile.ext_can).map(DiagServer::Kwp2000),
+        };
+        match server {
+            Ok(server) => return Ok(ConnectedSession { profile: *profile, server }),
+            Err(e) => last_err = e
+        }
+    }
+    Err(last_err)
+}
+
+#[test]
+fn test_auto_connect_third_profile() {
+    use crate::commapi::comm_api::ISO15765Data;
+    use crate::commapi::mock_api::MockComServer;
+
+    // ECU is on a 250kbps bus with 29bit addressing, and speaks UDS
+    let mut mock = MockComServer::new();
+    let mock_t = mock.clone();
+    mock.set_iso15765_responder(move |req| {
+        if mock_t.iso15765_bus() != Some((250_000, true)) || req.id != 0x18DA10F1 {
+            return vec![]
+        }
+        let data = match req.data.as_slice() {
+            [0x3E, 0x00] => vec![0x7E, 0x00],
+            [0x10, 0x03] => vec![0x50, 0x03],
+            _ => return vec![]
+        };
+        vec![ISO15765Data { id: 0x18DAF110, data, pad_frame: false }]
+    });
+    let candidates = vec![
+        ConnectProfile::new(500_000, false, 0x07E0, 0x07E8, DiagProtocol::Uds),
+        ConnectProfile::new(250_000, false, 0x07E0, 0x07E8, DiagProtocol::Uds),
+        ConnectProfile::new(250_000, true, 0x18DA10F1, 0x18DAF110, DiagProtocol::Uds),
+        ConnectProfile::new(250_000, true, 0x18DA10F1, 0x18DAF110, DiagProtocol::Kwp2000),
+    ];
+    let mut server: Box<dyn ComServer> = Box::new(mock.clone());
+    let mut session = auto_connect(&mut server, &candidates).unwrap();
+    assert_eq!(session.profile.bus_speed, 250_000);
+    assert!(session.profile.ext_can);
+    assert_eq!(session.profile.protocol, DiagProtocol::Uds);
+    assert!(matches!(session.server, DiagServer::Uds(_)));
+    assert_eq!(session.profile.to_string(), "Uds at 250kbps, 29bit 0x18DA10F1 -> 0x18DAF110");
+
+    let probes: Vec<(u32, Vec<u8>)> = mock.get_iso15765_tx_log().into_iter().map(|d| (d.id, d.data)).collect();
+    assert_eq!(&probes[..4], &[
+        (0x07E0, vec![0x3E, 0x00]),
+        (0x07E0, vec![0x3E, 0x00]),
+        (0x18DA10F1, vec![0x3E, 0x00]),
+        (0x18DA10F1, vec![0x10, 0x03]),
+    ]);
+    session.server.exit_diag_session();
+    assert!(!mock.is_iso15765_open());
+
+    // Nothing answers
+    let mut server: Box<dyn ComServer> = Box::new(MockComServer::new());
+    assert!(matches!(auto_connect(&mut server, &candidates[..2]), Err(ProtocolError::Timeout)));
+    assert!(matches!(auto_connect(&mut server, &[]), Err(ProtocolError::InvalidRequest(_))));
+}
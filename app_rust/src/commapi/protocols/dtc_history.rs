@@ -0,0 +1,222 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+use super::uds::{ExtDataRecord, ExtDataValue};
+
+/// A DTC reported by one read of the ECU's fault memory
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DtcObservation {
+    /// 3 byte DTC number
+    pub dtc: u32,
+    /// Occurrence counter from the DTC's extended data, if it was read
+    pub occurrence_count: Option<u32>,
+}
+
+impl DtcObservation {
+    /// Creates an observation with the occurrence counter from a DTC's
+    /// [extended data records](fn@super::uds::UDSECU::read_dtc_extended_data), if there is one
+    pub fn from_ext_data(dtc: u32, records: &[ExtDataRecord]) -> Self {
+        let occurrence_count = records.iter().find_map(|r| match r.value {
+            Some(ExtDataValue::OccurrenceCount(c)) => Some(c),
+            _ => None
+        });
+        Self { dtc, occurrence_count }
+    }
+}
+
+/// How a fault has behaved over the session
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FaultKind {
+    /// Reported by every read since it first appeared, without the ECU detecting it again
+    Hard,
+    /// Has disappeared since it first appeared, or the ECU detected it again between reads
+    Intermittent,
+}
+
+impl std::fmt::Display for FaultKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FaultKind::Hard => write!(f, "Hard"),
+            FaultKind::Intermittent => write!(f, "Intermittent"),
+        }
+    }
+}
+
+/// History of one DTC over the session
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DtcRecord {
+    pub dtc: u32,
+    /// Time since the history was created of the first read the DTC was in
+    pub first_seen: Duration,
+    /// Time since the history was created of the last read the DTC was in
+    pub last_seen: Duration,
+    /// Number of reads the DTC was in
+    pub times_seen: u32,
+    /// Number of times the DTC appeared after not being in the previous read, including the first time
+    pub appearances: u32,
+    /// DTC was in the last read
+    pub present: bool,
+    /// Occurrence counter from the first read it was known in
+    pub first_count: Option<u32>,
+    /// Occurrence counter from the last read it was known in
+    pub last_count: Option<u32>,
+    /// Number of the last read the DTC was in
+    last_read: u32,
+}
+
+impl DtcRecord {
+    /// Change in the ECU's occurrence counter over the session.
+    /// None unless the counter was read at least once
+    pub fn count_delta(&self) -> Option<u32> {
+        Some(self.last_count?.saturating_sub(self.first_count?))
+    }
+
+    pub fn kind(&self) -> FaultKind {
+        if self.appearances > 1 || !self.present || self.count_delta().unwrap_or(0) > 0 {
+            FaultKind::Intermittent
+        } else {
+            FaultKind::Hard
+        }
+    }
+}
+
+/// Tracks the DTCs reported by each read of an ECU's fault memory over a session,
+/// so faults which come and go can be told apart from faults which are always present
+#[derive(Debug, Clone)]
+pub struct DtcHistory {
+    start: Instant,
+    reads: u32,
+    dtcs: BTreeMap<u32, DtcRecord>,
+}
+
+impl Default for DtcHistory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DtcHistory {
+    pub fn new() -> Self {
+        Self { start: Instant::now(), reads: 0, dtcs: BTreeMap::new() }
+    }
+
+    /// Adds the DTCs reported by a read which has just completed
+    pub fn record(&mut self, observations: &[DtcObservation]) {
+        let at = self.start.elapsed();
+        self.record_at(at, observations)
+    }
+
+    /// Adds the DTCs reported by a read
+    ///
+    /// # Params
+    /// * at - Time since the history was created that the read was done
+    /// * observations - Every DTC the read returned. DTCs not in the list are treated as gone
+    pub fn record_at(&mut self, at: Duration, observations: &[DtcObservation]) {
+        self.reads += 1;
+        let read = self.reads;
+        for o in observations {
+            let r = self.dtcs.entry(o.dtc).or_insert(DtcRecord {
+                dtc: o.dtc,
+                first_seen: at,
+                last_seen: at,
+                times_seen: 0,
+                appearances: 0,
+                present: false,
+                first_count: None,
+                last_count: None,
+                last_read: 0,
+            });
+            if r.last_read == read {
+                continue // Listed twice by the same read
+            }
+            if r.times_seen == 0 || r.last_read != read - 1 {
+                r.appearances += 1;
+            }
+            r.last_read = read;
+            r.last_seen = at;
+            r.times_seen += 1;
+            if let Some(c) = o.occurrence_count {
+                r.first_count.get_or_insert(c);
+                r.last_count = Some(c);
+            }
+        }
+        for r in self.dtcs.values_mut() {
+            r.present = r.last_read == read;
+        }
+    }
+
+    /// Number of reads recorded
+    pub fn reads(&self) -> u32 {
+        self.reads
+    }
+
+    pub fn get(&self, dtc: u32) -> Option<&DtcRecord> {
+        self.dtcs.get(&dtc)
+    }
+
+    /// Every DTC seen over the session, in DTC number order
+    pub fn records(&self) -> impl Iterator<Item = &DtcRecord> {
+        self.dtcs.values()
+    }
+
+    /// Forgets every read. Used after the ECU's fault memory has been cleared
+    pub fn clear(&mut self) {
+        *self = Self::new()
+    }
+}
+
+#[test]
+fn test_intermittent_dtc_history() {
+    let secs = Duration::from_secs;
+    let obs = |dtc, occurrence_count| DtcObservation { dtc, occurrence_count };
+    let mut history = DtcHistory::new();
+    // P0300 (Misfire) comes and goes, P0420 (Catalyst) is always there
+    history.record_at(secs(0), &[obs(0x030000, Some(2)), obs(0x042000, Some(1))]);
+    history.record_at(secs(10), &[obs(0x042000, Some(1))]);
+    history.record_at(secs(20), &[obs(0x030000, Some(4)), obs(0x042000, Some(1)), obs(0x030000, Some(4))]);
+    history.record_at(secs(30), &[obs(0x030000, None), obs(0x042000, Some(1))]);
+    assert_eq!(history.reads(), 4);
+
+    let misfire = history.get(0x030000).unwrap();
+    assert_eq!(misfire, &DtcRecord {
+        dtc: 0x030000,
+        first_seen: secs(0),
+        last_seen: secs(30),
+        times_seen: 3,
+        appearances: 2,
+        present: true,
+        first_count: Some(2),
+        last_count: Some(4),
+        last_read: 4,
+    });
+    assert_eq!(misfire.count_delta(), Some(2));
+    assert_eq!(misfire.kind(), FaultKind::Intermittent);
+
+    let cat = history.get(0x042000).unwrap();
+    assert_eq!((cat.times_seen, cat.appearances, cat.count_delta()), (4, 1, Some(0)));
+    assert_eq!(cat.kind(), FaultKind::Hard);
+
+    // Gone in the latest read
+    history.record_at(secs(40), &[obs(0x030000, Some(4))]);
+    let cat = history.get(0x042000).unwrap();
+    assert!(!cat.present);
+    assert_eq!(cat.last_seen, secs(30));
+    assert_eq!(cat.kind().to_string(), "Intermittent");
+
+    // Occurrence counter increases while the DTC stays present
+    let mut history = DtcHistory::new();
+    history.record_at(secs(0), &[obs(0x012300, Some(1))]);
+    history.record_at(secs(5), &[obs(0x012300, Some(3))]);
+    assert_eq!(history.get(0x012300).unwrap().kind(), FaultKind::Intermittent);
+    assert_eq!(history.records().count(), 1);
+    assert!(history.get(0x042000).is_none());
+}
+
+#[test]
+fn test_observation_from_ext_data() {
+    let records = vec![
+        ExtDataRecord { record_number: 0x02, data: vec![0x00, 0x28], name: None, value: Some(ExtDataValue::AgingCounter(40)) },
+        ExtDataRecord { record_number: 0x01, data: vec![0x05], name: None, value: Some(ExtDataValue::OccurrenceCount(5)) },
+    ];
+    assert_eq!(DtcObservation::from_ext_data(0x9D0013, &records), DtcObservation { dtc: 0x9D0013, occurrence_count: Some(5) });
+    assert_eq!(DtcObservation::from_ext_data(0x9D0013, &records[..1]).occurrence_count, None);
+}
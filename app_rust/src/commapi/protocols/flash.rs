@@ -0,0 +1,601 @@
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use common::raf::{Raf, RafByteOrder};
+use super::{Cancellable, CancellationToken, ProtocolError, ProtocolResult, ProtocolServer};
+use super::uds::{encode_memory_address, UDSCommand, UDSECU, DEFAULT_MEMORY_BLOCK_LEN};
+
+/// Routine most ECUs use to check the integrity of downloaded memory (RoutineControl 0x31)
+pub const ROUTINE_CHECK_MEMORY: u16 = 0x0202;
+
+/// How each block is checked after it has been transferred
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BlockVerify {
+    /// Blocks are trusted once the ECU accepts them
+    None,
+    /// Each block is read back with ReadMemoryByAddress and compared
+    ReadBack,
+    /// The ECU runs a check memory routine over each block, with the CRC32 of the block
+    Routine(u16),
+}
+
+/// Progress of a flash operation, which is saved after each block so an interrupted
+/// flash can be resumed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlashProgress {
+    /// Address the image is written to
+    pub address: u64,
+    pub image_len: usize,
+    /// CRC32 of the image, so progress is never resumed with a different image
+    pub image_crc: u32,
+    /// Length of each block. 0 until the ECU has accepted the first download request
+    pub block_len: usize,
+    /// Number of blocks which have been transferred and verified
+    pub confirmed_blocks: usize,
+}
+
+impl FlashProgress {
+    /// Returns the number of bytes of the image which have been confirmed
+    pub fn confirmed_len(&self) -> usize {
+        std::cmp::min(self.confirmed_blocks * self.block_len, self.image_len)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.block_len != 0 && self.confirmed_len() == self.image_len
+    }
+
+    /// Saves the progress to a file. The file is replaced atomically, so a crash whilst
+    /// saving leaves the previous progress intact
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        let tmp = path.as_ref().with_extension("tmp");
+        std::fs::write(&tmp, self.to_string())?;
+        std::fs::rename(tmp, path)
+    }
+
+    /// Loads progress saved with [save](fn@FlashProgress::save)
+    pub fn load<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        Self::parse(&text).ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid flash progress file"))
+    }
+
+    fn parse(text: &str) -> Option<Self> {
+        let field = |name: &str| text.lines()
+            .find_map(|l| l.strip_prefix(name).and_then(|v| v.strip_prefix('=')))
+            .map(str::trim);
+        Some(Self {
+            address: u64::from_str_radix(field("address")?, 16).ok()?,
+            image_len: field("image_len")?.parse().ok()?,
+            image_crc: u32::from_str_radix(field("image_crc")?, 16).ok()?,
+            block_len: field("block_len")?.parse().ok()?,
+            confirmed_blocks: field("confirmed_blocks")?.parse().ok()?,
+        })
+    }
+}
+
+impl std::fmt::Display for FlashProgress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "address={:X}", self.address)?;
+        writeln!(f, "image_len={}", self.image_len)?;
+        writeln!(f, "image_crc={:08X}", self.image_crc)?;
+        writeln!(f, "block_len={}", self.block_len)?;
+        writeln!(f, "confirmed_blocks={}", self.confirmed_blocks)
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    Raf::from_bytes(&data.to_vec(), RafByteOrder::BE).crc32(0, data.len()).unwrap_or(0)
+}
+
+/// Writes an image to an ECU with RequestDownload / TransferData, verifying each block
+/// and keeping track of which blocks are confirmed.
+///
+/// If the flash is interrupted or cancelled, [resume](fn@FlashSession::resume) starts a new download
+/// from the first unconfirmed block rather than the start of the image. The ECU must
+/// already be in the programming session, with security access granted.
+#[derive(Debug)]
+pub struct FlashSession<'a> {
+    ecu: &'a UDSECU,
+    image: &'a [u8],
+    addr_bytes: u8,
+    size_bytes: u8,
+    verify: BlockVerify,
+    progress: FlashProgress,
+    progress_file: Option<PathBuf>,
+}
+
+impl<'a> FlashSession<'a> {
+    /// Creates a flash session
+    ///
+    /// # Params
+    /// * ecu - ECU to flash
+    /// * address - Address to download the image to
+    /// * image - Data to download
+    /// * addr_bytes - Number of bytes the ECU expects addresses in (1-8)
+    /// * size_bytes - Number of bytes the ECU expects sizes in (1-4)
+    pub fn new(ecu: &'a UDSECU, address: u64, image: &'a [u8], addr_bytes: u8, size_bytes: u8) -> Self {
+        Self {
+            ecu,
+            image,
+            addr_bytes,
+            size_bytes,
+            verify: BlockVerify::None,
+            progress: FlashProgress { address, image_len: image.len(), image_crc: crc32(image), block_len: 0, confirmed_blocks: 0 },
+            progress_file: None,
+        }
+    }
+
+    pub fn set_verify(&mut self, verify: BlockVerify) {
+        self.verify = verify
+    }
+
+    /// Saves progress to a file after every confirmed block. If the file already holds
+    /// progress for the same image and address, the session continues from it
+    pub fn set_progress_file<P: AsRef<Path>>(&mut self, path: P) {
+        if let Ok(saved) = FlashProgress::load(&path) {
+            if (saved.address, saved.image_len, saved.image_crc) == (self.progress.address, self.progress.image_len, self.progress.image_crc) {
+                self.progress = saved;
+            }
+        }
+        self.progress_file = Some(path.as_ref().to_path_buf());
+    }
+
+    pub fn progress(&self) -> &FlashProgress {
+        &self.progress
+    }
+
+    /// Flashes the image, continuing after the last confirmed block
+    pub fn run(&mut self, token: &CancellationToken) -> ProtocolResult<Cancellable<()>> {
+        self.resume(self.progress.confirmed_blocks, token)
+    }
+
+    /// Flashes the image starting from a block. Every block before it must have been confirmed
+    ///
+    /// If the token is cancelled, the flash stops before the next block is transferred and the
+    /// download is ended with TransferExit. The number of confirmed blocks is returned, and the
+    /// flash can be resumed from there
+    pub fn resume(&mut self, from_block: usize, token: &CancellationToken) -> ProtocolResult<Cancellable<()>> {
+        if from_block > self.progress.confirmed_blocks {
+            return Err(ProtocolError::InvalidRequest(format!("Cannot resume from block {}, only {} blocks are confirmed", from_block, self.progress.confirmed_blocks)))
+        }
+        self.progress.confirmed_blocks = from_block;
+        let start = self.progress.confirmed_len();
+        if self.progress.block_len != 0 && start == self.image.len() {
+            return Ok(Cancellable::Complete(()))
+        }
+
+        let max_block = self.request_download(self.progress.address + start as u64, self.image.len() - start)?;
+        // Max block length includes the SID and block sequence counter
+        let data_len = max_block.saturating_sub(2);
+        if data_len == 0 || (self.progress.block_len != 0 && data_len < self.progress.block_len) {
+            return Err(ProtocolError::InvalidResponse(format!("ECU only accepts {} byte blocks", max_block)))
+        }
+        if self.progress.block_len == 0 {
+            self.progress.block_len = data_len;
+        }
+
+        let block_len = self.progress.block_len;
+        for (idx, block) in self.image[start..].chunks(block_len).enumerate() {
+            if token.is_cancelled() {
+                self.ecu.run_command(UDSCommand::TransferExit, &[], 1000)?;
+                return Ok(Cancellable::Cancelled { partial: (), completed: self.progress.confirmed_blocks })
+            }
+            // Sequence counter starts at 1 for each download, and wraps from 0xFF to 0x00
+            let seq = (idx + 1) as u8;
+            let mut args = vec![seq];
+            args.extend_from_slice(block);
+            let res = self.ecu.run_command(UDSCommand::TransferData, &args, 1000)?;
+            if res.first() != Some(&seq) {
+                return Err(ProtocolError::InvalidResponse(format!("Transfer data response {:02X?} for block {}", res, seq)))
+            }
+            let block_address = self.progress.address + (start + idx * block_len) as u64;
+            self.verify_block(block_address, block)?;
+            self.progress.confirmed_blocks += 1;
+            self.save_progress()?;
+        }
+        self.ecu.run_command(UDSCommand::TransferExit, &[], 1000)?;
+        Ok(Cancellable::Complete(()))
+    }
+
+    /// Runs a check memory routine over the whole image
+    pub fn check_memory(&self, routine_id: u16) -> ProtocolResult<()> {
+        self.run_check_routine(routine_id, self.progress.address, self.image)
+    }
+
+    /// Sends RequestDownload, returning the max block length the ECU accepts
+    fn request_download(&self, address: u64, len: usize) -> ProtocolResult<usize> {
+        // 0x00 - No compression or encryption
+        let mut args = vec![0x00];
+        args.extend(encode_memory_address(address, len, self.addr_bytes, self.size_bytes)?);
+        let res = self.ecu.run_command(UDSCommand::RequestDownload, &args, 1000)?;
+        // [length format ID, max block length...]
+        let len_bytes = res.first().map(|x| (x >> 4) as usize).unwrap_or(0);
+        if len_bytes == 0 || len_bytes > 8 || res.len() < 1 + len_bytes {
+            return Err(ProtocolError::InvalidResponse(format!("Invalid request download response {:02X?}", res)))
+        }
+        Ok(res[1..1 + len_bytes].iter().fold(0usize, |acc, b| acc << 8 | *b as usize))
+    }
+
+    fn verify_block(&self, address: u64, block: &[u8]) -> ProtocolResult<()> {
+        match self.verify {
+            BlockVerify::None => Ok(()),
+            BlockVerify::ReadBack => {
+                if self.ecu.read_memory(address, block.len(), self.addr_bytes, self.size_bytes)? != block {
+                    return Err(ProtocolError::InvalidResponse(format!("Block at 0x{:X} does not match after reading back", address)))
+                }
+                Ok(())
+            },
+            BlockVerify::Routine(id) => self.run_check_routine(id, address, block)
+        }
+    }
+
+    fn run_check_routine(&self, routine_id: u16, address: u64, data: &[u8]) -> ProtocolResult<()> {
+        let status = run_check_routine(self.ecu, routine_id, address, data, self.addr_bytes, self.size_bytes)?;
+        if status != 0x00 {
+            return Err(ProtocolError::InvalidResponse(format!("Check memory failed at 0x{:X} (Status 0x{:02X})", address, status)))
+        }
+        Ok(())
+    }
+
+    fn save_progress(&self) -> ProtocolResult<()> {
+        if let Some(path) = &self.progress_file {
+            self.progress.save(path).map_err(|e| ProtocolError::InvalidRequest(format!("Cannot save flash progress: {}", e)))?;
+        }
+        Ok(())
+    }
+}
+
+/// Starts a check memory routine over some memory, with the CRC32 the memory should have.
+/// Returns the status the ECU reports, where 0x00 means the memory is correct
+fn run_check_routine(ecu: &UDSECU, routine_id: u16, address: u64, data: &[u8], addr_bytes: u8, size_bytes: u8) -> ProtocolResult<u8> {
+    // 0x01 - Start routine
+    let mut args = vec![0x01, (routine_id >> 8) as u8, routine_id as u8];
+    args.extend(encode_memory_address(address, data.len(), addr_bytes, size_bytes)?);
+    args.extend_from_slice(&crc32(data).to_be_bytes());
+    let res = ecu.run_command(UDSCommand::RoutineControl, &args, 5000)?;
+    // [0x01, routine ID, status]
+    if res.len() < 4 || res[0..3] != args[0..3] {
+        return Err(ProtocolError::InvalidResponse(format!("Invalid routine control response {:02X?}", res)))
+    }
+    Ok(res[3])
+}
+
+/// How a flashed image was verified
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum VerifyMethod {
+    /// Memory was read back with ReadMemoryByAddress and compared byte for byte
+    ReadBack,
+    /// The ECU does not allow memory to be read back, so a check memory routine was run
+    /// over the whole image instead
+    Routine(u16),
+}
+
+/// Result of [verify_flash]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Address the image was flashed to
+    pub address: u64,
+    pub image_len: usize,
+    pub method: VerifyMethod,
+    /// Ranges of the image (Offsets from the start of the image) which do not match the ECU's memory.
+    /// With [VerifyMethod::Routine], the ECU only reports if the whole image matches, so a failed
+    /// check is reported as the whole image
+    pub mismatches: Vec<Range<usize>>,
+}
+
+impl VerifyReport {
+    pub fn passed(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+impl std::fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let method = match self.method {
+            VerifyMethod::ReadBack => "read back".to_string(),
+            VerifyMethod::Routine(id) => format!("routine 0x{:04X}", id),
+        };
+        if self.passed() {
+            return write!(f, "{} bytes at 0x{:X} match ({})", self.image_len, self.address, method)
+        }
+        write!(f, "{} mismatched range(s) at 0x{:X} ({}):", self.mismatches.len(), self.address, method)?;
+        for r in &self.mismatches {
+            write!(f, " +0x{:X}..+0x{:X}", r.start, r.end)?;
+        }
+        Ok(())
+    }
+}
+
+/// Verifies a flashed image by reading the ECU's memory back in [DEFAULT_MEMORY_BLOCK_LEN]
+/// chunks and comparing it to the image.
+///
+/// If the ECU rejects ReadMemoryByAddress, a check memory routine is run over the whole image instead
+///
+/// If the token is cancelled, verifying stops before the next read, and the mismatches found so far
+/// are returned along with how many chunks were compared
+///
+/// # Params
+/// * ecu - ECU which was flashed
+/// * image - Image which was flashed
+/// * address - Address the image was flashed to
+/// * addr_bytes - Number of bytes the ECU expects addresses in (1-8)
+/// * size_bytes - Number of bytes the ECU expects sizes in (1-4)
+/// * routine_id - Check memory routine to fall back to. Usually [ROUTINE_CHECK_MEMORY]
+/// * token - Token to stop verifying with
+pub fn verify_flash(ecu: &UDSECU, image: &[u8], address: u64, addr_bytes: u8, size_bytes: u8, routine_id: u16, token: &CancellationToken) -> ProtocolResult<Cancellable<VerifyReport>> {
+    let mut report = VerifyReport { address, image_len: image.len(), method: VerifyMethod::ReadBack, mismatches: Vec::new() };
+    for (idx, chunk) in image.chunks(DEFAULT_MEMORY_BLOCK_LEN).enumerate() {
+        if token.is_cancelled() {
+            return Ok(Cancellable::Cancelled { partial: report, completed: idx })
+        }
+        let offset = idx * DEFAULT_MEMORY_BLOCK_LEN;
+        let read = match ecu.read_memory(address + offset as u64, chunk.len(), addr_bytes, size_bytes) {
+            Ok(read) => read,
+            // Negative response to the first read, so the ECU doesn't allow reading back
+            Err(ProtocolError::ProtocolError(_)) if idx == 0 => {
+                let status = run_check_routine(ecu, routine_id, address, image, addr_bytes, size_bytes)?;
+                report.method = VerifyMethod::Routine(routine_id);
+                if status != 0x00 && !image.is_empty() {
+                    report.mismatches.push(0..image.len());
+                }
+                return Ok(Cancellable::Complete(report))
+            },
+            Err(e) => return Err(e),
+        };
+        for (i, (a, b)) in chunk.iter().zip(read.iter()).enumerate() {
+            if a == b {
+                continue
+            }
+            let pos = offset + i;
+            match report.mismatches.last_mut() {
+                Some(r) if r.end == pos => r.end += 1,
+                _ => report.mismatches.push(pos..pos + 1),
+            }
+        }
+    }
+    Ok(Cancellable::Complete(report))
+}
+
+/// Simulated flash memory for the tests. The ECU stops responding to TransferData
+/// once `fail_after` blocks have been written
+#[cfg(test)]
+fn start_flash_mock(memory: std::sync::Arc<std::sync::Mutex<Vec<u8>>>, fail_after: Option<usize>) -> (crate::commapi::mock_api::MockComServer, UDSECU) {
+    super::uds::start_mock_session(flash_mock_responder(memory, fail_after))
+}
+
+/// Responder behind [start_flash_mock], for tests which need to see the requests as well
+#[cfg(test)]
+fn flash_mock_responder(memory: std::sync::Arc<std::sync::Mutex<Vec<u8>>>, fail_after: Option<usize>) -> impl Fn(&[u8]) -> Option<Vec<u8>> + Send + Sync + 'static {
+    use std::sync::{Arc, Mutex};
+    let cursor = Arc::new(Mutex::new((0usize, 0usize))); // Write address, blocks written
+    move |req: &[u8]| {
+        let mut mem = memory.lock().unwrap();
+        let mut cursor = cursor.lock().unwrap();
+        // Tests use 2 byte addresses and sizes
+        let addr = |p: &[u8]| (p[0] as usize) << 8 | p[1] as usize;
+        match req[0] {
+            0x34 => {
+                cursor.0 = addr(&req[3..]);
+                Some(vec![0x74, 0x10, 0x0A]) // 8 data bytes per block
+            },
+            0x36 => {
+                if Some(cursor.1) == fail_after {
+                    return None
+                }
+                let data = &req[2..];
+                mem[cursor.0..cursor.0 + data.len()].copy_from_slice(data);
+                cursor.0 += data.len();
+                cursor.1 += 1;
+                Some(vec![0x76, req[1]])
+            },
+            0x37 => Some(vec![0x77]),
+            0x23 => {
+                let (start, len) = (addr(&req[2..]), addr(&req[4..]));
+                let mut resp = vec![0x63];
+                resp.extend_from_slice(&mem[start..start + len]);
+                Some(resp)
+            },
+            0x31 => {
+                let (start, len) = (addr(&req[5..]), addr(&req[7..]));
+                let ok = crc32(&mem[start..start + len]).to_be_bytes() == req[9..13];
+                Some(vec![0x71, 0x01, req[2], req[3], if ok { 0x00 } else { 0x01 }])
+            },
+            _ => Some(vec![0x7F, req[0], 0x11])
+        }
+    }
+}
+
+#[test]
+fn test_resume_interrupted_flash() {
+    use std::sync::{Arc, Mutex};
+    let image: Vec<u8> = (0..60).collect();
+    let memory = Arc::new(Mutex::new(vec![0xFF; 0x200]));
+    let path = std::env::temp_dir().join(format!("ovd_flash_progress_{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+
+    {
+        // Power is lost after 3 blocks
+        let (_mock, ecu) = start_flash_mock(memory.clone(), Some(3));
+        let mut session = FlashSession::new(&ecu, 0x0100, &image, 2, 2);
+        session.set_verify(BlockVerify::ReadBack);
+        session.set_progress_file(&path);
+        assert!(matches!(session.run(&CancellationToken::new()), Err(ProtocolError::Timeout)));
+        assert_eq!(session.progress().confirmed_blocks, 3);
+    }
+    assert_eq!(FlashProgress::load(&path).unwrap().confirmed_len(), 24);
+
+    // New session picks up the saved progress, and only sends the remaining blocks
+    let (mock, ecu) = start_flash_mock(memory.clone(), None);
+    let mut session = FlashSession::new(&ecu, 0x0100, &image, 2, 2);
+    session.set_verify(BlockVerify::Routine(ROUTINE_CHECK_MEMORY));
+    session.set_progress_file(&path);
+    assert_eq!(session.progress().confirmed_blocks, 3);
+    assert_eq!(session.run(&CancellationToken::new()).unwrap(), Cancellable::Complete(()));
+    assert!(session.progress().is_complete());
+    session.check_memory(ROUTINE_CHECK_MEMORY).unwrap();
+    assert_eq!(&memory.lock().unwrap()[0x100..0x100 + 60], image.as_slice());
+
+    let requests: Vec<Vec<u8>> = mock.get_iso15765_tx_log().into_iter().map(|d| d.data).collect();
+    // Download restarts at the first unconfirmed block
+    assert!(requests.contains(&vec![0x34, 0x00, 0x22, 0x01, 0x18, 0x00, 0x24]));
+    assert_eq!(requests.iter().filter(|r| r[0] == 0x36).count(), 5);
+    assert!(session.resume(9, &CancellationToken::new()).is_err());
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn test_cancel_flash() {
+    use std::sync::{Arc, Mutex};
+    let image: Vec<u8> = (0..60).collect();
+    let memory = Arc::new(Mutex::new(vec![0xFF; 0x200]));
+    let token = CancellationToken::new();
+    let token_t = token.clone();
+    let responder = flash_mock_responder(memory.clone(), None);
+    let (mock, ecu) = super::uds::start_mock_session(move |req| {
+        if req[0] == 0x36 && req[1] == 3 {
+            token_t.cancel(); // User presses cancel while the 3rd block is in flight
+        }
+        responder(req)
+    });
+    let mut session = FlashSession::new(&ecu, 0x0100, &image, 2, 2);
+    session.set_verify(BlockVerify::ReadBack);
+    assert_eq!(session.run(&token).unwrap(), Cancellable::Cancelled { partial: (), completed: 3 });
+    assert_eq!(session.progress().confirmed_len(), 24);
+
+    // The block in flight is finished, then the download is ended
+    let requests: Vec<Vec<u8>> = mock.get_iso15765_tx_log().into_iter().map(|d| d.data).filter(|r| r[0] != 0x3E).collect();
+    assert_eq!(requests.iter().filter(|r| r[0] == 0x36).count(), 3);
+    assert_eq!(requests.last(), Some(&vec![0x37]));
+
+    assert_eq!(session.run(&CancellationToken::new()).unwrap(), Cancellable::Complete(()));
+    assert_eq!(&memory.lock().unwrap()[0x100..0x100 + 60], image.as_slice());
+}
+
+#[test]
+fn test_progress_for_other_image_is_ignored() {
+    let path = std::env::temp_dir().join(format!("ovd_flash_progress_other_{}", std::process::id()));
+    FlashProgress { address: 0x0100, image_len: 60, image_crc: 0x1234_5678, block_len: 8, confirmed_blocks: 5 }.save(&path).unwrap();
+    let (_mock, ecu) = start_flash_mock(std::sync::Arc::new(std::sync::Mutex::new(vec![0xFF; 0x200])), None);
+    let image = vec![0xAA; 60];
+    let mut session = FlashSession::new(&ecu, 0x0100, &image, 2, 2);
+    session.set_progress_file(&path);
+    assert_eq!(session.progress().confirmed_blocks, 0);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn test_dry_run_flash() {
+    let (mock, mut ecu) = start_flash_mock(std::sync::Arc::new(std::sync::Mutex::new(vec![0xFF; 0x200])), None);
+    let sent_before = mock.get_iso15765_tx_log().len();
+    ecu.set_dry_run(true);
+    ecu.set_memory_block_len(8);
+    let image: Vec<u8> = (0..20).collect();
+    let mut session = FlashSession::new(&ecu, 0x0100, &image, 2, 2);
+    session.set_verify(BlockVerify::Routine(ROUTINE_CHECK_MEMORY));
+    session.run(&CancellationToken::new()).unwrap();
+
+    let mut expected = vec![vec![0x34, 0x00, 0x22, 0x01, 0x00, 0x00, 0x14]];
+    for (seq, block) in image.chunks(8).enumerate() {
+        let mut transfer = vec![0x36, seq as u8 + 1];
+        transfer.extend_from_slice(block);
+        expected.push(transfer);
+        let mut check = vec![0x31, 0x01, 0x02, 0x02, 0x22, 0x01, seq as u8 * 8, 0x00, block.len() as u8];
+        check.extend_from_slice(&crc32(block).to_be_bytes());
+        expected.push(check);
+    }
+    expected.push(vec![0x37]);
+    assert_eq!(ecu.dry_run_transcript(), expected);
+    // Nothing other than tester present reached the ECU
+    assert!(mock.get_iso15765_tx_log()[sent_before..].iter().all(|r| r.data[0] == 0x3E));
+}
+
+/// Runs [verify_flash] with the test addressing, expecting it to complete
+#[cfg(test)]
+fn verify_complete(ecu: &UDSECU, image: &[u8]) -> VerifyReport {
+    match verify_flash(ecu, image, 0x0100, 2, 2, ROUTINE_CHECK_MEMORY, &CancellationToken::new()).unwrap() {
+        Cancellable::Complete(report) => report,
+        x => panic!("Expected verify to complete, got {:?}", x)
+    }
+}
+
+#[test]
+fn test_verify_flash() {
+    use std::sync::{Arc, Mutex};
+    // Longer than one read, so mismatches are merged across chunks
+    let image: Vec<u8> = (0..0x180).map(|x| x as u8).collect();
+    let memory = Arc::new(Mutex::new(vec![0xFF; 0x400]));
+    memory.lock().unwrap()[0x100..0x280].copy_from_slice(&image);
+    let (_mock, ecu) = start_flash_mock(memory.clone(), None);
+
+    let report = verify_complete(&ecu, &image);
+    assert!(report.passed());
+    assert_eq!(report.method, VerifyMethod::ReadBack);
+
+    {
+        let mut mem = memory.lock().unwrap();
+        mem[0x105] ^= 0x01;
+        mem[0x1FF] = 0xEE;
+        mem[0x200] = 0xEE;
+    }
+    let report = verify_complete(&ecu, &image);
+    assert!(!report.passed());
+    assert_eq!(report.mismatches, vec![5..6, 0xFF..0x101]);
+    assert_eq!(report.to_string(), "2 mismatched range(s) at 0x100 (read back): +0x5..+0x6 +0xFF..+0x101");
+}
+
+#[test]
+fn test_verify_flash_falls_back_to_routine() {
+    let image: Vec<u8> = (0..40).collect();
+    let flashed = std::sync::Arc::new(std::sync::Mutex::new(image.clone()));
+    let memory = flashed.clone();
+    let (mock, ecu) = super::uds::start_mock_session(move |req| match req[0] {
+        0x23 => Some(vec![0x7F, 0x23, 0x33]), // Security access denied
+        0x31 => {
+            let ok = crc32(&memory.lock().unwrap()).to_be_bytes() == req[9..13];
+            Some(vec![0x71, 0x01, req[2], req[3], if ok { 0x00 } else { 0x01 }])
+        },
+        _ => Some(vec![0x7F, req[0], 0x11])
+    });
+
+    let report = verify_complete(&ecu, &image);
+    assert_eq!(report.method, VerifyMethod::Routine(ROUTINE_CHECK_MEMORY));
+    assert!(report.passed());
+    let requests: Vec<Vec<u8>> = mock.get_iso15765_tx_log().into_iter().map(|d| d.data).collect();
+    assert!(requests.contains(&vec![0x23, 0x22, 0x01, 0x00, 0x00, 0x28]));
+
+    flashed.lock().unwrap()[10] = 0xAA;
+    let report = verify_complete(&ecu, &image);
+    assert_eq!(report.mismatches, vec![0..40]);
+}
+
+#[test]
+fn test_cancel_verify_flash() {
+    use std::sync::{Arc, Mutex};
+    // 4 reads long
+    let image: Vec<u8> = (0..DEFAULT_MEMORY_BLOCK_LEN * 4).map(|x| x as u8).collect();
+    let memory = Arc::new(Mutex::new(vec![0xFF; 0x100 + image.len()]));
+    memory.lock().unwrap()[0x100..].copy_from_slice(&image);
+    memory.lock().unwrap()[0x101] = 0xEE;
+    let token = CancellationToken::new();
+    let token_t = token.clone();
+    let reads = Arc::new(Mutex::new(0usize));
+    let reads_t = reads.clone();
+    let responder = flash_mock_responder(memory, None);
+    let (_mock, ecu) = super::uds::start_mock_session(move |req| {
+        if req[0] == 0x23 {
+            let mut reads = reads_t.lock().unwrap();
+            *reads += 1;
+            if *reads == 2 {
+                token_t.cancel(); // User presses cancel while the 2nd read is in flight
+            }
+        }
+        responder(req)
+    });
+
+    match verify_flash(&ecu, &image, 0x0100, 2, 2, ROUTINE_CHECK_MEMORY, &token).unwrap() {
+        Cancellable::Cancelled { partial, completed } => {
+            assert_eq!(completed, 2);
+            assert_eq!(partial.mismatches, vec![1..2]);
+        },
+        x => panic!("Expected verify to be cancelled, got {:?}", x)
+    }
+    assert_eq!(*reads.lock().unwrap(), 2);
+}
@@ -0,0 +1,229 @@
+use crate::commapi::can_channel::CanChannel;
+use crate::commapi::comm_api::{CanFrame, CanIdFilter, ComServerError};
+use super::DTC;
+
+// Based on SAE J1939-21 (Data link layer) and J1939-71/73 (Application layer, diagnostics)
+
+/// Request PGN, used to ask a node to broadcast another PGN
+pub const PGN_REQUEST: u32 = 0xEA00;
+/// Electronic engine controller 1 (Contains engine speed)
+pub const PGN_EEC1: u32 = 0xF004;
+/// Cruise control / vehicle speed
+pub const PGN_CCVS: u32 = 0xFEF1;
+/// DM1 - Active diagnostic trouble codes
+pub const PGN_DM1: u32 = 0xFECA;
+/// Destination address that every node on the network accepts
+pub const GLOBAL_ADDRESS: u8 = 0xFF;
+
+/// Raw values at or above this are error / not available indicators (J1939-71)
+const MAX_VALID_U16: u16 = 0xFAFF;
+
+/// A 29 bit CAN ID split into its J1939 fields
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct J1939Id {
+    /// Priority, 0 is the highest
+    pub priority: u8,
+    /// Parameter group number
+    pub pgn: u32,
+    /// Address of the node that sent the frame
+    pub source: u8,
+    /// Address of the node the frame is for. PDU2 PGNs are always broadcast to [GLOBAL_ADDRESS]
+    pub destination: u8,
+}
+
+impl J1939Id {
+    pub fn new(priority: u8, pgn: u32, source: u8, destination: u8) -> Self {
+        Self { priority, pgn, source, destination }
+    }
+
+    /// Decodes a 29 bit CAN ID
+    pub fn from_can_id(id: u32) -> Self {
+        let priority = ((id >> 26) & 0x07) as u8;
+        let dp = (id >> 24) & 0x03; // Extended data page and data page
+        let pf = (id >> 16) & 0xFF;
+        let ps = ((id >> 8) & 0xFF) as u8;
+        let source = (id & 0xFF) as u8;
+        if pf < 0xF0 {
+            // PDU1 - PS is the destination address
+            Self { priority, pgn: dp << 16 | pf << 8, source, destination: ps }
+        } else {
+            // PDU2 - PS is part of the PGN (Group extension)
+            Self { priority, pgn: dp << 16 | pf << 8 | ps as u32, source, destination: GLOBAL_ADDRESS }
+        }
+    }
+
+    /// Encodes the fields back into a 29 bit CAN ID
+    pub fn to_can_id(&self) -> u32 {
+        let pf = (self.pgn >> 8) & 0xFF;
+        let ps = if pf < 0xF0 { self.destination as u32 } else { self.pgn & 0xFF };
+        (self.priority as u32 & 0x07) << 26 | (self.pgn & 0x3_0000) << 8 | pf << 16 | ps << 8 | self.source as u32
+    }
+}
+
+/// Creates a request frame (PGN 0xEA00), asking `destination` to send `pgn`
+pub fn request_pgn_frame(pgn: u32, source: u8, destination: u8) -> CanFrame {
+    let id = J1939Id::new(6, PGN_REQUEST, source, destination).to_can_id();
+    CanFrame::new(id, &[pgn as u8, (pgn >> 8) as u8, (pgn >> 16) as u8])
+}
+
+/// Decodes engine speed (SPN 190) from EEC1 data, in RPM
+pub fn decode_engine_speed(data: &[u8]) -> Option<f32> {
+    read_u16_spn(data, 3).map(|raw| raw as f32 * 0.125)
+}
+
+/// Decodes wheel based vehicle speed (SPN 84) from CCVS data, in km/h
+pub fn decode_vehicle_speed(data: &[u8]) -> Option<f32> {
+    read_u16_spn(data, 1).map(|raw| raw as f32 / 256.0)
+}
+
+fn read_u16_spn(data: &[u8], idx: usize) -> Option<u16> {
+    if data.len() < idx + 2 {
+        return None;
+    }
+    let raw = data[idx] as u16 | (data[idx + 1] as u16) << 8;
+    if raw > MAX_VALID_U16 { None } else { Some(raw) }
+}
+
+/// A single J1939 trouble code
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct J1939DTC {
+    /// Suspect parameter number
+    pub spn: u32,
+    /// Failure mode identifier
+    pub fmi: u8,
+    /// Number of times the fault has occurred
+    pub occurrences: u8,
+}
+
+/// Contents of a DM1 (Active DTCs) message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DM1 {
+    pub malfunction_lamp: bool,
+    pub red_stop_lamp: bool,
+    pub amber_warning_lamp: bool,
+    pub protect_lamp: bool,
+    pub dtcs: Vec<J1939DTC>,
+}
+
+impl DM1 {
+    /// Decodes DM1 data. Messages with more than one DTC are sent using the transport protocol,
+    /// so `data` should be the reassembled message
+    pub fn from_data(data: &[u8]) -> Option<Self> {
+        if data.len() < 6 {
+            return None;
+        }
+        let lamp_on = |shift: u8| (data[0] >> shift) & 0x03 == 0x01;
+        let mut dtcs = Vec::new();
+        for d in data[2..].chunks_exact(4) {
+            let spn = d[0] as u32 | (d[1] as u32) << 8 | ((d[2] as u32 & 0xE0) >> 5) << 16;
+            let fmi = d[2] & 0x1F;
+            // SPN 0 / FMI 0 means no active DTCs, 0xFF is padding
+            if (spn == 0 && fmi == 0) || d == [0xFF, 0xFF, 0xFF, 0xFF] {
+                continue;
+            }
+            dtcs.push(J1939DTC { spn, fmi, occurrences: d[3] & 0x7F })
+        }
+        Some(Self {
+            malfunction_lamp: lamp_on(6),
+            red_stop_lamp: lamp_on(4),
+            amber_warning_lamp: lamp_on(2),
+            protect_lamp: lamp_on(0),
+            dtcs,
+        })
+    }
+
+    /// Converts the active codes to [DTC]s, as shown by the rest of the application
+    pub fn get_dtcs(&self) -> Vec<DTC> {
+        self.dtcs.iter().map(|d| DTC {
+            error: format!("SPN {} FMI {}", d.spn, d.fmi),
+            present: true,
+            stored: true,
+            check_engine_on: self.malfunction_lamp,
+        }).collect()
+    }
+}
+
+/// A J1939 node on the network. The CAN channel must be opened with extended (29 bit) IDs
+#[derive(Debug)]
+pub struct J1939Node {
+    channel: CanChannel,
+    address: u8,
+}
+
+impl J1939Node {
+    /// # Params
+    /// * channel - CAN channel opened with 29 bit IDs
+    /// * address - Source address of this node
+    pub fn new(mut channel: CanChannel, address: u8) -> Result<Self, ComServerError> {
+        channel.set_filter(&[CanIdFilter::new(0, 0)])?;
+        Ok(Self { channel, address })
+    }
+
+    /// Requests a PGN from every node on the network, and waits for the first response
+    ///
+    /// # Returns
+    /// The response data, or None if no node responded within `timeout_ms`
+    pub fn request_pgn(&mut self, pgn: u32, timeout_ms: u32) -> Result<Option<Vec<u8>>, ComServerError> {
+        self.channel.send(&[request_pgn_frame(pgn, self.address, GLOBAL_ADDRESS)], 0)?;
+        let start = std::time::Instant::now();
+        while start.elapsed().as_millis() < timeout_ms as u128 {
+            for f in self.channel.recv(0, 10)? {
+                let id = J1939Id::from_can_id(f.id);
+                if id.pgn == pgn && (id.destination == GLOBAL_ADDRESS || id.destination == self.address) {
+                    return Ok(Some(Vec::from(f.get_data())))
+                }
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        Ok(None)
+    }
+}
+
+#[test]
+fn test_decode_id() {
+    let eec1 = J1939Id::from_can_id(0x0CF0_0400);
+    assert_eq!(eec1, J1939Id::new(3, PGN_EEC1, 0x00, GLOBAL_ADDRESS));
+    assert_eq!(eec1.to_can_id(), 0x0CF0_0400);
+
+    // PDU1, Request from 0xF9 to 0x00
+    let req = J1939Id::from_can_id(0x18EA_00F9);
+    assert_eq!(req, J1939Id::new(6, PGN_REQUEST, 0xF9, 0x00));
+    assert_eq!(req.to_can_id(), 0x18EA_00F9);
+
+    assert_eq!(decode_engine_speed(&[0xF0, 0x7D, 0x7D, 0x40, 0x1F, 0x00, 0xF0, 0x7D]), Some(1000.0));
+    assert_eq!(decode_engine_speed(&[0xFF; 8]), None);
+}
+
+#[test]
+fn test_decode_dm1() {
+    // MIL on, amber warning on, SPN 100 (Oil pressure) FMI 1 occurred 3 times
+    let dm1 = DM1::from_data(&[0x44, 0xFF, 0x64, 0x00, 0x01, 0x03, 0xFF, 0xFF]).unwrap();
+    assert!(dm1.malfunction_lamp);
+    assert!(dm1.amber_warning_lamp);
+    assert!(!dm1.red_stop_lamp);
+    assert_eq!(dm1.dtcs, vec![J1939DTC { spn: 100, fmi: 1, occurrences: 3 }]);
+    assert_eq!(dm1.get_dtcs()[0].error, "SPN 100 FMI 1");
+
+    // High bits of the SPN are stored in the FMI byte
+    let dm1 = DM1::from_data(&[0x00, 0x00, 0x3F, 0x0D, 0x6E, 0x01]).unwrap();
+    assert_eq!(dm1.dtcs[0].spn, 0x3_0D3F);
+    assert_eq!(dm1.dtcs[0].fmi, 0x0E);
+    assert!(DM1::from_data(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00]).unwrap().dtcs.is_empty());
+}
+
+#[test]
+fn test_request_pgn() {
+    let mut mock = crate::commapi::mock_api::MockComServer::new();
+    mock.set_responder(|f| {
+        if J1939Id::from_can_id(f.id).pgn == PGN_REQUEST && f.get_data() == [0xF1, 0xFE, 0x00] {
+            vec![CanFrame::new(0x18FE_F100, &[0x00, 0x00, 0x32, 0x00, 0x00, 0x00, 0x00, 0x00])]
+        } else {
+            vec![]
+        }
+    });
+    let mut node = J1939Node::new(CanChannel::new(Box::new(mock.clone())), 0xF9).unwrap();
+    let data = node.request_pgn(PGN_CCVS, 100).unwrap().unwrap();
+    assert_eq!(decode_vehicle_speed(&data), Some(50.0));
+    assert_eq!(mock.get_tx_log()[0].id, 0x18EA_FFF9);
+    assert_eq!(node.request_pgn(PGN_EEC1, 10).unwrap(), None);
+}
@@ -1,4 +1,4 @@
-use std::{sync::{Arc, atomic::AtomicBool}};
+use std::{sync::{Arc, Mutex, atomic::AtomicBool}, thread::JoinHandle};
 use std::sync::atomic::Ordering::Relaxed;
 use commapi::comm_api::{ComServer, ISO15765Config, ISO15765Data};
 
@@ -290,6 +290,7 @@ pub struct KWP2000ECU {
     iso_tp_settings: ISO15765Config,
     should_run: Arc<AtomicBool>,
     stop_tester_present: Arc<AtomicBool>,
+    tester_present_thread: Arc<Mutex<Option<JoinHandle<()>>>>,
 }
 
 #[derive(Debug, Clone)]
@@ -363,14 +364,28 @@ impl KWP2000ECU {
     }
 }
 
-impl ProtocolServer for KWP2000ECU {
-    type Command = Service;
+/// Ends the diagnostic session when the last clone of the ECU is dropped without
+/// [exit_diag_session](fn@ProtocolServer::exit_diag_session) having been called
+impl Drop for KWP2000ECU {
+    fn drop(&mut self) {
+        if Arc::strong_count(&self.tester_present_thread) == 1 && self.should_run.load(Relaxed) {
+            self.exit_diag_session()
+        }
+    }
+}
 
-    fn start_diag_session(mut comm_server: Box<dyn ComServer>, cfg: &ISO15765Config) -> ProtocolResult<Self> {
-        comm_server.open_iso15765_interface(500_000, false).map_err(ProtocolError::CommError)?;
+impl KWP2000ECU {
+    /// Same as [start_diag_session](fn@ProtocolServer::start_diag_session), but on a CAN bus
+    /// other than the 500kbps 11bit bus of the OBD-II port
+    ///
+    /// # Params
+    /// * bus_speed - Speed of the CAN bus in bps
+    /// * is_ext_can - True if the ECU uses 29bit CAN IDs
+    pub fn start_on_bus(mut comm_server: Box<dyn ComServer>, cfg: &ISO15765Config, bus_speed: u32, is_ext_can: bool) -> ProtocolResult<Self> {
+        comm_server.open_iso15765_interface(bus_speed, is_ext_can).map_err(ProtocolError::CommError)?;
         comm_server.add_iso15765_filter(
             cfg.recv_id,
-            0xFFF, 
+            if is_ext_can { 0x1FFF_FFFF } else { 0xFFF },
             cfg.send_id
         ).map_err(ProtocolError::CommError)?;
         comm_server.set_iso15765_params(cfg.sep_time, cfg.block_size).map_err(ProtocolError::CommError)?;
@@ -385,7 +400,7 @@ impl ProtocolServer for KWP2000ECU {
         let server_t = comm_server.clone();
         let ecu_id = cfg.send_id;
         // Enter extended diagnostic session (Full features)
-        std::thread::spawn(move || {
+        let handle = std::thread::spawn(move || {
             let mut last_send = std::time::Instant::now();
             println!("DIAG SERVER START");
             while should_run_t.load(Relaxed) {
@@ -413,11 +428,11 @@ impl ProtocolServer for KWP2000ECU {
             iso_tp_settings: *cfg,
             stop_tester_present: stop_send_tester_present,
             should_run,
-
+            tester_present_thread: Arc::new(Mutex::new(Some(handle))),
         };
         if let Err(e) = ecu.run_command(Service::StartDiagSession, &[0x92], 250) {
             eprintln!("Error sending tester present {:?}", e);
-            ecu.comm_server.close_iso15765_interface();
+            ecu.exit_diag_session();
             Err(e)
         } else {
             ecu.stop_tester_present.store(false, Relaxed);
@@ -425,9 +440,21 @@ impl ProtocolServer for KWP2000ECU {
         }
     
     }
+}
+
+impl ProtocolServer for KWP2000ECU {
+    type Command = Service;
+
+    fn start_diag_session(comm_server: Box<dyn ComServer>, cfg: &ISO15765Config) -> ProtocolResult<Self> {
+        Self::start_on_bus(comm_server, cfg, 500_000, false)
+    }
 
     fn exit_diag_session(&mut self) {
+        // Tester present thread uses the ISO-TP channel, so it must be stopped before closing it
         self.should_run.store(false, Relaxed);
+        if let Some(handle) = self.tester_present_thread.lock().unwrap().take() {
+            let _ = handle.join();
+        }
         self.comm_server.close_iso15765_interface();
     }
 
@@ -502,4 +529,8 @@ impl ProtocolServer for KWP2000ECU {
         }
         Ok(res)
     }
+
+    fn send_raw(&self, req: &[u8], max_timeout_ms: u128) -> ProtocolResult<Vec<u8>> {
+        super::send_raw_iso15765(self.comm_server.as_ref(), self.iso_tp_settings.send_id, req, max_timeout_ms, super::uds::DEFAULT_P2_STAR.as_millis())
+    }
 }
\ No newline at end of file
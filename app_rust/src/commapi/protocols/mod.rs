@@ -1,23 +1,95 @@
 use std::fmt::Display;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 
-use comm_api::ISO15765Config;
+use comm_api::{CanError, ComServerError, ISO15765Config, ISO15765Data};
 
 use super::comm_api::{self, ComServer};
 
 pub mod uds;
+pub mod flash;
+pub mod safe_session;
+pub mod adaptation;
+pub mod routine;
+pub mod rate_limit;
+pub mod trace;
 pub mod obd2;
 pub mod vin;
 pub mod kwp2000;
+pub mod j1939;
+pub mod auto_connect;
+pub mod dtc_history;
 
-#[derive(Debug)]
+#[derive(Debug, thiserror::Error)]
 pub enum ProtocolError {
+    #[error("Adapter error: {0}")]
     CommError(comm_api::ComServerError),
+    #[error("ECU rejected the request: {}", .0.get_text())]
     ProtocolError(Box<dyn CommandError>),
+    /// ECU responded, but the response could not be decoded
+    #[error("Invalid response from the ECU: {0}")]
+    InvalidResponse(String),
+    /// Request could not be built from the provided arguments, nothing was sent to the ECU
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+    /// CAN controller reported an error during the request, usually caused by
+    /// wiring, termination or baud rate problems
+    #[error("{0}")]
+    BusError(CanError),
+    #[error("ECU did not respond in time")]
     Timeout,
 }
 
+impl ProtocolError {
+    /// Converts an adapter error, keeping CAN bus errors separate from other adapter errors
+    pub(crate) fn from_comm(e: ComServerError) -> Self {
+        match e.can_error() {
+            Some(err) => ProtocolError::BusError(err),
+            None => ProtocolError::CommError(e)
+        }
+    }
+}
+
 type ProtocolResult<T> = std::result::Result<T, ProtocolError>;
 
+/// Shared flag used to stop a long running operation (Such as a scan) from another thread.
+///
+/// Operations check the token between requests to the ECU, so a request is never
+/// cut off midway through
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Requests that the operation using this token stops
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed)
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+/// Result of an operation which can be stopped with a [CancellationToken]
+#[derive(Debug, Clone, PartialEq)]
+pub enum Cancellable<T> {
+    /// Operation ran to completion
+    Complete(T),
+    /// Operation was cancelled
+    Cancelled {
+        /// Results gathered before the operation was cancelled
+        partial: T,
+        /// Number of steps which were completed
+        completed: usize,
+    },
+}
+
 pub trait Selectable {
     fn get_byte(&self) -> u8;
     fn get_desc(&self) -> String;
@@ -70,4 +142,72 @@ pub trait ProtocolServer : Clone {
     fn run_command(&self, cmd: Self::Command, args: &[u8], max_timeout_ms: u128) -> ProtocolResult<Vec<u8>>;
 
     fn read_errors(&self) -> ProtocolResult<Vec<DTC>>;
+
+    /// Sends a raw request payload (Including the SID), for ad-hoc requests typed in by the user.
+    ///
+    /// Unlike [run_command](fn@ProtocolServer::run_command), a negative response is returned
+    /// as the response rather than as an error, so it can be shown to the user
+    ///
+    /// # Returns
+    /// The full response payload, including the SID (0x7F for a negative response)
+    fn send_raw(&self, req: &[u8], max_timeout_ms: u128) -> ProtocolResult<Vec<u8>>;
+}
+
+/// Sends a raw request over ISO-TP and waits for the response to it. Response pending (0x78)
+/// responses are skipped, and extend the wait by P2*
+///
+/// # Params
+/// * p2_ms - Time to wait for the first response
+/// * p2_star_ms - Time to wait after each response pending
+pub(crate) fn send_raw_iso15765(server: &dyn ComServer, send_id: u32, req: &[u8], p2_ms: u128, p2_star_ms: u128) -> ProtocolResult<Vec<u8>> {
+    send_raw_iso15765_routed(server, send_id, None, req, p2_ms, p2_star_ms)
+}
+
+/// Same as [send_raw_iso15765], for a request which a gateway routes to the ECU with address
+/// `target`. The request is sent with the target address before it, and only responses which
+/// start with the target address are accepted. The address is removed from the response
+pub(crate) fn send_raw_iso15765_routed(server: &dyn ComServer, send_id: u32, target: Option<u8>, req: &[u8], p2_ms: u128, p2_star_ms: u128) -> ProtocolResult<Vec<u8>> {
+    if req.is_empty() {
+        return Err(ProtocolError::InvalidRequest("Request is empty".into()))
+    }
+    let payload = ISO15765Data { id: send_id, data: target.into_iter().chain(req.iter().copied()).collect(), pad_frame: false };
+    server.send_iso15765_data(&[payload], 0).map_err(ProtocolError::from_comm)?;
+    let start = std::time::Instant::now();
+    let mut timeout = p2_ms;
+    while start.elapsed().as_millis() < timeout {
+        if let Ok(msgs) = server.read_iso15765_packets(0, 1) {
+            for m in msgs {
+                let data = match unroute(m.data, target) {
+                    Some(data) if !data.is_empty() => data,
+                    _ => continue // First frame indication, or a response from another ECU
+                };
+                if data[0] == req[0].wrapping_add(0x40) {
+                    return Ok(data)
+                } else if data[0] == 0x7F && data.get(1) == Some(&req[0]) {
+                    if data.get(2) == Some(&0x78) {
+                        timeout = start.elapsed().as_millis() + p2_star_ms;
+                    } else {
+                        return Ok(data)
+                    }
+                }
+            }
+        }
+        std::thread::sleep(std::time::Duration::from_millis(1));
+    }
+    Err(ProtocolError::Timeout)
+}
+
+/// Removes the address a gateway put before a routed response
+///
+/// # Returns
+/// The response payload, or None if the response is not from `target`
+pub(crate) fn unroute(mut data: Vec<u8>, target: Option<u8>) -> Option<Vec<u8>> {
+    match target {
+        None => Some(data),
+        Some(t) if data.first() == Some(&t) => {
+            data.remove(0);
+            Some(data)
+        },
+        Some(_) => None
+    }
 }
\ No newline at end of file
@@ -2,6 +2,7 @@ use std::env::set_current_dir;
 
 use crate::commapi::comm_api::{ComServer, ComServerError, ISO15765Config, ISO15765Data};
 use crate::commapi::protocols::vin::Vin;
+use common::dtc::{decode_dtcs, DtcFormat};
 pub type Result<T> = std::result::Result<T, OBDProcessError>;
 
 fn read_write_payload_isotp(server: &mut Box<dyn ComServer>, payload: &OBDRequest) -> Result<Vec<u8>> {
@@ -437,10 +438,11 @@ pub enum FuelType {
 pub struct Service03;
 
 impl Service03 {
-    pub fn get_error_codes(server: &mut Box<dyn ComServer>, use_can: bool) -> Result<()> {
-        read_write_payload(server, use_can, &OBDRequest::new_nopid(0x03)).map(|mut res| {
-            println!("{:02X?}", res)
-        })
+    /// Reads the stored DTCs, as 3 byte numbers with no failure type (See [DtcFormat::decode])
+    pub fn get_error_codes(server: &mut Box<dyn ComServer>, use_can: bool) -> Result<Vec<u32>> {
+        let res = read_write_payload(server, use_can, &OBDRequest::new_nopid(0x03))?;
+        // [number of DTCs, (DTC (2 bytes))...]
+        decode_dtcs(res.data.get(1..).unwrap_or_default(), DtcFormat::TwoByte).ok_or_else(|| OBDProcessError::InvalidResponse(format!("Invalid DTC list {:02X?}", res.data)))
     }
 }
 
@@ -0,0 +1,201 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Debug;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Source of time for a [RateLimiter], so tests can run against a simulated clock
+pub trait Clock: Send + Sync + Debug {
+    /// Time since an arbitrary fixed point
+    fn now(&self) -> Duration;
+    fn sleep(&self, d: Duration);
+}
+
+#[derive(Debug)]
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self { start: Instant::now() }
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    fn sleep(&self, d: Duration) {
+        std::thread::sleep(d)
+    }
+}
+
+/// Limits on how quickly requests are sent to an ECU
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RateLimits {
+    /// Maximum number of requests sent to the ECU in any one second, across all services
+    pub max_per_second: Option<u32>,
+    /// Minimum time between 2 requests of the same service, keyed by SID
+    pub min_interval: HashMap<u8, Duration>,
+}
+
+impl RateLimits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn max_per_second(mut self, max: u32) -> Self {
+        self.max_per_second = Some(max);
+        self
+    }
+
+    pub fn min_interval(mut self, sid: u8, interval: Duration) -> Self {
+        self.min_interval.insert(sid, interval);
+        self
+    }
+}
+
+#[derive(Debug, Default)]
+struct LimiterState {
+    /// When each service was last sent to each ECU
+    last_sent: HashMap<(u32, u8), Duration>,
+    /// Send times within the last second for each ECU
+    recent: HashMap<u32, VecDeque<Duration>>,
+}
+
+/// Spaces out requests to ECUs which lock up or log tamper events (Airbag, immobilizer...)
+/// when polled too quickly. Polling loops call [acquire](fn@RateLimiter::acquire) before each
+/// request, which sleeps until the request is within the limits.
+///
+/// Each caller is given the next free slot before it sleeps, so several threads sharing a
+/// limiter are spaced out between them rather than all waking up at once
+#[derive(Debug)]
+pub struct RateLimiter {
+    default: RateLimits,
+    /// Limits which replace the default limits for an ECU, keyed by the ECU's address
+    per_ecu: HashMap<u32, RateLimits>,
+    clock: Box<dyn Clock>,
+    state: Mutex<LimiterState>,
+}
+
+impl RateLimiter {
+    /// Creates a limiter which applies the same limits to every ECU
+    pub fn new(default: RateLimits) -> Self {
+        Self::with_clock(default, Box::new(SystemClock::default()))
+    }
+
+    pub fn with_clock(default: RateLimits, clock: Box<dyn Clock>) -> Self {
+        Self { default, per_ecu: HashMap::new(), clock, state: Mutex::new(LimiterState::default()) }
+    }
+
+    /// Uses different limits for one ECU, instead of the default limits
+    pub fn set_ecu_limits(&mut self, ecu: u32, limits: RateLimits) {
+        self.per_ecu.insert(ecu, limits);
+    }
+
+    pub fn limits_for(&self, ecu: u32) -> &RateLimits {
+        self.per_ecu.get(&ecu).unwrap_or(&self.default)
+    }
+
+    /// Waits until a request can be sent, and records it as sent
+    ///
+    /// # Params
+    /// * ecu - Address the request is sent to
+    /// * sid - Service of the request
+    ///
+    /// # Returns
+    /// How long the caller was made to wait
+    pub fn acquire(&self, ecu: u32, sid: u8) -> Duration {
+        let limits = self.limits_for(ecu);
+        let now = self.clock.now();
+        let slot = {
+            let mut state = self.state.lock().unwrap();
+            let mut slot = now;
+            if let (Some(interval), Some(last)) = (limits.min_interval.get(&sid), state.last_sent.get(&(ecu, sid))) {
+                slot = slot.max(*last + *interval);
+            }
+            let recent = state.recent.entry(ecu).or_default();
+            while recent.front().map(|t| *t + Duration::from_secs(1) <= now).unwrap_or(false) {
+                recent.pop_front();
+            }
+            if let Some(max) = limits.max_per_second {
+                let max = max.max(1) as usize;
+                if recent.len() >= max {
+                    // The request that is `max` requests back has to be over a second old
+                    slot = slot.max(recent[recent.len() - max] + Duration::from_secs(1));
+                }
+            }
+            recent.push_back(slot);
+            state.last_sent.insert((ecu, sid), slot);
+            slot
+        };
+        let wait = slot - now;
+        if wait > Duration::from_secs(0) {
+            self.clock.sleep(wait)
+        }
+        wait
+    }
+}
+
+/// Clock which only moves when slept on
+#[cfg(test)]
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SimClock {
+    now_us: std::sync::Arc<std::sync::atomic::AtomicU64>,
+}
+
+#[cfg(test)]
+impl Clock for SimClock {
+    fn now(&self) -> Duration {
+        Duration::from_micros(self.now_us.load(std::sync::atomic::Ordering::SeqCst))
+    }
+
+    fn sleep(&self, d: Duration) {
+        self.now_us.fetch_add(d.as_micros() as u64, std::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn test_min_interval_per_service() {
+    let clock = SimClock::default();
+    let mut limiter = RateLimiter::with_clock(RateLimits::new().min_interval(0x22, Duration::from_millis(200)), Box::new(clock.clone()));
+    limiter.set_ecu_limits(0x07E1, RateLimits::new());
+
+    assert_eq!(limiter.acquire(0x07E0, 0x22), Duration::from_millis(0));
+    assert_eq!(limiter.acquire(0x07E0, 0x22), Duration::from_millis(200));
+    // Other services and ECUs are not held back
+    assert_eq!(limiter.acquire(0x07E0, 0x3E), Duration::from_millis(0));
+    assert_eq!(limiter.acquire(0x07E1, 0x22), Duration::from_millis(0));
+    assert_eq!(limiter.acquire(0x07E1, 0x22), Duration::from_millis(0));
+    assert_eq!(clock.now(), Duration::from_millis(200));
+}
+
+#[test]
+fn test_rate_limited_polling() {
+    use std::sync::Arc;
+    use super::uds::start_mock_session;
+
+    let clock = SimClock::default();
+    let (_mock, mut ecu) = start_mock_session(|req| match req {
+        [0x22, a, b] => Some(vec![0x62, *a, *b, 0x01]),
+        _ => None
+    });
+    ecu.set_rate_limiter(Some(Arc::new(RateLimiter::with_clock(RateLimits::new().max_per_second(10), Box::new(clock.clone())))));
+
+    // Poll as fast as possible for a simulated second
+    let mut sent = Vec::new();
+    while clock.now() < Duration::from_secs(1) {
+        ecu.read_data_by_id(0xF190).unwrap();
+        sent.push(clock.now());
+    }
+    assert_eq!(sent.iter().filter(|t| **t < Duration::from_secs(1)).count(), 10);
+    // No window of a second ever has more than 10 requests
+    for _ in 0..15 {
+        ecu.read_data_by_id(0xF190).unwrap();
+        sent.push(clock.now());
+    }
+    for w in sent.windows(11) {
+        assert!(w[10] - w[0] >= Duration::from_secs(1), "{:?}", w);
+    }
+}
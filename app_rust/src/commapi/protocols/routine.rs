@@ -0,0 +1,241 @@
+use common::schema::{RoutineDef, SchemaV1};
+use super::{CommandError, ProtocolError, ProtocolResult, ProtocolServer};
+use super::uds::UDSNegativeCode;
+
+/// Time to wait for the ECU to respond to a RoutineControl request
+const ROUTINE_TIMEOUT_MS: u128 = 2000;
+
+/// Sub-function of a RoutineControl (0x31) request
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum RoutineControlType {
+    Start = 0x01,
+    Stop = 0x02,
+    RequestResults = 0x03,
+}
+
+/// Sends a RoutineControl request
+///
+/// # Params
+/// * id - Routine identifier
+/// * params - Routine control option record, sent after the routine ID
+///
+/// # Returns
+/// The routine status record, excluding the sub-function and routine ID echoed back by the ECU
+pub fn routine_control<P: ProtocolServer>(server: &P, control: RoutineControlType, id: u16, params: &[u8]) -> ProtocolResult<Vec<u8>> {
+    let mut req = vec![0x31, control as u8, (id >> 8) as u8, id as u8];
+    req.extend_from_slice(params);
+    let res = server.send_raw(&req, ROUTINE_TIMEOUT_MS)?;
+    match res.as_slice() {
+        [0x7F, 0x31, nrc, ..] => Err(ProtocolError::ProtocolError(Box::new(<UDSNegativeCode as CommandError>::from_byte(*nrc)))),
+        [0x71, echo @ ..] if echo.len() >= 3 && echo[0..3] == req[1..4] => Ok(echo[3..].to_vec()),
+        _ => Err(ProtocolError::InvalidResponse(format!("Invalid routine control response {:02X?}", res)))
+    }
+}
+
+/// Parses the values the user entered for a routine's parameters into its option record.
+/// Values are decimal, or hex with a `0x` prefix. An empty value uses the parameter's default
+pub fn encode_routine_params(def: &RoutineDef, values: &[String]) -> ProtocolResult<Vec<u8>> {
+    let mut res = Vec::new();
+    for (i, p) in def.params.iter().enumerate() {
+        if !(1..=4).contains(&p.len) {
+            return Err(ProtocolError::InvalidRequest(format!("{} has an invalid size of {} bytes", p.name, p.len)))
+        }
+        let input = values.get(i).map(|v| v.trim()).unwrap_or("");
+        let value = if input.is_empty() {
+            p.default as u64
+        } else {
+            let parsed = match input.strip_prefix("0x").or_else(|| input.strip_prefix("0X")) {
+                Some(hex) => u64::from_str_radix(hex, 16),
+                None => input.parse::<u64>()
+            };
+            parsed.map_err(|_| ProtocolError::InvalidRequest(format!("'{}' is not a valid value for {}", input, p.name)))?
+        };
+        if value >> (p.len as u32 * 8) != 0 {
+            return Err(ProtocolError::InvalidRequest(format!("{} does not fit in {} ({} bytes)", value, p.name, p.len)))
+        }
+        res.extend_from_slice(&value.to_be_bytes()[8 - p.len as usize..]);
+    }
+    Ok(res)
+}
+
+/// Result of asking for a routine to be started
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StartOutcome {
+    /// Routine is running. Holds the routine status record
+    Started(Vec<u8>),
+    /// Routine is flagged as dangerous, and nothing is sent until [confirm](fn@ActuatorTests::confirm) is called
+    NeedsConfirmation,
+}
+
+/// Runs the actuator tests and routines listed in an ECU definition.
+///
+/// Only one routine is kept running at a time. Starting another routine stops the running one
+/// first, and [close](fn@ActuatorTests::close) stops whatever is still running, so a fan or pump
+/// is never left running once the user is no longer looking at it
+#[derive(Debug, Clone, Default)]
+pub struct ActuatorTests {
+    tests: Vec<RoutineDef>,
+    /// Dangerous test waiting for the user to confirm, and its option record
+    pending: Option<(usize, Vec<u8>)>,
+    running: Option<usize>,
+}
+
+impl ActuatorTests {
+    pub fn new(tests: Vec<RoutineDef>) -> Self {
+        Self { tests, pending: None, running: None }
+    }
+
+    pub fn from_definition(model: &SchemaV1) -> Self {
+        Self::new(model.routine_defs().to_vec())
+    }
+
+    pub fn tests(&self) -> &[RoutineDef] {
+        &self.tests
+    }
+
+    /// Returns the test which is waiting for the user to confirm it
+    pub fn pending_confirmation(&self) -> Option<&RoutineDef> {
+        self.pending.as_ref().map(|(idx, _)| &self.tests[*idx])
+    }
+
+    pub fn running(&self) -> Option<&RoutineDef> {
+        self.running.map(|idx| &self.tests[idx])
+    }
+
+    /// Starts a test. Dangerous tests are only started once confirmed
+    ///
+    /// # Params
+    /// * idx - Index of the test in [tests](fn@ActuatorTests::tests)
+    /// * params - Routine control option record. See [encode_routine_params]
+    pub fn start<P: ProtocolServer>(&mut self, server: &P, idx: usize, params: Vec<u8>) -> ProtocolResult<StartOutcome> {
+        let def = self.tests.get(idx).ok_or_else(|| ProtocolError::InvalidRequest(format!("No actuator test {}", idx)))?;
+        if def.dangerous {
+            self.pending = Some((idx, params));
+            return Ok(StartOutcome::NeedsConfirmation)
+        }
+        self.pending = None;
+        self.start_now(server, idx, &params)
+    }
+
+    /// Starts the test waiting for confirmation
+    pub fn confirm<P: ProtocolServer>(&mut self, server: &P) -> ProtocolResult<StartOutcome> {
+        let (idx, params) = self.pending.take().ok_or_else(|| ProtocolError::InvalidRequest("No actuator test is waiting to be confirmed".into()))?;
+        self.start_now(server, idx, &params)
+    }
+
+    /// Drops the test waiting for confirmation, without sending anything
+    pub fn cancel(&mut self) {
+        self.pending = None;
+    }
+
+    fn start_now<P: ProtocolServer>(&mut self, server: &P, idx: usize, params: &[u8]) -> ProtocolResult<StartOutcome> {
+        self.stop(server)?;
+        let status = routine_control(server, RoutineControlType::Start, self.tests[idx].id, params)?;
+        self.running = Some(idx);
+        Ok(StartOutcome::Started(status))
+    }
+
+    /// Reads the results of the running test
+    pub fn results<P: ProtocolServer>(&self, server: &P) -> ProtocolResult<Vec<u8>> {
+        let def = self.running().ok_or_else(|| ProtocolError::InvalidRequest("No actuator test is running".into()))?;
+        routine_control(server, RoutineControlType::RequestResults, def.id, &[])
+    }
+
+    /// Stops the running test, if there is one. If the ECU rejects the request the test
+    /// is still treated as running, so stopping it can be retried
+    pub fn stop<P: ProtocolServer>(&mut self, server: &P) -> ProtocolResult<()> {
+        if let Some(idx) = self.running {
+            routine_control(server, RoutineControlType::Stop, self.tests[idx].id, &[])?;
+            self.running = None;
+        }
+        Ok(())
+    }
+
+    /// Called when the tests are no longer shown. Stops the running test and drops any test waiting for confirmation
+    pub fn close<P: ProtocolServer>(&mut self, server: &P) -> ProtocolResult<()> {
+        self.pending = None;
+        self.stop(server)
+    }
+}
+
+#[test]
+fn test_actuator_lifecycle() {
+    use std::sync::{Arc, Mutex};
+    use super::uds::start_mock_session;
+
+    let model = SchemaV1::from_json(r#"{
+        "meta": { "name": "ME97", "vendor": "Bosch", "desc": "Engine controller" },
+        "err_table": [],
+        "comm_data": [],
+        "routines": [
+            { "id": 4097, "name": "Idle speed check" },
+            { "id": 4098, "name": "Fuel pump actuation", "dangerous": true,
+              "params": [{ "name": "Duration", "len": 2, "unit": "s", "default": 10 }] }
+        ]
+    }"#).unwrap();
+    let sent = Arc::new(Mutex::new(Vec::new()));
+    let sent_t = sent.clone();
+    let (_mock, ecu) = start_mock_session(move |req| {
+        sent_t.lock().unwrap().push(req.to_vec());
+        match req {
+            [0x31, sub, 0x10, id, ..] => Some(vec![0x71, *sub, 0x10, *id, 0x00]),
+            _ => None
+        }
+    });
+    let mut tests = ActuatorTests::from_definition(&model);
+    assert_eq!(tests.tests().len(), 2);
+
+    assert_eq!(tests.start(&ecu, 0, vec![]).unwrap(), StartOutcome::Started(vec![0x00]));
+    assert_eq!(tests.running().unwrap().name, "Idle speed check");
+
+    // Dangerous test is not sent until confirmed
+    let params = encode_routine_params(&tests.tests()[1], &["".into()]).unwrap();
+    assert_eq!(params, vec![0x00, 0x0A]);
+    assert_eq!(tests.start(&ecu, 1, params).unwrap(), StartOutcome::NeedsConfirmation);
+    assert_eq!(tests.pending_confirmation().unwrap().id, 0x1002);
+    assert_eq!(sent.lock().unwrap().len(), 1);
+    tests.cancel();
+    assert!(tests.confirm(&ecu).is_err());
+    assert_eq!(sent.lock().unwrap().len(), 1);
+
+    // Confirming stops the running test before starting the next one
+    tests.start(&ecu, 1, encode_routine_params(&tests.tests()[1], &["0x1E".into()]).unwrap()).unwrap();
+    assert_eq!(tests.confirm(&ecu).unwrap(), StartOutcome::Started(vec![0x00]));
+    assert_eq!(tests.running().unwrap().id, 0x1002);
+    assert_eq!(tests.results(&ecu).unwrap(), vec![0x00]);
+
+    // Closing the panel stops the test
+    tests.close(&ecu).unwrap();
+    assert!(tests.running().is_none());
+    tests.close(&ecu).unwrap();
+    assert_eq!(*sent.lock().unwrap(), vec![
+        vec![0x31, 0x01, 0x10, 0x01],
+        vec![0x31, 0x02, 0x10, 0x01],
+        vec![0x31, 0x01, 0x10, 0x02, 0x00, 0x1E],
+        vec![0x31, 0x03, 0x10, 0x02],
+        vec![0x31, 0x02, 0x10, 0x02],
+    ]);
+}
+
+#[test]
+fn test_actuator_stop_rejected() {
+    use super::uds::start_mock_session;
+
+    let def = RoutineDef { id: 0x0203, name: "Cooling fan".into(), params: vec![], dangerous: false };
+    let (_mock, ecu) = start_mock_session(|req| match req {
+        [0x31, 0x01, 0x02, 0x03] => Some(vec![0x71, 0x01, 0x02, 0x03]),
+        [0x31, 0x02, ..] => Some(vec![0x7F, 0x31, 0x22]),
+        _ => None
+    });
+    let mut tests = ActuatorTests::new(vec![def.clone()]);
+    assert_eq!(tests.start(&ecu, 0, vec![]).unwrap(), StartOutcome::Started(vec![]));
+    assert!(matches!(tests.close(&ecu), Err(ProtocolError::ProtocolError(_))));
+    // Still running, so the stop can be retried
+    assert_eq!(tests.running(), Some(&def));
+
+    let p = common::schema::RoutineParam { name: "Speed".into(), len: 1, unit: "%".into(), default: 0 };
+    let def = RoutineDef { params: vec![p], ..def };
+    assert_eq!(encode_routine_params(&def, &["255".into()]).unwrap(), vec![0xFF]);
+    assert!(encode_routine_params(&def, &["256".into()]).is_err());
+    assert!(encode_routine_params(&def, &["fast".into()]).is_err());
+}
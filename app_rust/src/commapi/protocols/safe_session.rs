@@ -0,0 +1,153 @@
+use super::{CommandError, ProtocolError, ProtocolResult, ProtocolServer};
+use super::uds::UDSNegativeCode;
+
+/// Time to wait for the ECU to respond to each cleanup request
+const CLEANUP_TIMEOUT_MS: u128 = 1000;
+
+/// Request sent by [SafeSession] to return the ECU to a safe state
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CleanupStep {
+    /// CommunicationControl - Enable Rx and Tx of normal communication messages
+    EnableCommunication,
+    /// ControlDTCSetting - DTC setting on
+    EnableDtcSetting,
+    /// DiagnosticSessionControl - Default session
+    DefaultSession,
+}
+
+impl CleanupStep {
+    /// Every step, in the order they are run
+    pub const ALL: [CleanupStep; 3] = [CleanupStep::EnableCommunication, CleanupStep::EnableDtcSetting, CleanupStep::DefaultSession];
+
+    pub fn request(&self) -> &'static [u8] {
+        match self {
+            CleanupStep::EnableCommunication => &[0x28, 0x00, 0x01],
+            CleanupStep::EnableDtcSetting => &[0x85, 0x01],
+            CleanupStep::DefaultSession => &[0x10, 0x01],
+        }
+    }
+}
+
+impl std::fmt::Display for CleanupStep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CleanupStep::EnableCommunication => write!(f, "Enable communication"),
+            CleanupStep::EnableDtcSetting => write!(f, "Enable DTC setting"),
+            CleanupStep::DefaultSession => write!(f, "Return to default session"),
+        }
+    }
+}
+
+/// Guard for a sequence which leaves the ECU in an unsafe state whilst it runs, such as
+/// flashing with communication and DTC setting disabled in the programming session.
+///
+/// When the guard is dropped, or [abort](fn@SafeSession::abort) is called, communication
+/// and DTC setting are re-enabled and the ECU is returned to the default session. This is
+/// best effort, every step is tried even if an earlier one fails, and failures are logged.
+/// If the sequence has already left the ECU in a safe state (Such as by resetting it),
+/// call [dismiss](fn@SafeSession::dismiss) so the guard does nothing
+pub struct SafeSession<'a, P: ProtocolServer> {
+    server: &'a P,
+    armed: bool,
+}
+
+impl<'a, P: ProtocolServer> SafeSession<'a, P> {
+    pub fn new(server: &'a P) -> Self {
+        Self { server, armed: true }
+    }
+
+    /// Server the guard cleans up, for running the guarded sequence
+    pub fn server(&self) -> &P {
+        self.server
+    }
+
+    /// Runs the cleanup sequence now
+    ///
+    /// # Returns
+    /// Each step which failed, and why
+    pub fn abort(mut self) -> Vec<(CleanupStep, ProtocolError)> {
+        self.armed = false;
+        cleanup(self.server)
+    }
+
+    /// Disarms the guard without sending anything to the ECU
+    pub fn dismiss(mut self) {
+        self.armed = false;
+    }
+}
+
+impl<'a, P: ProtocolServer> Drop for SafeSession<'a, P> {
+    fn drop(&mut self) {
+        if self.armed {
+            cleanup(self.server);
+        }
+    }
+}
+
+/// Runs every [CleanupStep], logging the ones which fail
+fn cleanup<P: ProtocolServer>(server: &P) -> Vec<(CleanupStep, ProtocolError)> {
+    let mut failures = Vec::new();
+    for step in CleanupStep::ALL.iter() {
+        let req = step.request();
+        let res = server.send_raw(req, CLEANUP_TIMEOUT_MS).and_then(|resp| match resp.as_slice() {
+            [0x7F, _, nrc, ..] => Err(ProtocolError::ProtocolError(Box::new(<UDSNegativeCode as CommandError>::from_byte(*nrc)))),
+            [sid, ..] if *sid == req[0] + 0x40 => Ok(()),
+            _ => Err(ProtocolError::InvalidResponse(format!("Invalid response {:02X?}", resp)))
+        });
+        if let Err(e) = res {
+            eprintln!("Cannot return ECU to a safe state. {} failed: {}", step, e);
+            failures.push((*step, e));
+        }
+    }
+    failures
+}
+
+#[cfg(test)]
+fn start_recording_session(reject: Option<u8>) -> (crate::commapi::mock_api::MockComServer, super::uds::UDSECU, std::sync::Arc<std::sync::Mutex<Vec<Vec<u8>>>>) {
+    use std::sync::{Arc, Mutex};
+    let sent = Arc::new(Mutex::new(Vec::new()));
+    let sent_t = sent.clone();
+    let (mock, ecu) = super::uds::start_mock_session(move |req| {
+        if req[0] == 0x3E {
+            return None
+        }
+        sent_t.lock().unwrap().push(req.to_vec());
+        match req {
+            [sid, ..] if Some(*sid) == reject => Some(vec![0x7F, *sid, 0x22]),
+            [sid, sub, ..] => Some(vec![sid + 0x40, *sub]),
+            _ => None
+        }
+    });
+    (mock, ecu, sent)
+}
+
+#[test]
+fn test_cleanup_on_drop() {
+    let (_mock, ecu, sent) = start_recording_session(None);
+    {
+        let guard = SafeSession::new(&ecu);
+        guard.server().send_raw(&[0x10, 0x02], 1000).unwrap();
+        guard.server().send_raw(&[0x85, 0x02], 1000).unwrap();
+        guard.server().send_raw(&[0x28, 0x03, 0x01], 1000).unwrap();
+        // Sequence fails here, and the guard goes out of scope
+    }
+    assert_eq!(*sent.lock().unwrap(), vec![
+        vec![0x10, 0x02], vec![0x85, 0x02], vec![0x28, 0x03, 0x01],
+        vec![0x28, 0x00, 0x01], vec![0x85, 0x01], vec![0x10, 0x01],
+    ]);
+
+    sent.lock().unwrap().clear();
+    SafeSession::new(&ecu).dismiss();
+    assert!(sent.lock().unwrap().is_empty());
+}
+
+#[test]
+fn test_abort_is_best_effort() {
+    let (_mock, ecu, sent) = start_recording_session(Some(0x85));
+    let failures = SafeSession::new(&ecu).abort();
+    assert_eq!(failures.len(), 1);
+    assert_eq!(failures[0].0, CleanupStep::EnableDtcSetting);
+    assert!(matches!(failures[0].1, ProtocolError::ProtocolError(_)));
+    // Later steps still run, and nothing is sent again when the guard is dropped by abort
+    assert_eq!(*sent.lock().unwrap(), vec![vec![0x28, 0x00, 0x01], vec![0x85, 0x01], vec![0x10, 0x01]]);
+}
@@ -0,0 +1,195 @@
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use common::hex::format_hex;
+
+/// Services whose payloads are redacted unless the caller changes the list.
+/// SecurityAccess (0x27) carries seeds and keys
+pub const DEFAULT_REDACTED_SERVICES: [u8; 1] = [0x27];
+
+/// Number of exchanges kept before the oldest are dropped
+const MAX_ENTRIES: usize = 10_000;
+
+/// What came back for a traced request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraceResponse {
+    /// Positive response payload, including the SID. Only the SID is kept if the service is redacted
+    Positive(Vec<u8>),
+    /// Negative response. Never redacted, as it only holds the NRC
+    Negative { sid: u8, nrc: u8 },
+    /// Request was sent without waiting for a response
+    NotRead,
+    /// No response, or the request could not be sent
+    Error(String),
+}
+
+/// A single request / response exchange
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEntry {
+    /// Time since the trace was created that the request was sent
+    pub at: Duration,
+    /// Time taken for the ECU to respond
+    pub duration: Duration,
+    /// Request payload, including the SID. Only the SID is kept if the service is redacted
+    pub request: Vec<u8>,
+    pub response: TraceResponse,
+    /// Payloads were masked
+    pub redacted: bool,
+}
+
+fn format_payload(payload: &[u8], redacted: bool) -> String {
+    match payload {
+        [] => "(Empty)".into(),
+        [sid, ..] if redacted => format!("{:02X} **", sid),
+        _ => format_hex(payload, " ")
+    }
+}
+
+impl std::fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let resp = match &self.response {
+            TraceResponse::Positive(p) => format_payload(p, self.redacted),
+            TraceResponse::Negative { sid, nrc } => format!("7F {:02X} {:02X}", sid, nrc),
+            TraceResponse::NotRead => "(Not read)".into(),
+            TraceResponse::Error(e) => e.clone(),
+        };
+        write!(f, "{:>10.3}s {} -> {} ({} ms)", self.at.as_secs_f32(), format_payload(&self.request, self.redacted), resp, self.duration.as_millis())
+    }
+}
+
+#[derive(Debug)]
+struct TraceState {
+    redacted: BTreeSet<u8>,
+    entries: Vec<TraceEntry>,
+}
+
+/// Log of every request sent to an ECU and its response, for debugging sessions.
+///
+/// Payloads of sensitive services are masked with `**` before they are stored, so they
+/// never end up in a shared log. The SID, NRC and timing of every exchange are always kept.
+/// Clones share the same log
+#[derive(Debug, Clone)]
+pub struct ServiceTrace {
+    start: Instant,
+    state: Arc<Mutex<TraceState>>,
+}
+
+impl Default for ServiceTrace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ServiceTrace {
+    /// Creates an empty trace, redacting [DEFAULT_REDACTED_SERVICES]
+    pub fn new() -> Self {
+        Self::with_redacted(&DEFAULT_REDACTED_SERVICES)
+    }
+
+    /// Creates an empty trace, redacting only the services listed
+    pub fn with_redacted(services: &[u8]) -> Self {
+        Self {
+            start: Instant::now(),
+            state: Arc::new(Mutex::new(TraceState {
+                redacted: services.iter().copied().collect(),
+                entries: Vec::new(),
+            }))
+        }
+    }
+
+    /// Marks a service as sensitive or not. Only applies to exchanges recorded afterwards
+    pub fn set_redacted(&self, sid: u8, redacted: bool) {
+        let mut state = self.state.lock().unwrap();
+        if redacted {
+            state.redacted.insert(sid);
+        } else {
+            state.redacted.remove(&sid);
+        }
+    }
+
+    /// Returns the services which are redacted, in ascending order
+    pub fn redacted_services(&self) -> Vec<u8> {
+        self.state.lock().unwrap().redacted.iter().copied().collect()
+    }
+
+    /// Records an exchange
+    ///
+    /// # Params
+    /// * req - Request payload, including the SID
+    /// * resp - Full response payload (Empty if the response was not waited for), or why there was no response
+    /// * duration - Time from sending the request until the response arrived
+    pub fn record(&self, req: &[u8], resp: Result<&[u8], String>, duration: Duration) {
+        let at = self.start.elapsed().checked_sub(duration).unwrap_or_default();
+        let mut state = self.state.lock().unwrap();
+        let redacted = req.first().map(|sid| state.redacted.contains(sid)).unwrap_or(false);
+        let keep = |p: &[u8]| if redacted { p.iter().take(1).copied().collect::<Vec<u8>>() } else { p.to_vec() };
+        let response = match resp {
+            Ok([]) => TraceResponse::NotRead,
+            Ok([0x7F, sid, nrc, ..]) => TraceResponse::Negative { sid: *sid, nrc: *nrc },
+            Ok(p) => TraceResponse::Positive(keep(p)),
+            Err(e) => TraceResponse::Error(e),
+        };
+        state.entries.push(TraceEntry { at, duration, request: keep(req), response, redacted });
+        if state.entries.len() > MAX_ENTRIES {
+            state.entries.remove(0);
+        }
+    }
+
+    pub fn entries(&self) -> Vec<TraceEntry> {
+        self.state.lock().unwrap().entries.clone()
+    }
+
+    /// Returns each exchange formatted for display. Example: `     1.250s 22 F1 90 -> 62 F1 90 57 (12 ms)`
+    pub fn lines(&self) -> Vec<String> {
+        self.state.lock().unwrap().entries.iter().map(|e| e.to_string()).collect()
+    }
+
+    pub fn clear(&self) {
+        self.state.lock().unwrap().entries.clear()
+    }
+}
+
+#[test]
+fn test_trace_redaction() {
+    use super::ProtocolServer;
+    use super::uds::{start_mock_session, UDSCommand};
+
+    let (_mock, mut ecu) = start_mock_session(|req| match req {
+        [0x27, 0x01] => Some(vec![0x67, 0x01, 0x12, 0x34]),
+        [0x27, 0x02, 0xED, 0xCB] => Some(vec![0x67, 0x02]),
+        [0x27, 0x02, ..] => Some(vec![0x7F, 0x27, 0x35]),
+        [0x22, 0xF1, 0x90] => Some(vec![0x62, 0xF1, 0x90, 0x57, 0x44]),
+        _ => None
+    });
+    let trace = ServiceTrace::new();
+    assert_eq!(trace.redacted_services(), vec![0x27]);
+    ecu.set_trace(Some(trace.clone()));
+
+    ecu.run_command(UDSCommand::SecurityAccess, &[0x01], 500).unwrap();
+    ecu.run_command(UDSCommand::SecurityAccess, &[0x02, 0xED, 0xCB], 500).unwrap();
+    assert!(ecu.run_command(UDSCommand::SecurityAccess, &[0x02, 0x00, 0x00], 500).is_err());
+    assert_eq!(ecu.read_data_by_id(0xF190).unwrap(), vec![0x57, 0x44]);
+
+    let lines = trace.lines();
+    assert_eq!(lines.len(), 4);
+    assert!(lines[0].contains("27 ** -> 67 ** ("), "{}", lines[0]);
+    assert!(lines[1].contains("27 ** -> 67 ** ("), "{}", lines[1]);
+    // NRC is still shown
+    assert!(lines[2].contains("27 ** -> 7F 27 35 ("), "{}", lines[2]);
+    assert!(lines[3].contains("22 F1 90 -> 62 F1 90 57 44 ("), "{}", lines[3]);
+    assert!(lines.iter().all(|l| l.ends_with(" ms)")));
+    // Seed and key bytes are not stored at all
+    for e in trace.entries().iter().take(3) {
+        assert!(e.redacted);
+        assert_eq!(e.request, vec![0x27]);
+    }
+
+    trace.set_redacted(0x22, true);
+    trace.set_redacted(0x27, false);
+    trace.clear();
+    ecu.read_data_by_id(0xF190).unwrap();
+    ecu.run_command(UDSCommand::SecurityAccess, &[0x01], 500).unwrap();
+    let lines = trace.lines();
+    assert!(lines[0].contains("22 ** -> 62 ** ("), "{}", lines[0]);
+    assert!(lines[1].contains("27 01 -> 67 01 12 34 ("), "{}", lines[1]);
+}
@@ -1,5 +1,18 @@
-
-use crate::commapi::comm_api::{ComServer, ISO15765Config, ComServerError, ISO15765Data};
+use std::sync::{Arc, Mutex, atomic::{AtomicBool, AtomicU32}};
+use std::thread::JoinHandle;
+use std::ops::RangeInclusive;
+use std::time::Duration;
+use std::sync::atomic::Ordering::Relaxed;
+use crate::commapi::comm_api::{CanError, ComServer, ISO15765Config, ComServerError, ISO15765Data};
+use crate::commapi::connection::{ConnectionEvent, ConnectionObserver, ConnectionObservers, SessionType};
+use common::dtc::{DtcFormat, ExtDataKind, ExtDataRecordDef};
+use common::hex::format_hex;
+use common::decoder::DecoderRegistry;
+use common::measurement::{DidDef, FormattedValue, ScaledValue};
+use common::schema::SchemaV1;
+use super::rate_limit::RateLimiter;
+use super::trace::ServiceTrace;
+use super::{send_raw_iso15765_routed, unroute, Cancellable, CancellationToken, CautionLevel, CommandError, CommandLevel, DTC, ProtocolError, ProtocolResult, ProtocolServer, Selectable};
 
 pub type Result<T> = std::result::Result<T, UDSProcessError>;
 
@@ -200,7 +213,7 @@ pub enum UDSCommand {
     /// This service is used to initiate a file download from the client to the server or upload
     /// from the server to the client. Additionally information about the file system are available
     /// by this service.
-    RequestFileTransfer = 0x3F,
+    RequestFileTransfer = 0x38,
 
     /// Enable or disable the detection of any or all errors. This is important when
     /// diagnostic work is performed in the car, which can cause an anomalous behavior of
@@ -608,4 +621,2222 @@ impl UDSResponse {
             Ok(UDSResponse::PositiveResponse(cmd, args))
         }
     }
-}
\ No newline at end of file
+}
+impl Selectable for UDSCommand {
+    fn get_byte(&self) -> u8 {
+        *self as u8
+    }
+
+    fn get_desc(&self) -> String {
+        match self {
+            UDSCommand::DiagnosticSessionControl => "Diagnostic session control",
+            UDSCommand::ECUReset => "Reset ECU",
+            UDSCommand::ClearDTCInformation => "Clear diagnostic information",
+            UDSCommand::ReadDTCInformation => "Read diagnostic trouble codes",
+            UDSCommand::ReadDataByID => "Read data by ID",
+            UDSCommand::ReadMemoryByAddress => "Read memory by address",
+            UDSCommand::ReadScalingDataById => "Read scaling data by ID",
+            UDSCommand::SecurityAccess => "Security access",
+            UDSCommand::CommunicationControl => "Communication control",
+            UDSCommand::Authentication => "Authentication",
+            UDSCommand::ReadDataByPeriodicID => "Read data by periodic ID",
+            UDSCommand::DynamicDefineDataId => "Dynamically define data ID",
+            UDSCommand::WriteDataByID => "Write data by ID",
+            UDSCommand::IOCTLById => "IOCTL by ID",
+            UDSCommand::RoutineControl => "Routine control",
+            UDSCommand::RequestDownload => "Request download",
+            UDSCommand::RequestUpload => "Request upload",
+            UDSCommand::TransferData => "Transfer data",
+            UDSCommand::TransferExit => "Request transfer exit",
+            UDSCommand::WriteMemoryByAddress => "Write memory by address",
+            UDSCommand::TesterPresent => "Tester present",
+            UDSCommand::RequestFileTransfer => "Request file transfer",
+            UDSCommand::ControlDTCSetting => "Control DTC Settings",
+            UDSCommand::LinkControl => "Link control",
+        }.into()
+    }
+}
+
+impl CommandLevel for UDSCommand {
+    fn get_caution_level(&self) -> CautionLevel {
+        match self {
+            UDSCommand::DiagnosticSessionControl => CautionLevel::None,
+            UDSCommand::ECUReset => CautionLevel::Warn,
+            UDSCommand::ClearDTCInformation => CautionLevel::None,
+            UDSCommand::ReadDTCInformation => CautionLevel::None,
+            UDSCommand::ReadDataByID => CautionLevel::None,
+            UDSCommand::ReadMemoryByAddress => CautionLevel::Alert,
+            UDSCommand::ReadScalingDataById => CautionLevel::None,
+            UDSCommand::SecurityAccess => CautionLevel::Warn,
+            UDSCommand::CommunicationControl => CautionLevel::Alert,
+            UDSCommand::Authentication => CautionLevel::Warn,
+            UDSCommand::ReadDataByPeriodicID => CautionLevel::None,
+            UDSCommand::DynamicDefineDataId => CautionLevel::Alert,
+            UDSCommand::WriteDataByID => CautionLevel::Alert,
+            UDSCommand::IOCTLById => CautionLevel::Alert,
+            UDSCommand::RoutineControl => CautionLevel::Alert,
+            UDSCommand::RequestDownload => CautionLevel::Alert,
+            UDSCommand::RequestUpload => CautionLevel::Alert,
+            UDSCommand::TransferData => CautionLevel::Alert,
+            UDSCommand::TransferExit => CautionLevel::Alert,
+            UDSCommand::WriteMemoryByAddress => CautionLevel::Alert,
+            UDSCommand::TesterPresent => CautionLevel::None,
+            UDSCommand::RequestFileTransfer => CautionLevel::Alert,
+            UDSCommand::ControlDTCSetting => CautionLevel::Warn,
+            UDSCommand::LinkControl => CautionLevel::Alert,
+        }
+    }
+}
+
+impl CommandError for UDSNegativeCode {
+    fn get_text(&self) -> String {
+        match self {
+            UDSNegativeCode::ISOReserved => "ISO Reserved",
+            UDSNegativeCode::GeneralReject => "General reject",
+            UDSNegativeCode::ServiceNotSupported => "Service is not supported",
+            UDSNegativeCode::SubFunctionNotSupported => "Sub function not supported",
+            UDSNegativeCode::IncorrectMessageLength => "Incorrect message length or invalid format",
+            UDSNegativeCode::ResponseTooLong => "Response too long",
+            UDSNegativeCode::BusyRepeatRequest => "ECU is busy, repeat the request",
+            UDSNegativeCode::ConditionsNotCorrect => "Conditions are not correct",
+            UDSNegativeCode::RequestSequenceError => "Request sequence error",
+            UDSNegativeCode::RequestOutOfRange => "The request is out of range",
+            UDSNegativeCode::SecurityAccessDenied => "Security access denied",
+            UDSNegativeCode::InvalidKey => "Invalid security key",
+            UDSNegativeCode::ExceedNumberOfAttempts => "Exceeded number of security access attempts",
+            UDSNegativeCode::RequiredTimeDelayNotExpired => "The required time delay has not yet expired",
+            UDSNegativeCode::ExtendedDataLinkSecurity => "Extended data link security error",
+            UDSNegativeCode::UploadDownloadNotAccepted => "Upload / download not accepted",
+            UDSNegativeCode::TransferDataSuspended => "Data transfer suspended",
+            UDSNegativeCode::GeneralProgrammingFailure => "General programming failure",
+            UDSNegativeCode::WrongBlockSequenceCounter => "Wrong block sequence counter",
+            UDSNegativeCode::ResponsePending => "Response pending...",
+            UDSNegativeCode::SubFunctionNotSupportedActiveSession => "Sub function not supported in the active session",
+            UDSNegativeCode::ServiceNotSupportedActiveSession => "Service not supported in the active session",
+            UDSNegativeCode::RpmTooHigh => "Engine RPM too high",
+            UDSNegativeCode::RpmTooLow => "Engine RPM too low",
+            UDSNegativeCode::EngineIsRunning => "Engine is running",
+            UDSNegativeCode::EngineIsNotRunning => "Engine is not running",
+            UDSNegativeCode::EngineRunTimeTooLow => "Engine run time too low",
+            UDSNegativeCode::TempTooHigh => "Temperature too high",
+            UDSNegativeCode::TempTooLow => "Temperature too low",
+            UDSNegativeCode::SpeedTooHigh => "Vehicle speed too high",
+            UDSNegativeCode::SpeedTooLow => "Vehicle speed too low",
+            UDSNegativeCode::ThrottleTooHigh => "Throttle / pedal too high",
+            UDSNegativeCode::ThrottleTooLow => "Throttle / pedal too low",
+            UDSNegativeCode::TransmissionNotInNeutral => "Transmission not in neutral",
+            UDSNegativeCode::TransmissionNotInGear => "Transmission not in gear",
+            UDSNegativeCode::BrakeNotApplied => "Brake not applied",
+            UDSNegativeCode::ShifterNotInPark => "Shifter lever not in park",
+            UDSNegativeCode::TorqueConverterClutchLocked => "Torque converter clutch locked",
+            UDSNegativeCode::VoltageTooHigh => "Voltage too high",
+            UDSNegativeCode::VoltageTooLow => "Voltage too low",
+            UDSNegativeCode::ReservedSpecificConditionsIncorrect => "Reserved for specific conditions not correct",
+        }.into()
+    }
+
+    fn get_help(&self) -> Option<String> {
+        match self {
+            UDSNegativeCode::ServiceNotSupported => Some("This service is not supported by the ECU".into()),
+            UDSNegativeCode::SubFunctionNotSupported => Some("The arguments provided in the command may not be correct".into()),
+            UDSNegativeCode::BusyRepeatRequest => Some("The ECU is currently performing another operation, please wait".into()),
+            UDSNegativeCode::RequestSequenceError => Some("The ECU requires something to be ran prior to running this command".into()),
+            UDSNegativeCode::SecurityAccessDenied => Some("In order to execute this function, you need to obtain a higher security clearance.".into()),
+            UDSNegativeCode::ServiceNotSupportedActiveSession => Some("This function is not supported in the current diagnostic session. Try to switch diagnostic sessions".into()),
+            _ => None
+        }
+    }
+
+    fn from_byte(b: u8) -> Self {
+        UDSNegativeCode::from_byte(&b).unwrap_or(UDSNegativeCode::ISOReserved)
+    }
+}
+
+// DTC status bits, as reported by service 0x19
+pub const DTC_STATUS_TEST_FAILED: u8 = 0b0000_0001;
+pub const DTC_STATUS_TEST_FAILED_THIS_CYCLE: u8 = 0b0000_0010;
+pub const DTC_STATUS_PENDING: u8 = 0b0000_0100;
+pub const DTC_STATUS_CONFIRMED: u8 = 0b0000_1000;
+pub const DTC_STATUS_NOT_COMPLETED_SINCE_CLEAR: u8 = 0b0001_0000;
+pub const DTC_STATUS_FAILED_SINCE_CLEAR: u8 = 0b0010_0000;
+pub const DTC_STATUS_NOT_COMPLETED_THIS_CYCLE: u8 = 0b0100_0000;
+pub const DTC_STATUS_WARNING_INDICATOR: u8 = 0b1000_0000;
+
+/// Decodes the response of ReadDTCInformation sub function 0x01 (Number of DTCs by status mask)
+///
+/// # Params
+/// * resp - Response from the ECU, excluding the SID byte
+///
+/// # Returns
+/// The status availability mask and number of DTCs matching the requested mask
+fn parse_dtc_count(resp: &[u8]) -> ProtocolResult<(u8, u16)> {
+    // [sub function, availability mask, DTC format ID, count (2 bytes)]
+    if resp.len() < 5 || resp[0] != 0x01 {
+        return Err(ProtocolError::InvalidResponse(format!("Invalid DTC count response {:02X?}", resp)))
+    }
+    Ok((resp[1], (resp[3] as u16) << 8 | resp[4] as u16))
+}
+
+/// Converts a DTC status byte into a [DTC], ignoring any status bits which the ECU
+/// does not support according to its status availability mask
+fn dtc_from_status(dtc: u32, status: u8, availability: u8) -> DTC {
+    let status = status & availability;
+    DTC {
+        error: format!("{:06X}", dtc),
+        present: status & DTC_STATUS_TEST_FAILED != 0,
+        stored: status & (DTC_STATUS_CONFIRMED | DTC_STATUS_PENDING) != 0,
+        check_engine_on: status & DTC_STATUS_WARNING_INDICATOR != 0,
+    }
+}
+
+/// Decodes the response of ReadDTCInformation sub function 0x02 (DTCs by status mask)
+///
+/// # Params
+/// * resp - Response from the ECU, excluding the SID byte
+fn parse_dtc_list(resp: &[u8]) -> ProtocolResult<Vec<DTC>> {
+    // [sub function, availability mask, (DTC (3 bytes), status)...]
+    if resp.len() < 2 || resp[0] != 0x02 || (resp.len() - 2) % 4 != 0 {
+        return Err(ProtocolError::InvalidResponse(format!("Invalid DTC list response {:02X?}", resp)))
+    }
+    let availability = resp[1];
+    Ok(resp[2..].chunks(4).map(|r| {
+        let dtc = DtcFormat::ThreeByte.decode(&r[..3]).unwrap_or_default();
+        dtc_from_status(dtc, r[3], availability)
+    }).collect())
+}
+
+/// DTCs to clear with [clear_dtcs](fn@UDSECU::clear_dtcs)
+///
+/// ISO 14229 only defines [All](DtcGroup::All) and [Emissions](DtcGroup::Emissions) as groups.
+/// The system groups are manufacturer specific, an ECU which does not use them rejects them with
+/// requestOutOfRange (0x31), or treats them as the single DTC with the same number
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DtcGroup {
+    All,
+    /// Emissions-related DTCs
+    Emissions,
+    /// P codes (Manufacturer specific)
+    Powertrain,
+    /// C codes (Manufacturer specific)
+    Chassis,
+    /// B codes (Manufacturer specific)
+    Body,
+    /// U codes (Manufacturer specific)
+    Network,
+    /// One DTC, by its 3 byte number
+    Single(u32),
+}
+
+impl DtcGroup {
+    /// Returns the 3 byte groupOfDTC sent to the ECU. System groups are the first DTC of the
+    /// system, as the top 2 bits of a DTC select its system (Same numbering as KWP2000 groups)
+    pub fn mask(&self) -> u32 {
+        match self {
+            DtcGroup::All => 0xFFFFFF,
+            DtcGroup::Emissions => 0xFFFF00 | DTC_FUNCTIONAL_GROUP_EMISSIONS as u32,
+            DtcGroup::Powertrain => 0x000000,
+            DtcGroup::Chassis => 0x400000,
+            DtcGroup::Body => 0x800000,
+            DtcGroup::Network => 0xC00000,
+            DtcGroup::Single(dtc) => dtc & 0xFFFFFF,
+        }
+    }
+}
+
+/// Functional group [read_dtcs_with_severity](fn@UDSECU::read_dtcs_with_severity) reads the DTCs of
+pub const DTC_FUNCTIONAL_GROUP_EMISSIONS: u8 = 0x33;
+
+/// DTC severity bits, in the upper 3 bits of the severity byte
+pub const DTC_SEVERITY_MAINTENANCE_ONLY: u8 = 0b0010_0000;
+pub const DTC_SEVERITY_CHECK_AT_NEXT_HALT: u8 = 0b0100_0000;
+pub const DTC_SEVERITY_CHECK_IMMEDIATELY: u8 = 0b1000_0000;
+
+/// How urgently a DTC needs attention. Ordered from least to most urgent
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DtcSeverity {
+    /// ECU did not set a severity
+    NotClassified,
+    /// Only needs fixing at the next service
+    MaintenanceOnly,
+    /// Needs checking when the vehicle is next stopped
+    CheckAtNextHalt,
+    /// Stop driving
+    CheckImmediately,
+}
+
+impl DtcSeverity {
+    /// Decodes the severity bits of a severity byte. If several bits are set, the most urgent one is used
+    pub fn from_byte(severity: u8) -> Self {
+        if severity & DTC_SEVERITY_CHECK_IMMEDIATELY != 0 {
+            DtcSeverity::CheckImmediately
+        } else if severity & DTC_SEVERITY_CHECK_AT_NEXT_HALT != 0 {
+            DtcSeverity::CheckAtNextHalt
+        } else if severity & DTC_SEVERITY_MAINTENANCE_ONLY != 0 {
+            DtcSeverity::MaintenanceOnly
+        } else {
+            DtcSeverity::NotClassified
+        }
+    }
+}
+
+/// GTR (WWH-OBD) class of a DTC, from the lower 5 bits of the severity byte
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DtcClass {
+    Class0,
+    ClassA,
+    ClassB1,
+    ClassB2,
+    ClassC,
+}
+
+impl DtcClass {
+    pub fn from_byte(severity: u8) -> Option<Self> {
+        match severity & 0x1F {
+            x if x & 0x01 != 0 => Some(DtcClass::Class0),
+            x if x & 0x02 != 0 => Some(DtcClass::ClassA),
+            x if x & 0x04 != 0 => Some(DtcClass::ClassB1),
+            x if x & 0x08 != 0 => Some(DtcClass::ClassB2),
+            x if x & 0x10 != 0 => Some(DtcClass::ClassC),
+            _ => None
+        }
+    }
+}
+
+/// A DTC read with its severity information
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SeverityDtc {
+    /// 3 byte DTC number
+    pub dtc: u32,
+    /// Status byte, with the bits the ECU does not support cleared
+    pub status: u8,
+    pub severity: DtcSeverity,
+    pub class: Option<DtcClass>,
+    /// Functional group (Group of ECU functions, such as emissions) the ECU reported the DTC for
+    pub functional_group: u8,
+}
+
+/// Decodes the response of ReadDTCInformation sub function 0x42 (WWH-OBD DTCs by mask record)
+///
+/// # Params
+/// * resp - Response from the ECU, excluding the SID byte
+fn parse_severity_dtc_list(resp: &[u8]) -> ProtocolResult<Vec<SeverityDtc>> {
+    // [sub function, functional group, status availability mask, severity availability mask,
+    //  DTC format ID, (severity, DTC (3 bytes), status)...]
+    if resp.len() < 5 || resp[0] != 0x42 || (resp.len() - 5) % 5 != 0 {
+        return Err(ProtocolError::InvalidResponse(format!("Invalid DTC severity response {:02X?}", resp)))
+    }
+    let (functional_group, status_availability, severity_availability) = (resp[1], resp[2], resp[3]);
+    Ok(resp[5..].chunks(5).map(|r| {
+        let severity = r[0] & severity_availability;
+        SeverityDtc {
+            dtc: (r[1] as u32) << 16 | (r[2] as u32) << 8 | r[3] as u32,
+            status: r[4] & status_availability,
+            severity: DtcSeverity::from_byte(severity),
+            class: DtcClass::from_byte(severity),
+            functional_group,
+        }
+    }).collect())
+}
+
+/// Requests every extended data record stored for a DTC
+pub const DTC_EXT_DATA_ALL_RECORDS: u8 = 0xFF;
+
+/// Decoded value of a DTC extended data record
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ExtDataValue {
+    OccurrenceCount(u32),
+    AgingCounter(u32),
+}
+
+/// A DTC extended data record read from the ECU
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtDataRecord {
+    pub record_number: u8,
+    pub data: Vec<u8>,
+    /// Name of the record, if the definition declares it
+    pub name: Option<String>,
+    /// Decoded value, if the definition declares the record as a known type
+    pub value: Option<ExtDataValue>,
+}
+
+/// Decodes the response of ReadDTCInformation sub function 0x06 (DTC extended data records)
+///
+/// Record lengths are ECU specific, so each record is split using the length in `defs`. A record
+/// which is not in `defs` is assumed to take up the rest of the response.
+///
+/// # Params
+/// * resp - Response from the ECU, excluding the SID byte
+/// * dtc - DTC that was requested
+/// * defs - Record layouts from the definition (Can be empty)
+fn parse_dtc_ext_data(resp: &[u8], dtc: u32, defs: &[ExtDataRecordDef]) -> ProtocolResult<Vec<ExtDataRecord>> {
+    // [sub function, DTC (3 bytes), status, (record number, data...)...]
+    if resp.len() < 5 || resp[0] != 0x06 || resp[1..4] != [(dtc >> 16) as u8, (dtc >> 8) as u8, dtc as u8] {
+        return Err(ProtocolError::InvalidResponse(format!("Invalid DTC extended data response {:02X?}", resp)))
+    }
+    let mut records = Vec::new();
+    let mut pos = 5;
+    while pos < resp.len() {
+        let record_number = resp[pos];
+        let def = defs.iter().find(|d| d.record_number == record_number);
+        let len = def.map(|d| d.len).unwrap_or(resp.len() - pos - 1);
+        if pos + 1 + len > resp.len() {
+            return Err(ProtocolError::InvalidResponse(format!("DTC extended data record 0x{:02X} is truncated", record_number)))
+        }
+        let data = Vec::from(&resp[pos + 1..pos + 1 + len]);
+        let counter = data.iter().take(4).fold(0u32, |acc, b| acc << 8 | *b as u32);
+        let value = match def.map(|d| d.kind) {
+            Some(ExtDataKind::OccurrenceCounter) => Some(ExtDataValue::OccurrenceCount(counter)),
+            Some(ExtDataKind::AgingCounter) => Some(ExtDataValue::AgingCounter(counter)),
+            _ => None
+        };
+        records.push(ExtDataRecord { record_number, data, name: def.map(|d| d.name.clone()), value });
+        pos += 1 + len;
+    }
+    Ok(records)
+}
+
+/// Standard identification data read from a UDS ECU.
+/// Any field the ECU does not support is left as None
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ECUIdentification {
+    /// 0xF187 - Spare part number
+    pub part_number: Option<String>,
+    /// 0xF18C - ECU serial number
+    pub serial_number: Option<String>,
+    /// 0xF190 - VIN
+    pub vin: Option<String>,
+    /// 0xF191 - ECU hardware number
+    pub hw_number: Option<String>,
+    /// 0xF194 - ECU software number
+    pub sw_number: Option<String>,
+}
+
+/// Decodes an identification DID. Most ECUs store these as ASCII (Padded with spaces,
+/// 0x00 or 0xFF), but some store numbers as BCD, which is decoded as a hex string instead
+fn decode_ident_string(data: &[u8]) -> String {
+    let trimmed: &[u8] = {
+        let end = data.iter().rposition(|b| !matches!(b, 0x00 | 0x20 | 0xFF)).map(|x| x + 1).unwrap_or(0);
+        &data[0..end]
+    };
+    if trimmed.iter().all(|b| b.is_ascii_graphic() || *b == b' ') {
+        String::from_utf8_lossy(trimmed).trim().into()
+    } else {
+        format_hex(trimmed, "")
+    }
+}
+
+/// Reads the standard identification DIDs using the provided function
+///
+/// # Params
+/// * read_did - Function which reads a DID from the ECU, returning its data (Without the DID echo)
+fn read_identification_with<F: FnMut(u16) -> ProtocolResult<Vec<u8>>>(mut read_did: F) -> ProtocolResult<ECUIdentification> {
+    let mut read_opt = |did: u16| -> ProtocolResult<Option<String>> {
+        match read_did(did) {
+            Ok(data) => Ok(Some(decode_ident_string(&data))),
+            Err(ProtocolError::ProtocolError(_)) => Ok(None), // Negative response, ECU doesn't support the DID
+            Err(e) => Err(e)
+        }
+    };
+    Ok(ECUIdentification {
+        part_number: read_opt(0xF187)?,
+        serial_number: read_opt(0xF18C)?,
+        vin: read_opt(0xF190)?,
+        hw_number: read_opt(0xF191)?,
+        sw_number: read_opt(0xF194)?,
+    })
+}
+
+/// What is done with received messages still buffered when a new request is sent. Without
+/// a purge, a late response to an earlier request (Or a response to another tester) can be
+/// taken as the response to the new request
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RxPurge {
+    /// Buffered messages are left, and skipped only if they are not a response to the request
+    Off,
+    /// The adapter's ISO-TP receive buffer is cleared
+    ClearBuffer,
+    /// Buffered messages are read and discarded, for adapters which do not support clearing
+    /// the receive buffer. CAN errors seen whilst draining are kept for the request
+    Drain,
+}
+
+impl Default for RxPurge {
+    fn default() -> Self {
+        RxPurge::ClearBuffer
+    }
+}
+
+/// Route to an ECU on a sub-bus which the tester cannot reach directly, through a gateway
+/// on the diagnostic bus. See [set_route](fn@UDSECU::set_route)
+///
+/// Routing uses extended addressing (ISO15765-2): Requests are sent to the gateway's CAN ID,
+/// with the address of the target ECU as the first byte of the payload. The gateway forwards
+/// the request to the target, and responds with the target's address as the first byte of the
+/// response. Only physical routing to a single ECU is supported, functional requests which
+/// several ECUs behind the gateway respond to are not
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct GatewayRoute {
+    /// CAN ID the gateway receives requests on
+    pub gateway_tx: u32,
+    /// CAN ID the gateway responds with
+    pub gateway_rx: u32,
+    /// True if the gateway's IDs are 29bit
+    pub extended: bool,
+    /// Address of the ECU on the sub-bus
+    pub target: u8,
+}
+
+/// How a [transaction](fn@UDSECU::transaction) is retried when it fails
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Number of times the transaction is run before giving up, including the first. 1 disables retrying
+    pub max_attempts: u32,
+    /// Delay before each retry
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            backoff: Duration::from_millis(100),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Returns true if a transaction which failed with this error may succeed when run again.
+    /// Negative responses and invalid requests are not retried, as they would only be rejected again,
+    /// and neither is a lost adapter, which has to be reconnected first
+    pub fn is_retryable(e: &ProtocolError) -> bool {
+        match e {
+            ProtocolError::Timeout | ProtocolError::BusError(_) | ProtocolError::InvalidResponse(_) => true,
+            ProtocolError::CommError(e) => !e.is_device_lost(),
+            ProtocolError::ProtocolError(_) | ProtocolError::InvalidRequest(_) => false,
+        }
+    }
+}
+
+/// How an ECU responded to a probe of one of its services. See [probe_services](fn@UDSECU::probe_services)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServiceProbeResult {
+    /// ECU responded positively, or rejected the probe for a reason other than not having the service
+    /// (Example: Incorrect message length, sub-function not supported or security access denied)
+    Supported,
+    /// ECU responded with serviceNotSupported (0x11)
+    NotSupported,
+    /// ECU responded with serviceNotSupportedInActiveSession (0x7F). The service may be supported
+    /// in another session
+    NotSupportedInSession,
+    /// ECU responded with conditionsNotCorrect (0x22), so it could not tell if the service is supported
+    ConditionsNotCorrect,
+    /// ECU did not respond to the probe
+    NoResponse,
+    /// Probe failed for another reason, such as an adapter error
+    Failed(String),
+}
+
+impl ServiceProbeResult {
+    fn from_response(sid: u8, res: ProtocolResult<Vec<u8>>) -> Self {
+        match res.as_deref() {
+            Ok([0x7F, _, 0x11, ..]) => Self::NotSupported,
+            Ok([0x7F, _, 0x7F, ..]) => Self::NotSupportedInSession,
+            Ok([0x7F, _, 0x22, ..]) => Self::ConditionsNotCorrect,
+            Ok([0x7F, _, _, ..]) => Self::Supported,
+            Ok([b, ..]) if *b == sid.wrapping_add(0x40) => Self::Supported,
+            Ok(r) => Self::Failed(format!("Invalid response {:02X?}", r)),
+            Err(ProtocolError::Timeout) => Self::NoResponse,
+            Err(e) => Self::Failed(e.to_string()),
+        }
+    }
+}
+
+/// Requests sent by default to find out if an ECU supports each service. Only services which
+/// read from the ECU are probed, with complete requests which read as little as possible, or
+/// without their parameters, which a supporting ECU rejects with incorrectMessageLength (0x13),
+/// and an ECU without the service rejects with serviceNotSupported (0x11)
+const SERVICE_PROBES: &[(UDSCommand, &[u8])] = &[
+    (UDSCommand::ReadDTCInformation, &[0x01, 0xFF]), // Number of DTCs matching any status
+    (UDSCommand::ReadDataByID, &[0xF1, 0x90]), // VIN
+    (UDSCommand::ReadMemoryByAddress, &[]),
+    (UDSCommand::ReadScalingDataById, &[]),
+    (UDSCommand::TesterPresent, &[0x00]),
+];
+
+/// Requests for services which change the ECU's state, only sent when asked for. Each is a request
+/// a supporting ECU can only reject. Services with a sub-function are sent the reserved sub-function
+/// 0x7F, which is rejected with subFunctionNotSupported (0x12), and services without one are sent
+/// without their parameters.
+///
+/// TransferExit is never probed, as the bare request is a complete TransferExit
+const STATE_CHANGING_PROBES: &[(UDSCommand, &[u8])] = &[
+    (UDSCommand::DiagnosticSessionControl, &[0x7F]),
+    (UDSCommand::ECUReset, &[0x7F]),
+    (UDSCommand::ClearDTCInformation, &[]),
+    (UDSCommand::SecurityAccess, &[0x7F]),
+    (UDSCommand::CommunicationControl, &[0x7F]),
+    (UDSCommand::Authentication, &[0x7F]),
+    (UDSCommand::ReadDataByPeriodicID, &[]),
+    (UDSCommand::DynamicDefineDataId, &[0x7F]),
+    (UDSCommand::WriteDataByID, &[]),
+    (UDSCommand::IOCTLById, &[]),
+    (UDSCommand::RoutineControl, &[0x7F]),
+    (UDSCommand::RequestDownload, &[]),
+    (UDSCommand::RequestUpload, &[]),
+    (UDSCommand::TransferData, &[]),
+    (UDSCommand::RequestFileTransfer, &[]),
+    (UDSCommand::WriteMemoryByAddress, &[]),
+    (UDSCommand::ControlDTCSetting, &[0x7F]),
+    (UDSCommand::LinkControl, &[0x7F]),
+];
+
+/// Round trip times of requests to an ECU, for finding out if the adapter is slowing down
+/// communication. See [measure_latency](fn@UDSECU::measure_latency)
+///
+/// If no request got a response, every time is 0
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LatencyReport {
+    /// Round trip time of each request which got a response, in the order they were sent
+    pub samples: Vec<Duration>,
+    /// Number of requests which got no response
+    pub failures: usize,
+    pub min: Duration,
+    pub median: Duration,
+    /// 95th percentile
+    pub p95: Duration,
+    pub max: Duration,
+    /// Mean difference between the round trip times of consecutive requests
+    pub jitter: Duration,
+}
+
+impl LatencyReport {
+    pub fn from_samples(samples: Vec<Duration>, failures: usize) -> Self {
+        let mut sorted = samples.clone();
+        sorted.sort();
+        // Nearest rank
+        let percentile = |p: usize| match sorted.len() {
+            0 => Duration::default(),
+            n => sorted[((n * p + 99) / 100).max(1) - 1],
+        };
+        let jitter = match samples.len() {
+            0 | 1 => Duration::default(),
+            n => {
+                let total: Duration = samples.windows(2).map(|w| if w[1] > w[0] { w[1] - w[0] } else { w[0] - w[1] }).sum();
+                total / (n - 1) as u32
+            }
+        };
+        Self {
+            min: sorted.first().cloned().unwrap_or_default(),
+            median: percentile(50),
+            p95: percentile(95),
+            max: sorted.last().cloned().unwrap_or_default(),
+            jitter,
+            samples,
+            failures,
+        }
+    }
+}
+
+impl std::fmt::Display for LatencyReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        write!(f, "{} requests ({} failed): min {:.1}ms, median {:.1}ms, p95 {:.1}ms, max {:.1}ms, jitter {:.1}ms",
+            self.samples.len() + self.failures, self.failures, ms(self.min), ms(self.median), ms(self.p95), ms(self.max), ms(self.jitter))
+    }
+}
+
+/// Default largest block of memory sent in a single WriteMemoryByAddress request
+pub const DEFAULT_MEMORY_BLOCK_LEN: usize = 0x100;
+
+/// Largest ISO-TP payload using the 12 bit first frame length
+const ISO_TP_MAX_PAYLOAD: usize = 0x0FFF;
+
+/// Time to wait for a response after the ECU reports response pending (0x78),
+/// if the ECU has not told us its P2* and it has not been overridden
+pub const DEFAULT_P2_STAR: Duration = Duration::from_millis(5000);
+
+/// P2 (Time to the first response) and P2* (Time to respond after a response pending) timings
+#[derive(Debug, Copy, Clone, Default)]
+struct SessionTiming {
+    /// Reported by the ECU in its last DiagnosticSessionControl response
+    learned: Option<(Duration, Duration)>,
+    /// Set with [set_timing](fn@UDSECU::set_timing)
+    overridden: Option<(Duration, Duration)>,
+    ignore_ecu_timing: bool,
+}
+
+impl SessionTiming {
+    /// Reads P2 and P2* from a DiagnosticSessionControl positive response (Excluding the SID)
+    fn learn(&mut self, resp: &[u8]) {
+        if resp.len() >= 5 {
+            let p2 = (resp[1] as u64) << 8 | resp[2] as u64;
+            // P2* is in units of 10ms
+            let p2_star = ((resp[3] as u64) << 8 | resp[4] as u64) * 10;
+            self.learned = Some((Duration::from_millis(p2), Duration::from_millis(p2_star)));
+        }
+    }
+
+    /// Returns the time in ms to wait for the first response, and to wait after each response pending
+    ///
+    /// # Params
+    /// * timeout_ms - Timeout the request was sent with
+    fn timeouts_ms(&self, timeout_ms: u128) -> (u128, u128) {
+        let learned = self.learned.filter(|_| !self.ignore_ecu_timing);
+        match (self.overridden, learned) {
+            (Some((p2, p2_star)), _) => (p2.as_millis(), p2_star.as_millis()),
+            (None, Some((p2, p2_star))) => (std::cmp::max(timeout_ms, p2.as_millis()), p2_star.as_millis()),
+            (None, None) => (timeout_ms, DEFAULT_P2_STAR.as_millis())
+        }
+    }
+}
+
+/// Builds the addressAndLengthFormatIdentifier, address and size parameters of a memory request
+///
+/// # Params
+/// * address - Start address
+/// * len - Number of bytes
+/// * addr_bytes - Number of bytes to encode the address with (1-8)
+/// * size_bytes - Number of bytes to encode the size with (1-4)
+pub(crate) fn encode_memory_address(address: u64, len: usize, addr_bytes: u8, size_bytes: u8) -> ProtocolResult<Vec<u8>> {
+    if !(1..=8).contains(&addr_bytes) || !(1..=4).contains(&size_bytes) {
+        return Err(ProtocolError::InvalidRequest(format!("Invalid address / size length ({} / {} bytes)", addr_bytes, size_bytes)))
+    }
+    if addr_bytes < 8 && address >> (addr_bytes * 8) != 0 {
+        return Err(ProtocolError::InvalidRequest(format!("Address 0x{:X} does not fit in {} bytes", address, addr_bytes)))
+    }
+    if size_bytes < 4 && len >> (size_bytes * 8) != 0 {
+        return Err(ProtocolError::InvalidRequest(format!("Size {} does not fit in {} bytes", len, size_bytes)))
+    }
+    let mut res = vec![size_bytes << 4 | addr_bytes];
+    res.extend_from_slice(&address.to_be_bytes()[8 - addr_bytes as usize..]);
+    res.extend_from_slice(&(len as u32).to_be_bytes()[4 - size_bytes as usize..]);
+    Ok(res)
+}
+
+/// Mode of operation of a RequestFileTransfer request
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FileTransferMode {
+    AddFile = 0x01,
+    DeleteFile = 0x02,
+    ReplaceFile = 0x03,
+    ReadFile = 0x04,
+    ReadDir = 0x05,
+}
+
+impl FileTransferMode {
+    fn from_byte(b: u8) -> Option<Self> {
+        match b {
+            0x01 => Some(Self::AddFile),
+            0x02 => Some(Self::DeleteFile),
+            0x03 => Some(Self::ReplaceFile),
+            0x04 => Some(Self::ReadFile),
+            0x05 => Some(Self::ReadDir),
+            _ => None
+        }
+    }
+}
+
+/// Positive response to a RequestFileTransfer request. Fields the ECU does not send
+/// in the requested mode are None
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileTransfer {
+    pub mode: FileTransferMode,
+    /// Largest TransferData request the ECU accepts, including the SID and block sequence counter
+    pub max_block_len: Option<usize>,
+    /// Compression and encryption method of the data (0x00 - Neither)
+    pub data_format: Option<u8>,
+    /// Size of the file (ReadFile) or of the directory listing (ReadDir)
+    pub size_uncompressed: Option<u64>,
+    /// Size of the file as it will be transferred (ReadFile)
+    pub size_compressed: Option<u64>,
+}
+
+/// Builds the parameters of a RequestFileTransfer request
+///
+/// # Params
+/// * mode - Mode of operation
+/// * path - Path and name of the file or directory on the ECU
+/// * data_format - Compression and encryption method. Not sent for DeleteFile or ReadDir
+/// * file_size - Uncompressed and compressed size of the file. Only sent, and required, for AddFile and ReplaceFile
+pub(crate) fn encode_file_transfer(mode: FileTransferMode, path: &str, data_format: u8, file_size: Option<(u64, u64)>) -> ProtocolResult<Vec<u8>> {
+    if path.is_empty() || path.len() > 0xFFFF {
+        return Err(ProtocolError::InvalidRequest(format!("Invalid file path length ({} bytes)", path.len())))
+    }
+    let mut res = vec![mode as u8, (path.len() >> 8) as u8, path.len() as u8];
+    res.extend_from_slice(path.as_bytes());
+    if !matches!(mode, FileTransferMode::DeleteFile | FileTransferMode::ReadDir) {
+        res.push(data_format);
+    }
+    if matches!(mode, FileTransferMode::AddFile | FileTransferMode::ReplaceFile) {
+        let (uncompressed, compressed) = file_size.ok_or_else(|| ProtocolError::InvalidRequest(format!("{:?} requires the file size", mode)))?;
+        // Smallest number of bytes both sizes fit in
+        let size_bytes = std::cmp::max(1, (64 - (uncompressed | compressed).leading_zeros() as usize + 7) / 8);
+        res.push(size_bytes as u8);
+        res.extend_from_slice(&uncompressed.to_be_bytes()[8 - size_bytes..]);
+        res.extend_from_slice(&compressed.to_be_bytes()[8 - size_bytes..]);
+    }
+    Ok(res)
+}
+
+/// Parses a RequestFileTransfer positive response (Excluding the SID)
+pub(crate) fn parse_file_transfer(resp: &[u8], mode: FileTransferMode) -> ProtocolResult<FileTransfer> {
+    let invalid = || ProtocolError::InvalidResponse(format!("Invalid file transfer response {:02X?}", resp));
+    let read_be = |bytes: &[u8]| bytes.iter().fold(0u64, |acc, x| acc << 8 | *x as u64);
+    if resp.first().and_then(|x| FileTransferMode::from_byte(*x)) != Some(mode) {
+        return Err(invalid())
+    }
+    let mut res = FileTransfer { mode, max_block_len: None, data_format: None, size_uncompressed: None, size_compressed: None };
+    if mode == FileTransferMode::DeleteFile {
+        return Ok(res)
+    }
+    // Some ECUs encode the length in the upper nibble, like RequestDownload
+    let lfi = *resp.get(1).ok_or_else(invalid)? as usize;
+    let len_bytes = if lfi > 8 { lfi >> 4 } else { lfi };
+    if !(1..=8).contains(&len_bytes) {
+        return Err(invalid())
+    }
+    let mut pos = 2 + len_bytes;
+    res.max_block_len = Some(read_be(resp.get(2..pos).ok_or_else(invalid)?) as usize);
+    res.data_format = Some(*resp.get(pos).ok_or_else(invalid)?);
+    pos += 1;
+    if matches!(mode, FileTransferMode::ReadFile | FileTransferMode::ReadDir) {
+        let size_bytes = read_be(resp.get(pos..pos + 2).ok_or_else(invalid)?) as usize;
+        if !(1..=8).contains(&size_bytes) {
+            return Err(invalid())
+        }
+        pos += 2;
+        res.size_uncompressed = Some(read_be(resp.get(pos..pos + size_bytes).ok_or_else(invalid)?));
+        pos += size_bytes;
+        if mode == FileTransferMode::ReadFile {
+            res.size_compressed = Some(read_be(resp.get(pos..pos + size_bytes).ok_or_else(invalid)?));
+        }
+    }
+    Ok(res)
+}
+
+/// Returns true if a service changes the state of the ECU, so must not be sent in dry run mode
+fn modifies_ecu(cmd: UDSCommand) -> bool {
+    matches!(cmd,
+        UDSCommand::DiagnosticSessionControl | UDSCommand::ECUReset | UDSCommand::ClearDTCInformation |
+        UDSCommand::CommunicationControl | UDSCommand::DynamicDefineDataId | UDSCommand::WriteDataByID |
+        UDSCommand::IOCTLById | UDSCommand::RoutineControl | UDSCommand::RequestDownload |
+        UDSCommand::TransferData | UDSCommand::TransferExit | UDSCommand::WriteMemoryByAddress |
+        UDSCommand::RequestFileTransfer | UDSCommand::ControlDTCSetting | UDSCommand::LinkControl
+    )
+}
+
+/// Builds the positive response (Excluding the SID) an ECU would most likely send to a request,
+/// so workflows can continue in dry run mode
+fn dry_run_response(cmd: UDSCommand, args: &[u8], memory_block_len: usize) -> Vec<u8> {
+    match cmd {
+        // Echo of the sub function
+        UDSCommand::DiagnosticSessionControl | UDSCommand::ECUReset | UDSCommand::CommunicationControl |
+        UDSCommand::ControlDTCSetting | UDSCommand::LinkControl | UDSCommand::TransferData => args.iter().take(1).copied().collect(),
+        // Echo of the DID
+        UDSCommand::WriteDataByID | UDSCommand::IOCTLById => args.iter().take(2).copied().collect(),
+        // Echo of the address and length format, address and size
+        UDSCommand::WriteMemoryByAddress => {
+            let len = args.first().map(|x| 1 + (x >> 4) as usize + (x & 0x0F) as usize).unwrap_or(0);
+            args.iter().take(len).copied().collect()
+        },
+        // Routine started, status 0x00
+        UDSCommand::RoutineControl => {
+            let mut res: Vec<u8> = args.iter().take(3).copied().collect();
+            res.push(0x00);
+            res
+        },
+        // Max block length (Including the SID and sequence counter) as 2 bytes
+        UDSCommand::RequestDownload => {
+            let max = std::cmp::min(memory_block_len + 2, 0xFFFF) as u16;
+            vec![0x20, (max >> 8) as u8, max as u8]
+        },
+        // Mode echo, max block length and data format. Files and directories read as empty
+        UDSCommand::RequestFileTransfer => {
+            let max = std::cmp::min(memory_block_len + 2, 0xFFFF) as u16;
+            match args.first().and_then(|x| FileTransferMode::from_byte(*x)) {
+                Some(FileTransferMode::DeleteFile) => vec![0x02],
+                Some(FileTransferMode::ReadFile) => vec![0x04, 0x02, (max >> 8) as u8, max as u8, 0x00, 0x00, 0x01, 0x00, 0x00],
+                Some(FileTransferMode::ReadDir) => vec![0x05, 0x02, (max >> 8) as u8, max as u8, 0x00, 0x00, 0x01, 0x00],
+                Some(mode) => vec![mode as u8, 0x02, (max >> 8) as u8, max as u8, 0x00],
+                None => Vec::new()
+            }
+        },
+        _ => Vec::new()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct UDSECU {
+    comm_server: Box<dyn ComServer>,
+    iso_tp_settings: ISO15765Config,
+    bus_speed: u32,
+    ext_can: bool,
+    /// Index of the ISO-TP filter for the ECU's responses
+    rx_filter: Option<u32>,
+    /// CAN ID tester present is sent to
+    tester_present_id: Arc<AtomicU32>,
+    /// Address of the ECU behind a gateway requests are routed to, if any
+    target_address: Option<u8>,
+    /// Address tester present is routed to
+    tester_present_target: Arc<Mutex<Option<u8>>>,
+    memory_block_len: usize,
+    /// Requests which were not sent to the ECU because dry run mode is enabled
+    dry_run: Option<Arc<Mutex<Vec<Vec<u8>>>>>,
+    trace: Option<ServiceTrace>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    decoders: Option<Arc<DecoderRegistry>>,
+    rx_purge: RxPurge,
+    retry_policy: RetryPolicy,
+    observers: ConnectionObservers,
+    timing: Arc<Mutex<SessionTiming>>,
+    should_run: Arc<AtomicBool>,
+    stop_tester_present: Arc<AtomicBool>,
+    tester_present_thread: Arc<Mutex<Option<JoinHandle<()>>>>,
+}
+
+impl UDSECU {
+    pub (crate) fn send_uds_cmd(server: &dyn ComServer, send_id: u32, target: Option<u8>, cmd: UDSCommand, args: &[u8]) -> std::result::Result<usize, ComServerError> {
+        let mut data = ISO15765Data {
+            id: send_id,
+            data: target.into_iter().chain(std::iter::once(cmd as u8)).collect(),
+            pad_frame: false,
+        };
+        data.data.extend_from_slice(args);
+        server.send_iso15765_data(&[data], 0)
+    }
+
+    /// Reads the number of DTCs stored on the ECU which match a status mask
+    ///
+    /// # Params
+    /// * status_mask - DTC status mask. Only DTCs with at least one of these status bits set are counted
+    ///
+    /// # Returns
+    /// The ECU's DTC status availability mask (Status bits the ECU supports),
+    /// and the number of DTCs which matched the mask
+    pub fn read_dtc_count(&self, status_mask: u8) -> ProtocolResult<(u8, u16)> {
+        let res = self.run_command(UDSCommand::ReadDTCInformation, &[0x01, status_mask], 500)?;
+        parse_dtc_count(&res)
+    }
+
+    /// Reads the DTCs matching a status mask along with their severity, which tells whether the
+    /// fault only needs fixing at the next service or means the vehicle should not be driven.
+    /// Uses sub function 0x42 (WWH-OBD DTCs by mask record), for the emissions functional group
+    ///
+    /// # Params
+    /// * mask - DTC status mask. Only DTCs with at least one of these status bits set are returned
+    pub fn read_dtcs_with_severity(&self, mask: u8) -> ProtocolResult<Vec<SeverityDtc>> {
+        // 0xFF - Any severity
+        let res = self.run_command(UDSCommand::ReadDTCInformation, &[0x42, DTC_FUNCTIONAL_GROUP_EMISSIONS, mask, 0xFF], 500)?;
+        parse_severity_dtc_list(&res)
+    }
+
+    /// Reads the extended data records (Occurrence counters, aging counters...) the ECU stores for a DTC
+    ///
+    /// # Params
+    /// * dtc - 3 byte DTC number
+    /// * record_number - Record to read, or [DTC_EXT_DATA_ALL_RECORDS] to read all of them
+    /// * model - Definition of the ECU. Without one, only a single record can be split from the response
+    /// and none are decoded
+    pub fn read_dtc_extended_data(&self, dtc: u32, record_number: u8, model: Option<&SchemaV1>) -> ProtocolResult<Vec<ExtDataRecord>> {
+        let res = self.run_command(UDSCommand::ReadDTCInformation, &[0x06, (dtc >> 16) as u8, (dtc >> 8) as u8, dtc as u8, record_number], 500)?;
+        parse_dtc_ext_data(&res, dtc, model.map(|m| m.dtc_ext_record_defs()).unwrap_or(&[]))
+    }
+
+    /// Reads a data identifier from the ECU
+    ///
+    /// # Returns
+    /// The data stored under the DID, excluding the DID echoed back by the ECU
+    pub fn read_data_by_id(&self, did: u16) -> ProtocolResult<Vec<u8>> {
+        let res = self.run_command(UDSCommand::ReadDataByID, &[(did >> 8) as u8, did as u8], 500)?;
+        if res.len() < 2 || res[0] != (did >> 8) as u8 || res[1] != did as u8 {
+            return Err(ProtocolError::InvalidResponse(format!("Invalid response for DID 0x{:04X} {:02X?}", did, res)))
+        }
+        Ok(Vec::from(&res[2..]))
+    }
+
+    /// Writes a data identifier to the ECU. Most ECUs only accept writes once security access has been granted
+    pub fn write_data_by_id(&self, did: u16, data: &[u8]) -> ProtocolResult<()> {
+        let mut args = vec![(did >> 8) as u8, did as u8];
+        args.extend_from_slice(data);
+        let res = self.run_command(UDSCommand::WriteDataByID, &args, 1000)?;
+        if res.len() < 2 || res[0] != (did >> 8) as u8 || res[1] != did as u8 {
+            return Err(ProtocolError::InvalidResponse(format!("Invalid write response for DID 0x{:04X} {:02X?}", did, res)))
+        }
+        Ok(())
+    }
+
+    /// Reads the standard identification DIDs (Part number, serial number, VIN, hardware and software number)
+    /// from the ECU. DIDs that the ECU rejects are left as None
+    pub fn read_identification(&self) -> ProtocolResult<ECUIdentification> {
+        read_identification_with(|did| self.read_data_by_id(did))
+    }
+
+    /// Reads every DID in a range. DIDs which the ECU rejects or does not respond to are skipped.
+    ///
+    /// If the token is cancelled, the scan stops before the next request, and the DIDs
+    /// found so far are returned along with how many DIDs were scanned
+    pub fn scan_dids(&self, dids: RangeInclusive<u16>, token: &CancellationToken) -> ProtocolResult<Cancellable<Vec<(u16, Vec<u8>)>>> {
+        let mut found = Vec::new();
+        for (completed, did) in dids.enumerate() {
+            if token.is_cancelled() {
+                // Drop any late response so it is not read as the response to the next request
+                let _ = self.comm_server.clear_iso15765_rx_buffer();
+                return Ok(Cancellable::Cancelled { partial: found, completed })
+            }
+            match self.read_data_by_id(did) {
+                Ok(data) => found.push((did, data)),
+                Err(ProtocolError::CommError(e)) => return Err(ProtocolError::CommError(e)),
+                Err(_) => {} // DID not supported
+            }
+        }
+        Ok(Cancellable::Complete(found))
+    }
+
+    /// Probes which standard UDS services the ECU supports, by sending each service a request
+    /// which does nothing on an ECU that supports it.
+    ///
+    /// Only read only services are probed unless `state_changing` is set. Then services such as
+    /// [ECUReset](UDSCommand::ECUReset) are also probed, with requests an ECU can only reject, but
+    /// which a poorly implemented ECU could still act on. Any of them can be excluded, and excluded
+    /// services are not sent or returned. In dry run mode, services which modify the ECU are not
+    /// sent, and show as supported
+    ///
+    /// # Returns
+    /// The SID of each probed service, and how the ECU responded to it
+    pub fn probe_services(&self, state_changing: bool, exclude: &[UDSCommand]) -> Vec<(u8, ServiceProbeResult)> {
+        let extra: &[(UDSCommand, &[u8])] = if state_changing { STATE_CHANGING_PROBES } else { &[] };
+        SERVICE_PROBES.iter()
+            .chain(extra)
+            .filter(|(cmd, _)| !exclude.contains(cmd))
+            .map(|(cmd, args)| {
+                let mut req = vec![*cmd as u8];
+                req.extend_from_slice(args);
+                (*cmd as u8, ServiceProbeResult::from_response(*cmd as u8, self.send_raw(&req, 500)))
+            })
+            .collect()
+    }
+
+    /// Measures the round trip time of the adapter and ECU, by sending TesterPresent requests one
+    /// after another. Any response counts, including a negative one. A request without a response
+    /// within 1 second is counted as a failure, and not included in the times
+    ///
+    /// Useful for telling if a slow adapter is the bottleneck, and for choosing STmin and timeouts
+    pub fn measure_latency(&self, samples: usize) -> LatencyReport {
+        let mut times = Vec::with_capacity(samples);
+        let mut failures = 0;
+        for _ in 0..samples {
+            let start = std::time::Instant::now();
+            match self.send_raw(&[UDSCommand::TesterPresent as u8, 0x00], 1000) {
+                Ok(res) if !res.is_empty() => times.push(start.elapsed()),
+                _ => failures += 1,
+            }
+        }
+        LatencyReport::from_samples(times, failures)
+    }
+
+    /// Reads every measurement DID declared by a definition, and converts each to its physical value.
+    /// A DID which fails to read or scale does not stop the rest from being read
+    pub fn read_all_measurements(&self, model: &SchemaV1) -> Vec<(DidDef, ProtocolResult<ScaledValue>)> {
+        self.read_measurements(model.measurement_dids())
+    }
+
+    /// Reads a DID and decodes every value in it, with a registered decoder if one was
+    /// set with [set_decoders](fn@UDSECU::set_decoders), otherwise with the definition
+    pub fn read_decoded_did(&self, did: u16, model: Option<&SchemaV1>) -> ProtocolResult<Vec<(String, FormattedValue)>> {
+        let data = self.read_data_by_id(did)?;
+        let res = match (&self.decoders, model) {
+            (Some(d), _) => d.decode_did(did, &data, model),
+            (None, Some(m)) => m.decode_did_response(did, &data),
+            (None, None) => Err(common::measurement::ScaleError::UnknownDid(did)),
+        };
+        res.map_err(|e| ProtocolError::InvalidResponse(format!("DID 0x{:04X}: {}", did, e)))
+    }
+
+    fn read_measurements<'a, I: Iterator<Item = &'a DidDef>>(&self, defs: I) -> Vec<(DidDef, ProtocolResult<ScaledValue>)> {
+        defs.map(|def| {
+            let res = self.read_data_by_id(def.did).and_then(|data| {
+                def.scale(&data).map_err(|e| ProtocolError::InvalidResponse(format!("DID 0x{:04X}: {}", def.did, e)))
+            });
+            (def.clone(), res)
+        }).collect()
+    }
+
+    /// Reads a region of the ECU's memory using ReadMemoryByAddress
+    ///
+    /// # Params
+    /// * address - Address to start reading at
+    /// * len - Number of bytes to read
+    /// * addr_bytes - Number of bytes the ECU expects the address in (1-8)
+    /// * size_bytes - Number of bytes the ECU expects the size in (1-4)
+    pub fn read_memory(&self, address: u64, len: usize, addr_bytes: u8, size_bytes: u8) -> ProtocolResult<Vec<u8>> {
+        let args = encode_memory_address(address, len, addr_bytes, size_bytes)?;
+        let res = self.run_command(UDSCommand::ReadMemoryByAddress, &args, 1000)?;
+        if res.len() != len {
+            return Err(ProtocolError::InvalidResponse(format!("Read {} bytes at 0x{:X}, expected {}", res.len(), address, len)))
+        }
+        Ok(res)
+    }
+
+    /// Enables or disables dry run mode.
+    ///
+    /// In dry run mode, requests which would change the ECU (Writes, routines, downloads, session
+    /// changes...) are recorded in a transcript instead of being sent, and answered with the positive
+    /// response the ECU would most likely give. Read only requests are still sent. Enabling dry run
+    /// mode starts a new transcript
+    pub fn set_dry_run(&mut self, enabled: bool) {
+        self.dry_run = if enabled { Some(Arc::new(Mutex::new(Vec::new()))) } else { None };
+    }
+
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run.is_some()
+    }
+
+    /// Returns every request (Including the SID) which was not sent because of dry run mode
+    pub fn dry_run_transcript(&self) -> Vec<Vec<u8>> {
+        self.dry_run.as_ref().map(|t| t.lock().unwrap().clone()).unwrap_or_default()
+    }
+
+    /// Sets the largest block of memory the ECU accepts in a single WriteMemoryByAddress request.
+    /// Larger writes are split into multiple requests
+    pub fn set_memory_block_len(&mut self, len: usize) {
+        self.memory_block_len = std::cmp::max(len, 1);
+    }
+
+    /// Writes data to the ECU's memory using WriteMemoryByAddress, splitting it into blocks
+    /// the ECU accepts
+    ///
+    /// # Params
+    /// * address - Address to start writing at
+    /// * data - Data to write
+    /// * addr_bytes - Number of bytes the ECU expects the address in (1-8)
+    /// * size_bytes - Number of bytes the ECU expects the block size in (1-4)
+    pub fn write_memory(&self, address: u64, data: &[u8], addr_bytes: u8, size_bytes: u8) -> ProtocolResult<()> {
+        // Largest block that fits in an ISO-TP payload alongside the SID and memory parameters
+        let max_block = std::cmp::min(self.memory_block_len, ISO_TP_MAX_PAYLOAD - 2 - addr_bytes as usize - size_bytes as usize);
+        address.checked_add(data.len() as u64)
+            .filter(|end| addr_bytes >= 8 || *end <= 1 << (addr_bytes as u32 * 8))
+            .ok_or_else(|| ProtocolError::InvalidRequest(format!("Writing {} bytes at 0x{:X} overflows the address space", data.len(), address)))?;
+        let mut block_address = address;
+        for block in data.chunks(max_block) {
+            let mut args = encode_memory_address(block_address, block.len(), addr_bytes, size_bytes)?;
+            let params_len = args.len();
+            args.extend_from_slice(block);
+            let res = self.run_command(UDSCommand::WriteMemoryByAddress, &args, 1000)?;
+            // Response echoes the format identifier, address and size
+            if res != args[..params_len] {
+                return Err(ProtocolError::InvalidResponse(format!("Write memory response {:02X?} does not match the request at 0x{:X}", res, block_address)))
+            }
+            block_address += block.len() as u64;
+        }
+        Ok(())
+    }
+
+    /// Starts a file transfer with an ECU that has a file system, using RequestFileTransfer.
+    ///
+    /// For every mode but DeleteFile, the file (Or directory listing) is then transferred with
+    /// TransferData requests no larger than the returned max block length, followed by TransferExit.
+    /// See [read_file](fn@UDSECU::read_file) to read a whole file
+    ///
+    /// # Params
+    /// * mode - Mode of operation
+    /// * path - Path and name of the file or directory on the ECU
+    /// * data_format - Compression and encryption method (0x00 - Neither). Not sent for DeleteFile or ReadDir
+    /// * file_size - Uncompressed and compressed size of the file. Required for AddFile and ReplaceFile
+    pub fn request_file_transfer(&self, mode: FileTransferMode, path: &str, data_format: u8, file_size: Option<(u64, u64)>) -> ProtocolResult<FileTransfer> {
+        let args = encode_file_transfer(mode, path, data_format, file_size)?;
+        let res = self.run_command(UDSCommand::RequestFileTransfer, &args, 1000)?;
+        parse_file_transfer(&res, mode)
+    }
+
+    /// Reads a whole file from the ECU with RequestFileTransfer and TransferData
+    ///
+    /// # Returns
+    /// The file as sent by the ECU. If the ECU reported a data format, the data is still compressed / encrypted
+    pub fn read_file(&self, path: &str) -> ProtocolResult<Vec<u8>> {
+        let transfer = self.request_file_transfer(FileTransferMode::ReadFile, path, 0x00, None)?;
+        let size = transfer.size_compressed.unwrap_or(0) as usize;
+        let mut file = Vec::with_capacity(std::cmp::min(size, ISO_TP_MAX_PAYLOAD));
+        let mut seq: u8 = 1;
+        while file.len() < size {
+            let res = self.run_command(UDSCommand::TransferData, &[seq], 1000)?;
+            if res.first() != Some(&seq) {
+                return Err(ProtocolError::InvalidResponse(format!("TransferData response for block {} has the wrong sequence counter {:02X?}", seq, res.first())))
+            }
+            if res.len() == 1 || file.len() + res.len() - 1 > size {
+                return Err(ProtocolError::InvalidResponse(format!("TransferData block {} has an invalid length ({} bytes)", seq, res.len() - 1)))
+            }
+            file.extend_from_slice(&res[1..]);
+            seq = seq.wrapping_add(1);
+        }
+        self.run_command(UDSCommand::TransferExit, &[], 1000)?;
+        Ok(file)
+    }
+
+    pub fn clear_errors(&self) -> ProtocolResult<()> {
+        self.clear_dtcs(DtcGroup::All)
+    }
+
+    /// Clears the DTCs of a group, or a single DTC, with ClearDiagnosticInformation
+    pub fn clear_dtcs(&self, group: DtcGroup) -> ProtocolResult<()> {
+        let mask = group.mask();
+        let res = self.run_command(UDSCommand::ClearDTCInformation, &[(mask >> 16) as u8, (mask >> 8) as u8, mask as u8], 1000)?;
+        if !res.is_empty() {
+            return Err(ProtocolError::InvalidResponse(format!("Unexpected data in clear DTC response {:02X?}", res)))
+        }
+        Ok(())
+    }
+
+    /// Same as [start_diag_session](fn@ProtocolServer::start_diag_session), but the observer
+    /// is registered first, so it also receives the events from starting the session
+    pub fn start_with_observer(comm_server: Box<dyn ComServer>, cfg: &ISO15765Config, observer: Arc<dyn ConnectionObserver>) -> ProtocolResult<Self> {
+        let observers = ConnectionObservers::new();
+        observers.add(observer);
+        Self::start(comm_server, cfg, 500_000, false, observers)
+    }
+
+    /// Same as [start_diag_session](fn@ProtocolServer::start_diag_session), but on a CAN bus
+    /// other than the 500kbps 11bit bus of the OBD-II port
+    ///
+    /// # Params
+    /// * bus_speed - Speed of the CAN bus in bps
+    /// * is_ext_can - True if the ECU uses 29bit CAN IDs
+    pub fn start_on_bus(comm_server: Box<dyn ComServer>, cfg: &ISO15765Config, bus_speed: u32, is_ext_can: bool) -> ProtocolResult<Self> {
+        Self::start(comm_server, cfg, bus_speed, is_ext_can, ConnectionObservers::new())
+    }
+
+    /// Changes the CAN IDs requests are sent to and responses are read from, for ECUs on
+    /// non-standard diagnostic IDs. The response filter is replaced, and if the addressing mode
+    /// changes the ISO-TP interface is re-opened at the same bus speed.
+    ///
+    /// The diagnostic session is not changed, so the ECU at the new IDs is in whichever session it was already in.
+    /// Clones of this ECU keep sending to the old IDs
+    ///
+    /// # Params
+    /// * tx - CAN ID to send requests to
+    /// * rx - CAN ID the ECU responds with
+    /// * extended - True if the IDs are 29bit, false if they are 11bit
+    pub fn set_addresses(&mut self, tx: u32, rx: u32, extended: bool) -> ProtocolResult<()> {
+        let (max_id, bits) = if extended { (0x1FFF_FFFF, 29) } else { (0x7FF, 11) };
+        if let Some(id) = [tx, rx].iter().find(|id| **id > max_id) {
+            return Err(ProtocolError::InvalidRequest(format!("CAN ID 0x{:X} does not fit in {} bits", id, bits)))
+        }
+        if extended != self.ext_can {
+            // Filters are removed along with the interface
+            self.rx_filter = None;
+            self.comm_server.close_iso15765_interface().map_err(ProtocolError::CommError)?;
+            self.comm_server.open_iso15765_interface(self.bus_speed, extended).map_err(ProtocolError::CommError)?;
+            self.comm_server.set_iso15765_params(self.iso_tp_settings.sep_time, self.iso_tp_settings.block_size).map_err(ProtocolError::CommError)?;
+            self.ext_can = extended;
+        } else if let Some(idx) = self.rx_filter.take() {
+            self.comm_server.rem_iso15765_filter(idx).map_err(ProtocolError::CommError)?;
+        }
+        self.rx_filter = Some(self.comm_server.add_iso15765_filter(rx, if extended { 0x1FFF_FFFF } else { 0xFFF }, tx).map_err(ProtocolError::CommError)?);
+        self.iso_tp_settings.send_id = tx;
+        self.iso_tp_settings.recv_id = rx;
+        self.tester_present_id.store(tx, Relaxed);
+        Ok(())
+    }
+
+    /// Routes requests to an ECU behind a gateway, or sends them directly again. Routing
+    /// changes the CAN IDs to the gateway's, as [set_addresses](fn@UDSECU::set_addresses) does,
+    /// and tester present is routed to the same ECU. Removing the route leaves the CAN IDs
+    /// as they are, use [set_addresses](fn@UDSECU::set_addresses) to talk to another ECU directly
+    pub fn set_route(&mut self, route: Option<GatewayRoute>) -> ProtocolResult<()> {
+        if let Some(r) = route {
+            self.set_addresses(r.gateway_tx, r.gateway_rx, r.extended)?;
+        }
+        self.target_address = route.map(|r| r.target);
+        *self.tester_present_target.lock().unwrap() = self.target_address;
+        Ok(())
+    }
+
+    /// Returns the route requests are sent through, if any
+    pub fn route(&self) -> Option<GatewayRoute> {
+        self.target_address.map(|target| GatewayRoute {
+            gateway_tx: self.iso_tp_settings.send_id,
+            gateway_rx: self.iso_tp_settings.recv_id,
+            extended: self.ext_can,
+            target,
+        })
+    }
+
+    /// Overrides the P2 and P2* timings the ECU reported when entering its session, for ECUs
+    /// which report timings they do not meet.
+    ///
+    /// Precedence, highest first:
+    /// 1. Overridden timings. P2 replaces the timeout of every request, and P2* is waited after each response pending
+    /// 2. Timings the ECU reported in its last DiagnosticSessionControl response (Unless ignored with
+    /// [set_ignore_ecu_timing](fn@UDSECU::set_ignore_ecu_timing)). P2 only extends the timeout of a request
+    /// if it is longer, P2* is waited after each response pending
+    /// 3. The timeout of each request, and [DEFAULT_P2_STAR] after each response pending
+    ///
+    /// # Params
+    /// * p2 - Time to wait for the ECU's first response to a request
+    /// * p2_star - Time to wait for a response after the ECU reports response pending (0x78)
+    pub fn set_timing(&mut self, p2: Duration, p2_star: Duration) {
+        self.timing.lock().unwrap().overridden = Some((p2, p2_star))
+    }
+
+    /// Removes the timings set with [set_timing](fn@UDSECU::set_timing)
+    pub fn clear_timing(&mut self) {
+        self.timing.lock().unwrap().overridden = None
+    }
+
+    /// Ignores the P2 and P2* timings the ECU reports when entering a session
+    pub fn set_ignore_ecu_timing(&mut self, ignore: bool) {
+        self.timing.lock().unwrap().ignore_ecu_timing = ignore
+    }
+
+    /// Restarts the adapter's CAN controller after a request failed with [CanError::BusOff]
+    pub fn recover_from_bus_off(&mut self) -> ProtocolResult<()> {
+        self.comm_server.recover_from_bus_off().map_err(ProtocolError::CommError)
+    }
+
+    /// Records every request sent with [run_command](fn@ProtocolServer::run_command) or
+    /// [send_raw](fn@ProtocolServer::send_raw), and the ECU's response, to a trace. None stops tracing
+    pub fn set_trace(&mut self, trace: Option<ServiceTrace>) {
+        self.trace = trace
+    }
+
+    /// Consults a rate limiter before every request, so polling loops and scans do not
+    /// send requests faster than the ECU can cope with. None removes the limiter
+    pub fn set_rate_limiter(&mut self, limiter: Option<Arc<RateLimiter>>) {
+        self.rate_limiter = limiter
+    }
+
+    /// Uses decoders registered at runtime when decoding DIDs, in preference to the definition.
+    /// None decodes with the definition only
+    pub fn set_decoders(&mut self, decoders: Option<Arc<DecoderRegistry>>) {
+        self.decoders = decoders
+    }
+
+    /// Sets how stale received messages are dropped before each request
+    pub fn set_rx_purge(&mut self, purge: RxPurge) {
+        self.rx_purge = purge
+    }
+
+    /// Sets how [transaction](fn@UDSECU::transaction) retries a failed transaction
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry_policy = policy
+    }
+
+    /// Runs a transaction (One or more requests, with [send_raw](fn@ProtocolServer::send_raw) or the
+    /// typed services), retrying the whole transaction according to the [RetryPolicy] if it fails
+    /// with an error that may not happen again, such as a timeout or an ISO-TP sequence error.
+    ///
+    /// Received messages still buffered are dropped before each retry, and the CAN controller is
+    /// restarted if the bus went off
+    pub fn transaction<T>(&mut self, mut f: impl FnMut(&mut Self) -> ProtocolResult<T>) -> ProtocolResult<T> {
+        let mut attempt = 1;
+        loop {
+            match f(self) {
+                Err(e) if attempt < self.retry_policy.max_attempts && RetryPolicy::is_retryable(&e) => {
+                    if let ProtocolError::BusError(CanError::BusOff) = e {
+                        if self.recover_from_bus_off().is_err() {
+                            return Err(e)
+                        }
+                    }
+                    // Always purged, as a late response to the failed attempt is the most likely stale message
+                    self.purge_rx(if self.rx_purge == RxPurge::Off { RxPurge::ClearBuffer } else { self.rx_purge });
+                    std::thread::sleep(self.retry_policy.backoff);
+                    attempt += 1;
+                },
+                res => return res
+            }
+        }
+    }
+
+    /// Drops any messages still buffered from before a request is sent
+    ///
+    /// # Returns
+    /// The CAN error seen whilst draining the buffer, if any
+    fn purge_rx(&self, mode: RxPurge) -> Option<CanError> {
+        match mode {
+            RxPurge::Off => None,
+            RxPurge::ClearBuffer => {
+                if let Err(e) = self.comm_server.clear_iso15765_rx_buffer() {
+                    eprintln!("Cannot clear ISO-TP Rx buffer {}", e)
+                }
+                None
+            },
+            RxPurge::Drain => {
+                loop {
+                    match self.comm_server.read_iso15765_packets(0, 16) {
+                        Ok(msgs) if msgs.is_empty() => return None,
+                        Ok(_) => continue,
+                        Err(e) => return e.can_error()
+                    }
+                }
+            }
+        }
+    }
+
+    /// Registers an observer which is told when the session, security level or connection changes
+    pub fn add_observer(&self, observer: Arc<dyn ConnectionObserver>) {
+        self.observers.add(observer)
+    }
+
+    /// Tells the observers about any change of state the ECU confirmed with a positive response
+    fn notify_positive_response(&self, cmd: UDSCommand, args: &[u8]) {
+        match (cmd, args.first()) {
+            (UDSCommand::DiagnosticSessionControl, Some(sub)) => self.observers.notify(ConnectionEvent::SessionChanged(SessionType::from_byte(*sub))),
+            // ECU always starts up in the default session
+            (UDSCommand::ECUReset, _) => self.observers.notify(ConnectionEvent::SessionChanged(SessionType::Default)),
+            // sendKey is the even sub function after the level's requestSeed
+            (UDSCommand::SecurityAccess, Some(sub)) if *sub & 0x7F != 0 && *sub & 0x01 == 0 => {
+                self.observers.notify(ConnectionEvent::SecurityUnlocked((*sub & 0x7F) - 1))
+            },
+            _ => {}
+        }
+    }
+
+    /// Sends a request and waits for the ECU's final response to it
+    ///
+    /// # Returns
+    /// The full response payload including the SID, or nothing if `max_timeout_ms` is 0
+    fn exchange(&self, cmd: UDSCommand, args: &[u8], max_timeout_ms: u128) -> ProtocolResult<Vec<u8>> {
+        if let Some(limiter) = &self.rate_limiter {
+            limiter.acquire(self.iso_tp_settings.send_id, cmd as u8);
+        }
+        // Error frames are not fatal, but explain a timeout better than no response at all
+        let mut bus_error = self.purge_rx(self.rx_purge);
+        if let Err(e) = UDSECU::send_uds_cmd(self.comm_server.as_ref(), self.iso_tp_settings.send_id, self.target_address, cmd, args) {
+            return Err(ProtocolError::from_comm(e));
+        }
+        if max_timeout_ms == 0 {
+            return Ok(vec![])
+        }
+        let (p2_ms, p2_star_ms) = self.timing.lock().unwrap().timeouts_ms(max_timeout_ms);
+        let start = std::time::Instant::now();
+        let mut timeout = p2_ms;
+        while start.elapsed().as_millis() < timeout {
+            let msgs = match self.comm_server.read_iso15765_packets(0, 1) {
+                Ok(msgs) => msgs,
+                Err(e) => {
+                    match e.can_error() {
+                        Some(CanError::BusOff) => {
+                            self.stop_tester_present.store(false, Relaxed);
+                            return Err(ProtocolError::BusError(CanError::BusOff))
+                        },
+                        Some(err) => bus_error = Some(err),
+                        // Response could not be received (ISO-TP sequence error...)
+                        None => {
+                            self.stop_tester_present.store(false, Relaxed);
+                            return Err(ProtocolError::CommError(e))
+                        }
+                    }
+                    Vec::new()
+                }
+            };
+            for m in msgs {
+                let data = match unroute(m.data, self.target_address) {
+                    Some(data) if !data.is_empty() => data,
+                    _ => continue // First frame indication, or a response from another ECU behind the gateway
+                };
+                if data[0] == cmd as u8 + 0x40 {
+                    self.stop_tester_present.store(false, Relaxed);
+                    if cmd == UDSCommand::DiagnosticSessionControl {
+                        self.timing.lock().unwrap().learn(&data[1..]);
+                    }
+                    self.notify_positive_response(cmd, args);
+                    return Ok(data)
+                } else if data[0] == 0x7F && data.len() == 3 && data[1] == cmd as u8 {
+                    if data[2] == 0x78 {
+                        // Response pending, the ECU has until P2* to respond
+                        self.stop_tester_present.store(true, Relaxed);
+                        timeout = start.elapsed().as_millis() + p2_star_ms;
+                    } else {
+                        self.stop_tester_present.store(false, Relaxed);
+                        return Ok(data)
+                    }
+                }
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        self.stop_tester_present.store(false, Relaxed);
+        Err(bus_error.map(ProtocolError::BusError).unwrap_or(ProtocolError::Timeout))
+    }
+
+    fn start(mut comm_server: Box<dyn ComServer>, cfg: &ISO15765Config, bus_speed: u32, is_ext_can: bool, observers: ConnectionObservers) -> ProtocolResult<Self> {
+        comm_server.open_iso15765_interface(bus_speed, is_ext_can).map_err(ProtocolError::CommError)?;
+        let rx_filter = comm_server.add_iso15765_filter(cfg.recv_id, if is_ext_can { 0x1FFF_FFFF } else { 0xFFF }, cfg.send_id).map_err(ProtocolError::CommError)?;
+        comm_server.set_iso15765_params(cfg.sep_time, cfg.block_size).map_err(ProtocolError::CommError)?;
+
+        let should_run = Arc::new(AtomicBool::new(true));
+        let stop_send_tester_present = Arc::new(AtomicBool::new(true));
+
+        let should_run_t = should_run.clone();
+        let stop_tester_present_t = stop_send_tester_present.clone();
+
+        let server_t = comm_server.clone();
+        let tester_present_id = Arc::new(AtomicU32::new(cfg.send_id));
+        let ecu_id = tester_present_id.clone();
+        let tester_present_target = Arc::new(Mutex::new(None));
+        let ecu_target = tester_present_target.clone();
+        let handle = std::thread::spawn(move || {
+            let mut last_send = std::time::Instant::now();
+            while should_run_t.load(Relaxed) {
+                if last_send.elapsed().as_millis() > 2000 {
+                    last_send = std::time::Instant::now();
+                    if !stop_tester_present_t.load(Relaxed) {
+                        // 0x80 - Suppress positive response
+                        if let Err(e) = UDSECU::send_uds_cmd(server_t.as_ref(), ecu_id.load(Relaxed), *ecu_target.lock().unwrap(), UDSCommand::TesterPresent, &[0x80]) {
+                            eprintln!("Error sending tester present {}", e)
+                        }
+                    }
+                }
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
+        });
+
+        let mut ecu = UDSECU {
+            comm_server,
+            iso_tp_settings: *cfg,
+            bus_speed,
+            ext_can: is_ext_can,
+            rx_filter: Some(rx_filter),
+            tester_present_id,
+            target_address: None,
+            tester_present_target,
+            memory_block_len: DEFAULT_MEMORY_BLOCK_LEN,
+            dry_run: None,
+            trace: None,
+            rate_limiter: None,
+            decoders: None,
+            rx_purge: RxPurge::default(),
+            retry_policy: RetryPolicy::default(),
+            observers,
+            timing: Arc::new(Mutex::new(SessionTiming::default())),
+            stop_tester_present: stop_send_tester_present,
+            should_run,
+            tester_present_thread: Arc::new(Mutex::new(Some(handle))),
+        };
+        ecu.observers.notify(ConnectionEvent::Connected);
+        // Enter extended diagnostic session
+        if let Err(e) = ecu.run_command(UDSCommand::DiagnosticSessionControl, &[0x03], 250) {
+            ecu.exit_diag_session();
+            Err(e)
+        } else {
+            ecu.stop_tester_present.store(false, Relaxed);
+            Ok(ecu)
+        }
+    }
+}
+
+/// Exits the diagnostic session if the last clone of the ECU is dropped while the session
+/// is still running, so the tester present thread is stopped and the ISO-TP channel is released
+impl Drop for UDSECU {
+    fn drop(&mut self) {
+        if Arc::strong_count(&self.tester_present_thread) == 1 && self.should_run.load(Relaxed) {
+            self.exit_diag_session()
+        }
+    }
+}
+
+impl ProtocolServer for UDSECU {
+    type Command = UDSCommand;
+
+    fn start_diag_session(comm_server: Box<dyn ComServer>, cfg: &ISO15765Config) -> ProtocolResult<Self> {
+        Self::start(comm_server, cfg, 500_000, false, ConnectionObservers::new())
+    }
+
+    fn exit_diag_session(&mut self) {
+        // Stop and wait for the tester present thread first, as it still holds
+        // a handle to the channel which is about to be closed
+        let was_running = self.should_run.swap(false, Relaxed);
+        if let Some(handle) = self.tester_present_thread.lock().unwrap().take() {
+            let _ = handle.join();
+        }
+        if let Err(e) = self.comm_server.close_iso15765_interface() {
+            eprintln!("FATAL Cannot close ISO-TP Interface {}", e)
+        }
+        if was_running {
+            self.observers.notify(ConnectionEvent::Disconnected)
+        }
+    }
+
+    fn run_command(&self, cmd: Self::Command, args: &[u8], max_timeout_ms: u128) -> ProtocolResult<Vec<u8>> {
+        if let Some(transcript) = &self.dry_run {
+            if modifies_ecu(cmd) {
+                let mut req = vec![cmd as u8];
+                req.extend_from_slice(args);
+                transcript.lock().unwrap().push(req);
+                return Ok(dry_run_response(cmd, args, self.memory_block_len))
+            }
+        }
+        let start = std::time::Instant::now();
+        let res = self.exchange(cmd, args, max_timeout_ms);
+        if let Some(trace) = &self.trace {
+            let mut req = vec![cmd as u8];
+            req.extend_from_slice(args);
+            trace.record(&req, res.as_ref().map(|r| r.as_slice()).map_err(|e| format!("{:?}", e)), start.elapsed());
+        }
+        match res?.as_slice() {
+            [] => Ok(vec![]),
+            [0x7F, _, nrc] => Err(ProtocolError::ProtocolError(Box::new(<UDSNegativeCode as CommandError>::from_byte(*nrc)))),
+            [_, data @ ..] => Ok(data.to_vec())
+        }
+    }
+
+    fn send_raw(&self, req: &[u8], max_timeout_ms: u128) -> ProtocolResult<Vec<u8>> {
+        if let (Some(transcript), Some(cmd)) = (&self.dry_run, req.first().and_then(|b| UDSCommand::from_byte(b).ok())) {
+            if modifies_ecu(cmd) {
+                transcript.lock().unwrap().push(req.to_vec());
+                let mut resp = vec![req[0].wrapping_add(0x40)];
+                resp.extend(dry_run_response(cmd, &req[1..], self.memory_block_len));
+                return Ok(resp)
+            }
+        }
+        if let (Some(limiter), Some(sid)) = (&self.rate_limiter, req.first()) {
+            limiter.acquire(self.iso_tp_settings.send_id, *sid);
+        }
+        self.purge_rx(self.rx_purge);
+        let (p2_ms, p2_star_ms) = self.timing.lock().unwrap().timeouts_ms(max_timeout_ms);
+        let start = std::time::Instant::now();
+        let res = send_raw_iso15765_routed(self.comm_server.as_ref(), self.iso_tp_settings.send_id, self.target_address, req, p2_ms, p2_star_ms);
+        if let Some(trace) = &self.trace {
+            trace.record(req, res.as_ref().map(|r| r.as_slice()).map_err(|e| format!("{:?}", e)), start.elapsed());
+        }
+        res
+    }
+
+    fn read_errors(&self) -> ProtocolResult<Vec<DTC>> {
+        // 0x02 - Report DTCs by status mask
+        // 0xFF - Any status bit
+        let res = self.run_command(UDSCommand::ReadDTCInformation, &[0x02, 0xFF], 500)?;
+        parse_dtc_list(&res)
+    }
+}
+
+/// Starts a session with a mock ECU. `responder` is called with every request payload
+/// (Other than the session change) and returns the ECU's response, if any
+#[cfg(test)]
+pub(crate) fn start_mock_session<F: Fn(&[u8]) -> Option<Vec<u8>> + Send + Sync + 'static>(responder: F) -> (crate::commapi::mock_api::MockComServer, UDSECU) {
+    let mut mock = crate::commapi::mock_api::MockComServer::new();
+    mock.set_iso15765_responder(move |req| {
+        let resp = if req.data == [0x10, 0x03] { Some(vec![0x50, 0x03]) } else { responder(&req.data) };
+        resp.map(|data| vec![ISO15765Data { id: 0x07E8, data, pad_frame: false }]).unwrap_or_default()
+    });
+    let cfg = ISO15765Config { send_id: 0x07E0, recv_id: 0x07E8, block_size: 8, sep_time: 20 };
+    let ecu = UDSECU::start_diag_session(Box::new(mock.clone()), &cfg).unwrap();
+    (mock, ecu)
+}
+
+#[test]
+fn test_read_decoded_did() {
+    let model = SchemaV1::from_json(r#"{
+        "meta": { "name": "EGS52", "vendor": "Mercedes-Benz", "desc": "722.6 Controller Generation" },
+        "err_table": [],
+        "comm_data": [],
+        "measurements": [{ "did": 4353, "name": "Gear", "byte_len": 1, "compu": { "TextTable": [[0, "Park"], [1, "Drive"]] } }]
+    }"#).unwrap();
+    let (_mock, mut ecu) = start_mock_session(|req| match req {
+        [0x22, a, b] => Some(vec![0x62, *a, *b, 0x01]),
+        _ => None
+    });
+    assert_eq!(ecu.read_decoded_did(0x1101, Some(&model)).unwrap()[0].1.to_string(), "Drive");
+    assert!(ecu.read_decoded_did(0x1101, None).is_err());
+
+    let mut registry = DecoderRegistry::new();
+    registry.register_did(0x1101, "Gear lever", |raw| FormattedValue { value: ScaledValue::Text(format!("Position {}", raw[0])), decimals: 0 });
+    ecu.set_decoders(Some(Arc::new(registry)));
+    let res = ecu.read_decoded_did(0x1101, Some(&model)).unwrap();
+    assert_eq!(res[0].0, "Gear lever");
+    assert_eq!(res[0].1.to_string(), "Position 1");
+}
+
+#[test]
+fn test_clear_dtc_groups() {
+    use std::sync::Mutex;
+
+    let sent = Arc::new(Mutex::new(Vec::new()));
+    let sent_t = sent.clone();
+    let (_mock, ecu) = start_mock_session(move |req| {
+        sent_t.lock().unwrap().push(req.to_vec());
+        match req {
+            [0x14, 0x12, 0x34, 0x56] => Some(vec![0x7F, 0x14, 0x31]),
+            [0x14, 0xAA, ..] => Some(vec![0x54, 0x00]),
+            [0x14, ..] => Some(vec![0x54]),
+            _ => None
+        }
+    });
+    let groups = [DtcGroup::All, DtcGroup::Emissions, DtcGroup::Powertrain, DtcGroup::Chassis, DtcGroup::Body, DtcGroup::Network, DtcGroup::Single(0x9D0013)];
+    for g in &groups {
+        ecu.clear_dtcs(*g).unwrap();
+    }
+    assert_eq!(*sent.lock().unwrap(), vec![
+        vec![0x14, 0xFF, 0xFF, 0xFF],
+        vec![0x14, 0xFF, 0xFF, 0x33],
+        vec![0x14, 0x00, 0x00, 0x00],
+        vec![0x14, 0x40, 0x00, 0x00],
+        vec![0x14, 0x80, 0x00, 0x00],
+        vec![0x14, 0xC0, 0x00, 0x00],
+        vec![0x14, 0x9D, 0x00, 0x13],
+    ]);
+    ecu.clear_errors().unwrap();
+    assert_eq!(sent.lock().unwrap().last().unwrap(), &vec![0x14, 0xFF, 0xFF, 0xFF]);
+
+    // DTC is not supported
+    assert!(matches!(ecu.clear_dtcs(DtcGroup::Single(0x123456)), Err(ProtocolError::ProtocolError(_))));
+    assert!(matches!(ecu.clear_dtcs(DtcGroup::Single(0xAA0000)), Err(ProtocolError::InvalidResponse(_))));
+    assert_eq!(DtcGroup::Single(0xFF9D0013).mask(), 0x9D0013);
+}
+
+#[test]
+fn test_dtc_count_response() {
+    // Availability 0x7F, ISO14229-1 DTC format, 0x0102 DTCs
+    let (availability, count) = parse_dtc_count(&[0x01, 0x7F, 0x01, 0x01, 0x02]).unwrap();
+    assert_eq!(availability, 0x7F);
+    assert_eq!(count, 0x0102);
+    assert!(parse_dtc_count(&[0x01, 0x7F]).is_err());
+}
+
+#[test]
+fn test_dtc_ext_data() {
+    let defs = vec![
+        ExtDataRecordDef { record_number: 0x01, name: "Occurrence counter".into(), len: 1, kind: ExtDataKind::OccurrenceCounter },
+        ExtDataRecordDef { record_number: 0x02, name: "Healing counter".into(), len: 2, kind: ExtDataKind::AgingCounter },
+    ];
+    let resp = [0x06, 0x9D, 0x00, 0x13, 0x2F, 0x01, 0x05, 0x02, 0x00, 0x28];
+    let records = parse_dtc_ext_data(&resp, 0x9D0013, &defs).unwrap();
+    assert_eq!(records, vec![
+        ExtDataRecord { record_number: 0x01, data: vec![0x05], name: Some("Occurrence counter".into()), value: Some(ExtDataValue::OccurrenceCount(5)) },
+        ExtDataRecord { record_number: 0x02, data: vec![0x00, 0x28], name: Some("Healing counter".into()), value: Some(ExtDataValue::AgingCounter(40)) },
+    ]);
+
+    // Without a definition the records cannot be split
+    let records = parse_dtc_ext_data(&resp, 0x9D0013, &[]).unwrap();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].data, vec![0x05, 0x02, 0x00, 0x28]);
+    // No records stored
+    assert!(parse_dtc_ext_data(&resp[..5], 0x9D0013, &defs).unwrap().is_empty());
+    assert!(parse_dtc_ext_data(&resp[..8], 0x9D0013, &defs).is_err());
+    assert!(parse_dtc_ext_data(&resp, 0x9D0014, &defs).is_err());
+}
+
+#[test]
+fn test_dtc_status_availability() {
+    // ECU does not support the warning indicator bit (0x80), or the pending bit (0x04)
+    let dtcs = parse_dtc_list(&[0x02, 0x7B, 0x01, 0x23, 0x45, 0x85, 0x0A, 0xBC, 0xDE, 0x09]).unwrap();
+    assert_eq!(dtcs.len(), 2);
+    assert_eq!(dtcs[0].error, "012345");
+    assert!(dtcs[0].present);
+    assert!(!dtcs[0].stored); // Only the unsupported pending bit was set
+    assert!(!dtcs[0].check_engine_on);
+    assert!(dtcs[1].present);
+    assert!(dtcs[1].stored);
+}
+
+#[test]
+fn test_dtc_severity() {
+    let (_mock, ecu) = start_mock_session(|req| match req {
+        [0x19, 0x42, 0x33, 0x08, 0xFF] => Some(vec![
+            0x59, 0x42, 0x33, 0xFF, 0xE0, 0x04,
+            0x80, 0x01, 0x23, 0x45, 0x09, // Stop driving
+            0x60, 0x0A, 0xBC, 0xDE, 0x08, // Next halt and maintenance, next halt is more urgent
+            0x22, 0x9D, 0x00, 0x13, 0x08, // Maintenance only, class A not supported by the ECU
+        ]),
+        _ => None
+    });
+    let dtcs = ecu.read_dtcs_with_severity(DTC_STATUS_CONFIRMED).unwrap();
+    assert_eq!(dtcs, vec![
+        SeverityDtc { dtc: 0x012345, status: 0x09, severity: DtcSeverity::CheckImmediately, class: None, functional_group: 0x33 },
+        SeverityDtc { dtc: 0x0ABCDE, status: 0x08, severity: DtcSeverity::CheckAtNextHalt, class: None, functional_group: 0x33 },
+        SeverityDtc { dtc: 0x9D0013, status: 0x08, severity: DtcSeverity::MaintenanceOnly, class: None, functional_group: 0x33 },
+    ]);
+    assert!(dtcs[0].severity > dtcs[1].severity);
+
+    let dtcs = parse_severity_dtc_list(&[0x42, 0x33, 0xFF, 0xFF, 0x04, 0x04, 0x01, 0x23, 0x45, 0x01]).unwrap();
+    assert_eq!(dtcs[0].severity, DtcSeverity::NotClassified);
+    assert_eq!(dtcs[0].class, Some(DtcClass::ClassB1));
+    assert!(parse_severity_dtc_list(&[0x42, 0x33, 0xFF, 0xFF, 0x04]).unwrap().is_empty());
+    assert!(parse_severity_dtc_list(&[0x42, 0x33, 0xFF, 0xFF, 0x04, 0x80, 0x01]).is_err());
+}
+
+#[test]
+fn test_read_identification() {
+    let ident = read_identification_with(|did| match did {
+        0xF187 => Ok(b"A2115402645  ".to_vec()),
+        0xF18C => Ok(vec![0x12, 0x34, 0x56, 0x78]), // BCD serial
+        0xF190 => Ok(b"WDB2110421A123456".to_vec()),
+        0xF191 => Ok(vec![b'H', b'W', b'1', 0x00, 0x00]),
+        _ => Err(ProtocolError::ProtocolError(Box::new(UDSNegativeCode::RequestOutOfRange)))
+    }).unwrap();
+    assert_eq!(ident.part_number.as_deref(), Some("A2115402645"));
+    assert_eq!(ident.serial_number.as_deref(), Some("12345678"));
+    assert_eq!(ident.vin.as_deref(), Some("WDB2110421A123456"));
+    assert_eq!(ident.hw_number.as_deref(), Some("HW1"));
+    assert_eq!(ident.sw_number, None);
+
+    // Comm errors are not treated as an unsupported DID
+    assert!(read_identification_with(|_| Err(ProtocolError::Timeout)).is_err());
+}
+
+#[test]
+fn test_drop_stops_tester_present() {
+    let (mock, ecu) = start_mock_session(|_| None);
+    assert!(mock.is_iso15765_open());
+
+    // Dropping a clone must not end the session for the other
+    drop(ecu.clone());
+    assert!(mock.is_iso15765_open());
+
+    let should_run = ecu.should_run.clone();
+    drop(ecu);
+    assert!(!should_run.load(Relaxed));
+    // Only our clone is left, so the thread has exited
+    assert_eq!(Arc::strong_count(&should_run), 1);
+    assert!(!mock.is_iso15765_open());
+}
+
+#[test]
+fn test_scan_dids_cancel() {
+    let token = CancellationToken::new();
+    let token_t = token.clone();
+    let (_mock, ecu) = start_mock_session(move |req| {
+        let did = (req[1] as u16) << 8 | req[2] as u16;
+        if did == 0x0105 {
+            token_t.cancel(); // User presses cancel while this request is in flight
+        }
+        if did % 2 == 0 {
+            Some(vec![0x62, req[1], req[2], 0xAA])
+        } else {
+            Some(vec![0x7F, 0x22, 0x31])
+        }
+    });
+    match ecu.scan_dids(0x0100..=0x01FF, &token).unwrap() {
+        Cancellable::Cancelled { partial, completed } => {
+            assert_eq!(completed, 6);
+            let dids: Vec<u16> = partial.iter().map(|(did, _)| *did).collect();
+            assert_eq!(dids, vec![0x0100, 0x0102, 0x0104]);
+        },
+        x => panic!("Expected scan to be cancelled, got {:?}", x)
+    }
+    let res = ecu.scan_dids(0x0200..=0x0203, &CancellationToken::new()).unwrap();
+    assert_eq!(res, Cancellable::Complete(vec![(0x0200, vec![0xAA]), (0x0202, vec![0xAA])]));
+}
+
+#[test]
+fn test_read_measurements() {
+    use common::measurement::CompuMethod;
+    let (_mock, ecu) = start_mock_session(|req| match req {
+        [0x22, 0x11, 0x00] => Some(vec![0x62, 0x11, 0x00, 0x82]),
+        [0x22, 0x11, 0x01] => Some(vec![0x62, 0x11, 0x01, 0x07]),
+        _ => Some(vec![0x7F, 0x22, 0x31])
+    });
+    let defs = vec![
+        DidDef { did: 0x1100, name: "Oil temperature".into(), byte_offset: 0, byte_len: 1, signed: false,
+            compu: CompuMethod::Linear { factor: 1.0, offset: -40.0 }, unit: Some("°C".into()), decimals: None },
+        DidDef { did: 0x1101, name: "Gear".into(), byte_offset: 0, byte_len: 1, signed: false,
+            compu: CompuMethod::TextTable(vec![(0, "Park".into()), (1, "Drive".into())]), unit: None, decimals: None },
+    ];
+    let res = ecu.read_measurements(defs.iter());
+    assert_eq!(res.len(), 2);
+    assert_eq!(res[0].0, defs[0]);
+    assert_eq!(res[0].1.as_ref().unwrap(), &ScaledValue::Number { value: 90.0, unit: Some("°C".into()) });
+    // Raw value is not in the text table
+    assert!(matches!(res[1].1, Err(ProtocolError::InvalidResponse(_))));
+}
+
+#[test]
+fn test_write_memory_blocks() {
+    let written = Arc::new(Mutex::new(Vec::new()));
+    let written_t = written.clone();
+    let (_mock, mut ecu) = start_mock_session(move |req| {
+        // 2 byte size, 3 byte address
+        assert_eq!(req[0..2], [0x3D, 0x23]);
+        let len = (req[5] as usize) << 8 | req[6] as usize;
+        assert_eq!(req.len(), 7 + len);
+        written_t.lock().unwrap().push((req[2..5].to_vec(), req[7..].to_vec()));
+        let mut resp = vec![0x7D];
+        resp.extend_from_slice(&req[1..7]);
+        Some(resp)
+    });
+    ecu.set_memory_block_len(0x40);
+    let data: Vec<u8> = (0..0x90).map(|x| x as u8).collect();
+    ecu.write_memory(0x01_2000, &data, 3, 2).unwrap();
+    let written = written.lock().unwrap();
+    assert_eq!(written.len(), 3);
+    assert_eq!(written[0], (vec![0x01, 0x20, 0x00], data[0..0x40].to_vec()));
+    assert_eq!(written[1], (vec![0x01, 0x20, 0x40], data[0x40..0x80].to_vec()));
+    assert_eq!(written[2], (vec![0x01, 0x20, 0x80], data[0x80..].to_vec()));
+
+    // Writes which do not fit in the address or size format are rejected without being sent
+    assert!(matches!(ecu.write_memory(0xFF_FFF0, &data, 3, 2), Err(ProtocolError::InvalidRequest(_))));
+    assert!(matches!(ecu.write_memory(0, &data, 9, 2), Err(ProtocolError::InvalidRequest(_))));
+    assert_eq!(written.len(), 3);
+    assert!(encode_memory_address(0, 0x100, 4, 1).is_err());
+    assert_eq!(encode_memory_address(0x1234, 0x10, 2, 1).unwrap(), vec![0x12, 0x12, 0x34, 0x10]);
+}
+
+#[test]
+fn test_write_memory_bad_echo() {
+    let (_mock, ecu) = start_mock_session(|_| Some(vec![0x7D, 0x12, 0x00, 0x00, 0x04]));
+    assert!(matches!(ecu.write_memory(0x1000, &[0xAA; 4], 2, 1), Err(ProtocolError::InvalidResponse(_))));
+}
+
+#[test]
+fn test_connection_events() {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let events_t = events.clone();
+    let mut mock = crate::commapi::mock_api::MockComServer::new();
+    mock.set_iso15765_responder(|req| {
+        let data = match req.data.as_slice() {
+            [0x10, 0x03] => vec![0x50, 0x03],
+            [0x27, 0x01] => vec![0x67, 0x01, 0x12, 0x34],
+            [0x27, 0x02, 0xED, 0xCB] => vec![0x67, 0x02],
+            [0x27, 0x02, ..] => vec![0x7F, 0x27, 0x35], // Invalid key
+            _ => return Vec::new()
+        };
+        vec![ISO15765Data { id: 0x07E8, data, pad_frame: false }]
+    });
+    let cfg = ISO15765Config { send_id: 0x07E0, recv_id: 0x07E8, block_size: 8, sep_time: 20 };
+    let observer = Arc::new(move |e: &ConnectionEvent| events_t.lock().unwrap().push(e.clone()));
+    let mut ecu = UDSECU::start_with_observer(Box::new(mock), &cfg, observer).unwrap();
+
+    // Rejected key does not unlock anything
+    assert!(ecu.run_command(UDSCommand::SecurityAccess, &[0x02, 0x00, 0x00], 250).is_err());
+    let seed = ecu.run_command(UDSCommand::SecurityAccess, &[0x01], 250).unwrap();
+    ecu.run_command(UDSCommand::SecurityAccess, &[0x02, !seed[1], !seed[2]], 250).unwrap();
+    ecu.exit_diag_session();
+    // Already closed, so no second event
+    drop(ecu);
+
+    assert_eq!(*events.lock().unwrap(), vec![
+        ConnectionEvent::Connected,
+        ConnectionEvent::SessionChanged(SessionType::Extended),
+        ConnectionEvent::SecurityUnlocked(0x01),
+        ConnectionEvent::Disconnected,
+    ]);
+}
+
+#[test]
+fn test_session_timing_precedence() {
+    let mut timing = SessionTiming::default();
+    assert_eq!(timing.timeouts_ms(500), (500, 5000));
+    // P2 50ms, P2* 2s
+    timing.learn(&[0x03, 0x00, 0x32, 0x00, 0xC8]);
+    assert_eq!(timing.timeouts_ms(500), (500, 2000));
+    assert_eq!(timing.timeouts_ms(10), (50, 2000));
+    timing.ignore_ecu_timing = true;
+    assert_eq!(timing.timeouts_ms(10), (10, 5000));
+    timing.overridden = Some((Duration::from_millis(20), Duration::from_millis(8000)));
+    assert_eq!(timing.timeouts_ms(500), (20, 8000));
+}
+
+#[test]
+fn test_timing_override() {
+    // Routine control is always pending, and nothing else is answered
+    let (_mock, mut ecu) = start_mock_session(|req| if req[0] == 0x31 { Some(vec![0x7F, 0x31, 0x78]) } else { None });
+    ecu.set_timing(Duration::from_millis(200), Duration::from_millis(300));
+
+    let start = std::time::Instant::now();
+    assert!(matches!(ecu.run_command(UDSCommand::ReadDataByID, &[0xF1, 0x90], 10), Err(ProtocolError::Timeout)));
+    assert!(start.elapsed() >= Duration::from_millis(200));
+
+    let start = std::time::Instant::now();
+    assert!(matches!(ecu.run_command(UDSCommand::RoutineControl, &[0x01, 0xFF, 0x00], 10), Err(ProtocolError::Timeout)));
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(300));
+    assert!(elapsed < DEFAULT_P2_STAR);
+}
+
+#[test]
+fn test_bus_off_during_request() {
+    use crate::commapi::mock_api::MockComServer;
+    let mut mock = MockComServer::new();
+    let bus = mock.clone();
+    let went_off = Arc::new(AtomicBool::new(false));
+    mock.set_iso15765_responder(move |req| {
+        let data = match req.data.as_slice() {
+            [0x10, 0x03] => vec![0x50, 0x03],
+            // Bus goes off the first time, before the ECU can respond
+            [0x22, 0xF1, 0x90] if !went_off.swap(true, Relaxed) => {
+                bus.simulate_can_error(Some(CanError::BusOff));
+                return Vec::new()
+            },
+            [0x22, 0xF1, 0x90] => vec![0x62, 0xF1, 0x90, 0x41],
+            _ => return Vec::new()
+        };
+        vec![ISO15765Data { id: 0x07E8, data, pad_frame: false }]
+    });
+    let cfg = ISO15765Config { send_id: 0x07E0, recv_id: 0x07E8, block_size: 8, sep_time: 20 };
+    let mut ecu = UDSECU::start_diag_session(Box::new(mock.clone()), &cfg).unwrap();
+
+    let start = std::time::Instant::now();
+    assert!(matches!(ecu.read_data_by_id(0xF190), Err(ProtocolError::BusError(CanError::BusOff))));
+    // Reported straight away, rather than waiting for the timeout
+    assert!(start.elapsed() < Duration::from_millis(500));
+    // Still off until recovered
+    assert!(matches!(ecu.read_data_by_id(0xF190), Err(ProtocolError::BusError(CanError::BusOff))));
+
+    ecu.recover_from_bus_off().unwrap();
+    assert_eq!(ecu.read_data_by_id(0xF190).unwrap(), vec![0x41]);
+
+    // Error frames alone do not stop the request, but explain the timeout
+    mock.simulate_can_error(Some(CanError::ErrorFrame));
+    assert!(matches!(ecu.run_command(UDSCommand::ReadDataByID, &[0xF1, 0x91], 20), Err(ProtocolError::BusError(CanError::ErrorFrame))));
+}
+
+#[test]
+fn test_stale_response_purged() {
+    let (mock, mut ecu) = start_mock_session(|req| match req {
+        [0x22, 0xF1, 0x90] => Some(vec![0x62, 0xF1, 0x90, 0x02]),
+        _ => None
+    });
+    let stale = ISO15765Data { id: 0x07E8, data: vec![0x62, 0xF1, 0x90, 0x01], pad_frame: false };
+
+    // Without a purge, the late response is taken as the response to the new request
+    ecu.set_rx_purge(RxPurge::Off);
+    mock.push_iso15765_rx(stale.clone());
+    assert_eq!(ecu.read_data_by_id(0xF190).unwrap(), vec![0x01]);
+
+    for purge in &[RxPurge::ClearBuffer, RxPurge::Drain] {
+        ecu.set_rx_purge(*purge);
+        mock.push_iso15765_rx(stale.clone());
+        mock.push_iso15765_rx(stale.clone());
+        assert_eq!(ecu.read_data_by_id(0xF190).unwrap(), vec![0x02], "{:?}", purge);
+    }
+}
+
+#[test]
+fn test_transaction_retry_policy() {
+    let (mock, mut ecu) = start_mock_session(|req| match req {
+        [0x22, 0xF1, 0x90] => Some(vec![0x62, 0xF1, 0x90, 0x41]),
+        [0x22, ..] => Some(vec![0x7F, 0x22, 0x31]),
+        _ => None
+    });
+    ecu.set_retry_policy(RetryPolicy { max_attempts: 3, backoff: Duration::from_millis(1) });
+
+    // Bus is recovered before retrying
+    mock.simulate_can_error(Some(CanError::BusOff));
+    let mut attempts = 0;
+    assert_eq!(ecu.transaction(|e| { attempts += 1; e.read_data_by_id(0xF190) }).unwrap(), vec![0x41]);
+    assert_eq!(attempts, 2);
+
+    // Rejected by the ECU, so not retried
+    attempts = 0;
+    assert!(matches!(ecu.transaction(|e| { attempts += 1; e.read_data_by_id(0xF191) }), Err(ProtocolError::ProtocolError(_))));
+    assert_eq!(attempts, 1);
+
+    // Gives up after the last attempt
+    attempts = 0;
+    assert!(matches!(ecu.transaction(|e| { attempts += 1; e.send_raw(&[0x31, 0x01, 0xFF, 0x00], 10) }), Err(ProtocolError::Timeout)));
+    assert_eq!(attempts, 3);
+}
+
+#[test]
+fn test_encode_file_transfer() {
+    let req = encode_file_transfer(FileTransferMode::AddFile, "/a.bin", 0x00, Some((0x1234, 0x0100))).unwrap();
+    assert_eq!(req, vec![0x01, 0x00, 0x06, b'/', b'a', b'.', b'b', b'i', b'n', 0x00, 0x02, 0x12, 0x34, 0x01, 0x00]);
+    let req = encode_file_transfer(FileTransferMode::DeleteFile, "/a", 0x11, None).unwrap();
+    assert_eq!(req, vec![0x02, 0x00, 0x02, b'/', b'a']);
+    assert!(matches!(encode_file_transfer(FileTransferMode::ReplaceFile, "/a", 0x00, None), Err(ProtocolError::InvalidRequest(_))));
+    assert!(matches!(encode_file_transfer(FileTransferMode::ReadDir, "", 0x00, None), Err(ProtocolError::InvalidRequest(_))));
+
+    let resp = parse_file_transfer(&[0x05, 0x02, 0x01, 0x02, 0x00, 0x00, 0x02, 0x00, 0x80], FileTransferMode::ReadDir).unwrap();
+    assert_eq!(resp.max_block_len, Some(0x0102));
+    assert_eq!(resp.size_uncompressed, Some(0x80));
+    assert_eq!(resp.size_compressed, None);
+    // Length in the upper nibble
+    assert_eq!(parse_file_transfer(&[0x01, 0x20, 0x04, 0x02, 0x00], FileTransferMode::AddFile).unwrap().max_block_len, Some(0x0402));
+    assert!(parse_file_transfer(&[0x02], FileTransferMode::DeleteFile).unwrap().max_block_len.is_none());
+    assert!(matches!(parse_file_transfer(&[0x04, 0x02, 0x01], FileTransferMode::ReadFile), Err(ProtocolError::InvalidResponse(_))));
+    assert!(matches!(parse_file_transfer(&[0x03], FileTransferMode::DeleteFile), Err(ProtocolError::InvalidResponse(_))));
+}
+
+#[test]
+fn test_read_file() {
+    let file: Vec<u8> = (0..40).collect();
+    let file_t = file.clone();
+    let (mock, ecu) = start_mock_session(move |req| match req[0] {
+        0x38 => {
+            assert_eq!(&req[1..4], &[0x04, 0x00, 0x08]);
+            assert_eq!(&req[4..12], b"/log.txt");
+            // 18 byte blocks (16 bytes of data), 40 byte file
+            Some(vec![0x78, 0x04, 0x02, 0x00, 0x12, 0x00, 0x00, 0x01, 0x28, 0x28])
+        },
+        0x36 => {
+            let start = (req[1] as usize - 1) * 16;
+            let mut resp = vec![0x76, req[1]];
+            resp.extend_from_slice(&file_t[start..std::cmp::min(start + 16, file_t.len())]);
+            Some(resp)
+        },
+        0x37 => Some(vec![0x77]),
+        _ => None
+    });
+    assert_eq!(ecu.read_file("/log.txt").unwrap(), file);
+    let requests: Vec<u8> = mock.get_iso15765_tx_log().iter().map(|r| r.data[0]).collect();
+    assert_eq!(requests, vec![0x10, 0x38, 0x36, 0x36, 0x36, 0x37]);
+}
+
+#[test]
+fn test_set_addresses() {
+    let mut mock = crate::commapi::mock_api::MockComServer::new();
+    // Tuner module on a non-standard ID answers with its own ID in the response
+    mock.set_iso15765_responder(|req| {
+        let data = match (req.id, req.data.as_slice()) {
+            (_, [0x10, 0x03]) => vec![0x50, 0x03],
+            (0x07E0, [0x22, 0xF1, 0x90]) => vec![0x62, 0xF1, 0x90, 0xE0],
+            (0x07A5, [0x22, 0xF1, 0x90]) => vec![0x62, 0xF1, 0x90, 0xA5],
+            (0x18DA45F1, [0x22, 0xF1, 0x90]) => vec![0x62, 0xF1, 0x90, 0x45],
+            _ => return vec![]
+        };
+        vec![ISO15765Data { id: req.id + 8, data, pad_frame: false }]
+    });
+    let cfg = ISO15765Config { send_id: 0x07E0, recv_id: 0x07E8, block_size: 8, sep_time: 20 };
+    let mut ecu = UDSECU::start_diag_session(Box::new(mock.clone()), &cfg).unwrap();
+    assert_eq!(mock.get_iso15765_filters(), vec![(0x07E8, 0xFFF, 0x07E0)]);
+    assert_eq!(ecu.read_data_by_id(0xF190).unwrap(), vec![0xE0]);
+
+    ecu.set_addresses(0x07A5, 0x07AD, false).unwrap();
+    assert_eq!(mock.get_iso15765_filters(), vec![(0x07AD, 0xFFF, 0x07A5)]);
+    assert_eq!(ecu.read_data_by_id(0xF190).unwrap(), vec![0xA5]);
+    assert_eq!(mock.get_iso15765_tx_log().last().unwrap().id, 0x07A5);
+
+    // Switching to 29bit IDs re-opens the interface
+    ecu.set_addresses(0x18DA45F1, 0x18DAF145, true).unwrap();
+    assert_eq!(mock.iso15765_bus(), Some((500_000, true)));
+    assert_eq!(mock.get_iso15765_filters(), vec![(0x18DAF145, 0x1FFF_FFFF, 0x18DA45F1)]);
+    assert_eq!(ecu.read_data_by_id(0xF190).unwrap(), vec![0x45]);
+
+    // IDs out of range for the mode are rejected, and nothing changes
+    assert!(matches!(ecu.set_addresses(0x0800, 0x07E8, false), Err(ProtocolError::InvalidRequest(_))));
+    assert!(matches!(ecu.set_addresses(0x18DA45F1, 0x2000_0000, true), Err(ProtocolError::InvalidRequest(_))));
+    assert_eq!(mock.get_iso15765_filters(), vec![(0x18DAF145, 0x1FFF_FFFF, 0x18DA45F1)]);
+    assert_eq!(ecu.read_data_by_id(0xF190).unwrap(), vec![0x45]);
+    ecu.exit_diag_session();
+}
+
+#[test]
+fn test_gateway_route() {
+    // Sub-bus ECUs behind the gateway, by address
+    fn sub_ecu(addr: u8, req: &[u8]) -> Option<Vec<u8>> {
+        match (addr, req) {
+            (0x40, [0x22, 0xF1, 0x90]) => Some(vec![0x62, 0xF1, 0x90, 0x40]),
+            (0x41, [0x22, 0xF1, 0x90]) => Some(vec![0x62, 0xF1, 0x90, 0x41]),
+            (0x41, [0x31, ..]) => Some(vec![0x7F, 0x31, 0x33]),
+            _ => None
+        }
+    }
+    let mut mock = crate::commapi::mock_api::MockComServer::new();
+    // Gateway on 0x710, which forwards on the target address and puts it before the response.
+    // Another ECU on the sub-bus also responds to every request
+    mock.set_iso15765_responder(|req| {
+        let resp = match (req.id, req.data.as_slice()) {
+            (_, [0x10, 0x03]) => Some(vec![0x50, 0x03]),
+            (0x0710, [addr, payload @ ..]) => sub_ecu(*addr, payload).map(|r| std::iter::once(*addr).chain(r).collect()),
+            _ => None
+        };
+        resp.map(|data| vec![
+            ISO15765Data { id: req.id + 8, data: vec![0x7A, 0x62, 0xF1, 0x90, 0x7A], pad_frame: false },
+            ISO15765Data { id: req.id + 8, data, pad_frame: false },
+        ]).unwrap_or_default()
+    });
+    let cfg = ISO15765Config { send_id: 0x07E0, recv_id: 0x07E8, block_size: 8, sep_time: 20 };
+    let mut ecu = UDSECU::start_diag_session(Box::new(mock.clone()), &cfg).unwrap();
+    assert_eq!(ecu.route(), None);
+
+    let route = GatewayRoute { gateway_tx: 0x0710, gateway_rx: 0x0718, extended: false, target: 0x40 };
+    ecu.set_route(Some(route)).unwrap();
+    assert_eq!(ecu.route(), Some(route));
+    assert_eq!(mock.get_iso15765_filters(), vec![(0x0718, 0xFFF, 0x0710)]);
+    assert_eq!(ecu.read_data_by_id(0xF190).unwrap(), vec![0x40]);
+    let sent = mock.get_iso15765_tx_log().last().unwrap().clone();
+    assert_eq!((sent.id, sent.data), (0x0710, vec![0x40, 0x22, 0xF1, 0x90]));
+
+    ecu.set_route(Some(GatewayRoute { target: 0x41, ..route })).unwrap();
+    assert_eq!(ecu.read_data_by_id(0xF190).unwrap(), vec![0x41]);
+    // Raw requests are routed too, and negative responses come back without the address
+    assert_eq!(ecu.send_raw(&[0x31, 0x01, 0x02, 0x03], 500).unwrap(), vec![0x7F, 0x31, 0x33]);
+    // No ECU at the address
+    ecu.set_route(Some(GatewayRoute { target: 0x42, ..route })).unwrap();
+    assert!(matches!(ecu.read_data_by_id(0xF190), Err(ProtocolError::Timeout)));
+
+    // Without the route, the gateway does not forward the request
+    ecu.set_route(None).unwrap();
+    assert_eq!(ecu.route(), None);
+    assert!(matches!(ecu.read_data_by_id(0xF190), Err(ProtocolError::Timeout)));
+    ecu.exit_diag_session();
+}
+
+#[test]
+fn test_probe_services() {
+    let (mock, ecu) = start_mock_session(|req| match req {
+        [0x10, 0x7F] | [0x31, 0x7F] => Some(vec![0x7F, req[0], 0x12]), // Sub-function not supported
+        [0x19, 0x01, 0xFF] => Some(vec![0x59, 0x01, 0xFF, 0x01, 0x00, 0x02]),
+        [0x22, 0xF1, 0x90] => Some(vec![0x7F, 0x22, 0x31]), // No VIN
+        [0x23] => Some(vec![0x7F, 0x23, 0x13]),
+        [0x27, 0x7F] => Some(vec![0x7F, 0x27, 0x22]),
+        [0x2E] => Some(vec![0x7F, 0x2E, 0x7F]),
+        [0x3E, 0x00] => Some(vec![0x7E, 0x00]),
+        [0x87, 0x7F] => None,
+        _ => Some(vec![0x7F, req[0], 0x11])
+    });
+    let sent_since = |start: usize| -> Vec<Vec<u8>> {
+        mock.get_iso15765_tx_log().into_iter().skip(start).map(|r| r.data).filter(|d| d != &[0x3E, 0x80]).collect()
+    };
+    // Only read only services by default
+    let start = mock.get_iso15765_tx_log().len();
+    let res = ecu.probe_services(false, &[]);
+    assert_eq!(res.iter().map(|(sid, _)| *sid).collect::<Vec<u8>>(), vec![0x19, 0x22, 0x23, 0x24, 0x3E]);
+    assert_eq!(res[4].1, ServiceProbeResult::Supported);
+    assert_eq!(res[3].1, ServiceProbeResult::NotSupported);
+    assert_eq!(sent_since(start), vec![vec![0x19, 0x01, 0xFF], vec![0x22, 0xF1, 0x90], vec![0x23], vec![0x24], vec![0x3E, 0x00]]);
+
+    let start = mock.get_iso15765_tx_log().len();
+    let res = ecu.probe_services(true, &[UDSCommand::ECUReset]);
+    let result = |sid: u8| res.iter().find(|(s, _)| *s == sid).map(|(_, r)| r.clone());
+    assert_eq!(res.len(), SERVICE_PROBES.len() + STATE_CHANGING_PROBES.len() - 1);
+    for sid in &[0x10, 0x19, 0x22, 0x23, 0x31, 0x3E] {
+        assert_eq!(result(*sid), Some(ServiceProbeResult::Supported), "SID {:02X}", sid);
+    }
+    assert_eq!(result(0x27), Some(ServiceProbeResult::ConditionsNotCorrect));
+    assert_eq!(result(0x2E), Some(ServiceProbeResult::NotSupportedInSession));
+    assert_eq!(result(0x87), Some(ServiceProbeResult::NoResponse));
+    assert_eq!(result(0x14), Some(ServiceProbeResult::NotSupported));
+    assert_eq!(result(0x85), Some(ServiceProbeResult::NotSupported));
+    assert_eq!(result(0x11), None);
+    assert_eq!(result(0x37), None);
+
+    // Excluded services and TransferExit are never sent, and nothing is sent that could change the ECU
+    let sent = sent_since(start);
+    assert!(!sent.iter().any(|d| d[0] == 0x11 || d[0] == 0x37));
+    assert!(sent.contains(&vec![0x10, 0x7F]));
+    assert!(!sent.contains(&vec![0x10]));
+    assert!(sent.contains(&vec![0x14]));
+    assert!(sent.contains(&vec![0x34]));
+    assert!(sent.contains(&vec![0x85, 0x7F]));
+}
+
+#[test]
+fn test_latency_report() {
+    let ms = Duration::from_millis;
+    let report = LatencyReport::from_samples((1..=20).map(ms).collect(), 1);
+    assert_eq!((report.min, report.median, report.p95, report.max), (ms(1), ms(10), ms(19), ms(20)));
+    assert_eq!(report.jitter, ms(1));
+    assert_eq!(report.to_string(), "21 requests (1 failed): min 1.0ms, median 10.0ms, p95 19.0ms, max 20.0ms, jitter 1.0ms");
+    let report = LatencyReport::from_samples(vec![ms(5), ms(9), ms(5)], 0);
+    assert_eq!((report.median, report.jitter), (ms(5), ms(4)));
+    assert_eq!(LatencyReport::from_samples(Vec::new(), 3).max, Duration::default());
+}
+
+#[test]
+fn test_measure_latency() {
+    let delay = Duration::from_millis(20);
+    let (_mock, ecu) = start_mock_session(move |req| match req {
+        [0x3E, 0x00] => {
+            std::thread::sleep(delay);
+            Some(vec![0x7E, 0x00])
+        },
+        _ => None
+    });
+    let report = ecu.measure_latency(10);
+    assert_eq!((report.samples.len(), report.failures), (10, 0));
+    // How much longer than the delay each request takes depends on the machine running the test,
+    // so only the lower bound and the ordering of the statistics are checked
+    assert!(report.samples.iter().all(|t| *t >= delay), "{:?}", report);
+    assert!(report.min >= delay, "{:?}", report);
+    assert!(report.min <= report.median && report.median <= report.p95 && report.p95 <= report.max, "{:?}", report);
+    assert!(report.jitter <= report.max - report.min, "{:?}", report);
+}
@@ -0,0 +1,219 @@
+use std::cmp::min;
+use std::io::{self, Read, Write};
+use flate2::Compression;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use crate::commapi::comm_api::{dlc_to_len, CanFrame, CAN_MAX_DATA_LEN};
+use crate::commapi::pcap::CapturedFrame;
+
+// Compact recording of bus traffic, for replaying a capture later. Much smaller than a pcap
+// or candump log since timestamps are stored as the difference to the previous frame.
+//
+// Header (6 bytes):
+//   4 bytes - Magic, "OVDR"
+//   1 byte  - Format version, currently 1
+//   1 byte  - Flags. Bit 0 is set if everything after the header is a zlib stream
+//
+// Followed by one record per frame until the end of the file:
+//   varint  - Microseconds since the previous frame. The first frame stores its full timestamp
+//   varint  - CAN ID shifted left by 1. Bit 0 is set for a 29 bit ID
+//   1 byte  - Bits 0-3 DLC, bit 4 CAN-FD, bit 5 BRS, bit 6 RTR, bit 7 error frame
+//   N bytes - Data, as many bytes as the DLC maps to
+//
+// Varints are unsigned LEB128: 7 bits per byte, least significant first, bit 7 set
+// on every byte except the last
+
+const MAGIC: &[u8; 4] = b"OVDR";
+const VERSION: u8 = 1;
+const FLAG_ZLIB: u8 = 0x01;
+
+const FRAME_DLC_MASK: u8 = 0x0F;
+const FRAME_FD: u8 = 0x10;
+const FRAME_BRS: u8 = 0x20;
+const FRAME_RTR: u8 = 0x40;
+const FRAME_ERR: u8 = 0x80;
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let b = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(b);
+            return
+        }
+        out.push(b | 0x80);
+    }
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> io::Result<u64> {
+    let mut res = 0u64;
+    for shift in (0..64).step_by(7) {
+        let b = *data.get(*pos).ok_or_else(|| truncated(*pos))?;
+        *pos += 1;
+        res |= ((b & 0x7F) as u64) << shift;
+        if b & 0x80 == 0 {
+            return Ok(res)
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::InvalidData, format!("Varint ending at {} is too long", pos)))
+}
+
+fn truncated(pos: usize) -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, format!("Recording is truncated at byte {}", pos))
+}
+
+/// Encodes the records, without the header
+fn encode_records<'a, I: IntoIterator<Item = &'a CapturedFrame>>(frames: I) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut last_ts = 0;
+    for f in frames {
+        // Frames from different channels can be slightly out of order. Rather than
+        // storing a negative delta, the frame is given the previous timestamp
+        let ts = f.timestamp_us.max(last_ts);
+        write_varint(&mut out, ts - last_ts);
+        last_ts = ts;
+        write_varint(&mut out, ((f.frame.id as u64) << 1) | f.extended as u64);
+        let mut flags = f.frame.dlc & FRAME_DLC_MASK;
+        if f.frame.fd {
+            flags |= FRAME_FD
+        }
+        if f.frame.brs {
+            flags |= FRAME_BRS
+        }
+        if f.rtr {
+            flags |= FRAME_RTR
+        }
+        if f.error {
+            flags |= FRAME_ERR
+        }
+        out.push(flags);
+        out.extend_from_slice(f.frame.get_data());
+    }
+    out
+}
+
+fn decode_records(data: &[u8]) -> io::Result<Vec<CapturedFrame>> {
+    let mut res = Vec::new();
+    let mut pos = 0;
+    let mut ts = 0u64;
+    while pos < data.len() {
+        ts = ts.checked_add(read_varint(data, &mut pos)?)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Timestamp overflows"))?;
+        let id = read_varint(data, &mut pos)?;
+        let flags = *data.get(pos).ok_or_else(|| truncated(pos))?;
+        pos += 1;
+        let fd = flags & FRAME_FD != 0;
+        let dlc = flags & FRAME_DLC_MASK;
+        let len = if fd { dlc_to_len(dlc) } else { min(dlc as usize, CAN_MAX_DATA_LEN) };
+        let bytes = data.get(pos..pos + len).ok_or_else(|| truncated(data.len()))?;
+        pos += len;
+        let mut frame = if fd { CanFrame::new_fd((id >> 1) as u32, bytes, flags & FRAME_BRS != 0) } else { CanFrame::new((id >> 1) as u32, bytes) };
+        // Classic DLCs 9-15 only carry 8 bytes, but the DLC itself is kept
+        frame.dlc = dlc;
+        res.push(CapturedFrame {
+            timestamp_us: ts,
+            frame,
+            extended: id & 0x01 != 0,
+            rtr: flags & FRAME_RTR != 0,
+            error: flags & FRAME_ERR != 0,
+        });
+    }
+    Ok(res)
+}
+
+/// Writes frames as a recording which can be replayed with [read_recording]
+///
+/// ## Params
+/// * compress - Compress the records with zlib. Worth it for long captures of periodic traffic
+pub fn write_recording<'a, W: Write, I: IntoIterator<Item = &'a CapturedFrame>>(mut writer: W, frames: I, compress: bool) -> io::Result<()> {
+    let records = encode_records(frames);
+    writer.write_all(MAGIC)?;
+    writer.write_all(&[VERSION, if compress { FLAG_ZLIB } else { 0x00 }])?;
+    if compress {
+        let mut encoder = ZlibEncoder::new(&mut writer, Compression::default());
+        encoder.write_all(&records)?;
+        encoder.finish()?;
+    } else {
+        writer.write_all(&records)?;
+    }
+    writer.flush()
+}
+
+/// Reads every frame of a recording, in the order they were captured
+pub fn read_recording<R: Read>(mut reader: R) -> io::Result<Vec<CapturedFrame>> {
+    let mut header = [0u8; 6];
+    reader.read_exact(&mut header)?;
+    if &header[0..4] != MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a bus recording"))
+    }
+    if header[4] != VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unsupported recording version {}", header[4])))
+    }
+    let mut records = Vec::new();
+    if header[5] & FLAG_ZLIB != 0 {
+        ZlibDecoder::new(reader).read_to_end(&mut records)?;
+    } else {
+        reader.read_to_end(&mut records)?;
+    }
+    decode_records(&records)
+}
+
+#[test]
+fn test_varint() {
+    for v in &[0u64, 1, 0x7F, 0x80, 0x3FFF, 0x4000, 1_600_000_000_000_000, u64::MAX] {
+        let mut buf = Vec::new();
+        write_varint(&mut buf, *v);
+        let mut pos = 0;
+        assert_eq!(read_varint(&buf, &mut pos).unwrap(), *v);
+        assert_eq!(pos, buf.len());
+    }
+    let mut buf = Vec::new();
+    write_varint(&mut buf, 300);
+    assert_eq!(buf, vec![0xAC, 0x02]);
+    assert!(read_varint(&[0x80, 0x80], &mut 0).is_err());
+}
+
+#[test]
+fn test_recording_round_trip() {
+    let mut frames = Vec::new();
+    let mut ts = 1_600_000_000_000_000u64;
+    for i in 0..300u32 {
+        ts += 1000 + (i as u64 % 7) * 13;
+        let frame = match i % 5 {
+            0 => CapturedFrame::new(ts, CanFrame::new(0x07E8, &[0x03, 0x62, 0xF1, i as u8])),
+            1 => CapturedFrame::new(ts, CanFrame::new(0x18DA_F110, &[0x02, 0x3E, 0x00])),
+            2 => CapturedFrame::new(ts, CanFrame::new_fd(0x0456, &[i as u8; 20], i % 2 == 0)),
+            3 => CapturedFrame { rtr: true, ..CapturedFrame::new(ts, CanFrame::new(0x0123, &[])) },
+            _ => CapturedFrame::new(ts, CanFrame::new(0x0200 + i % 4, &(i as u64).to_be_bytes())),
+        };
+        frames.push(frame);
+    }
+
+    let candump: String = frames.iter().map(|f| {
+        format!("({}.{:06}) can0 {:03X}#{}\n", f.timestamp_us / 1_000_000, f.timestamp_us % 1_000_000, f.frame.id, common::hex::format_hex(f.frame.get_data(), ""))
+    }).collect();
+
+    for compress in &[false, true] {
+        let mut out = Vec::new();
+        write_recording(&mut out, &frames, *compress).unwrap();
+        assert_eq!(&out[0..6], &[b'O', b'V', b'D', b'R', 0x01, *compress as u8]);
+        assert!(out.len() < candump.len() / 2, "{} bytes vs {} bytes of candump", out.len(), candump.len());
+
+        let read = read_recording(out.as_slice()).unwrap();
+        assert_eq!(read.len(), frames.len());
+        for (a, b) in frames.iter().zip(read.iter()) {
+            assert_eq!(a.timestamp_us, b.timestamp_us);
+            assert_eq!(a.frame.id, b.frame.id);
+            assert_eq!(a.frame.dlc, b.frame.dlc);
+            assert_eq!(a.frame.fd, b.frame.fd);
+            assert_eq!(a.frame.brs, b.frame.brs);
+            assert_eq!(a.frame.get_data(), b.frame.get_data());
+            assert_eq!((a.extended, a.rtr, a.error), (b.extended, b.rtr, b.error));
+        }
+    }
+
+    let mut out = Vec::new();
+    write_recording(&mut out, &frames, false).unwrap();
+    assert!(read_recording(&out[0..out.len() - 1]).is_err());
+    assert!(read_recording(&b"OVDX\x01\x00"[..]).is_err());
+}
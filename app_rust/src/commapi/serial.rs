@@ -0,0 +1,225 @@
+use std::io::{ErrorKind, Read, Write};
+use std::time::{Duration, Instant};
+use serialport::{ClearBuffer, SerialPort, SerialPortType};
+use crate::commapi::comm_api::{ComServerError, ERR_DEVICE_LOST, ERR_FAILED};
+
+/// Byte stream to an adapter which runs a line protocol (ELM327 and clones) rather than
+/// exposing a driver API. Adapter backends parse their protocol on top of this, so they do
+/// not depend on how the adapter is connected
+pub trait ByteTransport: Send {
+    /// Writes every byte, blocking until they have been sent
+    fn write_bytes(&mut self, data: &[u8]) -> Result<(), ComServerError>;
+
+    /// Reads the bytes which are available, waiting up to `timeout` for the first to arrive
+    ///
+    /// # Returns
+    /// The number of bytes read into `buf`. 0 if nothing arrived before the timeout
+    fn read_bytes(&mut self, buf: &mut [u8], timeout: Duration) -> Result<usize, ComServerError>;
+
+    /// Drops any bytes received but not read yet
+    fn clear_input(&mut self) -> Result<(), ComServerError>;
+
+    /// Reads until a terminator byte is received (Example: the `>` prompt of an ELM327)
+    ///
+    /// # Returns
+    /// Everything read including the terminator, or whatever was read before the timeout if the terminator never arrived
+    fn read_until(&mut self, terminator: u8, timeout: Duration) -> Result<Vec<u8>, ComServerError> {
+        let start = Instant::now();
+        let mut res = Vec::new();
+        let mut buf = [0u8; 256];
+        while let Some(left) = timeout.checked_sub(start.elapsed()) {
+            let n = self.read_bytes(&mut buf, left)?;
+            if let Some(pos) = buf[..n].iter().position(|b| *b == terminator) {
+                // Bytes after the terminator belong to the next response, which is not expected
+                // until this one has been handled, so they are dropped
+                res.extend_from_slice(&buf[..=pos]);
+                return Ok(res)
+            }
+            res.extend_from_slice(&buf[..n]);
+        }
+        Ok(res)
+    }
+}
+
+/// Serial port found on the system
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SerialPortDesc {
+    /// Name to open the port with. Example: `COM3` or `/dev/ttyUSB0`
+    pub path: String,
+    /// USB vendor and product ID, if the port is a USB device
+    pub usb_id: Option<(u16, u16)>,
+    /// Manufacturer and product name reported by the device, if any
+    pub description: Option<String>,
+}
+
+/// Lists the serial ports on the system, so the user can pick the one their adapter is on
+pub fn list_serial_ports() -> Result<Vec<SerialPortDesc>, ComServerError> {
+    let ports = serialport::available_ports().map_err(serial_error)?;
+    Ok(ports.into_iter().map(|p| match p.port_type {
+        SerialPortType::UsbPort(usb) => {
+            let description = match (usb.manufacturer, usb.product) {
+                (Some(m), Some(p)) => Some(format!("{} {}", m, p)),
+                (m, p) => m.or(p)
+            };
+            SerialPortDesc { path: p.port_name, usb_id: Some((usb.vid, usb.pid)), description }
+        },
+        _ => SerialPortDesc { path: p.port_name, usb_id: None, description: None }
+    }).collect())
+}
+
+/// Settings used to open a serial port
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SerialConfig {
+    pub path: String,
+    /// Baud rate. ELM327 clones default to 38400, some are set to 115200
+    pub baud: u32,
+    /// State of the DTR line once opened. Some adapters are held in reset while DTR is low
+    pub dtr: bool,
+    /// State of the RTS line once opened
+    pub rts: bool,
+}
+
+impl SerialConfig {
+    pub fn new(path: &str, baud: u32) -> Self {
+        Self { path: path.into(), baud, dtr: true, rts: true }
+    }
+}
+
+fn serial_error(e: serialport::Error) -> ComServerError {
+    let err_code = match e.kind() {
+        serialport::ErrorKind::NoDevice => ERR_DEVICE_LOST,
+        _ => ERR_FAILED
+    };
+    ComServerError { err_code, err_desc: e.to_string() }
+}
+
+fn io_error(e: std::io::Error) -> ComServerError {
+    let err_code = match e.kind() {
+        // Adapter was unplugged
+        ErrorKind::BrokenPipe | ErrorKind::NotConnected | ErrorKind::PermissionDenied => ERR_DEVICE_LOST,
+        _ => ERR_FAILED
+    };
+    ComServerError { err_code, err_desc: e.to_string() }
+}
+
+/// [ByteTransport] over a serial port, such as a USB serial adapter
+pub struct SerialTransport {
+    port: Box<dyn SerialPort>,
+}
+
+impl std::fmt::Debug for SerialTransport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SerialTransport ({})", self.port.name().unwrap_or_else(|| "Unnamed".into()))
+    }
+}
+
+impl SerialTransport {
+    /// Opens a serial port, setting DTR and RTS as configured
+    pub fn open(cfg: &SerialConfig) -> Result<Self, ComServerError> {
+        let port = serialport::new(&cfg.path, cfg.baud)
+            .timeout(Duration::from_millis(100))
+            .open()
+            .map_err(serial_error)?;
+        let mut res = Self::from_port(port);
+        res.set_dtr(cfg.dtr)?;
+        res.set_rts(cfg.rts)?;
+        Ok(res)
+    }
+
+    /// Uses a port which has already been opened
+    pub fn from_port(port: Box<dyn SerialPort>) -> Self {
+        Self { port }
+    }
+
+    pub fn set_dtr(&mut self, level: bool) -> Result<(), ComServerError> {
+        self.port.write_data_terminal_ready(level).map_err(serial_error)
+    }
+
+    pub fn set_rts(&mut self, level: bool) -> Result<(), ComServerError> {
+        self.port.write_request_to_send(level).map_err(serial_error)
+    }
+
+    pub fn set_baud(&mut self, baud: u32) -> Result<(), ComServerError> {
+        self.port.set_baud_rate(baud).map_err(serial_error)
+    }
+
+    /// Starts or stops sending a break, which holds the TX line low
+    pub fn set_break(&mut self, on: bool) -> Result<(), ComServerError> {
+        let res = if on { self.port.set_break() } else { self.port.clear_break() };
+        res.map_err(serial_error)
+    }
+}
+
+impl ByteTransport for SerialTransport {
+    fn write_bytes(&mut self, data: &[u8]) -> Result<(), ComServerError> {
+        self.port.write_all(data).map_err(io_error)?;
+        self.port.flush().map_err(io_error)
+    }
+
+    fn read_bytes(&mut self, buf: &mut [u8], timeout: Duration) -> Result<usize, ComServerError> {
+        if buf.is_empty() {
+            return Ok(0)
+        }
+        // A zero timeout would block forever on some platforms
+        self.port.set_timeout(std::cmp::max(timeout, Duration::from_millis(1))).map_err(serial_error)?;
+        match self.port.read(buf) {
+            Ok(n) => Ok(n),
+            Err(e) if e.kind() == ErrorKind::TimedOut || e.kind() == ErrorKind::WouldBlock => Ok(0),
+            Err(e) if e.kind() == ErrorKind::Interrupted => Ok(0),
+            Err(e) => Err(io_error(e))
+        }
+    }
+
+    fn clear_input(&mut self) -> Result<(), ComServerError> {
+        self.port.clear(ClearBuffer::Input).map_err(serial_error)
+    }
+}
+
+/// Opens both ends of a loopback for testing. A pseudo terminal pair is used, unless
+/// `OVD_SERIAL_LOOPBACK` names a port with its TX and RX wired together.
+/// None if neither is available, so the test can be skipped
+#[cfg(test)]
+fn open_loopback() -> Option<(SerialTransport, SerialTransport)> {
+    if let Ok(path) = std::env::var("OVD_SERIAL_LOOPBACK") {
+        let port = SerialTransport::open(&SerialConfig::new(&path, 115_200)).ok()?;
+        let other = SerialTransport::from_port(port.port.try_clone().ok()?);
+        return Some((port, other))
+    }
+    #[cfg(unix)]
+    let pair = serialport::TTYPort::pair().ok()
+        .map(|(a, b)| (SerialTransport::from_port(Box::new(a)), SerialTransport::from_port(Box::new(b))));
+    #[cfg(not(unix))]
+    let pair = None;
+    pair
+}
+
+#[test]
+fn test_serial_loopback() {
+    let (mut a, mut b) = match open_loopback() {
+        Some(pair) => pair,
+        None => {
+            eprintln!("No loopback serial port available, skipping");
+            return
+        }
+    };
+    a.clear_input().unwrap();
+    b.clear_input().unwrap();
+
+    // Nothing sent, so the read times out
+    let mut buf = [0u8; 16];
+    let start = Instant::now();
+    assert_eq!(b.read_bytes(&mut buf, Duration::from_millis(50)).unwrap(), 0);
+    assert!(start.elapsed() >= Duration::from_millis(40));
+
+    a.write_bytes(b"ATZ\r").unwrap();
+    assert_eq!(b.read_until(b'\r', Duration::from_millis(1000)).unwrap(), b"ATZ\r".to_vec());
+
+    // Response arriving in pieces is read until the prompt
+    b.write_bytes(b"ELM327 v1.5\r").unwrap();
+    b.write_bytes(b"\r>").unwrap();
+    assert_eq!(a.read_until(b'>', Duration::from_millis(1000)).unwrap(), b"ELM327 v1.5\r\r>".to_vec());
+
+    // Terminator never arrives
+    a.write_bytes(b"41 0C").unwrap();
+    assert_eq!(b.read_until(b'>', Duration::from_millis(100)).unwrap(), b"41 0C".to_vec());
+}
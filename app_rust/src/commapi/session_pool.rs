@@ -0,0 +1,361 @@
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicBool, Ordering::Relaxed};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use crate::commapi::protocols::{ProtocolError, ProtocolResult, ProtocolServer};
+use crate::commapi::protocols::uds::{UDSCommand, UDSECU};
+
+/// Change in the sessions held by a [SessionPool]
+#[derive(Debug, Clone, PartialEq)]
+pub enum PoolEvent {
+    /// Session was added to the pool
+    Added(String),
+    /// Session was removed after failing a health check. The session has been closed
+    Evicted { name: String, reason: String },
+    /// Active session changed. None if the pool is empty
+    ActiveChanged(Option<String>),
+}
+
+struct PoolState {
+    /// Sessions in the order they were added
+    sessions: Vec<(String, UDSECU)>,
+    active: Option<String>,
+}
+
+struct PoolInner {
+    state: Mutex<PoolState>,
+    /// Held for every request made through the pool, so only one session uses the adapter at a time.
+    /// Not held by the sessions' tester present threads
+    bus: Mutex<()>,
+    max_sessions: usize,
+    listeners: Mutex<Vec<Arc<dyn Fn(&PoolEvent) + Send + Sync>>>,
+    /// Health check thread, and the flag which keeps it running
+    health_check_thread: Mutex<Option<(Arc<AtomicBool>, JoinHandle<()>)>>,
+}
+
+impl PoolInner {
+    fn notify(&self, event: PoolEvent) {
+        // Copied so a listener can use the pool without deadlocking
+        let listeners = self.listeners.lock().unwrap().clone();
+        for l in listeners {
+            l(&event)
+        }
+    }
+
+    /// Stops the health check thread and waits for it to finish. If this is called from the
+    /// health check thread (By a listener, or dropping the pool), it finishes after the current check
+    fn stop_health_checks(&self) {
+        let thread = self.health_check_thread.lock().unwrap().take();
+        if let Some((running, handle)) = thread {
+            running.store(false, Relaxed);
+            if handle.thread().id() != std::thread::current().id() {
+                let _ = handle.join();
+            }
+        }
+    }
+}
+
+/// Named diagnostic sessions which are kept open together, such as one per vehicle in a
+/// multi-bay workshop, with one of them active in the UI.
+///
+/// Every request made through the pool ([with_session](fn@SessionPool::with_session) and the
+/// health checks) holds a single bus lock, so sessions which share one adapter never use it at
+/// the same time. Requests made on a session directly, outside of the pool, are not serialized.
+///
+/// Tester present is the exception. Each session keeps its own tester present thread, which sends
+/// on the adapter without taking the bus lock, so one session's keep-alive can be sent in the middle
+/// of another session's request. It is sent with the suppress positive response bit set, so no
+/// response to it is read as the response to the other request.
+///
+/// Clones of the pool share the same sessions
+#[derive(Clone)]
+pub struct SessionPool {
+    inner: Arc<PoolInner>,
+}
+
+impl std::fmt::Debug for SessionPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SessionPool ({}/{} sessions)", self.len(), self.inner.max_sessions)
+    }
+}
+
+impl SessionPool {
+    /// # Params
+    /// * max_sessions - Most sessions the pool will hold at once
+    pub fn new(max_sessions: usize) -> Self {
+        Self {
+            inner: Arc::new(PoolInner {
+                state: Mutex::new(PoolState { sessions: Vec::new(), active: None }),
+                bus: Mutex::new(()),
+                max_sessions,
+                listeners: Mutex::new(Vec::new()),
+                health_check_thread: Mutex::new(None),
+            })
+        }
+    }
+
+    /// Adds a listener which is sent every [PoolEvent]. Events are sent from whichever thread
+    /// caused them (Including the health check thread), so listeners should return quickly
+    pub fn add_listener<F: Fn(&PoolEvent) + Send + Sync + 'static>(&self, listener: F) {
+        self.inner.listeners.lock().unwrap().push(Arc::new(listener))
+    }
+
+    /// Adds a connected session to the pool. The first session added becomes the active session.
+    ///
+    /// Returns [ProtocolError::InvalidRequest] if the pool is full or already has a session with the name
+    pub fn add(&self, name: &str, session: UDSECU) -> ProtocolResult<()> {
+        let became_active = {
+            let mut state = self.inner.state.lock().unwrap();
+            if state.sessions.iter().any(|(n, _)| n == name) {
+                return Err(ProtocolError::InvalidRequest(format!("Pool already has a session named {}", name)))
+            }
+            if state.sessions.len() >= self.inner.max_sessions {
+                return Err(ProtocolError::InvalidRequest(format!("Pool is full ({} sessions)", self.inner.max_sessions)))
+            }
+            state.sessions.push((name.into(), session));
+            let became_active = state.active.is_none();
+            if became_active {
+                state.active = Some(name.into());
+            }
+            became_active
+        };
+        self.inner.notify(PoolEvent::Added(name.into()));
+        if became_active {
+            self.inner.notify(PoolEvent::ActiveChanged(Some(name.into())));
+        }
+        Ok(())
+    }
+
+    /// Closes a session and removes it from the pool
+    ///
+    /// # Returns
+    /// False if there is no session with the name
+    pub fn remove(&self, name: &str) -> bool {
+        self.take(name).map(|mut s| s.exit_diag_session()).is_some()
+    }
+
+    /// Removes a session from the pool, moving the active session to the first remaining session if it was active
+    fn take(&self, name: &str) -> Option<UDSECU> {
+        let (session, active) = {
+            let mut state = self.inner.state.lock().unwrap();
+            let pos = state.sessions.iter().position(|(n, _)| n == name)?;
+            let (_, session) = state.sessions.remove(pos);
+            if state.active.as_deref() != Some(name) {
+                return Some(session)
+            }
+            state.active = state.sessions.first().map(|(n, _)| n.clone());
+            (session, state.active.clone())
+        };
+        self.inner.notify(PoolEvent::ActiveChanged(active));
+        Some(session)
+    }
+
+    /// Names of the sessions in the pool, in the order they were added
+    pub fn names(&self) -> Vec<String> {
+        self.inner.state.lock().unwrap().sessions.iter().map(|(n, _)| n.clone()).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.state.lock().unwrap().sessions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Makes a session the active session, as shown by the UI
+    pub fn set_active(&self, name: &str) -> ProtocolResult<()> {
+        {
+            let mut state = self.inner.state.lock().unwrap();
+            if !state.sessions.iter().any(|(n, _)| n == name) {
+                return Err(ProtocolError::InvalidRequest(format!("Pool has no session named {}", name)))
+            }
+            if state.active.as_deref() == Some(name) {
+                return Ok(())
+            }
+            state.active = Some(name.into());
+        }
+        self.inner.notify(PoolEvent::ActiveChanged(Some(name.into())));
+        Ok(())
+    }
+
+    pub fn active_name(&self) -> Option<String> {
+        self.inner.state.lock().unwrap().active.clone()
+    }
+
+    /// Runs requests on a session, holding the bus lock so no other session in the pool uses the adapter meanwhile
+    pub fn with_session<T, F: FnOnce(&mut UDSECU) -> ProtocolResult<T>>(&self, name: &str, f: F) -> ProtocolResult<T> {
+        let mut session = self.inner.state.lock().unwrap().sessions.iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, s)| s.clone())
+            .ok_or_else(|| ProtocolError::InvalidRequest(format!("Pool has no session named {}", name)))?;
+        let _bus = self.inner.bus.lock().unwrap();
+        f(&mut session)
+    }
+
+    /// Same as [with_session](fn@SessionPool::with_session), using the active session
+    pub fn with_active<T, F: FnOnce(&mut UDSECU) -> ProtocolResult<T>>(&self, f: F) -> ProtocolResult<T> {
+        let name = self.active_name().ok_or_else(|| ProtocolError::InvalidRequest("Pool has no sessions".into()))?;
+        self.with_session(&name, f)
+    }
+
+    /// Sends a TesterPresent to every session which expects a response, and evicts each session
+    /// that does not respond. A negative response still counts as a response, as the ECU is there.
+    ///
+    /// # Returns
+    /// Names of the sessions which were evicted
+    pub fn check_health(&self) -> Vec<String> {
+        let sessions: Vec<(String, UDSECU)> = self.inner.state.lock().unwrap().sessions.clone();
+        let mut evicted = Vec::new();
+        for (name, session) in sessions {
+            let res = {
+                let _bus = self.inner.bus.lock().unwrap();
+                session.run_command(UDSCommand::TesterPresent, &[0x00], 500)
+            };
+            let reason = match res {
+                Ok(_) | Err(ProtocolError::ProtocolError(_)) => continue,
+                Err(e) => e.to_string()
+            };
+            if let Some(mut s) = self.take(&name) {
+                s.exit_diag_session();
+                self.inner.notify(PoolEvent::Evicted { name: name.clone(), reason });
+                evicted.push(name);
+            }
+        }
+        evicted
+    }
+
+    /// Starts a background thread which runs [check_health](fn@SessionPool::check_health) every `interval`.
+    /// The thread stops when [stop_health_checks](fn@SessionPool::stop_health_checks) is called or the
+    /// last clone of the pool is dropped. Does nothing if the thread is already running
+    pub fn start_health_checks(&self, interval: Duration) {
+        let mut thread = self.inner.health_check_thread.lock().unwrap();
+        if thread.is_some() {
+            return
+        }
+        let running = Arc::new(AtomicBool::new(true));
+        let running_t = running.clone();
+        let pool: Weak<PoolInner> = Arc::downgrade(&self.inner);
+        let handle = std::thread::spawn(move || {
+            let mut next = Instant::now() + interval;
+            while running_t.load(Relaxed) {
+                if Instant::now() < next {
+                    std::thread::sleep(Duration::from_millis(10));
+                    continue
+                }
+                match pool.upgrade() {
+                    Some(inner) => SessionPool { inner }.check_health(),
+                    None => return
+                };
+                next = Instant::now() + interval;
+            }
+        });
+        *thread = Some((running, handle));
+    }
+
+    /// Stops the health check thread, waiting for a check in progress to finish
+    pub fn stop_health_checks(&self) {
+        self.inner.stop_health_checks()
+    }
+}
+
+impl Drop for PoolInner {
+    fn drop(&mut self) {
+        self.stop_health_checks()
+    }
+}
+
+#[test]
+fn test_evict_dead_session() {
+    use crate::commapi::protocols::uds::start_mock_session;
+
+    let engine_alive = Arc::new(AtomicBool::new(true));
+    let alive = engine_alive.clone();
+    let (_engine_mock, engine) = start_mock_session(move |req| match req {
+        [0x3E, 0x00] if alive.load(Relaxed) => Some(vec![0x7E, 0x00]),
+        [0x22, 0xF1, 0x90] => Some(vec![0x62, 0xF1, 0x90, 0x01]),
+        _ => None
+    });
+    // Rejects TesterPresent, but is still there
+    let (_gearbox_mock, gearbox) = start_mock_session(|req| match req {
+        [0x3E, 0x00] => Some(vec![0x7F, 0x3E, 0x22]),
+        [0x22, 0xF1, 0x90] => Some(vec![0x62, 0xF1, 0x90, 0x02]),
+        _ => None
+    });
+    let (_spare_mock, spare) = start_mock_session(|_| None);
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let events_t = events.clone();
+    let pool = SessionPool::new(2);
+    pool.add_listener(move |e| events_t.lock().unwrap().push(e.clone()));
+    pool.add("Engine", engine).unwrap();
+    pool.add("Gearbox", gearbox.clone()).unwrap();
+    assert!(matches!(pool.add("Spare", spare), Err(ProtocolError::InvalidRequest(_))));
+    assert!(matches!(pool.add("Engine", gearbox), Err(ProtocolError::InvalidRequest(_))));
+    assert_eq!(pool.active_name().as_deref(), Some("Engine"));
+    assert_eq!(pool.with_active(|ecu| ecu.read_data_by_id(0xF190)).unwrap(), vec![0x01]);
+
+    assert!(pool.check_health().is_empty());
+    assert_eq!(pool.len(), 2);
+
+    // Engine stops responding
+    engine_alive.store(false, Relaxed);
+    assert_eq!(pool.check_health(), vec!["Engine".to_string()]);
+    assert_eq!(pool.names(), vec!["Gearbox".to_string()]);
+    assert_eq!(pool.active_name().as_deref(), Some("Gearbox"));
+    assert_eq!(pool.with_active(|ecu| ecu.read_data_by_id(0xF190)).unwrap(), vec![0x02]);
+    assert!(pool.with_session("Engine", |ecu| ecu.read_data_by_id(0xF190)).is_err());
+    assert!(pool.set_active("Engine").is_err());
+
+    let events = events.lock().unwrap();
+    assert_eq!(&events[..3], &[
+        PoolEvent::Added("Engine".into()),
+        PoolEvent::ActiveChanged(Some("Engine".into())),
+        PoolEvent::Added("Gearbox".into()),
+    ]);
+    assert_eq!(events[3], PoolEvent::ActiveChanged(Some("Gearbox".into())));
+    assert!(matches!(&events[4], PoolEvent::Evicted { name, .. } if name == "Engine"));
+    assert_eq!(events.len(), 5);
+}
+
+#[test]
+fn test_background_health_checks() {
+    use crate::commapi::protocols::uds::start_mock_session;
+
+    let (_mock, ecu) = start_mock_session(|_| None);
+    let pool = SessionPool::new(4);
+    let (tx, rx) = std::sync::mpsc::channel();
+    let tx = Mutex::new(tx);
+    pool.add_listener(move |e| if let PoolEvent::Evicted { name, .. } = e { let _ = tx.lock().unwrap().send(name.clone()); });
+    pool.add("Engine", ecu).unwrap();
+    pool.start_health_checks(Duration::from_millis(50));
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), "Engine");
+    assert!(pool.is_empty());
+    assert_eq!(pool.active_name(), None);
+    pool.stop_health_checks();
+}
+
+#[test]
+fn test_restart_health_checks() {
+    use crate::commapi::protocols::uds::start_mock_session;
+
+    let (mock, ecu) = start_mock_session(|req| match req {
+        [0x3E, 0x00] => Some(vec![0x7E, 0x00]),
+        _ => None
+    });
+    let pool = SessionPool::new(1);
+    pool.add("Engine", ecu).unwrap();
+    let checks = || mock.get_iso15765_tx_log().iter().filter(|r| r.data == [0x3E, 0x00]).count();
+    for _ in 0..3 {
+        pool.start_health_checks(Duration::from_millis(20));
+        pool.start_health_checks(Duration::from_millis(20)); // Already running
+        std::thread::sleep(Duration::from_millis(100));
+        pool.stop_health_checks();
+        // Thread has finished, so no more checks are sent, even after it is restarted
+        let sent = checks();
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(checks(), sent);
+    }
+    assert!(checks() > 0);
+    assert_eq!(pool.len(), 1);
+}
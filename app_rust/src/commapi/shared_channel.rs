@@ -0,0 +1,233 @@
+use crate::commapi::comm_api::{ComServer, CanFrame, ComServerError, FilterType};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+struct Subscription {
+    id: u32,
+    can_ids: Vec<u32>,
+    filter_ids: Vec<u32>,
+    queue: Sender<CanFrame>,
+}
+
+impl Subscription {
+    fn wants(&self, frame: &CanFrame) -> bool {
+        self.can_ids.is_empty() || self.can_ids.contains(&frame.id)
+    }
+}
+
+struct SharedInner {
+    server: Box<dyn ComServer>,
+    subs: Vec<Subscription>,
+    next_sub_id: u32,
+}
+
+impl SharedInner {
+    /// Reads everything waiting in the adapter's Rx queue and hands each frame
+    /// to every subscriber that has subscribed to its CAN ID
+    fn pump(&mut self) -> Result<(), ComServerError> {
+        let frames = self.server.read_can_packets(0, 100)?;
+        for f in frames {
+            for s in self.subs.iter().filter(|s| s.wants(&f)) {
+                // Subscriber might be mid-drop, ignore
+                let _ = s.queue.send(f);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Allows multiple consumers (For example the CAN Tracer and a diagnostic session)
+/// to share a single CAN channel on one adapter.
+///
+/// Every call to the adapter is serialized through a single mutex. Received frames
+/// are not read by a background thread, instead whichever subscriber is reading pumps
+/// the adapter's Rx queue and distributes frames to every subscriber by CAN ID.
+///
+/// Tradeoffs of this approach:
+/// * Frames pile up in a subscriber's queue until it reads them, so a subscriber that
+/// never reads will keep growing its queue. Drop the [ChannelSubscriber] when it is no longer needed.
+/// * Latency of a read depends on how long other subscribers hold the lock. Each lock is
+/// only held for a single adapter call, so no subscriber can starve another, but a slow
+/// adapter call (Large send with a long timeout) delays everyone else.
+/// * A subscriber with no CAN IDs receives a copy of every frame, it does not take them away
+/// from other subscribers.
+#[derive(Clone)]
+pub struct SharedChannel {
+    inner: Arc<Mutex<SharedInner>>,
+}
+
+impl std::fmt::Debug for SharedChannel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SharedChannel ({} subscribers)", self.inner.lock().unwrap().subs.len())
+    }
+}
+
+impl SharedChannel {
+    /// Wraps a comm server which already has an open CAN interface
+    pub fn new(server: Box<dyn ComServer>) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(SharedInner {
+                server,
+                subs: Vec::new(),
+                next_sub_id: 0,
+            }))
+        }
+    }
+
+    /// Subscribes to a list of CAN IDs. A pass filter is added to the adapter for each ID.
+    ///
+    /// # Params
+    /// * can_ids - CAN IDs to receive. An empty list subscribes to every frame on the bus
+    pub fn subscribe(&self, can_ids: &[u32]) -> Result<ChannelSubscriber, ComServerError> {
+        let mut inner = self.inner.lock().unwrap();
+        let mut filter_ids = Vec::new();
+        if can_ids.is_empty() {
+            filter_ids.push(inner.server.add_can_filter(FilterType::Pass, 0x0000, 0x0000)?);
+        } else {
+            for id in can_ids {
+                match inner.server.add_can_filter(FilterType::Pass, *id, 0xFFFF_FFFF) {
+                    Ok(f) => filter_ids.push(f),
+                    Err(e) => {
+                        for f in filter_ids {
+                            let _ = inner.server.rem_can_filter(f);
+                        }
+                        return Err(e)
+                    }
+                }
+            }
+        }
+        let (tx, rx) = channel();
+        let id = inner.next_sub_id;
+        inner.next_sub_id += 1;
+        inner.subs.push(Subscription {
+            id,
+            can_ids: Vec::from(can_ids),
+            filter_ids,
+            queue: tx,
+        });
+        Ok(ChannelSubscriber {
+            id,
+            channel: self.clone(),
+            queue: rx,
+        })
+    }
+
+    /// Runs a function with the wrapped comm server, for calls which are not about
+    /// CAN frames (Battery voltage, capabilities...)
+    pub(crate) fn with_server<T, F: FnOnce(&dyn ComServer) -> T>(&self, f: F) -> T {
+        f(self.inner.lock().unwrap().server.as_ref())
+    }
+
+    fn unsubscribe(&self, id: u32) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(pos) = inner.subs.iter().position(|s| s.id == id) {
+            let sub = inner.subs.remove(pos);
+            for f in sub.filter_ids {
+                if let Err(e) = inner.server.rem_can_filter(f) {
+                    eprintln!("Cannot remove CAN filter {} {}", f, e)
+                }
+            }
+        }
+    }
+}
+
+/// Handle to a [SharedChannel] which only receives the CAN IDs it subscribed to
+pub struct ChannelSubscriber {
+    id: u32,
+    channel: SharedChannel,
+    queue: Receiver<CanFrame>,
+}
+
+impl ChannelSubscriber {
+    /// Sends frames to the CAN network. See [ComServer::send_can_packets](fn@ComServer::send_can_packets)
+    pub fn send_can_packets(&self, data: &[CanFrame], timeout_ms: u32) -> Result<usize, ComServerError> {
+        self.channel.inner.lock().unwrap().server.send_can_packets(data, timeout_ms)
+    }
+
+    /// Reads frames for the subscribed CAN IDs.
+    ///
+    /// # Params
+    /// * timeout_ms - Max time to wait for at least 1 frame. A value of 0 returns
+    /// instantly with what is already available
+    /// * max_msgs - The maximum number of frames to return
+    pub fn read_can_packets(&self, timeout_ms: u32, max_msgs: usize) -> Result<Vec<CanFrame>, ComServerError> {
+        let start = std::time::Instant::now();
+        let mut res = Vec::new();
+        loop {
+            self.channel.inner.lock().unwrap().pump()?;
+            while res.len() < max_msgs {
+                match self.queue.try_recv() {
+                    Ok(f) => res.push(f),
+                    Err(_) => break
+                }
+            }
+            if !res.is_empty() || start.elapsed().as_millis() >= timeout_ms as u128 {
+                return Ok(res)
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+    }
+}
+
+impl Drop for ChannelSubscriber {
+    fn drop(&mut self) {
+        self.channel.unsubscribe(self.id)
+    }
+}
+
+#[test]
+fn test_shared_channel_subscribers() {
+    use crate::commapi::iso_tp::{encode_payload, flow_control_frame, FlowStatus, IsoTpDecoder, IsoTpOptions, RxResult};
+    let opts = IsoTpOptions::default();
+    // ECU answers a VIN request with a multi frame response, sending the consecutive frames after flow control
+    let mut vin_resp = vec![0x62, 0xF1, 0x90];
+    vin_resp.extend_from_slice(b"WDD2050081R123456");
+    let resp_frames = encode_payload(0x07E8, &vin_resp, &opts).unwrap();
+    let mut mock = crate::commapi::mock_api::MockComServer::new();
+    mock.set_responder(move |f| match (f.id, f.get_data().first()) {
+        (0x07E0, Some(&0x03)) if f.get_data()[1..4] == [0x22, 0xF1, 0x90] => vec![resp_frames[0]],
+        (0x07E0, Some(&0x30)) => resp_frames[1..].to_vec(),
+        _ => Vec::new()
+    });
+    for i in 0..20u8 {
+        mock.push_rx(CanFrame::new(0x0100, &[i]));
+    }
+    let shared = SharedChannel::new(Box::new(mock.clone()));
+    let tracer = shared.subscribe(&[0x0100]).unwrap();
+    let uds = shared.subscribe(&[0x07E8]).unwrap();
+
+    let tracer_t = std::thread::spawn(move || {
+        let mut frames = Vec::new();
+        let start = std::time::Instant::now();
+        while frames.len() < 20 && start.elapsed().as_millis() < 1000 {
+            frames.append(&mut tracer.read_can_packets(10, 5).unwrap());
+        }
+        frames
+    });
+    let uds_t = std::thread::spawn(move || {
+        uds.send_can_packets(&encode_payload(0x07E0, &[0x22, 0xF1, 0x90], &opts).unwrap(), 0).unwrap();
+        let mut decoder = IsoTpDecoder::with_options(&opts);
+        let start = std::time::Instant::now();
+        while start.elapsed().as_millis() < 1000 {
+            for f in uds.read_can_packets(10, 1).unwrap() {
+                match decoder.on_frame(&f).unwrap() {
+                    RxResult::Complete(payload) => return payload,
+                    RxResult::FlowControlRequired => {
+                        uds.send_can_packets(&[flow_control_frame(0x07E0, FlowStatus::ContinueToSend, 0, 0, &opts)], 0).unwrap();
+                    },
+                    RxResult::Pending => {}
+                }
+            }
+        }
+        panic!("No response to the VIN request")
+    });
+    let tracer_frames = tracer_t.join().unwrap();
+    assert_eq!(uds_t.join().unwrap(), vin_resp);
+
+    assert_eq!(tracer_frames.len(), 20);
+    assert!(tracer_frames.iter().all(|f| f.id == 0x0100));
+    // Order is preserved per subscriber
+    assert!(tracer_frames.iter().enumerate().all(|(i, f)| f.get_data()[0] == i as u8));
+    // Request and flow control both went out on the shared channel
+    assert_eq!(mock.get_tx_log().iter().map(|f| f.get_data()[0]).collect::<Vec<u8>>(), vec![0x03, 0x30]);
+}
@@ -0,0 +1,164 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use serde::Serialize;
+
+/// A single measurement sample
+#[derive(Debug, Clone, Serialize)]
+pub struct Sample {
+    /// Microseconds since the UNIX epoch
+    pub timestamp_us: u64,
+    /// Name or ID of the ECU the sample was read from
+    pub source: String,
+    /// Name of the signal
+    pub signal: String,
+    pub value: f32,
+    pub unit: String,
+}
+
+/// Records measurements from one or more ECUs on a common clock, which can
+/// then be exported as CSV or newline delimited JSON.
+///
+/// Timestamps are taken from a monotonic clock, offset by the wall clock time
+/// when the logger was created, so they are always increasing even if the system
+/// clock is changed mid session.
+#[derive(Debug, Clone)]
+pub struct DataLogger {
+    start: Instant,
+    start_us: u64,
+    samples: Vec<Sample>,
+}
+
+impl DataLogger {
+    pub fn new() -> Self {
+        let start_us = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_micros() as u64).unwrap_or(0);
+        Self {
+            start: Instant::now(),
+            start_us,
+            samples: Vec::new(),
+        }
+    }
+
+    /// Logs a sample, timestamped with the current time
+    ///
+    /// # Params
+    /// * source - ECU the sample is from
+    /// * signal - Name of the signal
+    /// * value - Value of the signal
+    /// * unit - Unit of the value
+    pub fn log(&mut self, source: &str, signal: &str, value: f32, unit: &str) {
+        let timestamp_us = self.start_us + self.start.elapsed().as_micros() as u64;
+        self.log_at(timestamp_us, source, signal, value, unit)
+    }
+
+    /// Logs a sample with an explicit timestamp (Microseconds since the UNIX epoch)
+    pub fn log_at(&mut self, timestamp_us: u64, source: &str, signal: &str, value: f32, unit: &str) {
+        self.samples.push(Sample {
+            timestamp_us,
+            source: source.into(),
+            signal: signal.into(),
+            value,
+            unit: unit.into(),
+        })
+    }
+
+    pub fn get_samples(&self) -> &[Sample] {
+        &self.samples
+    }
+
+    /// Removes all buffered samples
+    pub fn clear(&mut self) {
+        self.samples.clear()
+    }
+
+    /// Returns the list of columns (source.signal (unit)) in the order signals first appeared
+    fn get_columns(&self) -> Vec<(String, String)> {
+        let mut columns: Vec<(String, String)> = Vec::new();
+        for s in &self.samples {
+            if !columns.iter().any(|(src, sig)| src == &s.source && sig == &s.signal) {
+                columns.push((s.source.clone(), s.signal.clone()));
+            }
+        }
+        columns
+    }
+
+    /// Writes all buffered samples as CSV, with one row per timestamp and one column per signal.
+    ///
+    /// If a signal has no sample at a timestamp, its cell is left empty
+    pub fn write_csv<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        let columns = self.get_columns();
+        let mut header = String::from("timestamp_us");
+        for (src, sig) in &columns {
+            let unit = self.samples.iter().find(|s| &s.source == src && &s.signal == sig).map(|s| s.unit.as_str()).unwrap_or("");
+            header.push_str(&format!(",{}", escape_csv(&format!("{}.{} ({})", src, sig, unit))));
+        }
+        writeln!(writer, "{}", header)?;
+
+        let mut rows: BTreeMap<u64, Vec<Option<f32>>> = BTreeMap::new();
+        for s in &self.samples {
+            let idx = columns.iter().position(|(src, sig)| src == &s.source && sig == &s.signal).unwrap();
+            rows.entry(s.timestamp_us).or_insert_with(|| vec![None; columns.len()])[idx] = Some(s.value);
+        }
+        for (ts, values) in rows {
+            let mut line = ts.to_string();
+            for v in values {
+                line.push(',');
+                if let Some(x) = v {
+                    line.push_str(&x.to_string());
+                }
+            }
+            writeln!(writer, "{}", line)?;
+        }
+        Ok(())
+    }
+
+    /// Writes all buffered samples as newline delimited JSON, one object per sample
+    pub fn write_ndjson<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        for s in &self.samples {
+            writeln!(writer, "{}", serde_json::to_string(s)?)?;
+        }
+        Ok(())
+    }
+}
+
+fn escape_csv(s: &str) -> String {
+    if s.contains(',') || s.contains('"') {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.into()
+    }
+}
+
+#[test]
+fn test_csv_output() {
+    let mut logger = DataLogger::new();
+    logger.log_at(1000, "ECM", "RPM", 800.0, "rpm");
+    logger.log_at(1000, "EGS", "Gear", 1.0, "");
+    logger.log_at(2000, "ECM", "RPM", 850.5, "rpm");
+    logger.log_at(3000, "EGS", "Gear", 2.0, "");
+    logger.log_at(3000, "ECM", "Coolant", 90.0, "C"); // Appears mid session
+
+    let mut out = Vec::new();
+    logger.write_csv(&mut out).unwrap();
+    let lines: Vec<String> = String::from_utf8(out).unwrap().lines().map(String::from).collect();
+    assert_eq!(lines[0], "timestamp_us,ECM.RPM (rpm),EGS.Gear (),ECM.Coolant (C)");
+    assert_eq!(lines[1], "1000,800,1,");
+    assert_eq!(lines[2], "2000,850.5,,");
+    assert_eq!(lines[3], "3000,,2,90");
+}
+
+#[test]
+fn test_ndjson_output() {
+    let mut logger = DataLogger::new();
+    logger.log("ECM", "RPM", 800.0, "rpm");
+    logger.log("EGS", "Gear", 1.0, "");
+    logger.log("ECM", "RPM", 810.0, "rpm");
+
+    let mut out = Vec::new();
+    logger.write_ndjson(&mut out).unwrap();
+    let lines: Vec<serde_json::Value> = String::from_utf8(out).unwrap().lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+    assert_eq!(lines.len(), 3);
+    assert_eq!(lines[1]["source"], "EGS");
+    assert_eq!(lines[2]["value"], 810.0);
+    assert!(lines[2]["timestamp_us"].as_u64().unwrap() >= lines[0]["timestamp_us"].as_u64().unwrap());
+}
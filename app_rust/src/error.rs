@@ -0,0 +1,75 @@
+use common::measurement::ScaleError;
+use common::raf::RafError;
+use thiserror::Error;
+use crate::commapi::comm_api::ComServerError;
+use crate::commapi::protocols::ProtocolError;
+
+/// Any error from the layers below the UI, so application code can use `?` on
+/// reads, adapter calls and ECU requests alike. Each module keeps its own error type,
+/// which converts into this one
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Data error: {0}")]
+    Raf(#[from] RafError),
+    /// Error from the adapter that was not part of an ECU request
+    #[error("Adapter error: {0}")]
+    ComServer(#[from] ComServerError),
+    #[error(transparent)]
+    Protocol(#[from] ProtocolError),
+    /// ECU definition or saved file could not be parsed
+    #[error("Parse error: {0}")]
+    Parse(#[from] serde_json::Error),
+    #[error(transparent)]
+    Scale(#[from] ScaleError),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[test]
+fn test_error_conversions() {
+    use crate::commapi::comm_api::CanError;
+    use crate::commapi::protocols::CommandError;
+    use crate::commapi::protocols::uds::UDSNegativeCode;
+
+    /// Propagates a module error with `?`, as application code would
+    fn propagate<E>(e: E) -> Result<()> where Error: From<E> {
+        let res: std::result::Result<(), E> = Err(e);
+        res?;
+        Ok(())
+    }
+    fn parse() -> Result<()> {
+        common::schema::SchemaV1::from_json("{")?;
+        Ok(())
+    }
+    fn io() -> Result<()> {
+        std::fs::read("/this/file/does/not/exist")?;
+        Ok(())
+    }
+
+    let e = propagate(RafError::ChecksumMismatch { expected: 0x1234, actual: 0xABCD }).unwrap_err();
+    assert!(matches!(e, Error::Raf(RafError::ChecksumMismatch { .. })));
+    assert_eq!(e.to_string(), "Data error: Checksum mismatch, expected 0x00001234 but data has 0x0000ABCD");
+
+    let e = propagate(ComServerError { err_code: 0x08, err_desc: "Device lost".into() }).unwrap_err();
+    assert!(matches!(e, Error::ComServer(_)));
+    assert_eq!(e.to_string(), "Adapter error: Error code 8 (Device lost)");
+
+    let e = propagate(ProtocolError::Timeout).unwrap_err();
+    assert_eq!(e.to_string(), "ECU did not respond in time");
+    let nrc = <UDSNegativeCode as CommandError>::from_byte(0x31);
+    let text = nrc.get_text();
+    let e = propagate(ProtocolError::ProtocolError(Box::new(nrc))).unwrap_err();
+    assert!(matches!(e, Error::Protocol(ProtocolError::ProtocolError(_))));
+    assert_eq!(e.to_string(), format!("ECU rejected the request: {}", text));
+    let e = propagate(ProtocolError::BusError(CanError::BusOff)).unwrap_err();
+    assert_eq!(e.to_string(), CanError::BusOff.to_string());
+
+    let e = parse().unwrap_err();
+    assert!(matches!(e, Error::Parse(_)));
+    assert!(e.to_string().starts_with("Parse error: "));
+
+    assert_eq!(propagate(ScaleError::TooShort { needed: 2, got: 1 }).unwrap_err().to_string(), "Response too short, needed 2 bytes, got 1");
+    assert!(matches!(io().unwrap_err(), Error::Io(_)));
+}
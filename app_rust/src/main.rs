@@ -1,5 +1,7 @@
 use iced::{Application, Settings};
 mod commapi;
+mod data_logger;
+mod error;
 mod passthru;
 mod themes;
 mod windows;
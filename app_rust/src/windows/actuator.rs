@@ -0,0 +1,237 @@
+use iced::{Column, Element, Length, Row, Space, TextInput};
+use common::hex::format_hex;
+use common::schema::SchemaV1;
+use crate::commapi::protocols::ProtocolServer;
+use crate::commapi::protocols::routine::{encode_routine_params, ActuatorTests, StartOutcome};
+use crate::themes::{button_coloured, button_outlined, text, title_text, ButtonType, TextType, TitleSize};
+
+#[derive(Debug, Clone)]
+pub enum ActuatorMessage {
+    OpenDefinition,
+    SelectTest(usize),
+    ParamChanged(usize, String),
+    Start,
+    Confirm,
+    Cancel,
+    Stop,
+    ReadResults,
+}
+
+/// Panel listing the actuator tests from an ECU definition, for running them by hand
+#[derive(Debug, Clone, Default)]
+pub struct ActuatorPanel {
+    tests: ActuatorTests,
+    selected: Option<usize>,
+    param_values: Vec<String>,
+    status: String,
+    error: Option<String>,
+    open_state: iced::button::State,
+    test_states: Vec<iced::button::State>,
+    param_states: Vec<iced::text_input::State>,
+    start_state: iced::button::State,
+    stop_state: iced::button::State,
+    confirm_state: iced::button::State,
+    cancel_state: iced::button::State,
+    results_state: iced::button::State,
+    scroll_state: iced::scrollable::State,
+}
+
+impl ActuatorPanel {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replaces the listed tests with those from a definition.
+    /// The caller must [close](fn@ActuatorPanel::close) the panel first if a test may be running
+    pub fn load_definition(&mut self, model: &SchemaV1) {
+        self.tests = ActuatorTests::from_definition(model);
+        self.test_states = vec![iced::button::State::default(); self.tests.tests().len()];
+        self.selected = None;
+        self.param_values.clear();
+        self.param_states.clear();
+        self.status = format!("{} actuator tests for {}", self.tests.tests().len(), model.ecu_name());
+        self.error = None;
+    }
+
+    /// # Params
+    /// * server - Session to run tests with, or None if there is no ECU connected
+    pub fn update<P: ProtocolServer>(&mut self, msg: &ActuatorMessage, server: Option<&P>) {
+        self.error = None;
+        match msg {
+            ActuatorMessage::OpenDefinition => {
+                if self.tests.running().is_some() {
+                    self.error = Some("Stop the running test before opening another definition".into());
+                    return
+                }
+                if let nfd::Response::Okay(f_path) = nfd::open_file_dialog(Some("json"), None).unwrap_or(nfd::Response::Cancel) {
+                    match std::fs::read_to_string(&f_path).map_err(crate::error::Error::from)
+                        .and_then(|s| SchemaV1::from_json(&s).map_err(crate::error::Error::from)) {
+                        Ok(model) => self.load_definition(&model),
+                        Err(e) => self.error = Some(format!("Cannot open {}: {}", f_path, e))
+                    }
+                }
+            },
+            ActuatorMessage::SelectTest(idx) => {
+                if let Some(def) = self.tests.tests().get(*idx) {
+                    self.param_values = def.params.iter().map(|p| p.default.to_string()).collect();
+                    self.param_states = vec![iced::text_input::State::default(); def.params.len()];
+                    self.selected = Some(*idx);
+                    self.tests.cancel();
+                }
+            },
+            ActuatorMessage::ParamChanged(i, s) => {
+                if let Some(v) = self.param_values.get_mut(*i) {
+                    *v = s.clone();
+                }
+            },
+            ActuatorMessage::Cancel => {
+                self.tests.cancel();
+                self.status = "Test was not started".into();
+            },
+            _ => {
+                let server = match server {
+                    Some(s) => s,
+                    None => {
+                        self.error = Some("Not connected to an ECU".into());
+                        return
+                    }
+                };
+                if let Err(e) = self.run_command(msg, server) {
+                    self.error = Some(e.to_string());
+                }
+            }
+        }
+    }
+
+    fn run_command<P: ProtocolServer>(&mut self, msg: &ActuatorMessage, server: &P) -> crate::error::Result<()> {
+        match msg {
+            ActuatorMessage::Start => {
+                if let Some(idx) = self.selected {
+                    let params = encode_routine_params(&self.tests.tests()[idx], &self.param_values)?;
+                    let outcome = self.tests.start(server, idx, params)?;
+                    self.show_outcome(outcome);
+                }
+            },
+            ActuatorMessage::Confirm => {
+                let outcome = self.tests.confirm(server)?;
+                self.show_outcome(outcome);
+            },
+            ActuatorMessage::Stop => {
+                self.tests.stop(server)?;
+                self.status = "Test stopped".into();
+            },
+            ActuatorMessage::ReadResults => {
+                let res = self.tests.results(server)?;
+                self.status = format!("Results: {}", format_hex(&res, " "));
+            },
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn show_outcome(&mut self, outcome: StartOutcome) {
+        match (outcome, self.tests.running()) {
+            (StartOutcome::Started(status), Some(def)) => self.status = format!("{} running. Status: {}", def.name, format_hex(&status, " ")),
+            (StartOutcome::NeedsConfirmation, _) => self.status = "Confirm the test before it is started".into(),
+            _ => {}
+        }
+    }
+
+    /// Stops the running test. Called when the panel is closed or the ECU is disconnected
+    pub fn close<P: ProtocolServer>(&mut self, server: Option<&P>) {
+        if let Some(s) = server {
+            if let Err(e) = self.tests.close(s) {
+                eprintln!("ERROR stopping actuator test {}", e)
+            }
+        }
+        self.tests.cancel();
+    }
+
+    pub fn view(&mut self) -> Element<ActuatorMessage> {
+        let mut c = Column::new().spacing(5)
+            .push(Row::new().spacing(10)
+                .push(title_text("Actuator tests", TitleSize::P4))
+                .push(button_outlined(&mut self.open_state, "Open definition", ButtonType::Primary).on_press(ActuatorMessage::OpenDefinition)));
+        if !self.status.is_empty() {
+            c = c.push(text(&self.status, TextType::Normal));
+        }
+        if let Some(e) = &self.error {
+            c = c.push(text(e, TextType::Danger));
+        }
+
+        let running = self.tests.running().map(|d| d.id);
+        let mut list = Column::new().spacing(2);
+        for (idx, (def, state)) in self.tests.tests().iter().zip(self.test_states.iter_mut()).enumerate() {
+            let mut label = format!("{} (0x{:04X})", def.name, def.id);
+            if def.dangerous {
+                label.push_str(" !");
+            }
+            let btn_type = if running == Some(def.id) { ButtonType::Success } else if def.dangerous { ButtonType::Warning } else { ButtonType::Secondary };
+            list = list.push(button_outlined(state, &label, btn_type).width(Length::Fill).on_press(ActuatorMessage::SelectTest(idx)));
+        }
+
+        let mut details = Column::new().spacing(5);
+        let tests = &self.tests;
+        if let Some(def) = self.selected.and_then(|idx| tests.tests().get(idx)) {
+            details = details.push(text(&def.name, TextType::Normal));
+            for (i, (p, state)) in def.params.iter().zip(self.param_states.iter_mut()).enumerate() {
+                details = details.push(Row::new().spacing(5)
+                    .push(text(&format!("{} ({} bytes)", p.name, p.len), TextType::Normal).width(Length::FillPortion(1)))
+                    .push(TextInput::new(state, &p.default.to_string(), &self.param_values[i], move |s| ActuatorMessage::ParamChanged(i, s)).width(Length::FillPortion(1)))
+                    .push(text(&p.unit, TextType::Normal)));
+            }
+            if let Some(pending) = self.tests.pending_confirmation() {
+                details = details.push(text(&format!("{} moves or powers parts of the vehicle. Make sure it is safe to run before starting it", pending.name), TextType::Warning))
+                    .push(Row::new().spacing(5)
+                        .push(button_coloured(&mut self.confirm_state, "Start anyway", ButtonType::Danger).on_press(ActuatorMessage::Confirm))
+                        .push(button_outlined(&mut self.cancel_state, "Cancel", ButtonType::Secondary).on_press(ActuatorMessage::Cancel)));
+            } else {
+                details = details.push(button_coloured(&mut self.start_state, "Start", ButtonType::Primary).on_press(ActuatorMessage::Start));
+            }
+        }
+        if running.is_some() {
+            details = details.push(Row::new().spacing(5)
+                .push(button_coloured(&mut self.stop_state, "Stop", ButtonType::Danger).on_press(ActuatorMessage::Stop))
+                .push(button_outlined(&mut self.results_state, "Read results", ButtonType::Secondary).on_press(ActuatorMessage::ReadResults)));
+        }
+
+        c.push(Row::new().spacing(10)
+            .push(iced::scrollable::Scrollable::new(&mut self.scroll_state).push(list).width(Length::FillPortion(1)).height(Length::Shrink))
+            .push(details.width(Length::FillPortion(1))))
+            .push(Space::with_height(Length::Units(5)))
+            .into()
+    }
+}
+
+#[test]
+fn test_panel_stops_test_on_close() {
+    use std::sync::{Arc, Mutex};
+    use crate::commapi::protocols::uds::start_mock_session;
+
+    let model = SchemaV1::from_json(r#"{
+        "meta": { "name": "SAM", "vendor": "Hella", "desc": "Signal acquisition module" },
+        "err_table": [],
+        "comm_data": [],
+        "routines": [{ "id": 768, "name": "Cooling fan", "dangerous": true,
+                       "params": [{ "name": "Speed", "len": 1, "unit": "%", "default": 50 }] }]
+    }"#).unwrap();
+    let sent = Arc::new(Mutex::new(Vec::new()));
+    let sent_t = sent.clone();
+    let (_mock, ecu) = start_mock_session(move |req| {
+        sent_t.lock().unwrap().push(req.to_vec());
+        Some(vec![0x71, req[1], req[2], req[3]])
+    });
+    let mut panel = ActuatorPanel::new();
+    panel.load_definition(&model);
+    panel.update(&ActuatorMessage::SelectTest(0), Some(&ecu));
+    panel.update(&ActuatorMessage::ParamChanged(0, "100".into()), Some(&ecu));
+    panel.update(&ActuatorMessage::Start, Some(&ecu));
+    assert!(sent.lock().unwrap().is_empty());
+    panel.update(&ActuatorMessage::Confirm, Some(&ecu));
+    assert!(panel.error.is_none(), "{:?}", panel.error);
+    assert_eq!(panel.tests.running().unwrap().id, 0x0300);
+
+    panel.close(Some(&ecu));
+    assert!(panel.tests.running().is_none());
+    assert_eq!(*sent.lock().unwrap(), vec![vec![0x31, 0x01, 0x03, 0x00, 0x64], vec![0x31, 0x02, 0x03, 0x00]]);
+}
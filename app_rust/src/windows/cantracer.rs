@@ -1,17 +1,31 @@
 use crate::commapi::comm_api::{ComServer, CanFrame, FilterType};
+use crate::commapi::frame_clock::FrameClock;
+use crate::commapi::pcap::{write_pcap, CapturedFrame};
+use crate::commapi::bus_stats::{compute_stats, BusStats};
 use iced::{Element, Column, Text, Length, Subscription, Row, Checkbox, Color, button};
 use iced::time;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use crate::windows::window::WindowMessage;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::io::Write;
 use crate::themes::{button_coloured, ButtonType};
 
+/// Bitrate the tracer opens the CAN interface at
+const CAN_BAUD: u32 = 500_000;
+
+/// Number of IDs shown in the busiest IDs list
+const TOP_TALKERS: usize = 5;
+
+/// Maximum number of frames kept for exporting. Oldest frames are dropped first
+const MAX_CAPTURE_FRAMES: usize = 100_000;
+
 #[derive(Debug, Clone)]
 pub enum TracerMessage {
     NewData(Instant),
     ConnectCan,
     DisconnectCan,
-    ToggleBinaryMode(bool)
+    ToggleBinaryMode(bool),
+    ExportPcap,
 }
 
 
@@ -20,7 +34,12 @@ pub struct CanTracer {
     server: Box<dyn ComServer>,
     connect_state: button::State,
     disconnect_state: button::State,
+    export_state: button::State,
     can_queue: HashMap<u32, CanFrame>,
+    /// Every frame received since connecting, for exporting
+    capture: VecDeque<CapturedFrame>,
+    /// Timestamps captured frames, using the adapter's timestamps when it provides them
+    clock: FrameClock,
     can_prev: HashMap<u32, CanFrame>,
     is_connected: bool,
     is_binary_fmt: bool,
@@ -33,7 +52,10 @@ impl<'a> CanTracer {
             server,
             connect_state: button::State::default(),
             disconnect_state: button::State::default(),
+            export_state: button::State::default(),
             can_queue: HashMap::new(),
+            capture: VecDeque::new(),
+            clock: FrameClock::new(None),
             can_prev: HashMap::new(),
             is_connected: false,
             is_binary_fmt: false,
@@ -43,10 +65,24 @@ impl<'a> CanTracer {
 
     pub fn insert_frames_to_map(&mut self, frames: Vec<CanFrame>) {
         for f in frames {
+            if self.capture.len() == MAX_CAPTURE_FRAMES {
+                self.capture.pop_front();
+            }
+            self.capture.push_back(self.clock.capture(f));
             self.can_queue.insert(f.id, f);
         }
     }
 
+    /// Computes the frame rates and bus load over the last `window` of the capture
+    pub fn stats(&self, window: Duration) -> BusStats {
+        compute_stats(&self.capture, window, CAN_BAUD)
+    }
+
+    /// Writes every captured frame as a SocketCAN pcap file, which can be opened in Wireshark
+    pub fn export_pcap(&self, writer: impl Write) -> std::io::Result<()> {
+        write_pcap(writer, &self.capture)
+    }
+
     pub fn update(&mut self, msg: &TracerMessage) -> Option<WindowMessage> {
         match msg {
             TracerMessage::NewData(_) => {
@@ -55,10 +91,12 @@ impl<'a> CanTracer {
                 }
             },
             TracerMessage::ConnectCan => {
-                if let Err(e) = self.server.as_mut().open_can_interface(500_000, false) {
+                if let Err(e) = self.server.as_mut().open_can_interface(CAN_BAUD, false) {
                     self.status_text = format!("Error opening CAN Interface {}",  e)
                 } else {
                     self.is_connected = true;
+                    self.capture.clear();
+                    self.clock = FrameClock::new(self.server.timestamp_rollover_us());
                     if let Err(e) = self.server.as_mut().add_can_filter(FilterType::Pass, 0x0000, 0x0000) {
                         self.status_text = format!("Error setting CAN Filter {}",  e)
                     } else if let Err(e) = self.server.send_can_packets(&[CanFrame::new(0x07DF, &[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00])], 0) {
@@ -78,6 +116,15 @@ impl<'a> CanTracer {
             },
             TracerMessage::ToggleBinaryMode(b) => {
                 self.is_binary_fmt = *b
+            },
+            TracerMessage::ExportPcap => {
+                if let nfd::Response::Okay(f_path) = nfd::open_save_dialog(Some("pcap"), None).unwrap_or(nfd::Response::Cancel) {
+                    let res = std::fs::File::create(&f_path).and_then(|f| self.export_pcap(std::io::BufWriter::new(f)));
+                    self.status_text = match res {
+                        Ok(()) => format!("Exported {} frames to {}", self.capture.len(), f_path),
+                        Err(e) => format!("Error exporting capture {}", e)
+                    }
+                }
             }
         }
         None
@@ -91,6 +138,12 @@ impl<'a> CanTracer {
     }
 
     pub fn view(&mut self) -> Element<TracerMessage> {
+        let stats = self.stats(Duration::from_secs(1));
+        let talkers = stats.top_talkers(TOP_TALKERS).iter()
+            .map(|t| format!("{:04X} ({:.0}/s)", t.id, t.frames_per_sec))
+            .collect::<Vec<String>>()
+            .join(", ");
+
         let mut connect_btn = button_coloured(&mut self.connect_state, "Connect", ButtonType::Info);
         let check = self.is_binary_fmt;
         if !self.is_connected {
@@ -102,14 +155,22 @@ impl<'a> CanTracer {
             disconnect_btn = disconnect_btn.on_press(TracerMessage::DisconnectCan)
         }
 
+        let mut export_btn = button_coloured(&mut self.export_state, "Export pcap", ButtonType::Secondary);
+        if !self.capture.is_empty() {
+            export_btn = export_btn.on_press(TracerMessage::ExportPcap)
+        }
+
        Column::new()
            .push(Text::new("CAN Tracer"))
            .push(Row::new()
                .padding(5)
                .spacing(5)
                .push(connect_btn)
-               .push(disconnect_btn))
+               .push(disconnect_btn)
+               .push(export_btn)
+               .push(Text::new(&self.status_text)))
            .push(Checkbox::new(check, "View CAN in Binary", TracerMessage::ToggleBinaryMode))
+           .push(Text::new(format!("{:.0} frames/s, bus load {:.1}%. Busiest IDs: {}", stats.frames_per_sec, stats.bus_load_pct, talkers)))
            .push(Self::build_can_list(&self.is_binary_fmt, &self.can_queue, &mut self.can_prev))
            .into()
     }
@@ -126,7 +187,7 @@ impl<'a> CanTracer {
                 // Old frame exists, try to work out what changed
                 let old_data = old_frame.get_data();
                 for (i, byte) in i.get_data().iter().enumerate() {
-                    container = if *byte == old_data[i] { // Same as old data
+                    container = if Some(byte) == old_data.get(i) { // Same as old data
                         match binary {
                             true => container.push(Row::new().push(Text::new(format!("{:08b}", byte)))), // Cram all binary bits together
                             false => container.push(Row::new().push(Text::new(format!("{:02X}", byte)).width(Length::Units(30))))
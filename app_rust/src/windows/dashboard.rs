@@ -0,0 +1,351 @@
+use std::collections::VecDeque;
+use std::path::Path;
+use iced::{Column, Element, Length, Row, Space};
+use serde::{Deserialize, Serialize};
+use common::measurement::{DidDef, FormattedValue, ScaledValue};
+use common::schema::SchemaV1;
+use crate::error::Result;
+use crate::themes::{button_outlined, container, progress_bar, text, title_text, ButtonType, TextType, TitleSize};
+
+/// Number of samples a graph keeps
+const GRAPH_HISTORY: usize = 60;
+
+/// How a measurement is shown on the dashboard
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WidgetKind {
+    /// Latest value as text
+    Value,
+    /// Latest value as a bar between the widget's min and max
+    Gauge,
+    /// Recent values over time
+    Graph,
+}
+
+fn default_span() -> u16 {
+    1
+}
+
+/// One measurement on a dashboard
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DashboardWidget {
+    pub did: u16,
+    /// Name of the measurement in the definition, as one DID can hold several measurements
+    pub name: String,
+    pub kind: WidgetKind,
+    /// Row of the dashboard the widget is in, from the top
+    pub row: u16,
+    /// Position of the widget within its row, from the left
+    pub col: u16,
+    /// Number of columns the widget is wide
+    #[serde(default = "default_span")]
+    pub span: u16,
+    /// Range of gauges and graphs. If not set, the range of the values read so far is used
+    #[serde(default)]
+    pub min: Option<f64>,
+    #[serde(default)]
+    pub max: Option<f64>,
+}
+
+/// Gauges and graphs a user has set up for an ECU, saved so the dashboard can be
+/// reloaded the next time the vehicle is connected
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DashboardLayout {
+    /// Vehicle the layout was built for
+    pub vehicle: String,
+    /// Name of the ECU definition the layout was built for. Example: EGS52
+    pub ecu: String,
+    pub widgets: Vec<DashboardWidget>,
+}
+
+impl DashboardLayout {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    pub fn from_json(json: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(json)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        Ok(std::fs::write(path, self.to_json())?)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        Ok(Self::from_json(&text)?)
+    }
+
+    /// Returns true if the layout was built for the ECU a definition is for, ignoring case
+    pub fn is_for(&self, model: &SchemaV1) -> bool {
+        self.ecu.eq_ignore_ascii_case(model.ecu_name())
+    }
+
+    /// Looks up the measurement each widget shows in a definition. A widget for a measurement
+    /// the definition no longer has (Example: it was renamed in a newer definition) gets None
+    ///
+    /// # Returns
+    /// Each widget, in the order they are in the layout, with its measurement
+    pub fn resolve(&self, model: &SchemaV1) -> Vec<(DashboardWidget, Option<DidDef>)> {
+        self.widgets.iter().map(|w| {
+            let def = model.measurement_dids().find(|d| d.did == w.did && d.name == w.name).cloned();
+            (w.clone(), def)
+        }).collect()
+    }
+}
+
+/// A widget on the dashboard, with the values read for it
+#[derive(Debug, Clone)]
+struct Tile {
+    widget: DashboardWidget,
+    /// None if the definition does not have the measurement, in which case a placeholder is shown
+    def: Option<DidDef>,
+    latest: Option<FormattedValue>,
+    history: VecDeque<f64>,
+}
+
+impl Tile {
+    fn title(&self) -> String {
+        format!("{} (0x{:04X})", self.widget.name, self.widget.did)
+    }
+
+    /// Range of the gauge or graph. Limits not set by the layout follow the values read so far
+    fn range(&self) -> (f64, f64) {
+        let seen_min = self.history.iter().cloned().fold(f64::INFINITY, f64::min);
+        let seen_max = self.history.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+        let min = self.widget.min.unwrap_or(if seen_min.is_finite() { seen_min } else { 0.0 });
+        let max = self.widget.max.unwrap_or(if seen_max.is_finite() { seen_max } else { min + 1.0 });
+        if max > min { (min, max) } else { (min, min + 1.0) }
+    }
+
+    /// Draws the history as a line of block characters, scaled to the range
+    fn sparkline(&self) -> String {
+        const BLOCKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+        let (min, max) = self.range();
+        self.history.iter().map(|v| {
+            let level = ((v - min) / (max - min) * 7.0).round().clamp(0.0, 7.0);
+            BLOCKS[level as usize]
+        }).collect()
+    }
+
+    fn view(&self) -> Element<DashboardMessage> {
+        let mut c = Column::new().spacing(5).push(text(&self.title(), TextType::Normal));
+        if self.def.is_none() {
+            return container(c.push(text("Not in the current ECU definition", TextType::Warning)))
+                .width(Length::FillPortion(self.widget.span)).padding(5).into()
+        }
+        let latest = self.latest.as_ref().map(|v| v.to_string()).unwrap_or_else(|| "-".into());
+        c = c.push(text(&latest, TextType::Normal));
+        match (self.widget.kind, self.history.back()) {
+            (WidgetKind::Gauge, Some(v)) => {
+                let (min, max) = self.range();
+                c = c.push(progress_bar(min as f32..=max as f32, *v as f32, ButtonType::Primary));
+            },
+            (WidgetKind::Graph, Some(_)) => c = c.push(text(&self.sparkline(), TextType::Normal)),
+            _ => {}
+        }
+        container(c).width(Length::FillPortion(self.widget.span)).padding(5).into()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum DashboardMessage {
+    OpenLayout,
+    SaveLayout,
+}
+
+/// Panel showing live measurements laid out by a [DashboardLayout]
+#[derive(Debug, Clone, Default)]
+pub struct DashboardPanel {
+    layout: DashboardLayout,
+    tiles: Vec<Tile>,
+    status: String,
+    error: Option<String>,
+    open_state: iced::button::State,
+    save_state: iced::button::State,
+    scroll_state: iced::scrollable::State,
+}
+
+impl DashboardPanel {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Builds the dashboard from a layout, looking up each widget's measurement in the
+    /// definition of the connected ECU. Widgets for measurements the definition does not
+    /// have are shown as placeholders, and kept in the layout if it is saved again
+    pub fn load_layout(&mut self, layout: DashboardLayout, model: &SchemaV1) {
+        self.tiles = layout.resolve(model).into_iter()
+            .map(|(widget, def)| Tile { widget, def, latest: None, history: VecDeque::new() })
+            .collect();
+        self.tiles.sort_by_key(|t| (t.widget.row, t.widget.col));
+        let missing = self.tiles.iter().filter(|t| t.def.is_none()).count();
+        self.status = format!("{} widgets for {}", self.tiles.len(), layout.vehicle);
+        self.error = match (layout.is_for(model), missing) {
+            (false, _) => Some(format!("Layout was built for {}, not {}", layout.ecu, model.ecu_name())),
+            (true, 0) => None,
+            (true, n) => Some(format!("{} widgets show measurements which are not in the definition", n)),
+        };
+        self.layout = layout;
+    }
+
+    pub fn layout(&self) -> &DashboardLayout {
+        &self.layout
+    }
+
+    /// Measurements the dashboard needs to be read, without the missing ones
+    pub fn dids(&self) -> Vec<&DidDef> {
+        self.tiles.iter().filter_map(|t| t.def.as_ref()).collect()
+    }
+
+    /// Updates every widget showing a measurement with a value which has just been read
+    pub fn set_value(&mut self, did: u16, name: &str, value: &FormattedValue) {
+        for t in self.tiles.iter_mut().filter(|t| t.def.is_some() && t.widget.did == did && t.widget.name == name) {
+            if let ScaledValue::Number { value, .. } = value.value {
+                if t.history.len() == GRAPH_HISTORY {
+                    t.history.pop_front();
+                }
+                t.history.push_back(value);
+            }
+            t.latest = Some(value.clone());
+        }
+    }
+
+    /// # Params
+    /// * model - Definition of the connected ECU, or None if there is no definition loaded
+    pub fn update(&mut self, msg: &DashboardMessage, model: Option<&SchemaV1>) {
+        self.error = None;
+        match msg {
+            DashboardMessage::OpenLayout => {
+                let model = match model {
+                    Some(m) => m,
+                    None => {
+                        self.error = Some("Open the ECU definition before opening a layout".into());
+                        return
+                    }
+                };
+                if let nfd::Response::Okay(f_path) = nfd::open_file_dialog(Some("json"), None).unwrap_or(nfd::Response::Cancel) {
+                    match DashboardLayout::load(&f_path) {
+                        Ok(layout) => self.load_layout(layout, model),
+                        Err(e) => self.error = Some(format!("Cannot open {}: {}", f_path, e))
+                    }
+                }
+            },
+            DashboardMessage::SaveLayout => {
+                if let nfd::Response::Okay(f_path) = nfd::open_save_dialog(Some("json"), None).unwrap_or(nfd::Response::Cancel) {
+                    if let Err(e) = self.layout.save(&f_path) {
+                        self.error = Some(format!("Cannot save {}: {}", f_path, e))
+                    }
+                }
+            },
+        }
+    }
+
+    pub fn view(&mut self) -> Element<DashboardMessage> {
+        let mut c = Column::new().spacing(5)
+            .push(Row::new().spacing(10)
+                .push(title_text("Dashboard", TitleSize::P4))
+                .push(button_outlined(&mut self.open_state, "Open layout", ButtonType::Primary).on_press(DashboardMessage::OpenLayout))
+                .push(button_outlined(&mut self.save_state, "Save layout", ButtonType::Secondary).on_press(DashboardMessage::SaveLayout)));
+        if !self.status.is_empty() {
+            c = c.push(text(&self.status, TextType::Normal));
+        }
+        if let Some(e) = &self.error {
+            c = c.push(text(e, TextType::Warning));
+        }
+
+        // Tiles are sorted by row then column
+        let mut grid = Column::new().spacing(5);
+        let mut row: Option<(u16, Row<DashboardMessage>)> = None;
+        for t in &self.tiles {
+            let (idx, r) = match row.take() {
+                Some((idx, r)) if idx == t.widget.row => (idx, r),
+                Some((_, r)) => {
+                    grid = grid.push(r);
+                    (t.widget.row, Row::new().spacing(5))
+                },
+                None => (t.widget.row, Row::new().spacing(5)),
+            };
+            row = Some((idx, r.push(t.view())));
+        }
+        if let Some((_, r)) = row {
+            grid = grid.push(r);
+        }
+        c.push(iced::scrollable::Scrollable::new(&mut self.scroll_state).push(grid).width(Length::Fill))
+            .push(Space::with_height(Length::Units(5)))
+            .into()
+    }
+}
+
+#[cfg(test)]
+fn test_layout() -> DashboardLayout {
+    let widget = |did, name: &str, kind, row, col| DashboardWidget { did, name: name.into(), kind, row, col, span: 1, min: None, max: None };
+    DashboardLayout {
+        vehicle: "W203 C200".into(),
+        ecu: "EGS52".into(),
+        widgets: vec![
+            DashboardWidget { min: Some(-40.0), max: Some(150.0), span: 2, ..widget(0x0130, "Transmission oil temperature", WidgetKind::Gauge, 0, 0) },
+            widget(0x0140, "Turbine speed", WidgetKind::Graph, 1, 0),
+            widget(0x0150, "Torque converter slip", WidgetKind::Value, 1, 1),
+        ],
+    }
+}
+
+#[cfg(test)]
+fn test_definition() -> SchemaV1 {
+    SchemaV1::from_json(r#"{
+        "meta": { "name": "EGS52", "vendor": "Siemens", "desc": "Transmission" },
+        "err_table": [],
+        "comm_data": [],
+        "measurements": [
+            { "did": 304, "name": "Transmission oil temperature", "byte_len": 1, "compu": { "Linear": { "factor": 1.0, "offset": -40.0 } }, "unit": "°C" },
+            { "did": 320, "name": "Turbine speed", "byte_len": 2, "unit": "rpm" }
+        ]
+    }"#).unwrap()
+}
+
+#[test]
+fn test_layout_round_trip() {
+    let layout = test_layout();
+    let path = std::env::temp_dir().join(format!("ovd_dashboard_{}.json", std::process::id()));
+    layout.save(&path).unwrap();
+    let loaded = DashboardLayout::load(&path).unwrap();
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(loaded, layout);
+    assert!(loaded.is_for(&test_definition()));
+
+    // Span and range can be left out
+    let minimal = DashboardLayout::from_json(r#"{ "vehicle": "", "ecu": "EGS52",
+        "widgets": [{ "did": 320, "name": "Turbine speed", "kind": "Value", "row": 0, "col": 0 }] }"#).unwrap();
+    assert_eq!((minimal.widgets[0].span, minimal.widgets[0].max), (1, None));
+}
+
+#[test]
+fn test_missing_did_placeholder() {
+    let model = test_definition();
+    let resolved = test_layout().resolve(&model);
+    assert!(resolved[0].1.is_some());
+    assert!(resolved[1].1.is_some());
+    // 0x0150 was removed from the definition
+    assert_eq!(resolved[2].1, None);
+
+    let mut panel = DashboardPanel::new();
+    panel.load_layout(test_layout(), &model);
+    assert_eq!(panel.error.as_deref(), Some("1 widgets show measurements which are not in the definition"));
+    assert_eq!(panel.dids().iter().map(|d| d.did).collect::<Vec<_>>(), vec![0x0130, 0x0140]);
+    let value = |v| FormattedValue { value: ScaledValue::Number { value: v, unit: None }, decimals: 0 };
+    panel.set_value(0x0150, "Torque converter slip", &value(20.0));
+    for rpm in &[800.0, 1600.0, 2400.0] {
+        panel.set_value(0x0140, "Turbine speed", &value(*rpm));
+    }
+    let placeholder = panel.tiles.iter().find(|t| t.widget.did == 0x0150).unwrap();
+    assert!(placeholder.def.is_none() && placeholder.latest.is_none());
+    let graph = panel.tiles.iter().find(|t| t.widget.did == 0x0140).unwrap();
+    assert_eq!(graph.sparkline(), "▁▅█");
+    // Placeholders are kept when the layout is saved again
+    assert_eq!(panel.layout(), &test_layout());
+
+    let mut other = test_layout();
+    other.ecu = "ESP".into();
+    panel.load_layout(other, &model);
+    assert_eq!(panel.error.as_deref(), Some("Layout was built for ESP, not EGS52"));
+}
@@ -14,7 +14,8 @@ pub struct Home {
     server: Box<dyn ComServer>,
     can_state: button::State,
     uds_state: button::State,
-    obd_state: button::State
+    obd_state: button::State,
+    inspector_state: button::State
 }
 
 impl Home {
@@ -23,7 +24,8 @@ impl Home {
             server,
             can_state: button::State::default(),
             uds_state: button::State::default(),
-            obd_state: button::State::default()
+            obd_state: button::State::default(),
+            inspector_state: button::State::default()
         };
         // To guarantee everything works as it should, home screen should have NO interfaces open
         if let Err(e) = ret.server.close_can_interface() {
@@ -59,11 +61,13 @@ impl Home {
                     .push(
                 Column::new()
                         .push(text("CAN", TextType::Normal))
+                        .push(text("CAN-FD", TextType::Normal))
                         .push(text("ISO-TP", TextType::Normal))
                         .push(text("ISO9141", TextType::Normal))
                         .push(text("ISO14230", TextType::Normal)))
                     .push(
                     Column::new()
+                            .push(Home::gen_cap_contents(cap.supports_can()))
                             .push(Home::gen_cap_contents(cap.support_can_fd()))
                             .push(Home::gen_cap_contents(cap.supports_iso15765()))
                             .push(Home::gen_cap_contents(cap.supports_iso9141()))
@@ -86,6 +90,7 @@ impl Home {
             .push(button_outlined(&mut self.can_state, "CAN Analyzer", ButtonType::Primary).on_press(WindowMessage::GoCanTracer))
             .push(button_outlined(&mut self.uds_state, "UDS Scanner", ButtonType::Primary).on_press(WindowMessage::GoUDS))
             .push(button_outlined(&mut self.obd_state, "OBD Tools", ButtonType::Primary).on_press(WindowMessage::GoOBD))
+            .push(button_outlined(&mut self.inspector_state, "Definition Inspector", ButtonType::Primary).on_press(WindowMessage::GoInspector))
             );
         contents.into()
     }
@@ -0,0 +1,264 @@
+use common::raf::{Raf, RafByteOrder, Result};
+use iced::{Element, Column, Row, Text, Length, Checkbox, Align, button, pick_list};
+use crate::windows::window::WindowMessage;
+use crate::themes::{button_coloured, button_outlined, picklist, title_text, text, ButtonType, TextType, TitleSize};
+
+const BYTES_PER_ROW: usize = 16;
+const ROWS_PER_PAGE: usize = 16;
+const PAGE_SIZE: usize = BYTES_PER_ROW * ROWS_PER_PAGE;
+
+/// Data type to decode at the cursor
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ProbeType {
+    U8,
+    I8,
+    U16,
+    I16,
+    U32,
+    I32,
+    U64,
+    I64,
+    F32,
+    F64,
+    CStr,
+}
+
+impl ProbeType {
+    pub const ALL: [ProbeType; 11] = [
+        ProbeType::U8,
+        ProbeType::I8,
+        ProbeType::U16,
+        ProbeType::I16,
+        ProbeType::U32,
+        ProbeType::I32,
+        ProbeType::U64,
+        ProbeType::I64,
+        ProbeType::F32,
+        ProbeType::F64,
+        ProbeType::CStr,
+    ];
+
+    /// Number of bytes the type occupies. Strings are variable length so return None
+    pub fn width(&self) -> Option<usize> {
+        match self {
+            ProbeType::U8 | ProbeType::I8 => Some(1),
+            ProbeType::U16 | ProbeType::I16 => Some(2),
+            ProbeType::U32 | ProbeType::I32 | ProbeType::F32 => Some(4),
+            ProbeType::U64 | ProbeType::I64 | ProbeType::F64 => Some(8),
+            ProbeType::CStr => None
+        }
+    }
+}
+
+impl std::fmt::Display for ProbeType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            ProbeType::U8 => "u8",
+            ProbeType::I8 => "i8",
+            ProbeType::U16 => "u16",
+            ProbeType::I16 => "i16",
+            ProbeType::U32 => "u32",
+            ProbeType::I32 => "i32",
+            ProbeType::U64 => "u64",
+            ProbeType::I64 => "i64",
+            ProbeType::F32 => "f32",
+            ProbeType::F64 => "f64",
+            ProbeType::CStr => "C String",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Decodes the value at an offset as a [ProbeType], using the reader's current byte order.
+///
+/// Only the non-mutating `read_*_at` functions of [Raf] are used, so the reader's position is never changed
+pub fn decode_at(raf: &Raf, offset: usize, probe: ProbeType) -> Result<String> {
+    Ok(match probe {
+        ProbeType::U8 => { let x = raf.read_u8_at(offset)?; format!("{} (0x{:02X})", x, x) },
+        ProbeType::I8 => raf.read_i8_at(offset)?.to_string(),
+        ProbeType::U16 => { let x = raf.read_u16_at(offset)?; format!("{} (0x{:04X})", x, x) },
+        ProbeType::I16 => raf.read_i16_at(offset)?.to_string(),
+        ProbeType::U32 => { let x = raf.read_u32_at(offset)?; format!("{} (0x{:08X})", x, x) },
+        ProbeType::I32 => raf.read_i32_at(offset)?.to_string(),
+        ProbeType::U64 => { let x = raf.read_u64_at(offset)?; format!("{} (0x{:016X})", x, x) },
+        ProbeType::I64 => raf.read_i64_at(offset)?.to_string(),
+        ProbeType::F32 => raf.read_f32_at(offset)?.to_string(),
+        ProbeType::F64 => raf.read_f64_at(offset)?.to_string(),
+        ProbeType::CStr => format!("{:?}", raf.read_cstr_at(offset)?),
+    })
+}
+
+/// Number of bytes to highlight in the hex view for a probe at an offset
+fn probe_len(raf: &Raf, offset: usize, probe: ProbeType) -> usize {
+    match probe.width() {
+        Some(w) => w,
+        None => raf.read_cstr_at(offset).map(|s| s.len() + 1).unwrap_or(1)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum InspectorMessage {
+    OpenFile,
+    SelectOffset(usize),
+    CursorLeft,
+    CursorRight,
+    PrevPage,
+    NextPage,
+    SelectType(ProbeType),
+    ToggleBigEndian(bool),
+}
+
+/// Hex view of a file, which decodes the data at the selected offset.
+/// Used for reverse engineering unknown definition files
+#[derive(Debug, Clone)]
+pub struct Inspector {
+    raf: Option<Raf>,
+    file_name: String,
+    cursor: usize,
+    page_start: usize,
+    probe: ProbeType,
+    byte_states: Vec<button::State>,
+    open_state: button::State,
+    left_state: button::State,
+    right_state: button::State,
+    prev_page_state: button::State,
+    next_page_state: button::State,
+    probe_state: pick_list::State<ProbeType>,
+    status_text: String,
+}
+
+impl<'a> Inspector {
+    pub(crate) fn new() -> Self {
+        Self {
+            raf: None,
+            file_name: "".into(),
+            cursor: 0,
+            page_start: 0,
+            probe: ProbeType::U8,
+            byte_states: vec![button::State::default(); PAGE_SIZE],
+            open_state: button::State::default(),
+            left_state: button::State::default(),
+            right_state: button::State::default(),
+            prev_page_state: button::State::default(),
+            next_page_state: button::State::default(),
+            probe_state: pick_list::State::default(),
+            status_text: "".into()
+        }
+    }
+
+    fn set_cursor(&mut self, pos: usize) {
+        let size = self.raf.as_ref().map(|r| r.size()).unwrap_or(0);
+        self.cursor = std::cmp::min(pos, size.saturating_sub(1));
+        self.page_start = self.cursor - (self.cursor % PAGE_SIZE);
+    }
+
+    pub fn update(&mut self, msg: &InspectorMessage) -> Option<WindowMessage> {
+        match msg {
+            InspectorMessage::OpenFile => {
+                if let nfd::Response::Okay(f_path) = nfd::open_file_dialog(None, None).unwrap_or(nfd::Response::Cancel) {
+                    match Raf::from_file(&f_path, RafByteOrder::LE) {
+                        Ok(raf) => {
+                            self.status_text = format!("{} ({} bytes)", f_path, raf.size());
+                            self.file_name = f_path;
+                            self.raf = Some(raf);
+                            self.set_cursor(0);
+                        },
+                        Err(e) => self.status_text = format!("Error opening file {}", e)
+                    }
+                }
+            },
+            InspectorMessage::SelectOffset(pos) => self.set_cursor(*pos),
+            InspectorMessage::CursorLeft => self.set_cursor(self.cursor.saturating_sub(1)),
+            InspectorMessage::CursorRight => self.set_cursor(self.cursor + 1),
+            InspectorMessage::PrevPage => self.set_cursor(self.page_start.saturating_sub(PAGE_SIZE)),
+            InspectorMessage::NextPage => self.set_cursor(self.page_start + PAGE_SIZE),
+            InspectorMessage::SelectType(t) => self.probe = *t,
+            InspectorMessage::ToggleBigEndian(be) => {
+                if let Some(raf) = self.raf.as_mut() {
+                    raf.set_byte_order(if *be { RafByteOrder::BE } else { RafByteOrder::LE })
+                }
+            }
+        }
+        None
+    }
+
+    pub fn view(&mut self) -> Element<InspectorMessage> {
+        let mut c = Column::new()
+            .padding(10)
+            .spacing(10)
+            .push(title_text("Definition inspector", TitleSize::P3))
+            .push(Row::new()
+                .spacing(10)
+                .align_items(Align::Center)
+                .push(button_coloured(&mut self.open_state, "Open file", ButtonType::Primary).on_press(InspectorMessage::OpenFile))
+                .push(Text::new(&self.status_text)));
+
+        let raf = match &self.raf {
+            Some(r) => r,
+            None => return c.into()
+        };
+        let is_be = raf.get_byte_order() == RafByteOrder::BE;
+        let highlight = self.cursor..self.cursor + probe_len(raf, self.cursor, self.probe);
+
+        let mut dump = Column::new().spacing(2);
+        let mut states = self.byte_states.iter_mut();
+        for row_start in (self.page_start..self.page_start + PAGE_SIZE).step_by(BYTES_PER_ROW) {
+            if row_start >= raf.size() {
+                break;
+            }
+            let bytes = raf.peek(row_start, std::cmp::min(BYTES_PER_ROW, raf.size() - row_start)).unwrap_or(&[]);
+            let mut r = Row::new()
+                .spacing(2)
+                .align_items(Align::Center)
+                .push(Text::new(format!("{:08X}", row_start)).width(Length::Units(90)));
+            for (i, state) in states.by_ref().take(BYTES_PER_ROW).enumerate() {
+                let pos = row_start + i;
+                if let Some(b) = bytes.get(i) {
+                    let btn_type = if highlight.contains(&pos) { ButtonType::Info } else { ButtonType::Secondary };
+                    r = r.push(button_outlined(state, &format!("{:02X}", b), btn_type)
+                        .padding(2)
+                        .on_press(InspectorMessage::SelectOffset(pos)));
+                }
+            }
+            let ascii: String = bytes.iter().map(|b| if b.is_ascii_graphic() { *b as char } else { '.' }).collect();
+            dump = dump.push(r.push(Text::new(ascii)));
+        }
+
+        let value = match decode_at(raf, self.cursor, self.probe) {
+            Ok(v) => text(&v, TextType::Normal),
+            Err(e) => text(&format!("Cannot decode {:?}", e), TextType::Danger)
+        };
+
+        c = c.push(Row::new()
+                .spacing(10)
+                .align_items(Align::Center)
+                .push(button_coloured(&mut self.prev_page_state, "Prev page", ButtonType::Secondary).on_press(InspectorMessage::PrevPage))
+                .push(button_coloured(&mut self.left_state, "<", ButtonType::Secondary).on_press(InspectorMessage::CursorLeft))
+                .push(button_coloured(&mut self.right_state, ">", ButtonType::Secondary).on_press(InspectorMessage::CursorRight))
+                .push(button_coloured(&mut self.next_page_state, "Next page", ButtonType::Secondary).on_press(InspectorMessage::NextPage))
+                .push(picklist(&mut self.probe_state, &ProbeType::ALL[..], Some(self.probe), InspectorMessage::SelectType))
+                .push(Checkbox::new(is_be, "Big endian", InspectorMessage::ToggleBigEndian)))
+            .push(dump)
+            .push(Row::new()
+                .spacing(10)
+                .push(Text::new(format!("Offset 0x{:08X} as {}:", self.cursor, self.probe)))
+                .push(value));
+        c.into()
+    }
+}
+
+#[test]
+fn test_decode_probe() {
+    let data: Vec<u8> = vec![0x01, 0x02, 0x00, 0x00, 0x80, 0x3F, b'O', b'V', b'D', 0x00];
+    let mut raf = Raf::from_bytes(&data, RafByteOrder::LE);
+    assert_eq!(decode_at(&raf, 0, ProbeType::U16).unwrap(), "513 (0x0201)");
+    assert_eq!(decode_at(&raf, 2, ProbeType::F32).unwrap(), "1");
+    assert_eq!(decode_at(&raf, 6, ProbeType::CStr).unwrap(), "\"OVD\"");
+    assert_eq!(probe_len(&raf, 6, ProbeType::CStr), 4);
+    assert!(decode_at(&raf, 8, ProbeType::U32).is_err());
+
+    raf.set_byte_order(RafByteOrder::BE);
+    assert_eq!(decode_at(&raf, 0, ProbeType::U16).unwrap(), "258 (0x0102)");
+    assert_eq!(decode_at(&raf, 4, ProbeType::I8).unwrap(), "-128");
+    assert_eq!(raf.pos, 0);
+}
@@ -5,3 +5,7 @@ pub (crate) mod uds_scanner;
 pub (crate) mod uds_manual;
 pub (crate) mod cantracer;
 pub (crate) mod obd;
+pub (crate) mod inspector;
+pub (crate) mod raw_console;
+pub (crate) mod actuator;
+pub (crate) mod dashboard;
\ No newline at end of file
@@ -0,0 +1,231 @@
+use iced::{Column, Element, Length, Row, Space, TextInput};
+use crate::commapi::protocols::{CommandError, ProtocolServer, Selectable};
+use crate::commapi::protocols::uds::{UDSCommand, UDSNegativeCode};
+use common::hex::{format_hex, parse_hex};
+use crate::themes::{button_outlined, text, ButtonType, TextType};
+
+/// Largest request that fits in an ISO-TP payload
+const MAX_REQUEST_LEN: usize = 0x0FFF;
+
+/// Number of requests kept in the history
+const MAX_HISTORY: usize = 50;
+
+/// Time to wait for the ECU to respond to a request
+const RESPONSE_TIMEOUT_MS: u128 = 2000;
+
+/// Parses a request typed in by the user. See [parse_hex] for the formats accepted
+pub fn parse_hex_payload(input: &str) -> Result<Vec<u8>, String> {
+    let res = parse_hex(input).map_err(|e| e.to_string())?;
+    if res.is_empty() {
+        return Err("Request is empty".into())
+    }
+    if res.len() > MAX_REQUEST_LEN {
+        return Err(format!("Request is {} bytes, the maximum is {}", res.len(), MAX_REQUEST_LEN))
+    }
+    Ok(res)
+}
+
+fn service_name(sid: u8) -> String {
+    UDSCommand::from_byte(&sid).map(|c| c.get_desc()).unwrap_or_else(|_| format!("service 0x{:02X}", sid))
+}
+
+/// Best effort description of a UDS response
+pub fn describe_response(resp: &[u8]) -> String {
+    match resp {
+        [] => "Empty response".into(),
+        [0x7F, sid, nrc, ..] => format!("Negative response to {}: {} (0x{:02X})", service_name(*sid), <UDSNegativeCode as CommandError>::from_byte(*nrc).get_text(), nrc),
+        [0x7F, ..] => "Malformed negative response".into(),
+        [sid, data @ ..] => format!("Positive response to {}, {} data bytes", service_name(sid.wrapping_sub(0x40)), data.len()),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum RawConsoleMessage {
+    InputChanged(String),
+    Send,
+    HistoryPrev,
+    HistoryNext,
+}
+
+#[derive(Debug, Clone)]
+struct ConsoleEntry {
+    req: String,
+    res: String,
+}
+
+/// Panel for sending raw requests typed in as hex to the ECU, with a history of sent requests
+#[derive(Debug, Clone, Default)]
+pub struct RawConsole {
+    input: String,
+    error: Option<String>,
+    history: Vec<Vec<u8>>,
+    /// Index of the history entry shown in the input, if the user is browsing the history
+    history_pos: Option<usize>,
+    log: Vec<ConsoleEntry>,
+    input_state: iced::text_input::State,
+    send_state: iced::button::State,
+    prev_state: iced::button::State,
+    next_state: iced::button::State,
+    scroll_state: iced::scrollable::State,
+}
+
+impl RawConsole {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns every request that has been sent, oldest first
+    pub fn history(&self) -> &[Vec<u8>] {
+        &self.history
+    }
+
+    fn add_history(&mut self, req: Vec<u8>) {
+        if self.history.last() != Some(&req) {
+            self.history.push(req);
+        }
+        if self.history.len() > MAX_HISTORY {
+            self.history.remove(0);
+        }
+    }
+
+    /// # Params
+    /// * server - Session to send requests with, or None if there is no ECU connected
+    pub fn update<P: ProtocolServer>(&mut self, msg: &RawConsoleMessage, server: Option<&P>) {
+        match msg {
+            RawConsoleMessage::InputChanged(s) => {
+                self.input = s.clone();
+                self.history_pos = None;
+                // Only complain once there is something to complain about
+                self.error = if s.trim().is_empty() { None } else { parse_hex_payload(s).err() };
+            },
+            RawConsoleMessage::Send => {
+                let req = match parse_hex_payload(&self.input) {
+                    Ok(r) => r,
+                    Err(e) => {
+                        self.error = Some(e);
+                        return
+                    }
+                };
+                let server = match server {
+                    Some(s) => s,
+                    None => {
+                        self.error = Some("Not connected to an ECU".into());
+                        return
+                    }
+                };
+                let res = match server.send_raw(&req, RESPONSE_TIMEOUT_MS) {
+                    Ok(resp) => format!("Recv: {}\n{}", format_hex(&resp, " "), describe_response(&resp)),
+                    Err(e) => format!("Err: {:?}", e)
+                };
+                self.log.push(ConsoleEntry { req: format!("Send: {}", format_hex(&req, " ")), res });
+                self.add_history(req);
+                self.input.clear();
+                self.history_pos = None;
+                self.error = None;
+            },
+            RawConsoleMessage::HistoryPrev => {
+                if self.history.is_empty() {
+                    return
+                }
+                let pos = match self.history_pos {
+                    None => self.history.len() - 1,
+                    Some(p) => p.saturating_sub(1)
+                };
+                self.history_pos = Some(pos);
+                self.input = format_hex(&self.history[pos], " ");
+                self.error = None;
+            },
+            RawConsoleMessage::HistoryNext => {
+                match self.history_pos {
+                    Some(p) if p + 1 < self.history.len() => {
+                        self.history_pos = Some(p + 1);
+                        self.input = format_hex(&self.history[p + 1], " ");
+                    },
+                    _ => {
+                        self.history_pos = None;
+                        self.input.clear();
+                    }
+                }
+                self.error = None;
+            }
+        }
+    }
+
+    pub fn view(&mut self) -> Element<RawConsoleMessage> {
+        let mut send = button_outlined(&mut self.send_state, "Send", ButtonType::Primary);
+        if self.error.is_none() && !self.input.trim().is_empty() {
+            send = send.on_press(RawConsoleMessage::Send);
+        }
+        let input_row = Row::new()
+            .spacing(5)
+            .push(TextInput::new(&mut self.input_state, "Raw request. Example: 22 F1 90", &self.input, RawConsoleMessage::InputChanged)
+                .on_submit(RawConsoleMessage::Send)
+                .width(Length::Fill))
+            .push(send)
+            .push(button_outlined(&mut self.prev_state, "<", ButtonType::Secondary).on_press(RawConsoleMessage::HistoryPrev))
+            .push(button_outlined(&mut self.next_state, ">", ButtonType::Secondary).on_press(RawConsoleMessage::HistoryNext));
+
+        let mut c = Column::new().spacing(5).push(input_row);
+        if let Some(e) = &self.error {
+            c = c.push(text(e, TextType::Danger));
+        }
+        let mut log_view = Column::new();
+        for entry in self.log.iter().rev() {
+            log_view = log_view.push(text(entry.req.as_str(), TextType::Normal))
+                .push(text(entry.res.as_str(), TextType::Normal))
+                .push(Space::with_height(Length::Units(5)));
+        }
+        c.push(iced::scrollable::Scrollable::new(&mut self.scroll_state).push(log_view).height(Length::Shrink)).into()
+    }
+}
+
+#[test]
+fn test_parse_hex_payload() {
+    assert_eq!(parse_hex_payload("22 F1 90"), Ok(vec![0x22, 0xF1, 0x90]));
+    assert_eq!(parse_hex_payload("  0x22,0xf1, 0X90 "), Ok(vec![0x22, 0xF1, 0x90]));
+    assert_eq!(parse_hex_payload("22F190"), Ok(vec![0x22, 0xF1, 0x90]));
+    assert_eq!(parse_hex_payload("3E 0"), Ok(vec![0x3E, 0x00]));
+
+    assert!(parse_hex_payload("").is_err());
+    assert!(parse_hex_payload("   ,").is_err());
+    assert!(parse_hex_payload("22 F1G0").is_err());
+    assert!(parse_hex_payload("22 F19").is_err());
+    assert!(parse_hex_payload("0x").is_err());
+    assert!(parse_hex_payload("22 é").is_err());
+    assert!(parse_hex_payload(&"00".repeat(MAX_REQUEST_LEN + 1)).is_err());
+    assert_eq!(format_hex(&[0x22, 0xF1, 0x90], " "), "22 F1 90");
+}
+
+#[test]
+fn test_describe_response() {
+    assert_eq!(describe_response(&[0x62, 0xF1, 0x90, 0x57]), "Positive response to Read data by ID, 3 data bytes");
+    assert!(describe_response(&[0x7F, 0x22, 0x31]).starts_with("Negative response to Read data by ID: "));
+    assert!(describe_response(&[0x7F, 0x22, 0x31]).ends_with("(0x31)"));
+    assert_eq!(describe_response(&[0x7F]), "Malformed negative response");
+}
+
+#[test]
+fn test_console_history() {
+    use crate::commapi::protocols::uds::start_mock_session;
+    let (_mock, ecu) = start_mock_session(|req| match req {
+        [0x22, 0xF1, 0x90] => Some(vec![0x62, 0xF1, 0x90, 0x57]),
+        _ => Some(vec![0x7F, req[0], 0x11])
+    });
+    let mut console = RawConsole::new();
+    console.update(&RawConsoleMessage::InputChanged("22 F1 9".into()), Some(&ecu));
+    assert!(console.error.is_some());
+    for req in &["22 F1 90", "0x31 0x01", "22F190"] {
+        console.update(&RawConsoleMessage::InputChanged(req.to_string()), Some(&ecu));
+        console.update(&RawConsoleMessage::Send, Some(&ecu));
+    }
+    assert_eq!(console.history().len(), 3);
+    assert!(console.log[0].res.contains("62 F1 90 57"));
+    assert!(console.log[1].res.starts_with("Recv: 7F 31 11"));
+
+    console.update(&RawConsoleMessage::HistoryPrev, Some(&ecu));
+    console.update(&RawConsoleMessage::HistoryPrev, Some(&ecu));
+    assert_eq!(console.input, "31 01");
+    console.update(&RawConsoleMessage::HistoryNext, Some(&ecu));
+    console.update(&RawConsoleMessage::HistoryNext, Some(&ecu));
+    assert_eq!(console.input, "");
+}
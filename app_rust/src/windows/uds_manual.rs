@@ -5,6 +5,8 @@ use crate::windows::window::WindowMessage;
 use crate::themes::{title_text, text, TextType, button_outlined, ButtonType, TitleSize, picklist};
 use crate::commapi::protocols::kwp2000::*;
 use super::uds_scanner::ECUISOTPSettings;
+use super::raw_console::{RawConsole, RawConsoleMessage};
+use super::actuator::{ActuatorPanel, ActuatorMessage};
 
 #[derive(Debug, Clone)]
 pub enum UDSManualMessage {
@@ -18,7 +20,9 @@ pub enum UDSManualMessage {
     SepTextInput(String),
     ReadErrors,
     ClearErrors,
-    ReadECUID
+    ReadECUID,
+    Console(RawConsoleMessage),
+    Actuator(ActuatorMessage),
 }
 
 #[derive(Debug, Clone)]
@@ -49,7 +53,9 @@ pub struct UDSManual {
     scroll_state: iced:: scrollable::State,
     show_clear_btn: bool,
     state4: iced::button::State,
-    textinput_strings: Vec<String>
+    textinput_strings: Vec<String>,
+    console: RawConsole,
+    actuators: ActuatorPanel,
 }
 
 impl UDSManual {
@@ -83,7 +89,9 @@ impl UDSManual {
             logs: Vec::new(),
             diag_server: None,
             scroll_state: iced:: scrollable::State::default(),
-            show_clear_btn: false
+            show_clear_btn: false,
+            console: RawConsole::new(),
+            actuators: ActuatorPanel::new(),
         };
         println!("Manual mode launching");
         // To guarantee everything works as it should, home screen should have NO interfaces open
@@ -161,6 +169,7 @@ impl UDSManual {
             }
         
             UDSManualMessage::DisconnectECU => {
+                self.actuators.close(self.diag_server.as_ref());
                 if let Some(ref mut s) = self.diag_server {
                     s.exit_diag_session();
                     self.in_session = false;
@@ -261,6 +270,8 @@ impl UDSManual {
                     }
                 }
             }
+            UDSManualMessage::Console(m) => self.console.update(m, self.diag_server.as_ref()),
+            UDSManualMessage::Actuator(m) => self.actuators.update(m, self.diag_server.as_ref()),
 
             _ => {},
         }
@@ -328,7 +339,9 @@ impl UDSManual {
             log_scroll = log_scroll.push(log_view).height(Length::Shrink);
             c = c.push(Row::new()
             .push(comm_view.width(Length::FillPortion(1)))
-            .push(log_scroll.width(Length::FillPortion(1))));
+            .push(log_scroll.width(Length::FillPortion(1))))
+                .push(self.console.view().map(UDSManualMessage::Console))
+                .push(self.actuators.view().map(UDSManualMessage::Actuator));
         } else {
             c = c
             .push(title_text("UDS Manual", TitleSize::P2))
@@ -359,6 +372,7 @@ impl UDSManual {
 
 impl Drop for UDSManual {
     fn drop(&mut self) {
+        self.actuators.close(self.diag_server.as_ref());
         if let Some(mut s) = self.diag_server.take() {
             s.exit_diag_session()
         }
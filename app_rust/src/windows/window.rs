@@ -7,6 +7,7 @@ use std::time::Instant;
 use crate::windows::cantracer::{CanTracer, TracerMessage};
 use crate::windows::uds_scanner::{UDSHomeMessage, UDSHome};
 use crate::windows::obd::{OBDMessage, OBDHome};
+use crate::windows::inspector::{Inspector, InspectorMessage};
 use crate::themes::{toggle_theme, button_coloured, ButtonType, container, text, TextType};
 
 #[derive(Debug, Clone)]
@@ -21,6 +22,7 @@ pub enum WindowState {
     CanTracer(CanTracer),
     UDSHome(UDSHome),
     OBDTools(OBDHome),
+    Inspector(Inspector),
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -30,6 +32,7 @@ pub enum WindowStateName {
     CanTracer,
     UDSHome,
     OBDTools,
+    Inspector,
 }
 
 impl<'a> WindowState {
@@ -40,6 +43,7 @@ impl<'a> WindowState {
             Self::CanTracer (tracer) => tracer.view().map(WindowMessage::CanTracer),
             Self::UDSHome (h) => h.view().map(WindowMessage::UDSScanner),
             Self::OBDTools (h) => h.view().map(WindowMessage::OBDTools),
+            Self::Inspector (i) => i.view().map(WindowMessage::Inspector),
         }
     }
 
@@ -69,6 +73,11 @@ impl<'a> WindowState {
                 if let WindowMessage::OBDTools(x) = msg {
                     return o.update(x).map(WindowMessage::OBDTools)
                 }
+            },
+            Self::Inspector(i) => {
+                if let WindowMessage::Inspector(x) = msg {
+                    return i.update(x);
+                }
             }
         }
         None
@@ -83,6 +92,7 @@ impl WindowState {
             WindowState::CanTracer { .. } => WindowStateName::CanTracer,
             WindowState::UDSHome { .. } => WindowStateName::UDSHome,
             WindowState::OBDTools { .. } => WindowStateName::OBDTools,
+            WindowState::Inspector { .. } => WindowStateName::Inspector,
         }
     }
 }
@@ -96,12 +106,14 @@ pub enum WindowMessage {
     CanTracer(TracerMessage),
     UDSScanner(UDSHomeMessage),
     OBDTools(OBDMessage),
+    Inspector(InspectorMessage),
     StartApp(Box<dyn ComServer>),
     StatusUpdate(Instant),
     GoHome, // Goto home page
     GoCanTracer, // Goto Can Tracer page
     GoUDS, // Goto UDS Scanner page
     GoOBD, // Goto OBD Toolbox page
+    GoInspector, // Goto definition inspector page
     ToggleTheme, // Toggle the theme
 }
 
@@ -135,7 +147,8 @@ impl Application for MainWindow {
             WindowState::Home { .. } => format!("OpenVehicleDiag ({} mode)", self.server.as_ref().map(|s| s.get_api()).unwrap_or("Unknown")),
             WindowState::CanTracer { .. } => "OpenVehicleDiag CanTracer".into(),
             WindowState::UDSHome { .. } => "OpenVehicleDiag UDS Scanner".into(),
-            WindowState::OBDTools { .. } => "OpenVehicleDiag OBD Toolbox".into()
+            WindowState::OBDTools { .. } => "OpenVehicleDiag OBD Toolbox".into(),
+            WindowState::Inspector { .. } => "OpenVehicleDiag Definition Inspector".into()
         }
     }
 
@@ -157,6 +170,9 @@ impl Application for MainWindow {
             },
             WindowMessage::GoOBD => {
                 self.state = WindowState::OBDTools(OBDHome::new(self.server.clone().unwrap()))
+            },
+            WindowMessage::GoInspector => {
+                self.state = WindowState::Inspector(Inspector::new())
             }
             WindowMessage::ToggleTheme => {
                 toggle_theme()
@@ -0,0 +1,62 @@
+use byteorder::{ByteOrder, LittleEndian};
+use common::raf::{Raf, RafByteOrder};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+/// Number of records in the test table, roughly the size of the largest tables in a CBF file
+const RECORDS: usize = 200_000;
+
+/// Size of a record: u16 ID, u32 offset, i32 value, f32 scale, u8 flags
+const RECORD_LEN: usize = 15;
+
+fn make_table() -> Vec<u8> {
+    let mut data = Vec::with_capacity(RECORDS * RECORD_LEN);
+    for i in 0..RECORDS {
+        data.extend_from_slice(&(i as u16).to_le_bytes());
+        data.extend_from_slice(&(i as u32 * RECORD_LEN as u32).to_le_bytes());
+        data.extend_from_slice(&(i as i32 - 1000).to_le_bytes());
+        data.extend_from_slice(&(i as f32 * 0.5).to_le_bytes());
+        data.push(i as u8);
+    }
+    data
+}
+
+fn parse_table(raf: &mut Raf) -> u64 {
+    raf.seek(0).unwrap();
+    let mut sum = 0u64;
+    for _ in 0..RECORDS {
+        sum = sum.wrapping_add(raf.read_u16().unwrap() as u64);
+        sum = sum.wrapping_add(raf.read_u32().unwrap() as u64);
+        sum = sum.wrapping_add(raf.read_i32().unwrap() as u64);
+        sum = sum.wrapping_add(raf.read_f32().unwrap() as u64);
+        sum = sum.wrapping_add(raf.read_u8().unwrap() as u64);
+    }
+    sum
+}
+
+/// Same parse using an allocation per read, which is how read_primitive used to work
+fn parse_table_allocating(raf: &mut Raf) -> u64 {
+    raf.seek(0).unwrap();
+    let mut sum = 0u64;
+    for _ in 0..RECORDS {
+        sum = sum.wrapping_add(LittleEndian::read_u16(&raf.read_bytes(2).unwrap()) as u64);
+        sum = sum.wrapping_add(LittleEndian::read_u32(&raf.read_bytes(4).unwrap()) as u64);
+        sum = sum.wrapping_add(LittleEndian::read_i32(&raf.read_bytes(4).unwrap()) as u64);
+        sum = sum.wrapping_add(LittleEndian::read_f32(&raf.read_bytes(4).unwrap()) as u64);
+        sum = sum.wrapping_add(raf.read_u8().unwrap() as u64);
+    }
+    sum
+}
+
+fn bench_table_parse(c: &mut Criterion) {
+    let data = make_table();
+    let mut raf = Raf::from_bytes(&data, RafByteOrder::LE);
+    assert_eq!(parse_table(&mut raf), parse_table_allocating(&mut raf));
+
+    let mut group = c.benchmark_group("table_parse");
+    group.bench_function("read_primitive", |b| b.iter(|| black_box(parse_table(&mut raf))));
+    group.bench_function("read_bytes", |b| b.iter(|| black_box(parse_table_allocating(&mut raf))));
+    group.finish();
+}
+
+criterion_group!(benches, bench_table_parse);
+criterion_main!(benches);
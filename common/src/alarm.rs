@@ -0,0 +1,223 @@
+use serde::{Deserialize, Serialize};
+use crate::measurement::ScaledValue;
+
+/// How a measurement is compared to an alarm's threshold
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Comparison {
+    GreaterThan,
+    GreaterOrEqual,
+    LessThan,
+    LessOrEqual,
+}
+
+impl Comparison {
+    fn matches(&self, value: f64, threshold: f64) -> bool {
+        match self {
+            Comparison::GreaterThan => value > threshold,
+            Comparison::GreaterOrEqual => value >= threshold,
+            Comparison::LessThan => value < threshold,
+            Comparison::LessOrEqual => value <= threshold,
+        }
+    }
+
+    fn is_upper(&self) -> bool {
+        matches!(self, Comparison::GreaterThan | Comparison::GreaterOrEqual)
+    }
+}
+
+impl std::fmt::Display for Comparison {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Comparison::GreaterThan => write!(f, ">"),
+            Comparison::GreaterOrEqual => write!(f, ">="),
+            Comparison::LessThan => write!(f, "<"),
+            Comparison::LessOrEqual => write!(f, "<="),
+        }
+    }
+}
+
+/// Raises an alarm when the physical value of a measurement DID crosses a threshold.
+/// Example: Coolant temperature > 110 °C
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AlarmRule {
+    /// Measurement DID the rule watches
+    pub did: u16,
+    pub condition: Comparison,
+    /// Physical value, in the unit of the DID's definition
+    pub threshold: f64,
+    /// How far the value must move back past the threshold before the alarm clears, so a value
+    /// hovering around the threshold does not keep raising and clearing it
+    #[serde(default)]
+    pub hysteresis: f64,
+}
+
+impl AlarmRule {
+    pub fn new(did: u16, condition: Comparison, threshold: f64) -> Self {
+        Self { did, condition, threshold, hysteresis: 0.0 }
+    }
+
+    pub fn with_hysteresis(mut self, hysteresis: f64) -> Self {
+        self.hysteresis = hysteresis.abs();
+        self
+    }
+
+    /// Returns true if the value clears an alarm which is active
+    fn clears(&self, value: f64) -> bool {
+        if self.condition.is_upper() {
+            value < self.threshold - self.hysteresis
+        } else {
+            value > self.threshold + self.hysteresis
+        }
+    }
+}
+
+impl std::fmt::Display for AlarmRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "DID 0x{:04X} {} {}", self.did, self.condition, self.threshold)
+    }
+}
+
+/// Change in the state of an [AlarmRule]
+#[derive(Debug, Clone, PartialEq)]
+pub enum AlarmEvent {
+    /// A sample violated the rule whilst the alarm was clear
+    Enter {
+        /// Index of the rule, in the order rules were added
+        rule: usize,
+        did: u16,
+        value: f64,
+    },
+    /// A sample moved back past the threshold, and the hysteresis, whilst the alarm was active
+    Exit {
+        rule: usize,
+        did: u16,
+        value: f64,
+    },
+}
+
+/// Checks scaled measurement samples against a set of [AlarmRule]s, and calls a
+/// callback each time an alarm is raised or cleared
+#[derive(Default)]
+pub struct AlarmEvaluator {
+    rules: Vec<(AlarmRule, bool)>,
+    callbacks: Vec<Box<dyn FnMut(&AlarmRule, &AlarmEvent) + Send>>,
+}
+
+impl std::fmt::Debug for AlarmEvaluator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AlarmEvaluator").field("rules", &self.rules).finish()
+    }
+}
+
+impl AlarmEvaluator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a rule, which starts clear. Returns the index of the rule
+    pub fn add_rule(&mut self, rule: AlarmRule) -> usize {
+        self.rules.push((rule, false));
+        self.rules.len() - 1
+    }
+
+    /// Adds a callback which is called with every [AlarmEvent], and the rule it is for
+    pub fn on_event<F: FnMut(&AlarmRule, &AlarmEvent) + Send + 'static>(&mut self, callback: F) {
+        self.callbacks.push(Box::new(callback))
+    }
+
+    pub fn rules(&self) -> impl Iterator<Item = &AlarmRule> {
+        self.rules.iter().map(|(r, _)| r)
+    }
+
+    /// Returns true if the alarm for a rule is currently raised
+    pub fn is_active(&self, rule: usize) -> bool {
+        self.rules.get(rule).map(|(_, active)| *active).unwrap_or(false)
+    }
+
+    /// Checks a sample of a DID against every rule for the DID, calling the callbacks
+    /// for any alarm which is raised or cleared. Text values are ignored
+    ///
+    /// # Returns
+    /// The events the sample caused
+    pub fn evaluate(&mut self, did: u16, sample: &ScaledValue) -> Vec<AlarmEvent> {
+        match sample {
+            ScaledValue::Number { value, .. } => self.evaluate_value(did, *value),
+            ScaledValue::Text(_) => Vec::new(),
+        }
+    }
+
+    /// Same as [evaluate](fn@AlarmEvaluator::evaluate), for a sample which is already a number
+    pub fn evaluate_value(&mut self, did: u16, value: f64) -> Vec<AlarmEvent> {
+        let mut events = Vec::new();
+        for (idx, (rule, active)) in self.rules.iter_mut().enumerate() {
+            if rule.did != did || value.is_nan() {
+                continue
+            }
+            let event = if !*active && rule.condition.matches(value, rule.threshold) {
+                AlarmEvent::Enter { rule: idx, did, value }
+            } else if *active && rule.clears(value) {
+                AlarmEvent::Exit { rule: idx, did, value }
+            } else {
+                continue
+            };
+            *active = !*active;
+            for cb in self.callbacks.iter_mut() {
+                cb(rule, &event);
+            }
+            events.push(event);
+        }
+        events
+    }
+
+    /// Clears every alarm without calling the callbacks. Used when the ECU is disconnected
+    pub fn reset(&mut self) {
+        for (_, active) in self.rules.iter_mut() {
+            *active = false;
+        }
+    }
+}
+
+#[test]
+fn test_alarm_hysteresis() {
+    use std::sync::{Arc, Mutex};
+    const COOLANT: u16 = 0xF405;
+    const BATTERY: u16 = 0xF442;
+    let mut eval = AlarmEvaluator::new();
+    let hot = eval.add_rule(AlarmRule::new(COOLANT, Comparison::GreaterThan, 110.0).with_hysteresis(5.0));
+    let low = eval.add_rule(AlarmRule::new(BATTERY, Comparison::LessOrEqual, 11.5).with_hysteresis(0.5));
+    let fired = Arc::new(Mutex::new(Vec::new()));
+    let fired_cb = fired.clone();
+    eval.on_event(move |rule, event| fired_cb.lock().unwrap().push(format!("{} {:?}", rule, event)));
+
+    let temp = |value| ScaledValue::Number { value, unit: Some("°C".into()) };
+    let mut entered_at = Vec::new();
+    let mut exited_at = Vec::new();
+    // Hovers around 110, dips below it without clearing, then cools down
+    for (idx, v) in [100.0, 110.0, 110.5, 109.0, 111.0, 106.0, 112.0, 104.9, 108.0, 111.0].iter().enumerate() {
+        for e in eval.evaluate(COOLANT, &temp(*v)) {
+            match e {
+                AlarmEvent::Enter { rule, .. } => { assert_eq!(rule, hot); entered_at.push(idx) },
+                AlarmEvent::Exit { rule, .. } => { assert_eq!(rule, hot); exited_at.push(idx) },
+            }
+        }
+    }
+    assert_eq!(entered_at, vec![2, 9]);
+    assert_eq!(exited_at, vec![7]);
+    assert!(eval.is_active(hot));
+    assert!(!eval.is_active(low));
+
+    // Rules for other DIDs and text values are ignored
+    assert!(eval.evaluate(COOLANT, &ScaledValue::Text("Sensor fault".into())).is_empty());
+    assert_eq!(eval.evaluate_value(BATTERY, 11.5), vec![AlarmEvent::Enter { rule: low, did: BATTERY, value: 11.5 }]);
+    assert!(eval.evaluate_value(BATTERY, 11.9).is_empty());
+    assert_eq!(eval.evaluate_value(BATTERY, 12.1), vec![AlarmEvent::Exit { rule: low, did: BATTERY, value: 12.1 }]);
+    assert!(eval.is_active(hot));
+
+    let fired = fired.lock().unwrap();
+    assert_eq!(fired.len(), 5);
+    assert_eq!(fired[0], "DID 0xF405 > 110 Enter { rule: 0, did: 62469, value: 110.5 }");
+    assert_eq!(fired[1], "DID 0xF405 > 110 Exit { rule: 0, did: 62469, value: 104.9 }");
+
+    eval.reset();
+    assert!(!eval.is_active(hot));
+}
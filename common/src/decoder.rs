@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+use crate::dtc::DecodedDtc;
+use crate::measurement::{FormattedValue, ScaleError};
+use crate::schema::SchemaV1;
+
+/// Decodes the data read from a DID
+pub type DidDecoder = Box<dyn Fn(&[u8]) -> FormattedValue + Send + Sync>;
+/// Describes a DTC, given the full DTC number including the fault type byte
+pub type DtcDecoder = Box<dyn Fn(u32) -> String + Send + Sync>;
+
+/// Decoders added at runtime, for DIDs and DTCs which are not in the ECU definition
+/// or which the definition gets wrong (Example: reverse engineered OEM DIDs).
+///
+/// Registered decoders take precedence over the definition. Anything without a
+/// registered decoder is decoded with the definition as normal
+#[derive(Default)]
+pub struct DecoderRegistry {
+    dids: HashMap<u16, (String, DidDecoder)>,
+    dtcs: HashMap<u32, DtcDecoder>,
+}
+
+impl std::fmt::Debug for DecoderRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "DecoderRegistry ({} DIDs, {} DTCs)", self.dids.len(), self.dtcs.len())
+    }
+}
+
+impl DecoderRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a decoder for a DID, replacing any decoder already registered for it
+    ///
+    /// # Params
+    /// * name - Name of the value the DID holds
+    pub fn register_did<F: Fn(&[u8]) -> FormattedValue + Send + Sync + 'static>(&mut self, did: u16, name: &str, f: F) {
+        self.dids.insert(did, (name.into(), Box::new(f)));
+    }
+
+    /// Registers a description for a DTC, replacing any decoder already registered for it.
+    ///
+    /// As with the definition's error table, the key is either the full DTC number (Example: 0x9D0013)
+    /// which matches one fault type, or the DTC with a fault type byte of 0 (Example: 0x9D0000)
+    /// which matches every fault type
+    pub fn register_dtc<F: Fn(u32) -> String + Send + Sync + 'static>(&mut self, dtc: u32, f: F) {
+        self.dtcs.insert(dtc, Box::new(f));
+    }
+
+    pub fn has_did(&self, did: u16) -> bool {
+        self.dids.contains_key(&did)
+    }
+
+    /// Decodes the data read from a DID, with its registered decoder if it has one,
+    /// otherwise with the definition. See [SchemaV1::decode_did_response]
+    ///
+    /// # Params
+    /// * raw - Data read from the DID, excluding the DID echoed back by the ECU
+    pub fn decode_did(&self, did: u16, raw: &[u8], model: Option<&SchemaV1>) -> Result<Vec<(String, FormattedValue)>, ScaleError> {
+        match (self.dids.get(&did), model) {
+            (Some((name, f)), _) => Ok(vec![(name.clone(), f(raw))]),
+            (None, Some(m)) => m.decode_did_response(did, raw),
+            (None, None) => Err(ScaleError::UnknownDid(did)),
+        }
+    }
+
+    /// Resolves a DTC with its registered decoder if it has one, otherwise with the definition.
+    /// If neither know the DTC, the generic SAE decoding is returned
+    pub fn decode_dtc(&self, number: u32, model: Option<&SchemaV1>) -> DecodedDtc {
+        let mut res = match model {
+            Some(m) => m.decode_dtc(number),
+            None => DecodedDtc::generic(number),
+        };
+        if let Some(f) = self.dtcs.get(&number).or_else(|| self.dtcs.get(&(number & 0xFFFF00))) {
+            res.desc = f(number);
+        }
+        res
+    }
+}
+
+#[test]
+fn test_registered_decoders() {
+    use crate::measurement::ScaledValue;
+
+    let model = SchemaV1::from_json(r#"{
+        "meta": { "name": "EGS52", "vendor": "Mercedes-Benz", "desc": "722.6 Controller Generation" },
+        "err_table": [{ "name": "P0715", "desc": "Turbine speed sensor" }],
+        "comm_data": [],
+        "measurements": [
+            { "did": 4352, "name": "Oil temperature", "byte_len": 1, "compu": { "Linear": { "factor": 1.0, "offset": -40.0 } }, "unit": "°C" },
+            { "did": 4353, "name": "Gear", "byte_len": 1 }
+        ]
+    }"#).unwrap();
+    let mut registry = DecoderRegistry::new();
+    // Definition has the gear DID as a plain number
+    registry.register_did(0x1101, "Selected gear", |raw| {
+        let gear = match raw.first() {
+            Some(0) => "Park".into(),
+            Some(g) => format!("D{}", g),
+            None => "Unknown".into(),
+        };
+        FormattedValue { value: ScaledValue::Text(gear), decimals: 0 }
+    });
+    registry.register_did(0x2000, "Clutch wear", |raw| {
+        FormattedValue { value: ScaledValue::Number { value: raw.len() as f64, unit: Some("%".into()) }, decimals: 1 }
+    });
+    registry.register_dtc(0x071500, |n| format!("Turbine speed sensor N2 ({:02X})", n as u8));
+    assert!(registry.has_did(0x1101));
+
+    let res = registry.decode_did(0x1101, &[0x03], Some(&model)).unwrap();
+    assert_eq!(res.len(), 1);
+    assert_eq!(res[0].0, "Selected gear");
+    assert_eq!(res[0].1.to_string(), "D3");
+    assert_eq!(model.decode_did_response(0x1101, &[0x03]).unwrap()[0].1.to_string(), "3");
+
+    // Fills a gap in the definition, and works without one
+    assert_eq!(registry.decode_did(0x2000, &[0x00, 0x00], Some(&model)).unwrap()[0].1.to_string(), "2.0 %");
+    assert_eq!(registry.decode_did(0x2000, &[0x00], None).unwrap()[0].1.to_string(), "1.0 %");
+
+    // Unregistered DIDs fall back to the definition
+    assert_eq!(registry.decode_did(0x1100, &[0x82], Some(&model)).unwrap()[0].1.to_string(), "90 °C");
+    assert_eq!(registry.decode_did(0x1100, &[0x82], None), Err(ScaleError::UnknownDid(0x1100)));
+
+    let dtc = registry.decode_dtc(0x071564, Some(&model));
+    assert_eq!(dtc.desc, "Turbine speed sensor N2 (64)");
+    assert_eq!(dtc.code, "P0715");
+    assert_eq!(registry.decode_dtc(0x012300, Some(&model)), DecodedDtc::generic(0x012300));
+}
@@ -0,0 +1,101 @@
+use crate::raf::Raf;
+use std::fmt::Write;
+
+/// Bytes shown per line by [format_region]
+const BYTES_PER_LINE: usize = 8;
+
+/// A contiguous run of bytes which differ between two dumps
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiffRegion {
+    /// Offset of the first differing byte
+    pub offset: usize,
+    /// Bytes in the first (old) dump. Shorter than `new` if the region is past the end of the old dump
+    pub old: Vec<u8>,
+    /// Bytes in the second (new) dump. Shorter than `old` if the region is past the end of the new dump
+    pub new: Vec<u8>,
+}
+
+impl DiffRegion {
+    /// Number of bytes the region covers
+    pub fn len(&self) -> usize {
+        std::cmp::max(self.old.len(), self.new.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Compares two dumps, returning every run of differing bytes.
+///
+/// If the dumps have different lengths, the bytes past the end of the shorter dump
+/// are reported as one final region
+pub fn diff_regions(a: &Raf, b: &Raf) -> Vec<DiffRegion> {
+    let old = a.peek(0, a.size()).unwrap_or(&[]);
+    let new = b.peek(0, b.size()).unwrap_or(&[]);
+    let common_len = std::cmp::min(old.len(), new.len());
+
+    let mut res = Vec::new();
+    let mut start: Option<usize> = None;
+    for i in 0..=common_len {
+        let differs = i < common_len && old[i] != new[i];
+        match (start, differs) {
+            (None, true) => start = Some(i),
+            (Some(s), false) => {
+                res.push(DiffRegion { offset: s, old: old[s..i].to_vec(), new: new[s..i].to_vec() });
+                start = None;
+            },
+            _ => {}
+        }
+    }
+    if old.len() != new.len() {
+        res.push(DiffRegion { offset: common_len, old: old[common_len..].to_vec(), new: new[common_len..].to_vec() })
+    }
+    res
+}
+
+fn hex_column(bytes: &[u8], start: usize, region_len: usize) -> String {
+    (start..start + BYTES_PER_LINE)
+        .map(|i| match bytes.get(i) {
+            Some(b) => format!("{:02X}", b),
+            None if i < region_len => "--".into(),
+            None => "  ".into(), // Past the end of the region
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// Formats a region as a side by side hex view, old bytes on the left and new bytes on the right.
+/// Bytes which do not exist in one of the dumps are shown as `--`
+pub fn format_region(region: &DiffRegion) -> String {
+    let mut s = format!("0x{:08X} - 0x{:08X} ({} bytes)\n", region.offset, region.offset + region.len() - 1, region.len());
+    for line in (0..region.len()).step_by(BYTES_PER_LINE) {
+        let _ = writeln!(s, "{:08X} | {} | {}", region.offset + line, hex_column(&region.old, line, region.len()), hex_column(&region.new, line, region.len()));
+    }
+    s
+}
+
+#[test]
+fn test_diff_regions() {
+    use crate::raf::RafByteOrder;
+    let mut old = vec![0u8; 32];
+    let mut new = old.clone();
+    new[1] = 0x11;
+    new[10..13].copy_from_slice(&[0xAA, 0xBB, 0xCC]);
+    new[31] = 0xFF;
+    old.extend_from_slice(&[0x01, 0x02]);
+
+    let regions = diff_regions(&Raf::from_bytes(&old, RafByteOrder::LE), &Raf::from_bytes(&new, RafByteOrder::LE));
+    assert_eq!(regions, vec![
+        DiffRegion { offset: 1, old: vec![0x00], new: vec![0x11] },
+        DiffRegion { offset: 10, old: vec![0x00; 3], new: vec![0xAA, 0xBB, 0xCC] },
+        DiffRegion { offset: 31, old: vec![0x00], new: vec![0xFF] },
+        DiffRegion { offset: 32, old: vec![0x01, 0x02], new: vec![] },
+    ]);
+    assert_eq!(format_region(&regions[1]),
+        "0x0000000A - 0x0000000C (3 bytes)\n0000000A | 00 00 00                | AA BB CC               \n");
+    assert_eq!(format_region(&regions[3]).lines().nth(1), Some("00000020 | 01 02                   | -- --                  "));
+
+    let same = Raf::from_bytes(&new, RafByteOrder::LE);
+    assert!(diff_regions(&same, &same.clone()).is_empty());
+}
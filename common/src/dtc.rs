@@ -0,0 +1,282 @@
+use serde::{Deserialize, Serialize};
+
+/// A DTC resolved against an ECU definition, or decoded generically if the definition
+/// does not know about it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodedDtc {
+    /// Raw 3 byte DTC number as reported by the ECU. Example: 0x9D0013
+    pub number: u32,
+    /// SAE J2012 style code of the top 2 bytes. Example: C1D00
+    pub code: String,
+    /// Description of the error
+    pub desc: String,
+    /// Meaning of the fault type byte (Lowest byte of the DTC), if known
+    pub fault_type: Option<String>,
+    /// DIDs which hold environment (freeze frame) data recorded with the DTC
+    pub env_dids: Vec<u16>,
+    /// True if the description came from the ECU definition
+    pub from_definition: bool,
+}
+
+impl DecodedDtc {
+    /// Decodes a DTC using only the SAE J2012 rules, for when no definition matches
+    pub fn generic(number: u32) -> Self {
+        let code = sae_code((number >> 8) as u16);
+        let fault_type = fault_type_desc(number as u8).map(String::from);
+        Self {
+            number,
+            desc: format!("Unknown {} DTC {}", system_name(&code), code),
+            code,
+            fault_type,
+            env_dids: Vec::new(),
+            from_definition: false,
+        }
+    }
+}
+
+impl std::fmt::Display for DecodedDtc {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.fault_type {
+            Some(ft) => write!(f, "{} ({:06X}) - {} - {}", self.code, self.number, self.desc, ft),
+            None => write!(f, "{} ({:06X}) - {}", self.code, self.number, self.desc),
+        }
+    }
+}
+
+/// Meaning of a DTC extended data record
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExtDataKind {
+    /// Number of times the DTC has been detected
+    OccurrenceCounter,
+    /// Number of fault free cycles since the DTC was last detected (Also called the healing counter)
+    AgingCounter,
+    /// OEM specific data that is not decoded
+    Raw,
+}
+
+impl Default for ExtDataKind {
+    fn default() -> Self {
+        ExtDataKind::Raw
+    }
+}
+
+/// Layout of a DTC extended data record, as declared by a definition. The length of
+/// each record is ECU specific, so records can only be split apart with a definition
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExtDataRecordDef {
+    pub record_number: u8,
+    pub name: String,
+    /// Length of the record data in bytes
+    pub len: usize,
+    #[serde(default)]
+    pub kind: ExtDataKind,
+}
+
+/// Width of the DTC numbers in a response
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DtcFormat {
+    /// 2 bytes, without a failure type byte. Used by OBD-II (Modes 03, 07 and 0A) and KWP2000
+    TwoByte,
+    /// 3 bytes, where the lowest byte is the failure type. Used by UDS (ReadDTCInformation)
+    ThreeByte,
+}
+
+impl DtcFormat {
+    /// Number of bytes each DTC takes
+    pub fn byte_len(&self) -> usize {
+        match self {
+            DtcFormat::TwoByte => 2,
+            DtcFormat::ThreeByte => 3,
+        }
+    }
+
+    /// Decodes one DTC into its 3 byte number, so DTCs of either format can be compared and
+    /// formatted with [sae_dtc_string]. 2 byte DTCs get a failure type of 0x00.
+    /// Returns None if `bytes` is not exactly [byte_len](fn@DtcFormat::byte_len) bytes
+    ///
+    /// # Example
+    /// ```
+    /// use common::dtc::DtcFormat;
+    ///
+    /// assert_eq!(DtcFormat::TwoByte.decode(&[0x04, 0x20]), Some(0x042000));
+    /// assert_eq!(DtcFormat::ThreeByte.decode(&[0x04, 0x20, 0x00]), Some(0x042000));
+    /// ```
+    pub fn decode(&self, bytes: &[u8]) -> Option<u32> {
+        match (self, bytes) {
+            (DtcFormat::TwoByte, [hi, lo]) => Some((*hi as u32) << 16 | (*lo as u32) << 8),
+            (DtcFormat::ThreeByte, [hi, mid, ftb]) => Some((*hi as u32) << 16 | (*mid as u32) << 8 | *ftb as u32),
+            _ => None
+        }
+    }
+}
+
+/// Decodes a list of back to back DTCs into their 3 byte numbers (See [DtcFormat::decode]).
+///
+/// Lists of 2 byte DTCs are padded with 0x0000 (P0000) when the ECU has fewer DTCs than fit in
+/// the response, so 0x0000 entries are skipped. Returns None if the list is not a whole number of DTCs
+pub fn decode_dtcs(data: &[u8], format: DtcFormat) -> Option<Vec<u32>> {
+    if data.len() % format.byte_len() != 0 {
+        return None
+    }
+    Some(data.chunks(format.byte_len())
+        .filter(|d| format == DtcFormat::ThreeByte || d.iter().any(|b| *b != 0))
+        .filter_map(|d| format.decode(d))
+        .collect())
+}
+
+/// Converts a 2 byte DTC into its SAE J2012 code. Example: 0x0100 -> P0100
+pub fn sae_code(dtc: u16) -> String {
+    let system = match dtc >> 14 {
+        0 => 'P', // Powertrain
+        1 => 'C', // Chassis
+        2 => 'B', // Body
+        _ => 'U', // Network
+    };
+    format!("{}{:04X}", system, dtc & 0x3FFF)
+}
+
+/// Formats a 3 byte (ISO 14229) DTC as its SAE J2012 code, followed by the failure type byte
+/// if it is set. Example: 0x042000 -> P0420, 0x9D0013 -> B1D00-13.
+/// 2 byte DTCs are formatted once decoded with [DtcFormat::decode]
+pub fn sae_dtc_string(dtc: u32) -> String {
+    let code = sae_code((dtc >> 8) as u16);
+    match dtc & 0xFF {
+        0 => code,
+        ftb => format!("{}-{:02X}", code, ftb)
+    }
+}
+
+/// Parses a DTC typed in by the user into its 3 byte number, the inverse of [sae_dtc_string].
+/// Case is ignored, and the failure type may be separated by a `-` or space, or left out
+/// (Example: `p0420`, `B1D00-13`, `B1D0013`). Returns None if the code is not valid
+pub fn parse_sae_dtc(s: &str) -> Option<u32> {
+    let s = s.trim();
+    let mut chars = s.chars();
+    let system = match chars.next()?.to_ascii_uppercase() {
+        'P' => 0,
+        'C' => 1,
+        'B' => 2,
+        'U' => 3,
+        _ => return None
+    };
+    let digits: String = chars.filter(|c| *c != '-' && *c != ' ').collect();
+    if !digits.chars().all(|c| c.is_ascii_hexdigit()) || (digits.len() != 4 && digits.len() != 6) {
+        return None
+    }
+    // First digit only holds 2 bits
+    if digits.as_bytes()[0] > b'3' {
+        return None
+    }
+    let code = u16::from_str_radix(&digits[0..4], 16).ok()?;
+    let ftb = if digits.len() == 6 { u8::from_str_radix(&digits[4..6], 16).ok()? } else { 0 };
+    Some((system << 22) | (code as u32) << 8 | ftb as u32)
+}
+
+fn system_name(code: &str) -> &'static str {
+    match code.chars().next() {
+        Some('P') => "powertrain",
+        Some('C') => "chassis",
+        Some('B') => "body",
+        _ => "network",
+    }
+}
+
+/// Returns the meaning of a fault type byte (SAE J2012-DA failure type), if it is a standard one
+pub fn fault_type_desc(ftb: u8) -> Option<&'static str> {
+    Some(match ftb {
+        0x00 => "No sub type information",
+        0x01 => "General electrical failure",
+        0x02 => "General signal failure",
+        0x03 => "FM/PWM failure",
+        0x04 => "System internal failure",
+        0x05 => "System programming failure",
+        0x06 => "Algorithm based failure",
+        0x07 => "Mechanical failure",
+        0x08 => "Bus signal / message failure",
+        0x09 => "Component failure",
+        0x11 => "Circuit short to ground",
+        0x12 => "Circuit short to battery",
+        0x13 => "Circuit open",
+        0x14 => "Circuit short to ground or open",
+        0x15 => "Circuit short to battery or open",
+        0x16 => "Circuit voltage below threshold",
+        0x17 => "Circuit voltage above threshold",
+        0x18 => "Circuit current below threshold",
+        0x19 => "Circuit current above threshold",
+        0x1C => "Circuit voltage out of range",
+        0x1F => "Circuit intermittent",
+        0x21 => "Signal amplitude below minimum",
+        0x22 => "Signal amplitude above maximum",
+        0x29 => "Signal invalid",
+        0x31 => "No signal",
+        0x41 => "General checksum failure",
+        0x42 => "General memory failure",
+        0x49 => "Internal electronic failure",
+        0x54 => "Missing calibration",
+        0x55 => "Not configured",
+        0x62 => "Signal compare failure",
+        0x64 => "Signal plausibility failure",
+        0x71 => "Actuator stuck",
+        0x81 => "Invalid serial data received",
+        0x82 => "Alive / sequence counter incorrect",
+        0x83 => "Value of signal protection calculation incorrect",
+        0x86 => "Signal invalid",
+        0x87 => "Missing message",
+        0x88 => "Bus off",
+        0x92 => "Performance or incorrect operation",
+        0x98 => "Component or system over temperature",
+        _ => return None
+    })
+}
+
+#[test]
+fn test_generic_decode() {
+    assert_eq!(sae_code(0x0100), "P0100");
+    assert_eq!(sae_code(0xC155), "U0155");
+    let dtc = DecodedDtc::generic(0x9D0013);
+    assert_eq!(dtc.code, "B1D00");
+    assert_eq!(dtc.fault_type.as_deref(), Some("Circuit open"));
+    assert!(!dtc.from_definition);
+    assert_eq!(dtc.to_string(), "B1D00 (9D0013) - Unknown body DTC B1D00 - Circuit open");
+    assert_eq!(DecodedDtc::generic(0x0100FE).fault_type, None);
+}
+
+#[test]
+fn test_sae_dtc_round_trip() {
+    for (number, code) in &[(0x04_2000, "P0420"), (0x52_3400, "C1234"), (0xC1_0000, "U0100"), (0x9D_0013, "B1D00-13"), (0xFF_FFFF, "U3FFF-FF")] {
+        assert_eq!(sae_dtc_string(*number), *code);
+        assert_eq!(parse_sae_dtc(code), Some(*number), "{}", code);
+    }
+    assert_eq!(parse_sae_dtc(" b1d0013 "), Some(0x9D_0013));
+    assert_eq!(parse_sae_dtc("u0100 87"), Some(0xC1_0087));
+    assert_eq!(parse_sae_dtc("P4420"), None);
+    assert_eq!(parse_sae_dtc("X0420"), None);
+    assert_eq!(parse_sae_dtc("P042"), None);
+    assert_eq!(parse_sae_dtc("P0420-1"), None);
+    assert_eq!(parse_sae_dtc("P04G0"), None);
+    assert_eq!(parse_sae_dtc(""), None);
+}
+
+#[test]
+fn test_dtc_formats() {
+    // P0420 with no failure type, from an OBD-II mode 03 response (After the SID and count byte)
+    // and a UDS ReadDTCInformation record
+    let obd = decode_dtcs(&[0x04, 0x20, 0x01, 0x71, 0x00, 0x00], DtcFormat::TwoByte).unwrap();
+    let uds = decode_dtcs(&[0x04, 0x20, 0x00, 0x01, 0x71, 0x00], DtcFormat::ThreeByte).unwrap();
+    assert_eq!(obd, vec![0x042000, 0x017100]);
+    assert_eq!(obd, uds);
+    let codes: Vec<String> = obd.iter().map(|d| sae_dtc_string(*d)).collect();
+    assert_eq!(codes, vec!["P0420", "P0171"]);
+
+    // Same fault, with a failure type only UDS can report
+    let uds = decode_dtcs(&[0xC1, 0x00, 0x87], DtcFormat::ThreeByte).unwrap();
+    let obd = decode_dtcs(&[0xC1, 0x00], DtcFormat::TwoByte).unwrap();
+    assert_eq!(sae_dtc_string(uds[0]), "U0100-87");
+    assert_eq!(sae_dtc_string(obd[0]), "U0100");
+    assert_eq!(uds[0] >> 8, obd[0] >> 8);
+
+    assert_eq!(decode_dtcs(&[0x04, 0x20, 0x00], DtcFormat::TwoByte), None);
+    assert_eq!(decode_dtcs(&[0x04, 0x20], DtcFormat::ThreeByte), None);
+    assert_eq!(decode_dtcs(&[], DtcFormat::TwoByte), Some(vec![]));
+    assert_eq!(DtcFormat::TwoByte.decode(&[0x04]), None);
+}
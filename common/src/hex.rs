@@ -0,0 +1,81 @@
+use thiserror::Error;
+
+/// Errors from parsing a hex string
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum HexError {
+    #[error("'{digit}' in '{token}' is not a hex digit")]
+    InvalidDigit { digit: char, token: String },
+    #[error("'{0}' has no digits")]
+    NoDigits(String),
+    #[error("'{0}' is not a whole number of bytes")]
+    OddLength(String),
+}
+
+pub type Result<T> = std::result::Result<T, HexError>;
+
+/// Parses hex bytes, as typed in by a user or stored in a file.
+///
+/// Bytes may be separated by spaces, commas or colons, have a `0x` prefix, or be run
+/// together (`22F190`). A single digit on its own is a whole byte (`3E 0`), any other odd
+/// number of digits is an error. An empty string is an empty list of bytes
+///
+/// # Example
+/// ```
+/// use common::hex::parse_hex;
+///
+/// assert_eq!(parse_hex("0x22 F1:90").unwrap(), vec![0x22, 0xF1, 0x90]);
+/// assert!(parse_hex("22F").is_err());
+/// ```
+pub fn parse_hex(s: &str) -> Result<Vec<u8>> {
+    let mut res = Vec::new();
+    for token in s.split(|c: char| c.is_whitespace() || c == ',' || c == ':').filter(|t| !t.is_empty()) {
+        let digits = token.strip_prefix("0x").or_else(|| token.strip_prefix("0X")).unwrap_or(token);
+        if let Some(digit) = digits.chars().find(|c| !c.is_ascii_hexdigit()) {
+            return Err(HexError::InvalidDigit { digit, token: token.into() })
+        }
+        match digits.len() {
+            0 => return Err(HexError::NoDigits(token.into())),
+            1 => res.push(u8::from_str_radix(digits, 16).unwrap()),
+            n if n % 2 != 0 => return Err(HexError::OddLength(token.into())),
+            n => {
+                for i in (0..n).step_by(2) {
+                    res.push(u8::from_str_radix(&digits[i..i + 2], 16).unwrap())
+                }
+            }
+        }
+    }
+    Ok(res)
+}
+
+/// Formats bytes as upper case hex, with `sep` between each byte. Example: `22 F1 90` with a separator of `" "`
+pub fn format_hex(bytes: &[u8], sep: &str) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect::<Vec<String>>().join(sep)
+}
+
+#[test]
+fn test_parse_hex() {
+    assert_eq!(parse_hex("22 F1 90"), Ok(vec![0x22, 0xF1, 0x90]));
+    assert_eq!(parse_hex("  0x22,0xf1, 0X90 "), Ok(vec![0x22, 0xF1, 0x90]));
+    assert_eq!(parse_hex("de:ad:BE:ef"), Ok(vec![0xDE, 0xAD, 0xBE, 0xEF]));
+    assert_eq!(parse_hex("22F190\n0102"), Ok(vec![0x22, 0xF1, 0x90, 0x01, 0x02]));
+    assert_eq!(parse_hex("3E 0"), Ok(vec![0x3E, 0x00]));
+    assert_eq!(parse_hex(""), Ok(vec![]));
+    assert_eq!(parse_hex(" ,: "), Ok(vec![]));
+
+    assert_eq!(parse_hex("22 F1G0"), Err(HexError::InvalidDigit { digit: 'G', token: "F1G0".into() }));
+    assert_eq!(parse_hex("22 é"), Err(HexError::InvalidDigit { digit: 'é', token: "é".into() }));
+    assert_eq!(parse_hex("22 F19"), Err(HexError::OddLength("F19".into())));
+    assert_eq!(parse_hex("0x"), Err(HexError::NoDigits("0x".into())));
+    assert_eq!(parse_hex("0x0x12").unwrap_err().to_string(), "'x' in '0x0x12' is not a hex digit");
+}
+
+#[test]
+fn test_hex_round_trip() {
+    let bytes: Vec<u8> = (0..=255).collect();
+    for sep in &["", " ", ":", ", "] {
+        let s = format_hex(&bytes, sep);
+        assert_eq!(parse_hex(&s).unwrap(), bytes, "separator '{}'", sep);
+    }
+    assert_eq!(format_hex(&[0x0A, 0xFF], ":"), "0A:FF");
+    assert_eq!(format_hex(&[], " "), "");
+}
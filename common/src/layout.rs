@@ -0,0 +1,475 @@
+use std::collections::HashMap;
+use crate::raf::{Raf, RafByteOrder, Result};
+
+/// Type of a field in a [Layout]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FieldType {
+    U8,
+    I8,
+    U16,
+    I16,
+    U32,
+    I32,
+    U64,
+    I64,
+    F32,
+    F64,
+    /// Fixed number of raw bytes
+    Bytes(usize),
+    /// Fixed length UTF8 string
+    String(usize),
+    /// String ending in 0x00
+    CStr,
+    /// Binary Coded Decimal number of N bytes
+    Bcd(usize),
+}
+
+impl FieldType {
+    /// Number of bytes the field takes, or None for a NUL terminated string
+    fn size(&self) -> Option<usize> {
+        Some(match self {
+            FieldType::U8 | FieldType::I8 => 1,
+            FieldType::U16 | FieldType::I16 => 2,
+            FieldType::U32 | FieldType::I32 | FieldType::F32 => 4,
+            FieldType::U64 | FieldType::I64 | FieldType::F64 => 8,
+            FieldType::Bytes(len) | FieldType::String(len) | FieldType::Bcd(len) => *len,
+            FieldType::CStr => return None,
+        })
+    }
+
+    /// True for numbers whose byte order matters
+    fn is_multi_byte_number(&self) -> bool {
+        !matches!(self, FieldType::U8 | FieldType::I8 | FieldType::Bytes(_) | FieldType::String(_) | FieldType::CStr | FieldType::Bcd(_))
+    }
+
+    fn c_decl(&self, name: &str) -> String {
+        match self {
+            FieldType::U8 => format!("uint8_t {}", name),
+            FieldType::I8 => format!("int8_t {}", name),
+            FieldType::U16 => format!("uint16_t {}", name),
+            FieldType::I16 => format!("int16_t {}", name),
+            FieldType::U32 => format!("uint32_t {}", name),
+            FieldType::I32 => format!("int32_t {}", name),
+            FieldType::U64 => format!("uint64_t {}", name),
+            FieldType::I64 => format!("int64_t {}", name),
+            FieldType::F32 => format!("float {}", name),
+            FieldType::F64 => format!("double {}", name),
+            FieldType::Bytes(len) | FieldType::Bcd(len) => format!("uint8_t {}[{}]", name, len),
+            FieldType::String(len) => format!("char {}[{}]", name, len),
+            FieldType::CStr => format!("char {}[]", name),
+        }
+    }
+
+    /// Kaitai Struct keys describing the type
+    fn ksy_keys(&self, bo: RafByteOrder) -> Vec<String> {
+        let endian = match bo {
+            RafByteOrder::BE => "be",
+            RafByteOrder::LE => "le",
+            RafByteOrder::Native if cfg!(target_endian = "big") => "be",
+            RafByteOrder::Native => "le",
+        };
+        let number = |t: &str| vec![format!("type: {}{}", t, endian)];
+        match self {
+            FieldType::U8 => vec!["type: u1".into()],
+            FieldType::I8 => vec!["type: s1".into()],
+            FieldType::U16 => number("u2"),
+            FieldType::I16 => number("s2"),
+            FieldType::U32 => number("u4"),
+            FieldType::I32 => number("s4"),
+            FieldType::U64 => number("u8"),
+            FieldType::I64 => number("s8"),
+            FieldType::F32 => number("f4"),
+            FieldType::F64 => number("f8"),
+            FieldType::Bytes(len) => vec![format!("size: {}", len)],
+            FieldType::String(len) => vec!["type: str".into(), format!("size: {}", len), "encoding: UTF-8".into()],
+            FieldType::CStr => vec!["type: strz".into(), "encoding: UTF-8".into()],
+            FieldType::Bcd(len) => vec![format!("size: {}", len), "doc: BCD, 2 digits per byte".into()],
+        }
+    }
+}
+
+/// Value read from a field of a [Layout]
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldValue {
+    Unsigned(u64),
+    Signed(i64),
+    Float(f64),
+    Bytes(Vec<u8>),
+    String(String),
+}
+
+impl std::fmt::Display for FieldValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FieldValue::Unsigned(x) => write!(f, "{}", x),
+            FieldValue::Signed(x) => write!(f, "{}", x),
+            FieldValue::Float(x) => write!(f, "{}", x),
+            FieldValue::Bytes(x) => write!(f, "{:02X?}", x),
+            FieldValue::String(x) => write!(f, "{}", x),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Field {
+    name: String,
+    ty: FieldType,
+    bo: RafByteOrder,
+    /// Offset from the start of the layout. None if it follows the previous field
+    offset: Option<usize>,
+    /// Name of an earlier unsigned field, and the bit of it which must be set for this field to be present
+    present_if: Option<(String, u32)>,
+}
+
+/// Declarative description of a C struct like block of data, read with [Raf::read_layout].
+///
+/// Fields are read in the order they are added. A field either follows the previous one,
+/// or is read at a fixed offset from the start of the layout
+#[derive(Debug, Clone, Default)]
+pub struct Layout {
+    fields: Vec<Field>,
+}
+
+impl Layout {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a field which starts straight after the previous field
+    pub fn field(mut self, name: &str, ty: FieldType, bo: RafByteOrder) -> Self {
+        self.fields.push(Field { name: name.into(), ty, bo, offset: None, present_if: None });
+        self
+    }
+
+    /// Adds a field at an offset from the start of the layout. Following fields continue after it
+    pub fn field_at(mut self, name: &str, offset: usize, ty: FieldType, bo: RafByteOrder) -> Self {
+        self.fields.push(Field { name: name.into(), ty, bo, offset: Some(offset), present_if: None });
+        self
+    }
+
+    /// Adds a field which starts straight after the previous field, but is only present if a bit
+    /// of an earlier unsigned field is set. This is how CBF records mark which fields they store.
+    /// A field which is not present is left out of the values, and takes no space
+    pub fn field_if(mut self, name: &str, ty: FieldType, bo: RafByteOrder, flags: &str, bit: u32) -> Self {
+        self.fields.push(Field { name: name.into(), ty, bo, offset: None, present_if: Some((flags.into(), bit)) });
+        self
+    }
+
+    /// Returns the field names in the order they are read
+    pub fn names(&self) -> Vec<&str> {
+        self.fields.iter().map(|f| f.name.as_str()).collect()
+    }
+
+    /// Describes the layout as a packed C struct named `{name}_t`, so it can be used by other tools.
+    ///
+    /// C has no byte order or optional fields, so the byte order of each number, and the bit an
+    /// optional field depends on, are noted beside the field. Optional fields are included as if
+    /// they are present. Gaps between fields are filled with padding arrays
+    ///
+    /// # Returns
+    /// An error if fields overlap, or a field follows a NUL terminated string (A string can
+    /// only be the last field, where it becomes a flexible array)
+    pub fn to_c_header(&self, name: &str) -> std::result::Result<String, String> {
+        let mut placed = Vec::new();
+        let mut cursor = Some(0);
+        for f in &self.fields {
+            let start = f.offset.or(cursor).ok_or_else(|| format!("{} follows a variable length field", f.name))?;
+            cursor = f.ty.size().map(|len| start + len);
+            placed.push((start, f));
+        }
+        placed.sort_by_key(|(offset, _)| *offset);
+
+        let mut out = String::from("/* Generated by OpenVehicleDiag. Fields are packed, numbers are stored in the byte order\n");
+        out.push_str(" * noted beside them, and optional fields are included as if they are present */\n");
+        out.push_str("#include <stdint.h>\n\n#pragma pack(push, 1)\ntypedef struct {\n");
+        let mut pos = 0;
+        for (idx, (offset, f)) in placed.iter().enumerate() {
+            if *offset < pos {
+                return Err(format!("{} overlaps the field before it", f.name))
+            }
+            if *offset > pos {
+                out.push_str(&format!("    uint8_t _pad_{:04x}[{}];\n", pos, offset - pos));
+            }
+            let mut comment = format!("0x{:04X}", offset);
+            match f.ty.size() {
+                Some(len) => pos = offset + len,
+                None if idx == placed.len() - 1 => comment.push_str(", NUL terminated"),
+                None => return Err(format!("{} follows a variable length field", placed[idx + 1].1.name)),
+            }
+            if f.ty.is_multi_byte_number() {
+                comment.push_str(match f.bo {
+                    RafByteOrder::BE => ", BE",
+                    RafByteOrder::LE => ", LE",
+                    RafByteOrder::Native => ", native",
+                });
+            }
+            if let FieldType::Bcd(_) = f.ty {
+                comment.push_str(", BCD");
+            }
+            if let Some((flags, bit)) = &f.present_if {
+                comment.push_str(&format!(", present if bit {} of {} is set", bit, flags));
+            }
+            out.push_str(&format!("    {}; /* {} */\n", f.ty.c_decl(&f.name), comment));
+        }
+        out.push_str(&format!("}} {}_t;\n#pragma pack(pop)\n", name));
+        Ok(out)
+    }
+
+    /// Describes the layout as a Kaitai Struct (.ksy) type, so it can be used by other tools.
+    ///
+    /// Fields which follow on from each other are in `seq`, with optional fields using `if`.
+    /// A field at an offset after the end of `seq` is added to `seq` after padding, otherwise it
+    /// becomes an instance at its offset, as do the fields which follow on from it
+    ///
+    /// # Returns
+    /// An error if the offset of an instance depends on a variable length or optional field
+    pub fn to_ksy(&self, id: &str) -> std::result::Result<String, String> {
+        let mut seq = String::new();
+        let mut instances = String::new();
+        // End of seq, and where the next field starts. None if it depends on the data
+        let mut seq_end = Some(0);
+        let mut cursor = Some(0);
+        // False once a field has been placed before the end of seq, until a field is at an offset after it
+        let mut at_seq_end = true;
+        for f in &self.fields {
+            let mut keys = f.ty.ksy_keys(f.bo);
+            if let Some((flags, bit)) = &f.present_if {
+                keys.push(format!("if: (({} >> {}) & 1) == 1", flags, bit));
+            }
+            let len = if f.present_if.is_some() { None } else { f.ty.size() };
+            at_seq_end = match (f.offset, seq_end) {
+                (Some(offset), Some(end)) if offset >= end => {
+                    if offset > end {
+                        seq.push_str(&format!("  - id: pad_{:x}\n    size: {}\n", end, offset - end));
+                    }
+                    true
+                },
+                (Some(_), _) => false,
+                (None, _) => at_seq_end,
+            };
+            let start = f.offset.or(cursor);
+            if at_seq_end {
+                seq.push_str(&format!("  - id: {}\n", f.name));
+                keys.iter().for_each(|k| seq.push_str(&format!("    {}\n", k)));
+                seq_end = start.and_then(|s| len.map(|l| s + l));
+            } else {
+                let pos = start.ok_or_else(|| format!("Offset of {} depends on the data", f.name))?;
+                instances.push_str(&format!("  {}:\n    pos: {}\n", f.name, pos));
+                keys.iter().for_each(|k| instances.push_str(&format!("    {}\n", k)));
+            }
+            cursor = start.and_then(|s| len.map(|l| s + l));
+        }
+        let mut out = format!("meta:\n  id: {}\n", id);
+        if !seq.is_empty() {
+            out.push_str("seq:\n");
+            out.push_str(&seq);
+        }
+        if !instances.is_empty() {
+            out.push_str("instances:\n");
+            out.push_str(&instances);
+        }
+        Ok(out)
+    }
+}
+
+impl Raf {
+    /// Reads every field of a layout, starting at the current position.
+    ///
+    /// If a field cannot be read, the error is returned and the position is not changed.
+    /// Otherwise the position is left after the last byte of the layout. If 2 fields have
+    /// the same name, the value of the last one is kept
+    ///
+    /// # Returns
+    /// The value of each field, keyed by its name
+    pub fn read_layout(&mut self, layout: &Layout) -> Result<HashMap<String, FieldValue>> {
+        let start = self.pos;
+        let prev_bo = self.get_byte_order();
+        let mut end = start;
+        let mut res = HashMap::new();
+        for field in &layout.fields {
+            if let Some((flags, bit)) = &field.present_if {
+                match res.get(flags) {
+                    Some(FieldValue::Unsigned(x)) if *bit < 64 && x >> bit & 1 != 0 => {},
+                    _ => continue
+                }
+            }
+            self.set_byte_order(field.bo);
+            let value = match field.offset {
+                Some(offset) => self.seek_checked(start + offset).and_then(|_| self.read_field(field.ty)),
+                None => self.read_field(field.ty)
+            };
+            self.set_byte_order(prev_bo);
+            match value {
+                Ok(v) => res.insert(field.name.clone(), v),
+                Err(e) => {
+                    self.pos = start;
+                    return Err(e)
+                }
+            };
+            end = std::cmp::max(end, self.pos);
+        }
+        self.pos = end;
+        Ok(res)
+    }
+
+    fn read_field(&mut self, ty: FieldType) -> Result<FieldValue> {
+        Ok(match ty {
+            FieldType::U8 => FieldValue::Unsigned(self.read_u8()? as u64),
+            FieldType::I8 => FieldValue::Signed(self.read_i8()? as i64),
+            FieldType::U16 => FieldValue::Unsigned(self.read_u16()? as u64),
+            FieldType::I16 => FieldValue::Signed(self.read_i16()? as i64),
+            FieldType::U32 => FieldValue::Unsigned(self.read_u32()? as u64),
+            FieldType::I32 => FieldValue::Signed(self.read_i32()? as i64),
+            FieldType::U64 => FieldValue::Unsigned(self.read_u64()?),
+            FieldType::I64 => FieldValue::Signed(self.read_i64()?),
+            FieldType::F32 => FieldValue::Float(self.read_f32()? as f64),
+            FieldType::F64 => FieldValue::Float(self.read_f64()?),
+            FieldType::Bytes(len) => FieldValue::Bytes(self.read_bytes(len)?),
+            FieldType::String(len) => FieldValue::String(self.read_string(len)?),
+            FieldType::CStr => FieldValue::String(self.read_cstr()?),
+            FieldType::Bcd(len) => FieldValue::Unsigned(self.read_bcd(len)?),
+        })
+    }
+}
+
+#[test]
+fn test_read_layout() {
+    let data: Vec<u8> = vec![
+        0xAA, 0xBB, // Padding
+        b'C', b'B', b'F', 0x00, // Magic
+        0x34, 0x12, // LE version
+        0xFF, 0xFE, // BE i16
+        0x00, 0x00, 0x80, 0x3F, // LE f32
+        0x20, 0x21, // BCD
+        0xDE, 0xAD, // Raw
+        0x07, // Count, read by offset
+    ];
+    let layout = Layout::new()
+        .field("magic", FieldType::CStr, RafByteOrder::LE)
+        .field("version", FieldType::U16, RafByteOrder::LE)
+        .field("delta", FieldType::I16, RafByteOrder::BE)
+        .field_at("count", 16, FieldType::U8, RafByteOrder::LE)
+        .field_at("scale", 8, FieldType::F32, RafByteOrder::LE)
+        .field("year", FieldType::Bcd(2), RafByteOrder::BE)
+        .field("raw", FieldType::Bytes(2), RafByteOrder::BE);
+    assert_eq!(layout.names(), vec!["magic", "version", "delta", "count", "scale", "year", "raw"]);
+
+    let mut raf = Raf::from_bytes(&data, RafByteOrder::BE);
+    raf.seek(2).unwrap();
+    let values = raf.read_layout(&layout).unwrap();
+    assert_eq!(values.len(), 7);
+    assert_eq!(values["magic"], FieldValue::String("CBF".into()));
+    assert_eq!(values["version"], FieldValue::Unsigned(0x1234));
+    assert_eq!(values["delta"], FieldValue::Signed(-2));
+    assert_eq!(values["count"], FieldValue::Unsigned(7));
+    assert_eq!(values["scale"], FieldValue::Float(1.0));
+    assert_eq!(values["year"], FieldValue::Unsigned(2021));
+    assert_eq!(values["raw"], FieldValue::Bytes(vec![0xDE, 0xAD]));
+    assert_eq!(values["raw"].to_string(), "[DE, AD]");
+    assert_eq!(raf.pos, data.len());
+    // Layout byte orders do not change the reader's
+    assert_eq!(raf.get_byte_order(), RafByteOrder::BE);
+
+    // Runs off the end, so nothing is read
+    raf.seek(10).unwrap();
+    assert!(raf.read_layout(&layout).is_err());
+    assert_eq!(raf.pos, 10);
+}
+
+#[cfg(test)]
+fn test_record_layout() -> Layout {
+    Layout::new()
+        .field("magic", FieldType::String(4), RafByteOrder::LE)
+        .field("version", FieldType::U16, RafByteOrder::BE)
+        .field("flags", FieldType::U8, RafByteOrder::LE)
+        .field_if("checksum", FieldType::U32, RafByteOrder::LE, "flags", 0)
+        .field_at("id", 0x0C, FieldType::Bytes(2), RafByteOrder::LE)
+        .field_at("count", 0x10, FieldType::I16, RafByteOrder::LE)
+        .field("name", FieldType::CStr, RafByteOrder::LE)
+}
+
+#[test]
+fn test_optional_fields() {
+    let mut data = vec![b'T', b'E', b'S', b'T', 0x00, 0x02, 0x01, 0x78, 0x56, 0x34, 0x12, 0x00, 0xAB, 0xCD, 0x00, 0x00, 0x05, 0x00, b'A', 0x00];
+    let values = Raf::from_bytes(&data, RafByteOrder::BE).read_layout(&test_record_layout()).unwrap();
+    assert_eq!(values["checksum"], FieldValue::Unsigned(0x1234_5678));
+    assert_eq!(values["name"], FieldValue::String("A".into()));
+
+    // Bit 0 clear, so checksum is not stored
+    data[6] = 0x00;
+    let layout = Layout::new()
+        .field("flags", FieldType::U8, RafByteOrder::LE)
+        .field_if("checksum", FieldType::U32, RafByteOrder::LE, "flags", 0)
+        .field("next", FieldType::U8, RafByteOrder::LE);
+    let mut raf = Raf::from_bytes(&data, RafByteOrder::BE);
+    raf.seek(6).unwrap();
+    let values = raf.read_layout(&layout).unwrap();
+    assert!(!values.contains_key("checksum"));
+    assert_eq!(values["next"], FieldValue::Unsigned(0x78));
+    assert_eq!(raf.pos, 8);
+}
+
+#[test]
+fn test_export_c_header() {
+    assert_eq!(test_record_layout().to_c_header("test_record").unwrap(), "\
+/* Generated by OpenVehicleDiag. Fields are packed, numbers are stored in the byte order
+ * noted beside them, and optional fields are included as if they are present */
+#include <stdint.h>
+
+#pragma pack(push, 1)
+typedef struct {
+    char magic[4]; /* 0x0000 */
+    uint16_t version; /* 0x0004, BE */
+    uint8_t flags; /* 0x0006 */
+    uint32_t checksum; /* 0x0007, LE, present if bit 0 of flags is set */
+    uint8_t _pad_000b[1];
+    uint8_t id[2]; /* 0x000C */
+    uint8_t _pad_000e[2];
+    int16_t count; /* 0x0010, LE */
+    char name[]; /* 0x0012, NUL terminated */
+} test_record_t;
+#pragma pack(pop)
+");
+    let after_string = Layout::new().field("name", FieldType::CStr, RafByteOrder::LE).field("len", FieldType::U8, RafByteOrder::LE);
+    assert_eq!(after_string.to_c_header("x"), Err("len follows a variable length field".into()));
+    let overlap = Layout::new().field("a", FieldType::U32, RafByteOrder::LE).field_at("b", 2, FieldType::U8, RafByteOrder::LE);
+    assert_eq!(overlap.to_c_header("x"), Err("b overlaps the field before it".into()));
+}
+
+#[test]
+fn test_export_ksy() {
+    assert_eq!(test_record_layout().to_ksy("test_record").unwrap(), "\
+meta:
+  id: test_record
+seq:
+  - id: magic
+    type: str
+    size: 4
+    encoding: UTF-8
+  - id: version
+    type: u2be
+  - id: flags
+    type: u1
+  - id: checksum
+    type: u4le
+    if: ((flags >> 0) & 1) == 1
+instances:
+  id:
+    pos: 12
+    size: 2
+  count:
+    pos: 16
+    type: s2le
+  name:
+    pos: 18
+    type: strz
+    encoding: UTF-8
+");
+    // Gaps in seq are padded
+    let padded = Layout::new().field("a", FieldType::U8, RafByteOrder::LE).field_at("b", 4, FieldType::Bcd(2), RafByteOrder::LE);
+    assert_eq!(padded.to_ksy("p").unwrap(), "meta:\n  id: p\nseq:\n  - id: a\n    type: u1\n  - id: pad_1\n    size: 3\n  - id: b\n    size: 2\n    doc: BCD, 2 digits per byte\n");
+    let unknown = Layout::new().field("name", FieldType::CStr, RafByteOrder::LE).field_at("a", 0, FieldType::U8, RafByteOrder::LE).field("b", FieldType::U8, RafByteOrder::LE);
+    assert_eq!(unknown.to_ksy("x").unwrap().matches("pos:").count(), 2);
+    let unknown = Layout::new().field_at("a", 4, FieldType::CStr, RafByteOrder::LE).field_at("b", 0, FieldType::U8, RafByteOrder::LE).field_at("c", 8, FieldType::CStr, RafByteOrder::LE).field("d", FieldType::U8, RafByteOrder::LE);
+    assert!(unknown.to_ksy("x").is_err());
+}
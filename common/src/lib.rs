@@ -1,3 +1,13 @@
 pub mod odb2;
+pub mod dtc;
+pub mod diff;
+pub mod hex;
+pub mod alarm;
+pub mod decoder;
+pub mod layout;
+pub mod measurement;
+pub mod parse_path;
 pub mod raf;
+pub mod raf_writer;
+pub mod records;
 pub mod schema;
\ No newline at end of file
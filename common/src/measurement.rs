@@ -0,0 +1,350 @@
+use serde::{Deserialize, Serialize};
+
+/// How a raw value read from the ECU is converted to its physical value (ODX COMPU-METHOD)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum CompuMethod {
+    /// Raw value is the physical value
+    Identical,
+    /// physical = raw * factor + offset
+    Linear { factor: f64, offset: f64 },
+    /// Raw values map to text. Example: 0 -> "Off"
+    TextTable(Vec<(i64, String)>),
+}
+
+impl Default for CompuMethod {
+    fn default() -> Self {
+        CompuMethod::Identical
+    }
+}
+
+/// A measurement DID declared by a definition
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DidDef {
+    /// Data identifier
+    pub did: u16,
+    /// Name of the measurement. Example: Transmission oil temperature
+    pub name: String,
+    /// Offset of the raw value in the response data, after the DID
+    #[serde(default)]
+    pub byte_offset: usize,
+    /// Length of the raw value in bytes (1-8). The value is big endian
+    pub byte_len: usize,
+    /// True if the raw value is two's complement signed
+    #[serde(default)]
+    pub signed: bool,
+    #[serde(default)]
+    pub compu: CompuMethod,
+    /// Unit of the physical value. Example: °C
+    #[serde(default)]
+    pub unit: Option<String>,
+    /// Number of decimal places to show the physical value with. If not set, this is
+    /// inferred from the resolution of the scaling
+    #[serde(default)]
+    pub decimals: Option<u8>,
+}
+
+/// Physical value of a measurement
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScaledValue {
+    Number { value: f64, unit: Option<String> },
+    Text(String),
+}
+
+impl std::fmt::Display for ScaledValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScaledValue::Number { value, unit: Some(u) } => write!(f, "{} {}", value, u),
+            ScaledValue::Number { value, unit: None } => write!(f, "{}", value),
+            ScaledValue::Text(t) => write!(f, "{}", t),
+        }
+    }
+}
+
+/// Most decimal places inferred from a scaling resolution
+const MAX_INFERRED_DECIMALS: u8 = 6;
+
+/// A physical value ready to be displayed, with numbers rounded to a fixed number of decimal places
+#[derive(Debug, Clone, PartialEq)]
+pub struct FormattedValue {
+    pub value: ScaledValue,
+    pub decimals: u8,
+}
+
+impl FormattedValue {
+    /// Returns the number formatted without the unit, or the text of a text table value.
+    /// This is the form used for CSV export, where the unit is in the column header
+    pub fn value_string(&self) -> String {
+        match &self.value {
+            ScaledValue::Number { value, .. } => {
+                let s = format!("{:.*}", self.decimals as usize, value);
+                // Don't show -0.0 for small negative values which round to 0
+                if s.starts_with('-') && s[1..].chars().all(|c| c == '0' || c == '.') {
+                    s[1..].into()
+                } else {
+                    s
+                }
+            },
+            ScaledValue::Text(t) => t.clone(),
+        }
+    }
+}
+
+impl std::fmt::Display for FormattedValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.value {
+            ScaledValue::Number { unit: Some(u), .. } => write!(f, "{} {}", self.value_string(), u),
+            _ => write!(f, "{}", self.value_string()),
+        }
+    }
+}
+
+/// System of units values are shown in
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum UnitSystem {
+    Metric,
+    /// US customary units (mph, °F, psi...)
+    Imperial,
+}
+
+impl Default for UnitSystem {
+    fn default() -> Self {
+        UnitSystem::Metric
+    }
+}
+
+/// Linear conversions between metric and imperial units: (metric, imperial, factor, offset),
+/// where imperial = metric * factor + offset. Units are matched ignoring case
+const UNIT_CONVERSIONS: &[(&str, &str, f64, f64)] = &[
+    ("km/h", "mph", 0.621_371_192, 0.0),
+    ("km", "mi", 0.621_371_192, 0.0),
+    ("m", "ft", 3.280_839_895, 0.0),
+    ("mm", "in", 0.039_370_079, 0.0),
+    ("°C", "°F", 1.8, 32.0),
+    ("kPa", "psi", 0.145_037_738, 0.0),
+    ("bar", "psi", 14.503_773_773, 0.0),
+    ("hPa", "inHg", 0.029_529_983, 0.0),
+    ("l", "gal", 0.264_172_052, 0.0),
+    ("kg", "lb", 2.204_622_622, 0.0),
+    ("Nm", "lb⋅ft", 0.737_562_149, 0.0),
+    ("kW", "hp", 1.341_022_090, 0.0),
+    ("g/s", "lb/min", 0.132_277_357, 0.0),
+];
+
+impl FormattedValue {
+    /// Converts the value to another system of units, for display. Units which are already in
+    /// the target system, are not known or have no counterpart (Example: rpm) are returned unchanged.
+    /// The value is shown with the same number of decimal places as before
+    pub fn to_unit_system(&self, system: UnitSystem) -> FormattedValue {
+        let (value, unit) = match &self.value {
+            ScaledValue::Number { value, unit: Some(u) } => (*value, u),
+            _ => return self.clone(),
+        };
+        let converted = UNIT_CONVERSIONS.iter().find_map(|(metric, imperial, factor, offset)| match system {
+            UnitSystem::Imperial if unit.eq_ignore_ascii_case(metric) => Some((value * factor + offset, *imperial)),
+            UnitSystem::Metric if unit.eq_ignore_ascii_case(imperial) => Some(((value - offset) / factor, *metric)),
+            _ => None
+        });
+        match converted {
+            Some((value, unit)) => FormattedValue { value: ScaledValue::Number { value, unit: Some(unit.into()) }, decimals: self.decimals },
+            None => self.clone(),
+        }
+    }
+}
+
+/// Returns the fewest decimal places which can show every multiple of `resolution` exactly
+fn decimals_for(resolution: f64) -> u8 {
+    (0..MAX_INFERRED_DECIMALS)
+        .find(|d| {
+            let scaled = resolution.abs() * 10f64.powi(*d as i32);
+            (scaled - scaled.round()).abs() < 1e-9 * scaled.max(1.0)
+        })
+        .unwrap_or(MAX_INFERRED_DECIMALS)
+}
+
+/// Errors that can be returned when scaling a raw value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScaleError {
+    /// The response is too short to contain the value
+    TooShort { needed: usize, got: usize },
+    /// The definition declares a value length that cannot be decoded
+    InvalidLength(usize),
+    /// The raw value is not in the text table
+    NoTextMatch(i64),
+    /// The definition declares no fields for the DID
+    UnknownDid(u16),
+}
+
+impl std::fmt::Display for ScaleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScaleError::TooShort { needed, got } => write!(f, "Response too short, needed {} bytes, got {}", needed, got),
+            ScaleError::InvalidLength(l) => write!(f, "Cannot decode a {} byte value", l),
+            ScaleError::NoTextMatch(raw) => write!(f, "No text for raw value {}", raw),
+            ScaleError::UnknownDid(did) => write!(f, "DID 0x{:04X} is not in the definition", did),
+        }
+    }
+}
+
+impl std::error::Error for ScaleError {}
+
+impl DidDef {
+    /// Extracts the raw value from the data read from the DID
+    pub fn raw_value(&self, data: &[u8]) -> Result<i64, ScaleError> {
+        if self.byte_len == 0 || self.byte_len > 8 {
+            return Err(ScaleError::InvalidLength(self.byte_len))
+        }
+        let end = self.byte_offset + self.byte_len;
+        if data.len() < end {
+            return Err(ScaleError::TooShort { needed: end, got: data.len() })
+        }
+        let raw = data[self.byte_offset..end].iter().fold(0u64, |acc, b| acc << 8 | *b as u64);
+        let bits = self.byte_len as u32 * 8;
+        Ok(if self.signed && bits < 64 {
+            // Sign extend
+            ((raw << (64 - bits)) as i64) >> (64 - bits)
+        } else {
+            raw as i64
+        })
+    }
+
+    /// Converts the data read from the DID to its physical value
+    pub fn scale(&self, data: &[u8]) -> Result<ScaledValue, ScaleError> {
+        let raw = self.raw_value(data)?;
+        let unit = self.unit.clone();
+        match &self.compu {
+            CompuMethod::Identical => Ok(ScaledValue::Number { value: raw as f64, unit }),
+            CompuMethod::Linear { factor, offset } => Ok(ScaledValue::Number { value: raw as f64 * factor + offset, unit }),
+            CompuMethod::TextTable(table) => table.iter()
+                .find(|(v, _)| *v == raw)
+                .map(|(_, t)| ScaledValue::Text(t.clone()))
+                .ok_or(ScaleError::NoTextMatch(raw)),
+        }
+    }
+
+    /// Returns the number of decimal places the physical value is shown with
+    pub fn display_decimals(&self) -> u8 {
+        if let Some(d) = self.decimals {
+            return d
+        }
+        match &self.compu {
+            CompuMethod::Linear { factor, offset } => std::cmp::max(decimals_for(*factor), decimals_for(*offset)),
+            _ => 0,
+        }
+    }
+
+    /// Converts the data read from the DID to its physical value, rounded for display
+    pub fn format(&self, data: &[u8]) -> Result<FormattedValue, ScaleError> {
+        Ok(FormattedValue { value: self.scale(data)?, decimals: self.display_decimals() })
+    }
+}
+
+#[test]
+fn test_scale() {
+    let mut temp = DidDef {
+        did: 0x1100,
+        name: "Oil temperature".into(),
+        byte_offset: 1,
+        byte_len: 1,
+        signed: false,
+        compu: CompuMethod::Linear { factor: 1.0, offset: -40.0 },
+        unit: Some("°C".into()),
+        decimals: None,
+    };
+    assert_eq!(temp.scale(&[0xFF, 0x82]).unwrap().to_string(), "90 °C");
+    assert_eq!(temp.scale(&[0xFF]), Err(ScaleError::TooShort { needed: 2, got: 1 }));
+
+    temp.byte_len = 2;
+    temp.byte_offset = 0;
+    temp.signed = true;
+    temp.compu = CompuMethod::Identical;
+    assert_eq!(temp.raw_value(&[0xFF, 0xF6]), Ok(-10));
+
+    let gear = DidDef {
+        did: 0x1101,
+        name: "Gear".into(),
+        byte_offset: 0,
+        byte_len: 1,
+        signed: false,
+        compu: CompuMethod::TextTable(vec![(0, "Park".into()), (1, "Drive".into())]),
+        unit: None,
+        decimals: None,
+    };
+    assert_eq!(gear.scale(&[0x01]), Ok(ScaledValue::Text("Drive".into())));
+    assert_eq!(gear.scale(&[0x05]), Err(ScaleError::NoTextMatch(5)));
+}
+
+#[test]
+fn test_format() {
+    let mut speed = DidDef {
+        did: 0x1200,
+        name: "Vehicle speed".into(),
+        byte_offset: 0,
+        byte_len: 2,
+        signed: false,
+        compu: CompuMethod::Linear { factor: 0.01, offset: 0.0 },
+        unit: Some("km/h".into()),
+        decimals: None,
+    };
+    // 0x5F00 * 0.01 is not exactly 243.2 as a float
+    assert_eq!(speed.display_decimals(), 2);
+    assert_eq!(speed.format(&[0x5F, 0x00]).unwrap().to_string(), "243.20 km/h");
+    speed.decimals = Some(0);
+    assert_eq!(speed.format(&[0x5F, 0x00]).unwrap().to_string(), "243 km/h");
+
+    let temp = DidDef {
+        did: 0x1100,
+        name: "Oil temperature".into(),
+        byte_offset: 0,
+        byte_len: 1,
+        signed: true,
+        compu: CompuMethod::Linear { factor: 0.25, offset: -0.5 },
+        unit: Some("°C".into()),
+        decimals: Some(1),
+    };
+    assert_eq!(temp.format(&[0x04]).unwrap().to_string(), "0.5 °C");
+    assert_eq!(temp.format(&[0x01]).unwrap().value_string(), "-0.2");
+    // Small negative values which round to 0 have no sign
+    let noise = FormattedValue { value: ScaledValue::Number { value: -0.04, unit: None }, decimals: 1 };
+    assert_eq!(noise.value_string(), "0.0");
+    assert_eq!(decimals_for(0.25), 2);
+    assert_eq!(decimals_for(1.0 / 3.0), MAX_INFERRED_DECIMALS);
+
+    let gear = DidDef {
+        did: 0x1101,
+        name: "Gear".into(),
+        byte_offset: 0,
+        byte_len: 1,
+        signed: false,
+        compu: CompuMethod::TextTable(vec![(0, "Park".into()), (1, "Drive".into())]),
+        unit: None,
+        decimals: Some(2),
+    };
+    assert_eq!(gear.format(&[0x00]).unwrap().to_string(), "Park");
+}
+
+#[test]
+fn test_unit_conversion() {
+    let value = |v: f64, unit: &str, decimals: u8| FormattedValue { value: ScaledValue::Number { value: v, unit: Some(unit.into()) }, decimals };
+
+    assert_eq!(value(100.0, "km/h", 0).to_unit_system(UnitSystem::Imperial).to_string(), "62 mph");
+    assert_eq!(value(90.0, "°C", 1).to_unit_system(UnitSystem::Imperial).to_string(), "194.0 °F");
+    assert_eq!(value(-40.0, "°C", 0).to_unit_system(UnitSystem::Imperial).to_string(), "-40 °F");
+    assert_eq!(value(250.0, "kPa", 1).to_unit_system(UnitSystem::Imperial).to_string(), "36.3 psi");
+    assert_eq!(value(101.3, "KPA", 2).to_unit_system(UnitSystem::Imperial).to_string(), "14.69 psi");
+
+    // Original value is kept
+    let speed = value(130.0, "km/h", 0);
+    let mph = speed.to_unit_system(UnitSystem::Imperial);
+    assert_eq!(mph.value_string(), "81");
+    assert_eq!(speed.to_string(), "130 km/h");
+    assert_eq!(mph.to_unit_system(UnitSystem::Metric).to_string(), "130 km/h");
+    assert_eq!(value(212.0, "°F", 0).to_unit_system(UnitSystem::Metric).to_string(), "100 °C");
+
+    // Already in the target system, unknown or no unit
+    assert_eq!(speed.to_unit_system(UnitSystem::Metric), speed);
+    assert_eq!(value(850.0, "rpm", 0).to_unit_system(UnitSystem::Imperial), value(850.0, "rpm", 0));
+    let gear = FormattedValue { value: ScaledValue::Text("Drive".into()), decimals: 0 };
+    assert_eq!(gear.to_unit_system(UnitSystem::Imperial), gear);
+    let unitless = FormattedValue { value: ScaledValue::Number { value: 3.0, unit: None }, decimals: 0 };
+    assert_eq!(unitless.to_unit_system(UnitSystem::Imperial), unitless);
+}
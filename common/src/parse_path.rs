@@ -0,0 +1,117 @@
+use thiserror::Error;
+use crate::raf::{Raf, RafError};
+
+/// Error from reading a field, with the path of the field which was being read
+#[derive(Debug, Error)]
+#[error("{path}: {source} at 0x{offset:X}")]
+pub struct PathError {
+    /// Path of the field. Example: `cbf.block[3].service[12].name`
+    pub path: String,
+    /// Position the field starts at
+    pub offset: usize,
+    pub source: RafError,
+}
+
+pub type PathResult<T> = std::result::Result<T, PathError>;
+
+/// Wrapper around a [Raf] which tracks which structure and field is being read, so an error
+/// deep inside a nested structure says where it happened rather than only the offset.
+///
+/// Parsers call [scope](fn@ParseContext::scope) or [item](fn@ParseContext::item) when they
+/// descend into a structure, and read each field with [field](fn@ParseContext::field)
+#[derive(Debug)]
+pub struct ParseContext<'a> {
+    raf: &'a mut Raf,
+    path: Vec<String>,
+}
+
+impl<'a> ParseContext<'a> {
+    /// # Params
+    /// * root - Name of the outermost structure. Example: `cbf`
+    pub fn new(raf: &'a mut Raf, root: &str) -> Self {
+        Self { raf, path: vec![root.into()] }
+    }
+
+    /// Returns the path to the structure being read, joined with `.`
+    pub fn path(&self) -> String {
+        self.path.join(".")
+    }
+
+    /// Returns the reader, for moving the position. Reads should go through
+    /// [field](fn@ParseContext::field) so errors have a path
+    pub fn raf(&mut self) -> &mut Raf {
+        self.raf
+    }
+
+    /// Reads the contents of a named structure
+    pub fn scope<T, F: FnOnce(&mut Self) -> PathResult<T>>(&mut self, name: &str, f: F) -> PathResult<T> {
+        self.path.push(name.into());
+        let res = f(self);
+        self.path.pop();
+        res
+    }
+
+    /// Reads one entry of a list of structures. The entry shows as `name[index]` in the path
+    pub fn item<T, F: FnOnce(&mut Self) -> PathResult<T>>(&mut self, name: &str, index: usize, f: F) -> PathResult<T> {
+        self.scope(&format!("{}[{}]", name, index), f)
+    }
+
+    /// Reads a field, adding the path of the field to any error
+    ///
+    /// # Example
+    /// ```
+    /// use common::raf::{Raf, RafByteOrder};
+    /// use common::parse_path::ParseContext;
+    ///
+    /// let mut raf = Raf::from_bytes(&vec![0x00, 0x01], RafByteOrder::BE);
+    /// let mut ctx = ParseContext::new(&mut raf, "header");
+    /// assert_eq!(ctx.field("version", Raf::read_u16).unwrap(), 1);
+    /// assert_eq!(ctx.field("size", Raf::read_u32).unwrap_err().to_string(),
+    ///            "header.size: Read runs past the end of the data at 0x2");
+    /// ```
+    pub fn field<T, F: FnOnce(&mut Raf) -> crate::raf::Result<T>>(&mut self, name: &str, f: F) -> PathResult<T> {
+        let offset = self.raf.pos;
+        f(&mut *self.raf).map_err(|source| PathError { path: format!("{}.{}", self.path(), name), offset, source })
+    }
+}
+
+#[test]
+fn test_nested_error_path() {
+    use crate::raf::RafByteOrder;
+
+    // 4 blocks, each with a count of services then (id: u16, name: 2 character string)
+    // services. The last name of the last block runs past the end of the data
+    let mut data = Vec::new();
+    for block in 0..4u8 {
+        let count = if block == 3 { 13 } else { 1 };
+        data.push(count);
+        for svc in 0..count {
+            data.extend_from_slice(&[0x00, svc, b'O', b'K']);
+        }
+    }
+    data.truncate(data.len() - 1);
+    let bad_offset = data.len() - 1;
+
+    let mut raf = Raf::from_bytes(&data, RafByteOrder::BE);
+    let mut ctx = ParseContext::new(&mut raf, "cbf");
+    let mut names = Vec::new();
+    let res: PathResult<()> = (0..4).try_for_each(|b| ctx.item("block", b, |ctx| {
+        let count = ctx.field("service_count", Raf::read_u8)?;
+        for s in 0..count as usize {
+            ctx.item("service", s, |ctx| {
+                ctx.field("id", Raf::read_u16)?;
+                names.push(ctx.field("name", |r| r.read_string(2))?);
+                Ok(())
+            })?;
+        }
+        Ok(())
+    }));
+    let err = res.unwrap_err();
+    assert_eq!(err.path, "cbf.block[3].service[12].name");
+    assert_eq!(err.offset, bad_offset);
+    assert!(matches!(err.source, RafError::BufferOverflow));
+    assert_eq!(err.to_string(), format!("cbf.block[3].service[12].name: Read runs past the end of the data at 0x{:X}", bad_offset));
+    assert_eq!(names.len(), 15);
+    // Path is unwound once the error is returned
+    assert_eq!(ctx.path(), "cbf");
+}
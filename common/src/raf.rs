@@ -1,43 +1,282 @@
 use byteorder::{BigEndian, ByteOrder, LittleEndian};
-use std::io::Read;
+use chrono::NaiveDate;
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+use std::io::{BufReader, Read};
+use std::ops::Range;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+use thiserror::Error;
 
 /// Random Access file
 ///
 /// Represents a stream of bytes
 /// that can be read in order
-/// or read data at specific offsets
-#[derive(Debug)]
+/// or read data at specific offsets.
+///
+/// Clones share the same data, but each has its own position
+#[derive(Debug, Clone)]
 pub struct Raf {
-    /// Data in bytes
-    data: Vec<u8>,
+    /// Data in bytes. Never changed once the [Raf] holds it
+    data: Arc<Vec<u8>>,
     /// Max size of buffer
     size: usize,
     /// Current pos in buffer
     pub pos: usize,
     /// Byte order
     bo: RafByteOrder,
+    /// Largest number of bytes a single read may allocate
+    alloc_limit: usize,
+    /// Seeking past the end of the data is an error, see [Raf::strict_mode]
+    strict: bool,
 }
 
 pub type Result<T> = std::result::Result<T, RafError>;
 
 /// Errors that can be returned during reading of data
-#[derive(Debug)]
+#[derive(Debug, Error)]
 pub enum RafError {
     /// End index requested exceeds the size of the data stored
+    #[error("Read runs past the end of the data")]
     BufferOverflow,
     /// Start index of requested data is more than the max data stored
+    #[error("Start position is past the end of the data")]
     StartOutOfRange,
     /// String parse failed. Due to invalid UTF8 Characters
-    StrParseError,
+    #[error("String at {str_offset} is not valid UTF8 after {valid_up_to} bytes")]
+    StrParseError {
+        /// Position in the buffer where the string starts
+        str_offset: usize,
+        /// Index within the string of the first invalid UTF8 byte
+        valid_up_to: usize,
+    },
+    /// Checksum stored in the data does not match the checksum computed over it
+    #[error("Checksum mismatch, expected 0x{expected:08X} but data has 0x{actual:08X}")]
+    ChecksumMismatch {
+        /// Checksum stored in the data
+        expected: u32,
+        /// Checksum computed over the data
+        actual: u32,
+    },
+    /// A single read would allocate more than the limit set with [Raf::set_alloc_limit]
+    #[error("Reading {requested} bytes exceeds the allocation limit of {limit} bytes")]
+    AllocationLimitExceeded {
+        /// Number of bytes the read needed
+        requested: usize,
+        /// Current allocation limit
+        limit: usize,
+    },
+    /// A BCD field contains a nibble over 9
+    #[error("Invalid BCD byte 0x{byte:02X} at {offset}")]
+    InvalidBcd {
+        /// Position in the buffer of the invalid byte
+        offset: usize,
+        byte: u8,
+    },
+    /// A BCD field has more digits than fit in the integer type
+    #[error("{0} byte BCD value is too long")]
+    BcdTooLong(usize),
+    /// A date field holds a day or month which does not exist
+    #[error("Invalid date {year:04}-{month:02}-{day:02} at {offset}")]
+    InvalidDate {
+        /// Position in the buffer where the date starts
+        offset: usize,
+        year: i32,
+        month: u32,
+        day: u32,
+    },
+    /// A compressed section could not be decompressed
+    #[error("Compressed data at {offset} is invalid: {reason}")]
+    InvalidCompressedData {
+        /// Position in the buffer where the compressed data starts
+        offset: usize,
+        reason: String,
+    },
+    /// A record parser returned without moving past the record, so would read it forever
+    #[error("Record at {offset} was parsed without advancing the position")]
+    NoProgress {
+        /// Position of the record
+        offset: usize,
+    },
+}
+
+/// Layout of a date stored as BCD, with 2 digits per byte
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DateFormat {
+    /// Year, month, day. 2 digit years below the pivot are 20xx, the rest are 19xx
+    YYMMDD { pivot: u8 },
+    /// Day, month, year. 2 digit years below the pivot are 20xx, the rest are 19xx
+    DDMMYY { pivot: u8 },
+    /// 4 digit year, month, day
+    YYYYMMDD,
+}
+
+impl DateFormat {
+    fn len(&self) -> usize {
+        match self {
+            DateFormat::YYYYMMDD => 4,
+            _ => 3,
+        }
+    }
+}
+
+/// Decodes the character at the start of `data` if it is printable
+///
+/// # Returns
+/// The character and its length in bytes
+fn printable_char(data: &[u8], utf8: bool) -> Option<(char, usize)> {
+    let b = data[0];
+    if b == b'\t' || (0x20..=0x7E).contains(&b) {
+        return Some((b as char, 1))
+    }
+    if !utf8 {
+        return None
+    }
+    let len = match b {
+        0xC2..=0xDF => 2,
+        0xE0..=0xEF => 3,
+        0xF0..=0xF4 => 4,
+        _ => return None
+    };
+    std::str::from_utf8(data.get(0..len)?).ok()
+        .and_then(|s| s.chars().next())
+        .filter(|c| !c.is_control())
+        .map(|c| (c, len))
 }
 
 /// Byte order representation struct
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum RafByteOrder {
     /// Big endian
     BE,
     /// Little endian
     LE,
+    /// Byte order of the host (Same as [byteorder::NativeEndian]). Only for data which is
+    /// written and read back on the same machine, such as caches. It must never be used
+    /// for on-disk formats, as the file would read differently on a host with the other byte order
+    Native,
+}
+
+impl RafByteOrder {
+    /// Resolves [RafByteOrder::Native] to the host's byte order
+    fn resolve(self) -> RafByteOrder {
+        match self {
+            RafByteOrder::Native if cfg!(target_endian = "big") => RafByteOrder::BE,
+            RafByteOrder::Native => RafByteOrder::LE,
+            bo => bo,
+        }
+    }
+
+    /// Gets the byte order matching a [byteorder] type. [byteorder::NativeEndian] gives
+    /// the host's byte order rather than [RafByteOrder::Native], as it is an alias of one of the others
+    pub fn of<B: ByteOrder + 'static>() -> Self {
+        if std::any::TypeId::of::<B>() == std::any::TypeId::of::<BigEndian>() {
+            RafByteOrder::BE
+        } else {
+            RafByteOrder::LE
+        }
+    }
+
+    /// Calls generic code with the [byteorder] type matching this byte order, for code which
+    /// is written over [ByteOrder] but only knows the byte order at runtime
+    ///
+    /// # Example
+    /// ```
+    /// use byteorder::ByteOrder;
+    /// use common::raf::{RafByteOrder, ByteOrderFn};
+    ///
+    /// struct ReadPair<'a>(&'a [u8]);
+    /// impl ByteOrderFn for ReadPair<'_> {
+    ///     type Output = (u16, u16);
+    ///     fn call<B: ByteOrder>(self) -> (u16, u16) {
+    ///         (B::read_u16(&self.0[0..2]), B::read_u16(&self.0[2..4]))
+    ///     }
+    /// }
+    /// assert_eq!(RafByteOrder::LE.dispatch(ReadPair(&[0x01, 0x00, 0x02, 0x00])), (1, 2));
+    /// ```
+    pub fn dispatch<F: ByteOrderFn>(self, f: F) -> F::Output {
+        match self.resolve() {
+            RafByteOrder::BE => f.call::<BigEndian>(),
+            _ => f.call::<LittleEndian>(),
+        }
+    }
+
+    #[inline]
+    fn decode<T>(self, bytes: &[u8], func_le: fn(&[u8]) -> T, func_be: fn(&[u8]) -> T) -> T {
+        match self.resolve() {
+            RafByteOrder::BE => func_be(bytes),
+            _ => func_le(bytes),
+        }
+    }
+
+    /// Decodes a u16 from the start of a slice. Panics if the slice is too short, as [ByteOrder] does
+    pub fn read_u16(&self, bytes: &[u8]) -> u16 {
+        self.decode(bytes, LittleEndian::read_u16, BigEndian::read_u16)
+    }
+
+    /// Decodes an i16 from the start of a slice. Panics if the slice is too short, as [ByteOrder] does
+    pub fn read_i16(&self, bytes: &[u8]) -> i16 {
+        self.decode(bytes, LittleEndian::read_i16, BigEndian::read_i16)
+    }
+
+    /// Decodes a u32 from the start of a slice. Panics if the slice is too short, as [ByteOrder] does
+    pub fn read_u32(&self, bytes: &[u8]) -> u32 {
+        self.decode(bytes, LittleEndian::read_u32, BigEndian::read_u32)
+    }
+
+    /// Decodes an i32 from the start of a slice. Panics if the slice is too short, as [ByteOrder] does
+    pub fn read_i32(&self, bytes: &[u8]) -> i32 {
+        self.decode(bytes, LittleEndian::read_i32, BigEndian::read_i32)
+    }
+
+    /// Decodes a u64 from the start of a slice. Panics if the slice is too short, as [ByteOrder] does
+    pub fn read_u64(&self, bytes: &[u8]) -> u64 {
+        self.decode(bytes, LittleEndian::read_u64, BigEndian::read_u64)
+    }
+
+    /// Decodes an i64 from the start of a slice. Panics if the slice is too short, as [ByteOrder] does
+    pub fn read_i64(&self, bytes: &[u8]) -> i64 {
+        self.decode(bytes, LittleEndian::read_i64, BigEndian::read_i64)
+    }
+
+    /// Decodes an f32 from the start of a slice. Panics if the slice is too short, as [ByteOrder] does
+    pub fn read_f32(&self, bytes: &[u8]) -> f32 {
+        self.decode(bytes, LittleEndian::read_f32, BigEndian::read_f32)
+    }
+
+    /// Decodes an f64 from the start of a slice. Panics if the slice is too short, as [ByteOrder] does
+    pub fn read_f64(&self, bytes: &[u8]) -> f64 {
+        self.decode(bytes, LittleEndian::read_f64, BigEndian::read_f64)
+    }
+
+    #[inline]
+    fn encode<T>(self, bytes: &mut [u8], n: T, func_le: fn(&mut [u8], T), func_be: fn(&mut [u8], T)) {
+        match self.resolve() {
+            RafByteOrder::BE => func_be(bytes, n),
+            _ => func_le(bytes, n),
+        }
+    }
+
+    /// Encodes a u16 to the start of a slice. Panics if the slice is too short, as [ByteOrder] does
+    pub fn write_u16(&self, bytes: &mut [u8], n: u16) {
+        self.encode(bytes, n, LittleEndian::write_u16, BigEndian::write_u16)
+    }
+
+    /// Encodes a u32 to the start of a slice. Panics if the slice is too short, as [ByteOrder] does
+    pub fn write_u32(&self, bytes: &mut [u8], n: u32) {
+        self.encode(bytes, n, LittleEndian::write_u32, BigEndian::write_u32)
+    }
+
+    /// Encodes a u64 to the start of a slice. Panics if the slice is too short, as [ByteOrder] does
+    pub fn write_u64(&self, bytes: &mut [u8], n: u64) {
+        self.encode(bytes, n, LittleEndian::write_u64, BigEndian::write_u64)
+    }
+}
+
+/// Generic code run by [RafByteOrder::dispatch] with a [byteorder] type
+pub trait ByteOrderFn {
+    type Output;
+    fn call<B: ByteOrder>(self) -> Self::Output;
 }
 
 impl Raf {
@@ -53,46 +292,386 @@ impl Raf {
     pub fn from_read<R: Read>(reader: &mut R, bo: RafByteOrder) -> std::io::Result<Self> {
         let mut data: Vec<u8> = Vec::new();
         reader.read_to_end(&mut data).map(|size| Raf {
-            data,
+            data: Arc::new(data),
             size,
             pos: 0,
             bo,
+            alloc_limit: size,
+            strict: false,
         })
     }
 
+    /// Creates a [Raf] struct by reading the entire contents of a file
+    ///
+    /// # Params
+    /// * path - Path of the file to read
+    /// * bo - Byte order of the source data
+    ///
+    /// # Returns
+    /// * Result, Raf is returned if the file could be opened and read, else the IO error
+    /// is returned with the file path added to its description
+    pub fn from_file<P: AsRef<Path>>(path: P, bo: RafByteOrder) -> std::io::Result<Self> {
+        let path = path.as_ref();
+        let with_path = |e: std::io::Error| std::io::Error::new(e.kind(), format!("{}: {}", path.display(), e));
+        let file = File::open(path).map_err(with_path)?;
+        Self::from_read(&mut BufReader::new(file), bo).map_err(with_path)
+    }
+
     /// Creates a [Raf] struct from a Vector of bytes
     /// 
     /// # Params
     /// * data - Original source data - Will be cloned
     /// * bo - Byte order of the source data
     pub fn from_bytes(data: &Vec<u8>, bo: RafByteOrder) -> Self {
+        Self::from_shared(Arc::new(data.clone()), bo)
+    }
+
+    /// Creates a [Raf] over a buffer shared with other readers, without copying it.
+    ///
+    /// Each [Raf] has its own position, byte order and limits, so readers over the same buffer
+    /// do not affect each other. A buffer is never changed whilst a [Raf] holds it: a producer
+    /// appending with [Arc::make_mut] is given its own copy if any reader still holds the old one.
+    /// Readers only see appended data once given the new buffer with [refresh_shared](fn@Raf::refresh_shared)
+    ///
+    /// # Params
+    /// * buf - Shared source data
+    /// * bo - Byte order of the source data
+    pub fn from_shared(buf: Arc<Vec<u8>>, bo: RafByteOrder) -> Self {
+        let size = buf.len();
         Raf {
-            data: data.clone(),
-            size: data.len(),
+            data: buf,
+            size,
             pos: 0,
             bo,
+            alloc_limit: size,
+            strict: false,
         }
     }
 
+    /// Switches to a newer version of the shared buffer, keeping the position, so data appended
+    /// since the last version can be read. The new buffer must start with the data of the old one,
+    /// which is not checked. An allocation limit left at its default grows with the buffer
+    ///
+    /// If the new buffer ends before the current position, [RafError::StartOutOfRange] is
+    /// returned and the old buffer is kept
+    pub fn refresh_shared(&mut self, buf: Arc<Vec<u8>>) -> Result<()> {
+        if buf.len() < self.pos {
+            return Err(RafError::StartOutOfRange)
+        }
+        if self.alloc_limit == self.size {
+            self.alloc_limit = buf.len();
+        }
+        self.size = buf.len();
+        self.data = buf;
+        Ok(())
+    }
 
+    /// Reads exactly `n` bytes into a new Vec, and moves past them.
+    ///
+    /// On error nothing is read and the position is not changed:
+    /// * [RafError::StartOutOfRange] if the position has been moved past the end of the data
+    /// * [RafError::BufferOverflow] if fewer than `n` bytes remain
+    /// * [RafError::AllocationLimitExceeded] if `n` is over the [allocation limit](fn@Raf::set_alloc_limit)
+    ///
+    /// Reading 0 bytes returns an empty Vec, as long as the position is within the data
+    pub fn read_exact_vec(&mut self, n: usize) -> Result<Vec<u8>> {
+        if self.pos > self.size {
+            return Err(RafError::StartOutOfRange);
+        }
+        if n > self.remaining() {
+            return Err(RafError::BufferOverflow);
+        }
+        self.check_alloc(n)?;
+        let res = self.data[self.pos..self.pos + n].to_vec();
+        self.pos += n;
+        Ok(res)
+    }
+
+    /// Same as [read_exact_vec](fn@Raf::read_exact_vec), which new code should use instead.
+    ///
+    /// This used to slice the data without checking the position first, so reading 0 bytes after
+    /// seeking past the end of the data panicked rather than returning an error. Callers which
+    /// relied on that returning an empty Vec now get [RafError::StartOutOfRange]
     pub fn read_bytes(&mut self, num_bytes: usize) -> Result<Vec<u8>> {
-        if self.pos+num_bytes-1 > self.size {
+        self.read_exact_vec(num_bytes)
+    }
+
+    /// Decompresses a section starting at the current position, and moves past it.
+    /// The compressed bytes are inflated straight from the buffer, without being copied first.
+    ///
+    /// The format is detected from the first bytes of the section: gzip (`1F 8B`), zlib (A valid
+    /// zlib header, usually `78 xx`), otherwise raw deflate.
+    ///
+    /// On error the position is not changed
+    ///
+    /// # Params
+    /// * compressed_len - Number of compressed bytes in the section
+    /// * max_len - Largest the decompressed data may be, such as the uncompressed length the
+    /// section declares. Larger data returns [RafError::AllocationLimitExceeded]
+    ///
+    /// # Returns
+    /// A reader over the decompressed data, with the same byte order and strict mode. Its
+    /// allocation limit is the size of the decompressed data
+    pub fn read_deflate_section(&mut self, compressed_len: usize, max_len: usize) -> Result<Raf> {
+        let start = self.pos;
+        let compressed = self.peek(start, compressed_len)?;
+        let decoder: Box<dyn Read + '_> = if compressed.starts_with(&[0x1F, 0x8B]) {
+            Box::new(GzDecoder::new(compressed))
+        } else if compressed.len() >= 2 && compressed[0] & 0x0F == 0x08 && u16::from_be_bytes([compressed[0], compressed[1]]) % 31 == 0 {
+            Box::new(ZlibDecoder::new(compressed))
+        } else {
+            Box::new(DeflateDecoder::new(compressed))
+        };
+        // Stop one byte over the limit, so a section which inflates to something huge is caught early
+        let mut data = Vec::new();
+        decoder.take(max_len.saturating_add(1) as u64).read_to_end(&mut data)
+            .map_err(|e| RafError::InvalidCompressedData { offset: start, reason: e.to_string() })?;
+        if data.len() > max_len {
+            return Err(RafError::AllocationLimitExceeded { requested: data.len(), limit: max_len })
+        }
+        self.pos = start + compressed_len;
+        Ok(Raf {
+            size: data.len(),
+            alloc_limit: data.len(),
+            data: Arc::new(data),
+            pos: 0,
+            bo: self.bo,
+            strict: self.strict,
+        })
+    }
+
+    /// Returns the total number of bytes stored
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns all of the data, for hashing or dumping it. Does not depend on or change the position
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Returns all of the data, consuming the reader. The position is ignored.
+    /// The data is only copied if it is shared with another reader
+    pub fn into_bytes(self) -> Vec<u8> {
+        Arc::try_unwrap(self.data).unwrap_or_else(|shared| shared.as_ref().clone())
+    }
+
+    /// Limits how many bytes a single read ([read_bytes](fn@read_bytes), [read_string](fn@read_string)
+    /// and the prefixed reads) may allocate. Reads over the limit return [RafError::AllocationLimitExceeded].
+    ///
+    /// Defaults to the size of the data, so a corrupt length can never allocate more than the file itself
+    pub fn set_alloc_limit(&mut self, bytes: usize) {
+        self.alloc_limit = bytes
+    }
+
+    /// Checks if an allocation of `bytes` is within the allocation limit. Parsers should call
+    /// this before allocating based on a count read from the data
+    pub fn check_alloc(&self, bytes: usize) -> Result<()> {
+        if bytes > self.alloc_limit {
+            return Err(RafError::AllocationLimitExceeded { requested: bytes, limit: self.alloc_limit })
+        }
+        Ok(())
+    }
+
+    /// Returns the byte order used for reading
+    pub fn get_byte_order(&self) -> RafByteOrder {
+        self.bo
+    }
+
+    /// Changes the byte order used for all subsequent reads
+    pub fn set_byte_order(&mut self, bo: RafByteOrder) {
+        self.bo = bo
+    }
+
+    /// Returns a slice of the data at a position, without changing the current position
+    pub fn peek(&self, pos: usize, len: usize) -> Result<&[u8]> {
+        if pos > self.size {
+            return Err(RafError::StartOutOfRange);
+        }
+        if len > self.size - pos {
+            return Err(RafError::BufferOverflow);
+        }
+        Ok(&self.data[pos..pos + len])
+    }
+
+    /// Returns the number of bytes left to read after the current position
+    pub fn remaining(&self) -> usize {
+        self.size.saturating_sub(self.pos)
+    }
+
+    /// Reads a u16 length, followed by that many bytes of data.
+    ///
+    /// If the length exceeds the remaining data, [RafError::BufferOverflow] is returned
+    /// and the position is left at the start of the length
+    pub fn read_u16_prefixed_bytes(&mut self) -> Result<Vec<u8>> {
+        let start = self.pos;
+        let len = self.read_u16()? as usize;
+        self.read_prefixed(start, len)
+    }
+
+    /// Reads a u32 length, followed by that many bytes of data.
+    ///
+    /// If the length exceeds the remaining data, [RafError::BufferOverflow] is returned
+    /// and the position is left at the start of the length
+    pub fn read_u32_prefixed_bytes(&mut self) -> Result<Vec<u8>> {
+        let start = self.pos;
+        let len = self.read_u32()? as usize;
+        self.read_prefixed(start, len)
+    }
+
+    fn read_prefixed(&mut self, start: usize, len: usize) -> Result<Vec<u8>> {
+        if len > self.remaining() {
+            self.pos = start;
             return Err(RafError::BufferOverflow);
         }
-        let res = Vec::from(&self.data[self.pos..self.pos + num_bytes]);
-        self.pos += num_bytes;
+        if let Err(e) = self.check_alloc(len) {
+            self.pos = start;
+            return Err(e);
+        }
+        let res = Vec::from(&self.data[self.pos..self.pos + len]);
+        self.pos += len;
         Ok(res)
     }
 
-    /// Seeks to location within the data stored
-    pub fn seek(&mut self, pos: usize) {
+    /// Computes the CRC32 (IEEE 802.3) of a range of the data, without
+    /// changing the current position
+    ///
+    /// # Params
+    /// * start - Offset of the first byte to include
+    /// * len - Number of bytes to include
+    pub fn crc32(&self, start: usize, len: usize) -> Result<u32> {
+        if start > self.size {
+            return Err(RafError::StartOutOfRange);
+        }
+        if len > self.size - start {
+            return Err(RafError::BufferOverflow);
+        }
+        let mut crc = 0xFFFF_FFFFu32;
+        for b in &self.data[start..start + len] {
+            crc ^= *b as u32;
+            for _ in 0..8 {
+                crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+            }
+        }
+        Ok(!crc)
+    }
+
+    /// Finds every run of printable ASCII of at least `min_len` characters in the data,
+    /// like the `strings` tool. The current position is not changed
+    ///
+    /// # Returns
+    /// The offset and text of each string
+    pub fn find_strings(&self, min_len: usize) -> Vec<(usize, String)> {
+        self.find_strings_in(0..self.size, min_len, false)
+    }
+
+    /// Same as [find_strings](fn@find_strings), but only scans a range of the data.
+    /// The range is clipped to the end of the data
+    ///
+    /// # Params
+    /// * range - Range of offsets to scan. A string that runs past the end of the range is cut off
+    /// * min_len - Minimum number of characters in a string
+    /// * utf8 - Also accept printable UTF-8 characters, not just ASCII
+    pub fn find_strings_in(&self, range: std::ops::Range<usize>, min_len: usize, utf8: bool) -> Vec<(usize, String)> {
+        let end = std::cmp::min(range.end, self.size);
+        let mut res = Vec::new();
+        let mut pos = range.start;
+        let mut start = pos;
+        let mut current = String::new();
+        let mut chars = 0;
+        while pos < end {
+            match printable_char(&self.data[pos..end], utf8) {
+                Some((c, len)) => {
+                    if chars == 0 {
+                        start = pos;
+                    }
+                    current.push(c);
+                    chars += 1;
+                    pos += len;
+                },
+                None => {
+                    if chars >= std::cmp::max(min_len, 1) {
+                        res.push((start, std::mem::take(&mut current)));
+                    }
+                    current.clear();
+                    chars = 0;
+                    pos += 1;
+                }
+            }
+        }
+        if chars >= std::cmp::max(min_len, 1) {
+            res.push((start, current));
+        }
+        res
+    }
+
+    /// Computes the Shannon entropy, in bits per byte (0 to 8), of each `block_size` bytes of the data.
+    /// Compressed or encrypted regions are close to 8, code and tables are lower, and padding is 0.
+    /// The last block is shorter if the data is not a whole number of blocks. The current position is not changed
+    ///
+    /// # Returns
+    /// The entropy of each block in order, or nothing if `block_size` is 0
+    pub fn entropy_map(&self, block_size: usize) -> Vec<f32> {
+        if block_size == 0 {
+            return Vec::new();
+        }
+        self.data[..self.size].chunks(block_size).map(|block| {
+            let mut counts = [0usize; 256];
+            for b in block {
+                counts[*b as usize] += 1;
+            }
+            let len = block.len() as f64;
+            let entropy: f64 = counts.iter().filter(|c| **c > 0).map(|c| {
+                let p = *c as f64 / len;
+                -p * p.log2()
+            }).sum();
+            entropy as f32
+        }).collect()
+    }
+
+    /// Enables or disables strict mode. Off by default.
+    ///
+    /// Normally [seek](fn@seek) accepts any position, and seeking past the end of the data only
+    /// shows up as an error on the next read. In strict mode, [seek](fn@seek) (And so
+    /// [seek_read](fn@seek_read) and [seek_read_at](fn@seek_read_at)) returns
+    /// [RafError::StartOutOfRange] for it instead, so the bad offset is caught where it was calculated.
+    /// [adv](fn@adv) and [seek_checked](fn@seek_checked) always check the position
+    pub fn strict_mode(&mut self, strict: bool) {
+        self.strict = strict
+    }
+
+    pub fn is_strict(&self) -> bool {
+        self.strict
+    }
+
+    /// Seeks to location within the data stored.
+    ///
+    /// This only fails in [strict mode](fn@strict_mode), with [RafError::StartOutOfRange] if the
+    /// position is past the end of the data, in which case the position is not changed
+    pub fn seek(&mut self, pos: usize) -> Result<()> {
+        if self.strict {
+            return self.seek_checked(pos)
+        }
         self.pos = pos;
+        Ok(())
     }
 
+    /// Seeks to location within the data stored. Seeking to the end of the data is allowed,
+    /// anything past it returns [RafError::StartOutOfRange] and the position is not changed
+    pub fn seek_checked(&mut self, pos: usize) -> Result<()> {
+        if pos > self.size {
+            return Err(RafError::StartOutOfRange)
+        }
+        self.pos = pos;
+        Ok(())
+    }
+
+    /// Moves the position forward. If this would move past the end of the data,
+    /// [RafError::StartOutOfRange] is returned and the position is not changed
     pub fn adv(&mut self, pos: usize) -> Result<()> {
-        match pos {
-            x if self.pos + x > self.size => Err(RafError::StartOutOfRange),
-            _ => Ok(self.pos += pos),
+        match self.pos.checked_add(pos) {
+            Some(x) if x <= self.size => Ok(self.pos = x),
+            _ => Err(RafError::StartOutOfRange),
         }
     }
 
@@ -112,10 +691,46 @@ impl Raf {
     /// * pos - Position in file to start reading from
     /// * func - Function to run to read data
     pub fn seek_read<R>(&mut self, pos: usize, func: fn(&mut Self) -> Result<R>) -> Result<R> {
-        self.seek(pos);
+        self.seek(pos)?;
         func(self)
     }
 
+    /// Reads data at a position within the file without moving the current position.
+    ///
+    /// Unlike [seek_read](fn@seek_read), the position in the buffer is restored to where it was
+    /// prior to calling this function, even if [func] returns an error.
+    ///
+    /// # Params
+    /// * pos - Position in file to start reading from
+    /// * func - Function to run to read data
+    pub fn seek_read_at<R, F: FnOnce(&mut Self) -> Result<R>>(&mut self, pos: usize, func: F) -> Result<R> {
+        let prev_pos = self.pos;
+        self.seek(pos)?;
+        let res = func(self);
+        self.pos = prev_pos;
+        res
+    }
+
+    /// Runs a read, returning the value and the range of bytes it consumed.
+    /// Used to tie decoded fields back to the bytes they came from (Example: highlighting them in a hex view)
+    ///
+    /// The range is from the position before `func` ran to the position after, so a read which
+    /// seeks backwards gives an empty range
+    ///
+    /// # Example
+    /// ```
+    /// use common::raf::{Raf, RafByteOrder};
+    ///
+    /// let mut reader = Raf::from_bytes(&vec![0x00, 0x00, 0x01, 0x00, 0x00, 0x00], RafByteOrder::LE);
+    /// reader.seek(2).unwrap();
+    /// assert_eq!(reader.read_tracked(Raf::read_u32).unwrap(), (1, 2..6));
+    /// ```
+    pub fn read_tracked<R, F: FnOnce(&mut Self) -> Result<R>>(&mut self, func: F) -> Result<(R, Range<usize>)> {
+        let start = self.pos;
+        let res = func(self)?;
+        Ok((res, start..std::cmp::max(start, self.pos)))
+    }
+
     #[inline]
     fn read_primitive<T>(
         &mut self,
@@ -123,26 +738,128 @@ impl Raf {
         func_le: fn(&[u8]) -> T,
         func_be: fn(&[u8]) -> T,
     ) -> Result<T> {
-        match self.bo {
-            RafByteOrder::BE => self.read_bytes(size).map(|r| func_be(&r)),
-            RafByteOrder::LE => self.read_bytes(size).map(|r| func_le(&r)),
+        match self.bo.resolve() {
+            RafByteOrder::BE => self.read_fixed(size, func_be),
+            _ => self.read_fixed(size, func_le),
         }
     }
 
+    /// Reads a value of a fixed size with a conversion function, ignoring the configured byte order
+    #[inline]
+    fn read_fixed<T>(&mut self, size: usize, func: fn(&[u8]) -> T) -> Result<T> {
+        // Read straight from the buffer, as allocating for every integer is slow on large files
+        if size > self.remaining() {
+            return Err(RafError::BufferOverflow);
+        }
+        let res = func(&self.data[self.pos..self.pos + size]);
+        self.pos += size;
+        Ok(res)
+    }
+
+    #[inline]
+    fn read_primitive_at<T>(
+        &self,
+        pos: usize,
+        size: usize,
+        func_le: fn(&[u8]) -> T,
+        func_be: fn(&[u8]) -> T,
+    ) -> Result<T> {
+        let bytes = self.peek(pos, size)?;
+        match self.bo.resolve() {
+            RafByteOrder::BE => Ok(func_be(bytes)),
+            _ => Ok(func_le(bytes)),
+        }
+    }
+
+    /// Reads f64 from data at a position, without changing the current position
+    pub fn read_f64_at(&self, pos: usize) -> Result<f64> {
+        self.read_primitive_at(pos, 8, LittleEndian::read_f64, BigEndian::read_f64)
+    }
+
+    /// Reads f32 from data at a position, without changing the current position
+    pub fn read_f32_at(&self, pos: usize) -> Result<f32> {
+        self.read_primitive_at(pos, 4, LittleEndian::read_f32, BigEndian::read_f32)
+    }
+
+    /// Reads u64 from data at a position, without changing the current position
+    pub fn read_u64_at(&self, pos: usize) -> Result<u64> {
+        self.read_primitive_at(pos, 8, LittleEndian::read_u64, BigEndian::read_u64)
+    }
+
+    /// Reads i64 from data at a position, without changing the current position
+    pub fn read_i64_at(&self, pos: usize) -> Result<i64> {
+        self.read_primitive_at(pos, 8, LittleEndian::read_i64, BigEndian::read_i64)
+    }
+
+    /// Reads u32 from data at a position, without changing the current position
+    pub fn read_u32_at(&self, pos: usize) -> Result<u32> {
+        self.read_primitive_at(pos, 4, LittleEndian::read_u32, BigEndian::read_u32)
+    }
+
+    /// Reads i32 from data at a position, without changing the current position
+    pub fn read_i32_at(&self, pos: usize) -> Result<i32> {
+        self.read_primitive_at(pos, 4, LittleEndian::read_i32, BigEndian::read_i32)
+    }
+
+    /// Reads u16 from data at a position, without changing the current position
+    pub fn read_u16_at(&self, pos: usize) -> Result<u16> {
+        self.read_primitive_at(pos, 2, LittleEndian::read_u16, BigEndian::read_u16)
+    }
+
+    /// Reads i16 from data at a position, without changing the current position
+    pub fn read_i16_at(&self, pos: usize) -> Result<i16> {
+        self.read_primitive_at(pos, 2, LittleEndian::read_i16, BigEndian::read_i16)
+    }
+
+    /// Reads a single byte from data at a position, without changing the current position
+    pub fn read_u8_at(&self, pos: usize) -> Result<u8> {
+        self.peek(pos, 1).map(|b| b[0])
+    }
+
+    /// Reads a single signed byte from data at a position, without changing the current position
+    pub fn read_i8_at(&self, pos: usize) -> Result<i8> {
+        self.peek(pos, 1).map(|b| b[0] as i8)
+    }
+
+    /// Reads a C String (Ends in 0x00) at a position, without changing the current position.
+    ///
+    /// As with [read_cstr](fn@read_cstr), a missing terminator returns [RafError::BufferOverflow]
+    pub fn read_cstr_at(&self, pos: usize) -> Result<String> {
+        let rest = self.peek(pos, self.size.saturating_sub(pos))?;
+        match rest.iter().position(|b| *b == 0) {
+            Some(end) => Self::bytes_to_string(Vec::from(&rest[..end]), pos),
+            None => Err(RafError::BufferOverflow)
+        }
+    }
+
+    /// Reads bytes up to and including the next `terminator`, moving the position past the terminator
+    ///
+    /// # Params
+    /// * include_terminator - Return the terminator as the last byte
+    ///
+    /// # Returns
+    /// [RafError::BufferOverflow] if the data ends before the terminator, in which case the position is not changed
+    pub fn read_until(&mut self, terminator: u8, include_terminator: bool) -> Result<Vec<u8>> {
+        let rest = self.peek(self.pos, self.size.saturating_sub(self.pos))?;
+        let end = rest.iter().position(|b| *b == terminator).ok_or(RafError::BufferOverflow)?;
+        let len = if include_terminator { end + 1 } else { end };
+        self.check_alloc(len)?;
+        let res = Vec::from(&rest[..len]);
+        self.pos += end + 1;
+        Ok(res)
+    }
+
+    /// Reads a string which ends in `term`, such as 0xFF padding or a newline.
+    /// The terminator is skipped, but not included in the string
+    pub fn read_cstr_term(&mut self, term: u8) -> Result<String> {
+        let start = self.pos;
+        let bytes = self.read_until(term, false)?;
+        Self::bytes_to_string(bytes, start)
+    }
+
     /// Reads a C String (Ends in 0x00)
     pub fn read_cstr(&mut self) -> Result<String> {
-        let mut bytes: Vec<u8> = Vec::new();
-        loop {
-            let nextByte = self.read_u8().expect("Read string error");
-            if nextByte == 0 {
-                return match String::from_utf8(bytes) {
-                    Err(_) => Err(RafError::StrParseError),
-                    Ok(s) => Ok(s)
-                }
-            } else {
-                bytes.push(nextByte);
-            }
-        }
+        self.read_cstr_term(0)
     }
 
     /// Reads f32 from data at current position in buffer
@@ -150,6 +867,11 @@ impl Raf {
         self.read_primitive(4, LittleEndian::read_f32, BigEndian::read_f32)
     }
 
+    /// Reads f64 from data at current position in buffer
+    pub fn read_f64(&mut self) -> Result<f64> {
+        self.read_primitive(8, LittleEndian::read_f64, BigEndian::read_f64)
+    }
+
     /// Reads u64 from data at current position in buffer
     pub fn read_u64(&mut self) -> Result<u64> {
         self.read_primitive(8, LittleEndian::read_u64, BigEndian::read_u64)
@@ -180,6 +902,86 @@ impl Raf {
         self.read_primitive(2, LittleEndian::read_i16, BigEndian::read_i16)
     }
 
+    /// Reads u16 as big endian from data at current position in buffer, regardless of the byte order
+    pub fn read_u16_be(&mut self) -> Result<u16> {
+        self.read_fixed(2, BigEndian::read_u16)
+    }
+
+    /// Reads u16 as little endian from data at current position in buffer, regardless of the byte order
+    pub fn read_u16_le(&mut self) -> Result<u16> {
+        self.read_fixed(2, LittleEndian::read_u16)
+    }
+
+    /// Reads i16 as big endian from data at current position in buffer, regardless of the byte order
+    pub fn read_i16_be(&mut self) -> Result<i16> {
+        self.read_fixed(2, BigEndian::read_i16)
+    }
+
+    /// Reads i16 as little endian from data at current position in buffer, regardless of the byte order
+    pub fn read_i16_le(&mut self) -> Result<i16> {
+        self.read_fixed(2, LittleEndian::read_i16)
+    }
+
+    /// Reads u32 as big endian from data at current position in buffer, regardless of the byte order
+    pub fn read_u32_be(&mut self) -> Result<u32> {
+        self.read_fixed(4, BigEndian::read_u32)
+    }
+
+    /// Reads u32 as little endian from data at current position in buffer, regardless of the byte order
+    pub fn read_u32_le(&mut self) -> Result<u32> {
+        self.read_fixed(4, LittleEndian::read_u32)
+    }
+
+    /// Reads i32 as big endian from data at current position in buffer, regardless of the byte order
+    pub fn read_i32_be(&mut self) -> Result<i32> {
+        self.read_fixed(4, BigEndian::read_i32)
+    }
+
+    /// Reads i32 as little endian from data at current position in buffer, regardless of the byte order
+    pub fn read_i32_le(&mut self) -> Result<i32> {
+        self.read_fixed(4, LittleEndian::read_i32)
+    }
+
+    /// Reads u64 as big endian from data at current position in buffer, regardless of the byte order
+    pub fn read_u64_be(&mut self) -> Result<u64> {
+        self.read_fixed(8, BigEndian::read_u64)
+    }
+
+    /// Reads u64 as little endian from data at current position in buffer, regardless of the byte order
+    pub fn read_u64_le(&mut self) -> Result<u64> {
+        self.read_fixed(8, LittleEndian::read_u64)
+    }
+
+    /// Reads i64 as big endian from data at current position in buffer, regardless of the byte order
+    pub fn read_i64_be(&mut self) -> Result<i64> {
+        self.read_fixed(8, BigEndian::read_i64)
+    }
+
+    /// Reads i64 as little endian from data at current position in buffer, regardless of the byte order
+    pub fn read_i64_le(&mut self) -> Result<i64> {
+        self.read_fixed(8, LittleEndian::read_i64)
+    }
+
+    /// Reads f32 as big endian from data at current position in buffer, regardless of the byte order
+    pub fn read_f32_be(&mut self) -> Result<f32> {
+        self.read_fixed(4, BigEndian::read_f32)
+    }
+
+    /// Reads f32 as little endian from data at current position in buffer, regardless of the byte order
+    pub fn read_f32_le(&mut self) -> Result<f32> {
+        self.read_fixed(4, LittleEndian::read_f32)
+    }
+
+    /// Reads f64 as big endian from data at current position in buffer, regardless of the byte order
+    pub fn read_f64_be(&mut self) -> Result<f64> {
+        self.read_fixed(8, BigEndian::read_f64)
+    }
+
+    /// Reads f64 as little endian from data at current position in buffer, regardless of the byte order
+    pub fn read_f64_le(&mut self) -> Result<f64> {
+        self.read_fixed(8, LittleEndian::read_f64)
+    }
+
     /// Reads a single byte from data at current position in buffer
     pub fn read_u8(&mut self) -> Result<u8> {
         self.read_byte()
@@ -190,21 +992,125 @@ impl Raf {
         self.read_byte().map(|x| x as i8)
     }
 
+    /// Reads a single byte from data at current position in buffer.
+    ///
+    /// Returns [RafError::StartOutOfRange] if the position has been moved past the end of the data,
+    /// or [RafError::BufferOverflow] at the end of the data (Including when the data is empty)
     pub fn read_byte(&mut self) -> Result<u8> {
         if self.pos > self.size {
             return Err(RafError::StartOutOfRange);
         }
-        let res = self.data[self.pos];
-        self.pos += 1;
-        Ok(res)
+        self.read_fixed(1, |b| b[0])
     }
 
     /// Reads utf8 string from data at current position in buffer
     pub fn read_string(&mut self, len: usize) -> Result<String> {
-        match String::from_utf8(self.read_bytes(len)?) {
-            Err(_) => Err(RafError::StrParseError),
-            Ok(s) => Ok(s),
+        let start = self.pos;
+        let bytes = self.read_bytes(len)?;
+        Self::bytes_to_string(bytes, start)
+    }
+
+    /// Reads a Binary Coded Decimal field as a string of digits, keeping any leading zeros.
+    ///
+    /// Each byte holds 2 digits, with the most significant digit in the upper nibble.
+    /// In big endian the first byte holds the most significant digits, in little endian the last byte does.
+    /// If a nibble is over 9, [RafError::InvalidBcd] is returned and the position is not changed
+    pub fn read_bcd_string(&mut self, num_bytes: usize) -> Result<String> {
+        let start = self.pos;
+        let bytes = self.read_bytes(num_bytes)?;
+        let mut digits = String::with_capacity(num_bytes * 2);
+        let order: Box<dyn Iterator<Item = usize>> = match self.bo.resolve() {
+            RafByteOrder::BE => Box::new(0..num_bytes),
+            _ => Box::new((0..num_bytes).rev()),
+        };
+        for idx in order {
+            let b = bytes[idx];
+            if b >> 4 > 9 || b & 0x0F > 9 {
+                self.pos = start;
+                return Err(RafError::InvalidBcd { offset: start + idx, byte: b })
+            }
+            digits.push((b'0' + (b >> 4)) as char);
+            digits.push((b'0' + (b & 0x0F)) as char);
         }
+        Ok(digits)
+    }
+
+    /// Reads a Binary Coded Decimal field as a number. See [read_bcd_string](fn@read_bcd_string)
+    /// for the layout. Up to 9 bytes (18 digits) can be read
+    pub fn read_bcd(&mut self, num_bytes: usize) -> Result<u64> {
+        if num_bytes > 9 {
+            return Err(RafError::BcdTooLong(num_bytes))
+        }
+        let digits = self.read_bcd_string(num_bytes)?;
+        Ok(digits.bytes().fold(0u64, |acc, d| acc * 10 + (d - b'0') as u64))
+    }
+
+    /// Reads a signed packed BCD number. The lower nibble of the last byte is the sign
+    /// (0xB or 0xD is negative, 0xA, 0xC, 0xE or 0xF is positive), so N bytes hold 2N - 1 digits.
+    ///
+    /// Digits are always most significant first, regardless of the byte order. Up to 9 bytes
+    /// (17 digits) can be read. If a digit or the sign is invalid, [RafError::InvalidBcd]
+    /// is returned and the position is not changed. 0 bytes has no sign, so returns [RafError::BufferOverflow]
+    pub fn read_bcd_signed(&mut self, num_bytes: usize) -> Result<i64> {
+        if num_bytes > 9 {
+            return Err(RafError::BcdTooLong(num_bytes))
+        }
+        if num_bytes == 0 {
+            return Err(RafError::BufferOverflow)
+        }
+        let start = self.pos;
+        let bytes = self.read_bytes(num_bytes)?;
+        let mut value = 0i64;
+        for (idx, b) in bytes.iter().enumerate() {
+            let last = idx == num_bytes - 1;
+            if b >> 4 > 9 || (!last && b & 0x0F > 9) || (last && b & 0x0F < 0x0A) {
+                self.pos = start;
+                return Err(RafError::InvalidBcd { offset: start + idx, byte: *b })
+            }
+            value = value * 10 + (b >> 4) as i64;
+            if !last {
+                value = value * 10 + (b & 0x0F) as i64;
+            }
+        }
+        match bytes.last().map(|b| b & 0x0F) {
+            Some(0x0B) | Some(0x0D) => Ok(-value),
+            _ => Ok(value)
+        }
+    }
+
+    /// Reads a date stored as BCD, one byte for each 2 digits.
+    ///
+    /// If a digit is over 9, [RafError::InvalidBcd] is returned. If the day or month does not
+    /// exist (Month 13, 30th February...), [RafError::InvalidDate] is returned. In both cases
+    /// the position is not changed
+    pub fn read_date_bcd(&mut self, format: DateFormat) -> Result<NaiveDate> {
+        let start = self.pos;
+        let bytes = self.read_bytes(format.len())?;
+        let mut fields = Vec::with_capacity(bytes.len());
+        for (idx, b) in bytes.iter().enumerate() {
+            if b >> 4 > 9 || b & 0x0F > 9 {
+                self.pos = start;
+                return Err(RafError::InvalidBcd { offset: start + idx, byte: *b })
+            }
+            fields.push((b >> 4) as u32 * 10 + (b & 0x0F) as u32);
+        }
+        let full_year = |yy: u32, pivot: u8| if yy < pivot as u32 { 2000 + yy as i32 } else { 1900 + yy as i32 };
+        let (year, month, day) = match format {
+            DateFormat::YYMMDD { pivot } => (full_year(fields[0], pivot), fields[1], fields[2]),
+            DateFormat::DDMMYY { pivot } => (full_year(fields[2], pivot), fields[1], fields[0]),
+            DateFormat::YYYYMMDD => ((fields[0] * 100 + fields[1]) as i32, fields[2], fields[3]),
+        };
+        NaiveDate::from_ymd_opt(year, month, day).ok_or_else(|| {
+            self.pos = start;
+            RafError::InvalidDate { offset: start, year, month, day }
+        })
+    }
+
+    fn bytes_to_string(bytes: Vec<u8>, str_offset: usize) -> Result<String> {
+        String::from_utf8(bytes).map_err(|e| RafError::StrParseError {
+            str_offset,
+            valid_up_to: e.utf8_error().valid_up_to(),
+        })
     }
 }
 
@@ -215,3 +1121,555 @@ fn test_seek() {
     let mut reader: Raf = Raf::from_bytes(&data, RafByteOrder::BE);
     println!("{}", reader.seek_read(0, Raf::read_i32).unwrap());
 }
+
+#[test]
+fn test_from_file() {
+    let path = std::env::temp_dir().join("raf_test_from_file.bin");
+    std::fs::write(&path, &[0x01, 0x02, 0x03, 0x04, 0xAA]).unwrap();
+    let mut raf = Raf::from_file(&path, RafByteOrder::BE).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(raf.read_u32().unwrap(), 0x01020304);
+    assert_eq!(raf.read_u8().unwrap(), 0xAA);
+    assert!(Raf::from_file(&path, RafByteOrder::BE).is_err());
+}
+
+#[test]
+fn test_invalid_utf8_offset() {
+    let data: Vec<u8> = vec![0xAA, 0xBB, b'O', b'K', 0xC3, 0x28, b'!', 0x00];
+    let mut reader = Raf::from_bytes(&data, RafByteOrder::LE);
+    reader.seek(2).unwrap();
+    match reader.read_cstr() {
+        Err(RafError::StrParseError { str_offset, valid_up_to }) => {
+            assert_eq!(str_offset, 2);
+            assert_eq!(valid_up_to, 2);
+        },
+        x => panic!("Expected StrParseError, got {:?}", x)
+    }
+    reader.seek(2).unwrap();
+    assert!(matches!(reader.read_string(5), Err(RafError::StrParseError { str_offset: 2, valid_up_to: 2 })));
+}
+
+#[test]
+fn test_seek_read_at() {
+    let data: Vec<u8> = (0x00..0xFF).collect();
+    let mut reader = Raf::from_bytes(&data, RafByteOrder::BE);
+    reader.seek(10).unwrap();
+    assert_eq!(reader.seek_read_at(2, Raf::read_u16).unwrap(), 0x0203);
+    assert_eq!(reader.pos, 10);
+    assert!(reader.seek_read_at(0x1000, Raf::read_u32).is_err());
+    assert_eq!(reader.pos, 10);
+}
+
+#[test]
+fn test_prefixed_bytes() {
+    let data: Vec<u8> = vec![0x00, 0x03, 0xAA, 0xBB, 0xCC, 0x00, 0x00, 0x00, 0x01, 0xDD, 0xFF, 0xFF, 0xFF, 0xFF, 0x01];
+    let mut reader = Raf::from_bytes(&data, RafByteOrder::BE);
+    assert_eq!(reader.read_u16_prefixed_bytes().unwrap(), vec![0xAA, 0xBB, 0xCC]);
+    assert_eq!(reader.read_u32_prefixed_bytes().unwrap(), vec![0xDD]);
+    assert_eq!(reader.remaining(), 5);
+    // Length is much larger than the remaining data
+    assert!(matches!(reader.read_u32_prefixed_bytes(), Err(RafError::BufferOverflow)));
+    assert_eq!(reader.pos, 10);
+}
+
+#[test]
+fn test_alloc_limit() {
+    let data: Vec<u8> = vec![0x00, 0x08, b'O', b'p', b'e', b'n', b'V', b'e', b'h', b'D'];
+    let mut reader = Raf::from_bytes(&data, RafByteOrder::BE);
+    reader.set_alloc_limit(4);
+    assert!(matches!(reader.read_u16_prefixed_bytes(), Err(RafError::AllocationLimitExceeded { requested: 8, limit: 4 })));
+    assert_eq!(reader.pos, 0);
+    reader.seek(2).unwrap();
+    assert!(matches!(reader.read_string(5), Err(RafError::AllocationLimitExceeded { .. })));
+    assert_eq!(reader.pos, 2);
+    assert_eq!(reader.read_string(4).unwrap(), "Open");
+    assert!(reader.check_alloc(1_000_000).is_err());
+
+    reader.set_alloc_limit(usize::MAX);
+    reader.seek(0).unwrap();
+    assert_eq!(reader.read_u16_prefixed_bytes().unwrap().len(), 8);
+    // Bounds are still checked when the limit is lifted
+    assert!(matches!(reader.read_bytes(usize::MAX), Err(RafError::BufferOverflow)));
+}
+
+#[test]
+fn test_native_byte_order() {
+    let data: Vec<u8> = [0x1234_5678u32.to_ne_bytes(), 0xCAFE_BABEu32.to_ne_bytes()].concat();
+    let mut reader = Raf::from_bytes(&data, RafByteOrder::Native);
+    assert_eq!(reader.read_u32().unwrap(), 0x1234_5678);
+    assert_eq!(reader.read_u32_at(4).unwrap(), 0xCAFE_BABE);
+
+    // Native is the same as one of the fixed orders, and the opposite of the other
+    let host = if cfg!(target_endian = "big") { RafByteOrder::BE } else { RafByteOrder::LE };
+    let other = if host == RafByteOrder::BE { RafByteOrder::LE } else { RafByteOrder::BE };
+    reader.set_byte_order(host);
+    assert_eq!(reader.read_u32_at(0).unwrap(), 0x1234_5678);
+    reader.set_byte_order(other);
+    assert_eq!(reader.read_u32_at(0).unwrap(), 0x7856_3412);
+}
+
+#[test]
+fn test_read_bcd() {
+    let mut raf = Raf::from_bytes(&vec![0x12, 0x34, 0x00, 0x05, 0x1A, 0x56, 0x34, 0x12], RafByteOrder::BE);
+    assert_eq!(raf.read_bcd(2).unwrap(), 1234);
+    assert_eq!(raf.read_bcd_string(2).unwrap(), "0005");
+    assert!(matches!(raf.read_bcd(1), Err(RafError::InvalidBcd { offset: 4, byte: 0x1A })));
+    assert_eq!(raf.pos, 4);
+    raf.pos = 5;
+    raf.set_byte_order(RafByteOrder::LE);
+    assert_eq!(raf.read_bcd(3).unwrap(), 123456);
+    assert!(matches!(raf.read_bcd(10), Err(RafError::BcdTooLong(10))));
+}
+
+#[test]
+fn test_crc32() {
+    let data: Vec<u8> = b"__123456789__".to_vec();
+    let mut reader = Raf::from_bytes(&data, RafByteOrder::LE);
+    reader.seek(5).unwrap();
+    assert_eq!(reader.crc32(2, 9).unwrap(), 0xCBF4_3926);
+    assert_eq!(reader.pos, 5);
+    assert!(matches!(reader.crc32(2, 12), Err(RafError::BufferOverflow)));
+}
+
+#[test]
+fn test_read_at() {
+    let data: Vec<u8> = vec![0x01, 0x02, 0x03, 0x04, b'H', b'i', 0x00, 0xFF];
+    let mut reader = Raf::from_bytes(&data, RafByteOrder::BE);
+    reader.seek(3).unwrap();
+    assert_eq!(reader.read_u16_at(0).unwrap(), 0x0102);
+    assert_eq!(reader.read_u32_at(0).unwrap(), 0x01020304);
+    assert_eq!(reader.read_i8_at(7).unwrap(), -1);
+    assert_eq!(reader.read_cstr_at(4).unwrap(), "Hi");
+    assert!(matches!(reader.read_cstr_at(7), Err(RafError::BufferOverflow)));
+    assert!(matches!(reader.read_u16_at(7), Err(RafError::BufferOverflow)));
+    reader.set_byte_order(RafByteOrder::LE);
+    assert_eq!(reader.read_u16_at(0).unwrap(), 0x0201);
+    assert_eq!(reader.pos, 3);
+}
+
+#[test]
+fn test_seek_checked() {
+    let data: Vec<u8> = vec![0x01, 0x02, 0x03, 0x04];
+    let mut reader = Raf::from_bytes(&data, RafByteOrder::BE);
+    reader.seek_checked(2).unwrap();
+    assert!(matches!(reader.seek_checked(5), Err(RafError::StartOutOfRange)));
+    assert_eq!(reader.pos, 2);
+    // End of the data is a valid position, there is just nothing left to read
+    reader.seek_checked(4).unwrap();
+    assert_eq!(reader.remaining(), 0);
+    assert!(matches!(reader.adv(1), Err(RafError::StartOutOfRange)));
+    assert!(matches!(reader.adv(usize::MAX), Err(RafError::StartOutOfRange)));
+    assert_eq!(reader.pos, 4);
+
+    // Permissive by default
+    reader.seek(100).unwrap();
+    assert!(reader.read_u8().is_err());
+}
+
+#[test]
+fn test_strict_mode() {
+    let data: Vec<u8> = vec![0x01, 0x02, 0x03, 0x04];
+    let mut reader = Raf::from_bytes(&data, RafByteOrder::BE);
+    // Permissive by default, the bad position only shows up on the read
+    reader.seek(8).unwrap();
+    assert_eq!(reader.pos, 8);
+    assert!(reader.read_u8().is_err());
+
+    reader.strict_mode(true);
+    reader.seek(4).unwrap();
+    // A bad seek is an error at the call, and the position is not changed
+    assert!(matches!(reader.seek(5), Err(RafError::StartOutOfRange)));
+    assert_eq!(reader.pos, 4);
+    assert!(matches!(reader.adv(1), Err(RafError::StartOutOfRange)));
+    let position = |r: &mut Raf| -> Result<usize> { Ok(r.pos) };
+    assert!(matches!(reader.seek_read_at(8, position), Err(RafError::StartOutOfRange)));
+    assert!(matches!(reader.seek_read(5, Raf::read_u8), Err(RafError::StartOutOfRange)));
+    assert_eq!(reader.pos, 4);
+}
+
+#[test]
+fn test_read_primitive_matches_read_bytes() {
+    let data: Vec<u8> = (0..64u8).map(|x| x.wrapping_mul(37).wrapping_add(11)).collect();
+    for bo in [RafByteOrder::BE, RafByteOrder::LE, RafByteOrder::Native].iter() {
+        let mut reader = Raf::from_bytes(&data, *bo);
+        let mut expected = Raf::from_bytes(&data, *bo);
+        let convert = |b: Vec<u8>| match bo.resolve() {
+            RafByteOrder::BE => (BigEndian::read_u16(&b), BigEndian::read_u32(&b), BigEndian::read_i64(&b), BigEndian::read_f32(&b)),
+            _ => (LittleEndian::read_u16(&b), LittleEndian::read_u32(&b), LittleEndian::read_i64(&b), LittleEndian::read_f32(&b)),
+        };
+        while reader.remaining() >= 8 {
+            let (u16_v, _, _, _) = convert(expected.read_bytes(8).unwrap());
+            assert_eq!(reader.read_u16().unwrap(), u16_v);
+            reader.adv(6).unwrap();
+        }
+        reader.seek(0).unwrap();
+        expected.seek(0).unwrap();
+        while reader.remaining() >= 8 {
+            let (_, u32_v, i64_v, f32_v) = convert(expected.read_bytes(8).unwrap());
+            let start = reader.pos;
+            assert_eq!(reader.read_u32().unwrap(), u32_v);
+            reader.seek(start).unwrap();
+            assert_eq!(reader.read_f32().unwrap().to_bits(), f32_v.to_bits());
+            reader.seek(start).unwrap();
+            assert_eq!(reader.read_i64().unwrap(), i64_v);
+            assert_eq!(reader.pos, expected.pos);
+        }
+    }
+
+    // Out of range reads fail without moving
+    let mut reader = Raf::from_bytes(&data, RafByteOrder::BE);
+    reader.seek(62).unwrap();
+    assert!(matches!(reader.read_u32(), Err(RafError::BufferOverflow)));
+    assert_eq!(reader.pos, 62);
+    assert!(reader.read_u16().is_ok());
+    assert!(matches!(reader.read_u16(), Err(RafError::BufferOverflow)));
+    assert_eq!(reader.pos, 64);
+}
+
+#[test]
+fn test_find_strings() {
+    let mut data: Vec<u8> = vec![0x00, 0xFF, 0x13, b'A', b'B', 0x01];
+    data.extend_from_slice(b"Firmware v1.2");
+    data.extend_from_slice(&[0x00, 0x00, 0x9C, 0x80, 0x7F]);
+    data.extend_from_slice("Temp 90°C".as_bytes());
+    data.extend_from_slice(&[0xC3]); // Truncated UTF-8
+    let mut reader = Raf::from_bytes(&data, RafByteOrder::BE);
+    reader.seek(3).unwrap();
+
+    let strings = reader.find_strings(4);
+    assert_eq!(strings, vec![(6, "Firmware v1.2".to_string()), (24, "Temp 90".to_string())]);
+    assert_eq!(reader.pos, 3);
+    assert_eq!(reader.find_strings_in(0..data.len(), 4, true), vec![(6, "Firmware v1.2".to_string()), (24, "Temp 90°C".to_string())]);
+    // Range cuts the string off, and is clipped to the data
+    assert_eq!(reader.find_strings_in(15..1000, 2, false), vec![(15, "v1.2".to_string()), (24, "Temp 90".to_string())]);
+    assert_eq!(reader.find_strings(2)[0], (3, "AB".to_string()));
+}
+
+#[test]
+fn test_explicit_byte_order() {
+    let data: Vec<u8> = vec![0x12, 0x34, 0x56, 0x78, 0x3F, 0x80, 0x00, 0x00];
+    let mut reader = Raf::from_bytes(&data, RafByteOrder::LE);
+    assert_eq!(reader.read_u32_be().unwrap(), 0x1234_5678);
+    reader.seek(0).unwrap();
+    assert_eq!(reader.read_u32_le().unwrap(), 0x7856_3412);
+    reader.seek(0).unwrap();
+    assert_eq!(reader.read_u16_be().unwrap(), 0x1234);
+    assert_eq!(reader.read_i16_le().unwrap(), 0x7856);
+    assert_eq!(reader.read_f32_be().unwrap(), 1.0);
+    assert_eq!(reader.get_byte_order(), RafByteOrder::LE);
+    assert!(matches!(reader.read_u16_be(), Err(RafError::BufferOverflow)));
+
+    // Configured order is still used by the normal reads
+    reader.seek(0).unwrap();
+    assert_eq!(reader.read_u64_be().unwrap(), 0x1234_5678_3F80_0000);
+    reader.seek(0).unwrap();
+    assert_eq!(reader.read_u64().unwrap(), reader.seek_read_at(0, Raf::read_u64_le).unwrap());
+}
+
+#[test]
+fn test_read_date_bcd() {
+    let mut reader = Raf::from_bytes(&vec![0x23, 0x05, 0x14, 0x14, 0x05, 0x23, 0x85, 0x01, 0x01, 0x20, 0x23, 0x05, 0x14], RafByteOrder::LE);
+    let may_14 = NaiveDate::from_ymd(2023, 5, 14);
+    assert_eq!(reader.read_date_bcd(DateFormat::YYMMDD { pivot: 70 }).unwrap(), may_14);
+    assert_eq!(reader.read_date_bcd(DateFormat::DDMMYY { pivot: 70 }).unwrap(), may_14);
+    assert_eq!(reader.read_date_bcd(DateFormat::YYMMDD { pivot: 70 }).unwrap(), NaiveDate::from_ymd(1985, 1, 1));
+    assert_eq!(reader.read_date_bcd(DateFormat::YYYYMMDD).unwrap(), may_14);
+    reader.seek(6).unwrap();
+    assert_eq!(reader.read_date_bcd(DateFormat::YYMMDD { pivot: 90 }).unwrap(), NaiveDate::from_ymd(2085, 1, 1));
+
+    let mut reader = Raf::from_bytes(&vec![0x23, 0x13, 0x01, 0x23, 0x02, 0x30, 0x23, 0x0A, 0x01], RafByteOrder::BE);
+    assert!(matches!(reader.read_date_bcd(DateFormat::YYMMDD { pivot: 70 }), Err(RafError::InvalidDate { offset: 0, month: 13, .. })));
+    assert_eq!(reader.pos, 0);
+    reader.seek(3).unwrap();
+    assert!(matches!(reader.read_date_bcd(DateFormat::YYMMDD { pivot: 70 }), Err(RafError::InvalidDate { day: 30, .. })));
+    reader.seek(6).unwrap();
+    assert!(matches!(reader.read_date_bcd(DateFormat::YYMMDD { pivot: 70 }), Err(RafError::InvalidBcd { offset: 7, byte: 0x0A })));
+    assert_eq!(reader.pos, 6);
+}
+
+#[test]
+fn test_read_bcd_signed() {
+    let mut reader = Raf::from_bytes(&vec![0x12, 0x3D, 0x12, 0x3C, 0x7F, 0x12, 0x35], RafByteOrder::LE);
+    assert_eq!(reader.read_bcd_signed(2).unwrap(), -123);
+    assert_eq!(reader.read_bcd_signed(2).unwrap(), 123);
+    assert_eq!(reader.read_bcd_signed(1).unwrap(), 7);
+    assert!(matches!(reader.read_bcd_signed(2), Err(RafError::InvalidBcd { offset: 6, byte: 0x35 })));
+    assert_eq!(reader.pos, 5);
+    assert!(matches!(reader.read_bcd_signed(0), Err(RafError::BufferOverflow)));
+    assert_eq!(reader.pos, 5);
+}
+
+#[test]
+fn test_backing_bytes() {
+    let data: Vec<u8> = (0..32).collect();
+    let mut raf = Raf::from_bytes(&data, RafByteOrder::LE);
+    raf.seek(10).unwrap();
+    raf.read_u32().unwrap();
+    assert_eq!(raf.as_bytes().len(), raf.size());
+    assert_eq!(raf.as_bytes(), data.as_slice());
+    assert_eq!(raf.pos, 14);
+    assert_eq!(raf.into_bytes(), data);
+}
+
+#[test]
+fn test_shared_buffer() {
+    let mut buf = Arc::new(vec![0x01, 0x02, 0x03, 0x04]);
+    let mut a = Raf::from_shared(buf.clone(), RafByteOrder::BE);
+    let mut b = Raf::from_shared(buf.clone(), RafByteOrder::LE);
+    assert!(std::ptr::eq(a.as_bytes(), b.as_bytes()));
+
+    // Positions and byte orders are independent
+    assert_eq!(a.read_u16().unwrap(), 0x0102);
+    assert_eq!(b.read_u32().unwrap(), 0x0403_0201);
+    assert_eq!(a.pos, 2);
+    assert!(b.read_u8().is_err());
+
+    // Producer appends, readers keep the old data until refreshed
+    Arc::make_mut(&mut buf).extend_from_slice(&[0x05, 0x06]);
+    assert_eq!(a.size(), 4);
+    a.refresh_shared(buf.clone()).unwrap();
+    b.refresh_shared(buf.clone()).unwrap();
+    assert_eq!(a.read_u32().unwrap(), 0x0304_0506);
+    assert_eq!(b.read_u16().unwrap(), 0x0605);
+    assert_eq!(a.remaining(), 0);
+
+    let mut c = Raf::from_shared(buf.clone(), RafByteOrder::LE);
+    c.seek(5).unwrap();
+    assert!(matches!(c.refresh_shared(Arc::new(vec![0x01])), Err(RafError::StartOutOfRange)));
+    assert_eq!(c.size(), 6);
+    // Copied, as the other readers still share it
+    assert_eq!(c.into_bytes(), vec![0x01, 0x02, 0x03, 0x04, 0x05, 0x06]);
+}
+
+#[test]
+fn test_read_exact_vec() {
+    let data: Vec<u8> = (0..8).collect();
+    let mut reader = Raf::from_bytes(&data, RafByteOrder::BE);
+    assert_eq!(reader.read_exact_vec(3).unwrap(), vec![0, 1, 2]);
+    assert_eq!(reader.pos, 3);
+
+    // Over-read does not move the position
+    assert!(matches!(reader.read_exact_vec(6), Err(RafError::BufferOverflow)));
+    assert_eq!(reader.pos, 3);
+    assert_eq!(reader.read_exact_vec(5).unwrap(), vec![3, 4, 5, 6, 7]);
+    assert!(reader.read_exact_vec(0).unwrap().is_empty());
+    assert_eq!(reader.pos, 8);
+
+    reader.seek(10).unwrap();
+    assert!(matches!(reader.read_exact_vec(0), Err(RafError::StartOutOfRange)));
+    assert!(matches!(reader.read_bytes(0), Err(RafError::StartOutOfRange)));
+    assert_eq!(reader.pos, 10);
+
+    reader.seek(0).unwrap();
+    reader.set_alloc_limit(2);
+    assert!(matches!(reader.read_exact_vec(4), Err(RafError::AllocationLimitExceeded { requested: 4, limit: 2 })));
+    assert_eq!(reader.pos, 0);
+}
+
+#[test]
+fn test_byte_order_matches_byteorder() {
+    use byteorder::NativeEndian;
+
+    let bytes = [0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC, 0xDE, 0xF0];
+    assert_eq!(RafByteOrder::BE.read_u16(&bytes), BigEndian::read_u16(&bytes));
+    assert_eq!(RafByteOrder::LE.read_u16(&bytes), LittleEndian::read_u16(&bytes));
+    assert_eq!(RafByteOrder::BE.read_u32(&bytes), 0x12345678);
+    assert_eq!(RafByteOrder::LE.read_u32(&bytes), 0x78563412);
+    assert_eq!(RafByteOrder::BE.read_i32(&bytes[4..]), BigEndian::read_i32(&bytes[4..]));
+    assert_eq!(RafByteOrder::LE.read_i64(&bytes), LittleEndian::read_i64(&bytes));
+    assert_eq!(RafByteOrder::BE.read_u64(&bytes), BigEndian::read_u64(&bytes));
+    assert_eq!(RafByteOrder::BE.read_f32(&bytes).to_bits(), BigEndian::read_f32(&bytes).to_bits());
+    assert_eq!(RafByteOrder::LE.read_f64(&bytes).to_bits(), LittleEndian::read_f64(&bytes).to_bits());
+    assert_eq!(RafByteOrder::Native.read_u32(&bytes), NativeEndian::read_u32(&bytes));
+
+    // Same as reading through a Raf
+    let mut reader = Raf::from_bytes(&bytes.to_vec(), RafByteOrder::LE);
+    assert_eq!(reader.read_i16().unwrap(), RafByteOrder::LE.read_i16(&bytes));
+
+    assert_eq!(RafByteOrder::of::<BigEndian>(), RafByteOrder::BE);
+    assert_eq!(RafByteOrder::of::<LittleEndian>(), RafByteOrder::LE);
+    assert_eq!(RafByteOrder::of::<NativeEndian>(), RafByteOrder::Native.resolve());
+
+    struct ReadU32<'a>(&'a [u8]);
+    impl ByteOrderFn for ReadU32<'_> {
+        type Output = u32;
+        fn call<B: ByteOrder>(self) -> u32 {
+            B::read_u32(self.0)
+        }
+    }
+    assert_eq!(RafByteOrder::BE.dispatch(ReadU32(&bytes)), BigEndian::read_u32(&bytes));
+    assert_eq!(RafByteOrder::LE.dispatch(ReadU32(&bytes)), LittleEndian::read_u32(&bytes));
+}
+
+#[test]
+fn test_read_tracked() {
+    let data: Vec<u8> = (0..16).collect();
+    let mut reader = Raf::from_bytes(&data, RafByteOrder::BE);
+    reader.seek(3).unwrap();
+    assert_eq!(reader.read_tracked(Raf::read_u32).unwrap(), (0x03040506, 3..7));
+    assert_eq!(reader.pos, 7);
+
+    // Several reads tracked as one field
+    let (v, range) = reader.read_tracked(|r| Ok((r.read_u8()?, r.read_u16()?))).unwrap();
+    assert_eq!(v, (0x07, 0x0809));
+    assert_eq!(range, 7..10);
+    assert_eq!(&data[range], &[0x07, 0x08, 0x09]);
+
+    assert_eq!(reader.read_tracked(|r| r.read_exact_vec(0)).unwrap().1, 10..10);
+    assert_eq!(reader.read_tracked(|r| r.seek(0)).unwrap().1, 10..10);
+    reader.seek(14).unwrap();
+    assert!(reader.read_tracked(Raf::read_u32).is_err());
+    assert_eq!(reader.pos, 14);
+}
+
+#[test]
+fn test_read_deflate_section() {
+    // "OpenVehicleDiag " * 4 followed by 01 02 03 04, compressed with zlib
+    let zlib: Vec<u8> = vec![
+        0x78, 0xDA, 0xF3, 0x2F, 0x48, 0xCD, 0x0B, 0x4B, 0xCD, 0xC8, 0x4C, 0xCE, 0x49, 0x75, 0xC9, 0x4C,
+        0x4C, 0x57, 0xF0, 0x27, 0x91, 0xCF, 0xC8, 0xC4, 0xCC, 0x02, 0x00, 0x66, 0x51, 0x17, 0xA7
+    ];
+    let mut data = vec![0xAA, 0xBB];
+    data.extend_from_slice(&zlib);
+    data.extend_from_slice(&[0xCC, 0xDD]);
+    let mut reader = Raf::from_bytes(&data, RafByteOrder::LE);
+    reader.seek(2).unwrap();
+    // Inflates to more than the whole file, which the reader's allocation limit does not restrict
+    let mut inflated = reader.read_deflate_section(zlib.len(), 68).unwrap();
+    assert_eq!(reader.pos, 2 + zlib.len());
+    assert_eq!(reader.read_u16().unwrap(), 0xDDCC);
+    assert_eq!(inflated.size(), 68);
+    assert_eq!(inflated.read_string(16).unwrap(), "OpenVehicleDiag ");
+    assert_eq!(inflated.get_byte_order(), RafByteOrder::LE);
+    assert_eq!(inflated.read_u32_at(64).unwrap(), 0x04030201);
+
+    // Raw deflate (zlib without the header and checksum), and gzip
+    let raw = Raf::from_bytes(&zlib[2..zlib.len() - 4].to_vec(), RafByteOrder::LE).read_deflate_section(zlib.len() - 6, 1024).unwrap();
+    assert_eq!(raw.as_bytes(), inflated.as_bytes());
+    let mut gzip = vec![0x1F, 0x8B, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03];
+    gzip.extend_from_slice(&zlib[2..zlib.len() - 4]);
+    gzip.extend_from_slice(&[0xD8, 0xFA, 0xB8, 0xDE, 0x44, 0x00, 0x00, 0x00]);
+    let gz = Raf::from_bytes(&gzip, RafByteOrder::BE).read_deflate_section(gzip.len(), 1024).unwrap();
+    assert_eq!(gz.as_bytes(), inflated.as_bytes());
+
+    // Errors leave the position where it was
+    reader.seek(2).unwrap();
+    assert!(matches!(reader.read_deflate_section(zlib.len() + 3, 1024), Err(RafError::BufferOverflow)));
+    assert!(matches!(reader.read_deflate_section(zlib.len(), 67), Err(RafError::AllocationLimitExceeded { requested: 68, limit: 67 })));
+    assert_eq!(reader.pos, 2);
+    let mut reader = Raf::from_bytes(&vec![0x00, 0xFF, 0xFF, 0xFF, 0xFF], RafByteOrder::LE);
+    reader.seek(1).unwrap();
+    assert!(matches!(reader.read_deflate_section(4, 1024), Err(RafError::InvalidCompressedData { offset: 1, .. })));
+    assert_eq!(reader.pos, 1);
+}
+
+#[test]
+fn test_entropy_map() {
+    // 4KB of zeros, 4KB of pseudo random bytes, every byte value once, then a partial block
+    let mut data = vec![0u8; 4096];
+    let mut x = 0x2545_F491u32;
+    for _ in 0..4096 {
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        data.push(x as u8);
+    }
+    data.extend((0..=255u8).cycle().take(4096));
+    data.extend_from_slice(&[0xAA, 0x55, 0xAA, 0x55]);
+    let mut raf = Raf::from_bytes(&data, RafByteOrder::BE);
+    raf.seek(10).unwrap();
+
+    let map = raf.entropy_map(4096);
+    assert_eq!(map.len(), 4);
+    assert_eq!(map[0], 0.0);
+    assert!(map[1] > 7.9, "{}", map[1]);
+    assert!((map[2] - 8.0).abs() < 1e-4, "{}", map[2]);
+    assert!((map[3] - 1.0).abs() < 1e-4, "{}", map[3]);
+    assert_eq!(raf.pos, 10);
+
+    // Small blocks still tell the regions apart
+    let map = raf.entropy_map(1024);
+    assert_eq!(map.len(), 13);
+    assert!(map[..4].iter().all(|e| *e == 0.0));
+    assert!(map[4..12].iter().all(|e| *e > 7.5));
+    assert!(raf.entropy_map(0).is_empty());
+    assert!(Raf::from_bytes(&vec![], RafByteOrder::BE).entropy_map(16).is_empty());
+}
+
+#[test]
+fn test_read_until() {
+    let data: Vec<u8> = vec![b'E', b'G', b'S', 0xFF, 0xFF, b'5', b'2', b'\n', 0x01, 0x02];
+    let mut reader = Raf::from_bytes(&data, RafByteOrder::BE);
+    assert_eq!(reader.read_until(0xFF, true).unwrap(), vec![b'E', b'G', b'S', 0xFF]);
+    assert_eq!(reader.pos, 4);
+    // Terminator straight away
+    assert_eq!(reader.read_until(0xFF, false).unwrap(), vec![]);
+    assert_eq!(reader.pos, 5);
+    assert_eq!(reader.read_cstr_term(b'\n').unwrap(), "52");
+    assert_eq!(reader.pos, 8);
+
+    // Data ends before the terminator
+    assert!(matches!(reader.read_until(0xFF, false), Err(RafError::BufferOverflow)));
+    assert!(matches!(reader.read_cstr(), Err(RafError::BufferOverflow)));
+    assert_eq!(reader.pos, 8);
+    assert_eq!(reader.read_u16().unwrap(), 0x0102);
+    assert!(matches!(reader.read_until(0xFF, true), Err(RafError::BufferOverflow)));
+
+    reader.seek(0).unwrap();
+    assert_eq!(reader.read_cstr_term(0xFF).unwrap(), "EGS");
+    assert_eq!(reader.pos, 4);
+}
+
+#[test]
+fn test_empty_raf() {
+    let overflow = |r: Result<()>| matches!(r, Err(RafError::BufferOverflow));
+    for bo in &[RafByteOrder::BE, RafByteOrder::LE, RafByteOrder::Native] {
+        let mut raf = Raf::from_bytes(&Vec::new(), *bo);
+        assert_eq!((raf.size(), raf.remaining()), (0, 0));
+        assert!(overflow(raf.read_u8().map(drop)));
+        assert!(overflow(raf.read_i8().map(drop)));
+        assert!(overflow(raf.read_byte().map(drop)));
+        assert!(overflow(raf.read_u16().map(drop)));
+        assert!(overflow(raf.read_i16().map(drop)));
+        assert!(overflow(raf.read_u32().map(drop)));
+        assert!(overflow(raf.read_i32().map(drop)));
+        assert!(overflow(raf.read_u64().map(drop)));
+        assert!(overflow(raf.read_i64().map(drop)));
+        assert!(overflow(raf.read_f32().map(drop)));
+        assert!(overflow(raf.read_f64().map(drop)));
+        assert!(overflow(raf.read_u16_be().map(drop)));
+        assert!(overflow(raf.read_u32_le().map(drop)));
+        assert!(overflow(raf.read_f64_be().map(drop)));
+        assert!(overflow(raf.read_bytes(1).map(drop)));
+        assert!(overflow(raf.read_string(1).map(drop)));
+        assert!(overflow(raf.read_cstr().map(drop)));
+        assert!(overflow(raf.read_u16_prefixed_bytes().map(drop)));
+        assert!(overflow(raf.read_u32_prefixed_bytes().map(drop)));
+        assert!(overflow(raf.read_bcd(1).map(drop)));
+        assert!(overflow(raf.read_bcd_signed(1).map(drop)));
+        assert!(overflow(raf.read_date_bcd(DateFormat::YYYYMMDD).map(drop)));
+        assert!(overflow(raf.read_u8_at(0).map(drop)));
+        assert!(overflow(raf.read_u32_at(0).map(drop)));
+        assert!(overflow(raf.read_cstr_at(0).map(drop)));
+        assert!(overflow(raf.crc32(0, 1).map(drop)));
+        assert!(matches!(raf.read_u8_at(1), Err(RafError::StartOutOfRange)));
+        assert!(matches!(raf.adv(1), Err(RafError::StartOutOfRange)));
+        assert_eq!(raf.read_bytes(0).unwrap(), Vec::<u8>::new());
+        assert_eq!(raf.crc32(0, 0).unwrap(), 0);
+        assert!(raf.find_strings(1).is_empty());
+        assert!(raf.entropy_map(16).is_empty());
+        // Failed reads don't move the position
+        assert_eq!(raf.remaining(), 0);
+        raf.seek_checked(0).unwrap();
+
+        // Past the end of the empty data
+        raf.seek(4).unwrap();
+        assert!(matches!(raf.read_byte(), Err(RafError::StartOutOfRange)));
+        assert!(matches!(raf.read_bytes(0), Err(RafError::StartOutOfRange)));
+        assert!(overflow(raf.read_u16().map(drop)));
+    }
+
+    let raf = Raf::from_read(&mut std::io::empty(), RafByteOrder::BE).unwrap();
+    assert_eq!(raf.size(), 0);
+    assert!(raf.as_bytes().is_empty());
+}
@@ -0,0 +1,134 @@
+use crate::raf::{RafByteOrder, RafError, Result};
+
+/// Builds a binary file to be read back with [Raf](crate::raf::Raf), in a fixed byte order.
+///
+/// Values are appended at the end of the data. Fields which are only known once the rest of
+/// the file has been written (Lengths, checksums) are written as a placeholder first, then
+/// overwritten with the `write_*_at` functions, which do not move the write position
+#[derive(Debug, Clone)]
+pub struct RafWriter {
+    data: Vec<u8>,
+    bo: RafByteOrder,
+}
+
+impl RafWriter {
+    pub fn new(bo: RafByteOrder) -> Self {
+        Self { data: Vec::new(), bo }
+    }
+
+    pub fn get_byte_order(&self) -> RafByteOrder {
+        self.bo
+    }
+
+    /// Offset the next value will be written at, which is also the number of bytes written so far
+    pub fn pos(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes)
+    }
+
+    pub fn write_u8(&mut self, n: u8) {
+        self.data.push(n)
+    }
+
+    pub fn write_u16(&mut self, n: u16) {
+        self.append(2, |bo, b| bo.write_u16(b, n))
+    }
+
+    pub fn write_u32(&mut self, n: u32) {
+        self.append(4, |bo, b| bo.write_u32(b, n))
+    }
+
+    pub fn write_u64(&mut self, n: u64) {
+        self.append(8, |bo, b| bo.write_u64(b, n))
+    }
+
+    /// Overwrites a u16 which has already been written. Returns [RafError::BufferOverflow] if
+    /// the value would run past the data written so far
+    pub fn write_u16_at(&mut self, offset: usize, n: u16) -> Result<()> {
+        self.patch(offset, 2, |bo, b| bo.write_u16(b, n))
+    }
+
+    /// Overwrites a u32 which has already been written. Returns [RafError::BufferOverflow] if
+    /// the value would run past the data written so far
+    ///
+    /// # Example
+    /// ```
+    /// use common::raf::RafByteOrder;
+    /// use common::raf_writer::RafWriter;
+    ///
+    /// let mut w = RafWriter::new(RafByteOrder::BE);
+    /// w.write_u32(0); // Length of the body, not known yet
+    /// w.write_bytes(b"body");
+    /// w.write_u32_at(0, 4).unwrap();
+    /// assert_eq!(w.into_bytes(), vec![0x00, 0x00, 0x00, 0x04, b'b', b'o', b'd', b'y']);
+    /// ```
+    pub fn write_u32_at(&mut self, offset: usize, n: u32) -> Result<()> {
+        self.patch(offset, 4, |bo, b| bo.write_u32(b, n))
+    }
+
+    /// Overwrites a u64 which has already been written. Returns [RafError::BufferOverflow] if
+    /// the value would run past the data written so far
+    pub fn write_u64_at(&mut self, offset: usize, n: u64) -> Result<()> {
+        self.patch(offset, 8, |bo, b| bo.write_u64(b, n))
+    }
+
+    fn append<F: FnOnce(RafByteOrder, &mut [u8])>(&mut self, len: usize, f: F) {
+        let start = self.data.len();
+        self.data.resize(start + len, 0);
+        f(self.bo, &mut self.data[start..])
+    }
+
+    fn patch<F: FnOnce(RafByteOrder, &mut [u8])>(&mut self, offset: usize, len: usize, f: F) -> Result<()> {
+        if offset > self.data.len() {
+            return Err(RafError::StartOutOfRange);
+        }
+        if len > self.data.len() - offset {
+            return Err(RafError::BufferOverflow);
+        }
+        f(self.bo, &mut self.data[offset..offset + len]);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_back_patch_length() {
+    use crate::raf::Raf;
+
+    for bo in &[RafByteOrder::BE, RafByteOrder::LE] {
+        let mut w = RafWriter::new(*bo);
+        w.write_u16(0xCAFE);
+        let len_offset = w.pos();
+        w.write_u32(0);
+        w.write_u64(0);
+        let body_start = w.pos();
+        w.write_bytes(&[0x11; 10]);
+        w.write_u8(0x22);
+        let body_len = w.pos() - body_start;
+        w.write_u32_at(len_offset, body_len as u32).unwrap();
+        w.write_u64_at(len_offset + 4, 0x0102_0304_0506_0708).unwrap();
+        w.write_u16_at(0, 0xBEEF).unwrap();
+        // Patching doesn't move the write position
+        assert_eq!(w.pos(), body_start + 11);
+        assert!(matches!(w.write_u32_at(w.pos() - 2, 0), Err(RafError::BufferOverflow)));
+        assert!(matches!(w.write_u16_at(w.pos() + 1, 0), Err(RafError::StartOutOfRange)));
+
+        let mut raf = Raf::from_bytes(&w.into_bytes(), *bo);
+        assert_eq!(raf.read_u16().unwrap(), 0xBEEF);
+        assert_eq!(raf.read_u32().unwrap(), 11);
+        assert_eq!(raf.read_u64().unwrap(), 0x0102_0304_0506_0708);
+        assert_eq!(raf.read_bytes(10).unwrap(), vec![0x11; 10]);
+        assert_eq!(raf.read_u8().unwrap(), 0x22);
+        assert_eq!(raf.remaining(), 0);
+    }
+}
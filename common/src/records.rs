@@ -0,0 +1,106 @@
+use crate::raf::{Raf, RafError, Result};
+
+/// Iterates over records stored back to back, reading each with a record parser until the end of the data.
+///
+/// The iterator is lenient: a record which fails to parse is returned as an error, and the next
+/// record is read from wherever the parser left the position. A parser which fails without moving
+/// the position forward (Including one which seeks backwards) would re-read the same bytes forever,
+/// so the iterator returns that error and then ends. A parser which succeeds without moving the
+/// position forward is treated the same way, and ends the iterator with [RafError::NoProgress]
+///
+/// This is opt-in for records stored back to back. The CBF tables are read through a table of
+/// offsets rather than back to back, so their loaders do not use it
+pub struct RecordIter<'a, T, F: FnMut(&mut Raf) -> Result<T>> {
+    raf: &'a mut Raf,
+    parse: F,
+    done: bool,
+}
+
+impl<'a, T, F: FnMut(&mut Raf) -> Result<T>> RecordIter<'a, T, F> {
+    /// Reads records from the current position of the reader
+    ///
+    /// # Example
+    /// ```
+    /// use common::raf::{Raf, RafByteOrder};
+    /// use common::records::RecordIter;
+    ///
+    /// let mut raf = Raf::from_bytes(&vec![0x00, 0x01, 0x00, 0x02], RafByteOrder::BE);
+    /// let ids: Vec<u16> = RecordIter::new(&mut raf, Raf::read_u16).map(|r| r.unwrap()).collect();
+    /// assert_eq!(ids, vec![1, 2]);
+    /// ```
+    pub fn new(raf: &'a mut Raf, parse: F) -> Self {
+        Self { raf, parse, done: false }
+    }
+}
+
+impl<'a, T, F: FnMut(&mut Raf) -> Result<T>> Iterator for RecordIter<'a, T, F> {
+    type Item = Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done || self.raf.remaining() == 0 {
+            return None
+        }
+        let start = self.raf.pos;
+        let res = (self.parse)(self.raf);
+        if self.raf.pos <= start {
+            self.done = true;
+            return Some(res.and(Err(RafError::NoProgress { offset: start })))
+        }
+        Some(res)
+    }
+}
+
+impl<'a, T, F: FnMut(&mut Raf) -> Result<T>> std::iter::FusedIterator for RecordIter<'a, T, F> {}
+
+#[test]
+fn test_record_iter_stops_without_progress() {
+    use crate::raf::RafByteOrder;
+
+    // Records are a length byte then that many bytes. The second record's length runs past
+    // the end of the data, and the parser leaves the position at the start of the record
+    let data = vec![0x02, 0xAA, 0xBB, 0x09, 0xCC];
+    let mut raf = Raf::from_bytes(&data, RafByteOrder::BE);
+    let parse = |r: &mut Raf| {
+        let start = r.pos;
+        let len = r.read_u8()? as usize;
+        r.read_bytes(len).map_err(|e| { r.pos = start; e })
+    };
+    let res: Vec<Result<Vec<u8>>> = RecordIter::new(&mut raf, parse).collect();
+    assert_eq!(res.len(), 2);
+    assert_eq!(res[0].as_ref().unwrap(), &vec![0xAA, 0xBB]);
+    assert!(matches!(res[1], Err(RafError::BufferOverflow)));
+    assert_eq!(raf.pos, 3);
+
+    // A failure that moves the position doesn't stop the iterator
+    raf.seek(0).unwrap();
+    let mut calls = 0;
+    let res: Vec<Result<u8>> = RecordIter::new(&mut raf, |r| {
+        calls += 1;
+        match r.read_u8()? {
+            0xAA => Err(RafError::InvalidBcd { offset: r.pos - 1, byte: 0xAA }),
+            b => Ok(b)
+        }
+    }).collect();
+    assert_eq!(res.len(), 5);
+    assert!(res[1].is_err());
+    assert_eq!(*res[4].as_ref().unwrap(), 0xCC);
+    assert_eq!(calls, 5);
+
+    // Parsing nothing is an error too
+    raf.seek(0).unwrap();
+    let mut iter = RecordIter::new(&mut raf, |_| Ok(()));
+    assert!(matches!(iter.next(), Some(Err(RafError::NoProgress { offset: 0 }))));
+    assert!(iter.next().is_none());
+
+    // So is seeking backwards, which would otherwise re-read the earlier records forever
+    raf.seek(0).unwrap();
+    let res: Vec<Result<u8>> = RecordIter::new(&mut raf, |r| {
+        let b = r.read_u8()?;
+        if r.pos == 3 {
+            r.seek(1)?;
+        }
+        Ok(b)
+    }).collect();
+    assert_eq!(res.len(), 3);
+    assert!(matches!(res[2], Err(RafError::NoProgress { offset: 2 })));
+}
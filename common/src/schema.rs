@@ -1,18 +1,146 @@
 use serde::{Deserialize, Serialize};
 use J2534Common::Protocol;
 use serde_json::*;
+use crate::dtc::{DecodedDtc, ExtDataRecordDef};
+use crate::measurement::{DidDef, FormattedValue, ScaleError};
 /// Schema V1 for data contains that OVD uses
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SchemaV1 {
     meta: ECUMeta,
     err_table: Vec<DTC>,
-    comm_data: Vec<CommData>
+    comm_data: Vec<CommData>,
+    /// DIDs the ECU supports which hold measurement values
+    #[serde(default)]
+    measurements: Vec<DidDef>,
+    /// Layout of the DTC extended data records the ECU stores
+    #[serde(default)]
+    dtc_ext_records: Vec<ExtDataRecordDef>,
+    /// Writable DIDs which hold coding and adaptation values
+    #[serde(default)]
+    adaptations: Vec<AdaptationDef>,
+    /// Routines and actuator tests which can be run with RoutineControl
+    #[serde(default)]
+    routines: Vec<RoutineDef>,
 }
 
 impl SchemaV1 {
     pub fn to_file(&self, path: &str) {
         std::fs::write(path, serde_json::to_string_pretty(self).unwrap()).unwrap();
     }
+
+    pub fn from_json(json: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(json)
+    }
+
+    /// Returns every measurement DID declared by the definition
+    pub fn measurement_dids(&self) -> impl Iterator<Item = &DidDef> {
+        self.measurements.iter()
+    }
+
+    /// Decodes a DID which holds a record of several values, using every measurement the
+    /// definition declares for the DID. Fields are returned in the order they are in the record.
+    ///
+    /// Padding after the last field is ignored. If the response is shorter than expected, the
+    /// fields which are complete are still returned, and only the ones it cuts off are left out
+    ///
+    /// # Params
+    /// * raw - Data read from the DID, excluding the DID echoed back by the ECU
+    pub fn decode_did_response(&self, did: u16, raw: &[u8]) -> std::result::Result<Vec<(String, FormattedValue)>, ScaleError> {
+        let mut fields: Vec<&DidDef> = self.measurements.iter().filter(|d| d.did == did).collect();
+        if fields.is_empty() {
+            return Err(ScaleError::UnknownDid(did))
+        }
+        fields.sort_by_key(|d| d.byte_offset);
+        let mut res = Vec::new();
+        for def in &fields {
+            match def.format(raw) {
+                Ok(v) => res.push((def.name.clone(), v)),
+                Err(ScaleError::TooShort { .. }) => {},
+                Err(e) => return Err(e)
+            }
+        }
+        if res.is_empty() {
+            // Not even the first field was in the response
+            let needed = fields.iter().map(|d| d.byte_offset + d.byte_len).min().unwrap_or(0);
+            return Err(ScaleError::TooShort { needed, got: raw.len() })
+        }
+        Ok(res)
+    }
+
+    /// Returns every adaptation DID declared by the definition
+    pub fn adaptation_dids(&self) -> impl Iterator<Item = &AdaptationDef> {
+        self.adaptations.iter()
+    }
+
+    /// Returns every routine / actuator test declared by the definition
+    pub fn routine_defs(&self) -> &[RoutineDef] {
+        &self.routines
+    }
+
+    /// Returns the name of the ECU. Example: EGS52
+    pub fn ecu_name(&self) -> &str {
+        &self.meta.name
+    }
+
+    /// Returns the layout of the ECU's DTC extended data records
+    pub fn dtc_ext_record_defs(&self) -> &[ExtDataRecordDef] {
+        &self.dtc_ext_records
+    }
+
+    /// Resolves a DTC number read from the ECU using this definition's error table.
+    ///
+    /// Entries are matched either by the full DTC number in hex (Example: 9D0013), or by
+    /// the SAE code without the fault type byte (Example: B1D00). If no entry matches,
+    /// the generic SAE decoding is returned
+    pub fn decode_dtc(&self, number: u32) -> DecodedDtc {
+        let mut res = DecodedDtc::generic(number);
+        let full = format!("{:06X}", number);
+        let entry = self.err_table.iter().find(|e| e.name.eq_ignore_ascii_case(&full))
+            .or_else(|| self.err_table.iter().find(|e| e.name.eq_ignore_ascii_case(&res.code)));
+        if let Some(e) = entry {
+            res.desc = e.desc.clone();
+            res.env_dids = e.env_dids.clone();
+            res.from_definition = true;
+        }
+        res
+    }
+}
+
+/// Writable DID which holds coding or adaptation data (Learnt values, variant coding...).
+/// These are backed up before they are changed, so they can be restored
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AdaptationDef {
+    pub did: u16,
+    /// Name of the adaptation. Example: Shift pressure adaptation
+    pub name: String,
+}
+
+/// Routine or actuator test the ECU runs when started with RoutineControl
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RoutineDef {
+    pub id: u16,
+    /// Name of the routine. Example: Fuel pump actuation
+    pub name: String,
+    /// Parameters sent with the start request, in order
+    #[serde(default)]
+    pub params: Vec<RoutineParam>,
+    /// Routine moves or powers something on the vehicle (Fuel pump, cooling fan...),
+    /// so the user has to confirm before it is started
+    #[serde(default)]
+    pub dangerous: bool,
+}
+
+/// Unsigned integer parameter of a routine, sent big endian
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RoutineParam {
+    pub name: String,
+    /// Size of the parameter in bytes (1-4)
+    pub len: u8,
+    #[serde(default)]
+    pub unit: String,
+    /// Value shown before the user changes it
+    #[serde(default)]
+    pub default: u32,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -74,7 +202,10 @@ pub struct DTC {
     /// Name of error code. Example: P2000
     name: String,
     /// Description of error code
-    desc: String
+    desc: String,
+    /// DIDs of the environment data the ECU stores with this error
+    #[serde(default)]
+    env_dids: Vec<u16>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -99,4 +230,77 @@ fn create_egs52() {
         desc: "722.6 Controller Generation".to_string()
     };
     println!("{:#?}", meta);
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_decode_dtc() {
+    let schema = SchemaV1::from_json(r#"{
+        "meta": { "name": "EGS52", "vendor": "Mercedes-Benz", "desc": "722.6 Controller Generation" },
+        "err_table": [
+            { "name": "9D0013", "desc": "Solenoid valve 1-2/4-5 open circuit", "env_dids": [4096, 4097] },
+            { "name": "P0715", "desc": "Turbine speed sensor" }
+        ],
+        "comm_data": []
+    }"#).unwrap();
+    let dtc = schema.decode_dtc(0x9D0013);
+    assert!(dtc.from_definition);
+    assert_eq!(dtc.desc, "Solenoid valve 1-2/4-5 open circuit");
+    assert_eq!(dtc.fault_type.as_deref(), Some("Circuit open"));
+    assert_eq!(dtc.env_dids, vec![0x1000, 0x1001]);
+
+    // Matched by SAE code, any fault type
+    let dtc = schema.decode_dtc(0x071564);
+    assert_eq!(dtc.desc, "Turbine speed sensor");
+    assert_eq!(dtc.fault_type.as_deref(), Some("Signal plausibility failure"));
+
+    let dtc = schema.decode_dtc(0x012300);
+    assert!(!dtc.from_definition);
+    assert_eq!(dtc, crate::dtc::DecodedDtc::generic(0x012300));
+    assert_eq!(schema.measurement_dids().count(), 0);
+}
+
+#[test]
+fn test_measurement_dids() {
+    let schema = SchemaV1::from_json(r#"{
+        "meta": { "name": "EGS52", "vendor": "Mercedes-Benz", "desc": "722.6 Controller Generation" },
+        "err_table": [],
+        "comm_data": [],
+        "measurements": [
+            { "did": 4352, "name": "Oil temperature", "byte_len": 1, "compu": { "Linear": { "factor": 1.0, "offset": -40.0 } }, "unit": "°C" },
+            { "did": 4353, "name": "Gear", "byte_len": 1, "compu": { "TextTable": [[0, "Park"], [1, "Drive"]] } }
+        ]
+    }"#).unwrap();
+    let dids: Vec<u16> = schema.measurement_dids().map(|d| d.did).collect();
+    assert_eq!(dids, vec![0x1100, 0x1101]);
+    assert_eq!(schema.measurement_dids().nth(1).unwrap().scale(&[0x00]).unwrap().to_string(), "Park");
+}
+
+#[test]
+fn test_decode_did_response() {
+    let schema = SchemaV1::from_json(r#"{
+        "meta": { "name": "ME97", "vendor": "Bosch", "desc": "Engine controller" },
+        "err_table": [],
+        "comm_data": [],
+        "measurements": [
+            { "did": 8192, "name": "Intake air temperature", "byte_offset": 3, "byte_len": 1, "compu": { "Linear": { "factor": 1.0, "offset": -40.0 } }, "unit": "°C" },
+            { "did": 8192, "name": "Engine speed", "byte_offset": 0, "byte_len": 2, "compu": { "Linear": { "factor": 0.25, "offset": 0.0 } }, "unit": "rpm" },
+            { "did": 8192, "name": "Throttle state", "byte_offset": 2, "byte_len": 1, "compu": { "TextTable": [[0, "Closed"], [1, "Open"]] } },
+            { "did": 8192, "name": "Boost pressure", "byte_offset": 4, "byte_len": 2, "signed": true, "unit": "hPa" },
+            { "did": 8193, "name": "Oil level", "byte_len": 1, "unit": "mm" }
+        ]
+    }"#).unwrap();
+    let to_strings = |v: Vec<(String, FormattedValue)>| v.into_iter().map(|(n, v)| format!("{}: {}", n, v)).collect::<Vec<String>>();
+
+    // Padding after the last field is ignored
+    let res = schema.decode_did_response(0x2000, &[0x0B, 0xB8, 0x01, 0x46, 0xFF, 0x9C, 0x00, 0x00]).unwrap();
+    assert_eq!(to_strings(res), vec!["Engine speed: 750.00 rpm", "Throttle state: Open", "Intake air temperature: 30 °C", "Boost pressure: -100 hPa"]);
+
+    // Boost pressure is cut off
+    let res = schema.decode_did_response(0x2000, &[0x0B, 0xB8, 0x00, 0x46, 0x00]).unwrap();
+    assert_eq!(to_strings(res), vec!["Engine speed: 750.00 rpm", "Throttle state: Closed", "Intake air temperature: 30 °C"]);
+
+    assert_eq!(schema.decode_did_response(0x2000, &[0x0B]), Err(ScaleError::TooShort { needed: 2, got: 1 }));
+    assert_eq!(schema.decode_did_response(0x2000, &[0x0B, 0xB8, 0x07]), Err(ScaleError::NoTextMatch(7)));
+    assert_eq!(schema.decode_did_response(0x2001, &[0x20]).unwrap().len(), 1);
+    assert_eq!(schema.decode_did_response(0x3000, &[0x20]), Err(ScaleError::UnknownDid(0x3000)));
+}